@@ -21,6 +21,7 @@
 mod fd_server_helper;
 mod instance_manager;
 mod instance_starter;
+mod metrics;
 mod odrefresh_task;
 mod service;
 
@@ -35,10 +35,15 @@ pub struct FdServerConfig {
     pub ro_file_fds: Vec<OwnedFd>,
     /// List of file FDs exposed for read-write operations.
     pub rw_file_fds: Vec<OwnedFd>,
+    /// List of file FDs exposed for append-only operations, e.g. logs.
+    pub append_file_fds: Vec<OwnedFd>,
     /// List of directory FDs exposed for read-only operations.
     pub ro_dir_fds: Vec<OwnedFd>,
     /// List of directory FDs exposed for read-write operations.
     pub rw_dir_fds: Vec<OwnedFd>,
+    /// List of directory FDs where new entries can be created, but existing ones can't be
+    /// modified.
+    pub create_only_dir_fds: Vec<OwnedFd>,
 }
 
 impl FdServerConfig {
@@ -65,6 +70,12 @@ impl FdServerConfig {
             args.push(raw_fd.to_string());
             inheritable_fds.push(raw_fd);
         }
+        for fd in &self.append_file_fds {
+            let raw_fd = fd.as_raw_fd();
+            args.push("--append-fds".to_string());
+            args.push(raw_fd.to_string());
+            inheritable_fds.push(raw_fd);
+        }
         for fd in &self.ro_dir_fds {
             let raw_fd = fd.as_raw_fd();
             args.push("--ro-dirs".to_string());
@@ -77,6 +88,12 @@ impl FdServerConfig {
             args.push(raw_fd.to_string());
             inheritable_fds.push(raw_fd);
         }
+        for fd in &self.create_only_dir_fds {
+            let raw_fd = fd.as_raw_fd();
+            args.push("--create-only-dirs".to_string());
+            args.push(raw_fd.to_string());
+            inheritable_fds.push(raw_fd);
+        }
         let ready_fd = ready_file.as_raw_fd();
         args.push("--ready-fd".to_string());
         args.push(ready_fd.to_string());
@@ -40,24 +40,62 @@ impl InstanceManager {
     }
 
     pub fn start_current_instance(&self) -> Result<CompOsInstance> {
+        self.start_instance(
+            CURRENT_INSTANCE_DIR,
+            current_vm_parameters()?,
+            InstanceStarter::start_new_instance,
+        )
+    }
+
+    /// Starts the existing current instance again, as started by `start_current_instance`, e.g. to
+    /// resume a compilation after its VM died. Its key is kept, so that the artifacts signed by the
+    /// VM which died can still be verified.
+    pub fn restart_current_instance(&self) -> Result<CompOsInstance> {
+        self.start_instance(
+            CURRENT_INSTANCE_DIR,
+            current_vm_parameters()?,
+            InstanceStarter::start_existing_instance,
+        )
+    }
+
+    /// Starts the existing current instance, as started by `start_current_instance`, to verify the
+    /// artifacts it compiled.
+    pub fn start_existing_current_instance(&self) -> Result<CompOsInstance> {
         let mut vm_parameters = new_vm_parameters()?;
-        vm_parameters.name = String::from("Composd");
+        vm_parameters.name = String::from("ComposdVerify");
+        // Verification needs little work, unlike compilation.
+        vm_parameters.cpu_topology = VmCpuTopology::OneCpu;
+        // The parameters which the signing key depends on must match those of the compilation.
         vm_parameters.prefer_staged = true;
-        self.start_instance(CURRENT_INSTANCE_DIR, vm_parameters)
+        self.start_instance(
+            CURRENT_INSTANCE_DIR,
+            vm_parameters,
+            InstanceStarter::start_existing_instance,
+        )
     }
 
     pub fn start_test_instance(&self, prefer_staged: bool) -> Result<CompOsInstance> {
-        let mut vm_parameters = new_vm_parameters()?;
-        vm_parameters.name = String::from("ComposdTest");
-        vm_parameters.debug_mode = true;
-        vm_parameters.prefer_staged = prefer_staged;
-        self.start_instance(TEST_INSTANCE_DIR, vm_parameters)
+        self.start_instance(
+            TEST_INSTANCE_DIR,
+            test_vm_parameters(prefer_staged)?,
+            InstanceStarter::start_new_instance,
+        )
+    }
+
+    /// Starts the existing test instance again, as started by `start_test_instance`.
+    pub fn restart_test_instance(&self, prefer_staged: bool) -> Result<CompOsInstance> {
+        self.start_instance(
+            TEST_INSTANCE_DIR,
+            test_vm_parameters(prefer_staged)?,
+            InstanceStarter::start_existing_instance,
+        )
     }
 
     fn start_instance(
         &self,
         instance_name: &str,
         vm_parameters: VmParameters,
+        start: fn(&InstanceStarter, &dyn IVirtualizationService) -> Result<CompOsInstance>,
     ) -> Result<CompOsInstance> {
         let mut state = self.state.lock().unwrap();
         state.mark_starting()?;
@@ -65,7 +103,7 @@ impl InstanceManager {
         drop(state);
 
         let instance_starter = InstanceStarter::new(instance_name, vm_parameters);
-        let instance = instance_starter.start_new_instance(&*self.service);
+        let instance = start(&instance_starter, &*self.service);
 
         let mut state = self.state.lock().unwrap();
         if let Ok(ref instance) = instance {
@@ -77,6 +115,21 @@ impl InstanceManager {
     }
 }
 
+fn current_vm_parameters() -> Result<VmParameters> {
+    let mut vm_parameters = new_vm_parameters()?;
+    vm_parameters.name = String::from("Composd");
+    vm_parameters.prefer_staged = true;
+    Ok(vm_parameters)
+}
+
+fn test_vm_parameters(prefer_staged: bool) -> Result<VmParameters> {
+    let mut vm_parameters = new_vm_parameters()?;
+    vm_parameters.name = String::from("ComposdTest");
+    vm_parameters.debug_mode = true;
+    vm_parameters.prefer_staged = prefer_staged;
+    Ok(vm_parameters)
+}
+
 fn new_vm_parameters() -> Result<VmParameters> {
     // By default, dex2oat starts as many threads as there are CPUs. This can be overridden with
     // a system property. Start the VM with all CPUs and assume the guest will start a suitable
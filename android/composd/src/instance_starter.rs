@@ -20,7 +20,7 @@
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
     IVirtualizationService::IVirtualizationService, PartitionType::PartitionType,
 };
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, ensure, Context, Result};
 use binder::{LazyServiceGuard, ParcelFileDescriptor, Strong};
 use compos_aidl_interface::aidl::com::android::compos::ICompOsService::ICompOsService;
 use compos_common::compos_client::{ComposClient, VmParameters};
@@ -32,6 +32,7 @@ use log::info;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 pub struct CompOsInstance {
     service: Strong<dyn ICompOsService>,
@@ -54,6 +55,16 @@ impl CompOsInstance {
         &self.instance_tracker
     }
 
+    /// Returns whether the VM dies within `timeout`.
+    pub fn died_within(&self, timeout: Duration) -> bool {
+        self.vm_instance.died_within(timeout)
+    }
+
+    /// Returns the highest resident memory of the VM seen so far, in KiB, if it is known.
+    pub fn peak_rss_kb(&self) -> Option<i64> {
+        self.vm_instance.peak_rss_kb()
+    }
+
     /// Attempt to shut down the VM cleanly, giving time for any relevant logs to be written.
     pub fn shutdown(self) -> LazyServiceGuard {
         self.vm_instance.shutdown(self.service);
@@ -124,6 +135,18 @@ impl InstanceStarter {
         Ok(instance)
     }
 
+    /// Starts the existing instance, without creating a new one, e.g. to verify the artifacts it
+    /// compiled earlier, which requires its signing key. Fails if there is no existing instance.
+    pub fn start_existing_instance(
+        &self,
+        virtualization_service: &dyn IVirtualizationService,
+    ) -> Result<CompOsInstance> {
+        info!("Starting existing {} CompOs instance", self.instance_name);
+
+        ensure!(self.instance_image.exists(), "No existing {} instance", self.instance_name);
+        self.start_vm(virtualization_service)
+    }
+
     fn start_vm(
         &self,
         virtualization_service: &dyn IVirtualizationService,
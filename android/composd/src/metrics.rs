@@ -0,0 +1,174 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Statistics of the compilation tasks, written to statsd as ComposCompilationReported atoms so
+//! that regressions of CompOS can be noticed in the field.
+//!
+//! Like the other atoms of AVF, ComposCompilationReported is defined in frameworks/proto_logging,
+//! in the virtualization atoms, and generated into statslog_virtualization_rust from there. The
+//! definition must land there before this module builds.
+
+use log::{info, warn};
+use statslog_virtualization_rust::compos_compilation_reported;
+use std::time::Duration;
+
+/// What a compilation task does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskKind {
+    /// Compiles the staged APEXes, see IIsolatedCompilationService.startStagedApexCompile.
+    Compile,
+    /// Compiles for testing, see IIsolatedCompilationService.startTestCompile.
+    TestCompile,
+    /// Re-verifies the pending artifacts.
+    Verification,
+}
+
+/// The stage at which a compilation task failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailureStage {
+    /// The CompOS VM couldn't be started.
+    StartVm,
+    /// Running odrefresh in the VM failed.
+    Compilation,
+    /// odrefresh ran, but reported a problem.
+    UnexpectedCompilationResult,
+    /// fs-verity couldn't be enabled for the artifacts.
+    EnableFsverity,
+    /// Running the verification in the VM failed.
+    Verification,
+    /// The verification found the artifacts invalid.
+    ArtifactsInvalid,
+    /// The task was cancelled before it ended.
+    Cancelled,
+}
+
+/// The statistics of a run of a compilation task.
+#[derive(Debug)]
+pub struct TaskMetrics {
+    pub kind: TaskKind,
+    /// How long the task ran for, not counting the start of the VM.
+    pub duration: Duration,
+    /// The total size of the artifacts in the target directory when the task ended.
+    pub artifacts_size_bytes: u64,
+    /// The highest resident memory of the VM, in KiB, if it is known.
+    pub vm_peak_rss_kb: Option<i64>,
+    /// Where the task failed, or None if it succeeded.
+    pub failure_stage: Option<FailureStage>,
+}
+
+impl TaskMetrics {
+    /// Returns the statistics of a task whose VM couldn't be started, after trying for `duration`.
+    pub fn failed_to_start(kind: TaskKind, duration: Duration) -> Self {
+        Self {
+            kind,
+            duration,
+            artifacts_size_bytes: 0,
+            vm_peak_rss_kb: None,
+            failure_stage: Some(FailureStage::StartVm),
+        }
+    }
+
+    /// Writes the statistics to statsd. Failures are only logged, as they don't affect the task.
+    pub fn write(&self) {
+        info!("Writing ComposCompilationReported atom into statsd: {self:?}");
+        if let Err(e) = self.atom().stats_write() {
+            warn!("Failed to write ComposCompilationReported atom: {e}");
+        }
+    }
+
+    fn atom(&self) -> compos_compilation_reported::ComposCompilationReported {
+        let task_kind = match self.kind {
+            TaskKind::Compile => compos_compilation_reported::TaskKind::Compile,
+            TaskKind::TestCompile => compos_compilation_reported::TaskKind::TestCompile,
+            TaskKind::Verification => compos_compilation_reported::TaskKind::Verification,
+        };
+        let failure_stage = match self.failure_stage {
+            None => compos_compilation_reported::FailureStage::None,
+            Some(FailureStage::StartVm) => compos_compilation_reported::FailureStage::StartVm,
+            Some(FailureStage::Compilation) => {
+                compos_compilation_reported::FailureStage::Compilation
+            }
+            Some(FailureStage::UnexpectedCompilationResult) => {
+                compos_compilation_reported::FailureStage::UnexpectedCompilationResult
+            }
+            Some(FailureStage::EnableFsverity) => {
+                compos_compilation_reported::FailureStage::EnableFsverity
+            }
+            Some(FailureStage::Verification) => {
+                compos_compilation_reported::FailureStage::Verification
+            }
+            Some(FailureStage::ArtifactsInvalid) => {
+                compos_compilation_reported::FailureStage::ArtifactsInvalid
+            }
+            Some(FailureStage::Cancelled) => compos_compilation_reported::FailureStage::Cancelled,
+        };
+        compos_compilation_reported::ComposCompilationReported {
+            task_kind,
+            duration_millis: self.duration.as_millis().try_into().unwrap_or(i64::MAX),
+            artifacts_size_bytes: self.artifacts_size_bytes.try_into().unwrap_or(i64::MAX),
+            vm_peak_rss_kb: self.vm_peak_rss_kb.unwrap_or(-1),
+            failure_stage,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compos_compilation_reported::FailureStage as AtomFailureStage;
+    use compos_compilation_reported::TaskKind as AtomTaskKind;
+
+    #[test]
+    fn successful_task_is_reported() {
+        let metrics = TaskMetrics {
+            kind: TaskKind::TestCompile,
+            duration: Duration::from_millis(12_345),
+            artifacts_size_bytes: 4096,
+            vm_peak_rss_kb: Some(300_000),
+            failure_stage: None,
+        };
+        let atom = metrics.atom();
+        assert!(matches!(atom.task_kind, AtomTaskKind::TestCompile));
+        assert_eq!(atom.duration_millis, 12_345);
+        assert_eq!(atom.artifacts_size_bytes, 4096);
+        assert_eq!(atom.vm_peak_rss_kb, 300_000);
+        assert!(matches!(atom.failure_stage, AtomFailureStage::None));
+    }
+
+    #[test]
+    fn failure_to_start_is_reported() {
+        let atom = TaskMetrics::failed_to_start(TaskKind::Compile, Duration::from_secs(2)).atom();
+        assert!(matches!(atom.task_kind, AtomTaskKind::Compile));
+        assert_eq!(atom.duration_millis, 2000);
+        assert_eq!(atom.artifacts_size_bytes, 0);
+        // The peak RSS is unknown without a VM.
+        assert_eq!(atom.vm_peak_rss_kb, -1);
+        assert!(matches!(atom.failure_stage, AtomFailureStage::StartVm));
+    }
+
+    #[test]
+    fn oversized_values_saturate() {
+        let metrics = TaskMetrics {
+            kind: TaskKind::Verification,
+            duration: Duration::MAX,
+            artifacts_size_bytes: u64::MAX,
+            vm_peak_rss_kb: None,
+            failure_stage: Some(FailureStage::Cancelled),
+        };
+        let atom = metrics.atom();
+        assert_eq!(atom.duration_millis, i64::MAX);
+        assert_eq!(atom.artifacts_size_bytes, i64::MAX);
+        assert!(matches!(atom.failure_stage, AtomFailureStage::Cancelled));
+    }
+}
@@ -18,54 +18,136 @@
 
 use crate::fd_server_helper::FdServerConfig;
 use crate::instance_starter::CompOsInstance;
+use crate::metrics::{FailureStage, TaskKind, TaskMetrics};
 use android_system_composd::aidl::android::system::composd::{
+    CompilationTaskInfo::CompilationTaskInfo,
     ICompilationTask::ICompilationTask,
     ICompilationTaskCallback::{FailureReason::FailureReason, ICompilationTaskCallback},
 };
 use anyhow::{Context, Result};
-use binder::{Interface, Result as BinderResult, Strong};
+use binder::{Interface, LazyServiceGuard, Result as BinderResult, Strong};
 use compos_aidl_interface::aidl::com::android::compos::ICompOsService::{
-    CompilationMode::CompilationMode, ICompOsService, OdrefreshArgs::OdrefreshArgs,
+    ArtifactDigest::ArtifactDigest, Checkpoint::Checkpoint, CompilationMode::CompilationMode,
+    ICompOsService, OdrefreshArgs::OdrefreshArgs,
 };
 use compos_common::odrefresh::{
-    is_system_property_interesting, ExitCode, CURRENT_ARTIFACTS_SUBDIR, ODREFRESH_OUTPUT_ROOT_DIR,
-    PENDING_ARTIFACTS_SUBDIR,
+    is_system_property_interesting, ExitCode, CHECKPOINT_SUFFIX, CURRENT_ARTIFACTS_SUBDIR,
+    ODREFRESH_OUTPUT_ROOT_DIR, PENDING_ARTIFACTS_SUBDIR,
 };
 use compos_common::BUILD_MANIFEST_SYSTEM_EXT_APK_PATH;
 use log::{error, info, warn};
 use odsign_proto::odsign_info::OdsignInfo;
 use protobuf::Message;
 use rustutils::system_properties;
-use std::fs::{remove_dir_all, File, OpenOptions};
+use std::collections::BTreeMap;
+use std::fs::{self, read_dir, remove_dir_all, remove_file, File, OpenOptions};
+use std::io::ErrorKind;
 use std::os::fd::AsFd;
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd, OwnedFd};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+
+/// Name of the file listing the digests of the artifacts, signed by CompOS.
+const COMPOS_INFO_FILE: &str = "compos.info";
+/// Name of the signature of `COMPOS_INFO_FILE`.
+const COMPOS_INFO_SIGNATURE_FILE: &str = "compos.info.signature";
+
+/// Suffix of the directory which the checkpointed artifacts are moved to while a compilation is
+/// resumed, for the new VM to restore them from.
+const RESUMED_SUFFIX: &str = ".resumed";
+
+/// How many times a compilation is resumed in a new VM after its VM died, so that a VM which dies
+/// deterministically, e.g. running out of memory, isn't restarted forever.
+const MAX_RESUMES: usize = 1;
+
+/// How long the VM may take to be reported dead after a call to it failed because it died.
+const VM_DEATH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The compilation tasks which haven't ended yet, keyed by their ID, so that they can be listed
+/// and cancelled by clients which don't hold them.
+#[derive(Clone, Default)]
+pub struct ActiveTasks(Arc<Mutex<ActiveTasksState>>);
+
+#[derive(Default)]
+struct ActiveTasksState {
+    next_id: i32,
+    tasks: BTreeMap<i32, OdrefreshTask>,
+}
+
+impl ActiveTasks {
+    /// Returns the descriptions of the active tasks, ordered by ID.
+    pub fn list(&self) -> Vec<CompilationTaskInfo> {
+        let tasks: Vec<_> = self.0.lock().unwrap().tasks.values().cloned().collect();
+        tasks.iter().map(OdrefreshTask::info).collect()
+    }
+
+    /// Cancels the task with `task_id`, returning its description as it was cancelled, or None if
+    /// there is no such active task.
+    pub fn cancel(&self, task_id: i32) -> Option<CompilationTaskInfo> {
+        // The task removes itself from the map as it is cancelled, so it mustn't be locked.
+        let task = self.0.lock().unwrap().tasks.get(&task_id).cloned()?;
+        let info = task.info();
+        task.cancel_task().then_some(info)
+    }
+
+    fn next_id(&self) -> i32 {
+        let mut state = self.0.lock().unwrap();
+        let id = state.next_id;
+        state.next_id = id.wrapping_add(1);
+        id
+    }
+
+    fn insert(&self, task: OdrefreshTask) {
+        self.0.lock().unwrap().tasks.insert(task.id, task);
+    }
+
+    fn remove(&self, task_id: i32) {
+        self.0.lock().unwrap().tasks.remove(&task_id);
+    }
+}
 
 #[derive(Clone)]
 pub struct OdrefreshTask {
+    id: i32,
+    compilation_mode: CompilationMode,
+    target_dir_name: String,
+    is_verification: bool,
+    start_time: Instant,
     running_task: Arc<Mutex<Option<RunningTask>>>,
+    active_tasks: ActiveTasks,
 }
 
 impl Interface for OdrefreshTask {}
 
 impl ICompilationTask for OdrefreshTask {
     fn cancel(&self) -> BinderResult<()> {
-        let task = self.take();
-        // Drop the VM, which should end compilation - and cause our thread to exit.
-        // Note that we don't do a graceful shutdown here; we've been asked to give up our resources
-        // ASAP, and the VM has not failed so we don't need to ensure VM logs are written.
-        drop(task);
+        self.cancel_task();
         Ok(())
     }
+
+    fn getTaskId(&self) -> BinderResult<i32> {
+        Ok(self.id)
+    }
 }
 
 struct RunningTask {
     callback: Strong<dyn ICompilationTaskCallback>,
-    #[allow(dead_code)] // Keeps the CompOS VM alive
-    comp_os: CompOsInstance,
+    /// Keeps the CompOS VM alive. None while the VM is checked or replaced, or if it couldn't be.
+    comp_os: Option<CompOsInstance>,
+}
+
+impl RunningTask {
+    /// Shuts down the VM, if any, returning its peak RSS and a guard which keeps composd alive
+    /// until the callback is delivered.
+    fn shutdown(self) -> (Strong<dyn ICompilationTaskCallback>, Option<i64>, LazyServiceGuard) {
+        match self.comp_os {
+            Some(comp_os) => (self.callback, comp_os.peak_rss_kb(), comp_os.shutdown()),
+            None => (self.callback, None, LazyServiceGuard::default()),
+        }
+    }
 }
 
 impl OdrefreshTask {
@@ -73,39 +155,188 @@ impl OdrefreshTask {
     /// Once removed, meaning the task has ended or been canceled, further calls will always return
     /// None.
     fn take(&self) -> Option<RunningTask> {
-        self.running_task.lock().unwrap().take()
+        let task = self.running_task.lock().unwrap().take();
+        self.active_tasks.remove(self.id);
+        task
+    }
+
+    /// End the task, reporting to the callback that it was cancelled, returning whether it was
+    /// still running.
+    fn cancel_task(&self) -> bool {
+        let Some(RunningTask { callback, comp_os }) = self.take() else { return false };
+        // Drop the VM, which should end compilation - and cause our thread to exit.
+        // Note that we don't do a graceful shutdown here; we've been asked to give up our resources
+        // ASAP, and the VM has not failed so we don't need to ensure VM logs are written.
+        drop(comp_os);
+        let message = format!("Compilation task {} was cancelled", self.id);
+        info!("{}", message);
+        if let Err(e) = callback.onFailure(FailureReason::Cancelled, &message) {
+            warn!("Failed to deliver callback: {:?}", e);
+        }
+        true
     }
 
+    fn info(&self) -> CompilationTaskInfo {
+        let target_path = Path::new(ODREFRESH_OUTPUT_ROOT_DIR).join(&self.target_dir_name);
+        CompilationTaskInfo {
+            taskId: self.id,
+            isTestCompile: self.compilation_mode == CompilationMode::TEST_COMPILE,
+            isVerification: self.is_verification,
+            elapsedMillis: self.start_time.elapsed().as_millis().try_into().unwrap_or(i64::MAX),
+            artifactCount: count_files(&target_path).try_into().unwrap_or(i32::MAX),
+        }
+    }
+
+    /// Writes the statistics of the task, which has just ended.
+    fn write_metrics(&self, vm_peak_rss_kb: Option<i64>, failure_stage: Option<FailureStage>) {
+        let kind = if self.is_verification {
+            TaskKind::Verification
+        } else if self.compilation_mode == CompilationMode::TEST_COMPILE {
+            TaskKind::TestCompile
+        } else {
+            TaskKind::Compile
+        };
+        let target_path = Path::new(ODREFRESH_OUTPUT_ROOT_DIR).join(&self.target_dir_name);
+        TaskMetrics {
+            kind,
+            duration: self.start_time.elapsed(),
+            artifacts_size_bytes: total_file_size(&target_path),
+            vm_peak_rss_kb,
+            failure_stage,
+        }
+        .write();
+    }
+
+    /// Start compiling in the instance. If its VM dies, the compilation is resumed in a VM of the
+    /// same instance started by `restart`.
     pub fn start(
         comp_os: CompOsInstance,
         compilation_mode: CompilationMode,
         target_dir_name: String,
         callback: &Strong<dyn ICompilationTaskCallback>,
+        active_tasks: &ActiveTasks,
+        restart: impl Fn() -> Result<CompOsInstance> + Send + 'static,
     ) -> Result<OdrefreshTask> {
         let service = comp_os.get_service();
-        let task = RunningTask { comp_os, callback: callback.clone() };
-        let task = OdrefreshTask { running_task: Arc::new(Mutex::new(Some(task))) };
+        let task = Self::register(
+            comp_os,
+            compilation_mode,
+            target_dir_name.clone(),
+            false,
+            callback,
+            active_tasks,
+        );
+        task.clone().start_thread(service, compilation_mode, target_dir_name, restart);
 
-        task.clone().start_thread(service, compilation_mode, target_dir_name);
+        Ok(task)
+    }
+
+    /// Start re-verifying the pending artifacts, as compiled by a NORMAL_COMPILE task, in the
+    /// instance which compiled them, optionally deleting them if they are invalid.
+    pub fn start_verification(
+        comp_os: CompOsInstance,
+        delete_if_invalid: bool,
+        callback: &Strong<dyn ICompilationTaskCallback>,
+        active_tasks: &ActiveTasks,
+    ) -> Result<OdrefreshTask> {
+        let service = comp_os.get_service();
+        let task = Self::register(
+            comp_os,
+            CompilationMode::NORMAL_COMPILE,
+            PENDING_ARTIFACTS_SUBDIR.to_owned(),
+            true,
+            callback,
+            active_tasks,
+        );
+        task.clone().start_verification_thread(service, delete_if_invalid);
 
         Ok(task)
     }
 
+    fn register(
+        comp_os: CompOsInstance,
+        compilation_mode: CompilationMode,
+        target_dir_name: String,
+        is_verification: bool,
+        callback: &Strong<dyn ICompilationTaskCallback>,
+        active_tasks: &ActiveTasks,
+    ) -> OdrefreshTask {
+        let task = RunningTask { comp_os: Some(comp_os), callback: callback.clone() };
+        let task = OdrefreshTask {
+            id: active_tasks.next_id(),
+            compilation_mode,
+            target_dir_name,
+            is_verification,
+            start_time: Instant::now(),
+            running_task: Arc::new(Mutex::new(Some(task))),
+            active_tasks: active_tasks.clone(),
+        };
+        // Registered before the thread starts, so that it can't end before being registered.
+        active_tasks.insert(task.clone());
+        task
+    }
+
+    /// Takes the VM out of the task if it died, rather than the task failing in it.
+    fn take_dead_vm(&self) -> Option<CompOsInstance> {
+        // The lock isn't held while waiting for the VM, so that the task can be cancelled
+        // meanwhile.
+        let comp_os = self.running_task.lock().unwrap().as_mut()?.comp_os.take()?;
+        if comp_os.died_within(VM_DEATH_TIMEOUT) {
+            return Some(comp_os);
+        }
+        // The VM is dropped if the task was cancelled meanwhile.
+        if let Some(task) = self.running_task.lock().unwrap().as_mut() {
+            task.comp_os = Some(comp_os);
+        }
+        None
+    }
+
+    /// Replaces `dead_vm`, the dead VM of the task, with the VM started by `restart`, returning
+    /// its service.
+    fn restart_vm(
+        &self,
+        dead_vm: CompOsInstance,
+        restart: &dyn Fn() -> Result<CompOsInstance>,
+    ) -> Result<Strong<dyn ICompOsService>> {
+        // The dead VM must be dropped before another VM of the instance can be started.
+        let _lazy_service_guard = dead_vm.shutdown();
+        // The lock isn't held while the new VM boots either. If the task is cancelled meanwhile,
+        // the new VM is dropped.
+        let comp_os = restart().context("Restarting CompOS")?;
+        let service = comp_os.get_service();
+        let mut running_task = self.running_task.lock().unwrap();
+        running_task.as_mut().context("Task was cancelled")?.comp_os = Some(comp_os);
+        Ok(service)
+    }
+
     fn start_thread(
         self,
         service: Strong<dyn ICompOsService>,
         compilation_mode: CompilationMode,
         target_dir_name: String,
+        restart: impl Fn() -> Result<CompOsInstance> + Send + 'static,
     ) {
         thread::spawn(move || {
-            let exit_code = run_in_vm(service, compilation_mode, &target_dir_name);
+            let mut exit_code = run_in_vm(service, compilation_mode, &target_dir_name, false);
+            for _ in 0..MAX_RESUMES {
+                if exit_code.is_ok() {
+                    break;
+                }
+                let Some(dead_vm) = self.take_dead_vm() else { break };
+                warn!("CompOS VM died while compiling, resuming in a new VM");
+                exit_code = self.restart_vm(dead_vm, &restart).and_then(|service| {
+                    run_in_vm(service, compilation_mode, &target_dir_name, true)
+                });
+            }
+            remove_checkpoint(Path::new(ODREFRESH_OUTPUT_ROOT_DIR), &target_dir_name);
 
             let task = self.take();
             // We don't do the callback if cancel has already happened.
-            if let Some(RunningTask { callback, comp_os }) = task {
+            if let Some(task) = task {
                 // Make sure we keep our service alive until we have called the callback.
-                let lazy_service_guard = comp_os.shutdown();
+                let (callback, vm_peak_rss_kb, lazy_service_guard) = task.shutdown();
 
+                let mut failure_stage = None;
                 let result = match exit_code {
                     Ok(ExitCode::CompilationSuccess) => {
                         if compilation_mode == CompilationMode::TEST_COMPILE {
@@ -117,6 +348,7 @@ impl OdrefreshTask {
                                 let message =
                                     format!("Unexpected failure when enabling fs-verity: {:?}", e);
                                 error!("{}", message);
+                                failure_stage = Some(FailureStage::EnableFsverity);
                                 callback.onFailure(FailureReason::FailedToEnableFsverity, &message)
                             } else {
                                 info!("Compilation success, fs-verity enabled");
@@ -127,27 +359,88 @@ impl OdrefreshTask {
                     Ok(exit_code) => {
                         let message = format!("Unexpected odrefresh result: {:?}", exit_code);
                         error!("{}", message);
+                        failure_stage = Some(FailureStage::UnexpectedCompilationResult);
                         callback.onFailure(FailureReason::UnexpectedCompilationResult, &message)
                     }
                     Err(e) => {
                         let message = format!("Running odrefresh failed: {:?}", e);
                         error!("{}", message);
+                        failure_stage = Some(FailureStage::Compilation);
                         callback.onFailure(FailureReason::CompilationFailed, &message)
                     }
                 };
                 if let Err(e) = result {
                     warn!("Failed to deliver callback: {:?}", e);
                 }
+                self.write_metrics(vm_peak_rss_kb, failure_stage);
+                drop(lazy_service_guard);
+            } else {
+                self.write_metrics(None, Some(FailureStage::Cancelled));
+            }
+        });
+    }
+}
+
+impl OdrefreshTask {
+    fn start_verification_thread(
+        self,
+        service: Strong<dyn ICompOsService>,
+        delete_if_invalid: bool,
+    ) {
+        thread::spawn(move || {
+            let problems = verify_in_vm(service);
+
+            let task = self.take();
+            // We don't do the callback if cancel has already happened.
+            if let Some(task) = task {
+                // Make sure we keep our service alive until we have called the callback.
+                let (callback, vm_peak_rss_kb, lazy_service_guard) = task.shutdown();
+
+                let mut failure_stage = None;
+                let result = match problems {
+                    Ok(problems) if problems.is_empty() => {
+                        info!("Pending artifacts verified");
+                        callback.onSuccess()
+                    }
+                    Ok(problems) => {
+                        let mut message =
+                            format!("Pending artifacts are invalid: {}", problems.join("; "));
+                        if delete_if_invalid {
+                            match delete_pending_artifacts() {
+                                Ok(()) => message.push_str(" (deleted them)"),
+                                Err(e) => message.push_str(&format!(" (failed to delete: {e:?})")),
+                            }
+                        }
+                        error!("{}", message);
+                        failure_stage = Some(FailureStage::ArtifactsInvalid);
+                        callback.onFailure(FailureReason::ArtifactsInvalid, &message)
+                    }
+                    Err(e) => {
+                        let message = format!("Verifying pending artifacts failed: {:?}", e);
+                        error!("{}", message);
+                        failure_stage = Some(FailureStage::Verification);
+                        callback.onFailure(FailureReason::VerificationFailed, &message)
+                    }
+                };
+                if let Err(e) = result {
+                    warn!("Failed to deliver callback: {:?}", e);
+                }
+                self.write_metrics(vm_peak_rss_kb, failure_stage);
                 drop(lazy_service_guard);
+            } else {
+                self.write_metrics(None, Some(FailureStage::Cancelled));
             }
         });
     }
 }
 
+/// Runs odrefresh in the VM. When `resume` is set, the VM restores the artifacts checkpointed by
+/// the VM which died, if any, rather than compiling them again.
 fn run_in_vm(
     service: Strong<dyn ICompOsService>,
     compilation_mode: CompilationMode,
     target_dir_name: &str,
+    resume: bool,
 ) -> Result<ExitCode> {
     let mut names = Vec::new();
     let mut values = Vec::new();
@@ -160,15 +453,33 @@ fn run_in_vm(
     service.initializeSystemProperties(&names, &values).context("initialize system properties")?;
 
     let output_root = Path::new(ODREFRESH_OUTPUT_ROOT_DIR);
-
-    // We need to remove the target directory because odrefresh running in compos will create it
-    // (and can't see the existing one, since authfs doesn't show it existing files in an output
-    // directory).
-    let target_path = output_root.join(target_dir_name);
-    if target_path.exists() {
-        remove_dir_all(&target_path)
-            .with_context(|| format!("Failed to delete {}", target_path.display()))?;
+    let saved_checkpoint =
+        prepare_output(output_root, target_dir_name, resume)?.and_then(|saved_checkpoint| {
+            // fd_server only serves the files of read-only directories with fs-verity enabled.
+            match enable_fsverity_recursively(&saved_checkpoint.dir) {
+                Ok(()) => Some(saved_checkpoint),
+                Err(e) => {
+                    warn!("Failed to enable fs-verity to the checkpoint, ignoring it: {e:?}");
+                    None
+                }
+            }
+        });
+    let result = run_odrefresh(service, compilation_mode, target_dir_name, saved_checkpoint);
+    if let Err(e) =
+        remove_dir_if_exists(&output_root.join(format!("{target_dir_name}{RESUMED_SUFFIX}")))
+    {
+        warn!("Failed to delete the checkpointed artifacts: {e:?}");
     }
+    result
+}
+
+fn run_odrefresh(
+    service: Strong<dyn ICompOsService>,
+    compilation_mode: CompilationMode,
+    target_dir_name: &str,
+    saved_checkpoint: Option<SavedCheckpoint>,
+) -> Result<ExitCode> {
+    let output_root = Path::new(ODREFRESH_OUTPUT_ROOT_DIR);
 
     let staging_dir_fd = open_dir(composd_native::palette_create_odrefresh_staging_directory()?)?;
     let system_dir_fd = open_dir(Path::new("/system"))?;
@@ -187,13 +498,23 @@ fn run_in_vm(
     // and feel slightly weird to encode the VM's state to the task itself, as it is a request to
     // the VM.
     let need_system_ext = Path::new(BUILD_MANIFEST_SYSTEM_EXT_APK_PATH).exists();
-    let (system_ext_dir_raw_fd, ro_dir_fds) = if need_system_ext {
+    let (system_ext_dir_raw_fd, mut ro_dir_fds) = if need_system_ext {
         let system_ext_dir_fd = open_dir(Path::new("/system_ext"))?;
         (system_ext_dir_fd.as_raw_fd(), vec![system_dir_fd, system_ext_dir_fd])
     } else {
         (-1, vec![system_dir_fd])
     };
 
+    let checkpoint = match saved_checkpoint {
+        Some(SavedCheckpoint { dir, info, signature }) => {
+            let dir_fd = open_dir(&dir)?;
+            let checkpoint = Checkpoint { dirFd: dir_fd.as_raw_fd(), info, signature };
+            ro_dir_fds.push(dir_fd);
+            Some(checkpoint)
+        }
+        None => None,
+    };
+
     // Spawn a fd_server to serve the FDs.
     let fd_server_config = FdServerConfig {
         ro_dir_fds,
@@ -215,6 +536,7 @@ fn run_in_vm(
         targetDirName: target_dir_name.to_string(),
         zygoteArch: zygote_arch,
         systemServerCompilerFilter: system_server_compiler_filter,
+        checkpoint,
     };
     let exit_code = service.odrefresh(&args)?;
 
@@ -222,13 +544,165 @@ fn run_in_vm(
     ExitCode::from_i32(exit_code.into())
 }
 
+/// The artifacts compiled by a VM which died, moved aside, and the checkpoint it signed for them.
+struct SavedCheckpoint {
+    dir: PathBuf,
+    info: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+/// Prepares `output_root` for compiling to the target directory. When resuming, the artifacts of
+/// the checkpoint left by the VM which died, if any, are moved aside and returned with it, for the
+/// new VM to restore them. Everything else from an earlier run is deleted, as odrefresh running in
+/// CompOS creates the target directory, and can't see existing files through authfs.
+fn prepare_output(
+    output_root: &Path,
+    target_dir_name: &str,
+    resume: bool,
+) -> Result<Option<SavedCheckpoint>> {
+    let resumed_path = output_root.join(format!("{target_dir_name}{RESUMED_SUFFIX}"));
+    remove_dir_if_exists(&resumed_path)?;
+    let mut saved_checkpoint = None;
+    if resume {
+        match save_checkpoint(output_root, target_dir_name, &resumed_path) {
+            Ok(saved) => saved_checkpoint = saved,
+            Err(e) => warn!("Failed to save the checkpoint, compiling everything: {e:?}"),
+        }
+    }
+    for path in checkpoint_paths(output_root, target_dir_name) {
+        remove_file_if_exists(&path)?;
+    }
+    remove_dir_if_exists(&output_root.join(target_dir_name))?;
+    Ok(saved_checkpoint)
+}
+
+/// Reads the checkpoint of the target directory, if any, moving its artifacts to `resumed_path`.
+fn save_checkpoint(
+    output_root: &Path,
+    target_dir_name: &str,
+    resumed_path: &Path,
+) -> Result<Option<SavedCheckpoint>> {
+    let [checkpoint_path, signature_path] = checkpoint_paths(output_root, target_dir_name);
+    if !checkpoint_path.exists() || !signature_path.exists() {
+        info!("No checkpoint of {target_dir_name}, compiling everything");
+        return Ok(None);
+    }
+    let info = fs::read(&checkpoint_path).context("Failed to read the checkpoint")?;
+    let signature = fs::read(&signature_path).context("Failed to read the checkpoint signature")?;
+    fs::rename(output_root.join(target_dir_name), resumed_path)
+        .context("Failed to move the checkpointed artifacts")?;
+    Ok(Some(SavedCheckpoint { dir: resumed_path.to_owned(), info, signature }))
+}
+
+/// Deletes the checkpoint of the target directory, once the task which wrote it has ended.
+fn remove_checkpoint(output_root: &Path, target_dir_name: &str) {
+    for path in checkpoint_paths(output_root, target_dir_name) {
+        if let Err(e) = remove_file_if_exists(&path) {
+            warn!("Failed to delete the checkpoint: {e:?}");
+        }
+    }
+}
+
+/// Returns the paths of the checkpoint of the target directory and of its signature.
+fn checkpoint_paths(output_root: &Path, target_dir_name: &str) -> [PathBuf; 2] {
+    let checkpoint_name = format!("{target_dir_name}{CHECKPOINT_SUFFIX}");
+    let signature_name = format!("{checkpoint_name}.signature");
+    [output_root.join(checkpoint_name), output_root.join(signature_name)]
+}
+
+fn remove_file_if_exists(path: &Path) -> Result<()> {
+    match remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to delete {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn remove_dir_if_exists(path: &Path) -> Result<()> {
+    match remove_dir_all(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to delete {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Has the VM verify the pending artifacts against the compos.info signed when they were compiled,
+/// returning the problems found, if any.
+fn verify_in_vm(service: Strong<dyn ICompOsService>) -> Result<Vec<String>> {
+    let pending_dir = Path::new(ODREFRESH_OUTPUT_ROOT_DIR).join(PENDING_ARTIFACTS_SUBDIR);
+    let info =
+        fs::read(pending_dir.join(COMPOS_INFO_FILE)).context("Failed to read compos.info")?;
+    let signature = fs::read(pending_dir.join(COMPOS_INFO_SIGNATURE_FILE))
+        .context("Failed to read compos.info signature")?;
+
+    let mut problems = Vec::new();
+    let mut digests = Vec::new();
+    measure_artifacts(&pending_dir, &pending_dir, &mut digests, &mut problems)?;
+
+    let verification =
+        service.verifyArtifacts(&info, &signature, &digests).context("Verifying artifacts")?;
+    if !verification.signatureValid {
+        problems.push("compos.info isn't signed by the CompOS instance".to_owned());
+    }
+    for path in verification.mismatchedArtifacts {
+        problems.push(format!("{path} is missing or doesn't match compos.info"));
+    }
+    for path in verification.unlistedArtifacts {
+        problems.push(format!("{path} isn't listed in compos.info"));
+    }
+    Ok(problems)
+}
+
+/// Measures the fs-verity digests of the artifacts under `dir`, recursively, naming them by the
+/// path they have in compos.info, i.e. once they are moved to the current artifacts directory.
+/// Artifacts without fs-verity enabled are reported as problems, as odsign would reject them.
+fn measure_artifacts(
+    pending_dir: &Path,
+    dir: &Path,
+    digests: &mut Vec<ArtifactDigest>,
+    problems: &mut Vec<String>,
+) -> Result<()> {
+    let current_dir = Path::new(ODREFRESH_OUTPUT_ROOT_DIR).join(CURRENT_ARTIFACTS_SUBDIR);
+    for entry in read_dir(dir).with_context(|| format!("Traversing {}", dir.display()))? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            measure_artifacts(pending_dir, &path, digests, problems)?;
+            continue;
+        }
+        if dir == pending_dir
+            && [COMPOS_INFO_FILE, COMPOS_INFO_SIGNATURE_FILE]
+                .iter()
+                .any(|name| entry.file_name() == *name)
+        {
+            continue;
+        }
+        let info_path = current_dir.join(path.strip_prefix(pending_dir)?);
+        let info_path = info_path.to_str().context("Invalid artifact path")?.to_owned();
+        let file = File::open(&path).with_context(|| format!("Failed to open {:?}", path))?;
+        match fsverity::measure(file.as_fd()) {
+            Ok(digest) => digests.push(ArtifactDigest { path: info_path, digest: digest.to_vec() }),
+            Err(e) => problems.push(format!("{info_path} has no fs-verity digest: {e}")),
+        }
+    }
+    Ok(())
+}
+
+fn delete_pending_artifacts() -> Result<()> {
+    let pending_dir = Path::new(ODREFRESH_OUTPUT_ROOT_DIR).join(PENDING_ARTIFACTS_SUBDIR);
+    remove_dir_all(&pending_dir)
+        .with_context(|| format!("Failed to delete {}", pending_dir.display()))
+}
+
 /// Enable fs-verity to output artifacts according to compos.info in the pending directory. Any
 /// error before the completion will just abort, leaving the previous files enabled.
 fn enable_fsverity_to_all() -> Result<()> {
     let odrefresh_current_dir = Path::new(ODREFRESH_OUTPUT_ROOT_DIR).join(CURRENT_ARTIFACTS_SUBDIR);
     let pending_dir = Path::new(ODREFRESH_OUTPUT_ROOT_DIR).join(PENDING_ARTIFACTS_SUBDIR);
     let mut reader =
-        File::open(pending_dir.join("compos.info")).context("Failed to open compos.info")?;
+        File::open(pending_dir.join(COMPOS_INFO_FILE)).context("Failed to open compos.info")?;
     let compos_info = OdsignInfo::parse_from_reader(&mut reader).context("Failed to parse")?;
 
     for path_str in compos_info.file_hashes.keys() {
@@ -247,6 +721,48 @@ fn enable_fsverity_to_all() -> Result<()> {
     Ok(())
 }
 
+/// Enables fs-verity to the files under `dir`, recursively.
+fn enable_fsverity_recursively(dir: &Path) -> Result<()> {
+    for entry in read_dir(dir).with_context(|| format!("Traversing {}", dir.display()))? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            enable_fsverity_recursively(&path)?;
+            continue;
+        }
+        let file = File::open(&path).with_context(|| format!("Failed to open {:?}", path))?;
+        fsverity::enable(file.as_fd())
+            .with_context(|| format!("Failed to enable fs-verity to {:?}", path))?;
+    }
+    Ok(())
+}
+
+/// Returns the number of files under `dir`, recursively, or 0 if it doesn't exist (yet).
+fn count_files(dir: &Path) -> usize {
+    let Ok(entries) = read_dir(dir) else { return 0 };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => count_files(&entry.path()),
+            Ok(_) => 1,
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// Returns the total size of the files under `dir`, recursively, or 0 if it doesn't exist.
+fn total_file_size(dir: &Path) -> u64 {
+    let Ok(entries) = read_dir(dir) else { return 0 };
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => total_file_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
 /// Returns an `OwnedFD` of the directory.
 fn open_dir(path: &Path) -> Result<OwnedFd> {
     Ok(OwnedFd::from(
@@ -257,3 +773,97 @@ fn open_dir(path: &Path) -> Result<OwnedFd> {
             .with_context(|| format!("Failed to open {:?} directory as path fd", path))?,
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn total_file_size_counts_nested_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("boot.art"), [0; 100])?;
+        fs::create_dir_all(dir.path().join("arm64/oat"))?;
+        fs::write(dir.path().join("arm64/oat/services.odex"), [0; 20])?;
+        fs::write(dir.path().join("arm64/services.vdex"), [])?;
+        assert_eq!(total_file_size(dir.path()), 120);
+        assert_eq!(count_files(dir.path()), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn total_file_size_of_missing_dir_is_zero() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        assert_eq!(total_file_size(&dir.path().join("missing")), 0);
+        Ok(())
+    }
+
+    /// Leaves the artifacts and the checkpoint of the VM which died in `root`.
+    fn write_checkpoint(root: &Path) -> Result<()> {
+        fs::create_dir_all(root.join("compos-pending/arm64"))?;
+        fs::write(root.join("compos-pending/arm64/boot.art"), "boot")?;
+        fs::write(root.join("compos-pending.checkpoint"), "info")?;
+        fs::write(root.join("compos-pending.checkpoint.signature"), "signature")?;
+        Ok(())
+    }
+
+    fn assert_checkpoint_removed(root: &Path) {
+        assert!(!root.join("compos-pending").exists());
+        assert!(!root.join("compos-pending.checkpoint").exists());
+        assert!(!root.join("compos-pending.checkpoint.signature").exists());
+    }
+
+    #[test]
+    fn prepare_output_deletes_earlier_run() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        write_checkpoint(root.path())?;
+        assert!(prepare_output(root.path(), "compos-pending", false)?.is_none());
+        assert_checkpoint_removed(root.path());
+        Ok(())
+    }
+
+    #[test]
+    fn prepare_output_saves_checkpoint_when_resuming() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        write_checkpoint(root.path())?;
+        let saved =
+            prepare_output(root.path(), "compos-pending", true)?.context("No checkpoint")?;
+        assert_eq!(saved.dir, root.path().join("compos-pending.resumed"));
+        assert_eq!(saved.info, b"info");
+        assert_eq!(saved.signature, b"signature");
+        assert_eq!(fs::read(saved.dir.join("arm64/boot.art"))?, b"boot");
+        assert_checkpoint_removed(root.path());
+        Ok(())
+    }
+
+    #[test]
+    fn prepare_output_ignores_unsigned_checkpoint() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        write_checkpoint(root.path())?;
+        fs::remove_file(root.path().join("compos-pending.checkpoint.signature"))?;
+        assert!(prepare_output(root.path(), "compos-pending", true)?.is_none());
+        assert_checkpoint_removed(root.path());
+        assert!(!root.path().join("compos-pending.resumed").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn prepare_output_deletes_stale_resumed_artifacts() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        fs::create_dir(root.path().join("compos-pending.resumed"))?;
+        fs::write(root.path().join("compos-pending.resumed/boot.art"), "stale")?;
+        assert!(prepare_output(root.path(), "compos-pending", true)?.is_none());
+        assert!(!root.path().join("compos-pending.resumed").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn remove_checkpoint_keeps_artifacts() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        write_checkpoint(root.path())?;
+        remove_checkpoint(root.path(), "compos-pending");
+        assert!(!root.path().join("compos-pending.checkpoint").exists());
+        assert!(!root.path().join("compos-pending.checkpoint.signature").exists());
+        assert!(root.path().join("compos-pending/arm64/boot.art").exists());
+        Ok(())
+    }
+}
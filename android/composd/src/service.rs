@@ -18,8 +18,11 @@
 //! desired.
 
 use crate::instance_manager::InstanceManager;
-use crate::odrefresh_task::OdrefreshTask;
+use crate::instance_starter::CompOsInstance;
+use crate::metrics::{TaskKind, TaskMetrics};
+use crate::odrefresh_task::{ActiveTasks, OdrefreshTask};
 use android_system_composd::aidl::android::system::composd::{
+    CompilationTaskInfo::CompilationTaskInfo,
     ICompilationTask::{BnCompilationTask, ICompilationTask},
     ICompilationTaskCallback::ICompilationTaskCallback,
     IIsolatedCompilationService::{
@@ -27,21 +30,26 @@ use android_system_composd::aidl::android::system::composd::{
     },
 };
 use anyhow::{Context, Result};
-use binder::{self, BinderFeatures, ExceptionCode, Interface, Status, Strong, ThreadState};
+use binder::{
+    self, BinderFeatures, ExceptionCode, Interface, IntoBinderResult, Status, Strong, ThreadState,
+};
 use compos_aidl_interface::aidl::com::android::compos::ICompOsService::CompilationMode::CompilationMode;
 use compos_common::binder::to_binder_result;
 use compos_common::odrefresh::{PENDING_ARTIFACTS_SUBDIR, TEST_ARTIFACTS_SUBDIR};
 use rustutils::{users::AID_ROOT, users::AID_SYSTEM};
 use std::sync::Arc;
+use std::time::Instant;
 
 pub struct IsolatedCompilationService {
     instance_manager: Arc<InstanceManager>,
+    active_tasks: ActiveTasks,
 }
 
 pub fn new_binder(
     instance_manager: Arc<InstanceManager>,
 ) -> Strong<dyn IIsolatedCompilationService> {
-    let service = IsolatedCompilationService { instance_manager };
+    let service =
+        IsolatedCompilationService { instance_manager, active_tasks: ActiveTasks::default() };
     BnIsolatedCompilationService::new_binder(service, BinderFeatures::default())
 }
 
@@ -69,6 +77,28 @@ impl IIsolatedCompilationService for IsolatedCompilationService {
         };
         to_binder_result(self.do_start_test_compile(prefer_staged, callback))
     }
+
+    fn startPendingArtifactVerification(
+        &self,
+        delete_if_invalid: bool,
+        callback: &Strong<dyn ICompilationTaskCallback>,
+    ) -> binder::Result<Strong<dyn ICompilationTask>> {
+        check_permissions()?;
+        to_binder_result(self.do_start_pending_artifact_verification(delete_if_invalid, callback))
+    }
+
+    fn cancelCompilation(&self, task_id: i32) -> binder::Result<CompilationTaskInfo> {
+        check_permissions()?;
+        self.active_tasks
+            .cancel(task_id)
+            .with_context(|| format!("No active compilation task {task_id}"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)
+    }
+
+    fn listActiveTasks(&self) -> binder::Result<Vec<CompilationTaskInfo>> {
+        check_permissions()?;
+        Ok(self.active_tasks.list())
+    }
 }
 
 impl IsolatedCompilationService {
@@ -76,14 +106,18 @@ impl IsolatedCompilationService {
         &self,
         callback: &Strong<dyn ICompilationTaskCallback>,
     ) -> Result<Strong<dyn ICompilationTask>> {
-        let comp_os = self.instance_manager.start_current_instance().context("Starting CompOS")?;
+        let comp_os =
+            start_instance(TaskKind::Compile, || self.instance_manager.start_current_instance())?;
 
         let target_dir_name = PENDING_ARTIFACTS_SUBDIR.to_owned();
+        let instance_manager = self.instance_manager.clone();
         let task = OdrefreshTask::start(
             comp_os,
             CompilationMode::NORMAL_COMPILE,
             target_dir_name,
             callback,
+            &self.active_tasks,
+            move || instance_manager.restart_current_instance(),
         )?;
 
         Ok(BnCompilationTask::new_binder(task, BinderFeatures::default()))
@@ -94,19 +128,55 @@ impl IsolatedCompilationService {
         prefer_staged: bool,
         callback: &Strong<dyn ICompilationTaskCallback>,
     ) -> Result<Strong<dyn ICompilationTask>> {
-        let comp_os =
-            self.instance_manager.start_test_instance(prefer_staged).context("Starting CompOS")?;
+        let comp_os = start_instance(TaskKind::TestCompile, || {
+            self.instance_manager.start_test_instance(prefer_staged)
+        })?;
 
         let target_dir_name = TEST_ARTIFACTS_SUBDIR.to_owned();
+        let instance_manager = self.instance_manager.clone();
         let task = OdrefreshTask::start(
             comp_os,
             CompilationMode::TEST_COMPILE,
             target_dir_name,
             callback,
+            &self.active_tasks,
+            move || instance_manager.restart_test_instance(prefer_staged),
         )?;
 
         Ok(BnCompilationTask::new_binder(task, BinderFeatures::default()))
     }
+
+    fn do_start_pending_artifact_verification(
+        &self,
+        delete_if_invalid: bool,
+        callback: &Strong<dyn ICompilationTaskCallback>,
+    ) -> Result<Strong<dyn ICompilationTask>> {
+        let comp_os = start_instance(TaskKind::Verification, || {
+            self.instance_manager.start_existing_current_instance()
+        })?;
+
+        let task = OdrefreshTask::start_verification(
+            comp_os,
+            delete_if_invalid,
+            callback,
+            &self.active_tasks,
+        )?;
+
+        Ok(BnCompilationTask::new_binder(task, BinderFeatures::default()))
+    }
+}
+
+/// Starts the CompOS instance for a task of the given kind, recording the failure if it can't.
+fn start_instance(
+    kind: TaskKind,
+    start: impl FnOnce() -> Result<CompOsInstance>,
+) -> Result<CompOsInstance> {
+    let start_time = Instant::now();
+    let instance = start();
+    if instance.is_err() {
+        TaskMetrics::failed_to_start(kind, start_time.elapsed()).write();
+    }
+    instance.context("Starting CompOS")
 }
 
 fn check_permissions() -> binder::Result<()> {
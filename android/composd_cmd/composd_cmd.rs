@@ -18,6 +18,7 @@
 
 use android_system_composd::{
     aidl::android::system::composd::{
+        CompilationTaskInfo::CompilationTaskInfo,
         ICompilationTask::ICompilationTask,
         ICompilationTaskCallback::{
             BnCompilationTaskCallback, FailureReason::FailureReason, ICompilationTaskCallback,
@@ -47,6 +48,22 @@ enum Actions {
         #[clap(long)]
         prefer_staged: bool,
     },
+
+    /// Verify the pending artifacts of a staged APEX compilation, in the existing CompOS instance.
+    VerifyPendingArtifacts {
+        /// Delete the pending artifacts if they are invalid.
+        #[clap(long)]
+        delete_if_invalid: bool,
+    },
+
+    /// List the compilation tasks which haven't ended yet.
+    ListTasks {},
+
+    /// Cancel the compilation task with the given ID.
+    Cancel {
+        /// ID of the task, as listed by list-tasks.
+        task_id: i32,
+    },
 }
 
 fn main() -> Result<()> {
@@ -57,6 +74,11 @@ fn main() -> Result<()> {
     match action {
         Actions::StagedApexCompile {} => run_staged_apex_compile()?,
         Actions::TestCompile { prefer_staged } => run_test_compile(prefer_staged)?,
+        Actions::VerifyPendingArtifacts { delete_if_invalid } => {
+            run_verify_pending_artifacts(delete_if_invalid)?
+        }
+        Actions::ListTasks {} => list_tasks()?,
+        Actions::Cancel { task_id } => cancel_task(task_id)?,
     }
 
     println!("All Ok!");
@@ -121,6 +143,46 @@ fn run_test_compile(prefer_staged: bool) -> Result<()> {
     run_async_compilation(|service, callback| service.startTestCompile(apex_source, callback))
 }
 
+fn run_verify_pending_artifacts(delete_if_invalid: bool) -> Result<()> {
+    run_async_compilation(|service, callback| {
+        service.startPendingArtifactVerification(delete_if_invalid, callback)
+    })
+}
+
+fn list_tasks() -> Result<()> {
+    let service = connect_service()?;
+    for info in service.listActiveTasks().context("Failed to list tasks")? {
+        print_task_info(&info);
+    }
+    Ok(())
+}
+
+fn cancel_task(task_id: i32) -> Result<()> {
+    let service = connect_service()?;
+    let info = service.cancelCompilation(task_id).context("Failed to cancel task")?;
+    print_task_info(&info);
+    Ok(())
+}
+
+fn print_task_info(info: &CompilationTaskInfo) {
+    let kind = if info.isVerification {
+        "pending artifact verification"
+    } else if info.isTestCompile {
+        "test compilation"
+    } else {
+        "staged APEX compilation"
+    };
+    println!(
+        "Task {}: {}, running for {} ms, {} artifacts written",
+        info.taskId, kind, info.elapsedMillis, info.artifactCount
+    );
+}
+
+fn connect_service() -> Result<Strong<dyn IIsolatedCompilationService>> {
+    wait_for_interface::<dyn IIsolatedCompilationService>("android.system.composd")
+        .context("Failed to connect to composd service")
+}
+
 fn run_async_compilation<F>(start_compile_fn: F) -> Result<()>
 where
     F: FnOnce(
@@ -134,8 +196,7 @@ where
         bail!("Device doesn't support protected or non-protected VMs")
     }
 
-    let service = wait_for_interface::<dyn IIsolatedCompilationService>("android.system.composd")
-        .context("Failed to connect to composd service")?;
+    let service = connect_service()?;
 
     let state = Arc::new(State::default());
     let callback = Callback(state.clone());
@@ -15,7 +15,7 @@
  */
 
 use anyhow::Result;
-use log::error;
+use log::{error, warn};
 use nix::{
     errno::Errno, fcntl::openat, fcntl::OFlag, sys::stat::fchmod, sys::stat::mkdirat,
     sys::stat::mode_t, sys::stat::Mode, sys::statvfs::statvfs, sys::statvfs::Statvfs,
@@ -24,15 +24,18 @@ use nix::{
 use std::cmp::min;
 use std::collections::{btree_map, BTreeMap};
 use std::convert::TryInto;
-use std::fs::File;
+use std::ffi::CStr;
+use std::fs::{self, File};
 use std::io;
 use std::os::unix::fs::FileExt;
-use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd};
 use std::path::{Component, Path, PathBuf, MAIN_SEPARATOR};
-use std::sync::{Arc, RwLock};
+use std::ptr::NonNull;
+use std::sync::{Arc, Mutex, RwLock};
 
 use authfs_aidl_interface::aidl::com::android::virt::fs::IVirtFdService::{
-    BnVirtFdService, FsStat::FsStat, IVirtFdService, MAX_REQUESTING_DATA,
+    BnVirtFdService, DirectoryEntry::DirectoryEntry, DirectoryPage::DirectoryPage, FsStat::FsStat,
+    IVirtFdService, MAX_REQUESTING_DATA, MAX_REQUESTING_DIRECTORY_ENTRIES,
 };
 use authfs_fsverity_metadata::{
     get_fsverity_metadata_path, parse_fsverity_metadata, FSVerityMetadata,
@@ -60,11 +63,20 @@ pub enum FdConfig {
     /// regular file and does not have any specific property.
     ReadWrite(File),
 
+    /// A writable file which can only be appended to, e.g. a log. Data already written can't be
+    /// overwritten or truncated. The lock serializes the appends, so that two of them can't both
+    /// be checked against the same end of the file.
+    AppendOnly(Mutex<File>),
+
     /// A read-only directory to serve by this server.
     InputDir(OwnedFd),
 
     /// A writable directory to serve by this server.
     OutputDir(OwnedFd),
+
+    /// A directory in which new files and directories can be created, but existing entries can't
+    /// be opened, truncated or deleted.
+    CreateOnlyDir(OwnedFd),
 }
 
 pub struct FdService {
@@ -122,14 +134,20 @@ impl IVirtFdService for FdService {
         let size: usize = validate_and_cast_size(size)?;
         let offset: u64 = validate_and_cast_offset(offset)?;
 
-        self.handle_fd(id, |config| match config {
-            FdConfig::Readonly { file, .. } | FdConfig::ReadWrite(file) => {
-                read_into_buf(file, size, offset).map_err(|e| {
-                    error!("readFile: read error: {}", e);
-                    new_errno_error(Errno::EIO)
-                })
-            }
-            FdConfig::InputDir(_) | FdConfig::OutputDir(_) => Err(new_errno_error(Errno::EISDIR)),
+        self.handle_fd(id, |config| {
+            let result = match config {
+                FdConfig::Readonly { file, .. } | FdConfig::ReadWrite(file) => {
+                    read_into_buf(file, size, offset)
+                }
+                FdConfig::AppendOnly(file) => read_into_buf(&file.lock().unwrap(), size, offset),
+                FdConfig::InputDir(_) | FdConfig::OutputDir(_) | FdConfig::CreateOnlyDir(_) => {
+                    return Err(new_errno_error(Errno::EISDIR))
+                }
+            };
+            result.map_err(|e| {
+                error!("readFile: read error: {}", e);
+                new_errno_error(Errno::EIO)
+            })
         })
     }
 
@@ -156,13 +174,15 @@ impl IVirtFdService for FdService {
                 buf.truncate(s);
                 Ok(buf)
             }
-            FdConfig::ReadWrite(_file) => {
+            FdConfig::ReadWrite(_file) | FdConfig::AppendOnly(_file) => {
                 // For a writable file, Merkle tree is not expected to be served since Auth FS
                 // doesn't trust it anyway. Auth FS may keep the Merkle tree privately for its own
                 // use.
                 Err(new_errno_error(Errno::ENOSYS))
             }
-            FdConfig::InputDir(_) | FdConfig::OutputDir(_) => Err(new_errno_error(Errno::EISDIR)),
+            FdConfig::InputDir(_) | FdConfig::OutputDir(_) | FdConfig::CreateOnlyDir(_) => {
+                Err(new_errno_error(Errno::EISDIR))
+            }
         })
     }
 
@@ -189,11 +209,13 @@ impl IVirtFdService for FdService {
                     Ok(buf)
                 }
             }
-            FdConfig::ReadWrite(_file) => {
+            FdConfig::ReadWrite(_file) | FdConfig::AppendOnly(_file) => {
                 // There is no signature for a writable file.
                 Err(new_errno_error(Errno::ENOSYS))
             }
-            FdConfig::InputDir(_) | FdConfig::OutputDir(_) => Err(new_errno_error(Errno::EISDIR)),
+            FdConfig::InputDir(_) | FdConfig::OutputDir(_) | FdConfig::CreateOnlyDir(_) => {
+                Err(new_errno_error(Errno::EISDIR))
+            }
         })
     }
 
@@ -211,7 +233,24 @@ impl IVirtFdService for FdService {
                     new_errno_error(Errno::EIO)
                 })? as i32)
             }
-            FdConfig::InputDir(_) | FdConfig::OutputDir(_) => Err(new_errno_error(Errno::EISDIR)),
+            FdConfig::AppendOnly(file) => {
+                let offset: u64 = offset.try_into().map_err(|_| new_errno_error(Errno::EINVAL))?;
+                if buf.len() > i32::MAX as usize {
+                    return Err(new_errno_error(Errno::EOVERFLOW));
+                }
+                let file = file.lock().unwrap();
+                // Only allow writing at the end of the file, so that written data is kept.
+                if offset != file_size(&file)? {
+                    return Err(new_errno_error(Errno::EPERM));
+                }
+                Ok(file.write_at(buf, offset).map_err(|e| {
+                    error!("writeFile: write error: {}", e);
+                    new_errno_error(Errno::EIO)
+                })? as i32)
+            }
+            FdConfig::InputDir(_) | FdConfig::OutputDir(_) | FdConfig::CreateOnlyDir(_) => {
+                Err(new_errno_error(Errno::EISDIR))
+            }
         })
     }
 
@@ -227,7 +266,23 @@ impl IVirtFdService for FdService {
                     new_errno_error(Errno::EIO)
                 })
             }
-            FdConfig::InputDir(_) | FdConfig::OutputDir(_) => Err(new_errno_error(Errno::EISDIR)),
+            FdConfig::AppendOnly(file) => {
+                if size < 0 {
+                    return Err(new_errno_error(Errno::EINVAL));
+                }
+                let file = file.lock().unwrap();
+                // Growing the file is like appending zeros, but shrinking it would drop data.
+                if (size as u64) < file_size(&file)? {
+                    return Err(new_errno_error(Errno::EPERM));
+                }
+                file.set_len(size as u64).map_err(|e| {
+                    error!("resize: set_len error: {}", e);
+                    new_errno_error(Errno::EIO)
+                })
+            }
+            FdConfig::InputDir(_) | FdConfig::OutputDir(_) | FdConfig::CreateOnlyDir(_) => {
+                Err(new_errno_error(Errno::EISDIR))
+            }
         })
     }
 
@@ -246,13 +301,15 @@ impl IVirtFdService for FdService {
                     new_errno_error(Errno::EFBIG)
                 })?)
             }
-            FdConfig::ReadWrite(_file) => {
+            FdConfig::ReadWrite(_file) | FdConfig::AppendOnly(_file) => {
                 // Content and metadata of a writable file needs to be tracked by authfs, since
                 // fd_server isn't considered trusted. So there is no point to support getFileSize
                 // for a writable file.
                 Err(new_errno_error(Errno::ENOSYS))
             }
-            FdConfig::InputDir(_) | FdConfig::OutputDir(_) => Err(new_errno_error(Errno::EISDIR)),
+            FdConfig::InputDir(_) | FdConfig::OutputDir(_) | FdConfig::CreateOnlyDir(_) => {
+                Err(new_errno_error(Errno::EISDIR))
+            }
         })
     }
 
@@ -277,6 +334,38 @@ impl IVirtFdService for FdService {
             FdConfig::OutputDir(_) => {
                 Err(new_errno_error(Errno::ENOSYS)) // TODO: Implement when needed
             }
+            FdConfig::CreateOnlyDir(_) => Err(new_errno_error(Errno::EACCES)),
+            _ => Err(new_errno_error(Errno::ENOTDIR)),
+        })
+    }
+
+    fn readDirectory(
+        &self,
+        dir_fd: i32,
+        pathname: &str,
+        offset: i64,
+        max_entries: i32,
+    ) -> BinderResult<DirectoryPage> {
+        let path_buf = PathBuf::from(pathname);
+        // Checks if the path is a simple, related path.
+        if path_buf.components().any(|c| !matches!(c, Component::Normal(_))) {
+            return Err(new_errno_error(Errno::EINVAL));
+        }
+        let offset: libc::c_long = offset
+            .try_into()
+            .ok()
+            .filter(|offset| *offset >= 0)
+            .ok_or_else(|| new_errno_error(Errno::EINVAL))?;
+        let max_entries = validate_and_cast_max_entries(max_entries)?;
+
+        self.handle_fd(dir_fd, |config| match config {
+            FdConfig::InputDir(dir) | FdConfig::OutputDir(dir) => {
+                read_dir_page(dir.as_fd(), &path_buf, offset, max_entries).map_err(|e| {
+                    error!("readDirectory: read error: {}", e);
+                    new_errno_error(Errno::EIO)
+                })
+            }
+            FdConfig::CreateOnlyDir(_) => Err(new_errno_error(Errno::EACCES)),
             _ => Err(new_errno_error(Errno::ENOTDIR)),
         })
     }
@@ -303,6 +392,20 @@ impl IVirtFdService for FdService {
                 let new_file = unsafe { File::from_raw_fd(new_fd) };
                 Ok((new_fd, FdConfig::ReadWrite(new_file)))
             }
+            FdConfig::CreateOnlyDir(dir) => {
+                let mode = validate_file_mode(mode)?;
+                // Unlike in an output directory, an existing file must not be truncated.
+                let new_fd = openat(
+                    Some(dir.as_raw_fd()),
+                    basename,
+                    OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_RDWR,
+                    mode,
+                )
+                .map_err(new_errno_error)?;
+                // SAFETY: new_fd is just created and not an error.
+                let new_file = unsafe { File::from_raw_fd(new_fd) };
+                Ok((new_fd, FdConfig::ReadWrite(new_file)))
+            }
             _ => Err(new_errno_error(Errno::ENOTDIR)),
         })
     }
@@ -317,7 +420,7 @@ impl IVirtFdService for FdService {
 
         self.insert_new_fd(dir_fd, |config| match config {
             FdConfig::InputDir(_) => Err(new_errno_error(Errno::EACCES)),
-            FdConfig::OutputDir(_) => {
+            FdConfig::OutputDir(_) | FdConfig::CreateOnlyDir(_) => {
                 let mode = validate_file_mode(mode)?;
                 mkdirat(Some(dir_fd), basename, mode).map_err(new_errno_error)?;
                 let new_dir_fd = openat(
@@ -329,7 +432,13 @@ impl IVirtFdService for FdService {
                 .map_err(new_errno_error)?;
                 // SAFETY: new_dir_fd is just created and not an error.
                 let fd_owner = unsafe { OwnedFd::from_raw_fd(new_dir_fd) };
-                Ok((new_dir_fd, FdConfig::OutputDir(fd_owner)))
+                // A subdirectory inherits the restrictions of its parent.
+                let new_config = if matches!(config, FdConfig::CreateOnlyDir(_)) {
+                    FdConfig::CreateOnlyDir(fd_owner)
+                } else {
+                    FdConfig::OutputDir(fd_owner)
+                };
+                Ok((new_dir_fd, new_config))
             }
             _ => Err(new_errno_error(Errno::ENOTDIR)),
         })
@@ -344,7 +453,9 @@ impl IVirtFdService for FdService {
                     .map_err(new_errno_error)?;
                 Ok(())
             }
-            FdConfig::InputDir(_) => Err(new_errno_error(Errno::EACCES)),
+            FdConfig::InputDir(_) | FdConfig::CreateOnlyDir(_) => {
+                Err(new_errno_error(Errno::EACCES))
+            }
             _ => Err(new_errno_error(Errno::ENOTDIR)),
         })
     }
@@ -358,7 +469,9 @@ impl IVirtFdService for FdService {
                     .map_err(new_errno_error)?;
                 Ok(())
             }
-            FdConfig::InputDir(_) => Err(new_errno_error(Errno::EACCES)),
+            FdConfig::InputDir(_) | FdConfig::CreateOnlyDir(_) => {
+                Err(new_errno_error(Errno::EACCES))
+            }
             _ => Err(new_errno_error(Errno::ENOTDIR)),
         })
     }
@@ -402,6 +515,100 @@ fn read_into_buf(file: &File, max_size: usize, offset: u64) -> io::Result<Vec<u8
     Ok(buf)
 }
 
+/// A directory stream, closed on drop.
+struct DirStream(NonNull<libc::DIR>);
+
+impl DirStream {
+    fn open(dir: BorrowedFd, path: &Path) -> io::Result<Self> {
+        let path = if path.as_os_str().is_empty() { Path::new(".") } else { path };
+        // The directory is opened again rather than duplicated, as duplicates share the position
+        // of the stream.
+        let fd = openat(
+            Some(dir.as_raw_fd()),
+            path,
+            OFlag::O_DIRECTORY | OFlag::O_RDONLY,
+            Mode::empty(),
+        )?;
+        // SAFETY: fd is just opened successfully and not owned.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        // SAFETY: fd is a valid directory FD, which the stream owns if it is created.
+        let stream = NonNull::new(unsafe { libc::fdopendir(fd.as_raw_fd()) })
+            .ok_or_else(io::Error::last_os_error)?;
+        let _ = fd.into_raw_fd();
+        Ok(Self(stream))
+    }
+}
+
+impl Drop for DirStream {
+    fn drop(&mut self) {
+        // SAFETY: The stream is valid, and not used after this.
+        unsafe { libc::closedir(self.0.as_ptr()) };
+    }
+}
+
+/// Lists up to `max_entries` entries of the directory at `path` under `dir`, from the position
+/// `offset` of its stream. The position is a cookie of the filesystem rather than an index, so
+/// that the entries before it don't need to be read again, and entries added or removed between
+/// pages don't shift the others.
+fn read_dir_page(
+    dir: BorrowedFd,
+    path: &Path,
+    offset: libc::c_long,
+    max_entries: usize,
+) -> io::Result<DirectoryPage> {
+    let stream = DirStream::open(dir, path)?;
+    // SAFETY: The stream is valid, and the offset is only a position to read it from.
+    unsafe { libc::seekdir(stream.0.as_ptr(), offset) };
+
+    let mut entries = Vec::new();
+    while entries.len() < max_entries {
+        // readdir returns null both at the end and on error, which are told apart by errno.
+        Errno::clear();
+        // SAFETY: The stream is valid.
+        let entry = unsafe { libc::readdir(stream.0.as_ptr()) };
+        if entry.is_null() {
+            return match Errno::last() {
+                Errno::UnknownErrno => Ok(DirectoryPage { entries, nextOffset: 0, end: true }),
+                errno => Err(errno.into()),
+            };
+        }
+        // SAFETY: The entry is valid until the stream is read again, and its name is
+        // NUL-terminated.
+        let (name, d_type) = unsafe { (CStr::from_ptr((*entry).d_name.as_ptr()), (*entry).d_type) };
+        let Ok(name) = name.to_str() else {
+            // Skipped without ending the page early, which would look like the end.
+            warn!("readDirectory: skipping non UTF-8 name {:?}", name);
+            continue;
+        };
+        if name == "." || name == ".." {
+            continue;
+        }
+        let is_directory = match d_type {
+            libc::DT_DIR => true,
+            libc::DT_UNKNOWN => {
+                // SAFETY: The stream is valid.
+                let stream_fd = unsafe { libc::dirfd(stream.0.as_ptr()) };
+                fs::symlink_metadata(format!("/proc/self/fd/{stream_fd}/{name}"))?.is_dir()
+            }
+            _ => false,
+        };
+        entries.push(DirectoryEntry { name: name.to_owned(), isDirectory: is_directory });
+    }
+    // SAFETY: The stream is valid.
+    let next_offset = unsafe { libc::telldir(stream.0.as_ptr()) };
+    Ok(DirectoryPage { entries, nextOffset: next_offset.into(), end: false })
+}
+
+fn file_size(file: &File) -> BinderResult<u64> {
+    Ok(file
+        .metadata()
+        .map_err(|e| {
+            error!("Failed to get the file size: {}", e);
+            new_errno_error(Errno::EIO)
+        })?
+        .len())
+}
+
 fn new_errno_error(errno: Errno) -> Status {
     Status::new_service_specific_error_str(errno as i32, Some(errno.desc()))
 }
@@ -425,6 +632,14 @@ fn validate_and_cast_size(size: i32) -> Result<usize, Status> {
     }
 }
 
+fn validate_and_cast_max_entries(max_entries: i32) -> Result<usize, Status> {
+    if max_entries > MAX_REQUESTING_DIRECTORY_ENTRIES {
+        Err(new_errno_error(Errno::EFBIG))
+    } else {
+        max_entries.try_into().map_err(|_| new_errno_error(Errno::EINVAL))
+    }
+}
+
 fn validate_basename(name: &str) -> BinderResult<()> {
     if name.contains(MAIN_SEPARATOR) {
         Err(new_errno_error(Errno::EINVAL))
@@ -441,3 +656,230 @@ fn validate_file_mode(mode: i32) -> BinderResult<Mode> {
         Ok(mode)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn service(configs: Vec<(i32, FdConfig)>) -> FdService {
+        FdService { fd_pool: Arc::new(RwLock::new(configs.into_iter().collect())) }
+    }
+
+    fn errno<T: std::fmt::Debug>(result: BinderResult<T>) -> i32 {
+        result.unwrap_err().service_specific_error()
+    }
+
+    fn append_only_service() -> Result<(FdService, i32)> {
+        let file = tempfile::tempfile()?;
+        let fd = file.as_raw_fd();
+        Ok((service(vec![(fd, FdConfig::AppendOnly(Mutex::new(file)))]), fd))
+    }
+
+    fn create_only_dir_service() -> Result<(tempfile::TempDir, FdService, i32)> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("existing"), "data")?;
+        fs::create_dir(dir.path().join("existing_dir"))?;
+        let dir_fd: OwnedFd = File::open(dir.path())?.into();
+        let fd = dir_fd.as_raw_fd();
+        Ok((dir, service(vec![(fd, FdConfig::CreateOnlyDir(dir_fd))]), fd))
+    }
+
+    fn input_dir_service() -> Result<(tempfile::TempDir, FdService, i32)> {
+        let dir = tempfile::tempdir()?;
+        let dir_fd: OwnedFd = File::open(dir.path())?.into();
+        let fd = dir_fd.as_raw_fd();
+        Ok((dir, service(vec![(fd, FdConfig::InputDir(dir_fd))]), fd))
+    }
+
+    /// Lists the directory page by page, checking that only the last page may be short.
+    fn list(
+        service: &FdService,
+        fd: i32,
+        path: &str,
+        max_entries: i32,
+    ) -> Result<Vec<(String, bool)>> {
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        loop {
+            let page = service.readDirectory(fd, path, offset, max_entries)?;
+            entries.extend(page.entries.iter().map(|e| (e.name.clone(), e.isDirectory)));
+            if page.end {
+                return Ok(entries);
+            }
+            assert_eq!(page.entries.len(), max_entries as usize);
+            offset = page.nextOffset;
+        }
+    }
+
+    #[test]
+    fn read_directory_lists_all_entries_by_page() -> Result<()> {
+        let (dir, service, fd) = input_dir_service()?;
+        for i in 0..10 {
+            fs::write(dir.path().join(format!("file{i}")), "")?;
+        }
+        fs::create_dir(dir.path().join("subdir"))?;
+
+        let mut entries = list(&service, fd, "", 3)?;
+        entries.sort();
+        let mut expected: Vec<_> = (0..10).map(|i| (format!("file{i}"), false)).collect();
+        expected.push(("subdir".to_owned(), true));
+        assert_eq!(entries, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn read_directory_skips_non_utf8_names_without_ending() -> Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let (dir, service, fd) = input_dir_service()?;
+        for i in 0..5 {
+            let name = [b'x', 0xff, b'0' + i];
+            fs::write(dir.path().join(std::ffi::OsStr::from_bytes(&name)), "")?;
+        }
+        fs::write(dir.path().join("valid"), "")?;
+
+        // Pages are only short at the end, even if the entries read for them were all skipped.
+        assert_eq!(list(&service, fd, "", 1)?, [("valid".to_owned(), false)]);
+        Ok(())
+    }
+
+    #[test]
+    fn read_directory_continues_after_changes() -> Result<()> {
+        let (dir, service, fd) = input_dir_service()?;
+        for i in 0..10 {
+            fs::write(dir.path().join(format!("file{i}")), "")?;
+        }
+
+        let first_page = service.readDirectory(fd, "", 0, 5)?;
+        assert!(!first_page.end);
+        // Deleting the listed entries doesn't make the next page skip any of the others.
+        for entry in &first_page.entries {
+            fs::remove_file(dir.path().join(&entry.name))?;
+        }
+        let mut names: Vec<_> = first_page.entries.into_iter().map(|e| e.name).collect();
+        let mut offset = first_page.nextOffset;
+        loop {
+            let page = service.readDirectory(fd, "", offset, 5)?;
+            names.extend(page.entries.into_iter().map(|e| e.name));
+            if page.end {
+                break;
+            }
+            offset = page.nextOffset;
+        }
+        names.sort();
+        assert_eq!(names, (0..10).map(|i| format!("file{i}")).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn read_directory_lists_subdirectories() -> Result<()> {
+        let (dir, service, fd) = input_dir_service()?;
+        fs::create_dir_all(dir.path().join("a/b"))?;
+        fs::write(dir.path().join("a/b/file"), "")?;
+
+        assert_eq!(list(&service, fd, "a/b", 10)?, [("file".to_owned(), false)]);
+        assert_eq!(errno(service.readDirectory(fd, "../a", 0, 10)), Errno::EINVAL as i32);
+        assert_eq!(errno(service.readDirectory(fd, "", -1, 10)), Errno::EINVAL as i32);
+        assert!(service.readDirectory(fd, "missing", 0, 10).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn append_only_accepts_writes_at_end_only() -> Result<()> {
+        let (service, fd) = append_only_service()?;
+        assert_eq!(service.writeFile(fd, b"hello", 0)?, 5);
+        assert_eq!(errno(service.writeFile(fd, b"HELLO", 0)), Errno::EPERM as i32);
+        assert_eq!(errno(service.writeFile(fd, b"!", 6)), Errno::EPERM as i32);
+        assert_eq!(service.writeFile(fd, b" world", 5)?, 6);
+        assert_eq!(service.readFile(fd, 0, 100)?, b"hello world");
+        Ok(())
+    }
+
+    #[test]
+    fn append_only_can_grow_but_not_shrink() -> Result<()> {
+        let (service, fd) = append_only_service()?;
+        service.writeFile(fd, b"hello", 0)?;
+        assert_eq!(errno(service.resize(fd, 4)), Errno::EPERM as i32);
+        service.resize(fd, 8)?;
+        assert_eq!(service.readFile(fd, 0, 100)?, b"hello\0\0\0");
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_appends_keep_all_data() -> Result<()> {
+        const THREADS: usize = 8;
+        const APPENDS: usize = 50;
+        let (service, fd) = append_only_service()?;
+        let service = Arc::new(service);
+        let threads: Vec<_> = (0..THREADS)
+            .map(|i| {
+                let service = service.clone();
+                thread::spawn(move || {
+                    let chunk = [b'a' + i as u8; 16];
+                    for _ in 0..APPENDS {
+                        // Retry at the new end when another thread appended in between.
+                        loop {
+                            let size = service.handle_fd(fd, |config| match config {
+                                FdConfig::AppendOnly(file) => file_size(&file.lock().unwrap()),
+                                _ => unreachable!(),
+                            });
+                            if service.writeFile(fd, &chunk, size.unwrap() as i64).is_ok() {
+                                break;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut content = Vec::new();
+        while let Ok(buf) = service.readFile(fd, content.len() as i64, MAX_REQUESTING_DATA) {
+            if buf.is_empty() {
+                break;
+            }
+            content.extend(buf);
+        }
+        assert_eq!(content.len(), THREADS * APPENDS * 16);
+        for chunk in content.chunks(16) {
+            assert!(chunk.iter().all(|b| *b == chunk[0]), "Interleaved appends: {chunk:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn create_only_dir_creates_new_entries() -> Result<()> {
+        let (dir, service, fd) = create_only_dir_service()?;
+        let file_fd = service.createFileInDirectory(fd, "new", 0o600)?;
+        service.writeFile(file_fd, b"data", 0)?;
+        assert_eq!(fs::read(dir.path().join("new"))?, b"data");
+
+        let subdir_fd = service.createDirectoryInDirectory(fd, "new_dir", 0o700)?;
+        assert!(dir.path().join("new_dir").is_dir());
+        // The restrictions apply to the new subdirectory too.
+        assert_eq!(errno(service.readDirectory(subdir_fd, "", 0, 10)), Errno::EACCES as i32);
+        service.createFileInDirectory(subdir_fd, "new", 0o600)?;
+        assert_eq!(errno(service.deleteFile(subdir_fd, "new")), Errno::EACCES as i32);
+        Ok(())
+    }
+
+    #[test]
+    fn create_only_dir_protects_existing_entries() -> Result<()> {
+        let (dir, service, fd) = create_only_dir_service()?;
+        assert_eq!(
+            errno(service.createFileInDirectory(fd, "existing", 0o600)),
+            Errno::EEXIST as i32
+        );
+        assert_eq!(errno(service.openFileInDirectory(fd, "existing")), Errno::EACCES as i32);
+        assert_eq!(errno(service.deleteFile(fd, "existing")), Errno::EACCES as i32);
+        assert_eq!(errno(service.deleteDirectory(fd, "existing_dir")), Errno::EACCES as i32);
+        assert_eq!(errno(service.chmod(fd, 0o777)), Errno::EACCES as i32);
+        assert_eq!(errno(service.readDirectory(fd, "", 0, 10)), Errno::EACCES as i32);
+        assert_eq!(fs::read(dir.path().join("existing"))?, b"data");
+        assert!(dir.path().join("existing_dir").is_dir());
+        Ok(())
+    }
+}
@@ -33,6 +33,7 @@ use rustutils::inherited_fd::take_fd_ownership;
 use std::collections::BTreeMap;
 use std::fs::File;
 use std::os::unix::io::OwnedFd;
+use std::sync::Mutex;
 
 use aidl::{FdConfig, FdService};
 use authfs_fsverity_metadata::parse_fsverity_metadata;
@@ -71,6 +72,10 @@ struct Args {
     #[clap(long)]
     rw_fds: Vec<i32>,
 
+    /// Append-only FD of file, e.g. for logs
+    #[clap(long)]
+    append_fds: Vec<i32>,
+
     /// Read-only FD of directory
     #[clap(long)]
     ro_dirs: Vec<i32>,
@@ -79,11 +84,24 @@ struct Args {
     #[clap(long)]
     rw_dirs: Vec<i32>,
 
+    /// FD of directory where new entries can be created, but existing ones can't be modified
+    #[clap(long)]
+    create_only_dirs: Vec<i32>,
+
     /// A pipe FD for signaling the other end once ready
     #[clap(long)]
     ready_fd: Option<i32>,
 }
 
+/// Takes the ownership of a writable file, which authfs expects to start empty.
+fn take_empty_file(fd: i32) -> Result<File> {
+    let file: File = take_fd_ownership(fd)?.into();
+    if file.metadata()?.len() > 0 {
+        bail!("File is expected to be empty");
+    }
+    Ok(file)
+}
+
 /// Convert argument strings and integers to a form that is easier to use and handles ownership.
 fn convert_args(args: Args) -> Result<(BTreeMap<i32, FdConfig>, Option<OwnedFd>)> {
     let mut fd_pool = BTreeMap::new();
@@ -92,11 +110,10 @@ fn convert_args(args: Args) -> Result<(BTreeMap<i32, FdConfig>, Option<OwnedFd>)
         fd_pool.insert(fd, config);
     }
     for fd in args.rw_fds {
-        let file: File = take_fd_ownership(fd)?.into();
-        if file.metadata()?.len() > 0 {
-            bail!("File is expected to be empty");
-        }
-        fd_pool.insert(fd, FdConfig::ReadWrite(file));
+        fd_pool.insert(fd, FdConfig::ReadWrite(take_empty_file(fd)?));
+    }
+    for fd in args.append_fds {
+        fd_pool.insert(fd, FdConfig::AppendOnly(Mutex::new(take_empty_file(fd)?)));
     }
     for fd in args.ro_dirs {
         fd_pool.insert(fd, FdConfig::InputDir(take_fd_ownership(fd)?));
@@ -104,6 +121,9 @@ fn convert_args(args: Args) -> Result<(BTreeMap<i32, FdConfig>, Option<OwnedFd>)
     for fd in args.rw_dirs {
         fd_pool.insert(fd, FdConfig::OutputDir(take_fd_ownership(fd)?));
     }
+    for fd in args.create_only_dirs {
+        fd_pool.insert(fd, FdConfig::CreateOnlyDir(take_fd_ownership(fd)?));
+    }
     let ready_fd = args.ready_fd.map(take_fd_ownership).transpose()?;
     Ok((fd_pool, ready_fd))
 }
@@ -15,38 +15,81 @@
 //! Implementation of the AIDL interface of the VirtualizationService.
 
 use crate::{get_calling_pid, get_calling_uid, get_this_pid};
+use crate::arch::Arch;
 use crate::atom::{write_vm_booted_stats, write_vm_creation_stats};
-use crate::composite::make_composite_image;
-use crate::crosvm::{AudioConfig, CrosvmConfig, DiskFile, DisplayConfig, GpuConfig, InputDeviceOption, PayloadState, UsbConfig, VmContext, VmInstance, VmState};
-use crate::debug_config::DebugConfig;
-use crate::dt_overlay::{create_device_tree_overlay, VM_DT_OVERLAY_MAX_SIZE, VM_DT_OVERLAY_PATH};
+use crate::composite::{
+    check_direct_io_allowed, check_pmem_allowed, get_raw_image_size, make_composite_image,
+};
+use crate::console_capture::ConsoleCapture;
+use crate::cpu_mitigations::cpu_mitigations_prop;
+use crate::crosvm::{swiotlb_size_mib, AudioConfig, CrosvmConfig, DiskFile, DisplayConfig, GpuConfig, InputDeviceOption, PayloadExit, PayloadState, PmemDisk, SharedDirectory, UsbConfig, VhostUserDevice, VmContext, VmInstance, VmState};
+use crate::debug_config::{check_kernel_cmdline_param, is_adb_requested, DebugConfig};
+use crate::deferred_start;
+use crate::deprecation::check_deprecations;
+use crate::dt_overlay::{
+    create_device_tree_overlay, read_vendor_dt_overlay, VM_DT_OVERLAY_MAX_SIZE, VM_DT_OVERLAY_PATH,
+};
+use crate::host_properties::HostPropertyFilter;
+use crate::labels::{check_labels, format_labels};
+use crate::persistent_vm;
 use crate::payload::{add_microdroid_payload_images, add_microdroid_system_images, add_microdroid_vendor_image};
-use crate::selinux::{getfilecon, SeContext};
+use crate::port_forwarding::parse_port_forwarding_rules;
+use crate::selinux::{getcon, getfilecon, SeContext};
+use crate::shutdown;
+use crate::storage_growth::StorageGrowth;
+use crate::storage_snapshot::{StorageSnapshots, STORAGE_SNAPSHOTS_DIRECTORY};
+use crate::vm_pool::{VmPool, WarmVmKey, WARM_VM_TIMEOUT};
+use crate::vsock_firewall::VsockFirewall;
 use android_os_permissions_aidl::aidl::android::os::IPermissionController;
 use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::{
     Certificate::Certificate,
     DeathReason::DeathReason,
     ErrorCode::ErrorCode,
+    IShutdownCallback::IShutdownCallback,
+    ISnapshotCallback::ISnapshotCallback,
 };
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
     AssignableDevice::AssignableDevice,
     CpuTopology::CpuTopology,
     DiskImage::DiskImage,
     InputDevice::InputDevice,
+    DebugConfig::DebugConfig as DebugConfigParcelable,
+    DebugFacility::DebugFacility,
+    DeferredStartStatus::DeferredStartStatus,
+    DeprecationWarning::DeprecationWarning,
+    GuestCrashReason::GuestCrashReason,
+    GuestPanicPolicy::GuestPanicPolicy,
     IVirtualMachine::{BnVirtualMachine, IVirtualMachine},
     IVirtualMachineCallback::IVirtualMachineCallback,
-    IVirtualizationService::IVirtualizationService,
+    IVirtualizationService::{
+        IVirtualizationService, ERROR_INVALID_CONFIG, ERROR_UNSUPPORTED_FEATURE,
+    },
+    NetworkFirewall::NetworkFirewall,
     Partition::Partition,
     PartitionType::PartitionType,
+    PmemDisk::PmemDisk as PmemDiskParcelable,
+    SharedDirectory::SharedDirectory as SharedDirectoryParcelable,
     VirtualMachineAppConfig::{DebugLevel::DebugLevel, Payload::Payload, VirtualMachineAppConfig},
     VirtualMachineConfig::VirtualMachineConfig,
+    VirtualMachineConfigOverrides::VirtualMachineConfigOverrides,
     VirtualMachineDebugInfo::VirtualMachineDebugInfo,
+    VirtualMachineResourceUsage::VirtualMachineResourceUsage,
     VirtualMachinePayloadConfig::VirtualMachinePayloadConfig,
     VirtualMachineRawConfig::VirtualMachineRawConfig,
     VirtualMachineState::VirtualMachineState,
+    VmEvent::VmEvent,
+    VmEventType::VmEventType,
+    VmInstanceInfo::VmInstanceInfo,
+    VmLabel::VmLabel,
+    VmOwnerInfo::VmOwnerInfo,
+    VmQosClass::VmQosClass,
 };
 use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IGlobalVmContext::IGlobalVmContext;
 use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IVirtualizationServiceInternal::IVirtualizationServiceInternal;
+use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IVmMemoryReclaimer::IVmMemoryReclaimer;
+use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IVmShutdownHandler::IVmShutdownHandler;
+use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::VhostUserConnection::VhostUserConnection;
+use android_system_virtualmachineservice::aidl::android::system::virtualmachineservice::HostCaCertificate::HostCaCertificate;
 use android_system_virtualmachineservice::aidl::android::system::virtualmachineservice::IVirtualMachineService::{
         BnVirtualMachineService, IVirtualMachineService,
 };
@@ -58,7 +101,7 @@ use android_hardware_security_authgraph::aidl::android::hardware::security::auth
     Key::Key, PubKey::PubKey, SessionIdSignature::SessionIdSignature, SessionInfo::SessionInfo,
     SessionInitiationInfo::SessionInitiationInfo,
 };
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use apkverify::{HashAlgorithm, V4Signature};
 use avflog::LogResult;
 use binder::{
@@ -87,7 +130,9 @@ use std::ops::Range;
 use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
 use std::os::unix::raw::pid_t;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex, Weak, LazyLock};
+use std::time::{Duration, Instant, SystemTime};
 use vbmeta::VbMetaImage;
 use vmconfig::{VmConfig, get_debug_level};
 use vsock::VsockStream;
@@ -120,6 +165,11 @@ const PARTITION_GRANULARITY_BYTES: u64 = 4096;
 
 const VM_REFERENCE_DT_ON_HOST_PATH: &str = "/proc/device-tree/avf/reference";
 
+/// Locations of the system CA store of the host, in order of preference. The conscrypt APEX
+/// carries the updatable copy.
+const HOST_CA_CERTIFICATES_DIRS: [&str; 2] =
+    ["/apex/com.android.conscrypt/cacerts", "/system/etc/security/cacerts"];
+
 pub static GLOBAL_SERVICE: LazyLock<Strong<dyn IVirtualizationServiceInternal>> =
     LazyLock::new(|| {
         if cfg!(early) {
@@ -176,7 +226,12 @@ fn get_current_sdk() -> Result<u32> {
 
 pub fn remove_temporary_files(path: &PathBuf) -> Result<()> {
     for dir_entry in read_dir(path)? {
-        remove_file(dir_entry?.path())?;
+        let path = dir_entry?.path();
+        // Storage snapshots outlive the VM, so that its owner can still roll back to them.
+        if path.ends_with(STORAGE_SNAPSHOTS_DIRECTORY) {
+            continue;
+        }
+        remove_file(path)?;
     }
     Ok(())
 }
@@ -200,12 +255,17 @@ impl Interface for VirtualizationService {
             writeln!(writer, "\tPayload state {:?}", vm.payload_state())
                 .or(Err(StatusCode::UNKNOWN_ERROR))?;
             writeln!(writer, "\tProtected: {}", vm.protected).or(Err(StatusCode::UNKNOWN_ERROR))?;
+            writeln!(writer, "\tLabels: {}", format_labels(&vm.labels))
+                .or(Err(StatusCode::UNKNOWN_ERROR))?;
             writeln!(writer, "\ttemporary_directory: {}", vm.temporary_directory.to_string_lossy())
                 .or(Err(StatusCode::UNKNOWN_ERROR))?;
             writeln!(writer, "\trequester_uid: {}", vm.requester_uid)
                 .or(Err(StatusCode::UNKNOWN_ERROR))?;
             writeln!(writer, "\trequester_debug_pid: {}", vm.requester_debug_pid)
                 .or(Err(StatusCode::UNKNOWN_ERROR))?;
+            if let VmState::Running { port_forwarder, .. } = &*vm.vm_state.lock().unwrap() {
+                port_forwarder.dump(writer).or(Err(StatusCode::UNKNOWN_ERROR))?;
+            }
         }
         Ok(())
     }
@@ -222,21 +282,93 @@ impl IVirtualizationService for VirtualizationService {
         console_in_fd: Option<&ParcelFileDescriptor>,
         log_fd: Option<&ParcelFileDescriptor>,
     ) -> binder::Result<Strong<dyn IVirtualMachine>> {
-        let mut is_protected = false;
-        let ret = self.create_vm_internal(
-            config,
+        if let Some(vm) = self.claim_warm_vm(config, console_out_fd, console_in_fd, log_fd)? {
+            return Ok(vm);
+        }
+        self.create_vm(config, None, console_out_fd, console_in_fd, log_fd)
+    }
+
+    /// Creates (but does not start) a new VM from the given JSON config file, with some of its
+    /// fields overridden.
+    fn createVmFromConfigFile(
+        &self,
+        config_fd: &ParcelFileDescriptor,
+        overrides: &VirtualMachineConfigOverrides,
+        console_out_fd: Option<&ParcelFileDescriptor>,
+        console_in_fd: Option<&ParcelFileDescriptor>,
+        log_fd: Option<&ParcelFileDescriptor>,
+    ) -> binder::Result<Strong<dyn IVirtualMachine>> {
+        // Checked before opening the files named by the config, rather than by createVm.
+        check_use_custom_virtual_machine()?;
+
+        let config = load_config_file(&clone_file(config_fd)?, overrides)
+            .context("Invalid config file")
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        // VMs created from config files have no debug level of their own.
+        let debug_level =
+            (overrides.debugLevel != DebugLevel::NONE).then_some(overrides.debugLevel);
+        self.create_vm(
+            &VirtualMachineConfig::RawConfig(config),
+            debug_level,
             console_out_fd,
             console_in_fd,
             log_fd,
-            &mut is_protected,
-        );
-        write_vm_creation_stats(config, is_protected, &ret);
-        ret
+        )
+    }
+
+    /// Boots a Microdroid VM ahead of time, and keeps it in the warm pool until it is claimed by
+    /// `createVm`.
+    fn warmUpVm(
+        &self,
+        config: &VirtualMachineConfig,
+        console_out_fd: Option<&ParcelFileDescriptor>,
+        console_in_fd: Option<&ParcelFileDescriptor>,
+        log_fd: Option<&ParcelFileDescriptor>,
+    ) -> binder::Result<()> {
+        let VirtualMachineConfig::AppConfig(app_config) = config else {
+            return Err(anyhow!("Only Microdroid VMs can be warmed up"))
+                .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+        };
+        check_no_extra_disks(app_config)?;
+        let key = WarmVmKey::new(get_calling_uid(), app_config)
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        if self.state.lock().unwrap().vm_pool.contains(&key) {
+            return Err(anyhow!("VM {:?} is already warm", app_config.name))
+                .or_binder_exception(ExceptionCode::ILLEGAL_STATE);
+        }
+
+        let vm = self.createVm(config, console_out_fd, console_in_fd, log_fd)?;
+        let cid = vm.getCid()?;
+        let instance = self.state.lock().unwrap().get_vm(cid as Cid).unwrap();
+        instance.payload_hold.hold();
+        vm.start()?;
+        self.state.lock().unwrap().vm_pool.add(key.clone(), cid, vm);
+        info!("Warmed up VM {:?} with CID {cid}", app_config.name);
+
+        let state = Arc::downgrade(&self.state);
+        std::thread::spawn(move || {
+            std::thread::sleep(WARM_VM_TIMEOUT);
+            let Some(state) = state.upgrade() else { return };
+            // Dropping the last reference to the VM stops it, which mustn't be done while holding
+            // the lock.
+            let vm = state.lock().unwrap().vm_pool.expire(&key, cid);
+            if vm.is_some() {
+                info!("Stopping warm VM with CID {cid}, which wasn't claimed in time");
+            }
+        });
+        Ok(())
+    }
+
+    /// Gets a running VM of the caller by its persistent name.
+    fn lookupVm(&self, name: &str) -> binder::Result<Option<Strong<dyn IVirtualMachine>>> {
+        check_manage_access()?;
+        persistent_vm::lookup(name)
     }
 
     /// Allocate a new instance_id to the VM
     fn allocateInstanceId(&self) -> binder::Result<[u8; 64]> {
         check_manage_access()?;
+        check_not_early("Instance ID management")?;
         GLOBAL_SERVICE.allocateInstanceId()
     }
 
@@ -298,12 +430,41 @@ impl IVirtualizationService for VirtualizationService {
     /// and as such is only permitted from the shell user.
     fn debugListVms(&self) -> binder::Result<Vec<VirtualMachineDebugInfo>> {
         // Delegate to the global service, including checking the debug permission.
+        check_not_early("Listing VMs")?;
         GLOBAL_SERVICE.debugListVms()
     }
 
+    /// Get a list of the currently running VMs which have all the given labels. This method is
+    /// only intended for debug purposes, and as such is only permitted from the shell user.
+    fn debugListVmsWithLabels(
+        &self,
+        selector: &[VmLabel],
+    ) -> binder::Result<Vec<VirtualMachineDebugInfo>> {
+        // Delegate to the global service, including checking the debug permission.
+        check_not_early("Listing VMs")?;
+        GLOBAL_SERVICE.debugListVmsWithLabels(selector)
+    }
+
+    /// Get the resources used by all the currently running VMs. This method is only intended for
+    /// debug purposes, and as such is only permitted from the shell user.
+    fn debugListVmResourceUsage(&self) -> binder::Result<Vec<VirtualMachineResourceUsage>> {
+        // Delegate to the global service, including checking the debug permission.
+        check_not_early("Listing VMs")?;
+        GLOBAL_SERVICE.debugListVmResourceUsage()
+    }
+
+    /// Shut down the running VMs which have all the given labels. This method is only intended
+    /// for debug purposes, and as such is only permitted from the shell user.
+    fn debugShutdownVmsWithLabels(&self, selector: &[VmLabel]) -> binder::Result<Vec<i32>> {
+        // Delegate to the global service, including checking the debug permission.
+        check_not_early("Shutting VMs down by label")?;
+        GLOBAL_SERVICE.debugShutdownVmsWithLabels(selector)
+    }
+
     /// Get a list of assignable device types.
     fn getAssignableDevices(&self) -> binder::Result<Vec<AssignableDevice>> {
         // Delegate to the global service, including checking the permission.
+        check_not_early("Device assignment")?;
         GLOBAL_SERVICE.getAssignableDevices()
     }
 
@@ -319,11 +480,15 @@ impl IVirtualizationService for VirtualizationService {
     }
 
     fn enableTestAttestation(&self) -> binder::Result<()> {
+        check_not_early("Remote attestation")?;
         GLOBAL_SERVICE.enableTestAttestation()
     }
 
     fn isRemoteAttestationSupported(&self) -> binder::Result<bool> {
         check_manage_access()?;
+        if cfg!(early) {
+            return Ok(false);
+        }
         GLOBAL_SERVICE.isRemoteAttestationSupported()
     }
 
@@ -337,12 +502,61 @@ impl IVirtualizationService for VirtualizationService {
 
     fn removeVmInstance(&self, instance_id: &[u8; 64]) -> binder::Result<()> {
         check_manage_access()?;
+        check_not_early("Instance ID management")?;
         GLOBAL_SERVICE.removeVmInstance(instance_id)
     }
 
-    fn claimVmInstance(&self, instance_id: &[u8; 64]) -> binder::Result<()> {
+    fn claimVmInstance(
+        &self,
+        instance_id: &[u8; 64],
+        transfer_token: Option<&[u8]>,
+    ) -> binder::Result<()> {
+        check_manage_access()?;
+        check_not_early("Instance ID management")?;
+        GLOBAL_SERVICE.claimVmInstance(instance_id, transfer_token)
+    }
+
+    fn allowVmInstanceTransfer(&self, instance_id: &[u8; 64]) -> binder::Result<Vec<u8>> {
+        check_manage_access()?;
+        GLOBAL_SERVICE.allowVmInstanceTransfer(instance_id)
+    }
+
+    fn reserveCidForInstance(&self, instance_id: &[u8; 64], count: i32) -> binder::Result<i32> {
+        check_manage_access()?;
+        check_use_custom_virtual_machine()?;
+        check_not_early("CID reservation")?;
+        GLOBAL_SERVICE.reserveCidForInstance(instance_id, count)
+    }
+
+    fn releaseCidForInstance(&self, instance_id: &[u8; 64]) -> binder::Result<()> {
+        check_manage_access()?;
+        check_use_custom_virtual_machine()?;
+        check_not_early("CID reservation")?;
+        GLOBAL_SERVICE.releaseCidForInstance(instance_id)
+    }
+
+    fn getCidForInstance(&self, instance_id: &[u8; 64]) -> binder::Result<i32> {
+        check_manage_access()?;
+        check_not_early("CID reservation")?;
+        GLOBAL_SERVICE.getCidForInstance(instance_id)
+    }
+
+    fn getVmEventLog(&self, instance_id: &[u8; 64]) -> binder::Result<Vec<VmEvent>> {
+        // Delegate to the global service, including checking the ownership of the instance.
+        check_not_early("The event log")?;
+        GLOBAL_SERVICE.getVmEventLog(instance_id)
+    }
+
+    fn listVmInstances(&self) -> binder::Result<Vec<VmInstanceInfo>> {
         check_manage_access()?;
-        GLOBAL_SERVICE.claimVmInstance(instance_id)
+        check_not_early("Instance ID management")?;
+        GLOBAL_SERVICE.listVmInstances(get_calling_uid() as i32)
+    }
+
+    fn getVmOwnerInfo(&self, cid: i32) -> binder::Result<VmOwnerInfo> {
+        // Delegate to the global service, including checking the permissions.
+        check_not_early("VM owner lookup")?;
+        GLOBAL_SERVICE.getVmOwnerInfo(cid)
     }
 }
 
@@ -391,6 +605,91 @@ impl IGlobalVmContext for EarlyVmContext {
             Some("Early VM doesn't support setting host console name"),
         ))
     }
+
+    fn setName(&self, _name: &str) -> binder::Result<()> {
+        // Early VMs aren't listed for debugging, so their name isn't needed.
+        Ok(())
+    }
+
+    fn setLabels(&self, _labels: &[VmLabel]) -> binder::Result<()> {
+        // Nor are their labels.
+        Ok(())
+    }
+
+    fn setDebuggable(&self, _debuggable: bool) -> binder::Result<()> {
+        // Early VMs aren't tracked by virtualizationservice, so their console isn't opened by it.
+        Ok(())
+    }
+
+    fn setProtected(&self, _protected: bool) -> binder::Result<()> {
+        // Nor are their owners looked up through it.
+        Ok(())
+    }
+
+    fn setAllowedHostVsockPorts(&self, _ports: &[i32]) -> binder::Result<()> {
+        // Host services can't look early VMs up, as for their owners.
+        Ok(())
+    }
+
+    fn setPersistentVm(
+        &self,
+        _name: &str,
+        _vm: &Strong<dyn IVirtualMachine>,
+    ) -> binder::Result<()> {
+        Err(Status::new_exception_str(
+            ExceptionCode::UNSUPPORTED_OPERATION,
+            Some("Early VM doesn't support persistent names"),
+        ))
+    }
+
+    fn clearPersistentVm(&self) -> binder::Result<()> {
+        Ok(())
+    }
+
+    fn setShutdownHandler(&self, _handler: &Strong<dyn IVmShutdownHandler>) -> binder::Result<()> {
+        // Early VMs aren't tracked by virtualizationservice, so their owner shuts them down.
+        Ok(())
+    }
+
+    fn setMemory(&self, _memory_mib: i32) -> binder::Result<()> {
+        // Early VMs aren't tracked by virtualizationservice, so their memory isn't capped.
+        Ok(())
+    }
+
+    fn setMemoryReclaimer(
+        &self,
+        _reclaimer: &Strong<dyn IVmMemoryReclaimer>,
+        _qos_class: VmQosClass,
+    ) -> binder::Result<()> {
+        // Early VMs aren't tracked by virtualizationservice, so their memory is never reclaimed.
+        Ok(())
+    }
+
+    fn connectVhostUserBackend(&self, _name: &str) -> binder::Result<VhostUserConnection> {
+        Err(Status::new_exception_str(
+            ExceptionCode::UNSUPPORTED_OPERATION,
+            Some("Early VM doesn't support vhost-user devices"),
+        ))
+    }
+
+    fn recordEvent(&self, _event_type: VmEventType, _detail: &str) -> binder::Result<()> {
+        // Early VMs aren't tracked by virtualizationservice, so their events are only logged.
+        Ok(())
+    }
+}
+
+fn get_config_name(config: &VirtualMachineConfig) -> &str {
+    match config {
+        VirtualMachineConfig::RawConfig(config) => &config.name,
+        VirtualMachineConfig::AppConfig(config) => &config.name,
+    }
+}
+
+fn get_config_labels(config: &VirtualMachineConfig) -> &[VmLabel] {
+    match config {
+        VirtualMachineConfig::RawConfig(config) => &config.labels,
+        VirtualMachineConfig::AppConfig(config) => &config.labels,
+    }
 }
 
 fn find_partition(path: &Path) -> binder::Result<String> {
@@ -408,6 +707,40 @@ impl VirtualizationService {
         VirtualizationService::default()
     }
 
+    /// Claims the warm VM for the config, if there is one. The config is checked like for a new
+    /// VM first. The console and log of a warm VM were set up when it was warmed up, so it isn't
+    /// claimed if the caller passes its own.
+    fn claim_warm_vm(
+        &self,
+        config: &VirtualMachineConfig,
+        console_out_fd: Option<&ParcelFileDescriptor>,
+        console_in_fd: Option<&ParcelFileDescriptor>,
+        log_fd: Option<&ParcelFileDescriptor>,
+    ) -> binder::Result<Option<Strong<dyn IVirtualMachine>>> {
+        let VirtualMachineConfig::AppConfig(app_config) = config else {
+            return Ok(None);
+        };
+        if console_out_fd.is_some() || console_in_fd.is_some() || log_fd.is_some() {
+            return Ok(None);
+        }
+        if check_no_extra_disks(app_config).is_err() {
+            return Ok(None);
+        }
+        let Ok(key) = WarmVmKey::new(get_calling_uid(), app_config) else {
+            return Ok(None);
+        };
+        if !self.state.lock().unwrap().vm_pool.contains(&key) {
+            return Ok(None);
+        }
+        check_manage_access()?;
+        check_config(config)?;
+        let vm = self.state.lock().unwrap().vm_pool.claim(&key);
+        if vm.is_some() {
+            info!("Claimed warm VM {:?}", app_config.name);
+        }
+        Ok(vm)
+    }
+
     fn create_early_vm_context(
         &self,
         config: &VirtualMachineConfig,
@@ -418,10 +751,7 @@ impl VirtualizationService {
             .or_service_specific_exception(-1)?;
         let partition = find_partition(&link)?;
 
-        let name = match config {
-            VirtualMachineConfig::RawConfig(config) => &config.name,
-            VirtualMachineConfig::AppConfig(config) => &config.name,
-        };
+        let name = get_config_name(config);
         let early_vm =
             find_early_vm_for_partition(&partition, name).or_service_specific_exception(-1)?;
         if Path::new(&early_vm.path) != link {
@@ -453,11 +783,13 @@ impl VirtualizationService {
     fn create_vm_context(
         &self,
         requester_debug_pid: pid_t,
+        instance_id: &[u8; 64],
     ) -> binder::Result<(VmContext, Cid, PathBuf)> {
         const NUM_ATTEMPTS: usize = 5;
 
         for _ in 0..NUM_ATTEMPTS {
-            let vm_context = GLOBAL_SERVICE.allocateGlobalVmContext(requester_debug_pid)?;
+            let vm_context =
+                GLOBAL_SERVICE.allocateGlobalVmContext(requester_debug_pid, instance_id)?;
             let cid = vm_context.getCid()? as Cid;
             let temp_dir: PathBuf = vm_context.getTemporaryDirectory()?.into();
             let service = VirtualMachineService::new_binder(self.state.clone(), cid).as_binder();
@@ -478,9 +810,32 @@ impl VirtualizationService {
             .or_service_specific_exception(-1)
     }
 
+    /// Creates a new VM, with `debug_level` overriding the one of its config if set.
+    fn create_vm(
+        &self,
+        config: &VirtualMachineConfig,
+        debug_level: Option<DebugLevel>,
+        console_out_fd: Option<&ParcelFileDescriptor>,
+        console_in_fd: Option<&ParcelFileDescriptor>,
+        log_fd: Option<&ParcelFileDescriptor>,
+    ) -> binder::Result<Strong<dyn IVirtualMachine>> {
+        let mut is_protected = false;
+        let ret = self.create_vm_internal(
+            config,
+            debug_level,
+            console_out_fd,
+            console_in_fd,
+            log_fd,
+            &mut is_protected,
+        );
+        write_vm_creation_stats(config, is_protected, &ret);
+        ret.map_err(config_error)
+    }
+
     fn create_vm_internal(
         &self,
         config: &VirtualMachineConfig,
+        debug_level: Option<DebugLevel>,
         console_out_fd: Option<&ParcelFileDescriptor>,
         console_in_fd: Option<&ParcelFileDescriptor>,
         log_fd: Option<&ParcelFileDescriptor>,
@@ -489,34 +844,38 @@ impl VirtualizationService {
         let requester_uid = get_calling_uid();
         let requester_debug_pid = get_calling_pid();
 
-        check_config_features(config)?;
-
-        if cfg!(early) {
-            check_config_allowed_for_early_vms(config)?;
-        }
+        check_config(config)?;
+        let deprecation_warnings =
+            check_deprecations(config).or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        let labels = get_config_labels(config);
 
         // Allocating VM context checks the MANAGE_VIRTUAL_MACHINE permission.
         let (vm_context, cid, temporary_directory) = if cfg!(early) {
             self.create_early_vm_context(config)?
         } else {
-            self.create_vm_context(requester_debug_pid)?
+            self.create_vm_context(requester_debug_pid, &extract_instance_id(config))?
         };
-
-        if is_custom_config(config) {
-            check_use_custom_virtual_machine()?;
-        }
+        // Lets debugging tools find the VM by name.
+        vm_context.global_context.setName(get_config_name(config))?;
+        vm_context.global_context.setLabels(labels)?;
+        // Lets the owner open the console of the VM through the VM manager interface.
+        let debug_level = debug_level.or(get_debug_level(config)).unwrap_or(DebugLevel::NONE);
+        let debuggable = debug_level != DebugLevel::NONE;
+        vm_context.global_context.setDebuggable(debuggable)?;
+        // Lets host services look up whether the VM is protected by its CID. The function is
+        // shadowed by the out parameter here.
+        vm_context.global_context.setProtected(self::is_protected(config))?;
 
         let gdb_port = extract_gdb_port(config);
 
-        // Additional permission checks if caller request gdb.
-        if gdb_port.is_some() {
-            check_gdb_allowed(config)?;
-        }
-
         let device_tree_overlay = maybe_create_device_tree_overlay(config, &temporary_directory)?;
 
-        let debug_config = DebugConfig::new(config);
-        let ramdump = if !uses_gki_kernel(config) && debug_config.is_ramdump_needed() {
+        let mut debug_config = DebugConfig::new(config);
+        debug_config.debug_level = debug_level;
+        if uses_gki_kernel(config) {
+            debug_config.disable_ramdump();
+        }
+        let ramdump = if debug_config.is_ramdump_needed() {
             Some(prepare_ramdump_file(&temporary_directory)?)
         } else {
             None
@@ -527,6 +886,22 @@ impl VirtualizationService {
             clone_or_prepare_logger_fd(console_out_fd, format!("Console({})", cid))?;
         let console_in_fd = console_in_fd.map(clone_file).transpose()?;
         let log_fd = clone_or_prepare_logger_fd(log_fd, format!("Log({})", cid))?;
+        let storage_snapshots = match config {
+            VirtualMachineConfig::AppConfig(config) => {
+                prepare_storage_snapshots(config, &temporary_directory)
+                    .context("Failed to prepare storage snapshots")
+                    .with_log()
+                    .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?
+            }
+            VirtualMachineConfig::RawConfig(_) => None,
+        };
+        let storage_growth = match config {
+            VirtualMachineConfig::AppConfig(config) => prepare_storage_growth(config)
+                .context("Failed to prepare storage growth")
+                .with_log()
+                .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?,
+            VirtualMachineConfig::RawConfig(_) => None,
+        };
 
         // Counter to generate unique IDs for temporary image files.
         let mut next_temporary_image_id = 0;
@@ -672,6 +1047,47 @@ impl VirtualizationService {
             vec![]
         };
 
+        if *is_protected && !config.sharedDirectories.is_empty() {
+            // The files would be outside of the memory protected from the host.
+            return Err(anyhow!("Shared directories are not supported for protected VMs"))
+                .with_log()
+                .or_binder_exception(ExceptionCode::UNSUPPORTED_OPERATION);
+        }
+        let mut shared_directories = config
+            .sharedDirectories
+            .iter()
+            .map(SharedDirectory::new)
+            .collect::<Result<Vec<_>>>()
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        for shared_directory in &mut shared_directories {
+            check_label_for_shared_directory(shared_directory)
+                .or_binder_exception(ExceptionCode::SECURITY)?;
+            // crosvm serves the directory as is, so the host only rejects the writes of the guest
+            // if the directory is on a read-only mount.
+            if !shared_directory.writable {
+                let directory = shared_directory
+                    .directory
+                    .try_clone()
+                    .context("Failed to clone the shared directory")
+                    .or_service_specific_exception(-1)?;
+                let read_only =
+                    GLOBAL_SERVICE.openReadOnlyDirectory(&ParcelFileDescriptor::new(directory))?;
+                shared_directory.directory = clone_file(&read_only)?;
+            }
+        }
+
+        let pmem_disks = config
+            .pmemDisks
+            .iter()
+            .map(|pmem_disk| {
+                let image = clone_file(&pmem_disk.image)?;
+                check_pmem_allowed(&image)
+                    .context("Invalid pmem disk")
+                    .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+                Ok(PmemDisk { image, writable: pmem_disk.writable })
+            })
+            .collect::<binder::Result<Vec<_>>>()?;
+
         // Create TAP network interface if the VM supports network.
         let tap = if cfg!(network) && config.networkSupported {
             if *is_protected {
@@ -690,6 +1106,28 @@ impl VirtualizationService {
         } else {
             None
         };
+        // The firewall is in place before the VM can send anything. Until then, vmnic drops all
+        // the traffic beyond the host.
+        if let (Some(tap), Some(firewall)) = (&tap, &config.networkFirewall) {
+            set_tap_firewall(tap, firewall)?;
+        }
+
+        // Connect to the backends of the devices which host daemons emulate for the VM.
+        let vhost_user_devices = if cfg!(paravirtualized_devices) {
+            config
+                .vhostUserDevices
+                .iter()
+                .map(|name| {
+                    let connection = vm_context.global_context.connectVhostUserBackend(name)?;
+                    Ok(VhostUserDevice {
+                        device_type: connection.deviceType,
+                        socket: clone_file(&connection.socket)?,
+                    })
+                })
+                .collect::<binder::Result<Vec<_>>>()?
+        } else {
+            vec![]
+        };
 
         let audio_config = if cfg!(paravirtualized_devices) {
             config.audioConfig.as_ref().map(AudioConfig::new)
@@ -704,10 +1142,110 @@ impl VirtualizationService {
             .unwrap_or(Ok(UsbConfig { controller: false }))
             .or_binder_exception(ExceptionCode::BAD_PARCELABLE)?;
 
+        let port_forwarding_rules = parse_port_forwarding_rules(&config.portForwardingRules)
+            .context("Invalid port forwarding rules")
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        let vsock_firewall = match &config.vsockFirewall {
+            Some(raw_firewall) => Some(
+                VsockFirewall::new(raw_firewall)
+                    .context("Invalid vsock firewall")
+                    .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?,
+            ),
+            // Forwarding rules are an allow-list of their own, so only the forwarded ports are
+            // exposed, rather than every port the payload listens on.
+            None if !port_forwarding_rules.is_empty() => {
+                Some(VsockFirewall::for_forwarded_ports(&port_forwarding_rules))
+            }
+            None => None,
+        };
+        if let Some(firewall) = &vsock_firewall {
+            for rule in &port_forwarding_rules {
+                firewall
+                    .check_guest_port(rule.guest_port)
+                    .with_context(|| format!("Can't forward @{}", rule.host_socket_name))
+                    .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+            }
+            if let Some(ports) = firewall.allowed_host_ports() {
+                // Only enforced by virtualizationservice for its tombstone listener, and by the
+                // host services which check getVmOwnerInfo.
+                vm_context.global_context.setAllowedHostVsockPorts(&ports)?;
+            }
+        }
+        let exposed_host_properties =
+            HostPropertyFilter::new(&config.exposedHostPropertyPrefixes, requester_uid)
+                .context("Invalid exposed host properties")
+                .or_binder_exception(ExceptionCode::SECURITY)?;
+
+        let memory_mib = config
+            .memoryMib
+            .try_into()
+            .ok()
+            .and_then(NonZeroU32::new)
+            .unwrap_or(NonZeroU32::new(256).unwrap());
+        let max_memory_mib = match config.maxMemoryMib {
+            0 => None,
+            max_memory_mib => Some(
+                u32::try_from(max_memory_mib)
+                    .ok()
+                    .and_then(NonZeroU32::new)
+                    .with_context(|| format!("Invalid maxMemoryMib {max_memory_mib}"))
+                    .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?,
+            ),
+        };
+        let swiotlb_mib = match config.swiotlbMib {
+            0 => None,
+            swiotlb_mib => Some(
+                u32::try_from(swiotlb_mib)
+                    .ok()
+                    .and_then(NonZeroU32::new)
+                    .with_context(|| format!("Invalid swiotlbMib {swiotlb_mib}"))
+                    .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?,
+            ),
+        };
+        // Fails if the owner of the VM would exceed its memory cap. The VM may be grown up to its
+        // max memory without asking again, so that is what counts towards the cap, along with the
+        // bounce buffer which protected VMs are given on top of their memory.
+        let swiotlb_size_mib =
+            if *is_protected { swiotlb_size_mib(swiotlb_mib, disks.len()) } else { 0 };
+        let accounted_mib = max_memory_mib.unwrap_or(memory_mib).saturating_add(swiotlb_size_mib);
+        vm_context.global_context.setMemory(
+            accounted_mib
+                .get()
+                .try_into()
+                .with_context(|| format!("Invalid memory size {accounted_mib} MiB"))
+                .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?,
+        )?;
+
+        let boot_deadline = match config.bootDeadlineMs {
+            0 => None,
+            // Only the payloads of app VMs report when they are ready.
+            deadline_ms if !is_app_config => {
+                return Err(anyhow!("bootDeadlineMs {deadline_ms} is only supported for app VMs"))
+                    .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+            }
+            deadline_ms => Some(
+                u64::try_from(deadline_ms)
+                    .map(Duration::from_millis)
+                    .with_context(|| format!("Invalid bootDeadlineMs {deadline_ms}"))
+                    .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?,
+            ),
+        };
+        // The last console output is attached to the error reported if the deadline expires.
+        let (console_capture, console_out_fd) = if boot_deadline.is_some() {
+            let (capture, console_out_fd) = ConsoleCapture::tee(console_out_fd)
+                .context("Failed to capture console output")
+                .with_log()
+                .or_service_specific_exception(-1)?;
+            (Some(capture), Some(console_out_fd))
+        } else {
+            (None, console_out_fd)
+        };
+
         // Actually start the VM.
         let crosvm_config = CrosvmConfig {
             cid,
             name: config.name.clone(),
+            persistent_name: config.persistentName.clone(),
             bootloader: maybe_clone_file(&config.bootloader)?,
             kernel,
             initrd,
@@ -715,27 +1253,31 @@ impl VirtualizationService {
             params: config.params.to_owned(),
             protected: *is_protected,
             debug_config,
-            memory_mib: config
-                .memoryMib
-                .try_into()
-                .ok()
-                .and_then(NonZeroU32::new)
-                .unwrap_or(NonZeroU32::new(256).unwrap()),
+            memory_mib,
+            max_memory_mib,
+            swiotlb_mib,
+            qos_class: config.qosClass,
+            labels: config.labels.clone(),
             cpus,
             host_cpu_topology,
             console_out_fd,
+            console_capture,
             console_in_fd,
             log_fd,
             ramdump,
             indirect_files,
             platform_version: parse_platform_version_req(&config.platformVersion)?,
             detect_hangup: is_app_config,
+            boot_deadline,
             gdb_port,
             vfio_devices,
             dtbo,
             device_tree_overlay,
             display_config,
             input_device_options,
+            vhost_user_devices,
+            shared_directories,
+            pmem_disks,
             hugepages: config.hugePages,
             tap,
             console_input_device: config.consoleInputDevice.clone(),
@@ -744,6 +1286,14 @@ impl VirtualizationService {
             audio_config,
             no_balloon: config.noBalloon,
             usb_config,
+            port_forwarding_rules,
+            vsock_firewall,
+            exposed_host_properties,
+            storage_snapshots,
+            storage_growth,
+            vendor_domain: config.vendorDomain,
+            panic_policy: config.panicPolicy,
+            restore_snapshot: None,
         };
         let instance = Arc::new(
             VmInstance::new(
@@ -758,7 +1308,9 @@ impl VirtualizationService {
             .or_service_specific_exception(-1)?,
         );
         state.add_vm(Arc::downgrade(&instance));
-        Ok(VirtualMachine::create(instance))
+        shutdown::set_handler(&instance)?;
+        deferred_start::set_memory_reclaimer(&instance)?;
+        Ok(VirtualMachine::create(instance, deprecation_warnings))
     }
 }
 
@@ -818,6 +1370,21 @@ fn extract_vendor_hashtree_digest(config: &VirtualMachineConfig) -> Result<Optio
     Err(anyhow!("No hashtree digest is extracted from microdroid vendor image"))
 }
 
+/// Reads and validates the vendor DT overlays of a raw config.
+fn extract_vendor_dt_overlays(config: &VirtualMachineConfig) -> binder::Result<Vec<Vec<u8>>> {
+    let VirtualMachineConfig::RawConfig(config) = config else { return Ok(vec![]) };
+    config
+        .vendorDtOverlays
+        .iter()
+        .enumerate()
+        .map(|(i, fd)| {
+            read_vendor_dt_overlay(clone_file(fd)?)
+                .with_context(|| format!("Invalid vendor DT overlay #{i}"))
+                .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)
+        })
+        .collect()
+}
+
 fn maybe_create_device_tree_overlay(
     config: &VirtualMachineConfig,
     temporary_directory: &Path,
@@ -825,7 +1392,10 @@ fn maybe_create_device_tree_overlay(
     // Currently, VirtMgr adds the host copy of reference DT & untrusted properties
     // (e.g. instance-id)
     let host_ref_dt = Path::new(VM_REFERENCE_DT_ON_HOST_PATH);
-    let host_ref_dt = if host_ref_dt.exists()
+    // The reference DT is only checked by pvmfw.
+    let host_ref_dt = if !Arch::host().has_pvmfw() {
+        None
+    } else if host_ref_dt.exists()
         && read_dir(host_ref_dt).or_service_specific_exception(-1)?.next().is_some()
     {
         Some(host_ref_dt)
@@ -849,7 +1419,9 @@ fn maybe_create_device_tree_overlay(
     };
 
     let instance_id;
-    let mut untrusted_props = Vec::with_capacity(2);
+    let cpu_mitigations;
+    let capabilities;
+    let mut untrusted_props = Vec::with_capacity(4);
     if cfg!(llpvm_changes) {
         instance_id = extract_instance_id(config);
         untrusted_props.push((cstr!("instance-id"), &instance_id[..]));
@@ -860,17 +1432,38 @@ fn maybe_create_device_tree_overlay(
             untrusted_props.push((cstr!("defer-rollback-protection"), &[]))
         }
     }
+    if let VirtualMachineConfig::AppConfig(app_config) = config {
+        // Lets the payload adapt to the CPU vulnerabilities of the host, see cpu_mitigations.rs
+        // for what is exposed.
+        cpu_mitigations = cpu_mitigations_prop();
+        if !cpu_mitigations.is_empty() {
+            untrusted_props.push((cstr!("cpu-mitigations"), &cpu_mitigations[..]));
+        }
+        capabilities = payload_capabilities_prop(app_config)?;
+        if !capabilities.is_empty() {
+            untrusted_props.push((cstr!("capabilities"), &capabilities[..]));
+        }
+    }
+
+    let vendor_overlays = extract_vendor_dt_overlays(config)?;
 
     let device_tree_overlay = if host_ref_dt.is_some()
         || !untrusted_props.is_empty()
         || !trusted_props.is_empty()
+        || !vendor_overlays.is_empty()
     {
         let dt_output = temporary_directory.join(VM_DT_OVERLAY_PATH);
-        let mut data = [0_u8; VM_DT_OVERLAY_MAX_SIZE];
-        let fdt =
-            create_device_tree_overlay(&mut data, host_ref_dt, &untrusted_props, &trusted_props)
-                .map_err(|e| anyhow!("Failed to create DT overlay, {e:?}"))
-                .or_service_specific_exception(-1)?;
+        let vendor_overlays_size: usize = vendor_overlays.iter().map(Vec::len).sum();
+        let mut data = vec![0_u8; VM_DT_OVERLAY_MAX_SIZE + vendor_overlays_size];
+        let fdt = create_device_tree_overlay(
+            &mut data,
+            host_ref_dt,
+            &untrusted_props,
+            &trusted_props,
+            vendor_overlays,
+        )
+        .map_err(|e| anyhow!("Failed to create DT overlay, {e:?}"))
+        .or_service_specific_exception(-1)?;
         fs::write(&dt_output, fdt.as_slice()).or_service_specific_exception(-1)?;
         Some(File::open(dt_output).or_service_specific_exception(-1)?)
     } else {
@@ -879,6 +1472,22 @@ fn maybe_create_device_tree_overlay(
     Ok(device_tree_overlay)
 }
 
+/// Returns the value of the `capabilities` property of the /avf/untrusted DT node: a string list of
+/// the features which the host enables for the payload. It must be kept in sync with the gating of
+/// these features, and with the names microdroid_manager understands.
+fn payload_capabilities_prop(config: &VirtualMachineAppConfig) -> binder::Result<Vec<u8>> {
+    let mut capabilities = vec![];
+    if cfg!(remote_attestation) && !cfg!(early) && GLOBAL_SERVICE.isRemoteAttestationSupported()? {
+        capabilities.push(cstr!("remote-attestation"));
+    }
+    let network_supported = config.customConfig.as_ref().is_some_and(|c| c.networkSupported);
+    // See the creation of the TAP interface in create_vm_internal.
+    if cfg!(network) && network_supported && !config.protectedVm {
+        capabilities.push(cstr!("network"));
+    }
+    Ok(capabilities.iter().flat_map(|c| c.to_bytes_with_nul()).copied().collect())
+}
+
 fn write_zero_filler(zero_filler_path: &Path) -> Result<()> {
     let file = OpenOptions::new()
         .create_new(true)
@@ -973,6 +1582,7 @@ fn assemble_disk_image(
             &composite_image_filenames.composite,
             &composite_image_filenames.header,
             &composite_image_filenames.footer,
+            disk.directIo,
         )
         .with_context(|| format!("Failed to make composite disk image with config {:?}", disk))
         .with_log()
@@ -984,14 +1594,21 @@ fn assemble_disk_image(
 
         image
     } else if let Some(image) = &disk.image {
-        clone_file(image)?
+        let image = clone_file(image)?;
+        if disk.directIo {
+            get_raw_image_size(&image)
+                .and_then(|size| check_direct_io_allowed(&image, size))
+                .context("Can't use O_DIRECT for disk image")
+                .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        }
+        image
     } else {
         warn!("DiskImage {:?} didn't contain image or partitions.", disk);
         return Err(anyhow!("DiskImage didn't contain image or partitions."))
             .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
     };
 
-    Ok(DiskFile { image, writable: disk.writable })
+    Ok(DiskFile { image, writable: disk.writable, direct_io: disk.directIo })
 }
 
 fn append_kernel_param(param: &str, vm_config: &mut VirtualMachineRawConfig) {
@@ -1102,6 +1719,7 @@ fn load_app_config(
             vm_config.kernel = Some(ParcelFileDescriptor::new(clone_file(file)?))
         }
         vm_config.gdbPort = custom_config.gdbPort;
+        vm_config.vendorDomain = custom_config.vendorDomain;
 
         if let Some(file) = custom_config.vendorImage.as_ref() {
             add_microdroid_vendor_image(clone_file(file)?, &mut vm_config);
@@ -1121,8 +1739,37 @@ fn load_app_config(
     if config.memoryMib > 0 {
         vm_config.memoryMib = config.memoryMib;
     }
+    vm_config.maxMemoryMib = config.maxMemoryMib;
+    vm_config.swiotlbMib = config.swiotlbMib;
+    vm_config.qosClass = config.qosClass;
+    vm_config.panicPolicy = config.panicPolicy;
+    vm_config.bootDeadlineMs = config.bootDeadlineMs;
+    vm_config.labels.clone_from(&config.labels);
+    vm_config.exposedHostPropertyPrefixes.clone_from(&config.exposedHostPropertyPrefixes);
+    vm_config.sharedDirectories = config
+        .sharedDirectories
+        .iter()
+        .map(|shared_directory| {
+            Ok(SharedDirectoryParcelable {
+                directory: ParcelFileDescriptor::new(clone_file(&shared_directory.directory)?),
+                tag: shared_directory.tag.clone(),
+                writable: shared_directory.writable,
+            })
+        })
+        .collect::<binder::Result<_>>()?;
+    vm_config.pmemDisks = config
+        .pmemDisks
+        .iter()
+        .map(|pmem_disk| {
+            Ok(PmemDiskParcelable {
+                image: ParcelFileDescriptor::new(clone_file(&pmem_disk.image)?),
+                writable: pmem_disk.writable,
+            })
+        })
+        .collect::<binder::Result<_>>()?;
 
     vm_config.name.clone_from(&config.name);
+    vm_config.persistentName.clone_from(&config.persistentName);
     vm_config.protectedVm = config.protectedVm;
     vm_config.cpuTopology = config.cpuTopology;
     vm_config.hugePages = config.hugePages || vm_payload_config.hugepages;
@@ -1146,6 +1793,42 @@ fn load_app_config(
     Ok(vm_config)
 }
 
+fn prepare_storage_snapshots(
+    config: &VirtualMachineAppConfig,
+    temporary_directory: &Path,
+) -> Result<Option<StorageSnapshots>> {
+    let Some(policy) = &config.encryptedStorageSnapshots else {
+        return Ok(None);
+    };
+    let storage = config
+        .encryptedStorageImage
+        .as_ref()
+        .context("Storage snapshots require an encrypted storage image")?;
+    Ok(Some(StorageSnapshots::new(policy, clone_file(storage)?, temporary_directory)?))
+}
+
+fn prepare_storage_growth(config: &VirtualMachineAppConfig) -> Result<Option<StorageGrowth>> {
+    if config.encryptedStorageMaxBytes == 0 {
+        return Ok(None);
+    }
+    let max_size = u64::try_from(config.encryptedStorageMaxBytes).with_context(|| {
+        format!("Invalid maximum storage size {}", config.encryptedStorageMaxBytes)
+    })?;
+    let storage = config
+        .encryptedStorageImage
+        .as_ref()
+        .context("Storage growth requires an encrypted storage image")?;
+    Ok(Some(StorageGrowth::new(clone_file(storage)?, max_size)?))
+}
+
+fn set_tap_firewall(tap: &File, firewall: &NetworkFirewall) -> binder::Result<()> {
+    let tap = tap
+        .try_clone()
+        .context("Failed to clone TAP interface")
+        .or_service_specific_exception(-1)?;
+    GLOBAL_SERVICE.setTapFirewall(&ParcelFileDescriptor::new(tap), firewall)
+}
+
 fn check_partition_for_file(fd: &ParcelFileDescriptor) -> Result<()> {
     let path = format!("/proc/self/fd/{}", fd.as_raw_fd());
     let link = fs::read_link(&path).context(format!("can't read_link {path}"))?;
@@ -1178,14 +1861,65 @@ fn check_partitions_for_files(config: &VirtualMachineRawConfig) -> Result<()> {
     Ok(())
 }
 
-fn load_vm_payload_config_from_file(apk_file: &File, config_path: &str) -> Result<VmPayloadConfig> {
-    let mut apk_zip = ZipArchive::new(apk_file)?;
-    let config_file = apk_zip.by_name(config_path)?;
-    Ok(serde_json::from_reader(config_file)?)
-}
-
-fn create_vm_payload_config(
-    payload_config: &VirtualMachinePayloadConfig,
+/// Loads the JSON config file at `path`, with the fields set in `overrides` replacing its own.
+fn load_config_file(
+    file: &File,
+    overrides: &VirtualMachineConfigOverrides,
+) -> Result<VirtualMachineRawConfig> {
+    let vm_config = VmConfig::load(file)?;
+    let mut config = vm_config.to_parcelable()?;
+    config.name = vm_config.name.unwrap_or_default();
+    apply_config_overrides(&mut config, overrides)?;
+    Ok(config)
+}
+
+/// Replaces the fields of `config` which are set in `overrides`. Only the fields which don't change
+/// what runs in the VM, e.g. its resources, can be overridden for a protected VM.
+fn apply_config_overrides(
+    config: &mut VirtualMachineRawConfig,
+    overrides: &VirtualMachineConfigOverrides,
+) -> Result<()> {
+    if config.protectedVm {
+        ensure!(overrides.params.is_none(), "Can't override kernel parameters of a protected VM");
+        ensure!(overrides.gdbPort == 0, "Can't override GDB port of a protected VM");
+        ensure!(
+            overrides.debugLevel == DebugLevel::NONE,
+            "Can't override debug level of a protected VM"
+        );
+    }
+    ensure!(overrides.memoryMib >= 0, "Invalid memory size {}", overrides.memoryMib);
+    ensure!(overrides.bootDeadlineMs >= 0, "Invalid boot deadline {}", overrides.bootDeadlineMs);
+    ensure!(overrides.gdbPort >= 0, "Invalid GDB port {}", overrides.gdbPort);
+
+    if let Some(name) = &overrides.name {
+        config.name.clone_from(name);
+    }
+    if overrides.memoryMib != 0 {
+        config.memoryMib = overrides.memoryMib;
+    }
+    if let Some(labels) = &overrides.labels {
+        config.labels.clone_from(labels);
+    }
+    if overrides.bootDeadlineMs != 0 {
+        config.bootDeadlineMs = overrides.bootDeadlineMs;
+    }
+    if let Some(params) = &overrides.params {
+        config.params = Some(params.clone());
+    }
+    if overrides.gdbPort != 0 {
+        config.gdbPort = overrides.gdbPort;
+    }
+    Ok(())
+}
+
+fn load_vm_payload_config_from_file(apk_file: &File, config_path: &str) -> Result<VmPayloadConfig> {
+    let mut apk_zip = ZipArchive::new(apk_file)?;
+    let config_file = apk_zip.by_name(config_path)?;
+    Ok(serde_json::from_reader(config_file)?)
+}
+
+fn create_vm_payload_config(
+    payload_config: &VirtualMachinePayloadConfig,
 ) -> Result<VmPayloadConfig> {
     // There isn't an actual config file. Construct a synthetic VmPayloadConfig from the explicit
     // parameters we've been given. Microdroid will do something equivalent inside the VM using the
@@ -1196,10 +1930,21 @@ fn create_vm_payload_config(
         bail!("Payload binary name must not specify a path: {payload_binary_name}");
     }
 
-    let task = Task { type_: TaskType::MicrodroidLauncher, command: payload_binary_name.clone() };
+    let extra_apk_count = payload_config.extraApks.len();
+    let extra_apk_index = match payload_config.payloadExtraApkIndex {
+        -1 => None,
+        i => match usize::try_from(i) {
+            Ok(i) if i < extra_apk_count => Some(i),
+            _ => bail!("Payload binary is in extra APK #{i}, out of {extra_apk_count} extra APKs"),
+        },
+    };
+    let task = Task {
+        type_: TaskType::MicrodroidLauncher,
+        command: payload_binary_name.clone(),
+        extra_apk_index,
+    };
 
     // The VM only cares about how many there are, these names are actually ignored.
-    let extra_apk_count = payload_config.extraApks.len();
     let extra_apks =
         (0..extra_apk_count).map(|i| ApkConfig { path: format!("extra-apk-{i}") }).collect();
 
@@ -1253,6 +1998,16 @@ fn check_permission(perm: &str) -> binder::Result<()> {
     }
 }
 
+/// Check whether the caller of the current Binder method is allowed to call debug methods.
+fn check_debug_access() -> binder::Result<()> {
+    check_permission("android.permission.DEBUG_VIRTUAL_MACHINE")
+}
+
+/// Returns whether the device runs a debuggable build of Android.
+fn is_debuggable_build() -> bool {
+    system_properties::read_bool("ro.debuggable", false).unwrap_or(false)
+}
+
 /// Check whether the caller of the current Binder method is allowed to manage VMs
 fn check_manage_access() -> binder::Result<()> {
     check_permission("android.permission.MANAGE_VIRTUAL_MACHINE")
@@ -1302,6 +2057,24 @@ fn check_label_is_allowed(context: &SeContext) -> Result<()> {
     }
 }
 
+/// Check that a shared directory is private to the owner of the VM, so that the VM can't reach
+/// files which the owner can't.
+fn check_label_for_shared_directory(shared_directory: &SharedDirectory) -> Result<()> {
+    let context = getfilecon(&shared_directory.directory)?;
+    match context.selinux_type()? {
+        | "app_data_file" // data directory of an app
+        | "privapp_data_file" // data directory of a privileged app
+        | "shell_data_file" // test files created via adb shell
+         => {}
+        _ => bail!("Label {} is not allowed for shared directory {}", context, shared_directory.tag),
+    }
+    // The files of an app have its categories, which virtmgr runs with too.
+    if !context.has_same_level(&getcon()?)? {
+        bail!("Shared directory {} doesn't belong to the owner of the VM", shared_directory.tag);
+    }
+    Ok(())
+}
+
 fn check_label_for_partition(partition: &Partition) -> Result<()> {
     let file = partition.image.as_ref().unwrap().as_ref();
     check_label_is_allowed(&getfilecon(file)?)
@@ -1325,16 +2098,47 @@ fn check_label_for_file(file: &File, name: &str) -> Result<()> {
 #[derive(Debug)]
 struct VirtualMachine {
     instance: Arc<VmInstance>,
+    deprecation_warnings: Vec<DeprecationWarning>,
 }
 
 impl VirtualMachine {
-    fn create(instance: Arc<VmInstance>) -> Strong<dyn IVirtualMachine> {
-        BnVirtualMachine::new_binder(VirtualMachine { instance }, BinderFeatures::default())
+    fn create(
+        instance: Arc<VmInstance>,
+        deprecation_warnings: Vec<DeprecationWarning>,
+    ) -> Strong<dyn IVirtualMachine> {
+        BnVirtualMachine::new_binder(
+            VirtualMachine { instance, deprecation_warnings },
+            BinderFeatures::default(),
+        )
     }
 }
 
 impl Interface for VirtualMachine {}
 
+/// Starts the VM, registering it under its persistent name if it has one.
+pub fn start_vm(
+    instance: &Arc<VmInstance>,
+    deprecation_warnings: &[DeprecationWarning],
+) -> binder::Result<()> {
+    if instance.payload_hold.release() {
+        // The VM was warmed up and is already running, only its payload was waiting.
+        info!("{instance} starts its payload");
+        return Ok(());
+    }
+    if instance.persistent_name.is_some() {
+        // The registry gets its own binder object for the VM, so that the VM keeps running
+        // when the client drops its binder objects.
+        let registered_vm = VirtualMachine::create(instance.clone(), deprecation_warnings.to_vec());
+        persistent_vm::register(instance, &registered_vm)?;
+    }
+    instance
+        .start()
+        .with_context(|| format!("Error starting VM with CID {}", instance.cid))
+        .with_log()
+        .or_service_specific_exception(-1)
+        .inspect_err(|_| persistent_vm::unregister(instance))
+}
+
 impl IVirtualMachine for VirtualMachine {
     fn getCid(&self) -> binder::Result<i32> {
         // Don't check permission. The owner of the VM might have passed this binder object to
@@ -1361,11 +2165,26 @@ impl IVirtualMachine for VirtualMachine {
     }
 
     fn start(&self) -> binder::Result<()> {
-        self.instance
-            .start()
-            .with_context(|| format!("Error starting VM with CID {}", self.instance.cid))
-            .with_log()
-            .or_service_specific_exception(-1)
+        // Starting right away supersedes a deferred start.
+        self.instance.deferred_start.cancel();
+        start_vm(&self.instance, &self.deprecation_warnings)
+    }
+
+    fn startWhenPossible(&self) -> binder::Result<()> {
+        if cfg!(early) {
+            return Err(anyhow!("Early VMs can't wait for memory to start"))
+                .or_binder_exception(ExceptionCode::UNSUPPORTED_OPERATION);
+        }
+        deferred_start::start_when_possible(&self.instance, &self.deprecation_warnings)
+    }
+
+    fn getDeferredStartStatus(&self) -> binder::Result<DeferredStartStatus> {
+        self.instance.deferred_start.status()
+    }
+
+    fn cancelDeferredStart(&self) -> binder::Result<()> {
+        self.instance.deferred_start.cancel();
+        Ok(())
     }
 
     fn stop(&self) -> binder::Result<()> {
@@ -1376,6 +2195,18 @@ impl IVirtualMachine for VirtualMachine {
             .or_service_specific_exception(-1)
     }
 
+    fn shutdown(&self, timeout_millis: i64) -> binder::Result<bool> {
+        let timeout = u64::try_from(timeout_millis)
+            .map(Duration::from_millis)
+            .with_context(|| format!("Invalid shutdown timeout {timeout_millis} ms"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        self.instance
+            .shutdown(Instant::now() + timeout)
+            .with_context(|| format!("Error shutting down VM with CID {}", self.instance.cid))
+            .with_log()
+            .or_service_specific_exception(-1)
+    }
+
     fn getMemoryBalloon(&self) -> binder::Result<i64> {
         let balloon = self
             .instance
@@ -1394,6 +2225,31 @@ impl IVirtualMachine for VirtualMachine {
             .or_service_specific_exception(-1)
     }
 
+    fn getPeakRssKb(&self) -> binder::Result<i64> {
+        Ok(self.instance.vm_metric.lock().unwrap().rss.map_or(-1, |rss| rss.vm))
+    }
+
+    fn setMemory(&self, target_mib: i32) -> binder::Result<()> {
+        if !matches!(&*self.instance.vm_state.lock().unwrap(), VmState::Running { .. }) {
+            return Err(anyhow!("VM is not running"))
+                .or_binder_exception(ExceptionCode::ILLEGAL_STATE);
+        }
+        let Some(range) = self.instance.hotplug_memory_range() else {
+            return Err(anyhow!("Memory hotplug isn't enabled for the VM"))
+                .or_binder_exception(ExceptionCode::ILLEGAL_STATE);
+        };
+        let target_mib = u32::try_from(target_mib)
+            .ok()
+            .filter(|target_mib| range.contains(target_mib))
+            .with_context(|| format!("Memory size {target_mib} MiB out of range {range:?}"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        self.instance
+            .set_memory(target_mib)
+            .with_context(|| format!("Error setting memory for VM with CID {}", self.instance.cid))
+            .with_log()
+            .or_service_specific_exception(-1)
+    }
+
     fn connectVsock(&self, port: i32) -> binder::Result<ParcelFileDescriptor> {
         if !matches!(&*self.instance.vm_state.lock().unwrap(), VmState::Running { .. }) {
             return Err(anyhow!("VM is not running")).or_service_specific_exception(-1);
@@ -1403,6 +2259,9 @@ impl IVirtualMachine for VirtualMachine {
             return Err(anyhow!("Can't connect to privileged port {port}"))
                 .or_service_specific_exception(-1);
         }
+        if let Some(firewall) = &self.instance.vsock_firewall {
+            firewall.check_guest_port(port).or_binder_exception(ExceptionCode::SECURITY)?;
+        }
         let stream = VsockStream::connect_with_cid_port(self.instance.cid, port)
             .context("Failed to connect")
             .or_service_specific_exception(-1)?;
@@ -1428,11 +2287,125 @@ impl IVirtualMachine for VirtualMachine {
             .with_log()
             .or_service_specific_exception(-1)
     }
+
+    fn getDebugConfig(&self) -> binder::Result<DebugConfigParcelable> {
+        // Don't check permission. The owner of the VM might have passed this binder object to
+        // others.
+        Ok(self.instance.get_debug_config())
+    }
+
+    fn setDebugFacilityEnabled(
+        &self,
+        facility: DebugFacility,
+        enabled: bool,
+    ) -> binder::Result<()> {
+        check_debug_access()?;
+        if !is_debuggable_build() {
+            return Err(anyhow!("Debug facilities can only be changed on debuggable builds"))
+                .or_binder_exception(ExceptionCode::SECURITY);
+        }
+        self.instance
+            .set_debug_facility_enabled(facility, enabled)
+            .with_context(|| {
+                format!("Error changing debug facility of VM with CID {}", self.instance.cid)
+            })
+            .with_log()
+            .or_binder_exception(ExceptionCode::ILLEGAL_STATE)
+    }
+
+    fn setRamdumpOutput(&self, output: &ParcelFileDescriptor) -> binder::Result<()> {
+        // Don't check permission. The owner of the VM might have passed this binder object to
+        // others. Whether the ramdump can be collected at all depends on the debug level, which
+        // only the owner controls.
+        let output = clone_file(output)?;
+        self.instance
+            .set_ramdump_output(output)
+            .with_context(|| {
+                format!("Error setting ramdump output of VM with CID {}", self.instance.cid)
+            })
+            .with_log()
+            .or_binder_exception(ExceptionCode::ILLEGAL_STATE)
+    }
+
+    fn prepareForSnapshot(&self, timeout_millis: i32) -> binder::Result<bool> {
+        let timeout_millis = u64::try_from(timeout_millis)
+            .with_context(|| format!("Invalid quiesce window {timeout_millis}"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        Ok(self.instance.snapshot_callback.pre_snapshot(Duration::from_millis(timeout_millis)))
+    }
+
+    fn notifyRestoredFromSnapshot(&self) -> binder::Result<()> {
+        self.instance.snapshot_callback.post_restore();
+        Ok(())
+    }
+
+    fn getDeprecationWarnings(&self) -> binder::Result<Vec<DeprecationWarning>> {
+        // Don't check permission. The owner of the VM might have passed this binder object to
+        // others.
+        Ok(self.deprecation_warnings.clone())
+    }
+
+    fn migrateToNewProcess(&self) -> binder::Result<()> {
+        // Don't check permission. The owner of the VM might have passed this binder object to
+        // others.
+        self.instance
+            .migrate()
+            .with_context(|| format!("Error migrating VM with CID {}", self.instance.cid))
+            .with_log()
+            .or_binder_exception(ExceptionCode::ILLEGAL_STATE)
+    }
+
+    fn setNetworkFirewall(&self, firewall: &NetworkFirewall) -> binder::Result<()> {
+        // Don't check permission. The owner of the VM might have passed this binder object to
+        // others.
+        // Holding the TAP interface prevents it from being deleted while the firewall is set.
+        let tap = self.instance.network_tap.lock().unwrap();
+        let Some(tap) = tap.as_ref() else {
+            return Err(anyhow!("VM with CID {} has no network interface", self.instance.cid))
+                .with_log()
+                .or_binder_exception(ExceptionCode::ILLEGAL_STATE);
+        };
+        set_tap_firewall(tap, firewall)
+    }
+
+    fn rollbackStorage(&self, generation: i32) -> binder::Result<()> {
+        // Don't check permission. The owner of the VM might have passed this binder object to
+        // others.
+        let generation = usize::try_from(generation)
+            .with_context(|| format!("Invalid storage generation {generation}"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        self.instance
+            .rollback_storage(generation)
+            .with_context(|| {
+                format!("Error rolling back storage of VM with CID {}", self.instance.cid)
+            })
+            .with_log()
+            .or_binder_exception(ExceptionCode::ILLEGAL_STATE)
+    }
+
+    fn provideHostFile(
+        &self,
+        request_id: i32,
+        file: Option<&ParcelFileDescriptor>,
+    ) -> binder::Result<()> {
+        // Don't check permission. The owner of the VM might have passed this binder object to
+        // others.
+        let file = file.map(clone_file).transpose()?;
+        self.instance
+            .host_file_requests
+            .provide(request_id, file)
+            .with_log()
+            .or_binder_exception(ExceptionCode::ILLEGAL_STATE)
+    }
 }
 
 impl Drop for VirtualMachine {
     fn drop(&mut self) {
         debug!("Dropping {:?}", self);
+        if self.instance.persistent_registered.load(Ordering::Relaxed) {
+            // Persistent VMs keep running until they are stopped explicitly.
+            return;
+        }
         if let Err(e) = self.instance.kill() {
             debug!("Error stopping dropped VM with CID {}: {:?}", self.instance.cid, e);
         }
@@ -1485,6 +2458,46 @@ impl VirtualMachineCallbacks {
         }
     }
 
+    /// Call all registered callbacks to notify that a ramdump of the VM has been written.
+    pub fn notify_ramdump_available(&self, cid: Cid) {
+        let callbacks = &*self.0.lock().unwrap();
+        for callback in callbacks {
+            if let Err(e) = callback.onRamdumpAvailable(cid as i32) {
+                error!("Error notifying ramdump event from VM CID {}: {:?}", cid, e);
+            }
+        }
+    }
+
+    /// Call all registered callbacks to ask for a host file chosen by the user.
+    pub fn notify_host_file_requested(&self, cid: Cid, request_id: i32, mime_type: &str) {
+        let callbacks = &*self.0.lock().unwrap();
+        for callback in callbacks {
+            if let Err(e) = callback.onHostFileRequested(cid as i32, request_id, mime_type) {
+                error!("Error notifying host file request from VM CID {}: {:?}", cid, e);
+            }
+        }
+    }
+
+    /// Call all registered callbacks to notify that the VM was restarted after a guest panic.
+    pub fn notify_restarted(&self, cid: Cid) {
+        let callbacks = &*self.0.lock().unwrap();
+        for callback in callbacks {
+            if let Err(e) = callback.onRestarted(cid as i32) {
+                error!("Error notifying restart of VM CID {}: {:?}", cid, e);
+            }
+        }
+    }
+
+    /// Call all registered callbacks to notify that the guest reported why it crashed.
+    pub fn notify_guest_crash_reported(&self, cid: Cid, reason: &GuestCrashReason) {
+        let callbacks = &*self.0.lock().unwrap();
+        for callback in callbacks {
+            if let Err(e) = callback.onGuestCrashReported(cid as i32, reason) {
+                error!("Error notifying guest crash from VM CID {}: {:?}", cid, e);
+            }
+        }
+    }
+
     /// Call all registered callbacks to say that the VM has died.
     pub fn callback_on_died(&self, cid: Cid, reason: DeathReason) {
         let callbacks = &*self.0.lock().unwrap();
@@ -1510,6 +2523,9 @@ struct State {
     /// the Binder client are dropped the weak reference here will become invalid, and will be
     /// removed from the list opportunistically the next time `add_vm` is called.
     vms: Vec<Weak<VmInstance>>,
+
+    /// The warm VMs which haven't been claimed yet.
+    vm_pool: VmPool,
 }
 
 impl State {
@@ -1612,6 +2628,55 @@ fn check_gdb_allowed(config: &VirtualMachineConfig) -> binder::Result<()> {
     Ok(())
 }
 
+fn check_adb_allowed(config: &VirtualMachineConfig) -> binder::Result<()> {
+    if is_protected(config) {
+        return Err(anyhow!("Can't force adb in protected VMs"))
+            .or_binder_exception(ExceptionCode::SECURITY);
+    }
+    check_debug_access()?;
+    if !is_debuggable_build() {
+        return Err(anyhow!("adb can only be forced in VMs on debuggable builds"))
+            .or_binder_exception(ExceptionCode::SECURITY);
+    }
+    Ok(())
+}
+
+fn check_qos_class_allowed(config: &VirtualMachineConfig) -> binder::Result<()> {
+    let qos_class = match config {
+        VirtualMachineConfig::RawConfig(config) => config.qosClass,
+        VirtualMachineConfig::AppConfig(config) => config.qosClass,
+    };
+    // Foreground VMs take memory away from the VMs of other apps, so only privileged callers may
+    // ask for it.
+    if qos_class == VmQosClass::FOREGROUND {
+        check_use_custom_virtual_machine()?;
+    }
+    Ok(())
+}
+
+fn check_panic_policy_allowed(config: &VirtualMachineConfig) -> binder::Result<()> {
+    let panic_policy = match config {
+        VirtualMachineConfig::RawConfig(config) => config.panicPolicy,
+        VirtualMachineConfig::AppConfig(config) => config.panicPolicy,
+    };
+    if panic_policy != GuestPanicPolicy::HALT {
+        return Ok(());
+    }
+
+    // A halted VM holds on to its resources until its owner notices, which is only fine when
+    // debugging.
+    if !is_debuggable_build() {
+        return Err(anyhow!("Can't halt VMs on panic on non-debuggable builds"))
+            .or_binder_exception(ExceptionCode::SECURITY);
+    }
+    if get_debug_level(config) == Some(DebugLevel::NONE) {
+        return Err(anyhow!("Can't halt non-debuggable VMs on panic"))
+            .or_binder_exception(ExceptionCode::SECURITY);
+    }
+
+    Ok(())
+}
+
 fn extract_instance_id(config: &VirtualMachineConfig) -> [u8; 64] {
     match config {
         VirtualMachineConfig::RawConfig(config) => config.instanceId,
@@ -1670,17 +2735,36 @@ fn check_no_extra_apks(config: &VirtualMachineConfig) -> binder::Result<()> {
     Ok(())
 }
 
-fn check_no_extra_kernel_cmdline_params(config: &VirtualMachineConfig) -> binder::Result<()> {
+fn check_allowed_extra_kernel_cmdline_params(config: &VirtualMachineConfig) -> binder::Result<()> {
     let VirtualMachineConfig::AppConfig(config) = config else { return Ok(()) };
     if let Some(custom_config) = &config.customConfig {
-        if !custom_config.extraKernelCmdlineParams.is_empty() {
-            return Err(anyhow!("debuggable_vms_improvements feature is disabled"))
-                .or_binder_exception(ExceptionCode::UNSUPPORTED_OPERATION);
+        for param in custom_config.extraKernelCmdlineParams.iter() {
+            check_kernel_cmdline_param(param, config.debugLevel)
+                .context("debuggable_vms_improvements feature is disabled")
+                .or_binder_exception(ExceptionCode::UNSUPPORTED_OPERATION)?;
         }
     }
     Ok(())
 }
 
+fn check_no_vendor_dt_overlays(config: &VirtualMachineConfig) -> binder::Result<()> {
+    let VirtualMachineConfig::RawConfig(config) = config else { return Ok(()) };
+    if !config.vendorDtOverlays.is_empty() {
+        return Err(anyhow!("paravirtualized_devices feature is disabled"))
+            .or_binder_exception(ExceptionCode::UNSUPPORTED_OPERATION);
+    }
+    Ok(())
+}
+
+fn check_no_vhost_user_devices(config: &VirtualMachineConfig) -> binder::Result<()> {
+    let VirtualMachineConfig::RawConfig(config) = config else { return Ok(()) };
+    if !config.vhostUserDevices.is_empty() {
+        return Err(anyhow!("paravirtualized_devices feature is disabled"))
+            .or_binder_exception(ExceptionCode::UNSUPPORTED_OPERATION);
+    }
+    Ok(())
+}
+
 fn check_protected_vm_is_supported() -> binder::Result<()> {
     let is_pvm_supported =
         hypervisor_props::is_protected_vm_supported().or_service_specific_exception(-1)?;
@@ -1692,7 +2776,58 @@ fn check_protected_vm_is_supported() -> binder::Result<()> {
     }
 }
 
+/// Checks that a VM to be warmed up has no disks besides those of Microdroid and its payload.
+fn check_no_extra_disks(config: &VirtualMachineAppConfig) -> binder::Result<()> {
+    if !config.sharedDirectories.is_empty() || !config.pmemDisks.is_empty() {
+        return Err(anyhow!("VMs with shared directories or pmem disks can't be warmed up"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+    }
+    Ok(())
+}
+
+/// Checks that the config is valid and that the caller may create a VM with it. The
+/// MANAGE_VIRTUAL_MACHINE permission is checked separately.
+fn check_config(config: &VirtualMachineConfig) -> binder::Result<()> {
+    check_config_features(config)?;
+    check_deprecations(config).or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+    check_labels(get_config_labels(config))
+        .context("Invalid labels")
+        .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+    if cfg!(early) {
+        check_config_allowed_for_early_vms(config)?;
+    }
+    if is_custom_config(config) {
+        check_use_custom_virtual_machine()?;
+    }
+    // Additional permission checks if caller request gdb.
+    if extract_gdb_port(config).is_some() {
+        check_gdb_allowed(config)?;
+    }
+    if is_adb_requested(config) {
+        check_adb_allowed(config)?;
+    }
+    check_qos_class_allowed(config)?;
+    check_panic_policy_allowed(config)
+}
+
+fn check_config_arch(config: &VirtualMachineConfig) -> binder::Result<()> {
+    let arch = Arch::host();
+    if !arch.is_supported() {
+        return Err(anyhow!("{arch} guests are not supported"))
+            .or_binder_exception(ExceptionCode::UNSUPPORTED_OPERATION);
+    }
+    if !arch.has_pvmfw() && is_protected(config) {
+        return Err(anyhow!("Protected VMs are not supported on {arch}"))
+            .or_binder_exception(ExceptionCode::UNSUPPORTED_OPERATION);
+    }
+    if !arch.supports_device_assignment() {
+        check_no_devices(config)?;
+    }
+    Ok(())
+}
+
 fn check_config_features(config: &VirtualMachineConfig) -> binder::Result<()> {
+    check_config_arch(config)?;
     if !cfg!(vendor_modules) {
         check_no_vendor_modules(config)?;
     }
@@ -1703,7 +2838,11 @@ fn check_config_features(config: &VirtualMachineConfig) -> binder::Result<()> {
         check_no_extra_apks(config)?;
     }
     if !cfg!(debuggable_vms_improvements) {
-        check_no_extra_kernel_cmdline_params(config)?;
+        check_allowed_extra_kernel_cmdline_params(config)?;
+    }
+    if !cfg!(paravirtualized_devices) {
+        check_no_vendor_dt_overlays(config)?;
+        check_no_vhost_user_devices(config)?;
     }
     Ok(())
 }
@@ -1711,10 +2850,48 @@ fn check_config_features(config: &VirtualMachineConfig) -> binder::Result<()> {
 fn check_config_allowed_for_early_vms(config: &VirtualMachineConfig) -> binder::Result<()> {
     check_no_vendor_modules(config)?;
     check_no_devices(config)?;
+    check_no_vhost_user_devices(config)?;
+    check_no_network(config)?;
 
     Ok(())
 }
 
+/// Early VMs have no network, as the TAP interfaces are created by VirtualizationServiceInternal.
+fn check_no_network(config: &VirtualMachineConfig) -> binder::Result<()> {
+    let network_supported = match config {
+        VirtualMachineConfig::RawConfig(config) => config.networkSupported,
+        VirtualMachineConfig::AppConfig(config) => {
+            config.customConfig.as_ref().is_some_and(|c| c.networkSupported)
+        }
+    };
+    if network_supported {
+        return Err(anyhow!("Early VMs can't have network"))
+            .or_binder_exception(ExceptionCode::UNSUPPORTED_OPERATION);
+    }
+    Ok(())
+}
+
+/// Turns a failure of creating a VM caused by its config into the service-specific error with the
+/// code for the cause, see IVirtualizationService.ERROR_*.
+fn config_error(status: Status) -> Status {
+    let code = match status.exception_code() {
+        ExceptionCode::UNSUPPORTED_OPERATION => ERROR_UNSUPPORTED_FEATURE,
+        ExceptionCode::ILLEGAL_ARGUMENT => ERROR_INVALID_CONFIG,
+        _ => return status,
+    };
+    Status::new_service_specific_error_str(code, Some(status.get_description()))
+}
+
+/// Fails if this is the early virtmgr, which runs before VirtualizationServiceInternal and /data
+/// are available, so it can't provide `feature`, which depends on them.
+fn check_not_early(feature: &str) -> binder::Result<()> {
+    if cfg!(early) {
+        return Err(anyhow!("{feature} isn't available to early VMs"))
+            .or_binder_exception(ExceptionCode::UNSUPPORTED_OPERATION);
+    }
+    Ok(())
+}
+
 fn clone_or_prepare_logger_fd(
     fd: Option<&ParcelFileDescriptor>,
     tag: String,
@@ -1778,6 +2955,19 @@ struct VirtualMachineService {
 impl Interface for VirtualMachineService {}
 
 impl IVirtualMachineService for VirtualMachineService {
+    fn notifyKernelBooted(&self) -> binder::Result<()> {
+        let cid = self.cid;
+        if let Some(vm) = self.state.lock().unwrap().get_vm(cid) {
+            info!("VM with CID {} booted its kernel", cid);
+            let mut vm_metric = vm.vm_metric.lock().unwrap();
+            vm_metric.kernel_booted_timestamp.get_or_insert(SystemTime::now());
+            Ok(())
+        } else {
+            error!("notifyKernelBooted is called from an unknown CID {}", cid);
+            Err(anyhow!("cannot find a VM with CID {}", cid)).or_service_specific_exception(-1)
+        }
+    }
+
     fn notifyPayloadStarted(&self) -> binder::Result<()> {
         let cid = self.cid;
         if let Some(vm) = self.state.lock().unwrap().get_vm(cid) {
@@ -1785,8 +2975,13 @@ impl IVirtualMachineService for VirtualMachineService {
             vm.update_payload_state(PayloadState::Started)
                 .or_binder_exception(ExceptionCode::ILLEGAL_STATE)?;
             vm.callbacks.notify_payload_started(cid);
+            vm.record_event(VmEventType::PAYLOAD_STARTED, "");
 
-            let vm_start_timestamp = vm.vm_metric.lock().unwrap().start_timestamp;
+            let vm_start_timestamp = {
+                let mut vm_metric = vm.vm_metric.lock().unwrap();
+                vm_metric.payload_started_timestamp = Some(SystemTime::now());
+                vm_metric.start_timestamp
+            };
             write_vm_booted_stats(vm.requester_uid as i32, &vm.name, vm_start_timestamp);
             Ok(())
         } else {
@@ -1801,7 +2996,9 @@ impl IVirtualMachineService for VirtualMachineService {
             info!("VM with CID {} reported payload is ready", cid);
             vm.update_payload_state(PayloadState::Ready)
                 .or_binder_exception(ExceptionCode::ILLEGAL_STATE)?;
+            vm.vm_metric.lock().unwrap().payload_ready_timestamp = Some(SystemTime::now());
             vm.callbacks.notify_payload_ready(cid);
+            vm.record_event(VmEventType::PAYLOAD_READY, "");
             Ok(())
         } else {
             error!("notifyPayloadReady is called from an unknown CID {}", cid);
@@ -1815,7 +3012,9 @@ impl IVirtualMachineService for VirtualMachineService {
             info!("VM with CID {} finished payload", cid);
             vm.update_payload_state(PayloadState::Finished)
                 .or_binder_exception(ExceptionCode::ILLEGAL_STATE)?;
+            vm.vm_metric.lock().unwrap().payload_exit = Some(PayloadExit::Finished(exit_code));
             vm.callbacks.notify_payload_finished(cid, exit_code);
+            vm.record_event(VmEventType::PAYLOAD_FINISHED, &exit_code.to_string());
             Ok(())
         } else {
             error!("notifyPayloadFinished is called from an unknown CID {}", cid);
@@ -1829,7 +3028,9 @@ impl IVirtualMachineService for VirtualMachineService {
             info!("VM with CID {} encountered an error", cid);
             vm.update_payload_state(PayloadState::Finished)
                 .or_binder_exception(ExceptionCode::ILLEGAL_STATE)?;
+            vm.vm_metric.lock().unwrap().payload_exit = Some(PayloadExit::Error(error_code));
             vm.callbacks.notify_error(cid, error_code, message);
+            vm.record_event(VmEventType::ERROR, &format!("{error_code:?}: {message}"));
             Ok(())
         } else {
             error!("notifyError is called from an unknown CID {}", cid);
@@ -1846,8 +3047,198 @@ impl IVirtualMachineService for VirtualMachineService {
     }
 
     fn requestAttestation(&self, csr: &[u8], test_mode: bool) -> binder::Result<Vec<Certificate>> {
+        check_not_early("Remote attestation")?;
         GLOBAL_SERVICE.requestAttestation(csr, get_calling_uid() as i32, test_mode)
     }
+
+    fn requestSealedKey(&self, csr: &[u8]) -> binder::Result<Vec<u8>> {
+        check_not_early("Key sealing")?;
+        GLOBAL_SERVICE.requestSealedKey(csr)
+    }
+
+    fn requestHostFile(&self, mime_type: &str) -> binder::Result<i32> {
+        let cid = self.cid;
+        let vm = self.state.lock().unwrap().get_vm(cid);
+        let Some(vm) = vm else {
+            error!("requestHostFile is called from an unknown CID {}", cid);
+            return Err(anyhow!("cannot find a VM with CID {}", cid))
+                .or_service_specific_exception(-1);
+        };
+        let port = vm
+            .host_file_requests
+            .request(&vm.callbacks, cid, mime_type)
+            .with_log()
+            .or_binder_exception(ExceptionCode::ILLEGAL_STATE)?;
+        Ok(port as i32)
+    }
+
+    fn getHostCaCertificates(&self) -> binder::Result<Vec<HostCaCertificate>> {
+        read_host_ca_certificates()
+            .context("Failed to read host CA certificates")
+            .with_log()
+            .or_service_specific_exception(-1)
+    }
+
+    fn setSnapshotCallback(
+        &self,
+        callback: Option<&Strong<dyn ISnapshotCallback>>,
+    ) -> binder::Result<()> {
+        let cid = self.cid;
+        let Some(vm) = self.state.lock().unwrap().get_vm(cid) else {
+            error!("setSnapshotCallback is called from an unknown CID {}", cid);
+            return Err(anyhow!("cannot find a VM with CID {}", cid))
+                .or_service_specific_exception(-1);
+        };
+        vm.snapshot_callback.set(callback.cloned());
+        Ok(())
+    }
+
+    fn setShutdownCallback(
+        &self,
+        callback: Option<&Strong<dyn IShutdownCallback>>,
+    ) -> binder::Result<()> {
+        let cid = self.cid;
+        let Some(vm) = self.state.lock().unwrap().get_vm(cid) else {
+            error!("setShutdownCallback is called from an unknown CID {}", cid);
+            return Err(anyhow!("cannot find a VM with CID {}", cid))
+                .or_service_specific_exception(-1);
+        };
+        vm.shutdown_callback.set(callback.cloned());
+        Ok(())
+    }
+
+    fn waitUntilPayloadReleased(&self) -> binder::Result<()> {
+        let cid = self.cid;
+        let Some(vm) = self.state.lock().unwrap().get_vm(cid) else {
+            error!("waitUntilPayloadReleased is called from an unknown CID {}", cid);
+            return Err(anyhow!("cannot find a VM with CID {}", cid))
+                .or_service_specific_exception(-1);
+        };
+        vm.payload_hold.wait_until_released();
+        Ok(())
+    }
+
+    fn startPayloadWatchdog(&self, interval_millis: i64) -> binder::Result<()> {
+        let cid = self.cid;
+        let Some(vm) = self.state.lock().unwrap().get_vm(cid) else {
+            error!("startPayloadWatchdog is called from an unknown CID {}", cid);
+            return Err(anyhow!("cannot find a VM with CID {}", cid))
+                .or_service_specific_exception(-1);
+        };
+        let interval = u64::try_from(interval_millis)
+            .map(Duration::from_millis)
+            .with_context(|| format!("Invalid watchdog interval {interval_millis} ms"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        info!("VM with CID {} started its payload watchdog, every {:?}", cid, interval);
+        if vm
+            .payload_watchdog
+            .start(interval)
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?
+        {
+            std::thread::spawn(move || vm.monitor_payload_watchdog());
+        }
+        Ok(())
+    }
+
+    fn kickPayloadWatchdog(&self) -> binder::Result<()> {
+        let cid = self.cid;
+        let Some(vm) = self.state.lock().unwrap().get_vm(cid) else {
+            error!("kickPayloadWatchdog is called from an unknown CID {}", cid);
+            return Err(anyhow!("cannot find a VM with CID {}", cid))
+                .or_service_specific_exception(-1);
+        };
+        vm.payload_watchdog.kick().or_binder_exception(ExceptionCode::ILLEGAL_STATE)
+    }
+
+    fn getHostTimeMillis(&self) -> binder::Result<i64> {
+        let elapsed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .context("The host clock is before the Unix epoch")
+            .with_log()
+            .or_service_specific_exception(-1)?;
+        Ok(elapsed.as_millis().try_into().unwrap_or(i64::MAX))
+    }
+
+    fn readHostProperty(&self, name: &str) -> binder::Result<Option<String>> {
+        let cid = self.cid;
+        let Some(vm) = self.state.lock().unwrap().get_vm(cid) else {
+            error!("readHostProperty is called from an unknown CID {}", cid);
+            return Err(anyhow!("cannot find a VM with CID {}", cid))
+                .or_service_specific_exception(-1);
+        };
+        if !vm.exposed_host_properties.is_exposed(name) {
+            return Err(anyhow!("Host property {name:?} isn't exposed to the VM"))
+                .with_log()
+                .or_binder_exception(ExceptionCode::SECURITY);
+        }
+        vm.exposed_host_properties
+            .read(name)
+            .with_context(|| format!("Failed to read host property {name:?}"))
+            .with_log()
+            .or_service_specific_exception(-1)
+    }
+
+    fn growEncryptedStorage(&self, size_bytes: i64) -> binder::Result<i64> {
+        let cid = self.cid;
+        let Some(vm) = self.state.lock().unwrap().get_vm(cid) else {
+            error!("growEncryptedStorage is called from an unknown CID {}", cid);
+            return Err(anyhow!("cannot find a VM with CID {}", cid))
+                .or_service_specific_exception(-1);
+        };
+        let Some(storage_growth) = &vm.storage_growth else {
+            return Err(anyhow!("The owner of the VM doesn't allow its storage to grow"))
+                .with_log()
+                .or_binder_exception(ExceptionCode::UNSUPPORTED_OPERATION);
+        };
+        let size = u64::try_from(size_bytes)
+            .with_context(|| format!("Invalid storage size {size_bytes}"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        let size = storage_growth
+            .grow(size)
+            .context("Failed to grow the encrypted storage")
+            .with_log()
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        Ok(size.try_into().unwrap())
+    }
+
+    fn openPayloadLog(&self) -> binder::Result<i32> {
+        let cid = self.cid;
+        let vm = self.state.lock().unwrap().get_vm(cid);
+        let Some(vm) = vm else {
+            error!("openPayloadLog is called from an unknown CID {}", cid);
+            return Err(anyhow!("cannot find a VM with CID {}", cid))
+                .or_service_specific_exception(-1);
+        };
+        let port = vm
+            .payload_log
+            .open(cid)
+            .with_log()
+            .or_binder_exception(ExceptionCode::ILLEGAL_STATE)?;
+        Ok(port as i32)
+    }
+}
+
+/// Reads the certificates in the first existing host CA store. They are public, so any VM can get
+/// them.
+fn read_host_ca_certificates() -> Result<Vec<HostCaCertificate>> {
+    let Some(dir) = HOST_CA_CERTIFICATES_DIRS.iter().map(Path::new).find(|dir| dir.is_dir()) else {
+        bail!("No host CA store found");
+    };
+    let mut certificates = vec![];
+    for entry in read_dir(dir).with_context(|| format!("Failed to read {dir:?}"))? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| anyhow!("Invalid certificate name {name:?}"))?;
+        let contents =
+            fs::read(entry.path()).with_context(|| format!("Failed to read {:?}", entry.path()))?;
+        certificates.push(HostCaCertificate { name, contents });
+    }
+    Ok(certificates)
 }
 
 fn is_secretkeeper_supported() -> bool {
@@ -2146,6 +3537,63 @@ mod tests {
         assert_eq!(vm_config.params, Some("foo=5 bar=42".to_owned()))
     }
 
+    #[test]
+    fn test_apply_config_overrides() -> Result<()> {
+        let mut config = VirtualMachineRawConfig {
+            name: "base".to_owned(),
+            memoryMib: 256,
+            params: Some("console=hvc0".to_owned()),
+            ..Default::default()
+        };
+        let overrides = VirtualMachineConfigOverrides {
+            memoryMib: 1024,
+            params: Some("console=hvc0 quiet".to_owned()),
+            ..Default::default()
+        };
+        apply_config_overrides(&mut config, &overrides)?;
+        assert_eq!(config.name, "base");
+        assert_eq!(config.memoryMib, 1024);
+        assert_eq!(config.params.as_deref(), Some("console=hvc0 quiet"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_config_overrides_to_protected_vm() -> Result<()> {
+        let mut config = VirtualMachineRawConfig { protectedVm: true, ..Default::default() };
+        let overrides = VirtualMachineConfigOverrides { memoryMib: 1024, ..Default::default() };
+        apply_config_overrides(&mut config, &overrides)?;
+        assert_eq!(config.memoryMib, 1024);
+
+        let overrides = VirtualMachineConfigOverrides {
+            params: Some("quiet".to_owned()),
+            ..Default::default()
+        };
+        assert!(apply_config_overrides(&mut config, &overrides).is_err());
+        let overrides = VirtualMachineConfigOverrides { gdbPort: 3456, ..Default::default() };
+        assert!(apply_config_overrides(&mut config, &overrides).is_err());
+        let overrides =
+            VirtualMachineConfigOverrides { debugLevel: DebugLevel::FULL, ..Default::default() };
+        assert!(apply_config_overrides(&mut config, &overrides).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_vm_payload_config_with_payload_in_extra_apk() -> Result<()> {
+        let mut payload_config = VirtualMachinePayloadConfig {
+            payloadBinaryName: "payload.so".to_owned(),
+            extraApks: vec![ParcelFileDescriptor::new(File::open("/dev/null")?)],
+            payloadExtraApkIndex: 0,
+        };
+        let task = create_vm_payload_config(&payload_config)?.task.unwrap();
+        assert_eq!(task.extra_apk_index, Some(0));
+
+        payload_config.payloadExtraApkIndex = 1;
+        assert!(create_vm_payload_config(&payload_config).is_err());
+        payload_config.payloadExtraApkIndex = -2;
+        assert!(create_vm_payload_config(&payload_config).is_err());
+        Ok(())
+    }
+
     fn test_extract_os_name_from_config_path(
         path: &Path,
         expected_result: Option<&str>,
@@ -0,0 +1,107 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The architecture of the guests, which is that of the host as crosvm doesn't emulate CPUs.
+
+use std::fmt;
+
+/// A CPU architecture which VMs can be run on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arch {
+    Aarch64,
+    Riscv64,
+    X86_64,
+}
+
+impl Arch {
+    /// Returns the architecture of the host, and thus of the guests.
+    pub fn host() -> Self {
+        if cfg!(target_arch = "aarch64") {
+            Self::Aarch64
+        } else if cfg!(target_arch = "riscv64") {
+            Self::Riscv64
+        } else {
+            Self::X86_64
+        }
+    }
+
+    /// Whether guests of this architecture are supported. RISC-V guests are experimental, and
+    /// behind the `riscv_guests` feature.
+    pub fn is_supported(self) -> bool {
+        match self {
+            Self::Aarch64 | Self::X86_64 => true,
+            Self::Riscv64 => cfg!(riscv_guests),
+        }
+    }
+
+    /// Whether protected VMs, and the pvmfw which verifies them, can exist on this architecture.
+    pub fn has_pvmfw(self) -> bool {
+        !matches!(self, Self::Riscv64)
+    }
+
+    /// Whether crosvm can assign the devices of the host to the VM, with VFIO.
+    pub fn supports_device_assignment(self) -> bool {
+        !matches!(self, Self::Riscv64)
+    }
+
+    /// Whether crosvm can expose the frequency of the host CPUs to the VM, see `--virt-cpufreq`.
+    pub fn supports_virt_cpufreq(self) -> bool {
+        matches!(self, Self::Aarch64)
+    }
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Aarch64 => "aarch64",
+            Self::Riscv64 => "riscv64",
+            Self::X86_64 => "x86_64",
+        };
+        f.write_str(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_is_target_arch() {
+        assert_eq!(Arch::host().to_string(), std::env::consts::ARCH);
+    }
+
+    #[test]
+    fn riscv_guests_are_behind_flag() {
+        assert!(Arch::Aarch64.is_supported());
+        assert!(Arch::X86_64.is_supported());
+        assert_eq!(Arch::Riscv64.is_supported(), cfg!(riscv_guests));
+    }
+
+    #[test]
+    fn existing_arches_keep_their_features() {
+        for arch in [Arch::Aarch64, Arch::X86_64] {
+            assert!(arch.has_pvmfw(), "{arch}");
+            assert!(arch.supports_device_assignment(), "{arch}");
+        }
+        assert!(Arch::Aarch64.supports_virt_cpufreq());
+        assert!(!Arch::X86_64.supports_virt_cpufreq());
+    }
+
+    #[test]
+    fn riscv_guests_are_unprotected_without_devices() {
+        assert!(!Arch::Riscv64.has_pvmfw());
+        assert!(!Arch::Riscv64.supports_device_assignment());
+        assert!(!Arch::Riscv64.supports_virt_cpufreq());
+    }
+}
@@ -15,11 +15,12 @@
 //! Functions for creating and collecting atoms.
 
 use crate::aidl::{clone_file, GLOBAL_SERVICE};
-use crate::crosvm::VmMetric;
+use crate::crosvm::{PayloadExit, VmMetric};
 use crate::get_calling_uid;
 use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::DeathReason::DeathReason;
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
     CpuTopology::CpuTopology,
+    GuestCrashReason::Kind::Kind as GuestCrashReasonKind,
     IVirtualMachine::IVirtualMachine,
     VirtualMachineAppConfig::{Payload::Payload, VirtualMachineAppConfig},
     VirtualMachineConfig::VirtualMachineConfig,
@@ -28,7 +29,9 @@ use android_system_virtualizationservice::binder::{Status, Strong};
 use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::{
     AtomVmBooted::AtomVmBooted,
     AtomVmCreationRequested::AtomVmCreationRequested,
-    AtomVmExited::AtomVmExited,
+    AtomVmExited::{
+        AtomVmExited, GuestCrashKind::GuestCrashKind, PayloadExitReason::PayloadExitReason,
+    },
 };
 use anyhow::{anyhow, Result};
 use binder::ParcelFileDescriptor;
@@ -79,6 +82,20 @@ fn get_duration(vm_start_timestamp: Option<SystemTime>) -> Duration {
     }
 }
 
+/// Returns the time in milliseconds from the start of the VM to the given event, or -1 if either
+/// of them hasn't happened.
+fn get_time_to_event_millis(
+    vm_start_timestamp: Option<SystemTime>,
+    event_timestamp: Option<SystemTime>,
+) -> i64 {
+    match (vm_start_timestamp, event_timestamp) {
+        (Some(start), Some(event)) => {
+            event.duration_since(start).unwrap_or_default().as_millis() as i64
+        }
+        _ => -1,
+    }
+}
+
 // Returns the number of CPUs configured in the host system.
 // This matches how crosvm determines the number of logical cores.
 // For telemetry purposes only.
@@ -197,6 +214,7 @@ pub fn write_vm_exited_stats_sync(
     vm_identifier: &str,
     reason: DeathReason,
     exit_signal: Option<i32>,
+    guest_crash_kind: Option<GuestCrashReasonKind>,
     vm_metric: &VmMetric,
 ) {
     if cfg!(early) {
@@ -207,6 +225,17 @@ pub fn write_vm_exited_stats_sync(
     let elapsed_time_millis = get_duration(vm_metric.start_timestamp).as_millis() as i64;
     let guest_time_millis = vm_metric.cpu_guest_time.unwrap_or_default();
     let rss = vm_metric.rss.unwrap_or_default();
+    let (payload_exit_reason, payload_exit_code) = match vm_metric.payload_exit {
+        Some(PayloadExit::Finished(exit_code)) => (PayloadExitReason::FINISHED, exit_code),
+        Some(PayloadExit::Error(error_code)) => (PayloadExitReason::ERROR, error_code.0),
+        None => (PayloadExitReason::NOT_REPORTED, 0),
+    };
+    let guest_crash_kind = match guest_crash_kind {
+        None => GuestCrashKind::NOT_REPORTED,
+        Some(GuestCrashReasonKind::PAYLOAD_CRASHED) => GuestCrashKind::PAYLOAD_CRASHED,
+        Some(GuestCrashReasonKind::BARE_METAL_PANIC) => GuestCrashKind::BARE_METAL_PANIC,
+        Some(_) => GuestCrashKind::UNKNOWN,
+    };
 
     let atom = AtomVmExited {
         uid,
@@ -217,6 +246,21 @@ pub fn write_vm_exited_stats_sync(
         rssVmKb: rss.vm,
         rssCrosvmKb: rss.crosvm,
         exitSignal: exit_signal.unwrap_or_default(),
+        kernelBootTimeMillis: get_time_to_event_millis(
+            vm_metric.start_timestamp,
+            vm_metric.kernel_booted_timestamp,
+        ),
+        payloadStartTimeMillis: get_time_to_event_millis(
+            vm_metric.start_timestamp,
+            vm_metric.payload_started_timestamp,
+        ),
+        payloadReadyTimeMillis: get_time_to_event_millis(
+            vm_metric.start_timestamp,
+            vm_metric.payload_ready_timestamp,
+        ),
+        payloadExitReason: payload_exit_reason,
+        payloadExitCode: payload_exit_code,
+        guestCrashKind: guest_crash_kind,
     };
 
     info!("Writing VmExited atom into statsd.");
@@ -15,11 +15,13 @@
 //! Functions for creating a composite disk image.
 
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::Partition::Partition;
-use anyhow::{bail, Context, Error};
+use anyhow::{bail, ensure, Context, Error};
 use disk::{create_composite_disk, ImagePartitionType, PartitionInfo};
+use dm::util::blkgetsize64;
+use nix::unistd::{linkat, LinkatFlags};
 use std::fs::{File, OpenOptions};
 use std::io::ErrorKind;
-use std::os::unix::fs::FileExt;
+use std::os::unix::fs::{FileExt, FileTypeExt, OpenOptionsExt};
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use zerocopy::AsBytes;
@@ -28,38 +30,50 @@ use zerocopy::FromZeroes;
 
 use uuid::Uuid;
 
+/// Alignment which the size of partition images must satisfy for them to be accessed with
+/// `O_DIRECT`. This is the largest logical block size commonly found on block devices.
+pub const DIRECT_IO_ALIGNMENT: u64 = 4096;
+
+/// Alignment which the size of virtio-pmem images must satisfy, so that the guest can map them with
+/// huge pages for DAX.
+pub const PMEM_ALIGNMENT: u64 = 2 << 20;
+
+const SECTOR_SIZE: u64 = 512;
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+/// The GPT partition type which crosvm gives to every partition.
+const LINUX_FILESYSTEM_TYPE_GUID: Uuid = Uuid::from_u128(0x0fc63daf_8483_4772_8e79_3d69d8477de4);
+
 /// Constructs a composite disk image for the given list of partitions, and opens it ready to use.
 ///
 /// Returns the composite disk image file, and a list of files whose file descriptors must be passed
 /// to any process which wants to use it. This is necessary because the composite image contains
 /// paths of the form `/proc/self/fd/N` for the partition images.
+///
+/// The composite image, header and footer must not exist yet. Each of them is generated in an
+/// anonymous file which is only linked at its path once complete, so that a failure never leaves a
+/// partially written image behind, and a symlink planted at one of the paths is never followed.
+///
+/// If `direct_io` is set, the partition images are checked to be usable with `O_DIRECT`.
 pub fn make_composite_image(
     partitions: &[Partition],
     zero_filler_path: &Path,
     output_path: &Path,
     header_path: &Path,
     footer_path: &Path,
+    direct_io: bool,
 ) -> Result<(File, Vec<File>), Error> {
-    let (partitions, mut files) = convert_partitions(partitions)?;
+    let partition_types = custom_partition_types(partitions)?;
+    let (partitions, mut files) = convert_partitions(partitions, direct_io)?;
 
-    let mut composite_image = OpenOptions::new()
-        .create_new(true)
-        .read(true)
-        .write(true)
-        .open(output_path)
-        .with_context(|| format!("Failed to create composite image {:?}", output_path))?;
-    let mut header_file =
-        OpenOptions::new().create_new(true).read(true).write(true).open(header_path).with_context(
-            || format!("Failed to create composite image header {:?}", header_path),
-        )?;
-    let mut footer_file =
-        OpenOptions::new().create_new(true).read(true).write(true).open(footer_path).with_context(
-            || format!("Failed to create composite image header {:?}", footer_path),
-        )?;
-    let zero_filler_file = File::open(zero_filler_path).with_context(|| {
+    let zero_filler_file = open_nofollow(zero_filler_path).with_context(|| {
         format!("Failed to open composite image zero filler {:?}", zero_filler_path)
     })?;
 
+    let output_dir = output_path.parent().context("Composite image path has no parent")?;
+    let mut composite_image = create_scratch_file(output_dir)?;
+    let mut header_file = create_scratch_file(output_dir)?;
+    let mut footer_file = create_scratch_file(output_dir)?;
     create_composite_disk(
         &partitions,
         &fd_path_for_file(&zero_filler_file),
@@ -69,9 +83,19 @@ pub fn make_composite_image(
         &mut footer_file,
         &mut composite_image,
     )?;
+    set_partition_types(&header_file, &footer_file, &partition_types)
+        .context("Failed to set the partition types")?;
+
+    // The composite image refers to the header and footer, so it is linked last.
+    link_scratch_file(&header_file, header_path)
+        .with_context(|| format!("Failed to create composite image header {:?}", header_path))?;
+    link_scratch_file(&footer_file, footer_path)
+        .with_context(|| format!("Failed to create composite image footer {:?}", footer_path))?;
+    link_scratch_file(&composite_image, output_path)
+        .with_context(|| format!("Failed to create composite image {:?}", output_path))?;
 
     // Re-open the composite image as read-only.
-    let composite_image = File::open(output_path)
+    let composite_image = open_nofollow(output_path)
         .with_context(|| format!("Failed to open composite image {:?}", output_path))?;
 
     files.push(header_file);
@@ -81,10 +105,139 @@ pub fn make_composite_image(
     Ok((composite_image, files))
 }
 
+/// GPT header, as specified in UEFI 2.10 s5.3.2.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, AsBytes, FromZeroes, FromBytes)]
+struct GptHeader {
+    signature: [u8; 8],
+    revision: u32,
+    header_size: u32,
+    header_crc32: u32,
+    reserved: u32,
+    my_lba: u64,
+    alternate_lba: u64,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    disk_guid: [u8; 16],
+    partition_entry_lba: u64,
+    number_of_partition_entries: u32,
+    size_of_partition_entry: u32,
+    partition_entry_array_crc32: u32,
+}
+
+/// Returns the indices and GPT partition types of the partitions whose type isn't the one crosvm
+/// gives to every partition.
+fn custom_partition_types(partitions: &[Partition]) -> Result<Vec<(usize, Uuid)>, Error> {
+    let mut partition_types = vec![];
+    for (index, partition) in partitions.iter().enumerate() {
+        let Some(type_guid) = &partition.typeGuid else { continue };
+        let type_guid = Uuid::parse_str(type_guid).with_context(|| {
+            format!("Invalid type GUID {type_guid:?} of partition {}", partition.label)
+        })?;
+        if type_guid != LINUX_FILESYSTEM_TYPE_GUID {
+            partition_types.push((index, type_guid));
+        }
+    }
+    Ok(partition_types)
+}
+
+/// Sets the types of the partitions at the given indices in both the primary GPT, which is in the
+/// header of a composite image, and the backup GPT, which is in its footer. crosvm can only create
+/// partitions of a few types.
+fn set_partition_types(
+    header: &File,
+    footer: &File,
+    partition_types: &[(usize, Uuid)],
+) -> Result<(), Error> {
+    if partition_types.is_empty() {
+        return Ok(());
+    }
+    // The header starts the disk, with the protective MBR followed by the primary GPT header.
+    set_gpt_partition_types(header, SECTOR_SIZE, 0, partition_types)
+        .context("Failed to update the primary GPT")?;
+
+    // The footer ends the disk, with the backup GPT header in its last sector.
+    let footer_size = footer.metadata()?.len();
+    let backup_header_offset = footer_size.checked_sub(SECTOR_SIZE).context("Footer too small")?;
+    let mut backup_header = GptHeader::new_zeroed();
+    footer.read_exact_at(backup_header.as_bytes_mut(), backup_header_offset)?;
+    let disk_size = backup_header
+        .my_lba
+        .checked_add(1)
+        .and_then(|sectors| sectors.checked_mul(SECTOR_SIZE))
+        .context("Invalid backup GPT header")?;
+    let footer_offset = disk_size.checked_sub(footer_size).context("Invalid backup GPT header")?;
+    set_gpt_partition_types(footer, backup_header_offset, footer_offset, partition_types)
+        .context("Failed to update the backup GPT")
+}
+
+/// Sets the types of the partitions at the given indices in the GPT whose header is at
+/// `header_offset` in `file`, which holds the part of the disk starting at `file_offset`.
+fn set_gpt_partition_types(
+    file: &File,
+    header_offset: u64,
+    file_offset: u64,
+    partition_types: &[(usize, Uuid)],
+) -> Result<(), Error> {
+    let mut header = GptHeader::new_zeroed();
+    file.read_exact_at(header.as_bytes_mut(), header_offset)?;
+    ensure!(&header.signature == GPT_SIGNATURE, "No GPT header");
+    let header_size = usize::try_from(header.header_size)?;
+    ensure!(header_size == header.as_bytes().len(), "Unsupported GPT header size {header_size}");
+
+    let entry_size = usize::try_from(header.size_of_partition_entry)?;
+    let entry_count = usize::try_from(header.number_of_partition_entries)?;
+    let entries_offset = header
+        .partition_entry_lba
+        .checked_mul(SECTOR_SIZE)
+        .and_then(|offset| offset.checked_sub(file_offset))
+        .context("Partition entries out of the file")?;
+    let mut entries = vec![0u8; entry_size.checked_mul(entry_count).context("Too many entries")?];
+    file.read_exact_at(&mut entries, entries_offset)?;
+    for &(index, type_guid) in partition_types {
+        ensure!(index < entry_count && entry_size >= 16, "No entry for partition {index}");
+        // The partition type GUID starts the entry, in the mixed-endian encoding of UEFI.
+        let start = index * entry_size;
+        entries[start..start + 16].copy_from_slice(&type_guid.to_bytes_le());
+    }
+    file.write_all_at(&entries, entries_offset)?;
+
+    header.partition_entry_array_crc32 = crc32fast::hash(&entries);
+    header.header_crc32 = 0;
+    header.header_crc32 = crc32fast::hash(header.as_bytes());
+    file.write_all_at(header.as_bytes(), header_offset)?;
+    Ok(())
+}
+
+/// Creates an anonymous file in the given directory.
+fn create_scratch_file(dir: &Path) -> Result<File, Error> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_TMPFILE)
+        .open(dir)
+        .with_context(|| format!("Failed to create temporary file in {:?}", dir))
+}
+
+/// Links an anonymous file created by [`create_scratch_file`] at the given path, which must not
+/// exist yet.
+fn link_scratch_file(file: &File, path: &Path) -> Result<(), Error> {
+    linkat(None, &fd_path_for_file(file), None, path, LinkatFlags::SymlinkFollow)?;
+    Ok(())
+}
+
+/// Opens the given file read-only, failing if it is a symlink.
+fn open_nofollow(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().read(true).custom_flags(libc::O_NOFOLLOW).open(path)
+}
+
 /// Given the AIDL config containing a list of partitions, with a [`ParcelFileDescriptor`] for each
 /// partition, returns the corresponding list of PartitionInfo and the list of files whose file
 /// descriptors must be passed to any process using the composite image.
-fn convert_partitions(partitions: &[Partition]) -> Result<(Vec<PartitionInfo>, Vec<File>), Error> {
+fn convert_partitions(
+    partitions: &[Partition],
+    direct_io: bool,
+) -> Result<(Vec<PartitionInfo>, Vec<File>), Error> {
     // File descriptors to pass to child process.
     let mut files = vec![];
 
@@ -101,7 +254,12 @@ fn convert_partitions(partitions: &[Partition]) -> Result<(Vec<PartitionInfo>, V
                 .context("Failed to clone partition image file descriptor")?
                 .into();
             let path = fd_path_for_file(&file);
-            let size = get_partition_size(&file)?;
+            let size = get_partition_size(&file)
+                .with_context(|| format!("Invalid partition {}", partition.label))?;
+            if direct_io {
+                check_direct_io_allowed(&file, size)
+                    .with_context(|| format!("Can't use O_DIRECT for {}", partition.label))?;
+            }
             files.push(file);
 
             Ok(PartitionInfo {
@@ -126,9 +284,10 @@ fn fd_path_for_file(file: &File) -> PathBuf {
 /// Find the size of the partition image in the given file by parsing the header.
 ///
 /// This will work for raw and Android sparse images. QCOW2 and composite images aren't supported.
+/// Raw images may be either regular files or host block devices.
 fn get_partition_size(file: &File) -> Result<u64, Error> {
     match detect_image_type(file).context("failed to detect partition image type")? {
-        ImageType::Raw => Ok(file.metadata().context("failed to get metadata")?.len()),
+        ImageType::Raw => get_raw_image_size(file),
         ImageType::AndroidSparse => {
             // Source: system/core/libsparse/sparse_format.h
             #[repr(C)]
@@ -156,6 +315,50 @@ fn get_partition_size(file: &File) -> Result<u64, Error> {
     }
 }
 
+/// Returns the size of a raw image, which must be either a regular file or a block device.
+pub fn get_raw_image_size(file: &File) -> Result<u64, Error> {
+    let metadata = file.metadata().context("failed to get metadata")?;
+    let file_type = metadata.file_type();
+    if file_type.is_file() {
+        Ok(metadata.len())
+    } else if file_type.is_block_device() {
+        blkgetsize64(&fd_path_for_file(file)).context("failed to get block device size")
+    } else {
+        bail!("image is neither a regular file nor a block device: {file_type:?}")
+    }
+}
+
+/// Checks that a raw image of the given size can be accessed with `O_DIRECT` by crosvm.
+pub fn check_direct_io_allowed(file: &File, size: u64) -> Result<(), Error> {
+    // crosvm parses the chunk headers of Android sparse images with unaligned reads.
+    let image_type = detect_image_type(file).context("failed to detect image type")?;
+    if image_type != ImageType::Raw {
+        bail!("only raw images can be used with O_DIRECT, not {image_type:?}");
+    }
+    if size % DIRECT_IO_ALIGNMENT != 0 {
+        bail!("image size {size} is not a multiple of {DIRECT_IO_ALIGNMENT}");
+    }
+    Ok(())
+}
+
+/// Checks that the image can be mapped into the memory of a VM by crosvm as a virtio-pmem device.
+pub fn check_pmem_allowed(file: &File) -> Result<(), Error> {
+    let metadata = file.metadata().context("failed to get metadata")?;
+    if !metadata.is_file() {
+        bail!("pmem image must be a regular file, not {:?}", metadata.file_type());
+    }
+    // The guest sees the image as memory, so there is no header for crosvm to interpret.
+    let image_type = detect_image_type(file).context("failed to detect image type")?;
+    if image_type != ImageType::Raw {
+        bail!("only raw images can be used for pmem, not {image_type:?}");
+    }
+    let size = metadata.len();
+    if size == 0 || size % PMEM_ALIGNMENT != 0 {
+        bail!("pmem image size {size} is not a nonzero multiple of {PMEM_ALIGNMENT}");
+    }
+    Ok(())
+}
+
 /// Image file types we can detect.
 #[derive(Debug, PartialEq, Eq)]
 enum ImageType {
@@ -196,3 +399,131 @@ fn detect_image_type(file: &File) -> std::io::Result<ImageType> {
 
     Ok(ImageType::Raw)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempfile;
+
+    fn file_with(content: &[u8]) -> File {
+        let file = tempfile().unwrap();
+        file.write_all_at(content, 0).unwrap();
+        file
+    }
+
+    fn content(file: &File) -> Vec<u8> {
+        let mut content = vec![0u8; file.metadata().unwrap().len() as usize];
+        file.read_exact_at(&mut content, 0).unwrap();
+        content
+    }
+
+    #[test]
+    fn scratch_files_are_linked_only_at_new_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("composite.img");
+        let file = create_scratch_file(dir.path()).unwrap();
+        file.write_all_at(b"image", 0).unwrap();
+        link_scratch_file(&file, &path).unwrap();
+        assert_eq!(content(&open_nofollow(&path).unwrap()), b"image");
+
+        let other = create_scratch_file(dir.path()).unwrap();
+        assert!(link_scratch_file(&other, &path).is_err());
+        let link = dir.path().join("link.img");
+        std::os::unix::fs::symlink(dir.path().join("target.img"), &link).unwrap();
+        assert!(link_scratch_file(&other, &link).is_err());
+        assert!(!dir.path().join("target.img").exists());
+        assert!(open_nofollow(&link).is_err());
+    }
+
+    #[test]
+    fn raw_image_size_of_regular_file() {
+        let file = file_with(&[0u8; 5000]);
+        assert_eq!(get_raw_image_size(&file).unwrap(), 5000);
+        let dir = File::open(std::env::temp_dir()).unwrap();
+        assert!(get_raw_image_size(&dir).is_err());
+    }
+
+    #[test]
+    fn direct_io_images_must_be_raw_and_aligned() {
+        let file = file_with(&[0u8; 2 * DIRECT_IO_ALIGNMENT as usize]);
+        assert!(check_direct_io_allowed(&file, 2 * DIRECT_IO_ALIGNMENT).is_ok());
+        assert!(check_direct_io_allowed(&file, DIRECT_IO_ALIGNMENT + 512).is_err());
+        file.write_all_at(&0xed26ff3a_u32.to_le_bytes(), 0).unwrap();
+        assert!(check_direct_io_allowed(&file, 2 * DIRECT_IO_ALIGNMENT).is_err());
+    }
+
+    #[test]
+    fn pmem_images_must_be_raw_and_aligned() {
+        let file = tempfile().unwrap();
+        assert!(check_pmem_allowed(&file).is_err());
+        file.set_len(PMEM_ALIGNMENT).unwrap();
+        assert!(check_pmem_allowed(&file).is_ok());
+        file.set_len(PMEM_ALIGNMENT + 4096).unwrap();
+        assert!(check_pmem_allowed(&file).is_err());
+        file.set_len(2 * PMEM_ALIGNMENT).unwrap();
+        file.write_all_at(&0x5146_49fb_u32.to_be_bytes(), 0).unwrap();
+        assert!(check_pmem_allowed(&file).is_err());
+    }
+
+    /// Returns the type GUID of the partition at `index` of the GPT whose header is at
+    /// `header_offset` in `file`, which holds the disk from `file_offset`, after checking its CRCs.
+    fn gpt_partition_type(file: &File, header_offset: u64, file_offset: u64, index: u64) -> Uuid {
+        let mut header = GptHeader::new_zeroed();
+        file.read_exact_at(header.as_bytes_mut(), header_offset).unwrap();
+        let entry_size = u64::from(header.size_of_partition_entry);
+        let mut entries =
+            vec![0u8; (entry_size * u64::from(header.number_of_partition_entries)) as usize];
+        let entries_offset = header.partition_entry_lba * SECTOR_SIZE - file_offset;
+        file.read_exact_at(&mut entries, entries_offset).unwrap();
+        assert_eq!({ header.partition_entry_array_crc32 }, crc32fast::hash(&entries));
+        let header_crc32 = header.header_crc32;
+        header.header_crc32 = 0;
+        assert_eq!(header_crc32, crc32fast::hash(header.as_bytes()));
+        let start = (index * entry_size) as usize;
+        Uuid::from_bytes_le(entries[start..start + 16].try_into().unwrap())
+    }
+
+    #[test]
+    fn set_partition_types_updates_both_gpts() {
+        const ESP_TYPE_GUID: Uuid = Uuid::from_u128(0xc12a7328_f81f_11d2_ba4b_00a0c93ec93b);
+
+        let images = [file_with(&[0u8; 4096]), file_with(&[0u8; 4096])];
+        let partitions: Vec<_> = images
+            .iter()
+            .enumerate()
+            .map(|(i, image)| PartitionInfo {
+                label: format!("partition-{i}"),
+                path: fd_path_for_file(image),
+                partition_type: ImagePartitionType::LinuxFilesystem,
+                writable: false,
+                size: 4096,
+                part_guid: None,
+            })
+            .collect();
+        let zero_filler = file_with(&[0u8; 4096]);
+        let mut header = tempfile().unwrap();
+        let mut footer = tempfile().unwrap();
+        let mut composite = tempfile().unwrap();
+        create_composite_disk(
+            &partitions,
+            &fd_path_for_file(&zero_filler),
+            &fd_path_for_file(&header),
+            &mut header,
+            &fd_path_for_file(&footer),
+            &mut footer,
+            &mut composite,
+        )
+        .unwrap();
+
+        set_partition_types(&header, &footer, &[(1, ESP_TYPE_GUID)]).unwrap();
+
+        assert_eq!(gpt_partition_type(&header, SECTOR_SIZE, 0, 0), LINUX_FILESYSTEM_TYPE_GUID);
+        assert_eq!(gpt_partition_type(&header, SECTOR_SIZE, 0, 1), ESP_TYPE_GUID);
+        let mut backup_header = GptHeader::new_zeroed();
+        let footer_size = footer.metadata().unwrap().len();
+        footer.read_exact_at(backup_header.as_bytes_mut(), footer_size - SECTOR_SIZE).unwrap();
+        let footer_offset = (backup_header.my_lba + 1) * SECTOR_SIZE - footer_size;
+        let backup_type = gpt_partition_type(&footer, footer_size - SECTOR_SIZE, footer_offset, 1);
+        assert_eq!(backup_type, ESP_TYPE_GUID);
+    }
+}
@@ -0,0 +1,108 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Capture of the last console output of a VM, to attach it to the errors reported to its owner.
+
+use anyhow::Result;
+use log::warn;
+use nix::{fcntl::OFlag, unistd::pipe2};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// How much of the console output is kept.
+const CAPACITY: usize = 16 * 1024;
+
+/// The last console output of a VM.
+#[derive(Clone, Debug, Default)]
+pub struct ConsoleCapture(Arc<Mutex<VecDeque<u8>>>);
+
+impl ConsoleCapture {
+    /// Interposes a pipe between crosvm and `output`, the console output of the VM, keeping the
+    /// last of what goes through it. Returns the write end of the pipe, to pass to crosvm instead.
+    pub fn tee(output: Option<File>) -> Result<(Self, File)> {
+        let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC)?;
+        let capture = Self::default();
+        let forwarder = capture.clone();
+        thread::spawn(move || forwarder.forward(File::from(read_fd), output));
+        Ok((capture, File::from(write_fd)))
+    }
+
+    fn forward(&self, mut input: File, mut output: Option<File>) {
+        let mut buffer = [0; 4096];
+        loop {
+            let size = match input.read(&mut buffer) {
+                Ok(0) => return,
+                Ok(size) => size,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("Failed to read console output: {e}");
+                    return;
+                }
+            };
+            if let Some(file) = &mut output {
+                if let Err(e) = file.write_all(&buffer[..size]) {
+                    // Keep capturing, the output is only lost for the owner of the VM.
+                    warn!("Failed to forward console output: {e}");
+                    output = None;
+                }
+            }
+            self.push(&buffer[..size]);
+        }
+    }
+
+    fn push(&self, data: &[u8]) {
+        let mut captured = self.0.lock().unwrap();
+        captured.extend(data);
+        let excess = captured.len().saturating_sub(CAPACITY);
+        captured.drain(..excess);
+    }
+
+    /// Returns the last console output, with invalid UTF-8 replaced.
+    pub fn contents(&self) -> String {
+        let captured = self.0.lock().unwrap();
+        let (front, back) = captured.as_slices();
+        String::from_utf8_lossy(&[front, back].concat()).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_last_output() {
+        let capture = ConsoleCapture::default();
+        capture.push(&[b'a'; CAPACITY]);
+        capture.push(b"boot failed\n");
+        let contents = capture.contents();
+        assert_eq!(contents.len(), CAPACITY);
+        assert!(contents.ends_with("aboot failed\n"));
+    }
+
+    #[test]
+    fn forwards_output() -> Result<()> {
+        let (output_read, output_write) = pipe2(OFlag::O_CLOEXEC)?;
+        let (capture, input) = ConsoleCapture::tee(Some(output_write.into()))?;
+        (&input).write_all(b"hello\n")?;
+        drop(input);
+        let mut forwarded = String::new();
+        File::from(output_read).read_to_string(&mut forwarded)?;
+        assert_eq!(forwarded, "hello\n");
+        assert_eq!(capture.contents(), "hello\n");
+        Ok(())
+    }
+}
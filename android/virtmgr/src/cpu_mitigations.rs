@@ -0,0 +1,146 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Summary of the CPU vulnerability mitigations of the host, passed to the guest.
+//!
+//! Only the category of the state of an allowlisted set of vulnerabilities is passed, never the
+//! description reported by the kernel, which can identify the firmware, microcode or kernel
+//! configuration of the host. The set is limited to vulnerabilities which payloads can work
+//! around themselves, e.g. by avoiding secret-dependent branches.
+
+use log::warn;
+use std::ffi::CString;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+const VULNERABILITIES_DIR: &str = "/sys/devices/system/cpu/vulnerabilities";
+
+/// The vulnerabilities passed to the guest. The names are those of the files in
+/// `VULNERABILITIES_DIR`, and must match the ones microdroid_manager understands.
+const ALLOWED_VULNERABILITIES: [&str; 4] =
+    ["meltdown", "spectre_v1", "spectre_v2", "spec_store_bypass"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MitigationState {
+    NotAffected,
+    Mitigated,
+    Vulnerable,
+}
+
+impl MitigationState {
+    /// Parses the content of a file in `VULNERABILITIES_DIR`, see
+    /// Documentation/ABI/testing/sysfs-devices-system-cpu in the kernel.
+    fn parse(status: &str) -> Option<Self> {
+        let status = status.trim();
+        if status == "Not affected" {
+            Some(Self::NotAffected)
+        } else if status.starts_with("Mitigation") {
+            Some(Self::Mitigated)
+        } else if status.starts_with("Vulnerable") {
+            Some(Self::Vulnerable)
+        } else {
+            None
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::NotAffected => "not-affected",
+            Self::Mitigated => "mitigated",
+            Self::Vulnerable => "vulnerable",
+        }
+    }
+}
+
+/// Returns the value of the `cpu-mitigations` property of the /avf/untrusted DT node: a string
+/// list of `<vulnerability>=<state>` entries. Vulnerabilities whose state is unknown are omitted.
+pub fn cpu_mitigations_prop() -> Vec<u8> {
+    encode(&read_states(Path::new(VULNERABILITIES_DIR)))
+}
+
+fn read_states(dir: &Path) -> Vec<(&'static str, MitigationState)> {
+    ALLOWED_VULNERABILITIES
+        .iter()
+        .filter_map(|&name| match fs::read_to_string(dir.join(name)) {
+            Ok(status) => MitigationState::parse(&status).map(|state| (name, state)),
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    warn!("Failed to read the state of CPU vulnerability {name}: {e}");
+                }
+                None
+            }
+        })
+        .collect()
+}
+
+fn encode(states: &[(&str, MitigationState)]) -> Vec<u8> {
+    states
+        .iter()
+        .flat_map(|(name, state)| {
+            CString::new(format!("{name}={}", state.as_str())).unwrap().into_bytes_with_nul()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn parse_states() {
+        assert_eq!(MitigationState::parse("Not affected\n"), Some(MitigationState::NotAffected));
+        assert_eq!(
+            MitigationState::parse("Mitigation: __user pointer sanitization\n"),
+            Some(MitigationState::Mitigated)
+        );
+        assert_eq!(
+            MitigationState::parse("Vulnerable: Unprivileged eBPF enabled\n"),
+            Some(MitigationState::Vulnerable)
+        );
+        assert_eq!(MitigationState::parse("Vulnerable\n"), Some(MitigationState::Vulnerable));
+        assert_eq!(MitigationState::parse("Unknown: No mitigations\n"), None);
+        assert_eq!(MitigationState::parse(""), None);
+    }
+
+    #[test]
+    fn only_allowed_vulnerabilities_are_read() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("meltdown"), "Not affected\n").unwrap();
+        fs::write(dir.path().join("spectre_v2"), "Mitigation: CSV2, BHB\n").unwrap();
+        fs::write(dir.path().join("spec_store_bypass"), "Unknown: foo\n").unwrap();
+        fs::write(dir.path().join("retbleed"), "Vulnerable\n").unwrap();
+
+        assert_eq!(
+            read_states(dir.path()),
+            vec![
+                ("meltdown", MitigationState::NotAffected),
+                ("spectre_v2", MitigationState::Mitigated)
+            ]
+        );
+    }
+
+    #[test]
+    fn encode_string_list() {
+        assert_eq!(encode(&[]), b"");
+        assert_eq!(
+            encode(&[
+                ("meltdown", MitigationState::NotAffected),
+                ("spectre_v1", MitigationState::Vulnerable)
+            ]),
+            b"meltdown=not-affected\0spectre_v1=vulnerable\0"
+        );
+    }
+}
@@ -15,13 +15,32 @@
 //! Functions for running instances of `crosvm`.
 
 use crate::aidl::{remove_temporary_files, Cid, GLOBAL_SERVICE, VirtualMachineCallbacks};
+use crate::arch::Arch;
 use crate::atom::{get_num_cpus, write_vm_exited_stats_sync};
+use crate::console_capture::ConsoleCapture;
 use crate::debug_config::DebugConfig;
-use anyhow::{anyhow, bail, Context, Error, Result};
+use crate::deferred_start::DeferredStart;
+use crate::guest_crash::split_guest_crash;
+use crate::host_file::HostFileRequests;
+use crate::host_properties::HostPropertyFilter;
+use crate::migration::{Migration, MIGRATION_TIMEOUT};
+use crate::persistent_vm;
+use crate::network_stub::NetworkStub;
+use crate::payload_log::PayloadLog;
+use crate::payload_watchdog::PayloadWatchdog;
+use crate::port_forwarding::{PortForwarder, PortForwardingRule};
+use crate::selinux::{getcon, setexeccon};
+use crate::shutdown::ShutdownCallback;
+use crate::snapshot::SnapshotCallback;
+use crate::storage_growth::StorageGrowth;
+use crate::storage_snapshot::{StorageSnapshots, QUIESCE_WINDOW};
+use crate::vm_pool::PayloadHold;
+use crate::vsock_firewall::VsockFirewall;
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
 use binder::ParcelFileDescriptor;
 use command_fds::CommandFdExt;
 use libc::{sysconf, _SC_CLK_TCK};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use semver::{Version, VersionReq};
 use nix::{fcntl::OFlag, unistd::pipe2, unistd::Uid, unistd::User};
 use regex::{Captures, Regex};
@@ -29,38 +48,57 @@ use rustutils::system_properties;
 use shared_child::SharedChild;
 use std::borrow::Cow;
 use std::cmp::max;
+use std::collections::HashSet;
 use std::fmt;
 use std::fs::{read_to_string, File};
 use std::io::{self, Read};
 use std::mem;
 use std::num::{NonZeroU16, NonZeroU32};
+use std::ops::RangeInclusive;
+use std::os::unix::fs::FileTypeExt;
 use std::os::unix::io::{AsRawFd, OwnedFd};
 use std::os::unix::process::ExitStatusExt;
 use std::path::{Path, PathBuf};
 use std::process::{Command, ExitStatus};
+use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Condvar, Mutex, LazyLock};
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use std::thread::{self, JoinHandle};
 use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::DeathReason::DeathReason;
+use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::ErrorCode::ErrorCode;
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
     VirtualMachineAppConfig::DebugLevel::DebugLevel,
     AudioConfig::AudioConfig as AudioConfigParcelable,
+    DebugConfig::DebugConfig as DebugConfigParcelable,
+    DebugFacility::DebugFacility,
     DisplayConfig::DisplayConfig as DisplayConfigParcelable,
     GpuConfig::GpuConfig as GpuConfigParcelable,
+    GuestPanicPolicy::GuestPanicPolicy,
+    SharedDirectory::SharedDirectory as SharedDirectoryParcelable,
     UsbConfig::UsbConfig as UsbConfigParcelable,
+    VmEventType::VmEventType,
+    VmLabel::VmLabel,
+    VmQosClass::VmQosClass,
 };
 use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IGlobalVmContext::IGlobalVmContext;
 use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IBoundDevice::IBoundDevice;
+use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::VhostUserDeviceType::VhostUserDeviceType;
 use binder::Strong;
 use android_system_virtualmachineservice::aidl::android::system::virtualmachineservice::IVirtualMachineService::IVirtualMachineService;
 use tombstoned_client::{TombstonedConnection, DebuggerdDumpType};
 use rpcbinder::RpcServer;
 
 /// external/crosvm
-use vm_control::{BalloonControlCommand, VmRequest, VmResponse};
+use vm_control::{
+    BalloonControlCommand, SnapshotCommand, VirtioMemControlCommand, VmRequest, VmResponse,
+};
 
 const CROSVM_PATH: &str = "/apex/com.android.virt/bin/crosvm";
 
+/// SELinux type of the domain in which crosvm runs for VMs mounting images supplied by the vendor.
+/// Its policy is listed in docs/platform_sepolicy.md.
+const CROSVM_VENDOR_DOMAIN: &str = "crosvm_vendor";
+
 /// Version of the platform that crosvm currently implements. The format follows SemVer. This
 /// should be updated when there is a platform change in the crosvm side. Having this value here is
 /// fine because virtualizationservice and crosvm are supposed to be updated together in the virt
@@ -73,11 +111,15 @@ const CROSVM_START_ERROR_STATUS: i32 = 1;
 const CROSVM_REBOOT_STATUS: i32 = 32;
 /// The exit status which crosvm returns when it crashes due to an error.
 const CROSVM_CRASH_STATUS: i32 = 33;
+/// The exit status which crosvm returns when the guest notifies a panic through pvpanic.
+const CROSVM_GUEST_PANIC_STATUS: i32 = 34;
 /// The exit status which crosvm returns when vcpu is stalled.
 const CROSVM_WATCHDOG_REBOOT_STATUS: i32 = 36;
 /// The size of memory (in MiB) reserved for ramdump
 const RAMDUMP_RESERVED_MIB: u32 = 17;
 
+const MIB: u64 = 1 << 20;
+
 const MILLIS_PER_SEC: i64 = 1000;
 
 const SYSPROP_CUSTOM_PVMFW_PATH: &str = "hypervisor.pvmfw.path";
@@ -88,6 +130,9 @@ const CONSOLE_HVC0: &str = "hvc0";
 /// Serial (emulated uart)
 const CONSOLE_TTYS0: &str = "ttyS0";
 
+/// The maximum length of the tag of a virtio-fs device, see the virtio specification.
+const MAX_VIRTIO_FS_TAG_LEN: usize = 36;
+
 /// If the VM doesn't move to the Started state within this amount time, a hang-up error is
 /// triggered.
 static BOOT_HANGUP_TIMEOUT: LazyLock<Duration> = LazyLock::new(|| {
@@ -99,11 +144,59 @@ static BOOT_HANGUP_TIMEOUT: LazyLock<Duration> = LazyLock::new(|| {
     }
 });
 
+/// How often to check whether crosvm exited while shutting the VM down.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long to wait, once the VM is dead, for a call to the shutdown callback of the payload to
+/// fail as the connection to the VM breaks.
+const SHUTDOWN_CALLBACK_JOIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How many times in a row a VM with `GuestPanicPolicy::RESTART` is restarted before it is left
+/// dead.
+const MAX_PANIC_RESTARTS: u32 = 5;
+/// The delay before the first restart after a guest panic, doubled for each consecutive restart.
+const PANIC_RESTART_BACKOFF: Duration = Duration::from_secs(1);
+/// How long a restarted VM must run without panicking for its panics to stop counting as
+/// consecutive.
+const PANIC_RESTART_RESET: Duration = Duration::from_secs(600);
+
+/// Counts the consecutive guest panics of a VM with `GuestPanicPolicy::RESTART`, to decide whether
+/// and when to restart it.
+#[derive(Debug, Default)]
+struct PanicRestarts {
+    count: u32,
+}
+
+impl PanicRestarts {
+    /// Returns how long to wait before restarting the VM after it panicked, having run for
+    /// `uptime`, or `None` if it panicked too many times in a row to be restarted.
+    fn next_backoff(&mut self, uptime: Duration) -> Option<Duration> {
+        if uptime >= PANIC_RESTART_RESET {
+            self.count = 0;
+        }
+        if self.count == MAX_PANIC_RESTARTS {
+            return None;
+        }
+        let backoff = PANIC_RESTART_BACKOFF * 2u32.pow(self.count);
+        self.count += 1;
+        Some(backoff)
+    }
+}
+
+/// Name of the snapshot of a VM being migrated to a new crosvm process, in its temporary directory.
+const MIGRATION_SNAPSHOT_NAME: &str = "migration_snapshot";
+/// How long a new crosvm process may take to restore a VM being migrated, within
+/// `MIGRATION_TIMEOUT`.
+const RESTORE_TIMEOUT: Duration = Duration::from_secs(20);
+/// How often to try resuming a VM which a new crosvm process is restoring.
+const RESTORE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 /// Configuration for a VM to run with crosvm.
 #[derive(Debug)]
 pub struct CrosvmConfig {
     pub cid: Cid,
     pub name: String,
+    pub persistent_name: Option<String>,
     pub bootloader: Option<File>,
     pub kernel: Option<File>,
     pub initrd: Option<File>,
@@ -112,21 +205,33 @@ pub struct CrosvmConfig {
     pub protected: bool,
     pub debug_config: DebugConfig,
     pub memory_mib: NonZeroU32,
+    pub max_memory_mib: Option<NonZeroU32>,
+    /// Size of the bounce buffer of a protected VM, instead of one sized from its devices.
+    pub swiotlb_mib: Option<NonZeroU32>,
+    pub qos_class: VmQosClass,
+    pub labels: Vec<VmLabel>,
     pub cpus: Option<NonZeroU32>,
     pub host_cpu_topology: bool,
     pub console_out_fd: Option<File>,
+    /// The last console output, captured if the VM has a boot deadline.
+    pub console_capture: Option<ConsoleCapture>,
     pub console_in_fd: Option<File>,
     pub log_fd: Option<File>,
     pub ramdump: Option<File>,
     pub indirect_files: Vec<File>,
     pub platform_version: VersionReq,
     pub detect_hangup: bool,
+    /// How long the payload may take to be ready, before the VM is killed.
+    pub boot_deadline: Option<Duration>,
     pub gdb_port: Option<NonZeroU16>,
     pub vfio_devices: Vec<VfioDevice>,
     pub dtbo: Option<File>,
     pub device_tree_overlay: Option<File>,
     pub display_config: Option<DisplayConfig>,
     pub input_device_options: Vec<InputDeviceOption>,
+    pub vhost_user_devices: Vec<VhostUserDevice>,
+    pub shared_directories: Vec<SharedDirectory>,
+    pub pmem_disks: Vec<PmemDisk>,
     pub hugepages: bool,
     pub tap: Option<File>,
     pub console_input_device: Option<String>,
@@ -135,9 +240,104 @@ pub struct CrosvmConfig {
     pub audio_config: Option<AudioConfig>,
     pub no_balloon: bool,
     pub usb_config: UsbConfig,
+    pub port_forwarding_rules: Vec<PortForwardingRule>,
+    pub vsock_firewall: Option<VsockFirewall>,
+    pub exposed_host_properties: HostPropertyFilter,
+    pub storage_snapshots: Option<StorageSnapshots>,
+    pub storage_growth: Option<StorageGrowth>,
+    pub vendor_domain: bool,
+    pub panic_policy: GuestPanicPolicy,
+    /// The snapshot to restore the VM from, when it is migrated to a new crosvm process.
+    pub restore_snapshot: Option<PathBuf>,
 }
 
-#[derive(Debug)]
+impl CrosvmConfig {
+    /// Duplicates the config, e.g. to start crosvm again after a guest panic or to migrate the VM
+    /// to a new crosvm process. The storage
+    /// snapshots and port forwarding rules aren't duplicated, as they are owned by the `VmInstance`
+    /// and `VmState` rather than crosvm.
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            cid: self.cid,
+            name: self.name.clone(),
+            persistent_name: self.persistent_name.clone(),
+            bootloader: try_clone_file(&self.bootloader)?,
+            kernel: try_clone_file(&self.kernel)?,
+            initrd: try_clone_file(&self.initrd)?,
+            disks: self.disks.iter().map(DiskFile::try_clone).collect::<io::Result<_>>()?,
+            params: self.params.clone(),
+            protected: self.protected,
+            debug_config: self.debug_config.clone(),
+            memory_mib: self.memory_mib,
+            max_memory_mib: self.max_memory_mib,
+            swiotlb_mib: self.swiotlb_mib,
+            qos_class: self.qos_class,
+            labels: self.labels.clone(),
+            cpus: self.cpus,
+            host_cpu_topology: self.host_cpu_topology,
+            console_out_fd: try_clone_file(&self.console_out_fd)?,
+            console_capture: self.console_capture.clone(),
+            console_in_fd: try_clone_file(&self.console_in_fd)?,
+            log_fd: try_clone_file(&self.log_fd)?,
+            ramdump: try_clone_file(&self.ramdump)?,
+            indirect_files: self
+                .indirect_files
+                .iter()
+                .map(File::try_clone)
+                .collect::<Result<_, _>>()?,
+            platform_version: self.platform_version.clone(),
+            detect_hangup: self.detect_hangup,
+            boot_deadline: self.boot_deadline,
+            gdb_port: self.gdb_port,
+            vfio_devices: self.vfio_devices.clone(),
+            dtbo: try_clone_file(&self.dtbo)?,
+            device_tree_overlay: try_clone_file(&self.device_tree_overlay)?,
+            display_config: self.display_config.clone(),
+            input_device_options: self
+                .input_device_options
+                .iter()
+                .map(InputDeviceOption::try_clone)
+                .collect::<io::Result<_>>()?,
+            vhost_user_devices: self
+                .vhost_user_devices
+                .iter()
+                .map(VhostUserDevice::try_clone)
+                .collect::<io::Result<_>>()?,
+            shared_directories: self
+                .shared_directories
+                .iter()
+                .map(SharedDirectory::try_clone)
+                .collect::<io::Result<_>>()?,
+            pmem_disks: self
+                .pmem_disks
+                .iter()
+                .map(PmemDisk::try_clone)
+                .collect::<io::Result<_>>()?,
+            hugepages: self.hugepages,
+            tap: try_clone_file(&self.tap)?,
+            console_input_device: self.console_input_device.clone(),
+            boost_uclamp: self.boost_uclamp,
+            gpu_config: self.gpu_config.clone(),
+            audio_config: self.audio_config.clone(),
+            no_balloon: self.no_balloon,
+            usb_config: self.usb_config.clone(),
+            port_forwarding_rules: Vec::new(),
+            vsock_firewall: self.vsock_firewall.clone(),
+            exposed_host_properties: self.exposed_host_properties.clone(),
+            storage_snapshots: None,
+            storage_growth: None,
+            vendor_domain: self.vendor_domain,
+            panic_policy: self.panic_policy,
+            restore_snapshot: None,
+        })
+    }
+}
+
+fn try_clone_file(file: &Option<File>) -> io::Result<Option<File>> {
+    file.as_ref().map(File::try_clone).transpose()
+}
+
+#[derive(Clone, Debug)]
 pub struct AudioConfig {
     pub use_microphone: bool,
     pub use_speaker: bool,
@@ -149,7 +349,7 @@ impl AudioConfig {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct UsbConfig {
     pub controller: bool,
 }
@@ -160,7 +360,7 @@ impl UsbConfig {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct DisplayConfig {
     pub width: NonZeroU32,
     pub height: NonZeroU32,
@@ -180,7 +380,7 @@ impl DisplayConfig {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct GpuConfig {
     pub backend: Option<String>,
     pub context_types: Option<Vec<String>>,
@@ -221,6 +421,91 @@ fn try_into_non_zero_u32(value: i32) -> Result<NonZeroU32> {
 pub struct DiskFile {
     pub image: File,
     pub writable: bool,
+    /// Whether crosvm should open the image (and its components) with `O_DIRECT`.
+    pub direct_io: bool,
+}
+
+impl DiskFile {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            image: self.image.try_clone()?,
+            writable: self.writable,
+            direct_io: self.direct_io,
+        })
+    }
+}
+
+/// A file to map into the memory of a VM as a virtio-pmem device.
+#[derive(Debug)]
+pub struct PmemDisk {
+    pub image: File,
+    pub writable: bool,
+}
+
+impl PmemDisk {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self { image: self.image.try_clone()?, writable: self.writable })
+    }
+}
+
+/// A device emulated out of process by a vhost-user backend, which a host daemon registered with
+/// virtualizationservice.
+#[derive(Debug)]
+pub struct VhostUserDevice {
+    pub device_type: VhostUserDeviceType,
+    /// The connection to the backend, which crosvm drives the device through.
+    pub socket: File,
+}
+
+impl VhostUserDevice {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self { device_type: self.device_type, socket: self.socket.try_clone()? })
+    }
+
+    /// The type of the device, as crosvm's `--vhost-user` names it.
+    fn crosvm_type(&self) -> Result<&'static str> {
+        match self.device_type {
+            VhostUserDeviceType::BLOCK => Ok("block"),
+            VhostUserDeviceType::NET => Ok("net"),
+            VhostUserDeviceType::FS => Ok("fs"),
+            device_type => bail!("Unsupported vhost-user device type {device_type:?}"),
+        }
+    }
+}
+
+/// A directory of the host shared with the VM through virtio-fs. This needs the policy listed in
+/// docs/platform_sepolicy.md.
+#[derive(Debug)]
+pub struct SharedDirectory {
+    pub directory: File,
+    pub tag: String,
+    pub writable: bool,
+}
+
+impl SharedDirectory {
+    pub fn new(raw_config: &SharedDirectoryParcelable) -> Result<SharedDirectory> {
+        let tag = &raw_config.tag;
+        // The tag is a fixed size field of the configuration of the virtio-fs device.
+        if tag.is_empty()
+            || tag.len() > MAX_VIRTIO_FS_TAG_LEN
+            || !tag.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        {
+            bail!("Invalid shared directory tag {tag:?}");
+        }
+        let directory = File::from(raw_config.directory.as_ref().try_clone()?);
+        if !directory.metadata()?.is_dir() {
+            bail!("Shared directory {tag} is not a directory");
+        }
+        Ok(SharedDirectory { directory, tag: tag.clone(), writable: raw_config.writable })
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            directory: self.directory.try_clone()?,
+            tag: self.tag.clone(),
+            writable: self.writable,
+        })
+    }
 }
 
 /// virtio-input device configuration from `external/crosvm/src/crosvm/config.rs`
@@ -236,6 +521,35 @@ pub enum InputDeviceOption {
     MultiTouch { file: File, width: u32, height: u32, name: Option<String> },
 }
 
+impl InputDeviceOption {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Self::EvDev(file) => Self::EvDev(file.try_clone()?),
+            Self::SingleTouch { file, width, height, name } => Self::SingleTouch {
+                file: file.try_clone()?,
+                width: *width,
+                height: *height,
+                name: name.clone(),
+            },
+            Self::Keyboard(file) => Self::Keyboard(file.try_clone()?),
+            Self::Mouse(file) => Self::Mouse(file.try_clone()?),
+            Self::Switches(file) => Self::Switches(file.try_clone()?),
+            Self::MultiTouchTrackpad { file, width, height, name } => Self::MultiTouchTrackpad {
+                file: file.try_clone()?,
+                width: *width,
+                height: *height,
+                name: name.clone(),
+            },
+            Self::MultiTouch { file, width, height, name } => Self::MultiTouch {
+                file: file.try_clone()?,
+                width: *width,
+                height: *height,
+                name: name.clone(),
+            },
+        })
+    }
+}
+
 type VfioDevice = Strong<dyn IBoundDevice>;
 
 /// The lifecycle state which the payload in the VM has reported itself to be in.
@@ -265,6 +579,11 @@ pub enum VmState {
         child: Arc<SharedChild>,
         /// The thread waiting for crosvm to finish.
         monitor_vm_exit_thread: Option<JoinHandle<()>>,
+        /// Forwards host sockets to the VM. Dropped, and thus torn down, when the VM dies.
+        port_forwarder: PortForwarder,
+        /// Serves DHCP and DNS to the VM if it has network. Dropped, and thus its lease ended,
+        /// when the VM dies.
+        _network_stub: Option<NetworkStub>,
     },
     /// The VM died or was killed.
     Dead,
@@ -289,6 +608,23 @@ pub struct VmMetric {
     pub cpu_guest_time: Option<i64>,
     /// Update maximum RSS values periodically from /proc/[crosvm pid]/smaps while VM is running.
     pub rss: Option<Rss>,
+    /// Recorded timestamp when the guest reports that its kernel has booted.
+    pub kernel_booted_timestamp: Option<SystemTime>,
+    /// Recorded timestamp when the payload is started.
+    pub payload_started_timestamp: Option<SystemTime>,
+    /// Recorded timestamp when the payload is ready to serve.
+    pub payload_ready_timestamp: Option<SystemTime>,
+    /// How the payload reported it ended, if it did.
+    pub payload_exit: Option<PayloadExit>,
+}
+
+/// How the payload in a VM ended.
+#[derive(Clone, Copy, Debug)]
+pub enum PayloadExit {
+    /// The payload finished with the given exit code.
+    Finished(i32),
+    /// The payload reported an error.
+    Error(ErrorCode),
 }
 
 impl VmState {
@@ -298,12 +634,35 @@ impl VmState {
     fn start(&mut self, instance: Arc<VmInstance>) -> Result<(), Error> {
         let state = mem::replace(self, VmState::Failed);
         if let VmState::NotStarted { config } = state {
-            let config = *config;
+            let mut config = *config;
             let detect_hangup = config.detect_hangup;
+            let boot_deadline = config.boot_deadline;
+            let console_capture = config.console_capture.clone();
             let (failure_pipe_read, failure_pipe_write) = create_pipe()?;
             let vfio_devices = config.vfio_devices.clone();
             let tap =
                 if let Some(tap_file) = &config.tap { Some(tap_file.try_clone()?) } else { None };
+            // Bind the host sockets before spawning crosvm, so that a conflicting socket name
+            // fails the start without leaving a running VM behind.
+            let port_forwarder = PortForwarder::start(
+                config.cid,
+                instance.requester_uid,
+                mem::take(&mut config.port_forwarding_rules),
+            )?;
+            // Without the stub the guest gets no address, so the VM fails to start instead.
+            let network_stub = config
+                .tap
+                .as_ref()
+                .map(|tap| NetworkStub::start(config.cid, tap))
+                .transpose()
+                .context("Failed to start network stub")?;
+
+            let respawn_config =
+                if config.panic_policy == GuestPanicPolicy::RESTART || instance.migratable {
+                    Some(config.try_clone()?)
+                } else {
+                    None
+                };
 
             // If this fails and returns an error, `self` will be left in the `Failed` state.
             let child =
@@ -318,9 +677,34 @@ impl VmState {
             let child_clone = child.clone();
             let instance_clone = instance.clone();
             let monitor_vm_exit_thread = Some(thread::spawn(move || {
-                instance_clone.monitor_vm_exit(child_clone, failure_pipe_read, vfio_devices, tap);
+                instance_clone.monitor_vm_exit(
+                    child_clone,
+                    failure_pipe_read,
+                    vfio_devices,
+                    tap,
+                    respawn_config,
+                );
             }));
 
+            if instance.storage_snapshots.is_some() {
+                let instance_clone = instance.clone();
+                thread::spawn(move || {
+                    instance_clone.snapshot_storage_periodically();
+                });
+            }
+
+            if let Some(boot_deadline) = boot_deadline {
+                let instance_clone = instance.clone();
+                let child_clone = child.clone();
+                thread::spawn(move || {
+                    instance_clone.monitor_boot_deadline(
+                        child_clone,
+                        boot_deadline,
+                        console_capture.as_ref(),
+                    );
+                });
+            }
+
             if detect_hangup {
                 let child_clone = child.clone();
                 thread::spawn(move || {
@@ -329,7 +713,12 @@ impl VmState {
             }
 
             // If it started correctly, update the state.
-            *self = VmState::Running { child, monitor_vm_exit_thread };
+            *self = VmState::Running {
+                child,
+                monitor_vm_exit_thread,
+                port_forwarder,
+                _network_stub: network_stub,
+            };
             Ok(())
         } else {
             *self = state;
@@ -338,6 +727,25 @@ impl VmState {
     }
 }
 
+/// Memory of a VM which can be grown or shrunk while it runs.
+///
+/// The VM boots with its minimum memory, and a virtio-mem device holds what is beyond it, of which
+/// only the part which is plugged in is backed by host memory.
+#[derive(Debug)]
+struct MemoryHotplug {
+    min_mib: u32,
+    max_mib: u32,
+    current_mib: Mutex<u32>,
+}
+
+impl MemoryHotplug {
+    /// Returns how much of the virtio-mem device must be plugged in for the VM to have
+    /// `memory_mib` of memory.
+    fn plugged_bytes(&self, memory_mib: u32) -> u64 {
+        u64::from(memory_mib - self.min_mib) * MIB
+    }
+}
+
 /// Internal struct that holds the handles to globally unique resources of a VM.
 #[derive(Debug)]
 pub struct VmContext {
@@ -368,6 +776,11 @@ pub struct VmInstance {
     crosvm_control_socket_path: PathBuf,
     /// The name of the VM.
     pub name: String,
+    /// The name under which the VM is registered once started, so that it keeps running when its
+    /// client dies.
+    pub persistent_name: Option<String>,
+    /// Whether the VM is currently registered under its persistent name.
+    pub persistent_registered: AtomicBool,
     /// Whether the VM is a protected VM.
     pub protected: bool,
     /// Directory of temporary files used by the VM while it is running.
@@ -390,6 +803,51 @@ pub struct VmInstance {
     payload_state_updated: Condvar,
     /// The human readable name of requester_uid
     requester_uid_name: String,
+    /// The debug configuration of the VM, as used when it is started.
+    debug_config: Mutex<DebugConfig>,
+    /// File provided by the owner of the VM to receive the ramdump if the guest kernel crashes.
+    ramdump_output: Mutex<Option<File>>,
+    /// Requests of the payload for host files chosen by the user.
+    pub host_file_requests: HostFileRequests,
+    /// Log records which the payload sends to be written to logcat.
+    pub payload_log: PayloadLog,
+    /// Callback of the payload notified around snapshots of the VM.
+    pub snapshot_callback: SnapshotCallback,
+    /// Callback of the payload notified when the VM is shut down gracefully.
+    pub shutdown_callback: ShutdownCallback,
+    /// Holds the payload of a warm VM back until the VM is claimed.
+    pub payload_hold: PayloadHold,
+    /// Watchdog which the payload may start, to be killed if it hangs.
+    pub payload_watchdog: PayloadWatchdog,
+    /// Snapshots of the encrypted storage, if its owner asked for them.
+    storage_snapshots: Option<StorageSnapshots>,
+    /// Whether the vCPUs of the VM are suspended.
+    suspended: Mutex<bool>,
+    /// Encrypted storage which the payload may grow, if its owner allows it.
+    pub storage_growth: Option<StorageGrowth>,
+    /// Memory of the VM, if it can be changed while the VM runs.
+    memory_hotplug: Option<MemoryHotplug>,
+    /// The memory which the VM starts with, in MiB.
+    pub memory_mib: NonZeroU32,
+    /// How important the VM is when the host runs short of memory.
+    pub qos_class: VmQosClass,
+    /// Labels of the VM, as given in its config.
+    pub labels: Vec<VmLabel>,
+    /// Memory reservation of the VM while it waits to start.
+    pub deferred_start: DeferredStart,
+    /// What happens to the VM when its kernel panics.
+    panic_policy: GuestPanicPolicy,
+    /// Vsock ports through which the VM and the host may connect to each other, if restricted.
+    pub vsock_firewall: Option<VsockFirewall>,
+    /// The host system properties which the VM may read.
+    pub exposed_host_properties: HostPropertyFilter,
+    /// The TAP interface of the VM, if it has network support, until it is deleted when the VM
+    /// dies.
+    pub network_tap: Mutex<Option<File>>,
+    /// Whether the VM can be migrated to a new crosvm process while it runs.
+    migratable: bool,
+    /// The migration of the VM to a new crosvm process, if one is in progress.
+    migration: Migration,
 }
 
 impl fmt::Display for VmInstance {
@@ -406,16 +864,39 @@ impl fmt::Display for VmInstance {
 impl VmInstance {
     /// Validates the given config and creates a new `VmInstance` but doesn't start running it.
     pub fn new(
-        config: CrosvmConfig,
+        mut config: CrosvmConfig,
         temporary_directory: PathBuf,
         requester_uid: u32,
         requester_debug_pid: i32,
         vm_context: VmContext,
     ) -> Result<VmInstance, Error> {
         validate_config(&config)?;
+        let config_summary = config_summary(&config);
         let cid = config.cid;
         let name = config.name.clone();
+        let persistent_name = config.persistent_name.clone();
         let protected = config.protected;
+        let debug_config = config.debug_config.clone();
+        let storage_snapshots = config.storage_snapshots.take();
+        let storage_growth = config.storage_growth.take();
+        let memory_mib = config.memory_mib;
+        let qos_class = config.qos_class;
+        let labels = config.labels.clone();
+        let panic_policy = config.panic_policy;
+        let vsock_firewall = config.vsock_firewall.clone();
+        let exposed_host_properties = config.exposed_host_properties.clone();
+        let network_tap = Mutex::new(try_clone_file(&config.tap)?);
+        // crosvm can't snapshot protected VMs, nor the devices it doesn't fully emulate itself.
+        let migratable = !config.protected
+            && config.vfio_devices.is_empty()
+            && config.vhost_user_devices.is_empty()
+            && config.display_config.is_none()
+            && config.gpu_config.is_none();
+        let memory_hotplug = config.max_memory_mib.map(|max_memory_mib| MemoryHotplug {
+            min_mib: config.memory_mib.get(),
+            max_mib: max_memory_mib.get(),
+            current_mib: Mutex::new(config.memory_mib.get()),
+        });
         let requester_uid_name = User::from_uid(Uid::from_raw(requester_uid))
             .ok()
             .flatten()
@@ -426,6 +907,8 @@ impl VmInstance {
             cid,
             crosvm_control_socket_path: temporary_directory.join("crosvm.sock"),
             name,
+            persistent_name,
+            persistent_registered: AtomicBool::new(false),
             protected,
             temporary_directory,
             requester_uid,
@@ -436,8 +919,31 @@ impl VmInstance {
             payload_state: Mutex::new(PayloadState::Starting),
             payload_state_updated: Condvar::new(),
             requester_uid_name,
+            debug_config: Mutex::new(debug_config),
+            ramdump_output: Mutex::new(None),
+            host_file_requests: Default::default(),
+            payload_log: Default::default(),
+            snapshot_callback: Default::default(),
+            shutdown_callback: Default::default(),
+            payload_hold: Default::default(),
+            payload_watchdog: Default::default(),
+            storage_snapshots,
+            suspended: Mutex::new(false),
+            storage_growth,
+            memory_hotplug,
+            memory_mib,
+            qos_class,
+            labels,
+            deferred_start: Default::default(),
+            panic_policy,
+            vsock_firewall,
+            exposed_host_properties,
+            network_tap,
+            migratable,
+            migration: Default::default(),
         };
         info!("{} created", &instance);
+        instance.record_event(VmEventType::CREATED, &config_summary);
         Ok(instance)
     }
 
@@ -449,6 +955,7 @@ impl VmInstance {
         let ret = self.vm_state.lock().unwrap().start(self.clone());
         if ret.is_ok() {
             info!("{} started", &self);
+            self.record_event(VmEventType::STARTED, "");
         }
         ret.with_context(|| format!("{} failed to start", &self))
     }
@@ -456,30 +963,105 @@ impl VmInstance {
     /// Monitors the exit of the VM (i.e. termination of the `child` process). When that happens,
     /// handles the event by updating the state, noityfing the event to clients by calling
     /// callbacks, and removing temporary files for the VM.
+    ///
+    /// If `respawn_config` is given, crosvm is started again with it when the guest kernel panics
+    /// or crosvm crashes, see `GuestPanicPolicy::RESTART`, or when crosvm exits for the VM to be
+    /// migrated.
     fn monitor_vm_exit(
-        &self,
-        child: Arc<SharedChild>,
+        self: &Arc<Self>,
+        mut child: Arc<SharedChild>,
         mut failure_pipe_read: File,
         vfio_devices: Vec<VfioDevice>,
         tap: Option<File>,
+        respawn_config: Option<CrosvmConfig>,
     ) {
-        let result = child.wait();
-        match &result {
-            Err(e) => error!("Error waiting for crosvm({}) instance to die: {}", child.id(), e),
-            Ok(status) => {
-                info!("crosvm({}) exited with status {}", child.id(), status);
-                if let Some(exit_status_code) = status.code() {
-                    if exit_status_code == CROSVM_WATCHDOG_REBOOT_STATUS {
-                        info!("detected vcpu stall on crosvm");
+        let mut restarts = PanicRestarts::default();
+        let mut started = Instant::now();
+        let result = loop {
+            let result = child.wait();
+            match &result {
+                Err(e) => error!("Error waiting for crosvm({}) instance to die: {}", child.id(), e),
+                Ok(status) => {
+                    info!("crosvm({}) exited with status {}", child.id(), status);
+                    if let Some(exit_status_code) = status.code() {
+                        if exit_status_code == CROSVM_WATCHDOG_REBOOT_STATUS {
+                            info!("detected vcpu stall on crosvm");
+                        }
                     }
                 }
             }
-        }
+
+            let Some(respawn_config) = &respawn_config else { break result };
+            // Only the clean exit which crosvm was asked for hands the VM over to a new process.
+            let exited_cleanly = matches!(&result, Ok(status) if status.success());
+            if let Some(snapshot) = self.migration.take_snapshot(exited_cleanly) {
+                match self.restore_after_migration(respawn_config, snapshot) {
+                    // If the restore failed, the new process was killed, and is handled as a
+                    // crash once it exited.
+                    Ok(Some((new_child, new_failure_pipe_read))) => {
+                        child = new_child;
+                        failure_pipe_read = new_failure_pipe_read;
+                        continue;
+                    }
+                    Ok(None) => {
+                        self.migration.complete(Err(anyhow!("The VM was killed")));
+                        break result;
+                    }
+                    Err(e) => {
+                        error!("Failed to restore {self} in a new crosvm process: {e:?}");
+                        self.migration.complete(Err(e));
+                        break result;
+                    }
+                }
+            }
+            if respawn_config.panic_policy != GuestPanicPolicy::RESTART {
+                break result;
+            }
+            let cause = match &result {
+                Ok(status) if status.code() == Some(CROSVM_GUEST_PANIC_STATUS) => "a guest panic",
+                // Kills by virtmgr itself end the VM, as they mark it as being killed first.
+                Ok(status) if status.code() == Some(CROSVM_CRASH_STATUS) => "a crosvm crash",
+                Ok(status) if status.signal().is_some() => "a crosvm crash",
+                _ => break result,
+            };
+            let Some(backoff) = restarts.next_backoff(started.elapsed()) else {
+                warn!("{self} died {} times in a row, not restarting it", restarts.count);
+                break result;
+            };
+            warn!("{self} died of {cause}, restarting it in {backoff:?}");
+            self.handle_ramdump().unwrap_or_else(|e| error!("Error handling ramdump: {}", e));
+            if !self.wait_before_restart(backoff) {
+                break result;
+            }
+            match self.restart(respawn_config, cause) {
+                Ok(Some((new_child, new_failure_pipe_read))) => {
+                    self.record_event(
+                        VmEventType::RESTARTED,
+                        &format!("restart {}", restarts.count),
+                    );
+                    self.callbacks.notify_restarted(self.cid);
+                    child = new_child;
+                    failure_pipe_read = new_failure_pipe_read;
+                    started = Instant::now();
+                }
+                Ok(None) => break result,
+                Err(e) => {
+                    error!("Failed to restart {self} after {cause}: {e:?}");
+                    break result;
+                }
+            }
+        };
 
         let mut vm_state = self.vm_state.lock().unwrap();
         *vm_state = VmState::Dead;
         // Ensure that the mutex is released before calling the callbacks.
         drop(vm_state);
+        // Unblock the monitors of a warm VM which was never claimed.
+        self.payload_hold.release();
+        self.payload_watchdog.stop();
+        if let Some(storage_snapshots) = &self.storage_snapshots {
+            storage_snapshots.stop();
+        }
         info!("{} exited", &self);
 
         // Read the pipe to see if any failure reason is written
@@ -489,23 +1071,41 @@ impl VmInstance {
             Ok(len) if len > 0 => info!("VM returned failure reason '{}'", &failure_reason),
             _ => (),
         };
+        let (guest_crash, failure_reason) = split_guest_crash(&failure_reason);
 
         // In case of hangup, the pipe doesn't give us any information because the hangup can't be
         // detected on the VM side (otherwise, it isn't a hangup), but in the
-        // monitor_payload_hangup function below which updates the payload state to Hangup.
-        let failure_reason =
-            if failure_reason.is_empty() && self.payload_state() == PayloadState::Hangup {
-                Cow::from("HANGUP")
-            } else {
-                Cow::from(failure_reason)
-            };
+        // monitor_payload_hangup and monitor_boot_deadline functions below which update the
+        // payload state to Hangup. The same goes for a payload which stopped kicking its watchdog.
+        let failure_reason = if !failure_reason.is_empty() {
+            Cow::from(failure_reason)
+        } else if self.payload_state() == PayloadState::Hangup {
+            Cow::from("HANGUP")
+        } else if self.payload_watchdog.has_expired() {
+            Cow::from("PAYLOAD_WATCHDOG_EXPIRED")
+        } else {
+            Cow::from(failure_reason)
+        };
 
         self.handle_ramdump().unwrap_or_else(|e| error!("Error handling ramdump: {}", e));
 
         let death_reason = death_reason(&result, &failure_reason);
         let exit_signal = exit_signal(&result);
 
+        if let Some(guest_crash) = &guest_crash {
+            error!("{self} reported a {:?} crash: {}", guest_crash.kind, guest_crash.message);
+            self.callbacks.notify_guest_crash_reported(self.cid, guest_crash);
+        }
         self.callbacks.callback_on_died(self.cid, death_reason);
+        let mut detail = match exit_signal {
+            Some(signal) => format!("{death_reason:?} (signal {signal})"),
+            None => format!("{death_reason:?}"),
+        };
+        if let Some(guest_crash) = &guest_crash {
+            detail += &format!(", guest crash {:?}", guest_crash.kind);
+        }
+        self.record_event(VmEventType::DIED, &detail);
+        persistent_vm::unregister(self);
 
         let vm_metric = self.vm_metric.lock().unwrap();
         write_vm_exited_stats_sync(
@@ -513,6 +1113,7 @@ impl VmInstance {
             &self.name,
             death_reason,
             exit_signal,
+            guest_crash.as_ref().map(|guest_crash| guest_crash.kind),
             &vm_metric,
         );
 
@@ -521,6 +1122,8 @@ impl VmInstance {
             error!("Error removing temporary files from {:?}: {}", self.temporary_directory, e);
         });
 
+        // The firewall of the interface can't be changed once it is deleted.
+        self.network_tap.lock().unwrap().take();
         if let Some(tap_file) = tap {
             GLOBAL_SERVICE
                 .deleteTapInterface(&ParcelFileDescriptor::new(OwnedFd::from(tap_file)))
@@ -532,9 +1135,138 @@ impl VmInstance {
         drop(vfio_devices); // Cleanup devices.
     }
 
+    /// Returns whether the VM is being killed, e.g. by `kill()`, which takes the thread monitoring
+    /// the exit of crosvm.
+    fn is_being_killed(&self) -> bool {
+        !matches!(
+            &*self.vm_state.lock().unwrap(),
+            VmState::Running { monitor_vm_exit_thread: Some(_), .. }
+        )
+    }
+
+    /// Waits for `delay` before restarting the VM after it died. Returns false if the VM is
+    /// killed in the meantime, in which case it must not be restarted.
+    fn wait_before_restart(&self, delay: Duration) -> bool {
+        let deadline = Instant::now() + delay;
+        while Instant::now() < deadline {
+            if self.is_being_killed() {
+                return false;
+            }
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+        !self.is_being_killed()
+    }
+
+    /// Starts crosvm again with `config` in place of the crosvm process which exited, unless the
+    /// VM is being killed. Returns the new crosvm process and the pipe to read its failure reason
+    /// from.
+    fn respawn(
+        self: &Arc<Self>,
+        config: CrosvmConfig,
+    ) -> Result<Option<(Arc<SharedChild>, File)>, Error> {
+        let mut vm_state = self.vm_state.lock().unwrap();
+        // Holding the state until the new child is recorded prevents kill() from missing it.
+        let VmState::Running { child, monitor_vm_exit_thread: Some(_), .. } = &mut *vm_state else {
+            return Ok(None);
+        };
+        let (failure_pipe_read, failure_pipe_write) = create_pipe()?;
+        let new_child =
+            Arc::new(run_vm(config, &self.crosvm_control_socket_path, failure_pipe_write)?);
+        *child = new_child.clone();
+        drop(vm_state);
+        // The new crosvm process runs the vCPUs.
+        *self.suspended.lock().unwrap() = false;
+
+        let instance = self.clone();
+        let child_monitor_status = new_child.clone();
+        thread::spawn(move || instance.monitor_vm_status(child_monitor_status));
+        Ok(Some((new_child, failure_pipe_read)))
+    }
+
+    /// Restores `snapshot` in a new crosvm process, once crosvm exited for the VM to be migrated,
+    /// and completes the migration once the new process resumed the restored VM. If it fails to,
+    /// it is killed.
+    fn restore_after_migration(
+        self: &Arc<Self>,
+        config: &CrosvmConfig,
+        snapshot: PathBuf,
+    ) -> Result<Option<(Arc<SharedChild>, File)>, Error> {
+        let mut config = config.try_clone()?;
+        config.restore_snapshot = Some(snapshot);
+        let Some((new_child, failure_pipe_read)) = self.respawn(config)? else {
+            return Ok(None);
+        };
+        match self.resume_restored(&new_child) {
+            Ok(()) => {
+                info!("{self} restored in a new crosvm process");
+                self.migration.complete(Ok(()));
+            }
+            Err(e) => {
+                error!("Failed to restore {self} in a new crosvm process: {e:?}");
+                if let Err(e) = new_child.kill() {
+                    error!("Error killing crosvm({}) instance: {e:?}", new_child.id());
+                }
+                self.migration.complete(Err(e));
+            }
+        }
+        Ok(Some((new_child, failure_pipe_read)))
+    }
+
+    /// Resumes the VM once `child` restored it, which it does before serving its control socket.
+    fn resume_restored(&self, child: &SharedChild) -> Result<(), Error> {
+        let deadline = Instant::now() + RESTORE_TIMEOUT;
+        loop {
+            if let Some(status) = child.try_wait()? {
+                bail!("crosvm exited with {status} while restoring the VM");
+            }
+            match self.resume() {
+                Ok(()) => return Ok(()),
+                Err(e) if Instant::now() >= deadline => return Err(e),
+                Err(_) => thread::sleep(RESTORE_POLL_INTERVAL),
+            }
+        }
+    }
+
+    /// Starts crosvm again with `config` after the VM died of `cause`, unless the VM is being
+    /// killed. Returns the new crosvm process and the pipe to read its failure reason from.
+    fn restart(
+        self: &Arc<Self>,
+        config: &CrosvmConfig,
+        cause: &str,
+    ) -> Result<Option<(Arc<SharedChild>, File)>, Error> {
+        let Some((new_child, failure_pipe_read)) = self.respawn(config.try_clone()?)? else {
+            return Ok(None);
+        };
+        info!("{self} restarted after {cause}");
+
+        // The payload starts over in the new VM.
+        *self.payload_state.lock().unwrap() = PayloadState::Starting;
+        self.payload_watchdog.disarm();
+        if let Some(boot_deadline) = config.boot_deadline {
+            let instance = self.clone();
+            let child_monitor_deadline = new_child.clone();
+            let console_capture = config.console_capture.clone();
+            thread::spawn(move || {
+                instance.monitor_boot_deadline(
+                    child_monitor_deadline,
+                    boot_deadline,
+                    console_capture.as_ref(),
+                )
+            });
+        }
+        if config.detect_hangup {
+            let instance = self.clone();
+            let child_monitor_hangup = new_child.clone();
+            thread::spawn(move || instance.monitor_payload_hangup(child_monitor_hangup));
+        }
+        Ok(Some((new_child, failure_pipe_read)))
+    }
+
     /// Waits until payload is started, or timeout expires. When timeout occurs, kill
     /// the VM to prevent indefinite hangup and update the payload_state accordingly.
     fn monitor_payload_hangup(&self, child: Arc<SharedChild>) {
+        // The payload of a warm VM can't start before the VM is claimed.
+        self.payload_hold.wait_until_released();
         debug!("Starting to monitor hangup for Microdroid({})", child.id());
         let (state, result) = self
             .payload_state_updated
@@ -557,14 +1289,61 @@ impl VmInstance {
         }
     }
 
+    /// Waits until the payload is ready, or `deadline` expires. When it expires, kill the VM and
+    /// report the timeout to its owner along with the last console output.
+    fn monitor_boot_deadline(
+        &self,
+        child: Arc<SharedChild>,
+        deadline: Duration,
+        console_capture: Option<&ConsoleCapture>,
+    ) {
+        // The payload of a warm VM can't start before the VM is claimed.
+        self.payload_hold.wait_until_released();
+        let (state, result) = self
+            .payload_state_updated
+            .wait_timeout_while(self.payload_state.lock().unwrap(), deadline, |s| {
+                *s < PayloadState::Ready
+            })
+            .unwrap();
+        drop(state); // we are not interested in state
+        let child_still_running = child.try_wait().ok() == Some(None);
+        if !result.timed_out() || !child_still_running {
+            return;
+        }
+        error!("Payload of {self} wasn't ready within {deadline:?} of booting. Shutting down.");
+        let console = console_capture.map(ConsoleCapture::contents).unwrap_or_default();
+        let message =
+            format!("Payload wasn't ready within {deadline:?}. Last console output:\n{console}");
+        self.callbacks.notify_error(self.cid, ErrorCode::BOOT_TIMEOUT, &message);
+        self.record_event(VmEventType::ERROR, &format!("{:?}: {message}", ErrorCode::BOOT_TIMEOUT));
+        if let Err(e) = self.update_payload_state(PayloadState::Hangup) {
+            warn!("{e:?}");
+        }
+        if let Err(e) = self.kill() {
+            error!("Error stopping VM with CID {} after its boot deadline: {:?}", self.cid, e);
+        }
+    }
+
+    /// Kills the VM if its payload started the watchdog and then stopped kicking it in time.
+    pub fn monitor_payload_watchdog(&self) {
+        if !self.payload_watchdog.wait_until_expired() {
+            return;
+        }
+        error!("Payload of {self} stopped kicking its watchdog. Shutting down.");
+        if let Err(e) = self.kill() {
+            error!("Error stopping VM with CID {} after its watchdog expired: {:?}", self.cid, e);
+        }
+    }
+
     fn monitor_vm_status(&self, child: Arc<SharedChild>) {
         let pid = child.id();
 
         loop {
             {
-                // Check VM state
+                // Check VM state, crosvm may also have been replaced after a guest panic.
                 let vm_state = &*self.vm_state.lock().unwrap();
-                if let VmState::Dead = vm_state {
+                let VmState::Running { child: current, .. } = vm_state else { break };
+                if !Arc::ptr_eq(current, &child) {
                     break;
                 }
 
@@ -592,6 +1371,71 @@ impl VmInstance {
         }
     }
 
+    /// Snapshots the encrypted storage on the schedule of the owner, until the VM exits.
+    fn snapshot_storage_periodically(&self) {
+        let Some(storage_snapshots) = &self.storage_snapshots else {
+            return;
+        };
+        while storage_snapshots.wait_for_next() {
+            if let Err(e) = self.snapshot_storage(storage_snapshots) {
+                error!("Failed to snapshot the storage of {self}: {e:?}");
+            }
+        }
+    }
+
+    fn snapshot_storage(&self, storage_snapshots: &StorageSnapshots) -> Result<(), Error> {
+        // Holding the lock keeps the VM from being suspended or resumed by its owner meanwhile.
+        let suspended = self.suspended.lock().unwrap();
+        if *suspended {
+            // Resuming the devices would also resume the vCPUs, which must stay suspended.
+            info!("Skipping the storage snapshot of {self}, which is suspended");
+            return Ok(());
+        }
+        // The snapshot is crash-consistent either way, as the VM is suspended while it is taken.
+        if !self.snapshot_callback.pre_snapshot(QUIESCE_WINDOW) {
+            warn!("Snapshotting the storage of {self} without the payload being quiescent");
+        }
+        // Suspending the devices as well as the vCPUs completes the pending disk requests, so that
+        // the snapshot has every write which the guest saw complete.
+        match vm_control::client::handle_request(
+            &VmRequest::SuspendVm,
+            &self.crosvm_control_socket_path,
+        ) {
+            Ok(VmResponse::Ok) => {}
+            e => bail!("Failed to suspend VM: {e:?}"),
+        }
+        let result = storage_snapshots.take();
+        match vm_control::client::handle_request(
+            &VmRequest::ResumeVm,
+            &self.crosvm_control_socket_path,
+        ) {
+            Ok(VmResponse::Ok) => result,
+            e => bail!("Failed to resume VM: {e:?}"),
+        }
+    }
+
+    /// Overwrites the encrypted storage with one of its snapshots, `generation` 0 being the most
+    /// recent one. The VM must not be running.
+    pub fn rollback_storage(&self, generation: usize) -> Result<(), Error> {
+        let Some(storage_snapshots) = &self.storage_snapshots else {
+            bail!("The storage of the VM isn't snapshotted");
+        };
+        // Holding the state prevents the VM from being started during the rollback.
+        let vm_state = self.vm_state.lock().unwrap();
+        if matches!(*vm_state, VmState::Running { .. }) {
+            bail!("The VM is running");
+        }
+        storage_snapshots.rollback(generation)
+    }
+
+    /// Appends an event to the log of the VM instance, which its owner can read once the VM is
+    /// gone.
+    pub fn record_event(&self, event_type: VmEventType, detail: &str) {
+        if let Err(e) = self.vm_context.global_context.recordEvent(event_type, detail) {
+            error!("Failed to record {event_type:?} event of {self}: {e:?}");
+        }
+    }
+
     /// Returns the last reported state of the VM payload.
     pub fn payload_state(&self) -> PayloadState {
         *self.payload_state.lock().unwrap()
@@ -615,7 +1459,7 @@ impl VmInstance {
     pub fn kill(&self) -> Result<(), Error> {
         let monitor_vm_exit_thread = {
             let vm_state = &mut *self.vm_state.lock().unwrap();
-            if let VmState::Running { child, monitor_vm_exit_thread } = vm_state {
+            if let VmState::Running { child, monitor_vm_exit_thread, .. } = vm_state {
                 let id = child.id();
                 debug!("Killing crosvm({})", id);
                 // TODO: Talk to crosvm to shutdown cleanly.
@@ -637,6 +1481,49 @@ impl VmInstance {
         Ok(())
     }
 
+    /// Shuts the VM down in stages before `deadline`: the payload is asked to make its state
+    /// consistent, then crosvm is asked to exit, which flushes the disk images, and it is killed if
+    /// it is still running at the deadline. Returns whether the VM shut down cleanly.
+    pub fn shutdown(&self, deadline: Instant) -> Result<bool, Error> {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let child = match &*self.vm_state.lock().unwrap() {
+            VmState::Running { child, .. } => child.clone(),
+            _ => return Ok(true),
+        };
+
+        let quiesce_window = deadline.saturating_duration_since(Instant::now()).min(QUIESCE_WINDOW);
+        // Payloads which don't listen to shutdown requests are at least made quiescent.
+        let quiescent = match self.shutdown_callback.request_shutdown(quiesce_window) {
+            Some(acknowledged) => acknowledged,
+            None => self.snapshot_callback.pre_snapshot(quiesce_window),
+        };
+        if !quiescent {
+            warn!("Shutting {self} down without the payload being quiescent");
+        }
+        match vm_control::client::handle_request(&VmRequest::Exit, &self.crosvm_control_socket_path)
+        {
+            Ok(VmResponse::Ok) => {}
+            e => warn!("Failed to ask crosvm({}) to exit: {e:?}", child.id()),
+        }
+        while Instant::now() < deadline {
+            if child.try_wait().context("Failed to check whether crosvm exited")?.is_some() {
+                info!("{self} shut down");
+                self.shutdown_callback.join(SHUTDOWN_CALLBACK_JOIN_TIMEOUT);
+                return Ok(true);
+            }
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+
+        warn!("{self} didn't shut down in time, killing it");
+        self.record_event(VmEventType::SHUTDOWN_TIMED_OUT, &timeout.as_millis().to_string());
+        if let Err(e) = self.kill() {
+            // The VM may have died in the meantime.
+            warn!("Failed to kill {self}: {e:?}");
+        }
+        self.shutdown_callback.join(SHUTDOWN_CALLBACK_JOIN_TIMEOUT);
+        Ok(false)
+    }
+
     /// Responds to memory-trimming notifications by inflating the virtio
     /// balloon to reclaim guest memory.
     pub fn get_memory_balloon(&self) -> Result<u64, Error> {
@@ -672,18 +1559,77 @@ impl VmInstance {
         Ok(())
     }
 
-    /// Checks if ramdump has been created. If so, send it to tombstoned.
+    /// Returns the current memory of the VM, in MiB, if memory hotplug is enabled.
+    pub fn hotplug_memory_mib(&self) -> Option<u32> {
+        self.memory_hotplug.as_ref().map(|hotplug| *hotplug.current_mib.lock().unwrap())
+    }
+
+    /// Returns the range of memory which the VM can be given with `set_memory`, in MiB, if memory
+    /// hotplug is enabled.
+    pub fn hotplug_memory_range(&self) -> Option<RangeInclusive<u32>> {
+        self.memory_hotplug.as_ref().map(|hotplug| hotplug.min_mib..=hotplug.max_mib)
+    }
+
+    /// Grows or shrinks the memory of the VM to `target_mib`, by plugging or unplugging memory of
+    /// its virtio-mem device. The maximum memory of the VM is already accounted towards the cap of
+    /// its owner, so this never exceeds the cap.
+    pub fn set_memory(&self, target_mib: u32) -> Result<(), Error> {
+        let hotplug = self.memory_hotplug.as_ref().context("Memory hotplug isn't enabled")?;
+        let mut current_mib = hotplug.current_mib.lock().unwrap();
+        let command =
+            VirtioMemControlCommand::Resize { plugged_bytes: hotplug.plugged_bytes(target_mib) };
+        match vm_control::client::handle_request(
+            &VmRequest::VirtioMemCommand(command),
+            &self.crosvm_control_socket_path,
+        ) {
+            Ok(VmResponse::Ok) => {}
+            e => bail!("Error resizing the virtio-mem device: {:?}", e),
+        }
+        info!("Set the memory of {self} from {} MiB to {target_mib} MiB", *current_mib);
+        *current_mib = target_mib;
+        Ok(())
+    }
+
+    /// Checks if ramdump has been created. If so, send it to tombstoned and to the output file
+    /// provided by the owner of the VM, if any.
     fn handle_ramdump(&self) -> Result<(), Error> {
         let ramdump_path = self.temporary_directory.join("ramdump");
         if !ramdump_path.as_path().try_exists()? {
             return Ok(());
         }
-        if std::fs::metadata(&ramdump_path)?.len() > 0 {
-            Self::send_ramdump_to_tombstoned(&ramdump_path)?;
+        if std::fs::metadata(&ramdump_path)?.len() == 0 {
+            return Ok(());
+        }
+        Self::send_ramdump_to_tombstoned(&ramdump_path)
+            .unwrap_or_else(|e| error!("Error sending ramdump to tombstoned: {e:?}"));
+
+        if let Some(mut output) = self.ramdump_output.lock().unwrap().take() {
+            let mut input = File::open(&ramdump_path)
+                .context(format!("Failed to open ramdump {:?} for reading", ramdump_path))?;
+            std::io::copy(&mut input, &mut output).context("Failed to write ramdump to output")?;
+            info!("Ramdump {:?} written to the output of {}", ramdump_path, &self);
+            self.callbacks.notify_ramdump_available(self.cid);
         }
         Ok(())
     }
 
+    /// Sets the file the ramdump is written to if the guest kernel crashes. Only the owner of a
+    /// debuggable VM with ramdump enabled can get it.
+    pub fn set_ramdump_output(&self, output: File) -> Result<(), Error> {
+        let debug_config = self.debug_config.lock().unwrap();
+        if debug_config.debug_level == DebugLevel::NONE {
+            bail!("Ramdump is only available for debuggable VMs");
+        }
+        if !debug_config.is_ramdump_needed() {
+            bail!("Ramdump is disabled for the VM");
+        }
+        if matches!(*self.vm_state.lock().unwrap(), VmState::Dead) {
+            bail!("The VM is dead");
+        }
+        *self.ramdump_output.lock().unwrap() = Some(output);
+        Ok(())
+    }
+
     fn send_ramdump_to_tombstoned(ramdump_path: &Path) -> Result<(), Error> {
         let mut input = File::open(ramdump_path)
             .context(format!("Failed to open ramdump {:?} for reading", ramdump_path))?;
@@ -705,25 +1651,128 @@ impl VmInstance {
 
     /// Suspends the VM
     pub fn suspend(&self) -> Result<(), Error> {
+        let mut suspended = self.suspended.lock().unwrap();
         match vm_control::client::handle_request(
             &VmRequest::SuspendVcpus,
             &self.crosvm_control_socket_path,
         ) {
-            Ok(VmResponse::Ok) => Ok(()),
+            Ok(VmResponse::Ok) => {
+                *suspended = true;
+                Ok(())
+            }
             e => bail!("Failed to suspend VM: {e:?}"),
         }
     }
 
     /// Resumes the suspended VM
     pub fn resume(&self) -> Result<(), Error> {
+        let mut suspended = self.suspended.lock().unwrap();
         match vm_control::client::handle_request(
             &VmRequest::ResumeVcpus,
             &self.crosvm_control_socket_path,
         ) {
-            Ok(VmResponse::Ok) => Ok(()),
+            Ok(VmResponse::Ok) => {
+                *suspended = false;
+                Ok(())
+            }
             e => bail!("Failed to resume: {e:?}"),
         }
     }
+
+    /// Migrates the running VM to a new crosvm process, see the `migration` module.
+    pub fn migrate(&self) -> Result<(), Error> {
+        ensure!(
+            self.migratable,
+            "Only non-protected VMs without assigned, vhost-user, display or GPU devices can be \
+             migrated"
+        );
+        ensure!(
+            matches!(&*self.vm_state.lock().unwrap(), VmState::Running { .. }),
+            "VM is not running"
+        );
+        let snapshot = self.temporary_directory.join(MIGRATION_SNAPSHOT_NAME);
+        self.migration.request(snapshot.clone())?;
+
+        if !self.snapshot_callback.pre_snapshot(QUIESCE_WINDOW) {
+            warn!("Migrating {self} without the payload being quiescent");
+        }
+        let result = match self.hand_over_to_new_crosvm(&snapshot) {
+            // The VM keeps running in the current crosvm process, unless that already exited.
+            Err(e) if self.migration.cancel() => {
+                self.resume().unwrap_or_else(|e| error!("Failed to resume {self}: {e:?}"));
+                Err(e)
+            }
+            _ => self.migration.wait(MIGRATION_TIMEOUT),
+        };
+        match std::fs::remove_dir_all(&snapshot) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                warn!("Failed to remove migration snapshot of {self}: {e:?}")
+            }
+            _ => {}
+        }
+        result?;
+
+        // The session of the guest to the VirtualMachineService was reset along with the other
+        // vsock connections of the VM. microdroid_manager sets it up again, registers the
+        // callbacks of the payload again and notifies it of the restore itself.
+        info!("{self} migrated to a new crosvm process");
+        self.record_event(VmEventType::MIGRATED, "");
+        Ok(())
+    }
+
+    /// Snapshots the suspended VM and asks crosvm to exit, for the VM to be restored in a new
+    /// crosvm process by `monitor_vm_exit`.
+    fn hand_over_to_new_crosvm(&self, snapshot: &Path) -> Result<(), Error> {
+        self.suspend()?;
+        let request = VmRequest::Snapshot(SnapshotCommand::Take {
+            snapshot_path: snapshot.to_owned(),
+            compress_memory: false,
+            encrypt: false,
+        });
+        match vm_control::client::handle_request(&request, &self.crosvm_control_socket_path) {
+            Ok(VmResponse::Ok) => {}
+            e => bail!("Failed to snapshot VM: {e:?}"),
+        }
+        self.migration.snapshot_taken()?;
+        match vm_control::client::handle_request(&VmRequest::Exit, &self.crosvm_control_socket_path)
+        {
+            Ok(VmResponse::Ok) => Ok(()),
+            e => bail!("Failed to ask crosvm to exit: {e:?}"),
+        }
+    }
+
+    /// Returns the effective debug configuration of the VM.
+    pub fn get_debug_config(&self) -> DebugConfigParcelable {
+        DebugConfigParcelable {
+            panicPolicy: self.panic_policy,
+            ..self.debug_config.lock().unwrap().to_parcelable()
+        }
+    }
+
+    /// Enables or disables a debug facility of the VM, which must not have been started yet.
+    pub fn set_debug_facility_enabled(
+        &self,
+        facility: DebugFacility,
+        enabled: bool,
+    ) -> Result<(), Error> {
+        let mut vm_state = self.vm_state.lock().unwrap();
+        let VmState::NotStarted { config } = &mut *vm_state else {
+            bail!("Debug facilities can't be changed once the VM has been started");
+        };
+        config.debug_config.set_facility_enabled(facility, enabled)?;
+
+        if !config.debug_config.is_ramdump_needed() {
+            config.ramdump = None;
+        } else if config.ramdump.is_none() {
+            let ramdump_path = self.temporary_directory.join("ramdump");
+            let ramdump = File::create(ramdump_path).context("Failed to prepare ramdump file")?;
+            config.ramdump = Some(ramdump);
+        }
+
+        self.debug_config.lock().unwrap().clone_from(&config.debug_config);
+        info!("{} debug facility {facility:?} set to {enabled}", &self);
+        Ok(())
+    }
 }
 
 impl Rss {
@@ -809,6 +1858,20 @@ fn get_rss(pid: u32) -> Result<Rss> {
     Ok(Rss { vm: rss_vm_total, crosvm: rss_crosvm_total })
 }
 
+/// Summarizes the config of a VM for its event log.
+fn config_summary(config: &CrosvmConfig) -> String {
+    format!(
+        "name={:?} protected={} debug_level={:?} memory_mib={} cpus={} disks={} panic_policy={:?}",
+        config.name,
+        config.protected,
+        config.debug_config.debug_level,
+        config.memory_mib,
+        config.cpus.map_or(1, NonZeroU32::get),
+        config.disks.len(),
+        config.panic_policy,
+    )
+}
+
 fn death_reason(result: &Result<ExitStatus, io::Error>, mut failure_reason: &str) -> DeathReason {
     if let Some((reason, info)) = failure_reason.split_once('|') {
         // Separator indicates extra context information is present after the failure name.
@@ -837,6 +1900,7 @@ fn death_reason(result: &Result<ExitStatus, io::Error>, mut failure_reason: &str
                 return DeathReason::MICRODROID_UNKNOWN_RUNTIME_ERROR
             }
             "HANGUP" => return DeathReason::HANGUP,
+            "PAYLOAD_WATCHDOG_EXPIRED" => return DeathReason::PAYLOAD_WATCHDOG_EXPIRED,
             _ => {}
         }
         match status.code() {
@@ -845,6 +1909,7 @@ fn death_reason(result: &Result<ExitStatus, io::Error>, mut failure_reason: &str
             Some(CROSVM_START_ERROR_STATUS) => DeathReason::START_FAILED,
             Some(CROSVM_REBOOT_STATUS) => DeathReason::REBOOT,
             Some(CROSVM_CRASH_STATUS) => DeathReason::CRASH,
+            Some(CROSVM_GUEST_PANIC_STATUS) => DeathReason::GUEST_PANIC,
             Some(CROSVM_WATCHDOG_REBOOT_STATUS) => DeathReason::WATCHDOG_REBOOT,
             Some(_) => DeathReason::UNKNOWN,
         }
@@ -904,9 +1969,7 @@ fn run_vm(
         .arg("--cid")
         .arg(config.cid.to_string());
 
-    if system_properties::read_bool("hypervisor.memory_reclaim.supported", false)?
-        && !config.no_balloon
-    {
+    if is_balloon_enabled(&config)? {
         command.arg("--balloon-page-reporting");
     } else {
         command.arg("--no-balloon");
@@ -926,11 +1989,9 @@ fn run_vm(
             _ => command.arg("--protected-vm"),
         };
 
-        // 3 virtio-console devices + vsock = 4.
-        let virtio_pci_device_count = 4 + config.disks.len();
-        // crosvm virtio queue has 256 entries, so 2 MiB per device (2 pages per entry) should be
-        // enough.
-        let swiotlb_size_mib = 2 * virtio_pci_device_count as u32;
+        // crosvm writes the size of the bounce buffer to the restricted DMA pool of the DT, where
+        // pvmfw validates it.
+        let swiotlb_size_mib = swiotlb_size_mib(config.swiotlb_mib, config.disks.len());
         command.arg("--swiotlb").arg(swiotlb_size_mib.to_string());
 
         // b/346770542 for consistent "usable" memory across protected and non-protected VMs.
@@ -956,8 +2017,19 @@ fn run_vm(
         command.arg("--params").arg("printk.devkmsg=on");
         command.arg("--params").arg("console=hvc0");
     }
+    if config.debug_config.debug_level == DebugLevel::NONE
+        && config.debug_config.is_adb_overridden()
+    {
+        // bootconfig.normal disables adb, but the owner asked for it.
+        command.arg("--params").arg("androidboot.adb.enabled=1");
+    }
 
     command.arg("--mem").arg(memory_mib.to_string());
+    if let Some(max_memory_mib) = config.max_memory_mib {
+        // The memory beyond the initial memory is plugged in as the VM grows.
+        let hotplug_mib = max_memory_mib.get() - config.memory_mib.get();
+        command.arg("--virtio-mem").arg(format!("size={}", u64::from(hotplug_mib) * MIB));
+    }
 
     if let Some(cpus) = config.cpus {
         command.arg("--cpus").arg(cpus.to_string());
@@ -966,10 +2038,8 @@ fn run_vm(
     if config.host_cpu_topology {
         if cfg!(virt_cpufreq) && check_if_all_cpus_allowed()? {
             command.arg("--host-cpu-topology");
-            cfg_if::cfg_if! {
-                if #[cfg(any(target_arch = "aarch64"))] {
-                    command.arg("--virt-cpufreq");
-                }
+            if Arch::host().supports_virt_cpufreq() {
+                command.arg("--virt-cpufreq");
             }
         } else if let Some(cpus) = get_num_cpus() {
             command.arg("--cpus").arg(cpus.to_string());
@@ -1043,12 +2113,34 @@ fn run_vm(
         command.arg("--params").arg(params);
     }
 
+    match config.panic_policy {
+        // The guest kernel notifies its panics through the pvpanic device, which crosvm exits on.
+        GuestPanicPolicy::NOTIFY | GuestPanicPolicy::RESTART => {
+            command.arg("--pvpanic");
+        }
+        // The guest kernel waits forever after a panic rather than rebooting. This comes after the
+        // params of the config so that it overrides any panic timeout they set.
+        GuestPanicPolicy::HALT => {
+            command.arg("--params").arg("panic=0");
+        }
+        _ => {}
+    }
+
     for disk in config.disks {
         // Disk file locking is disabled because of missing SELinux policies.
         command.arg("--block").arg(format!(
-            "path={},ro={},lock=false",
+            "path={},ro={},lock=false{}",
             add_preserved_fd(&mut preserved_fds, disk.image),
             !disk.writable,
+            if disk.direct_io { ",o_direct=true" } else { "" },
+        ));
+    }
+
+    for pmem_disk in config.pmem_disks {
+        command.arg("--pmem").arg(format!(
+            "path={},ro={}",
+            add_preserved_fd(&mut preserved_fds, pmem_disk.image),
+            !pmem_disk.writable,
         ));
     }
 
@@ -1159,10 +2251,32 @@ fn run_vm(
         }
     }
 
+    if cfg!(paravirtualized_devices) {
+        for device in config.vhost_user_devices {
+            let device_type = device.crosvm_type()?;
+            command.arg("--vhost-user").arg(format!(
+                "{device_type},socket={}",
+                add_preserved_fd(&mut preserved_fds, device.socket)
+            ));
+        }
+    }
+
+    for shared_directory in config.shared_directories {
+        // Read-only directories are on read-only mounts, so the writes of the guest fail whatever
+        // it mounts them as.
+        let path = add_preserved_fd(&mut preserved_fds, shared_directory.directory);
+        command.arg("--shared-dir").arg(format!("{path}:{}:type=fs", shared_directory.tag));
+    }
+
     if config.hugepages {
         command.arg("--hugepages");
     }
 
+    if let Some(snapshot) = &config.restore_snapshot {
+        // The VM is resumed once it has been restored, see `VmInstance::resume_restored`.
+        command.arg("--restore").arg(snapshot).arg("--suspended");
+    }
+
     if config.boost_uclamp {
         command.arg("--boost-uclamp");
     }
@@ -1196,6 +2310,14 @@ fn run_vm(
 
     print_crosvm_args(&command);
 
+    // Keeps the categories of the app owning the VM, which virtmgr runs with.
+    let _exec_context = if config.vendor_domain {
+        let context = getcon()?.with_selinux_type(CROSVM_VENDOR_DOMAIN)?;
+        info!("Running crosvm in {context}");
+        Some(setexeccon(&context)?)
+    } else {
+        None
+    };
     let result = SharedChild::spawn(&mut command)?;
     debug!("Spawned crosvm({}).", result.id());
     Ok(result)
@@ -1209,6 +2331,42 @@ fn validate_config(config: &CrosvmConfig) -> Result<(), Error> {
     if config.bootloader.is_some() && (config.kernel.is_some() || config.initrd.is_some()) {
         bail!("Can't have both bootloader and kernel/initrd image.");
     }
+    for disk in config.disks.iter().filter(|disk| disk.direct_io) {
+        let file_type = disk.image.metadata()?.file_type();
+        if !file_type.is_file() && !file_type.is_block_device() {
+            bail!("O_DIRECT disk must be a regular file or a block device, not {file_type:?}");
+        }
+    }
+    if config.protected && !config.pmem_disks.is_empty() {
+        // The guest can't share the memory pmem disks are mapped to with the host.
+        bail!("Protected VMs can't have pmem disks");
+    }
+    if let Some(swiotlb_mib) = config.swiotlb_mib {
+        if !config.protected {
+            bail!("Only protected VMs have a bounce buffer");
+        }
+        if swiotlb_mib.get() >= config.memory_mib.get() / 2 {
+            bail!(
+                "Bounce buffer of {swiotlb_mib} MiB isn't less than half of memory {} MiB",
+                config.memory_mib
+            );
+        }
+    }
+    if let Some(max_memory_mib) = config.max_memory_mib {
+        if max_memory_mib <= config.memory_mib {
+            bail!("Max memory {max_memory_mib} MiB isn't above memory {} MiB", config.memory_mib);
+        }
+    }
+    let mut shared_directory_tags = HashSet::new();
+    for shared_directory in &config.shared_directories {
+        if !shared_directory_tags.insert(&shared_directory.tag) {
+            bail!("Duplicate shared directory tag {}", shared_directory.tag);
+        }
+    }
+    if config.panic_policy == GuestPanicPolicy::RESTART && !config.vhost_user_devices.is_empty() {
+        // The connections to the backends are used up by the first crosvm.
+        bail!("VMs with vhost-user devices can't be restarted after a guest panic");
+    }
     let version = Version::parse(CROSVM_PLATFORM_VERSION).unwrap();
     if !config.platform_version.matches(&version) {
         bail!(
@@ -1222,6 +2380,11 @@ fn validate_config(config: &CrosvmConfig) -> Result<(), Error> {
     Ok(())
 }
 
+fn is_balloon_enabled(config: &CrosvmConfig) -> Result<bool, Error> {
+    Ok(system_properties::read_bool("hypervisor.memory_reclaim.supported", false)?
+        && !config.no_balloon)
+}
+
 /// Print arguments of the crosvm command. In doing so, /proc/self/fd/XX is annotated with the
 /// actual file path if the FD is backed by a regular file. If not, the /proc path is printed
 /// unmodified.
@@ -1290,3 +2453,71 @@ fn create_crosvm_control_listener(crosvm_control_socket_path: &Path) -> Result<O
     socket::listen(&fd, socket::Backlog::new(127).unwrap()).context("listen failed")?;
     Ok(fd)
 }
+
+/// Returns the size of the bounce buffer of a protected VM with `disk_count` disks, given the size
+/// requested by its owner, if any. The buffer is given to the VM on top of its memory.
+pub fn swiotlb_size_mib(swiotlb_mib: Option<NonZeroU32>, disk_count: usize) -> u32 {
+    swiotlb_mib.map_or_else(
+        || {
+            // 3 virtio-console devices + vsock = 4.
+            let virtio_pci_device_count = 4 + disk_count;
+            // crosvm virtio queue has 256 entries, so 2 MiB per device (2 pages per entry) should
+            // be enough.
+            2 * virtio_pci_device_count as u32
+        },
+        NonZeroU32::get,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swiotlb_sized_from_devices_by_default() {
+        assert_eq!(swiotlb_size_mib(None, 0), 8);
+        assert_eq!(swiotlb_size_mib(None, 3), 14);
+    }
+
+    #[test]
+    fn swiotlb_size_requested_by_owner() {
+        assert_eq!(swiotlb_size_mib(NonZeroU32::new(64), 3), 64);
+    }
+
+    #[test]
+    fn panic_restart_backoff_doubles() {
+        let mut restarts = PanicRestarts::default();
+        let backoffs: Vec<_> = (0..MAX_PANIC_RESTARTS)
+            .map(|_| restarts.next_backoff(Duration::ZERO).unwrap().as_secs())
+            .collect();
+        assert_eq!(backoffs, [1, 2, 4, 8, 16]);
+    }
+
+    #[test]
+    fn panic_restarts_give_up_after_max() {
+        let mut restarts = PanicRestarts::default();
+        for _ in 0..MAX_PANIC_RESTARTS {
+            assert!(restarts.next_backoff(Duration::from_secs(1)).is_some());
+        }
+        assert_eq!(restarts.next_backoff(Duration::from_secs(1)), None);
+        assert_eq!(restarts.next_backoff(Duration::from_secs(1)), None);
+    }
+
+    #[test]
+    fn panic_restarts_reset_after_long_uptime() {
+        let mut restarts = PanicRestarts::default();
+        for _ in 0..MAX_PANIC_RESTARTS {
+            restarts.next_backoff(Duration::ZERO);
+        }
+        assert_eq!(restarts.next_backoff(PANIC_RESTART_RESET), Some(PANIC_RESTART_BACKOFF));
+        assert_eq!(restarts.count, 1);
+    }
+
+    #[test]
+    fn guest_panic_death_reason() {
+        let status = ExitStatus::from_raw(CROSVM_GUEST_PANIC_STATUS << 8);
+        assert_eq!(death_reason(&Ok(status), ""), DeathReason::GUEST_PANIC);
+        let status = ExitStatus::from_raw(CROSVM_REBOOT_STATUS << 8);
+        assert_eq!(death_reason(&Ok(status), ""), DeathReason::REBOOT);
+    }
+}
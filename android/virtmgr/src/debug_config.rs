@@ -15,9 +15,10 @@
 //! Functions for AVF debug policy and debug level
 
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
+    DebugConfig::DebugConfig as DebugConfigParcelable, DebugFacility::DebugFacility,
     VirtualMachineAppConfig::DebugLevel::DebugLevel, VirtualMachineConfig::VirtualMachineConfig,
 };
-use anyhow::{anyhow, Context, Error, Result};
+use anyhow::{anyhow, bail, Context, Error, Result};
 use libfdt::{Fdt, FdtError};
 use log::{info, warn};
 use rustutils::system_properties;
@@ -149,7 +150,7 @@ impl OwnedFdt {
 }
 
 /// Debug configurations for debug policy.
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct DebugPolicy {
     log: bool,
     ramdump: bool,
@@ -179,11 +180,33 @@ impl DebugPolicy {
     }
 }
 
+/// Debug facilities requested in the VM config or toggled on a VM after its creation, overriding
+/// the debug policy.
+#[derive(Clone, Debug, Default)]
+struct DebugOverrides {
+    adb: Option<bool>,
+    console_output: Option<bool>,
+    ramdump: Option<bool>,
+}
+
 /// Debug configurations for both debug level and debug policy
-#[derive(Debug, Default)]
+#[derive(Clone, Debug)]
 pub struct DebugConfig {
     pub debug_level: DebugLevel,
     debug_policy: DebugPolicy,
+    overrides: DebugOverrides,
+    ramdump_supported: bool,
+}
+
+impl Default for DebugConfig {
+    fn default() -> Self {
+        Self {
+            debug_level: Default::default(),
+            debug_policy: Default::default(),
+            overrides: Default::default(),
+            ramdump_supported: true,
+        }
+    }
 }
 
 impl DebugConfig {
@@ -194,7 +217,11 @@ impl DebugConfig {
             Default::default()
         });
 
-        Self { debug_level, debug_policy }
+        let mut debug_config = Self { debug_level, debug_policy, ..Default::default() };
+        if is_adb_requested(config) {
+            debug_config.overrides.adb = Some(true);
+        }
+        debug_config
     }
 
     fn get_debug_policy() -> Option<DebugPolicy> {
@@ -237,20 +264,142 @@ impl DebugConfig {
     /// Get whether console output should be configred for VM to leave console and adb log.
     /// Caller should create pipe and prepare for receiving VM log with it.
     pub fn should_prepare_console_output(&self) -> bool {
-        self.debug_level != DebugLevel::NONE || self.debug_policy.log || self.debug_policy.adb
+        self.overrides.console_output.unwrap_or(
+            self.debug_level != DebugLevel::NONE
+                || self.debug_policy.log
+                || self.debug_policy.adb
+                || self.is_adb_overridden(),
+        )
     }
 
     /// Get whether debug apexes (MICRODROID_REQUIRED_APEXES_DEBUG) are required.
     pub fn should_include_debug_apexes(&self) -> bool {
-        self.debug_level != DebugLevel::NONE || self.debug_policy.adb
+        self.debug_level != DebugLevel::NONE || self.debug_policy.adb || self.is_adb_overridden()
+    }
+
+    /// Get whether adb was requested in the VM config, so that the VM must be told to start adbd
+    /// although neither its debug level nor the debug policy enable it.
+    pub fn is_adb_overridden(&self) -> bool {
+        self.overrides.adb == Some(true)
     }
 
     /// Decision to support ramdump
     pub fn is_ramdump_needed(&self) -> bool {
-        self.debug_level != DebugLevel::NONE || self.debug_policy.ramdump
+        self.ramdump_supported
+            && self
+                .overrides
+                .ramdump
+                .unwrap_or(self.debug_level != DebugLevel::NONE || self.debug_policy.ramdump)
+    }
+
+    /// Marks ramdump as unsupported by the VM (e.g. by its kernel), regardless of the policy.
+    pub fn disable_ramdump(&mut self) {
+        self.ramdump_supported = false;
+    }
+
+    /// Overrides the debug policy for the given facility.
+    pub fn set_facility_enabled(&mut self, facility: DebugFacility, enabled: bool) -> Result<()> {
+        match facility {
+            DebugFacility::ADB => {
+                // Debug APEXes are added to the payload when the VM is created.
+                if enabled != self.should_include_debug_apexes() {
+                    bail!("adb can only be configured when the VM is created, with enableAdb");
+                }
+            }
+            DebugFacility::CONSOLE_OUTPUT => {
+                if !enabled && self.debug_level != DebugLevel::NONE {
+                    bail!("Can't disable console output of a debuggable VM");
+                }
+                self.overrides.console_output = Some(enabled);
+            }
+            DebugFacility::RAMDUMP => {
+                if enabled && !self.ramdump_supported {
+                    bail!("Ramdump isn't supported by the VM");
+                }
+                self.overrides.ramdump = Some(enabled);
+            }
+            _ => bail!("Unknown debug facility {facility:?}"),
+        }
+        Ok(())
+    }
+
+    /// Returns the effective debug configuration, as exposed to clients.
+    pub fn to_parcelable(&self) -> DebugConfigParcelable {
+        DebugConfigParcelable {
+            debugLevel: self.debug_level,
+            adb: self.should_include_debug_apexes(),
+            consoleOutput: self.should_prepare_console_output(),
+            ramdump: self.is_ramdump_needed(),
+            // Filled in by the VM, see `VmInstance::get_debug_config`.
+            ..Default::default()
+        }
     }
 }
 
+/// Returns whether the VM config asks for adb regardless of the debug level of the VM.
+pub fn is_adb_requested(config: &VirtualMachineConfig) -> bool {
+    match config {
+        VirtualMachineConfig::AppConfig(config) => {
+            config.customConfig.as_ref().is_some_and(|c| c.enableAdb)
+        }
+        VirtualMachineConfig::RawConfig(_) => false,
+    }
+}
+
+/// Values which an allow-listed kernel command line parameter may take.
+enum KernelParamValue {
+    /// The parameter is a flag, e.g. `quiet`.
+    None,
+    /// The parameter takes one of the listed values.
+    OneOf(&'static [&'static str]),
+    /// The parameter takes any value, e.g. the console of `earlycon`.
+    Any,
+}
+
+/// Kernel command line parameters which clients may add without the debuggable VM improvements,
+/// as they only change how the kernel reports what happens in the VM.
+const ALLOWED_KERNEL_CMDLINE_PARAMS: &[(&str, KernelParamValue)] = &[
+    ("debug", KernelParamValue::None),
+    ("earlycon", KernelParamValue::Any),
+    ("ignore_loglevel", KernelParamValue::None),
+    ("initcall_debug", KernelParamValue::None),
+    ("loglevel", KernelParamValue::OneOf(&["0", "1", "2", "3", "4", "5", "6", "7"])),
+    ("printk.devkmsg", KernelParamValue::OneOf(&["on", "off", "ratelimit"])),
+    ("quiet", KernelParamValue::None),
+];
+
+/// Allow-listed parameters which are only allowed for VMs with `DebugLevel::FULL`, as they make
+/// the kernel write to a console regardless of the debug level.
+const FULL_DEBUG_KERNEL_CMDLINE_PARAMS: &[&str] = &["earlycon"];
+
+/// Checks that `param` is a single kernel command line parameter from the allow-list, with a
+/// value it accepts, for a VM with `debug_level`.
+pub fn check_kernel_cmdline_param(param: &str, debug_level: DebugLevel) -> Result<()> {
+    if param.is_empty() || param.contains(|c: char| c.is_whitespace() || c == '"') {
+        bail!("Malformed kernel command line parameter {param:?}");
+    }
+    let (name, value) = match param.split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (param, None),
+    };
+    let Some((_, allowed)) = ALLOWED_KERNEL_CMDLINE_PARAMS.iter().find(|(n, _)| *n == name) else {
+        bail!("Kernel command line parameter {name:?} isn't allowed");
+    };
+    let valid = match (allowed, value) {
+        (KernelParamValue::None, None) => true,
+        (KernelParamValue::OneOf(values), Some(value)) => values.contains(&value),
+        (KernelParamValue::Any, _) => true,
+        _ => false,
+    };
+    if !valid {
+        bail!("Invalid value for kernel command line parameter {param:?}");
+    }
+    if FULL_DEBUG_KERNEL_CMDLINE_PARAMS.contains(&name) && debug_level != DebugLevel::FULL {
+        bail!("Kernel command line parameter {name:?} is only allowed for debuggable VMs");
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -328,4 +477,80 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_allowed_kernel_cmdline_params() {
+        assert!(check_kernel_cmdline_param("quiet", DebugLevel::NONE).is_ok());
+        assert!(check_kernel_cmdline_param("loglevel=7", DebugLevel::NONE).is_ok());
+        assert!(check_kernel_cmdline_param("printk.devkmsg=on", DebugLevel::NONE).is_ok());
+        assert!(
+            check_kernel_cmdline_param("earlycon=uart8250,mmio,0x3f8", DebugLevel::FULL).is_ok()
+        );
+    }
+
+    #[test]
+    fn test_disallowed_kernel_cmdline_params() {
+        assert!(check_kernel_cmdline_param("", DebugLevel::NONE).is_err());
+        assert!(check_kernel_cmdline_param("init=/bin/sh", DebugLevel::NONE).is_err());
+        assert!(check_kernel_cmdline_param("loglevel=8", DebugLevel::NONE).is_err());
+        assert!(check_kernel_cmdline_param("loglevel", DebugLevel::NONE).is_err());
+        assert!(check_kernel_cmdline_param("quiet=1", DebugLevel::NONE).is_err());
+        assert!(check_kernel_cmdline_param("earlycon", DebugLevel::NONE).is_err());
+        assert!(
+            check_kernel_cmdline_param("earlycon=uart8250,mmio,0x3f8", DebugLevel::NONE).is_err()
+        );
+        assert!(check_kernel_cmdline_param("quiet init=/bin/sh", DebugLevel::NONE).is_err());
+        assert!(
+            check_kernel_cmdline_param("loglevel=\"7 init=/bin/sh\"", DebugLevel::NONE).is_err()
+        );
+    }
+
+    #[test]
+    fn test_set_facility_enabled() -> Result<()> {
+        let mut debug_config = DebugConfig::new_with_debug_level(DebugLevel::NONE);
+        assert!(!debug_config.should_prepare_console_output());
+        assert!(!debug_config.is_ramdump_needed());
+
+        debug_config.set_facility_enabled(DebugFacility::CONSOLE_OUTPUT, true)?;
+        debug_config.set_facility_enabled(DebugFacility::RAMDUMP, true)?;
+        assert!(debug_config.should_prepare_console_output());
+        assert!(debug_config.is_ramdump_needed());
+
+        debug_config.set_facility_enabled(DebugFacility::RAMDUMP, false)?;
+        assert!(!debug_config.is_ramdump_needed());
+
+        assert!(debug_config.set_facility_enabled(DebugFacility::ADB, true).is_err());
+        debug_config.set_facility_enabled(DebugFacility::ADB, false)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_adb_requested_in_config() -> Result<()> {
+        let mut debug_config = DebugConfig::new_with_debug_level(DebugLevel::NONE);
+        assert!(!debug_config.should_include_debug_apexes());
+        debug_config.overrides.adb = Some(true);
+        assert!(debug_config.is_adb_overridden());
+        assert!(debug_config.should_include_debug_apexes());
+        assert!(debug_config.should_prepare_console_output());
+        assert!(debug_config.to_parcelable().adb);
+
+        // adb can't be taken away once the debug APEXes are in the payload.
+        assert!(debug_config.set_facility_enabled(DebugFacility::ADB, false).is_err());
+        debug_config.set_facility_enabled(DebugFacility::ADB, true)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_facility_enabled_on_debuggable_vm() -> Result<()> {
+        let mut debug_config = DebugConfig::new_with_debug_level(DebugLevel::FULL);
+        assert!(debug_config.set_facility_enabled(DebugFacility::CONSOLE_OUTPUT, false).is_err());
+
+        debug_config.disable_ramdump();
+        assert!(!debug_config.is_ramdump_needed());
+        assert!(debug_config.set_facility_enabled(DebugFacility::RAMDUMP, true).is_err());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,164 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Starts of VMs deferred until virtualizationservice grants them the host memory they need, and
+//! reclaim of the memory of running VMs so that more important ones can start.
+
+use crate::aidl::{start_vm, GLOBAL_SERVICE};
+use crate::crosvm::{VmInstance, VmState};
+use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::ErrorCode::ErrorCode;
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
+    DeferredStartStatus::DeferredStartStatus, DeprecationWarning::DeprecationWarning,
+};
+use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::{
+    IMemoryReservation::IMemoryReservation,
+    IMemoryReservationCallback::{BnMemoryReservationCallback, IMemoryReservationCallback},
+    IVmMemoryReclaimer::{BnVmMemoryReclaimer, IVmMemoryReclaimer},
+};
+use anyhow::{anyhow, Context};
+use avflog::LogResult;
+use binder::{BinderFeatures, ExceptionCode, Interface, IntoBinderResult, Strong};
+use log::{error, info};
+use std::sync::{Arc, Mutex, Weak};
+
+/// The memory reservation of a VM waiting to start, if any.
+#[derive(Debug, Default)]
+pub struct DeferredStart(Mutex<Option<Strong<dyn IMemoryReservation>>>);
+
+impl DeferredStart {
+    /// Returns the progress of the VM waiting to start.
+    pub fn status(&self) -> binder::Result<DeferredStartStatus> {
+        let reservation = self.0.lock().unwrap();
+        let Some(reservation) = &*reservation else {
+            return Err(anyhow!("VM isn't waiting to start"))
+                .or_binder_exception(ExceptionCode::ILLEGAL_STATE);
+        };
+        Ok(DeferredStartStatus {
+            queuePosition: reservation.getQueuePosition()?.max(0),
+            estimatedDelayMillis: reservation.getEstimatedDelayMillis()?,
+        })
+    }
+
+    /// Cancels the deferred start, if the VM is still waiting.
+    pub fn cancel(&self) {
+        // Dropping the reservation cancels it.
+        self.0.lock().unwrap().take();
+    }
+}
+
+/// Starts the VM once virtualizationservice grants it the memory it needs.
+pub fn start_when_possible(
+    instance: &Arc<VmInstance>,
+    deprecation_warnings: &[DeprecationWarning],
+) -> binder::Result<()> {
+    if !matches!(&*instance.vm_state.lock().unwrap(), VmState::NotStarted { .. }) {
+        return Err(anyhow!("VM already started"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_STATE);
+    }
+    // Held until the reservation is stored, so that the callback doesn't miss it.
+    let mut reservation = instance.deferred_start.0.lock().unwrap();
+    if reservation.is_some() {
+        return Err(anyhow!("VM is already waiting to start"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_STATE);
+    }
+    let callback = StartOnGrant {
+        instance: Arc::downgrade(instance),
+        deprecation_warnings: deprecation_warnings.to_vec(),
+    };
+    let callback = BnMemoryReservationCallback::new_binder(callback, BinderFeatures::default());
+    *reservation = Some(GLOBAL_SERVICE.reserveMemory(
+        instance.memory_mib.get().into(),
+        instance.qos_class,
+        &callback,
+    )?);
+    info!("{instance} waits for {} MiB of memory to start", instance.memory_mib);
+    Ok(())
+}
+
+struct StartOnGrant {
+    /// The VM holds its reservation, which holds this callback.
+    instance: Weak<VmInstance>,
+    deprecation_warnings: Vec<DeprecationWarning>,
+}
+
+impl Interface for StartOnGrant {}
+
+impl IMemoryReservationCallback for StartOnGrant {
+    fn onGranted(&self) -> binder::Result<()> {
+        let Some(instance) = self.instance.upgrade() else {
+            return Ok(());
+        };
+        let Some(reservation) = instance.deferred_start.0.lock().unwrap().take() else {
+            // The deferred start was cancelled in the meantime.
+            return Ok(());
+        };
+        info!("{instance} got the memory it waited for");
+        if let Err(e) = start_vm(&instance, &self.deprecation_warnings) {
+            error!("Failed to start {instance} after waiting for memory: {e:?}");
+            instance.callbacks.notify_error(
+                instance.cid,
+                ErrorCode::UNKNOWN,
+                &format!("Failed to start the VM: {e}"),
+            );
+        }
+        // Only release the memory once the VM uses it.
+        drop(reservation);
+        Ok(())
+    }
+}
+
+/// Lets virtualizationservice take memory back from the VM, if its memory can be changed while it
+/// runs, so that VMs of a higher QoS class can start.
+pub fn set_memory_reclaimer(instance: &Arc<VmInstance>) -> binder::Result<()> {
+    if instance.hotplug_memory_range().is_none() {
+        return Ok(());
+    }
+    // Like the shutdown handler, the reclaimer is held as long as the VM context.
+    let reclaimer = VmMemoryReclaimer { instance: Arc::downgrade(instance) };
+    let reclaimer = BnVmMemoryReclaimer::new_binder(reclaimer, BinderFeatures::default());
+    instance.vm_context.global_context.setMemoryReclaimer(&reclaimer, instance.qos_class)
+}
+
+struct VmMemoryReclaimer {
+    instance: Weak<VmInstance>,
+}
+
+impl Interface for VmMemoryReclaimer {}
+
+impl IVmMemoryReclaimer for VmMemoryReclaimer {
+    fn reclaimMemory(&self, memory_mib: i64) -> binder::Result<i64> {
+        let Some(instance) = self.instance.upgrade() else {
+            return Ok(0);
+        };
+        if !matches!(&*instance.vm_state.lock().unwrap(), VmState::Running { .. }) {
+            return Ok(0);
+        }
+        let (Some(range), Some(current_mib)) =
+            (instance.hotplug_memory_range(), instance.hotplug_memory_mib())
+        else {
+            return Ok(0);
+        };
+        let memory_mib = u32::try_from(memory_mib).unwrap_or(u32::MAX);
+        let target_mib = current_mib.saturating_sub(memory_mib).max(*range.start());
+        if target_mib == current_mib {
+            return Ok(0);
+        }
+        instance
+            .set_memory(target_mib)
+            .with_context(|| format!("Error reclaiming memory from {instance}"))
+            .with_log()
+            .or_service_specific_exception(-1)?;
+        Ok((current_mib - target_mib).into())
+    }
+}
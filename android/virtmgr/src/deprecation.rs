@@ -0,0 +1,155 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Staged deprecation of VM configuration fields. Uses of deprecated fields are reported to the
+//! caller as warnings, and can be turned into errors with the device config before the fields are
+//! removed.
+
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
+    DeprecationWarning::DeprecationWarning, VirtualMachineConfig::VirtualMachineConfig,
+};
+use anyhow::{bail, Result};
+use log::{error, warn};
+use rustutils::system_properties;
+
+/// Device config flag listing the IDs of the deprecations to enforce as errors, separated by
+/// commas, or "all".
+const SYSPROP_DEPRECATIONS_AS_ERRORS: &str =
+    "persist.device_config.virtualization_framework_native.deprecations_as_errors";
+
+struct Deprecation {
+    id: &'static str,
+    message: &'static str,
+    replacement: &'static str,
+    is_used: fn(&VirtualMachineConfig) -> bool,
+}
+
+const DEPRECATIONS: &[Deprecation] = &[
+    Deprecation {
+        id: "legacy_instance_image",
+        message: "The VM instance is only identified by its instance.img",
+        replacement: "Allocate an instance ID with IVirtualizationService.allocateInstanceId() \
+                      and set it as instanceId",
+        is_used: uses_legacy_instance_image,
+    },
+    Deprecation {
+        id: "negative_memory_mib",
+        message: "A negative memoryMib selects the default amount of memory",
+        replacement: "Set memoryMib to 0",
+        is_used: uses_negative_memory_mib,
+    },
+];
+
+fn uses_legacy_instance_image(config: &VirtualMachineConfig) -> bool {
+    // Instance IDs are only allocated if the llpvm_changes feature is enabled.
+    match config {
+        VirtualMachineConfig::AppConfig(config) => {
+            cfg!(llpvm_changes) && config.instanceId == [0; 64]
+        }
+        VirtualMachineConfig::RawConfig(_) => false,
+    }
+}
+
+fn uses_negative_memory_mib(config: &VirtualMachineConfig) -> bool {
+    match config {
+        VirtualMachineConfig::AppConfig(config) => config.memoryMib < 0,
+        VirtualMachineConfig::RawConfig(config) => config.memoryMib < 0,
+    }
+}
+
+/// Returns the warnings about the deprecated fields used by `config`, which are also logged.
+/// Fails if one of them is enforced as an error by the device config.
+pub fn check_deprecations(config: &VirtualMachineConfig) -> Result<Vec<DeprecationWarning>> {
+    let enforced = system_properties::read(SYSPROP_DEPRECATIONS_AS_ERRORS)
+        .unwrap_or_else(|e| {
+            error!("Failed to read {SYSPROP_DEPRECATIONS_AS_ERRORS}: {e:?}");
+            None
+        })
+        .unwrap_or_default();
+    check_deprecations_with(config, &enforced)
+}
+
+fn check_deprecations_with(
+    config: &VirtualMachineConfig,
+    enforced: &str,
+) -> Result<Vec<DeprecationWarning>> {
+    let is_enforced = |id| enforced.split(',').map(str::trim).any(|e| e == "all" || e == id);
+    let mut warnings = vec![];
+    for deprecation in DEPRECATIONS.iter().filter(|d| (d.is_used)(config)) {
+        if is_enforced(deprecation.id) {
+            bail!(
+                "{} ({}), which is no longer supported. {}.",
+                deprecation.message,
+                deprecation.id,
+                deprecation.replacement
+            );
+        }
+        warn!(
+            "{} ({}), which is deprecated. {}.",
+            deprecation.message, deprecation.id, deprecation.replacement
+        );
+        warnings.push(DeprecationWarning {
+            id: deprecation.id.to_owned(),
+            message: deprecation.message.to_owned(),
+            replacement: deprecation.replacement.to_owned(),
+        });
+    }
+    Ok(warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
+        VirtualMachineAppConfig::VirtualMachineAppConfig,
+        VirtualMachineRawConfig::VirtualMachineRawConfig,
+    };
+
+    fn ids(warnings: &[DeprecationWarning]) -> Vec<&str> {
+        warnings.iter().map(|w| w.id.as_str()).collect()
+    }
+
+    #[test]
+    fn deprecated_fields_are_reported() -> Result<()> {
+        let config = VirtualMachineConfig::AppConfig(VirtualMachineAppConfig {
+            memoryMib: -1,
+            ..Default::default()
+        });
+        let warnings = check_deprecations_with(&config, "")?;
+        if cfg!(llpvm_changes) {
+            assert_eq!(vec!["legacy_instance_image", "negative_memory_mib"], ids(&warnings));
+        } else {
+            assert_eq!(vec!["negative_memory_mib"], ids(&warnings));
+        }
+
+        let config = VirtualMachineConfig::RawConfig(VirtualMachineRawConfig::default());
+        assert!(check_deprecations_with(&config, "")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn enforced_deprecations_are_errors() -> Result<()> {
+        let config = VirtualMachineConfig::AppConfig(VirtualMachineAppConfig {
+            memoryMib: -1,
+            ..Default::default()
+        });
+        assert!(check_deprecations_with(&config, "negative_memory_mib").is_err());
+        assert!(check_deprecations_with(&config, "foo, negative_memory_mib").is_err());
+        assert!(check_deprecations_with(&config, "all").is_err());
+
+        let warnings = check_deprecations_with(&config, "foo,bar")?;
+        assert!(ids(&warnings).contains(&"negative_memory_mib"));
+        Ok(())
+    }
+}
@@ -14,17 +14,44 @@
 
 //! This module support creating AFV related overlays, that can then be appended to DT by VM.
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use cstr::cstr;
 use fsfdt::FsFdt;
 use libfdt::{Fdt, FdtError};
 use std::ffi::CStr;
-use std::path::Path;
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
 
 pub(crate) const AVF_NODE_NAME: &CStr = cstr!("avf");
-pub(crate) const UNTRUSTED_NODE_NAME: &CStr = cstr!("untrusted");
 pub(crate) const VM_DT_OVERLAY_PATH: &str = "vm_dt_overlay.dtbo";
 pub(crate) const VM_DT_OVERLAY_MAX_SIZE: usize = 2000;
+pub(crate) const VENDOR_DT_OVERLAY_MAX_SIZE: usize = 64 * 1024;
+
+const FRAGMENT_OVERLAY_PATH: &CStr = cstr!("/fragment@0/__overlay__");
+const AVF_OVERLAY_PATH: &CStr = cstr!("/fragment@0/__overlay__/avf");
+const UNTRUSTED_OVERLAY_PATH: &CStr = cstr!("/fragment@0/__overlay__/avf/untrusted");
+const LOCAL_FIXUPS_NODE_NAME: &CStr = cstr!("__local_fixups__");
+
+/// Seeds the digests of vendor DT overlays, so that clients can't precompute colliding overlays.
+static DIGEST_STATE: LazyLock<RandomState> = LazyLock::new(RandomState::new);
+
+/// The part of the VM DT overlay which doesn't depend on the VM instance, as last built by this
+/// process. Clients usually start their VMs with the same vendor DT overlays, which saves reading
+/// the host reference DT and merging the vendor DT overlays again.
+static BASE_OVERLAY_CACHE: Mutex<Option<(BaseOverlayKey, Vec<u8>)>> = Mutex::new(None);
+
+/// Identifies the inputs of a base VM DT overlay. The vendor DT overlays are passed by the client
+/// and are keyed by their digest, which doesn't depend on how they are encoded. The host reference
+/// DT is keyed by its path alone: it is read from the live DT in /proc/device-tree, which is fixed
+/// once the kernel has booted, and digesting it would mean reading all of it, which is what the
+/// cache saves.
+#[derive(Debug, Eq, PartialEq)]
+struct BaseOverlayKey {
+    dt_path: Option<PathBuf>,
+    vendor_overlay_digests: Vec<u64>,
+}
 
 /// Create a Device tree overlay containing the provided proc style device tree & properties!
 /// # Arguments
@@ -33,8 +60,10 @@ pub(crate) const VM_DT_OVERLAY_MAX_SIZE: usize = 2000;
 ///   host provided properties such as `instance-id`.
 /// * `trusted_props` - Include a property in /avf node. This overwrites nodes included with
 ///   `dt_path`. In pVM, pvmfw will reject if it doesn't match the value in pvmfw config.
+/// * `vendor_overlays` - Vendor DT overlays, as returned by `read_vendor_dt_overlay`, whose
+///   fragments are merged into the overlay.
 ///
-/// Example: with `create_device_tree_overlay(_, _, [("instance-id", _),], [("digest", _),])`
+/// Example: with `create_device_tree_overlay(_, _, [("instance-id", _),], [("digest", _),], [])`
 /// ```
 ///   {
 ///     fragment@0 {
@@ -54,29 +83,28 @@ pub(crate) fn create_device_tree_overlay<'a>(
     dt_path: Option<&'a Path>,
     untrusted_props: &[(&'a CStr, &'a [u8])],
     trusted_props: &[(&'a CStr, &'a [u8])],
+    vendor_overlays: Vec<Vec<u8>>,
 ) -> Result<&'a mut Fdt> {
-    if dt_path.is_none() && untrusted_props.is_empty() && trusted_props.is_empty() {
+    if dt_path.is_none()
+        && untrusted_props.is_empty()
+        && trusted_props.is_empty()
+        && vendor_overlays.is_empty()
+    {
         return Err(anyhow!("Expected at least one device tree addition"));
     }
 
-    let fdt =
-        Fdt::create_empty_tree(buffer).map_err(|e| anyhow!("Failed to create empty Fdt: {e:?}"))?;
-    let mut fragment = fdt
-        .root_mut()
-        .add_subnode(cstr!("fragment@0"))
-        .map_err(|e| anyhow!("Failed to add fragment node: {e:?}"))?;
-    fragment
-        .setprop(cstr!("target-path"), b"/\0")
-        .map_err(|e| anyhow!("Failed to set target-path property: {e:?}"))?;
-    let overlay = fragment
-        .add_subnode(cstr!("__overlay__"))
-        .map_err(|e| anyhow!("Failed to add __overlay__ node: {e:?}"))?;
-    let avf =
-        overlay.add_subnode(AVF_NODE_NAME).map_err(|e| anyhow!("Failed to add avf node: {e:?}"))?;
+    let base = cached_base_overlay(dt_path, vendor_overlays)?;
+    let Some(base_buffer) = buffer.get_mut(..base.len()) else {
+        bail!("Base DT overlay doesn't fit in {} bytes", buffer.len());
+    };
+    base_buffer.copy_from_slice(&base);
+    let fdt = Fdt::from_mut_slice(buffer)
+        .map_err(|e| anyhow!("Failed to copy base DT overlay: {e:?}"))?;
+    fdt.unpack().map_err(|e| anyhow!("Failed to unpack DT overlay: {e:?}"))?;
 
     if !untrusted_props.is_empty() {
-        let mut untrusted = avf
-            .add_subnode(UNTRUSTED_NODE_NAME)
+        let mut untrusted = fdt
+            .add_node_path(UNTRUSTED_OVERLAY_PATH)
             .map_err(|e| anyhow!("Failed to add untrusted node: {e:?}"))?;
         for (name, value) in untrusted_props {
             untrusted
@@ -85,14 +113,9 @@ pub(crate) fn create_device_tree_overlay<'a>(
         }
     }
 
-    // Read dt_path from host DT and overlay onto fdt.
-    if let Some(path) = dt_path {
-        fdt.overlay_onto(cstr!("/fragment@0/__overlay__"), path)?;
-    }
-
     if cfg!(tpu_assignable_device) {
         let mut avf = fdt
-            .node_mut(cstr!("/fragment@0/__overlay__/avf"))
+            .node_mut(AVF_OVERLAY_PATH)
             .map_err(|e| anyhow!("Failed to search avf node: {e:?}"))?
             .ok_or(anyhow!("Failed to get avf node"))?;
         let vendor_digest = cstr!("vendor_hashtree_descriptor_root_digest");
@@ -111,7 +134,7 @@ pub(crate) fn create_device_tree_overlay<'a>(
         }
     } else if !trusted_props.is_empty() {
         let mut avf = fdt
-            .node_mut(cstr!("/fragment@0/__overlay__/avf"))
+            .node_mut(AVF_OVERLAY_PATH)
             .map_err(|e| anyhow!("Failed to search avf node: {e:?}"))?
             .ok_or(anyhow!("Failed to get avf node"))?;
         for (name, value) in trusted_props {
@@ -125,14 +148,145 @@ pub(crate) fn create_device_tree_overlay<'a>(
     Ok(fdt)
 }
 
+/// Returns the base VM DT overlay for the given inputs, from the cache if they didn't change since
+/// it was last built.
+fn cached_base_overlay(dt_path: Option<&Path>, vendor_overlays: Vec<Vec<u8>>) -> Result<Vec<u8>> {
+    let key = BaseOverlayKey {
+        dt_path: dt_path.map(Path::to_path_buf),
+        vendor_overlay_digests: vendor_overlays
+            .iter()
+            .map(|overlay| vendor_overlay_digest(overlay))
+            .collect::<Result<_>>()?,
+    };
+    let mut cache = BASE_OVERLAY_CACHE.lock().unwrap();
+    if let Some((cached_key, base)) = &*cache {
+        if *cached_key == key {
+            return Ok(base.clone());
+        }
+    }
+    let base = build_base_overlay(dt_path, vendor_overlays)?;
+    *cache = Some((key, base.clone()));
+    Ok(base)
+}
+
+fn vendor_overlay_digest(overlay: &[u8]) -> Result<u64> {
+    let fdt_err = |e: FdtError| anyhow!("Failed to digest vendor DT overlay: {e:?}");
+    let mut hasher = DIGEST_STATE.build_hasher();
+    Fdt::from_slice(overlay).and_then(|fdt| fdt.digest(&mut hasher)).map_err(fdt_err)?;
+    Ok(hasher.finish())
+}
+
+/// Builds the fragment of the VM DT overlay with the /avf node, the host reference DT at
+/// `dt_path` and the content of the vendor DT overlays.
+fn build_base_overlay(dt_path: Option<&Path>, vendor_overlays: Vec<Vec<u8>>) -> Result<Vec<u8>> {
+    let vendor_overlays_size: usize = vendor_overlays.iter().map(Vec::len).sum();
+    let mut buffer = vec![0_u8; VM_DT_OVERLAY_MAX_SIZE + vendor_overlays_size];
+    let fdt = Fdt::create_empty_tree(&mut buffer)
+        .map_err(|e| anyhow!("Failed to create empty Fdt: {e:?}"))?;
+    let mut fragment = fdt
+        .root_mut()
+        .add_subnode(cstr!("fragment@0"))
+        .map_err(|e| anyhow!("Failed to add fragment node: {e:?}"))?;
+    fragment
+        .setprop(cstr!("target-path"), b"/\0")
+        .map_err(|e| anyhow!("Failed to set target-path property: {e:?}"))?;
+    fdt.add_node_path(AVF_OVERLAY_PATH).map_err(|e| anyhow!("Failed to add avf node: {e:?}"))?;
+
+    // Read dt_path from host DT and overlay onto fdt.
+    if let Some(path) = dt_path {
+        fdt.overlay_onto(FRAGMENT_OVERLAY_PATH, path)?;
+    }
+
+    for mut vendor_overlay in vendor_overlays {
+        apply_vendor_dt_overlay(fdt, &mut vendor_overlay)?;
+    }
+
+    fdt.pack().map_err(|e| anyhow!("Failed to pack base DT overlay, {e:?}"))?;
+    let size = fdt.as_slice().len();
+    buffer.truncate(size);
+    Ok(buffer)
+}
+
+/// Reads a vendor DT overlay and checks that it can be merged into the VM DT overlay.
+///
+/// Vendor fragments can only target "/" with a target-path, as the base DT isn't known to
+/// virtmgr, and can't modify the /avf node. The returned buffer is larger than the overlay to
+/// leave room for rebasing its fragments.
+pub(crate) fn read_vendor_dt_overlay(reader: impl Read) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    reader.take(VENDOR_DT_OVERLAY_MAX_SIZE as u64 + 1).read_to_end(&mut buffer)?;
+    if buffer.len() > VENDOR_DT_OVERLAY_MAX_SIZE {
+        bail!("Vendor DT overlay is larger than {VENDOR_DT_OVERLAY_MAX_SIZE} bytes");
+    }
+    let overlay =
+        Fdt::from_slice(&buffer).map_err(|e| anyhow!("Invalid vendor DT overlay: {e:?}"))?;
+    validate_vendor_dt_overlay(overlay)?;
+
+    // Rebasing a fragment only grows its target-path, which is always smaller than the fragment.
+    buffer.resize(buffer.len() * 2, 0);
+    Ok(buffer)
+}
+
+fn validate_vendor_dt_overlay(overlay: &Fdt) -> Result<()> {
+    let fdt_err = |e: FdtError| anyhow!("Failed to read vendor DT overlay: {e:?}");
+    for fragment in overlay.root().subnodes().map_err(fdt_err)? {
+        let name = fragment.name().map_err(fdt_err)?;
+        match name.to_bytes() {
+            b"__local_fixups__" => continue,
+            b"__fixups__" => bail!("Labels of the base DT can't be referenced"),
+            b"__symbols__" => bail!("Symbols can't be exported"),
+            _ => {}
+        }
+        if fragment.getprop(cstr!("target")).map_err(fdt_err)?.is_some() {
+            bail!("Fragment {name:?} targets a phandle");
+        }
+        if fragment.getprop_str(cstr!("target-path")).map_err(fdt_err)? != Some(cstr!("/")) {
+            bail!("Fragment {name:?} doesn't target the root node");
+        }
+        let content = fragment
+            .subnode(cstr!("__overlay__"))
+            .map_err(fdt_err)?
+            .ok_or_else(|| anyhow!("Fragment {name:?} has no __overlay__ node"))?;
+        if content.subnode(AVF_NODE_NAME).map_err(fdt_err)?.is_some() {
+            bail!("Fragment {name:?} modifies the /avf node");
+        }
+    }
+    Ok(())
+}
+
+fn apply_vendor_dt_overlay(fdt: &mut Fdt, buffer: &mut [u8]) -> Result<()> {
+    let overlay = Fdt::from_mut_slice(buffer)
+        .map_err(|e| anyhow!("Failed to load vendor DT overlay: {e:?}"))?;
+    overlay.unpack().map_err(|e| anyhow!("Failed to unpack vendor DT overlay: {e:?}"))?;
+
+    // Fragments target "/" of the VM DT, which is the content of the VM DT overlay fragment.
+    let mut next = overlay.root_mut().first_subnode().map_err(|e| anyhow!("{e:?}"))?;
+    while let Some(mut fragment) = next {
+        let name = fragment.as_node().name().map_err(|e| anyhow!("{e:?}"))?;
+        if name != LOCAL_FIXUPS_NODE_NAME {
+            fragment
+                .setprop(cstr!("target-path"), FRAGMENT_OVERLAY_PATH.to_bytes_with_nul())
+                .map_err(|e| anyhow!("Failed to rebase vendor DT fragment: {e:?}"))?;
+        }
+        next = fragment.next_subnode().map_err(|e| anyhow!("{e:?}"))?;
+    }
+
+    // SAFETY: The vendor overlay is dropped by our caller after this call and the VM DT overlay is
+    // discarded if an error is returned.
+    unsafe { fdt.apply_overlay(overlay) }
+        .map_err(|e| anyhow!("Failed to apply vendor DT overlay: {e:?}"))?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use libfdt::FdtDiff;
 
     #[test]
     fn empty_overlays_not_allowed() {
         let mut buffer = vec![0_u8; VM_DT_OVERLAY_MAX_SIZE];
-        let res = create_device_tree_overlay(&mut buffer, None, &[], &[]);
+        let res = create_device_tree_overlay(&mut buffer, None, &[], &[], vec![]);
         assert!(res.is_err());
     }
 
@@ -141,9 +295,14 @@ mod tests {
         let mut buffer = vec![0_u8; VM_DT_OVERLAY_MAX_SIZE];
         let prop_name = cstr!("XOXO");
         let prop_val_input = b"OXOX";
-        let fdt =
-            create_device_tree_overlay(&mut buffer, None, &[(prop_name, prop_val_input)], &[])
-                .unwrap();
+        let fdt = create_device_tree_overlay(
+            &mut buffer,
+            None,
+            &[(prop_name, prop_val_input)],
+            &[],
+            vec![],
+        )
+        .unwrap();
 
         let prop_value_dt = fdt
             .node(cstr!("/fragment@0/__overlay__/avf/untrusted"))
@@ -160,9 +319,14 @@ mod tests {
         let mut buffer = vec![0_u8; VM_DT_OVERLAY_MAX_SIZE];
         let prop_name = cstr!("XOXOXO");
         let prop_val_input = b"OXOXOX";
-        let fdt =
-            create_device_tree_overlay(&mut buffer, None, &[], &[(prop_name, prop_val_input)])
-                .unwrap();
+        let fdt = create_device_tree_overlay(
+            &mut buffer,
+            None,
+            &[],
+            &[(prop_name, prop_val_input)],
+            vec![],
+        )
+        .unwrap();
 
         let prop_value_dt = fdt
             .node(cstr!("/fragment@0/__overlay__/avf"))
@@ -173,4 +337,92 @@ mod tests {
             .expect("Prop not found!");
         assert_eq!(prop_value_dt, prop_val_input, "Unexpected property value");
     }
+
+    fn build_vendor_overlay(target_path: &[u8], node_name: &CStr) -> Vec<u8> {
+        let mut buffer = vec![0_u8; VM_DT_OVERLAY_MAX_SIZE];
+        let fdt = Fdt::create_empty_tree(&mut buffer).unwrap();
+        let mut fragment = fdt.root_mut().add_subnode(cstr!("fragment@0")).unwrap();
+        fragment.setprop(cstr!("target-path"), target_path).unwrap();
+        let mut node =
+            fragment.add_subnode(cstr!("__overlay__")).unwrap().add_subnode(node_name).unwrap();
+        node.setprop(cstr!("compatible"), b"vendor,device\0").unwrap();
+        fdt.pack().unwrap();
+        fdt.as_slice().to_vec()
+    }
+
+    #[test]
+    fn vendor_overlay_test() {
+        let untrusted_props = [(cstr!("instance-id"), &b"id"[..])];
+        let mut base_buffer = vec![0_u8; VM_DT_OVERLAY_MAX_SIZE];
+        let base =
+            create_device_tree_overlay(&mut base_buffer, None, &untrusted_props, &[], vec![])
+                .unwrap();
+
+        let vendor_overlay = build_vendor_overlay(b"/\0", cstr!("vendor-device"));
+        let vendor_overlay = read_vendor_dt_overlay(vendor_overlay.as_slice()).unwrap();
+        let mut buffer = vec![0_u8; VM_DT_OVERLAY_MAX_SIZE + vendor_overlay.len()];
+        let fdt = create_device_tree_overlay(
+            &mut buffer,
+            None,
+            &untrusted_props,
+            &[],
+            vec![vendor_overlay],
+        )
+        .unwrap();
+
+        let vendor_device = fdt
+            .node(cstr!("/fragment@0/__overlay__/vendor-device"))
+            .unwrap()
+            .expect("/vendor-device node doesn't exist");
+        let compatible =
+            vendor_device.getprop(cstr!("compatible")).unwrap().expect("Prop not found!");
+        assert_eq!(compatible, b"vendor,device\0", "Unexpected property value");
+
+        // The vendor overlay only adds its node to the VM DT overlay.
+        let mut diffs = vec![];
+        base.diff(fdt, |diff| diffs.push(diff)).unwrap();
+        assert_eq!(diffs, [FdtDiff::NodeAdded(vendor_device)]);
+    }
+
+    #[test]
+    fn base_overlay_cache_keyed_by_vendor_overlay_digest() {
+        let overlay = build_vendor_overlay(b"/\0", cstr!("vendor-device"));
+        let overlay = read_vendor_dt_overlay(overlay.as_slice()).unwrap();
+        let other_overlay = build_vendor_overlay(b"/\0", cstr!("other-device"));
+        let other_overlay = read_vendor_dt_overlay(other_overlay.as_slice()).unwrap();
+        assert_ne!(
+            vendor_overlay_digest(&overlay).unwrap(),
+            vendor_overlay_digest(&other_overlay).unwrap()
+        );
+
+        // A differently encoded but equivalent overlay has the same digest.
+        let mut unpacked_overlay = overlay.clone();
+        Fdt::from_mut_slice(&mut unpacked_overlay).unwrap().unpack().unwrap();
+        assert_eq!(
+            vendor_overlay_digest(&overlay).unwrap(),
+            vendor_overlay_digest(&unpacked_overlay).unwrap()
+        );
+
+        let has_node =
+            |base: &[u8], path: &CStr| Fdt::from_slice(base).unwrap().node(path).unwrap().is_some();
+        let base = cached_base_overlay(None, vec![overlay]).unwrap();
+        assert!(has_node(&base, cstr!("/fragment@0/__overlay__/vendor-device")));
+        let base = cached_base_overlay(None, vec![unpacked_overlay]).unwrap();
+        assert!(has_node(&base, cstr!("/fragment@0/__overlay__/vendor-device")));
+        // A changed overlay isn't served from the cache.
+        let base = cached_base_overlay(None, vec![other_overlay]).unwrap();
+        assert!(has_node(&base, cstr!("/fragment@0/__overlay__/other-device")));
+        assert!(!has_node(&base, cstr!("/fragment@0/__overlay__/vendor-device")));
+    }
+
+    #[test]
+    fn invalid_vendor_overlays_not_allowed() {
+        let avf_overlay = build_vendor_overlay(b"/\0", AVF_NODE_NAME);
+        assert!(read_vendor_dt_overlay(avf_overlay.as_slice()).is_err());
+
+        let nested_overlay = build_vendor_overlay(b"/soc\0", cstr!("vendor-device"));
+        assert!(read_vendor_dt_overlay(nested_overlay.as_slice()).is_err());
+
+        assert!(read_vendor_dt_overlay(&b"not a dtbo"[..]).is_err());
+    }
 }
@@ -0,0 +1,84 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Crash reasons which guests report on their failure serial device before dying.
+//!
+//! The report is a line `GUEST_CRASH|<kind>|<message>`, written before any failure reason which
+//! the guest also reports, so that the failure reasons known by older versions keep their meaning.
+
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::GuestCrashReason::{
+    GuestCrashReason, Kind::Kind,
+};
+
+const GUEST_CRASH_PREFIX: &str = "GUEST_CRASH|";
+
+/// Longest message kept from a report, so that a guest can't make the host hold on to much data.
+const MAX_MESSAGE_LEN: usize = 256;
+
+/// Splits the crash reason which the guest reported, if any, off what it wrote to its failure
+/// serial device. Returns it with the remaining failure reason.
+pub fn split_guest_crash(failure: &str) -> (Option<GuestCrashReason>, &str) {
+    let Some(report) = failure.strip_prefix(GUEST_CRASH_PREFIX) else {
+        return (None, failure);
+    };
+    let (report, failure_reason) = report.split_once('\n').unwrap_or((report, ""));
+    let (kind, message) = report.split_once('|').unwrap_or((report, ""));
+    let kind = match kind {
+        "PAYLOAD_CRASHED" => Kind::PAYLOAD_CRASHED,
+        "BARE_METAL_PANIC" => Kind::BARE_METAL_PANIC,
+        _ => Kind::UNKNOWN,
+    };
+    let message = truncate(message.trim_end(), MAX_MESSAGE_LEN).to_owned();
+    (Some(GuestCrashReason { kind, message }), failure_reason)
+}
+
+fn truncate(s: &str, max_len: usize) -> &str {
+    let mut len = s.len().min(max_len);
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    &s[..len]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failure_reason_without_crash_is_unchanged() {
+        assert_eq!(split_guest_crash("HANGUP"), (None, "HANGUP"));
+        assert_eq!(split_guest_crash(""), (None, ""));
+    }
+
+    #[test]
+    fn crash_is_split_off_failure_reason() {
+        let (crash, failure_reason) = split_guest_crash(
+            "GUEST_CRASH|PAYLOAD_CRASHED|signal 11 (SIGSEGV)\nMICRODROID_UNKNOWN_RUNTIME_ERROR|x",
+        );
+        let crash = crash.unwrap();
+        assert_eq!(crash.kind, Kind::PAYLOAD_CRASHED);
+        assert_eq!(crash.message, "signal 11 (SIGSEGV)");
+        assert_eq!(failure_reason, "MICRODROID_UNKNOWN_RUNTIME_ERROR|x");
+    }
+
+    #[test]
+    fn unknown_kind_and_long_message() {
+        let report = format!("GUEST_CRASH|SOMETHING_NEW|{}\n", "é".repeat(MAX_MESSAGE_LEN));
+        let (crash, failure_reason) = split_guest_crash(&report);
+        let crash = crash.unwrap();
+        assert_eq!(crash.kind, Kind::UNKNOWN);
+        assert_eq!(crash.message, "é".repeat(MAX_MESSAGE_LEN / 2));
+        assert_eq!(failure_reason, "");
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Relaying of requests from the payload for a host file chosen by the user.
+//!
+//! The payload can't choose a host file by itself. Its request is relayed to the owner of the VM,
+//! which lets the user pick a file (e.g. with the system file picker) and provides it, or declines.
+//! Only the chosen file is then streamed, read-only, to the VM over vsock.
+//!
+//! The user may take minutes to choose, so the request returns as soon as it is relayed, with the
+//! vsock port on which the answer is sent. See IVirtualMachineService.requestHostFile.
+
+use crate::aidl::{Cid, VirtualMachineCallbacks};
+use crate::vm_connection::accept_from_vm;
+use android_system_virtualmachineservice::aidl::android::system::virtualmachineservice::IVirtualMachineService::{
+    HOST_FILE_DECLINED, HOST_FILE_PROVIDED,
+};
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use vsock::{VsockListener, VMADDR_CID_HOST};
+
+/// How long the owner of the VM has to provide a file, including the time it takes the user to
+/// pick one.
+const HOST_FILE_REQUEST_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How long the VM has to connect to the port on which the answer to its request is sent.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A request of the payload which the owner of the VM hasn't answered yet.
+#[derive(Debug)]
+struct PendingRequest {
+    id: i32,
+    sender: SyncSender<Option<File>>,
+}
+
+/// Requests of a VM for host files. A VM can only have one request pending at a time.
+#[derive(Debug, Default)]
+pub struct HostFileRequests {
+    next_id: Mutex<i32>,
+    pending: Arc<Mutex<Option<PendingRequest>>>,
+}
+
+impl HostFileRequests {
+    /// Asks the owner of the VM for a file of the given MIME type, without waiting for the answer.
+    /// Returns the host vsock port on which the answer is sent to the VM.
+    pub fn request(
+        &self,
+        callbacks: &VirtualMachineCallbacks,
+        cid: Cid,
+        mime_type: &str,
+    ) -> Result<u32> {
+        let listener = VsockListener::bind_with_cid_port(VMADDR_CID_HOST, libc::VMADDR_PORT_ANY)
+            .context("Failed to bind host file listener")?;
+        let port =
+            listener.local_addr().context("Failed to get host file listener address")?.port();
+
+        let (sender, receiver) = sync_channel(1);
+        let id = {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.is_some() {
+                bail!("Another host file request is pending");
+            }
+            let mut next_id = self.next_id.lock().unwrap();
+            let id = *next_id;
+            *next_id = next_id.wrapping_add(1);
+            *pending = Some(PendingRequest { id, sender });
+            id
+        };
+
+        info!("VM with CID {cid} requests a host file of type {mime_type:?} (request {id})");
+        callbacks.notify_host_file_requested(cid, id, mime_type);
+        let pending = self.pending.clone();
+        thread::spawn(move || {
+            match send_answer(&listener, cid, id, &receiver) {
+                Ok(Some(size)) => info!("Sent {size} bytes of host file to VM with CID {cid}"),
+                Ok(None) => info!("Host file request {id} of VM with CID {cid} was declined"),
+                Err(e) => warn!("Failed to answer host file request {id} of CID {cid}: {e:?}"),
+            }
+            pending.lock().unwrap().take_if(|pending| pending.id == id);
+        });
+        Ok(port)
+    }
+
+    /// Answers the pending request with the given ID, with the file chosen by the user or `None`
+    /// if the request is declined.
+    pub fn provide(&self, request_id: i32, file: Option<File>) -> Result<()> {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(request) = pending.take_if(|pending| pending.id == request_id) else {
+            bail!("No pending host file request {request_id}");
+        };
+        // The request may have timed out in the meantime, in which case the file is dropped.
+        let _ = request.sender.try_send(file);
+        Ok(())
+    }
+}
+
+/// Waits for the VM to connect and for the owner to answer, then sends the answer to the VM.
+/// Returns the size of the file sent, or `None` if the request was declined.
+fn send_answer(
+    listener: &VsockListener,
+    cid: Cid,
+    id: i32,
+    receiver: &Receiver<Option<File>>,
+) -> Result<Option<u64>> {
+    let mut stream = accept_from_vm(listener, cid, CONNECTION_TIMEOUT)?;
+    let file = match receiver.recv_timeout(HOST_FILE_REQUEST_TIMEOUT) {
+        Ok(file) => file,
+        Err(RecvTimeoutError::Timeout) => {
+            warn!("Host file request {id} of VM with CID {cid} timed out");
+            None
+        }
+        Err(RecvTimeoutError::Disconnected) => None,
+    };
+    let Some(mut file) = file else {
+        stream.write_all(&[HOST_FILE_DECLINED as u8])?;
+        return Ok(None);
+    };
+    stream.write_all(&[HOST_FILE_PROVIDED as u8])?;
+    Ok(Some(io::copy(&mut file, &mut stream)?))
+}
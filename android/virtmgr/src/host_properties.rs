@@ -0,0 +1,124 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Filter of the host system properties which the payload of a VM may read.
+
+use anyhow::{ensure, Result};
+use libc::uid_t;
+use rustutils::{system_properties, users::multiuser_get_app_id};
+
+/// Namespaces of the host properties which any owner may expose to its VMs. They describe the
+/// build and the device, which apps can read anyway, so virtmgr doesn't read them on behalf of
+/// apps which couldn't.
+const PUBLIC_NAMESPACES: &[&str] =
+    &["ro.build.version.", "ro.build.fingerprint", "ro.hardware", "ro.product.", "ro.soc."];
+
+/// Namespaces which owners which aren't apps, e.g. the shell running the vm tool, may also
+/// expose, for debugging.
+const NATIVE_NAMESPACES: &[&str] = &["debug.", "ro.debuggable"];
+
+/// The prefixes of the names of the host system properties exposed to a VM, as declared by its
+/// owner when the VM is created. No property is exposed by default.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct HostPropertyFilter {
+    prefixes: Vec<String>,
+}
+
+impl HostPropertyFilter {
+    /// Creates the filter of the properties which `owner_uid` exposes to its VM, which must be
+    /// within the namespaces which it may expose.
+    pub fn new(prefixes: &[String], owner_uid: uid_t) -> Result<HostPropertyFilter> {
+        const FIRST_APPLICATION_UID: uid_t = 10000;
+        let is_app = multiuser_get_app_id(owner_uid) >= FIRST_APPLICATION_UID;
+        for prefix in prefixes {
+            ensure!(is_valid_name(prefix), "Invalid host property prefix {prefix:?}");
+            let in_namespace = |namespace: &&str| prefix.starts_with(namespace);
+            ensure!(
+                PUBLIC_NAMESPACES.iter().any(in_namespace)
+                    || (!is_app && NATIVE_NAMESPACES.iter().any(in_namespace)),
+                "uid {owner_uid} can't expose host properties {prefix:?}"
+            );
+        }
+        Ok(HostPropertyFilter { prefixes: prefixes.to_vec() })
+    }
+
+    /// Returns whether the VM may read the host property `name`.
+    pub fn is_exposed(&self, name: &str) -> bool {
+        is_valid_name(name) && self.prefixes.iter().any(|prefix| name.starts_with(prefix))
+    }
+
+    /// Reads the host property `name`, failing unless it is exposed. Returns `None` if the
+    /// property isn't set.
+    pub fn read(&self, name: &str) -> Result<Option<String>> {
+        ensure!(self.is_exposed(name), "Host property {name:?} isn't exposed to the VM");
+        Ok(system_properties::read(name)?)
+    }
+}
+
+/// Returns whether `name` only has the characters allowed in the names of system properties.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-' | b':' | b'@'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APP_UID: uid_t = 1010123;
+    const SHELL_UID: uid_t = 2000;
+
+    #[test]
+    fn test_exposed_properties() -> Result<()> {
+        let filter = HostPropertyFilter::new(
+            &["ro.build.version.".into(), "ro.product.model".into()],
+            APP_UID,
+        )?;
+        assert!(filter.is_exposed("ro.build.version.sdk"));
+        assert!(filter.is_exposed("ro.product.model"));
+        assert!(!filter.is_exposed("ro.build.fingerprint"));
+        assert!(!filter.is_exposed("ro.build.version.sdk\n"));
+        assert!(filter.read("persist.sys.timezone").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_nothing_exposed_by_default() {
+        let filter = HostPropertyFilter::default();
+        assert!(!filter.is_exposed("ro.build.version.sdk"));
+    }
+
+    #[test]
+    fn test_invalid_prefixes() {
+        assert!(HostPropertyFilter::new(&["".into()], SHELL_UID).is_err());
+        assert!(HostPropertyFilter::new(&["ro build".into()], SHELL_UID).is_err());
+    }
+
+    #[test]
+    fn test_prefixes_outside_namespaces() {
+        for prefix in ["ro.", "ro.build.", "ro.boot.serialno", "persist.sys.", "debug."] {
+            assert!(HostPropertyFilter::new(&[prefix.into()], APP_UID).is_err(), "{prefix}");
+        }
+        assert!(HostPropertyFilter::new(&["ro.boot.serialno".into()], SHELL_UID).is_err());
+    }
+
+    #[test]
+    fn test_native_namespaces() -> Result<()> {
+        let filter = HostPropertyFilter::new(&["debug.avf.".into()], SHELL_UID)?;
+        assert!(filter.is_exposed("debug.avf.test"));
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Validation and formatting of the labels of VMs.
+
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
+    VmLabel::VmLabel, VmLabelKey::VmLabelKey,
+};
+use anyhow::{bail, Result};
+
+/// Maximum length of the value of a label.
+const MAX_LABEL_LEN: usize = 63;
+
+/// Checks that the labels have known and distinct keys, and that their values are small enough
+/// and can be formatted with `format_labels` unambiguously.
+pub fn check_labels(labels: &[VmLabel]) -> Result<()> {
+    for (i, label) in labels.iter().enumerate() {
+        let Some(key) = key_name(label.key) else {
+            bail!("Unknown label key {:?}", label.key);
+        };
+        if labels[..i].iter().any(|other| other.key == label.key) {
+            bail!("Duplicate label key {key}");
+        }
+        let value = &label.value;
+        if value.len() > MAX_LABEL_LEN {
+            bail!("Label value {value:?} is longer than {MAX_LABEL_LEN} characters");
+        }
+        if !value.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')) {
+            bail!("Label value {value:?} has characters other than [A-Za-z0-9._-]");
+        }
+    }
+    Ok(())
+}
+
+/// Returns the name of a label key, as the `vm` tool takes it.
+fn key_name(key: VmLabelKey) -> Option<&'static str> {
+    match key {
+        VmLabelKey::TEST_RUN => Some("test-run"),
+        VmLabelKey::TEST_NAME => Some("test-name"),
+        VmLabelKey::GROUP => Some("group"),
+        _ => None,
+    }
+}
+
+/// Formats the labels as comma-separated key=value pairs, e.g. for dumpsys.
+pub fn format_labels(labels: &[VmLabel]) -> String {
+    labels
+        .iter()
+        .map(|label| format!("{}={}", key_name(label.key).unwrap_or("unknown"), label.value))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label(key: VmLabelKey, value: &str) -> VmLabel {
+        VmLabel { key, value: value.to_owned() }
+    }
+
+    #[test]
+    fn test_valid_labels() -> Result<()> {
+        let labels = [
+            label(VmLabelKey::TEST_RUN, "123"),
+            label(VmLabelKey::TEST_NAME, "Team_A.b"),
+            label(VmLabelKey::GROUP, ""),
+        ];
+        check_labels(&labels)?;
+        assert_eq!(format_labels(&labels), "test-run=123,test-name=Team_A.b,group=");
+        check_labels(&[])?;
+        assert_eq!(format_labels(&[]), "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_labels() {
+        assert!(check_labels(&[label(VmLabelKey(42), "1")]).is_err());
+        let duplicate = [label(VmLabelKey::GROUP, "1"), label(VmLabelKey::GROUP, "2")];
+        assert!(check_labels(&duplicate).is_err());
+        assert!(check_labels(&[label(VmLabelKey::GROUP, "1,test-run=2")]).is_err());
+        let long = "x".repeat(MAX_LABEL_LEN + 1);
+        assert!(check_labels(&[label(VmLabelKey::GROUP, &long)]).is_err());
+    }
+}
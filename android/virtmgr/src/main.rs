@@ -15,13 +15,35 @@
 //! Android Virtualization Manager
 
 mod aidl;
+mod arch;
 mod atom;
 mod composite;
+mod console_capture;
+mod cpu_mitigations;
 mod crosvm;
 mod debug_config;
+mod deferred_start;
+mod deprecation;
 mod dt_overlay;
+mod guest_crash;
+mod host_file;
+mod host_properties;
+mod labels;
+mod migration;
+mod network_stub;
 mod payload;
+mod payload_log;
+mod payload_watchdog;
+mod persistent_vm;
+mod port_forwarding;
 mod selinux;
+mod shutdown;
+mod snapshot;
+mod storage_growth;
+mod storage_snapshot;
+mod vm_connection;
+mod vm_pool;
+mod vsock_firewall;
 
 use crate::aidl::{GLOBAL_SERVICE, VirtualizationService};
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::IVirtualizationService::BnVirtualizationService;
@@ -139,4 +161,7 @@ fn main() {
 
     server.join();
     info!("Shutting down VirtualizationService RpcServer");
+
+    // Persistent VMs keep running after their client died.
+    persistent_vm::wait_until_all_stopped();
 }
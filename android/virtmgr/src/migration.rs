@@ -0,0 +1,234 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Live migration of a running non-protected VM to a new crosvm process, e.g. to pick up an
+//! updated crosvm or to replace a misbehaving one.
+//!
+//! The VM is snapshotted and crosvm is asked to exit. The thread monitoring crosvm then restores
+//! the snapshot in a new crosvm process, instead of handling the exit as the death of the VM. As
+//! the `VmInstance` outlives crosvm, the VM keeps its CID, its IVirtualMachine binder, the
+//! callbacks of its clients and its forwarded host sockets. The migration only completes once the
+//! new process resumed the restored VM. If crosvm exits in any other way meanwhile, e.g. as it
+//! crashed, the migration fails and the exit is handled as usual.
+
+use anyhow::{anyhow, bail, ensure, Result};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// How long crosvm may take to exit and be restored in a new process, once the VM has been
+/// snapshotted.
+pub const MIGRATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Default)]
+enum MigrationState {
+    #[default]
+    Idle,
+    /// The VM is being snapshotted to the given path.
+    Requested { snapshot: PathBuf },
+    /// The VM was snapshotted to the given path, and crosvm is being asked to exit.
+    Exiting { snapshot: PathBuf },
+    /// crosvm exited, and the snapshot is being restored in a new crosvm process.
+    Restoring,
+    /// The new crosvm process resumed the restored VM, or the migration failed.
+    Done(Result<(), String>),
+}
+
+/// The migration of a VM, at most one of which may be in progress at a time.
+#[derive(Debug, Default)]
+pub struct Migration {
+    state: Mutex<MigrationState>,
+    updated: Condvar,
+}
+
+impl Migration {
+    /// Starts a migration, which restores `snapshot` in a new crosvm process once crosvm exits.
+    pub fn request(&self, snapshot: PathBuf) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        ensure!(
+            matches!(*state, MigrationState::Idle | MigrationState::Done(_)),
+            "The VM is already being migrated"
+        );
+        *state = MigrationState::Requested { snapshot };
+        Ok(())
+    }
+
+    /// Records that the VM was snapshotted, before crosvm is asked to exit. Fails if crosvm
+    /// already exited, which failed the migration.
+    pub fn snapshot_taken(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match mem::take(&mut *state) {
+            MigrationState::Requested { snapshot } => {
+                *state = MigrationState::Exiting { snapshot };
+                Ok(())
+            }
+            other => {
+                *state = other;
+                bail!("crosvm exited while the VM was being snapshotted")
+            }
+        }
+    }
+
+    /// Abandons the requested migration, e.g. if the VM couldn't be snapshotted or crosvm refused
+    /// to exit. Returns false if crosvm already exited, in which case the migration goes on or
+    /// failed.
+    pub fn cancel(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(*state, MigrationState::Requested { .. } | MigrationState::Exiting { .. }) {
+            return false;
+        }
+        *state = MigrationState::Idle;
+        true
+    }
+
+    /// Called once crosvm exited, cleanly or not. Returns the snapshot to restore in a new crosvm
+    /// process, if the exit is the one the migration asked for. Any other exit during a migration
+    /// fails it, and is to be handled as if there was no migration.
+    pub fn take_snapshot(&self, exited_cleanly: bool) -> Option<PathBuf> {
+        let mut state = self.state.lock().unwrap();
+        match mem::take(&mut *state) {
+            MigrationState::Exiting { snapshot } if exited_cleanly => {
+                *state = MigrationState::Restoring;
+                Some(snapshot)
+            }
+            MigrationState::Requested { .. } | MigrationState::Exiting { .. } => {
+                *state = MigrationState::Done(Err("crosvm exited unexpectedly".to_owned()));
+                self.updated.notify_all();
+                None
+            }
+            other => {
+                *state = other;
+                None
+            }
+        }
+    }
+
+    /// Reports whether the new crosvm process resumed the restored VM, ending `wait`.
+    pub fn complete(&self, result: Result<()>) {
+        *self.state.lock().unwrap() = MigrationState::Done(result.map_err(|e| format!("{e:?}")));
+        self.updated.notify_all();
+    }
+
+    /// Waits for the new crosvm process to resume the restored VM, for at most `timeout`.
+    pub fn wait(&self, timeout: Duration) -> Result<()> {
+        let state = self.state.lock().unwrap();
+        let (mut state, _) = self
+            .updated
+            .wait_timeout_while(state, timeout, |state| !matches!(state, MigrationState::Done(_)))
+            .unwrap();
+        match &*state {
+            MigrationState::Done(result) => result.clone().map_err(|e| anyhow!(e)),
+            MigrationState::Requested { .. } | MigrationState::Exiting { .. } => {
+                // crosvm never exited, so it must not be replaced if it does later.
+                *state = MigrationState::Idle;
+                bail!("crosvm didn't exit within {timeout:?}")
+            }
+            _ => bail!("The VM wasn't restored within {timeout:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn restores_snapshot_once() -> Result<()> {
+        let migration = Migration::default();
+        assert_eq!(migration.take_snapshot(true), None);
+        migration.request(PathBuf::from("snapshot"))?;
+        assert!(migration.request(PathBuf::from("other")).is_err());
+        migration.snapshot_taken()?;
+        assert_eq!(migration.take_snapshot(true), Some(PathBuf::from("snapshot")));
+        assert_eq!(migration.take_snapshot(true), None);
+        // The snapshot is being restored.
+        assert!(!migration.cancel());
+        Ok(())
+    }
+
+    #[test]
+    fn wait_returns_result_of_restore() -> Result<()> {
+        let migration = Arc::new(Migration::default());
+        migration.request(PathBuf::from("snapshot"))?;
+        migration.snapshot_taken()?;
+        let monitor = {
+            let migration = migration.clone();
+            thread::spawn(move || {
+                let snapshot = migration.take_snapshot(true);
+                migration.complete(snapshot.map(|_| ()).ok_or_else(|| anyhow!("No snapshot")));
+            })
+        };
+        migration.wait(TIMEOUT)?;
+        monitor.join().unwrap();
+
+        // The next migration can be requested once the previous one is done.
+        migration.request(PathBuf::from("snapshot"))?;
+        migration.snapshot_taken()?;
+        migration.take_snapshot(true);
+        migration.complete(Err(anyhow!("Failed to restore")));
+        assert!(migration.wait(TIMEOUT).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cancelled_migration_is_not_restored() -> Result<()> {
+        let migration = Migration::default();
+        migration.request(PathBuf::from("snapshot"))?;
+        assert!(migration.cancel());
+        assert_eq!(migration.take_snapshot(true), None);
+        migration.request(PathBuf::from("snapshot"))?;
+        migration.snapshot_taken()?;
+        assert!(migration.cancel());
+        assert_eq!(migration.take_snapshot(true), None);
+        Ok(())
+    }
+
+    #[test]
+    fn crash_while_snapshotting_fails_migration() -> Result<()> {
+        let migration = Migration::default();
+        migration.request(PathBuf::from("snapshot"))?;
+        assert_eq!(migration.take_snapshot(false), None);
+        assert!(migration.snapshot_taken().is_err());
+        assert!(!migration.cancel());
+        assert!(migration.wait(TIMEOUT).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn crash_while_exiting_fails_migration() -> Result<()> {
+        let migration = Migration::default();
+        migration.request(PathBuf::from("snapshot"))?;
+        migration.snapshot_taken()?;
+        assert_eq!(migration.take_snapshot(false), None);
+        assert!(migration.wait(TIMEOUT).is_err());
+        // The migration is over, so a later exit isn't handled as part of it.
+        assert_eq!(migration.take_snapshot(true), None);
+        Ok(())
+    }
+
+    #[test]
+    fn wait_times_out_if_crosvm_does_not_exit() -> Result<()> {
+        let migration = Migration::default();
+        migration.request(PathBuf::from("snapshot"))?;
+        migration.snapshot_taken()?;
+        assert!(migration.wait(Duration::from_millis(10)).is_err());
+        assert_eq!(migration.take_snapshot(true), None);
+        Ok(())
+    }
+}
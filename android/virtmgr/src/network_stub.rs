@@ -0,0 +1,580 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Minimal DHCP server and DNS forwarder for the TAP interface of a VM, so that the guest gets an
+//! address and name resolution without host daemons.
+//!
+//! Each VM gets a /30 subnet derived from its CID, holding the address of the host side of the
+//! TAP interface, which is the gateway and DNS server, and the address of the guest. The lease
+//! never expires, but it ends with the VM, as the stub is torn down when the VM dies. DNS queries
+//! for A and AAAA records are answered with the resolver of the host, by a few resolver threads so
+//! that a slow name doesn't hold up the other queries.
+//!
+//! The stub needs the policy listed in docs/platform_sepolicy.md.
+
+use crate::aidl::Cid;
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use nix::sys::ioctl::ioctl_num_type;
+use nix::sys::socket::{
+    bind, setsockopt, shutdown, socket, sockopt, AddressFamily, Shutdown, SockFlag, SockType,
+    SockaddrIn,
+};
+use std::ffi::{CStr, OsString};
+use std::fs::File;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const DNS_PORT: u16 = 53;
+
+/// Number of threads resolving the DNS queries of a VM.
+const RESOLVER_THREADS: usize = 4;
+/// Number of DNS queries of a VM waiting for a resolver thread, beyond which queries are dropped.
+/// The guest retries them.
+const MAX_PENDING_QUERIES: usize = 16;
+
+// From include/uapi/linux/if_tun.h
+const TUNGETIFF: ioctl_num_type = 0x800454d2u32 as ioctl_num_type;
+nix::ioctl_read_bad!(ioctl_tungetiff, TUNGETIFF, libc::ifreq);
+
+/// The addresses of the subnet of a VM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Subnet {
+    /// Address of the host side of the TAP interface, which is the router and DNS server.
+    pub gateway: Ipv4Addr,
+    /// Address leased to the guest.
+    pub guest: Ipv4Addr,
+}
+
+impl Subnet {
+    /// The subnets of the VMs are carved out of 172.30.0.0/16.
+    const BASE: u32 = 0xac1e_0000;
+    const COUNT: u32 = 1 << 14;
+    const MASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 252);
+
+    pub fn for_cid(cid: Cid) -> Self {
+        let network = Self::BASE + (cid % Self::COUNT) * 4;
+        Self { gateway: Ipv4Addr::from(network + 1), guest: Ipv4Addr::from(network + 2) }
+    }
+}
+
+/// Serves DHCP and DNS on the TAP interface of a VM as long as this object is alive.
+#[derive(Debug)]
+pub struct NetworkStub {
+    sockets: Vec<Arc<UdpSocket>>,
+    threads: Vec<JoinHandle<()>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl NetworkStub {
+    /// Starts serving the VM with the given CID on the interface of the given TAP device.
+    pub fn start(cid: Cid, tap: &File) -> Result<NetworkStub> {
+        let ifname = tap_interface_name(tap).context("Failed to get TAP interface name")?;
+        let subnet = Subnet::for_cid(cid);
+        let dhcp_socket = bind_to_interface(&ifname, DHCP_SERVER_PORT)
+            .with_context(|| format!("Failed to bind DHCP server to {ifname:?}"))?;
+        dhcp_socket.set_broadcast(true)?;
+        let dns_socket = bind_to_interface(&ifname, DNS_PORT)
+            .with_context(|| format!("Failed to bind DNS forwarder to {ifname:?}"))?;
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let dhcp_socket = Arc::new(dhcp_socket);
+        let dns_socket = Arc::new(dns_socket);
+        let (queries, pending_queries) = sync_channel(MAX_PENDING_QUERIES);
+        let mut threads = vec![
+            spawn_server(dhcp_socket.clone(), stopped.clone(), move |packet, _| {
+                let reply = handle_dhcp(&subnet, packet)?;
+                // The guest has no address yet, so it only receives broadcasts.
+                Some((reply, SocketAddr::from((Ipv4Addr::BROADCAST, DHCP_CLIENT_PORT))))
+            }),
+            // The resolver threads reply, and stop once this thread drops `queries`.
+            spawn_server(dns_socket.clone(), stopped.clone(), move |packet, source| {
+                if queries.try_send((packet.to_vec(), source)).is_err() {
+                    debug!("Too many pending DNS queries, dropping query from {source}");
+                }
+                None
+            }),
+        ];
+        threads.extend(spawn_resolvers(dns_socket.clone(), pending_queries));
+        info!(
+            "Serving {} to CID {cid} on {ifname:?} with gateway {}",
+            subnet.guest, subnet.gateway
+        );
+        Ok(NetworkStub { sockets: vec![dhcp_socket, dns_socket], threads, stopped })
+    }
+}
+
+impl Drop for NetworkStub {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        for socket in &self.sockets {
+            // Shutting down a socket wakes up the thread blocked in recv_from().
+            if let Err(e) = shutdown(socket.as_raw_fd(), Shutdown::Both) {
+                warn!("Failed to shut down network stub socket: {e}");
+            }
+        }
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn tap_interface_name(tap: &File) -> Result<OsString> {
+    // SAFETY: All-zero is a valid value for the ifreq type.
+    let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+    // SAFETY: The kernel only writes the interface name and flags to ifr.
+    unsafe { ioctl_tungetiff(tap.as_raw_fd(), &mut ifr) }?;
+    // SAFETY: The kernel returns a NUL-terminated name within IFNAMSIZ.
+    let ifname = unsafe { CStr::from_ptr(ifr.ifr_name.as_ptr()) };
+    Ok(OsString::from(ifname.to_str()?))
+}
+
+/// Binds a UDP socket to the given port of all the addresses of the given interface only, so that
+/// the stubs of several VMs don't conflict.
+fn bind_to_interface(ifname: &OsString, port: u16) -> Result<UdpSocket> {
+    let fd = socket(AddressFamily::Inet, SockType::Datagram, SockFlag::SOCK_CLOEXEC, None)?;
+    setsockopt(&fd, sockopt::BindToDevice, ifname)?;
+    setsockopt(&fd, sockopt::ReuseAddr, &true)?;
+    bind(fd.as_raw_fd(), &SockaddrIn::new(0, 0, 0, 0, port))?;
+    Ok(UdpSocket::from(fd))
+}
+
+fn spawn_server(
+    socket: Arc<UdpSocket>,
+    stopped: Arc<AtomicBool>,
+    handle: impl Fn(&[u8], SocketAddr) -> Option<(Vec<u8>, SocketAddr)> + Send + 'static,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut buf = [0u8; 1500];
+        while !stopped.load(Ordering::Relaxed) {
+            let (len, source) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) => {
+                    if !stopped.load(Ordering::Relaxed) {
+                        warn!("Failed to receive packet: {e}");
+                    }
+                    continue;
+                }
+            };
+            let Some((reply, destination)) = handle(&buf[..len], source) else {
+                continue;
+            };
+            if let Err(e) = socket.send_to(&reply, destination) {
+                warn!("Failed to send reply to {destination}: {e}");
+            }
+        }
+    })
+}
+
+/// Spawns the threads answering the DNS queries received from `queries`, until it is closed.
+fn spawn_resolvers(
+    socket: Arc<UdpSocket>,
+    queries: Receiver<(Vec<u8>, SocketAddr)>,
+) -> Vec<JoinHandle<()>> {
+    let queries = Arc::new(Mutex::new(queries));
+    (0..RESOLVER_THREADS)
+        .map(|_| {
+            let socket = socket.clone();
+            let queries = queries.clone();
+            thread::spawn(move || loop {
+                // The lock is only held while waiting for a query, not while resolving it.
+                let Ok((query, source)) = queries.lock().unwrap().recv() else { break };
+                let Some(response) = handle_dns(&query, resolve) else {
+                    continue;
+                };
+                if let Err(e) = socket.send_to(&response, source) {
+                    warn!("Failed to send DNS response to {source}: {e}");
+                }
+            })
+        })
+        .collect()
+}
+
+mod dhcp {
+    pub const BOOTREQUEST: u8 = 1;
+    pub const BOOTREPLY: u8 = 2;
+    pub const HTYPE_ETHERNET: u8 = 1;
+    pub const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+    /// Length of the fixed part of a message, up to and including the magic cookie.
+    pub const HEADER_LEN: usize = 240;
+    pub const CHADDR_OFFSET: usize = 28;
+
+    pub const OPTION_PAD: u8 = 0;
+    pub const OPTION_SUBNET_MASK: u8 = 1;
+    pub const OPTION_ROUTER: u8 = 3;
+    pub const OPTION_DNS_SERVER: u8 = 6;
+    pub const OPTION_REQUESTED_IP: u8 = 50;
+    pub const OPTION_LEASE_TIME: u8 = 51;
+    pub const OPTION_MESSAGE_TYPE: u8 = 53;
+    pub const OPTION_SERVER_ID: u8 = 54;
+    pub const OPTION_END: u8 = 255;
+
+    pub const DISCOVER: u8 = 1;
+    pub const OFFER: u8 = 2;
+    pub const REQUEST: u8 = 3;
+    pub const ACK: u8 = 5;
+    pub const NAK: u8 = 6;
+    pub const INFORM: u8 = 8;
+
+    /// The lease lasts as long as the VM.
+    pub const INFINITE_LEASE: u32 = u32::MAX;
+}
+
+/// Handles a DHCP request from the guest, and returns the reply to broadcast, if any.
+fn handle_dhcp(subnet: &Subnet, request: &[u8]) -> Option<Vec<u8>> {
+    use dhcp::*;
+
+    if request.len() < HEADER_LEN
+        || request[0] != BOOTREQUEST
+        || request[1] != HTYPE_ETHERNET
+        || request[236..240] != MAGIC_COOKIE
+    {
+        return None;
+    }
+    let mut message_type = None;
+    let mut requested_ip = None;
+    let mut server_id = None;
+    let mut options = &request[HEADER_LEN..];
+    while let Some((&code, rest)) = options.split_first() {
+        match code {
+            OPTION_PAD => {
+                options = rest;
+                continue;
+            }
+            OPTION_END => break,
+            _ => {}
+        }
+        let (&len, rest) = rest.split_first()?;
+        let value = rest.get(..usize::from(len))?;
+        match (code, value) {
+            (OPTION_MESSAGE_TYPE, &[t]) => message_type = Some(t),
+            (OPTION_REQUESTED_IP, &[a, b, c, d]) => requested_ip = Some(Ipv4Addr::new(a, b, c, d)),
+            (OPTION_SERVER_ID, &[a, b, c, d]) => server_id = Some(Ipv4Addr::new(a, b, c, d)),
+            _ => {}
+        }
+        options = &rest[usize::from(len)..];
+    }
+    if server_id.is_some_and(|id| id != subnet.gateway) {
+        // The guest picked another server.
+        return None;
+    }
+    let ciaddr = Ipv4Addr::new(request[12], request[13], request[14], request[15]);
+    let reply_type = match message_type? {
+        DISCOVER => OFFER,
+        REQUEST if requested_ip.unwrap_or(ciaddr) == subnet.guest => ACK,
+        REQUEST => NAK,
+        INFORM => ACK,
+        _ => return None,
+    };
+
+    let mut reply = vec![0u8; HEADER_LEN];
+    reply[0] = BOOTREPLY;
+    // htype, hlen, xid, flags, ciaddr, giaddr and chaddr are copied from the request.
+    reply[1..3].copy_from_slice(&request[1..3]);
+    reply[4..8].copy_from_slice(&request[4..8]);
+    reply[10..16].copy_from_slice(&request[10..16]);
+    if reply_type != NAK && message_type != Some(INFORM) {
+        reply[16..20].copy_from_slice(&subnet.guest.octets());
+    }
+    reply[24..CHADDR_OFFSET + 16].copy_from_slice(&request[24..CHADDR_OFFSET + 16]);
+    reply[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+    let mut add_option = |code: u8, value: &[u8]| {
+        reply.extend([code, value.len() as u8]);
+        reply.extend(value);
+    };
+    add_option(OPTION_MESSAGE_TYPE, &[reply_type]);
+    add_option(OPTION_SERVER_ID, &subnet.gateway.octets());
+    if reply_type != NAK {
+        if message_type != Some(INFORM) {
+            add_option(OPTION_LEASE_TIME, &INFINITE_LEASE.to_be_bytes());
+        }
+        add_option(OPTION_SUBNET_MASK, &Subnet::MASK.octets());
+        add_option(OPTION_ROUTER, &subnet.gateway.octets());
+        add_option(OPTION_DNS_SERVER, &subnet.gateway.octets());
+    }
+    reply.push(OPTION_END);
+    Some(reply)
+}
+
+mod dns {
+    pub const HEADER_LEN: usize = 12;
+    pub const FLAG_QR: u16 = 0x8000;
+    pub const FLAG_RD: u16 = 0x0100;
+    pub const FLAG_RA: u16 = 0x0080;
+    pub const OPCODE_MASK: u16 = 0x7800;
+
+    pub const RCODE_SERVFAIL: u16 = 2;
+    pub const RCODE_NOTIMP: u16 = 4;
+    pub const RCODE_FORMERR: u16 = 1;
+
+    pub const TYPE_A: u16 = 1;
+    pub const TYPE_AAAA: u16 = 28;
+    pub const CLASS_IN: u16 = 1;
+
+    /// TTL of the answers, short as the records are not authoritative.
+    pub const TTL: u32 = 60;
+    /// Pointer to the name of the question, which follows the header.
+    pub const QUESTION_NAME_POINTER: u16 = 0xc000 | HEADER_LEN as u16;
+}
+
+/// Resolves a name with the resolver of the host.
+fn resolve(name: &str) -> io::Result<Vec<IpAddr>> {
+    Ok((name, 0).to_socket_addrs()?.map(|addr| addr.ip()).collect())
+}
+
+/// Handles a DNS query from the guest, and returns the response, if any. Only queries with a
+/// single question are answered.
+fn handle_dns(query: &[u8], resolve: impl Fn(&str) -> io::Result<Vec<IpAddr>>) -> Option<Vec<u8>> {
+    use dns::*;
+
+    let header = query.get(..HEADER_LEN)?;
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    if flags & FLAG_QR != 0 {
+        return None;
+    }
+    let response = |question: &[u8], rcode: u16, answers: &[IpAddr], qtype: u16| {
+        let mut response = Vec::with_capacity(512);
+        response.extend(&header[..2]);
+        let flags = FLAG_QR | (flags & (OPCODE_MASK | FLAG_RD)) | FLAG_RA | rcode;
+        response.extend(flags.to_be_bytes());
+        let qdcount = if question.is_empty() { 0u16 } else { 1 };
+        response.extend(qdcount.to_be_bytes());
+        response.extend((answers.len() as u16).to_be_bytes());
+        response.extend([0u8; 4]);
+        response.extend(question);
+        for answer in answers {
+            response.extend(QUESTION_NAME_POINTER.to_be_bytes());
+            response.extend(qtype.to_be_bytes());
+            response.extend(CLASS_IN.to_be_bytes());
+            response.extend(TTL.to_be_bytes());
+            match answer {
+                IpAddr::V4(addr) => {
+                    response.extend(4u16.to_be_bytes());
+                    response.extend(addr.octets());
+                }
+                IpAddr::V6(addr) => {
+                    response.extend(16u16.to_be_bytes());
+                    response.extend(addr.octets());
+                }
+            }
+        }
+        response
+    };
+
+    if flags & OPCODE_MASK != 0 {
+        return Some(response(&[], RCODE_NOTIMP, &[], 0));
+    }
+    let qdcount = u16::from_be_bytes([header[4], header[5]]);
+    let Some((name, question_len)) = parse_name(&query[HEADER_LEN..]).filter(|_| qdcount == 1)
+    else {
+        return Some(response(&[], RCODE_FORMERR, &[], 0));
+    };
+    let Some(question) = query.get(HEADER_LEN..HEADER_LEN + question_len + 4) else {
+        return Some(response(&[], RCODE_FORMERR, &[], 0));
+    };
+    let qtype = u16::from_be_bytes([question[question_len], question[question_len + 1]]);
+    let qclass = u16::from_be_bytes([question[question_len + 2], question[question_len + 3]]);
+    if qclass != CLASS_IN || (qtype != TYPE_A && qtype != TYPE_AAAA) {
+        return Some(response(question, RCODE_NOTIMP, &[], qtype));
+    }
+    match resolve(&name) {
+        Ok(addresses) => {
+            let answers: Vec<_> =
+                addresses.into_iter().filter(|addr| addr.is_ipv4() == (qtype == TYPE_A)).collect();
+            Some(response(question, 0, &answers, qtype))
+        }
+        Err(e) => {
+            debug!("Failed to resolve {name}: {e}");
+            Some(response(question, RCODE_SERVFAIL, &[], qtype))
+        }
+    }
+}
+
+/// Parses an uncompressed domain name, and returns it with the length of its encoding.
+fn parse_name(data: &[u8]) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut offset = 0;
+    loop {
+        let len = usize::from(*data.get(offset)?);
+        offset += 1;
+        if len == 0 {
+            break;
+        }
+        // Compression pointers and extended label types aren't expected in questions.
+        if len > 63 {
+            return None;
+        }
+        let label = std::str::from_utf8(data.get(offset..offset + len)?).ok()?;
+        labels.push(label);
+        offset += len;
+    }
+    if labels.is_empty() {
+        return None;
+    }
+    Some((labels.join("."), offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHADDR: [u8; 6] = [0x02, 0x00, 0x00, 0x12, 0x34, 0x56];
+
+    fn dhcp_request(message_type: u8, extra_options: &[u8]) -> Vec<u8> {
+        let mut request = vec![0u8; dhcp::HEADER_LEN];
+        request[0] = dhcp::BOOTREQUEST;
+        request[1] = dhcp::HTYPE_ETHERNET;
+        request[2] = 6;
+        request[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        request[28..34].copy_from_slice(&CHADDR);
+        request[236..240].copy_from_slice(&dhcp::MAGIC_COOKIE);
+        request.extend([dhcp::OPTION_MESSAGE_TYPE, 1, message_type]);
+        request.extend(extra_options);
+        request.push(dhcp::OPTION_END);
+        request
+    }
+
+    /// Returns the value of the given option of a DHCP message.
+    fn dhcp_option(message: &[u8], code: u8) -> Option<&[u8]> {
+        let mut options = &message[dhcp::HEADER_LEN..];
+        while options[0] != dhcp::OPTION_END {
+            let len = usize::from(options[1]);
+            if options[0] == code {
+                return Some(&options[2..2 + len]);
+            }
+            options = &options[2 + len..];
+        }
+        None
+    }
+
+    #[test]
+    fn subnets_of_vms_differ() {
+        let subnet = Subnet::for_cid(2048);
+        assert_eq!(subnet.gateway, Ipv4Addr::new(172, 30, 32, 1));
+        assert_eq!(subnet.guest, Ipv4Addr::new(172, 30, 32, 2));
+        assert_ne!(Subnet::for_cid(2049), subnet);
+    }
+
+    #[test]
+    fn dhcp_discover_is_offered_guest_address() {
+        let subnet = Subnet::for_cid(2048);
+        let offer = handle_dhcp(&subnet, &dhcp_request(dhcp::DISCOVER, &[])).unwrap();
+        assert_eq!(offer[0], dhcp::BOOTREPLY);
+        assert_eq!(offer[4..8], [0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(offer[16..20], subnet.guest.octets());
+        assert_eq!(offer[28..34], CHADDR);
+        assert_eq!(dhcp_option(&offer, dhcp::OPTION_MESSAGE_TYPE), Some(&[dhcp::OFFER][..]));
+        assert_eq!(dhcp_option(&offer, dhcp::OPTION_ROUTER), Some(&subnet.gateway.octets()[..]));
+        assert_eq!(
+            dhcp_option(&offer, dhcp::OPTION_DNS_SERVER),
+            Some(&subnet.gateway.octets()[..])
+        );
+        assert_eq!(dhcp_option(&offer, dhcp::OPTION_LEASE_TIME), Some(&[0xff; 4][..]));
+    }
+
+    #[test]
+    fn dhcp_request_is_acked_only_for_guest_address() {
+        let subnet = Subnet::for_cid(2048);
+        let mut options = vec![dhcp::OPTION_REQUESTED_IP, 4];
+        options.extend(subnet.guest.octets());
+        let ack = handle_dhcp(&subnet, &dhcp_request(dhcp::REQUEST, &options)).unwrap();
+        assert_eq!(dhcp_option(&ack, dhcp::OPTION_MESSAGE_TYPE), Some(&[dhcp::ACK][..]));
+
+        let options = [dhcp::OPTION_REQUESTED_IP, 4, 10, 0, 0, 2];
+        let nak = handle_dhcp(&subnet, &dhcp_request(dhcp::REQUEST, &options)).unwrap();
+        assert_eq!(dhcp_option(&nak, dhcp::OPTION_MESSAGE_TYPE), Some(&[dhcp::NAK][..]));
+        assert_eq!(nak[16..20], [0; 4]);
+    }
+
+    #[test]
+    fn dhcp_ignores_other_messages() {
+        let subnet = Subnet::for_cid(2048);
+        // Request for another server.
+        let options = [dhcp::OPTION_SERVER_ID, 4, 10, 0, 0, 1];
+        assert!(handle_dhcp(&subnet, &dhcp_request(dhcp::REQUEST, &options)).is_none());
+        // Truncated option.
+        let mut request = dhcp_request(dhcp::DISCOVER, &[]);
+        request.truncate(request.len() - 2);
+        assert!(handle_dhcp(&subnet, &request).is_none());
+        // Reply.
+        let mut request = dhcp_request(dhcp::DISCOVER, &[]);
+        request[0] = dhcp::BOOTREPLY;
+        assert!(handle_dhcp(&subnet, &request).is_none());
+    }
+
+    fn dns_query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            query.push(label.len() as u8);
+            query.extend(label.as_bytes());
+        }
+        query.push(0);
+        query.extend(qtype.to_be_bytes());
+        query.extend(dns::CLASS_IN.to_be_bytes());
+        query
+    }
+
+    fn resolve_example(name: &str) -> io::Result<Vec<IpAddr>> {
+        match name {
+            "example.com" => Ok(vec![
+                IpAddr::V4(Ipv4Addr::new(93, 184, 215, 14)),
+                "2606:2800:21f:cb07:6820:80da:af6b:8b2c".parse().unwrap(),
+            ]),
+            _ => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    #[test]
+    fn dns_a_query_is_answered() {
+        let query = dns_query("example.com", dns::TYPE_A);
+        let response = handle_dns(&query, resolve_example).unwrap();
+        // ID, flags (QR, RD, RA), one question, one answer.
+        assert_eq!(response[..12], [0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(response[12..query.len()], query[12..]);
+        let answer = &response[query.len()..];
+        assert_eq!(answer[..4], [0xc0, 0x0c, 0, 1]);
+        assert_eq!(answer[10..], [0, 4, 93, 184, 215, 14]);
+    }
+
+    #[test]
+    fn dns_aaaa_query_is_answered() {
+        let query = dns_query("example.com", dns::TYPE_AAAA);
+        let response = handle_dns(&query, resolve_example).unwrap();
+        assert_eq!(response[6..8], [0, 1]);
+        assert_eq!(response.len(), query.len() + 12 + 16);
+    }
+
+    #[test]
+    fn dns_errors() {
+        let rcode = |response: Vec<u8>| response[3] & 0xf;
+        let query = dns_query("unknown.example", dns::TYPE_A);
+        assert_eq!(rcode(handle_dns(&query, resolve_example).unwrap()), 2);
+        let query = dns_query("example.com", 15 /* MX */);
+        assert_eq!(rcode(handle_dns(&query, resolve_example).unwrap()), 4);
+        let query = dns_query("example.com", dns::TYPE_A);
+        assert_eq!(rcode(handle_dns(&query[..query.len() - 1], resolve_example).unwrap()), 1);
+        // Responses are ignored.
+        let mut response = dns_query("example.com", dns::TYPE_A);
+        response[2] |= 0x80;
+        assert!(handle_dns(&response, resolve_example).is_none());
+    }
+}
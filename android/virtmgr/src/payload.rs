@@ -25,7 +25,9 @@ use android_system_virtualizationservice::aidl::android::system::virtualizations
 use anyhow::{anyhow, bail, Context, Result};
 use binder::{wait_for_interface, ParcelFileDescriptor};
 use log::{info, warn};
-use microdroid_metadata::{ApexPayload, ApkPayload, Metadata, PayloadConfig, PayloadMetadata};
+use microdroid_metadata::{
+    ApexPayload, ApkPayload, Metadata, PayloadConfig, PayloadMetadata, SharedDirectory,
+};
 use microdroid_payload_config::{ApexConfig, VmPayloadConfig};
 use once_cell::sync::OnceCell;
 use packagemanager_aidl::aidl::android::content::pm::{
@@ -201,6 +203,8 @@ fn make_metadata_file(
         Payload::PayloadConfig(payload_config) => PayloadMetadata::Config(PayloadConfig {
             payload_binary_name: payload_config.payloadBinaryName.clone(),
             extra_apk_count: payload_config.extraApks.len().try_into()?,
+            // Checked against the extra APKs by create_vm_payload_config.
+            payload_extra_apk_index: u32::try_from(payload_config.payloadExtraApkIndex).ok(),
             special_fields: Default::default(),
         }),
         Payload::ConfigPath(config_path) => {
@@ -231,6 +235,17 @@ fn make_metadata_file(
         })
         .into(),
         payload: Some(payload_metadata),
+        share_host_ca_certificates: app_config.shareHostCaCertificates,
+        shared_directories: app_config
+            .sharedDirectories
+            .iter()
+            .map(|shared_directory| SharedDirectory {
+                tag: shared_directory.tag.clone(),
+                writable: shared_directory.writable,
+                ..Default::default()
+            })
+            .collect(),
+        host_locales: app_config.hostLocales.clone(),
         ..Default::default()
     };
 
@@ -296,6 +311,7 @@ fn make_payload_disk(
         image: Some(metadata_file),
         writable: false,
         guid: None,
+        typeGuid: None,
     }];
 
     for (i, apex_info) in apex_infos.iter().enumerate() {
@@ -314,6 +330,7 @@ fn make_payload_disk(
             image: Some(apex_file),
             writable: false,
             guid: None,
+            typeGuid: None,
         });
     }
     partitions.push(Partition {
@@ -321,12 +338,14 @@ fn make_payload_disk(
         image: Some(ParcelFileDescriptor::new(apk_file)),
         writable: false,
         guid: None,
+        typeGuid: None,
     });
     partitions.push(Partition {
         label: "microdroid-apk-idsig".to_owned(),
         image: Some(ParcelFileDescriptor::new(idsig_file)),
         writable: false,
         guid: None,
+        typeGuid: None,
     });
 
     // we've already checked that extra_apks and extraIdsigs are in the same size.
@@ -339,6 +358,7 @@ fn make_payload_disk(
             image: Some(ParcelFileDescriptor::new(extra_apk_file)),
             writable: false,
             guid: None,
+            typeGuid: None,
         });
 
         partitions.push(Partition {
@@ -351,10 +371,11 @@ fn make_payload_disk(
             )),
             writable: false,
             guid: None,
+            typeGuid: None,
         });
     }
 
-    Ok(DiskImage { image: None, partitions, writable: false })
+    Ok(DiskImage { image: None, partitions, writable: false, directIo: false })
 }
 
 fn run_derive_classpath() -> Result<String> {
@@ -438,7 +459,9 @@ pub fn add_microdroid_vendor_image(vendor_image: File, vm_config: &mut VirtualMa
             image: Some(ParcelFileDescriptor::new(vendor_image)),
             writable: false,
             guid: None,
+            typeGuid: None,
         }],
+        directIo: false,
     })
 }
 
@@ -462,6 +485,7 @@ pub fn add_microdroid_system_images(
         image: Some(ParcelFileDescriptor::new(instance_file)),
         writable: true,
         guid: None,
+        typeGuid: None,
     }];
 
     if let Some(file) = storage_image {
@@ -470,6 +494,7 @@ pub fn add_microdroid_system_images(
             image: Some(ParcelFileDescriptor::new(file)),
             writable: true,
             guid: None,
+            typeGuid: None,
         });
     }
 
@@ -477,6 +502,7 @@ pub fn add_microdroid_system_images(
         image: None,
         partitions: writable_partitions,
         writable: true,
+        directIo: false,
     });
 
     Ok(())
@@ -0,0 +1,199 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Log records which the payload sends over vsock, written to logcat with their priority and tag.
+//!
+//! virtmgr runs as the app owning the VM, so the records are attributed to it in logcat. See
+//! IVirtualMachineService.openPayloadLog for how records are framed.
+
+use crate::aidl::Cid;
+use crate::vm_connection::accept_from_vm;
+use android_log_sys::__android_log_write;
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use std::ffi::{c_int, CString};
+use std::io::{self, BufReader, ErrorKind, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use vsock::{VsockListener, VMADDR_CID_HOST};
+
+/// How long the VM has to connect to the port on which its log records are received.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+// SYNC WITH libs/libvm_payload/src/payload_log.rs
+const MAX_MESSAGE_LEN: usize = 4000;
+
+const ANDROID_LOG_VERBOSE: u8 = 2;
+const ANDROID_LOG_INFO: u8 = 4;
+const ANDROID_LOG_FATAL: u8 = 7;
+
+/// A log record of the payload.
+#[derive(Debug, PartialEq)]
+struct LogRecord {
+    priority: u8,
+    tag: CString,
+    message: CString,
+}
+
+impl LogRecord {
+    fn write_to_logcat(&self) {
+        // SAFETY: The tag and message are valid nul-terminated strings, which liblog doesn't keep.
+        unsafe {
+            __android_log_write(self.priority as c_int, self.tag.as_ptr(), self.message.as_ptr())
+        };
+    }
+}
+
+/// The log of the payload of a VM. A VM can only have one connection to it at a time, so that it
+/// can't make virtmgr hold on to listeners and threads.
+#[derive(Debug, Default)]
+pub struct PayloadLog {
+    open: Arc<AtomicBool>,
+}
+
+impl PayloadLog {
+    /// Starts writing to logcat the log records which the VM with the given CID sends once it
+    /// connected. Returns the host vsock port the VM should connect to.
+    pub fn open(&self, cid: Cid) -> Result<u32> {
+        if self.open.swap(true, Ordering::SeqCst) {
+            bail!("The payload log is already open");
+        }
+        let (listener, port) = match bind() {
+            Ok(bound) => bound,
+            Err(e) => {
+                self.open.store(false, Ordering::SeqCst);
+                return Err(e);
+            }
+        };
+        let open = self.open.clone();
+        thread::spawn(move || {
+            match receive(listener, cid) {
+                Ok(count) => {
+                    info!("VM with CID {cid} closed its payload log after {count} records")
+                }
+                Err(e) => warn!("Failed to receive the payload log of VM with CID {cid}: {e:?}"),
+            }
+            // The payload opens the log again if it still has records to send.
+            open.store(false, Ordering::SeqCst);
+        });
+        Ok(port)
+    }
+}
+
+/// Returns a new listener for the log records, with its port.
+fn bind() -> Result<(VsockListener, u32)> {
+    let listener = VsockListener::bind_with_cid_port(VMADDR_CID_HOST, libc::VMADDR_PORT_ANY)
+        .context("Failed to bind payload log listener")?;
+    let port = listener.local_addr().context("Failed to get payload log listener address")?.port();
+    Ok((listener, port))
+}
+
+/// Waits for the VM to connect, then writes the records it sends to logcat until it disconnects.
+/// Returns the number of records.
+fn receive(listener: VsockListener, cid: Cid) -> Result<u64> {
+    let stream = accept_from_vm(&listener, cid, CONNECTION_TIMEOUT)?;
+    drop(listener);
+    let mut stream = BufReader::new(stream);
+    let mut count = 0;
+    while let Some(record) = read_record(&mut stream)? {
+        record.write_to_logcat();
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Reads the next record, or returns `None` if the stream ended between records.
+fn read_record(reader: &mut impl Read) -> io::Result<Option<LogRecord>> {
+    let mut header = [0u8; 4];
+    match reader.read_exact(&mut header) {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let [priority, tag_len, message_len @ ..] = header;
+    let message_len = u16::from_le_bytes(message_len).into();
+    if message_len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Log message of {message_len} bytes is too long"),
+        ));
+    }
+    let mut tag = vec![0; tag_len.into()];
+    reader.read_exact(&mut tag)?;
+    let mut message = vec![0; message_len];
+    reader.read_exact(&mut message)?;
+
+    // The payload can't pick priorities which liblog doesn't expect, e.g. to silence records.
+    let priority = if (ANDROID_LOG_VERBOSE..=ANDROID_LOG_FATAL).contains(&priority) {
+        priority
+    } else {
+        ANDROID_LOG_INFO
+    };
+    Ok(Some(LogRecord { priority, tag: to_c_string(&tag), message: to_c_string(&message) }))
+}
+
+/// Converts text from the payload, which may not be valid UTF-8 or may contain nul bytes.
+fn to_c_string(bytes: &[u8]) -> CString {
+    let text = String::from_utf8_lossy(bytes).replace('\0', "");
+    CString::new(text).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(priority: u8, tag: &[u8], message: &[u8]) -> Vec<u8> {
+        let mut frame = vec![priority, tag.len() as u8];
+        frame.extend_from_slice(&(message.len() as u16).to_le_bytes());
+        frame.extend_from_slice(tag);
+        frame.extend_from_slice(message);
+        frame
+    }
+
+    #[test]
+    fn reads_records_until_end_of_stream() -> io::Result<()> {
+        let mut stream = frame(6, b"payload", b"Failed");
+        stream.extend(frame(3, b"", b"tick"));
+        let mut stream = stream.as_slice();
+
+        let record = read_record(&mut stream)?.unwrap();
+        assert_eq!(record.priority, 6);
+        assert_eq!(record.tag.to_str().unwrap(), "payload");
+        assert_eq!(record.message.to_str().unwrap(), "Failed");
+        let record = read_record(&mut stream)?.unwrap();
+        assert_eq!(record.priority, 3);
+        assert_eq!(record.tag.to_str().unwrap(), "");
+        assert!(read_record(&mut stream)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn sanitizes_records() -> io::Result<()> {
+        let stream = frame(42, b"t\0ag", b"\xffmessage");
+        let record = read_record(&mut stream.as_slice())?.unwrap();
+        assert_eq!(record.priority, ANDROID_LOG_INFO);
+        assert_eq!(record.tag.to_str().unwrap(), "tag");
+        assert_eq!(record.message.to_str().unwrap(), "\u{fffd}message");
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_records() {
+        let stream = frame(4, b"tag", &[b'a'; MAX_MESSAGE_LEN + 1]);
+        assert!(read_record(&mut stream.as_slice()).is_err());
+        let stream = frame(4, b"tag", b"message");
+        assert!(read_record(&mut &stream[..stream.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Watchdog which the payload of a VM can start, so that the VM is killed if the payload hangs.
+
+use anyhow::{ensure, Context, Result};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Shortest interval the payload may kick the watchdog at, so that monitoring it stays cheap.
+const MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Default)]
+struct WatchdogState {
+    /// How long the payload may go without kicking the watchdog, once it started it.
+    interval: Option<Duration>,
+    /// When the watchdog expires unless it is kicked.
+    deadline: Option<Instant>,
+    /// Whether a thread is waiting in `wait_until_expired`.
+    monitored: bool,
+    /// Whether the payload stopped kicking the watchdog in time.
+    expired: bool,
+    /// Whether the VM is dead, so that the watchdog must not be monitored anymore.
+    stopped: bool,
+}
+
+/// The watchdog of the payload of a VM, which is disarmed until the payload starts it.
+#[derive(Debug, Default)]
+pub struct PayloadWatchdog {
+    state: Mutex<WatchdogState>,
+    updated: Condvar,
+}
+
+impl PayloadWatchdog {
+    /// Arms the watchdog, or changes its interval if it is armed already, and kicks it. Returns
+    /// whether the caller must start monitoring it with `wait_until_expired`, i.e. if nothing
+    /// monitors it yet, or the previous monitor returned when it expired.
+    pub fn start(&self, interval: Duration) -> Result<bool> {
+        ensure!(
+            interval >= MIN_INTERVAL,
+            "Watchdog interval {interval:?} is below {MIN_INTERVAL:?}"
+        );
+        let mut state = self.state.lock().unwrap();
+        ensure!(!state.stopped, "The VM is not running");
+        state.interval = Some(interval);
+        state.deadline = Some(Instant::now() + interval);
+        state.expired = false;
+        self.updated.notify_all();
+        Ok(!std::mem::replace(&mut state.monitored, true))
+    }
+
+    /// Postpones the expiry of the watchdog by its interval.
+    pub fn kick(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let interval = state.interval.context("The payload didn't start its watchdog")?;
+        state.deadline = Some(Instant::now() + interval);
+        Ok(())
+    }
+
+    /// Disarms the watchdog, e.g. when the VM restarts and its payload starts over.
+    pub fn disarm(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.interval = None;
+        state.deadline = None;
+        self.updated.notify_all();
+    }
+
+    /// Disarms the watchdog for good, when the VM dies, and ends `wait_until_expired`.
+    pub fn stop(&self) {
+        self.state.lock().unwrap().stopped = true;
+        self.updated.notify_all();
+    }
+
+    /// Blocks until the watchdog expires, returning true and disarming it, or is stopped, returning
+    /// false.
+    pub fn wait_until_expired(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.stopped {
+                return false;
+            }
+            match state.deadline {
+                None => state = self.updated.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        state.expired = true;
+                        state.interval = None;
+                        state.deadline = None;
+                        state.monitored = false;
+                        return true;
+                    }
+                    state = self.updated.wait_timeout(state, deadline - now).unwrap().0;
+                }
+            }
+        }
+    }
+
+    /// Whether the VM was killed because its payload stopped kicking the watchdog.
+    pub fn has_expired(&self) -> bool {
+        self.state.lock().unwrap().expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn kick_requires_start() {
+        let watchdog = PayloadWatchdog::default();
+        assert!(watchdog.kick().is_err());
+        assert!(watchdog.start(MIN_INTERVAL).unwrap());
+        assert!(watchdog.kick().is_ok());
+        // Only the first start needs a monitor.
+        assert!(!watchdog.start(MIN_INTERVAL * 2).unwrap());
+    }
+
+    #[test]
+    fn rejects_short_interval() {
+        let watchdog = PayloadWatchdog::default();
+        assert!(watchdog.start(Duration::from_millis(1)).is_err());
+    }
+
+    #[test]
+    fn expires_without_kicks() {
+        let watchdog = PayloadWatchdog::default();
+        watchdog.start(MIN_INTERVAL).unwrap();
+        assert!(watchdog.wait_until_expired());
+        assert!(watchdog.has_expired());
+    }
+
+    #[test]
+    fn restart_after_expiry_rearms() {
+        let watchdog = PayloadWatchdog::default();
+        watchdog.start(MIN_INTERVAL).unwrap();
+        assert!(watchdog.wait_until_expired());
+        assert!(watchdog.kick().is_err());
+
+        // The previous monitor returned, so a new one is needed.
+        assert!(watchdog.start(MIN_INTERVAL).unwrap());
+        assert!(!watchdog.has_expired());
+        assert!(watchdog.kick().is_ok());
+    }
+
+    #[test]
+    fn stop_ends_monitoring() {
+        let watchdog = Arc::new(PayloadWatchdog::default());
+        let monitor = {
+            let watchdog = watchdog.clone();
+            thread::spawn(move || watchdog.wait_until_expired())
+        };
+        watchdog.stop();
+        assert!(!monitor.join().unwrap());
+        assert!(!watchdog.has_expired());
+        assert!(watchdog.start(MIN_INTERVAL).is_err());
+    }
+}
@@ -0,0 +1,281 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! VMs with a persistent name, which keep running after the client which created them died.
+//!
+//! Once such a VM is started, it is registered in virtualizationservice, which holds a reference
+//! to it until it stops. The VM may belong to the virtmgr of a client which died, so the VM found
+//! by name is wrapped in a local binder object, which can be returned over RPC binder. Calls
+//! between virtmgr processes need the policy listed in docs/platform_sepolicy.md.
+
+use crate::aidl::GLOBAL_SERVICE;
+use crate::crosvm::VmInstance;
+use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::{
+    DeathReason::DeathReason, ErrorCode::ErrorCode,
+};
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
+    DebugConfig::DebugConfig,
+    DebugFacility::DebugFacility,
+    DeferredStartStatus::DeferredStartStatus,
+    DeprecationWarning::DeprecationWarning,
+    GuestCrashReason::GuestCrashReason,
+    IVirtualMachine::{BnVirtualMachine, IVirtualMachine},
+    IVirtualMachineCallback::{BnVirtualMachineCallback, IVirtualMachineCallback},
+    NetworkFirewall::NetworkFirewall,
+    VirtualMachineState::VirtualMachineState,
+};
+use binder::{BinderFeatures, ExceptionCode, Interface, ParcelFileDescriptor, Status, Strong};
+use log::{error, info};
+use std::sync::atomic::Ordering;
+use std::sync::{Condvar, Mutex};
+
+/// Number of persistent VMs of this process which are registered, and a condition notified when
+/// it changes.
+static REGISTERED_VMS: (Mutex<usize>, Condvar) = (Mutex::new(0), Condvar::new());
+
+/// Registers a VM which is about to start under its persistent name, if it has one. `vm` is a
+/// binder object for the VM which is owned by the registry.
+pub fn register(instance: &VmInstance, vm: &Strong<dyn IVirtualMachine>) -> binder::Result<()> {
+    let Some(name) = &instance.persistent_name else {
+        return Ok(());
+    };
+    instance.vm_context.global_context.setPersistentVm(name, vm)?;
+    instance.persistent_registered.store(true, Ordering::Relaxed);
+    *REGISTERED_VMS.0.lock().unwrap() += 1;
+    info!("{instance} registered as persistent VM {name:?}");
+    Ok(())
+}
+
+/// Unregisters a VM which stopped or failed to start, if it was registered.
+pub fn unregister(instance: &VmInstance) {
+    if !instance.persistent_registered.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    if let Err(e) = instance.vm_context.global_context.clearPersistentVm() {
+        error!("Failed to unregister persistent VM {instance}: {e:?}");
+    }
+    let (count, changed) = &REGISTERED_VMS;
+    *count.lock().unwrap() -= 1;
+    changed.notify_all();
+}
+
+/// Blocks until no persistent VM of this process is running anymore.
+pub fn wait_until_all_stopped() {
+    let (count, changed) = &REGISTERED_VMS;
+    let count = count.lock().unwrap();
+    if *count > 0 {
+        info!("Waiting for {} persistent VMs to stop", *count);
+    }
+    let _count = changed.wait_while(count, |count| *count > 0).unwrap();
+}
+
+/// Finds a running VM of the caller by its persistent name.
+pub fn lookup(name: &str) -> binder::Result<Option<Strong<dyn IVirtualMachine>>> {
+    if cfg!(early) {
+        return Err(Status::new_exception_str(
+            ExceptionCode::UNSUPPORTED_OPERATION,
+            Some("Early VMs can't be looked up by name"),
+        ));
+    }
+    let Some(vm) = GLOBAL_SERVICE.lookupPersistentVm(name)? else {
+        return Ok(None);
+    };
+    Ok(Some(BnVirtualMachine::new_binder(
+        RegisteredVirtualMachine { vm },
+        BinderFeatures::default(),
+    )))
+}
+
+/// A VM found by name, which forwards calls to the VM in the virtmgr which created it.
+struct RegisteredVirtualMachine {
+    vm: Strong<dyn IVirtualMachine>,
+}
+
+impl Interface for RegisteredVirtualMachine {}
+
+impl IVirtualMachine for RegisteredVirtualMachine {
+    fn getCid(&self) -> binder::Result<i32> {
+        self.vm.getCid()
+    }
+
+    fn getState(&self) -> binder::Result<VirtualMachineState> {
+        self.vm.getState()
+    }
+
+    fn registerCallback(
+        &self,
+        callback: &Strong<dyn IVirtualMachineCallback>,
+    ) -> binder::Result<()> {
+        // Binder objects of the client can't be passed on to another process, so relay the calls.
+        let relay = BnVirtualMachineCallback::new_binder(
+            CallbackRelay { callback: callback.clone() },
+            BinderFeatures::default(),
+        );
+        self.vm.registerCallback(&relay)
+    }
+
+    fn start(&self) -> binder::Result<()> {
+        self.vm.start()
+    }
+
+    fn stop(&self) -> binder::Result<()> {
+        self.vm.stop()
+    }
+
+    fn shutdown(&self, timeout_millis: i64) -> binder::Result<bool> {
+        self.vm.shutdown(timeout_millis)
+    }
+
+    fn getMemoryBalloon(&self) -> binder::Result<i64> {
+        self.vm.getMemoryBalloon()
+    }
+
+    fn setMemoryBalloon(&self, num_bytes: i64) -> binder::Result<()> {
+        self.vm.setMemoryBalloon(num_bytes)
+    }
+
+    fn getPeakRssKb(&self) -> binder::Result<i64> {
+        self.vm.getPeakRssKb()
+    }
+
+    fn setMemory(&self, target_mib: i32) -> binder::Result<()> {
+        self.vm.setMemory(target_mib)
+    }
+
+    fn startWhenPossible(&self) -> binder::Result<()> {
+        self.vm.startWhenPossible()
+    }
+
+    fn getDeferredStartStatus(&self) -> binder::Result<DeferredStartStatus> {
+        self.vm.getDeferredStartStatus()
+    }
+
+    fn cancelDeferredStart(&self) -> binder::Result<()> {
+        self.vm.cancelDeferredStart()
+    }
+
+    fn connectVsock(&self, port: i32) -> binder::Result<ParcelFileDescriptor> {
+        self.vm.connectVsock(port)
+    }
+
+    fn setHostConsoleName(&self, ptsname: &str) -> binder::Result<()> {
+        self.vm.setHostConsoleName(ptsname)
+    }
+
+    fn suspend(&self) -> binder::Result<()> {
+        self.vm.suspend()
+    }
+
+    fn resume(&self) -> binder::Result<()> {
+        self.vm.resume()
+    }
+
+    fn getDebugConfig(&self) -> binder::Result<DebugConfig> {
+        self.vm.getDebugConfig()
+    }
+
+    fn setDebugFacilityEnabled(
+        &self,
+        facility: DebugFacility,
+        enabled: bool,
+    ) -> binder::Result<()> {
+        self.vm.setDebugFacilityEnabled(facility, enabled)
+    }
+
+    fn setRamdumpOutput(&self, output: &ParcelFileDescriptor) -> binder::Result<()> {
+        self.vm.setRamdumpOutput(output)
+    }
+
+    fn provideHostFile(
+        &self,
+        request_id: i32,
+        file: Option<&ParcelFileDescriptor>,
+    ) -> binder::Result<()> {
+        self.vm.provideHostFile(request_id, file)
+    }
+
+    fn getDeprecationWarnings(&self) -> binder::Result<Vec<DeprecationWarning>> {
+        self.vm.getDeprecationWarnings()
+    }
+
+    fn prepareForSnapshot(&self, timeout_millis: i32) -> binder::Result<bool> {
+        self.vm.prepareForSnapshot(timeout_millis)
+    }
+
+    fn notifyRestoredFromSnapshot(&self) -> binder::Result<()> {
+        self.vm.notifyRestoredFromSnapshot()
+    }
+
+    fn rollbackStorage(&self, generation: i32) -> binder::Result<()> {
+        self.vm.rollbackStorage(generation)
+    }
+
+    fn migrateToNewProcess(&self) -> binder::Result<()> {
+        self.vm.migrateToNewProcess()
+    }
+
+    fn setNetworkFirewall(&self, firewall: &NetworkFirewall) -> binder::Result<()> {
+        self.vm.setNetworkFirewall(firewall)
+    }
+}
+
+/// Relays the notifications of a VM found by name to the callback of the client.
+struct CallbackRelay {
+    callback: Strong<dyn IVirtualMachineCallback>,
+}
+
+impl Interface for CallbackRelay {}
+
+impl IVirtualMachineCallback for CallbackRelay {
+    fn onPayloadStarted(&self, cid: i32) -> binder::Result<()> {
+        self.callback.onPayloadStarted(cid)
+    }
+
+    fn onPayloadReady(&self, cid: i32) -> binder::Result<()> {
+        self.callback.onPayloadReady(cid)
+    }
+
+    fn onPayloadFinished(&self, cid: i32, exit_code: i32) -> binder::Result<()> {
+        self.callback.onPayloadFinished(cid, exit_code)
+    }
+
+    fn onError(&self, cid: i32, error_code: ErrorCode, message: &str) -> binder::Result<()> {
+        self.callback.onError(cid, error_code, message)
+    }
+
+    fn onRamdumpAvailable(&self, cid: i32) -> binder::Result<()> {
+        self.callback.onRamdumpAvailable(cid)
+    }
+
+    fn onHostFileRequested(
+        &self,
+        cid: i32,
+        request_id: i32,
+        mime_type: &str,
+    ) -> binder::Result<()> {
+        self.callback.onHostFileRequested(cid, request_id, mime_type)
+    }
+
+    fn onRestarted(&self, cid: i32) -> binder::Result<()> {
+        self.callback.onRestarted(cid)
+    }
+
+    fn onGuestCrashReported(&self, cid: i32, reason: &GuestCrashReason) -> binder::Result<()> {
+        self.callback.onGuestCrashReported(cid, reason)
+    }
+
+    fn onDied(&self, cid: i32, reason: DeathReason) -> binder::Result<()> {
+        self.callback.onDied(cid, reason)
+    }
+}
@@ -0,0 +1,378 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Forwarding of connections from host abstract Unix domain sockets to vsock ports of a VM.
+
+use crate::aidl::Cid;
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::PortForwardingRule::PortForwardingRule as PortForwardingRuleParcelable;
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use nix::sys::socket::{getsockopt, shutdown, sockopt, Shutdown};
+use std::collections::HashSet;
+use std::io::{self, ErrorKind, Write};
+use std::net;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{SocketAddr, UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use vsock::VsockStream;
+
+/// Maximum length of an abstract socket name, excluding the leading NUL byte of `sun_path`.
+const MAX_ABSTRACT_SOCKET_NAME_LEN: usize = 107;
+
+/// The lowest vsock port that a rule may forward to. Lower ports are privileged.
+pub const MIN_GUEST_PORT: u32 = 1024;
+
+/// The maximum number of connections forwarded at the same time for a single rule. Further
+/// connections are closed as soon as they are accepted.
+const MAX_ACTIVE_CONNECTIONS: u64 = 16;
+
+/// How long to wait before accepting again after accept() failed, e.g. because the process ran
+/// out of file descriptors.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// A validated rule forwarding a host abstract socket to a vsock port of the VM.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PortForwardingRule {
+    pub host_socket_name: String,
+    pub guest_port: u32,
+}
+
+impl PortForwardingRule {
+    pub fn new(raw_rule: &PortForwardingRuleParcelable) -> Result<PortForwardingRule> {
+        let host_socket_name = &raw_rule.hostSocketName;
+        if host_socket_name.is_empty() {
+            bail!("Host socket name must not be empty");
+        }
+        if host_socket_name.len() > MAX_ABSTRACT_SOCKET_NAME_LEN {
+            bail!("Host socket name {host_socket_name:?} is too long");
+        }
+        if host_socket_name.contains('\0') {
+            bail!("Host socket name {host_socket_name:?} must not contain NUL");
+        }
+        let guest_port = u32::try_from(raw_rule.guestPort)
+            .with_context(|| format!("Invalid guest port {}", raw_rule.guestPort))?;
+        if guest_port < MIN_GUEST_PORT {
+            bail!("Can't forward to privileged port {guest_port}");
+        }
+        Ok(PortForwardingRule { host_socket_name: host_socket_name.clone(), guest_port })
+    }
+}
+
+/// Validates the given rules and converts them, rejecting duplicated host socket names.
+pub fn parse_port_forwarding_rules(
+    raw_rules: &[PortForwardingRuleParcelable],
+) -> Result<Vec<PortForwardingRule>> {
+    let mut names = HashSet::new();
+    raw_rules
+        .iter()
+        .map(|raw_rule| {
+            let rule = PortForwardingRule::new(raw_rule)?;
+            if !names.insert(rule.host_socket_name.clone()) {
+                bail!("Duplicated host socket name {:?}", rule.host_socket_name);
+            }
+            Ok(rule)
+        })
+        .collect()
+}
+
+/// Connection counters of a single forwarding rule.
+#[derive(Debug, Default)]
+pub struct ForwardingStats {
+    /// Number of connections accepted on the host socket since the VM started.
+    pub total_connections: AtomicU64,
+    /// Number of connections currently being forwarded.
+    pub active_connections: AtomicU64,
+    /// Number of accepted connections which couldn't be connected to the VM.
+    pub failed_connections: AtomicU64,
+    /// Number of accepted connections which were closed because they came from another uid than
+    /// the owner of the VM, or because too many connections were active.
+    pub rejected_connections: AtomicU64,
+}
+
+/// A host socket being listened on for a single forwarding rule.
+#[derive(Debug)]
+struct Forwarding {
+    rule: PortForwardingRule,
+    listener: Arc<UnixListener>,
+    stats: Arc<ForwardingStats>,
+    accept_thread: Option<JoinHandle<()>>,
+}
+
+/// Supervises the forwarding rules of a VM. The host sockets are listened on as long as this
+/// object is alive; dropping it stops accepting new connections.
+#[derive(Debug, Default)]
+pub struct PortForwarder {
+    forwardings: Vec<Forwarding>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl PortForwarder {
+    /// Binds the host sockets for all the rules and starts forwarding connections to the VM with
+    /// the given CID. Fails without listening on any socket if any of them can't be bound.
+    ///
+    /// Abstract sockets can't be protected by file permissions, so only connections from
+    /// `owner_uid` are forwarded; the others are closed.
+    pub fn start(
+        cid: Cid,
+        owner_uid: u32,
+        rules: Vec<PortForwardingRule>,
+    ) -> Result<PortForwarder> {
+        let listeners = rules
+            .iter()
+            .map(|rule| {
+                let addr = SocketAddr::from_abstract_name(rule.host_socket_name.as_bytes())?;
+                UnixListener::bind_addr(&addr)
+                    .with_context(|| format!("Failed to bind @{}", rule.host_socket_name))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let forwardings = rules
+            .into_iter()
+            .zip(listeners)
+            .map(|(rule, listener)| {
+                let listener = Arc::new(listener);
+                let stats = Arc::new(ForwardingStats::default());
+                let accept_thread = {
+                    let listener = listener.clone();
+                    let stats = stats.clone();
+                    let stopped = stopped.clone();
+                    let guest_port = rule.guest_port;
+                    thread::spawn(move || {
+                        accept_loop(&listener, cid, owner_uid, guest_port, &stats, &stopped)
+                    })
+                };
+                info!(
+                    "Forwarding @{} to vsock port {} of CID {cid}",
+                    rule.host_socket_name, rule.guest_port
+                );
+                Forwarding { rule, listener, stats, accept_thread: Some(accept_thread) }
+            })
+            .collect();
+        Ok(PortForwarder { forwardings, stopped })
+    }
+
+    /// Writes the rules and their connection counters in a human readable form.
+    pub fn dump(&self, writer: &mut dyn Write) -> io::Result<()> {
+        for forwarding in &self.forwardings {
+            writeln!(
+                writer,
+                "\tPort forwarding @{} -> vsock:{}: {} total, {} active, {} failed, {} rejected \
+                 connections",
+                forwarding.rule.host_socket_name,
+                forwarding.rule.guest_port,
+                forwarding.stats.total_connections.load(Ordering::Relaxed),
+                forwarding.stats.active_connections.load(Ordering::Relaxed),
+                forwarding.stats.failed_connections.load(Ordering::Relaxed),
+                forwarding.stats.rejected_connections.load(Ordering::Relaxed),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PortForwarder {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        for forwarding in &mut self.forwardings {
+            // Shutting down a listening socket wakes up the thread blocked in accept().
+            if let Err(e) = shutdown(forwarding.listener.as_raw_fd(), Shutdown::Both) {
+                warn!("Failed to shut down @{}: {e}", forwarding.rule.host_socket_name);
+            }
+            if let Some(accept_thread) = forwarding.accept_thread.take() {
+                let _ = accept_thread.join();
+            }
+            info!(
+                "Stopped forwarding @{} after {} connections",
+                forwarding.rule.host_socket_name,
+                forwarding.stats.total_connections.load(Ordering::Relaxed)
+            );
+        }
+    }
+}
+
+fn accept_loop(
+    listener: &UnixListener,
+    cid: Cid,
+    owner_uid: u32,
+    guest_port: u32,
+    stats: &Arc<ForwardingStats>,
+    stopped: &AtomicBool,
+) {
+    for stream in listener.incoming() {
+        if stopped.load(Ordering::Relaxed) {
+            return;
+        }
+        let host_stream = match stream {
+            Ok(stream) => stream,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                warn!("Failed to accept connection for vsock port {guest_port}: {e}");
+                // Don't spin if the error persists, e.g. when running out of file descriptors.
+                thread::sleep(ACCEPT_RETRY_DELAY);
+                continue;
+            }
+        };
+        stats.total_connections.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = check_peer_uid(&host_stream, owner_uid) {
+            warn!("Rejected connection for vsock port {guest_port} of CID {cid}: {e:?}");
+            stats.rejected_connections.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        if !try_acquire_connection(stats) {
+            warn!("Too many connections to vsock port {guest_port} of CID {cid}");
+            stats.rejected_connections.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        let stats = stats.clone();
+        thread::spawn(move || {
+            if let Err(e) = forward_connection(host_stream, cid, guest_port) {
+                debug!("Forwarding to vsock port {guest_port} of CID {cid} failed: {e:?}");
+                stats.failed_connections.fetch_add(1, Ordering::Relaxed);
+            }
+            stats.active_connections.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
+
+/// Checks that the process at the other end of `stream` runs as `owner_uid`.
+fn check_peer_uid(stream: &UnixStream, owner_uid: u32) -> Result<()> {
+    let credentials = getsockopt(stream, sockopt::PeerCredentials)
+        .context("Failed to get the credentials of the peer")?;
+    if credentials.uid() != owner_uid {
+        bail!("Peer uid {} isn't the owner of the VM", credentials.uid());
+    }
+    Ok(())
+}
+
+/// Counts a new active connection, unless `MAX_ACTIVE_CONNECTIONS` are already active.
+fn try_acquire_connection(stats: &ForwardingStats) -> bool {
+    stats
+        .active_connections
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |active| {
+            (active < MAX_ACTIVE_CONNECTIONS).then_some(active + 1)
+        })
+        .is_ok()
+}
+
+/// Copies data in both directions between `host_stream` and a new vsock connection to the VM,
+/// until both sides have closed.
+fn forward_connection(host_stream: UnixStream, cid: Cid, guest_port: u32) -> Result<()> {
+    let guest_stream = VsockStream::connect_with_cid_port(cid, guest_port)
+        .context("Failed to connect to the VM")?;
+
+    let mut host_reader = host_stream.try_clone()?;
+    let mut guest_writer = guest_stream.try_clone()?;
+    let to_guest = thread::spawn(move || {
+        let result = io::copy(&mut host_reader, &mut guest_writer);
+        let _ = guest_writer.shutdown(net::Shutdown::Write);
+        result
+    });
+
+    let mut guest_reader = guest_stream;
+    let mut host_writer = host_stream;
+    let to_host = io::copy(&mut guest_reader, &mut host_writer);
+    let _ = host_writer.shutdown(net::Shutdown::Write);
+
+    to_guest.join().unwrap().context("Failed to copy from host to guest")?;
+    to_host.context("Failed to copy from guest to host")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn raw_rule(name: &str, port: i32) -> PortForwardingRuleParcelable {
+        PortForwardingRuleParcelable { hostSocketName: name.to_owned(), guestPort: port }
+    }
+
+    #[test]
+    fn test_parse_valid_rules() -> Result<()> {
+        let rules = parse_port_forwarding_rules(&[raw_rule("a", 5000), raw_rule("b", 5000)])?;
+        assert_eq!(
+            rules,
+            vec![
+                PortForwardingRule { host_socket_name: "a".to_owned(), guest_port: 5000 },
+                PortForwardingRule { host_socket_name: "b".to_owned(), guest_port: 5000 },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_invalid_rules() {
+        assert!(parse_port_forwarding_rules(&[raw_rule("", 5000)]).is_err());
+        assert!(parse_port_forwarding_rules(&[raw_rule("a\0b", 5000)]).is_err());
+        assert!(parse_port_forwarding_rules(&[raw_rule(&"a".repeat(108), 5000)]).is_err());
+        assert!(parse_port_forwarding_rules(&[raw_rule("a", 1023)]).is_err());
+        assert!(parse_port_forwarding_rules(&[raw_rule("a", -1)]).is_err());
+        assert!(parse_port_forwarding_rules(&[raw_rule("a", 5000), raw_rule("a", 5001)]).is_err());
+    }
+
+    #[test]
+    fn test_check_peer_uid() -> Result<()> {
+        let (stream, _peer) = UnixStream::pair()?;
+        let uid = nix::unistd::getuid().as_raw();
+        check_peer_uid(&stream, uid)?;
+        assert!(check_peer_uid(&stream, uid + 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_active_connections_are_bounded() {
+        let stats = ForwardingStats::default();
+        for _ in 0..MAX_ACTIVE_CONNECTIONS {
+            assert!(try_acquire_connection(&stats));
+        }
+        assert!(!try_acquire_connection(&stats));
+        stats.active_connections.fetch_sub(1, Ordering::Relaxed);
+        assert!(try_acquire_connection(&stats));
+    }
+
+    #[test]
+    fn test_host_sockets_released_on_drop() -> Result<()> {
+        let name = format!("port_forwarding_test_{}", std::process::id());
+        let rules =
+            || vec![PortForwardingRule { host_socket_name: name.clone(), guest_port: 5000 }];
+        let uid = nix::unistd::getuid().as_raw();
+
+        let forwarder = PortForwarder::start(123, uid, rules())?;
+        // The name is taken while the forwarder is alive.
+        assert!(PortForwarder::start(123, uid, rules()).is_err());
+        drop(forwarder);
+        PortForwarder::start(123, uid, rules())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_connection_from_other_uid_is_rejected() -> Result<()> {
+        let name = format!("port_forwarding_reject_test_{}", std::process::id());
+        let rules = vec![PortForwardingRule { host_socket_name: name.clone(), guest_port: 5000 }];
+        let other_uid = nix::unistd::getuid().as_raw() + 1;
+        let forwarder = PortForwarder::start(123, other_uid, rules)?;
+
+        let stream = UnixStream::connect_addr(&SocketAddr::from_abstract_name(name.as_bytes())?)?;
+        // The forwarder closes the connection without forwarding it.
+        let mut buf = [0u8; 1];
+        assert_eq!((&stream).read(&mut buf)?, 0);
+        assert_eq!(forwarder.forwardings[0].stats.rejected_connections.load(Ordering::Relaxed), 1);
+        Ok(())
+    }
+}
@@ -15,6 +15,7 @@
 //! Wrapper to libselinux
 
 use anyhow::{anyhow, bail, Context, Result};
+use log::error;
 use std::ffi::{CStr, CString};
 use std::fmt;
 use std::io;
@@ -29,7 +30,6 @@ use std::ptr;
 /// `freecon` to free the resources when dropped. In its second variant it stores
 /// an `std::ffi::CString` that can be initialized from a Rust string slice.
 #[derive(Debug)]
-#[allow(dead_code)] // CString variant is used in tests
 pub enum SeContext {
     /// Wraps a raw context c-string as returned by libselinux.
     Raw(*mut ::std::os::raw::c_char),
@@ -78,7 +78,6 @@ impl Deref for SeContext {
 
 impl SeContext {
     /// Initializes the `SeContext::CString` variant from a Rust string slice.
-    #[allow(dead_code)] // Used in tests
     pub fn new(con: &str) -> Result<Self> {
         Ok(Self::CString(
             CString::new(con)
@@ -87,17 +86,76 @@ impl SeContext {
     }
 
     pub fn selinux_type(&self) -> Result<&str> {
+        // We only want the type.
+        Ok(self.fields()?[2])
+    }
+
+    /// Returns the same context, except for its type which is replaced by `selinux_type`. The
+    /// user, role and security level, e.g. the categories of the app owning a VM, are kept.
+    pub fn with_selinux_type(&self, selinux_type: &str) -> Result<SeContext> {
+        let mut fields = self.fields()?;
+        fields[2] = selinux_type;
+        SeContext::new(&fields.join(":"))
+    }
+
+    /// Returns whether the security level, i.e. the sensitivity and categories, is that of
+    /// `other`.
+    pub fn has_same_level(&self, other: &SeContext) -> Result<bool> {
+        Ok(self.fields()?[3..] == other.fields()?[3..])
+    }
+
+    fn fields(&self) -> Result<Vec<&str>> {
         let context = self.deref().to_str().context("Label is not valid UTF8")?;
 
         // The syntax is user:role:type:sensitivity[:category,...],
         // ignoring security level ranges, which don't occur on Android. See
         // https://github.com/SELinuxProject/selinux-notebook/blob/main/src/security_context.md
-        // We only want the type.
         let fields: Vec<_> = context.split(':').collect();
         if fields.len() < 4 || fields.len() > 5 {
             bail!("Syntactically invalid label {}", self);
         }
-        Ok(fields[2])
+        Ok(fields)
+    }
+}
+
+/// Returns the context of the current process.
+pub fn getcon() -> Result<SeContext> {
+    let mut con: *mut c_char = ptr::null_mut();
+    // SAFETY: the returned pointer `con` is wrapped in SeContext::Raw which is freed with
+    // `freecon` when it is dropped.
+    match unsafe { selinux_bindgen::getcon(&mut con) } {
+        0 => {
+            if !con.is_null() {
+                Ok(SeContext::Raw(con))
+            } else {
+                Err(anyhow!("getcon returned a NULL context"))
+            }
+        }
+        _ => Err(anyhow!(io::Error::last_os_error())).context("getcon failed"),
+    }
+}
+
+/// Sets the context of the programs executed by the current thread, until the returned guard is
+/// dropped. Processes forked by the thread in the meantime inherit it too.
+pub fn setexeccon(context: &SeContext) -> Result<ExecConGuard> {
+    // SAFETY: `context` is a valid C string, which libselinux doesn't keep after the call.
+    if unsafe { selinux_bindgen::setexeccon(context.as_ptr()) } != 0 {
+        return Err(anyhow!(io::Error::last_os_error()))
+            .with_context(|| format!("setexeccon({context}) failed"));
+    }
+    Ok(ExecConGuard(()))
+}
+
+/// Restores the default context of the programs executed by the current thread when dropped.
+#[must_use]
+pub struct ExecConGuard(());
+
+impl Drop for ExecConGuard {
+    fn drop(&mut self) {
+        // SAFETY: a NULL context is allowed, and restores the default transition of the policy.
+        if unsafe { selinux_bindgen::setexeccon(ptr::null()) } != 0 {
+            error!("Failed to reset the exec context: {}", io::Error::last_os_error());
+        }
     }
 }
 
@@ -117,3 +175,34 @@ pub fn getfilecon<F: AsRawFd>(file: &F) -> Result<SeContext> {
         _ => Err(anyhow!(io::Error::last_os_error())).context("fgetfilecon failed"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn with_selinux_type_keeps_the_categories() -> Result<()> {
+        let context = SeContext::new("u:r:virtualizationmanager:s0:c10,c256,c512,c768")?;
+        let vendor = context.with_selinux_type("crosvm_vendor")?;
+        assert_eq!(vendor.to_str()?, "u:r:crosvm_vendor:s0:c10,c256,c512,c768");
+        assert_eq!(vendor.selinux_type()?, "crosvm_vendor");
+        Ok(())
+    }
+
+    #[test]
+    fn with_selinux_type_without_categories() -> Result<()> {
+        let context = SeContext::new("u:r:virtualizationmanager:s0")?;
+        assert_eq!(context.with_selinux_type("crosvm_vendor")?.to_str()?, "u:r:crosvm_vendor:s0");
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_contexts() -> Result<()> {
+        for context in ["u:r:virtualizationmanager", "u:r:t:s0:c1:extra"] {
+            let context = SeContext::new(context)?;
+            assert!(context.selinux_type().is_err(), "{context}");
+            assert!(context.with_selinux_type("crosvm_vendor").is_err(), "{context}");
+        }
+        Ok(())
+    }
+}
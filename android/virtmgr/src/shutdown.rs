@@ -0,0 +1,201 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Graceful shutdown of the VMs, when the device shuts down or on request of their owner.
+
+use crate::crosvm::VmInstance;
+use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::IShutdownCallback::IShutdownCallback;
+use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IVmShutdownHandler::{
+    BnVmShutdownHandler, IVmShutdownHandler,
+};
+use anyhow::Context;
+use avflog::LogResult;
+use binder::{BinderFeatures, ExceptionCode, Interface, IntoBinderResult, Strong};
+use log::{info, warn};
+use std::sync::mpsc::{sync_channel, RecvTimeoutError};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often `ShutdownCallback::join` checks whether the call to the payload returned.
+const JOIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The shutdown callback registered by the payload, if any.
+#[derive(Debug, Default)]
+pub struct ShutdownCallback {
+    callback: Mutex<Option<Strong<dyn IShutdownCallback>>>,
+    /// The thread calling the payload, if the call didn't return in time.
+    pending: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl ShutdownCallback {
+    /// Replaces the callback of the payload.
+    pub fn set(&self, callback: Option<Strong<dyn IShutdownCallback>>) {
+        *self.callback.lock().unwrap() = callback;
+    }
+
+    /// Asks the payload to flush its state and exit, and waits for its acknowledgement for at most
+    /// `window`. Returns whether the payload acknowledged in time, or `None` if it doesn't listen
+    /// to shutdown requests.
+    ///
+    /// If the payload doesn't return in time, the call is left pending until `join`.
+    pub fn request_shutdown(&self, window: Duration) -> Option<bool> {
+        let callback = self.callback.lock().unwrap().clone()?;
+        let mut pending = self.pending.lock().unwrap();
+        if pending.as_ref().is_some_and(|thread| !thread.is_finished()) {
+            warn!("Payload is still handling the previous shutdown request");
+            return Some(false);
+        }
+        let (sender, receiver) = sync_channel(1);
+        // The callback is called from another thread, as the payload may not return in time.
+        let thread = thread::spawn(move || {
+            let _ = sender.send(callback.onShutdownRequested());
+        });
+        let result = receiver.recv_timeout(window);
+        if result.is_err() {
+            *pending = Some(thread);
+        } else if let Err(e) = thread.join() {
+            warn!("Shutdown callback thread panicked: {e:?}");
+        }
+        Some(match result {
+            Ok(Ok(())) => {
+                info!("Payload is ready to be shut down");
+                true
+            }
+            Ok(Err(e)) => {
+                warn!("Payload failed to prepare for shutdown: {e:?}");
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                warn!("Payload didn't prepare for shutdown within {window:?}");
+                false
+            }
+            Err(RecvTimeoutError::Disconnected) => false,
+        })
+    }
+
+    /// Waits for at most `timeout` for the call left pending by `request_shutdown` to return,
+    /// which it does once the VM is dead, as that breaks the connection to the payload. Returns
+    /// whether no call is pending anymore.
+    pub fn join(&self, timeout: Duration) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(thread) = pending.take() else { return true };
+        let deadline = Instant::now() + timeout;
+        while !thread.is_finished() {
+            if Instant::now() >= deadline {
+                warn!("Payload is still handling the shutdown request after {timeout:?}");
+                *pending = Some(thread);
+                return false;
+            }
+            thread::sleep(JOIN_POLL_INTERVAL);
+        }
+        if let Err(e) = thread.join() {
+            warn!("Shutdown callback thread panicked: {e:?}");
+        }
+        true
+    }
+}
+
+/// Lets virtualizationservice shut the VM down before the device shuts down.
+pub fn set_handler(instance: &Arc<VmInstance>) -> binder::Result<()> {
+    // The handler only holds a weak reference, as virtualizationservice holds the handler as long
+    // as the VM context, which the VM holds.
+    let handler = VmShutdownHandler { instance: Arc::downgrade(instance) };
+    let handler = BnVmShutdownHandler::new_binder(handler, BinderFeatures::default());
+    instance.vm_context.global_context.setShutdownHandler(&handler)
+}
+
+struct VmShutdownHandler {
+    instance: Weak<VmInstance>,
+}
+
+impl Interface for VmShutdownHandler {}
+
+impl IVmShutdownHandler for VmShutdownHandler {
+    fn shutdown(&self, timeout_millis: i64) -> binder::Result<bool> {
+        let Some(instance) = self.instance.upgrade() else {
+            return Ok(true);
+        };
+        let timeout = timeout_millis
+            .try_into()
+            .map(Duration::from_millis)
+            .context("Invalid shutdown timeout")
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        instance
+            .shutdown(Instant::now() + timeout)
+            .with_context(|| format!("Error shutting {instance} down"))
+            .with_log()
+            .or_service_specific_exception(-1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::IShutdownCallback::BnShutdownCallback;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+
+    /// Callback which only returns once told to.
+    struct BlockingCallback {
+        release: Mutex<Receiver<()>>,
+    }
+
+    impl Interface for BlockingCallback {}
+
+    impl IShutdownCallback for BlockingCallback {
+        fn onShutdownRequested(&self) -> binder::Result<()> {
+            let _ = self.release.lock().unwrap().recv();
+            Ok(())
+        }
+    }
+
+    fn blocking_callback() -> (ShutdownCallback, Sender<()>) {
+        let (release, receiver) = channel();
+        let callback = BnShutdownCallback::new_binder(
+            BlockingCallback { release: Mutex::new(receiver) },
+            BinderFeatures::default(),
+        );
+        let shutdown_callback = ShutdownCallback::default();
+        shutdown_callback.set(Some(callback));
+        (shutdown_callback, release)
+    }
+
+    #[test]
+    fn no_callback_is_none() {
+        let shutdown_callback = ShutdownCallback::default();
+        assert_eq!(shutdown_callback.request_shutdown(Duration::from_millis(10)), None);
+        assert!(shutdown_callback.join(Duration::ZERO));
+    }
+
+    #[test]
+    fn acknowledged_call_is_joined() {
+        let (shutdown_callback, release) = blocking_callback();
+        release.send(()).unwrap();
+        assert_eq!(shutdown_callback.request_shutdown(Duration::from_secs(10)), Some(true));
+        assert!(shutdown_callback.pending.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn late_call_is_joined_once_it_returns() {
+        let (shutdown_callback, release) = blocking_callback();
+        assert_eq!(shutdown_callback.request_shutdown(Duration::from_millis(10)), Some(false));
+        // The previous call is still pending, so the payload isn't called again.
+        assert_eq!(shutdown_callback.request_shutdown(Duration::from_millis(10)), Some(false));
+        assert!(!shutdown_callback.join(Duration::from_millis(10)));
+
+        release.send(()).unwrap();
+        assert!(shutdown_callback.join(Duration::from_secs(10)));
+        assert!(shutdown_callback.pending.lock().unwrap().is_none());
+    }
+}
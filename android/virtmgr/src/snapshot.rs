@@ -0,0 +1,164 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Coordination of host-initiated snapshots with the payload.
+//!
+//! Before the owner of the VM snapshots it, the payload is notified and given a bounded quiesce
+//! window to flush its state. Returning from the callback acknowledges that the payload is ready.
+//! The payload is notified again once the VM has been restored.
+
+use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::ISnapshotCallback::ISnapshotCallback;
+use binder::Strong;
+use log::{info, warn};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, RecvTimeoutError, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// The longest quiesce window the owner of the VM may grant the payload.
+pub const MAX_QUIESCE_WINDOW: Duration = Duration::from_secs(10);
+
+/// The snapshot callback registered by the payload, if any.
+#[derive(Debug, Default)]
+pub struct SnapshotCallback {
+    callback: Mutex<Option<Strong<dyn ISnapshotCallback>>>,
+    notifier: Mutex<Option<Notifier>>,
+}
+
+/// A notification to deliver to the payload.
+enum Notification {
+    PreSnapshot(Strong<dyn ISnapshotCallback>, SyncSender<binder::Result<()>>),
+    PostRestore(Strong<dyn ISnapshotCallback>),
+}
+
+/// The thread calling into the payload, so that the owner of the VM isn't blocked by a payload
+/// that doesn't return. There is at most one per VM, and it is stopped and joined along with the
+/// VM. A call the payload never returns from fails once the connection to the VM is gone.
+#[derive(Debug)]
+struct Notifier {
+    sender: Sender<Notification>,
+    pending: Arc<AtomicUsize>,
+    thread: JoinHandle<()>,
+}
+
+impl Notifier {
+    fn new() -> Self {
+        let (sender, receiver) = channel();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let thread = {
+            let pending = pending.clone();
+            thread::spawn(move || {
+                for notification in receiver {
+                    match notification {
+                        Notification::PreSnapshot(callback, result) => {
+                            // The owner of the VM may have stopped waiting already.
+                            let _ = result.send(callback.onPreSnapshot());
+                        }
+                        Notification::PostRestore(callback) => {
+                            if let Err(e) = callback.onPostRestore() {
+                                warn!("Failed to notify payload of restore: {e:?}");
+                            }
+                        }
+                    }
+                    pending.fetch_sub(1, Ordering::SeqCst);
+                }
+            })
+        };
+        Self { sender, pending, thread }
+    }
+
+    /// Queues `notification`, unless the payload is still handling an earlier one and
+    /// `only_if_idle` is set. Returns whether it was queued.
+    fn notify(&self, notification: Notification, only_if_idle: bool) -> bool {
+        if only_if_idle && self.pending.load(Ordering::SeqCst) > 0 {
+            return false;
+        }
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        if self.sender.send(notification).is_err() {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+}
+
+impl SnapshotCallback {
+    /// Replaces the callback of the payload.
+    pub fn set(&self, callback: Option<Strong<dyn ISnapshotCallback>>) {
+        *self.callback.lock().unwrap() = callback;
+    }
+
+    fn notify(&self, notification: Notification, only_if_idle: bool) -> bool {
+        self.notifier
+            .lock()
+            .unwrap()
+            .get_or_insert_with(Notifier::new)
+            .notify(notification, only_if_idle)
+    }
+
+    /// Notifies the payload of an upcoming snapshot and waits for its acknowledgement, for at most
+    /// `quiesce_window`. Returns whether the payload acknowledged in time, which is trivially the
+    /// case if it doesn't listen to snapshots.
+    pub fn pre_snapshot(&self, quiesce_window: Duration) -> bool {
+        let Some(callback) = self.callback.lock().unwrap().clone() else {
+            return true;
+        };
+        let quiesce_window = quiesce_window.min(MAX_QUIESCE_WINDOW);
+        let (sender, receiver) = sync_channel(1);
+        if !self.notify(Notification::PreSnapshot(callback, sender), true) {
+            warn!("Payload is still handling an earlier snapshot notification");
+            return false;
+        }
+        match receiver.recv_timeout(quiesce_window) {
+            Ok(Ok(())) => {
+                info!("Payload is ready to be snapshotted");
+                true
+            }
+            Ok(Err(e)) => {
+                warn!("Payload failed to prepare for snapshot: {e:?}");
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                warn!("Payload didn't prepare for snapshot within {quiesce_window:?}");
+                false
+            }
+            Err(RecvTimeoutError::Disconnected) => false,
+        }
+    }
+
+    /// Notifies the payload that the VM has been restored from a snapshot, without blocking the
+    /// owner of the VM on the payload.
+    pub fn post_restore(&self) {
+        let Some(callback) = self.callback.lock().unwrap().clone() else {
+            return;
+        };
+        self.notify(Notification::PostRestore(callback), false);
+    }
+}
+
+impl Drop for SnapshotCallback {
+    fn drop(&mut self) {
+        // Release the callback first, so that a call which is still in flight is the last
+        // reference to the payload.
+        self.callback.get_mut().unwrap().take();
+        if let Some(Notifier { sender, thread, .. }) = self.notifier.get_mut().unwrap().take() {
+            // Closing the channel stops the thread once it has delivered what is queued.
+            drop(sender);
+            if thread.join().is_err() {
+                warn!("Snapshot notifier thread panicked");
+            }
+        }
+    }
+}
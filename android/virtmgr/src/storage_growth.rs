@@ -0,0 +1,99 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Growth of the encrypted storage of a VM, up to the maximum size which its owner allows.
+//!
+//! The storage is a partition of the writable composite disk of the VM, whose layout crosvm can't
+//! change while the VM runs. The backing file is extended right away, but the VM only sees the
+//! larger partition from its next boot, when encryptedstore grows the filesystem to fill it.
+
+use anyhow::{ensure, Context, Result};
+use log::info;
+use std::fs::File;
+use std::sync::Mutex;
+
+/// The storage is grown in whole blocks of its filesystem.
+const BLOCK_SIZE: u64 = 4096;
+
+/// The encrypted storage of a VM, which its payload may grow up to `max_size`.
+#[derive(Debug)]
+pub struct StorageGrowth {
+    storage: Mutex<File>,
+    max_size: u64,
+}
+
+impl StorageGrowth {
+    /// Allows the payload to grow `storage` up to `max_size` bytes.
+    pub fn new(storage: File, max_size: u64) -> Result<Self> {
+        let size = storage.metadata()?.len();
+        ensure!(max_size >= size, "Maximum storage size {max_size} is below its size {size}");
+        Ok(Self { storage: Mutex::new(storage), max_size })
+    }
+
+    /// Extends the storage to at least `size` bytes, rounded up to whole blocks, and returns its
+    /// new size. The storage is never shrunk, so that the filesystem on it stays intact.
+    pub fn grow(&self, size: u64) -> Result<u64> {
+        let size = size
+            .checked_next_multiple_of(BLOCK_SIZE)
+            .with_context(|| format!("Invalid storage size {size}"))?;
+        let storage = self.storage.lock().unwrap();
+        let current_size = storage.metadata()?.len();
+        if size <= current_size {
+            return Ok(current_size);
+        }
+        ensure!(
+            size <= self.max_size,
+            "Storage size {size} exceeds the maximum of {} bytes",
+            self.max_size
+        );
+        storage.set_len(size).context("Failed to extend the storage")?;
+        info!("Grew the encrypted storage from {current_size} to {size} bytes");
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempfile;
+
+    fn storage(size: u64) -> Result<File> {
+        let file = tempfile()?;
+        file.set_len(size)?;
+        Ok(file)
+    }
+
+    #[test]
+    fn grows_in_whole_blocks() -> Result<()> {
+        let growth = StorageGrowth::new(storage(BLOCK_SIZE)?, 4 * BLOCK_SIZE)?;
+        assert_eq!(growth.grow(BLOCK_SIZE + 1)?, 2 * BLOCK_SIZE);
+        assert_eq!(growth.storage.lock().unwrap().metadata()?.len(), 2 * BLOCK_SIZE);
+        Ok(())
+    }
+
+    #[test]
+    fn never_shrinks() -> Result<()> {
+        let growth = StorageGrowth::new(storage(2 * BLOCK_SIZE)?, 4 * BLOCK_SIZE)?;
+        assert_eq!(growth.grow(BLOCK_SIZE)?, 2 * BLOCK_SIZE);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_growth_beyond_maximum() -> Result<()> {
+        let growth = StorageGrowth::new(storage(BLOCK_SIZE)?, 2 * BLOCK_SIZE)?;
+        assert!(growth.grow(3 * BLOCK_SIZE).is_err());
+        assert!(StorageGrowth::new(storage(2 * BLOCK_SIZE)?, BLOCK_SIZE).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Periodic snapshots of the encrypted storage of a VM.
+//!
+//! The snapshots are kept in the temporary directory of the VM until the VM is dropped, rather
+//! than removed when it exits, so that its owner can roll the storage back after a failure.
+
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::StorageSnapshotPolicy::StorageSnapshotPolicy;
+use anyhow::{ensure, Context, Result};
+use log::info;
+use std::collections::VecDeque;
+use std::fs::{create_dir, remove_file, File};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// Name of the subdirectory of the temporary directory of the VM holding the snapshots.
+pub const STORAGE_SNAPSHOTS_DIRECTORY: &str = "storage-snapshots";
+
+/// How long the payload may take to flush its state before each snapshot.
+pub const QUIESCE_WINDOW: Duration = Duration::from_secs(2);
+
+/// Size of the chunks in which the storage is copied.
+const CHUNK_SIZE: usize = 1 << 20;
+
+#[derive(Debug)]
+struct Snapshot {
+    path: PathBuf,
+    size: u64,
+}
+
+#[derive(Debug, Default)]
+struct Generations {
+    /// The kept snapshots, oldest first.
+    snapshots: VecDeque<Snapshot>,
+    /// Used to name the next snapshot.
+    next_id: u64,
+}
+
+/// Snapshots of the encrypted storage of a VM, taken according to the policy of its owner.
+#[derive(Debug)]
+pub struct StorageSnapshots {
+    storage: File,
+    directory: PathBuf,
+    interval: Duration,
+    max_generations: usize,
+    quota: u64,
+    generations: Mutex<Generations>,
+    stopped: Mutex<bool>,
+    stopped_changed: Condvar,
+}
+
+impl StorageSnapshots {
+    /// Validates `policy` and prepares to snapshot `storage` into `temporary_directory`.
+    pub fn new(
+        policy: &StorageSnapshotPolicy,
+        storage: File,
+        temporary_directory: &Path,
+    ) -> Result<Self> {
+        let interval = u64::try_from(policy.intervalSecs)
+            .ok()
+            .filter(|&secs| secs > 0)
+            .with_context(|| format!("Invalid snapshot interval {}", policy.intervalSecs))?;
+        let max_generations = usize::try_from(policy.generations)
+            .ok()
+            .filter(|&generations| generations > 0)
+            .with_context(|| format!("Invalid number of generations {}", policy.generations))?;
+        let quota = u64::try_from(policy.quotaBytes)
+            .ok()
+            .filter(|&quota| quota > 0)
+            .with_context(|| format!("Invalid snapshot quota {}", policy.quotaBytes))?;
+        let directory = temporary_directory.join(STORAGE_SNAPSHOTS_DIRECTORY);
+        create_dir(&directory).with_context(|| format!("Failed to create {directory:?}"))?;
+        Ok(Self {
+            storage,
+            directory,
+            interval: Duration::from_secs(interval),
+            max_generations,
+            quota,
+            generations: Default::default(),
+            stopped: Mutex::new(false),
+            stopped_changed: Condvar::new(),
+        })
+    }
+
+    /// Blocks until the next snapshot is due. Returns false if the snapshots were stopped first.
+    pub fn wait_for_next(&self) -> bool {
+        let (stopped, _) = self
+            .stopped_changed
+            .wait_timeout_while(self.stopped.lock().unwrap(), self.interval, |stopped| !*stopped)
+            .unwrap();
+        !*stopped
+    }
+
+    /// Stops taking snapshots, e.g. because the VM exited. The kept snapshots remain available.
+    pub fn stop(&self) {
+        *self.stopped.lock().unwrap() = true;
+        self.stopped_changed.notify_all();
+    }
+
+    /// Copies the current content of the storage to a new snapshot, deleting the oldest ones
+    /// beyond the retention policy. The VM must not write to the storage in the meantime, and its
+    /// disk requests must be complete.
+    pub fn take(&self) -> Result<()> {
+        let size = self.storage.metadata().context("Failed to get the storage size")?.len();
+        let quota = self.quota;
+        ensure!(size <= quota, "Storage of {size} bytes exceeds the quota of {quota} bytes");
+        let mut generations = self.generations.lock().unwrap();
+        // Make room first, so that the quota also holds while the new snapshot is written.
+        while generations.snapshots.len() >= self.max_generations
+            || total_size(&generations.snapshots) + size > quota
+        {
+            let Some(oldest) = generations.snapshots.pop_front() else {
+                break;
+            };
+            remove_file(&oldest.path).with_context(|| format!("Failed to remove {oldest:?}"))?;
+        }
+
+        let path = self.directory.join(format!("{}.img", generations.next_id));
+        generations.next_id += 1;
+        let snapshot = File::create_new(&path)
+            .with_context(|| format!("Failed to create {path:?}"))
+            .and_then(|snapshot| {
+                copy_content(&self.storage, &snapshot, size)?;
+                snapshot.sync_all().context("Failed to sync the snapshot")
+            });
+        if let Err(e) = snapshot {
+            let _ = remove_file(&path);
+            return Err(e);
+        }
+        info!("Snapshotted {size} bytes of storage to {path:?}");
+        generations.snapshots.push_back(Snapshot { path, size });
+        Ok(())
+    }
+
+    /// Overwrites the storage with a snapshot, `generation` 0 being the most recent one. The VM
+    /// must not be running.
+    pub fn rollback(&self, generation: usize) -> Result<()> {
+        let generations = self.generations.lock().unwrap();
+        let snapshot = generations
+            .snapshots
+            .iter()
+            .rev()
+            .nth(generation)
+            .with_context(|| format!("No storage snapshot of generation {generation}"))?;
+        let source = File::open(&snapshot.path)
+            .with_context(|| format!("Failed to open {:?}", snapshot.path))?;
+        copy_content(&source, &self.storage, snapshot.size)?;
+        self.storage.set_len(snapshot.size).context("Failed to resize the storage")?;
+        self.storage.sync_all().context("Failed to sync the storage")?;
+        info!("Rolled the storage back to {:?}", snapshot.path);
+        Ok(())
+    }
+}
+
+fn total_size(snapshots: &VecDeque<Snapshot>) -> u64 {
+    snapshots.iter().map(|snapshot| snapshot.size).sum()
+}
+
+/// Copies the first `size` bytes of `source` to `destination`. Positional I/O is used, as the
+/// file offsets may be shared with other processes.
+fn copy_content(source: &File, destination: &File, size: u64) -> Result<()> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < size {
+        let len = usize::try_from(size - offset).unwrap_or(CHUNK_SIZE).min(CHUNK_SIZE);
+        source.read_exact_at(&mut buffer[..len], offset).context("Failed to read the storage")?;
+        destination.write_all_at(&buffer[..len], offset).context("Failed to write the storage")?;
+        offset += len as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read, write, OpenOptions};
+    use tempfile::TempDir;
+
+    fn snapshots(dir: &TempDir, generations: i32, quota_bytes: i64) -> Result<StorageSnapshots> {
+        let storage_path = dir.path().join("storage.img");
+        write(&storage_path, b"first")?;
+        let storage = OpenOptions::new().read(true).write(true).open(storage_path)?;
+        let policy =
+            StorageSnapshotPolicy { intervalSecs: 60, generations, quotaBytes: quota_bytes };
+        StorageSnapshots::new(&policy, storage, dir.path())
+    }
+
+    fn kept(snapshots: &StorageSnapshots) -> Vec<PathBuf> {
+        let generations = snapshots.generations.lock().unwrap();
+        generations.snapshots.iter().map(|snapshot| snapshot.path.clone()).collect()
+    }
+
+    #[test]
+    fn keeps_the_latest_generations() -> Result<()> {
+        let dir = TempDir::new()?;
+        let snapshots = snapshots(&dir, 2, 1024)?;
+        for _ in 0..3 {
+            snapshots.take()?;
+        }
+        let directory = dir.path().join(STORAGE_SNAPSHOTS_DIRECTORY);
+        assert_eq!(kept(&snapshots), vec![directory.join("1.img"), directory.join("2.img")]);
+        assert!(!directory.join("0.img").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_the_snapshots_within_quota() -> Result<()> {
+        let dir = TempDir::new()?;
+        let snapshots = snapshots(&dir, 5, 12)?;
+        snapshots.take()?;
+        snapshots.take()?;
+        snapshots.take()?;
+        assert_eq!(kept(&snapshots).len(), 2);
+
+        snapshots.storage.write_all_at(b"too large", 0)?;
+        snapshots.storage.write_all_at(b"for the quota", 9)?;
+        assert!(snapshots.take().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rolls_back_to_generation() -> Result<()> {
+        let dir = TempDir::new()?;
+        let snapshots = snapshots(&dir, 3, 1024)?;
+        snapshots.take()?;
+        snapshots.storage.write_all_at(b"second", 0)?;
+        snapshots.take()?;
+        snapshots.storage.write_all_at(b"third, longer", 0)?;
+
+        snapshots.rollback(1)?;
+        assert_eq!(read(dir.path().join("storage.img"))?, b"first");
+        snapshots.rollback(0)?;
+        assert_eq!(read(dir.path().join("storage.img"))?, b"second");
+        assert!(snapshots.rollback(2).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_policy() -> Result<()> {
+        let dir = TempDir::new()?;
+        assert!(snapshots(&dir, 0, 1024).is_err());
+        assert!(snapshots(&dir, 1, 0).is_err());
+        assert!(snapshots(&dir, 1, -1).is_err());
+        Ok(())
+    }
+}
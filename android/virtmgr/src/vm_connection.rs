@@ -0,0 +1,54 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Connections from a VM to host vsock ports which virtmgr listens on for it.
+
+use crate::aidl::Cid;
+use anyhow::{bail, Context, Result};
+use log::warn;
+use std::io::ErrorKind;
+use std::thread;
+use std::time::{Duration, Instant};
+use vsock::{VsockListener, VsockStream};
+
+/// How often the listener is checked for new connections while waiting for the VM.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Waits up to `timeout` for the VM with the given CID to connect to `listener`, and returns the
+/// connection. Any VM can connect to a host vsock port, so connections from other VMs are closed.
+pub fn accept_from_vm(
+    listener: &VsockListener,
+    cid: Cid,
+    timeout: Duration,
+) -> Result<VsockStream> {
+    listener.set_nonblocking(true).context("Failed to make the listener non-blocking")?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        match listener.accept() {
+            Ok((stream, peer_addr)) if peer_addr.cid() == cid => {
+                stream.set_nonblocking(false).context("Failed to make the stream blocking")?;
+                return Ok(stream);
+            }
+            Ok((_, peer_addr)) => warn!("Closed unexpected connection from {peer_addr:?}"),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if Instant::now() >= deadline {
+                    bail!("VM with CID {cid} didn't connect within {timeout:?}");
+                }
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e).context("Failed to accept connection"),
+        }
+    }
+}
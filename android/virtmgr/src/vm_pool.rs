@@ -0,0 +1,194 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Warm pool of Microdroid VMs, which are booted ahead of time for latency-sensitive launches.
+//!
+//! A client warms a VM up with `IVirtualizationService.warmUpVm`. crosvm is started and the VM
+//! boots until Microdroid Manager is about to start the payload, where it waits. A later
+//! `createVm` call from the same UID with the same config claims the warm VM instead of creating
+//! a new one, and starting it only lets the payload start.
+
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
+    IVirtualMachine::IVirtualMachine, VirtualMachineAppConfig::VirtualMachineAppConfig,
+};
+use anyhow::{Context, Result};
+use binder::Strong;
+use libc::uid_t;
+use std::fmt::{self, Write};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// How long a warm VM is kept running if it isn't claimed.
+pub const WARM_VM_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Identifies the VMs which a warm VM can be claimed for: those of the same owner, with exactly the
+/// same config.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WarmVmKey {
+    uid: uid_t,
+    name: String,
+    config: String,
+}
+
+impl WarmVmKey {
+    pub fn new(uid: uid_t, config: &VirtualMachineAppConfig) -> Result<Self> {
+        Ok(Self { uid, name: config.name.clone(), config: describe_config(config)? })
+    }
+}
+
+/// Describes every field of the config. File descriptors are numbered differently in each call,
+/// so they are described by the file they refer to instead.
+fn describe_config(config: &VirtualMachineAppConfig) -> Result<String> {
+    describe_fds(&format!("{config:?}"))
+}
+
+/// Replaces the file descriptors in the debug representation of a parcelable by the device and
+/// inode of the files they refer to.
+fn describe_fds(debug: &str) -> Result<String> {
+    const FD_START: &str = "OwnedFd { fd: ";
+    const FD_END: &str = " }";
+    let mut description = String::with_capacity(debug.len());
+    let mut rest = debug;
+    while let Some(start) = rest.find(FD_START) {
+        description.push_str(&rest[..start]);
+        rest = &rest[start + FD_START.len()..];
+        let end = rest.find(FD_END).context("Malformed file descriptor")?;
+        let fd: i32 = rest[..end].parse().context("Malformed file descriptor")?;
+        let metadata = fs::metadata(format!("/proc/self/fd/{fd}"))
+            .with_context(|| format!("Failed to stat file descriptor {fd}"))?;
+        write!(description, "File {{ dev: {}, ino: {} }}", metadata.dev(), metadata.ino())?;
+        rest = &rest[end + FD_END.len()..];
+    }
+    description.push_str(rest);
+    Ok(description)
+}
+
+/// The warm VMs of the client, which have been started but not claimed yet.
+#[derive(Default)]
+pub struct VmPool {
+    vms: Vec<(WarmVmKey, i32, Strong<dyn IVirtualMachine>)>,
+}
+
+impl fmt::Debug for VmPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.vms.iter().map(|(key, cid, _)| (&key.name, cid))).finish()
+    }
+}
+
+impl VmPool {
+    pub fn contains(&self, key: &WarmVmKey) -> bool {
+        self.vms.iter().any(|(k, _, _)| k == key)
+    }
+
+    pub fn add(&mut self, key: WarmVmKey, cid: i32, vm: Strong<dyn IVirtualMachine>) {
+        self.vms.push((key, cid, vm));
+    }
+
+    /// Removes the warm VM for the given key from the pool, and returns it.
+    pub fn claim(&mut self, key: &WarmVmKey) -> Option<Strong<dyn IVirtualMachine>> {
+        let index = self.vms.iter().position(|(k, _, _)| k == key)?;
+        Some(self.vms.swap_remove(index).2)
+    }
+
+    /// Removes the warm VM with the given CID from the pool if it wasn't claimed, and returns it.
+    /// The key may have been used by another warm VM since.
+    pub fn expire(&mut self, key: &WarmVmKey, cid: i32) -> Option<Strong<dyn IVirtualMachine>> {
+        let index = self.vms.iter().position(|(k, c, _)| k == key && *c == cid)?;
+        Some(self.vms.swap_remove(index).2)
+    }
+}
+
+/// Holds the payload of a warm VM back until the VM is claimed.
+#[derive(Debug, Default)]
+pub struct PayloadHold {
+    held: Mutex<bool>,
+    released: Condvar,
+}
+
+impl PayloadHold {
+    /// Makes the payload wait in `wait_until_released` once the VM has booted.
+    pub fn hold(&self) {
+        *self.held.lock().unwrap() = true;
+    }
+
+    /// Lets the payload start. Returns whether it was held.
+    pub fn release(&self) -> bool {
+        let was_held = std::mem::replace(&mut *self.held.lock().unwrap(), false);
+        self.released.notify_all();
+        was_held
+    }
+
+    /// Blocks while the payload is held.
+    pub fn wait_until_released(&self) {
+        let _held = self.released.wait_while(self.held.lock().unwrap(), |held| *held).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::os::fd::OwnedFd;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn fds_are_described_by_their_file() -> Result<()> {
+        let file = OwnedFd::from(File::open("/dev/null")?);
+        let same_file = OwnedFd::from(File::open("/dev/null")?);
+        let other_file = OwnedFd::from(File::open("/proc/self/exe")?);
+        let describe = |fd: &OwnedFd| describe_fds(&format!("Config {{ apk: {fd:?}, x: 1 }}"));
+
+        let description = describe(&file)?;
+        assert!(!description.contains("fd:"), "{description}");
+        assert!(description.ends_with(", x: 1 }"), "{description}");
+        assert_eq!(description, describe(&same_file)?);
+        assert_ne!(description, describe(&other_file)?);
+        Ok(())
+    }
+
+    #[test]
+    fn warm_vm_key_covers_owner_and_config() -> Result<()> {
+        let config = VirtualMachineAppConfig { name: "vm".into(), ..Default::default() };
+        let key = WarmVmKey::new(10001, &config)?;
+        assert_eq!(key, WarmVmKey::new(10001, &config)?);
+        assert_ne!(key, WarmVmKey::new(10002, &config)?);
+
+        let other_config = VirtualMachineAppConfig { memoryMib: 512, ..config };
+        assert_ne!(key, WarmVmKey::new(10001, &other_config)?);
+        Ok(())
+    }
+
+    #[test]
+    fn payload_is_not_held_by_default() {
+        let hold = PayloadHold::default();
+        hold.wait_until_released();
+        assert!(!hold.release());
+    }
+
+    #[test]
+    fn release_unblocks_waiters() {
+        let hold = Arc::new(PayloadHold::default());
+        hold.hold();
+        let waiter = {
+            let hold = hold.clone();
+            thread::spawn(move || hold.wait_until_released())
+        };
+        assert!(hold.release());
+        waiter.join().unwrap();
+        assert!(!hold.release());
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Allow-lists of the vsock ports through which a VM and the host may connect to each other.
+
+use crate::port_forwarding::{PortForwardingRule, MIN_GUEST_PORT};
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::VsockFirewall::VsockFirewall as VsockFirewallParcelable;
+use anyhow::{bail, ensure, Context, Result};
+use std::collections::BTreeSet;
+
+/// A validated vsock firewall of a VM.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct VsockFirewall {
+    allowed_guest_ports: BTreeSet<u32>,
+    /// `None` if the VM may connect to any host port.
+    allowed_host_ports: Option<BTreeSet<u32>>,
+}
+
+impl VsockFirewall {
+    pub fn new(raw_firewall: &VsockFirewallParcelable) -> Result<VsockFirewall> {
+        let allowed_guest_ports = parse_ports(&raw_firewall.allowedGuestPorts)?;
+        if let Some(port) = allowed_guest_ports.iter().find(|&&port| port < MIN_GUEST_PORT) {
+            bail!("Can't allow connections to privileged guest port {port}");
+        }
+        let allowed_host_ports = Some(parse_ports(&raw_firewall.allowedHostPorts)?);
+        Ok(VsockFirewall { allowed_guest_ports, allowed_host_ports })
+    }
+
+    /// Returns the firewall of a VM whose config has port forwarding rules but no firewall of its
+    /// own: host processes may only connect to the forwarded ports, and the VM to any host port.
+    pub fn for_forwarded_ports(rules: &[PortForwardingRule]) -> VsockFirewall {
+        let allowed_guest_ports = rules.iter().map(|rule| rule.guest_port).collect();
+        VsockFirewall { allowed_guest_ports, allowed_host_ports: None }
+    }
+
+    /// Fails unless host processes may connect to `port` of the VM.
+    pub fn check_guest_port(&self, port: u32) -> Result<()> {
+        ensure!(
+            self.allowed_guest_ports.contains(&port),
+            "Connections to guest port {port} are blocked by the vsock firewall of the VM"
+        );
+        Ok(())
+    }
+
+    /// Returns the host ports which the VM may connect to, as enforced by virtualizationservice
+    /// for its tombstone listener and reported to host services, or `None` if it may connect to
+    /// any.
+    pub fn allowed_host_ports(&self) -> Option<Vec<i32>> {
+        // The ports were parsed from i32 values, so they can't overflow.
+        Some(self.allowed_host_ports.as_ref()?.iter().map(|&port| port as i32).collect())
+    }
+}
+
+fn parse_ports(raw_ports: &[i32]) -> Result<BTreeSet<u32>> {
+    raw_ports
+        .iter()
+        .map(|&port| u32::try_from(port).with_context(|| format!("Invalid vsock port {port}")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw_firewall(guest_ports: &[i32], host_ports: &[i32]) -> VsockFirewallParcelable {
+        VsockFirewallParcelable {
+            allowedGuestPorts: guest_ports.to_vec(),
+            allowedHostPorts: host_ports.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_allowed_ports() -> Result<()> {
+        let firewall = VsockFirewall::new(&raw_firewall(&[5000, 5001], &[9000, 8000, 9000]))?;
+        assert!(firewall.check_guest_port(5000).is_ok());
+        assert!(firewall.check_guest_port(5002).is_err());
+        assert_eq!(firewall.allowed_host_ports(), Some(vec![8000, 9000]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_firewall_blocks_everything() -> Result<()> {
+        let firewall = VsockFirewall::new(&raw_firewall(&[], &[]))?;
+        assert!(firewall.check_guest_port(5000).is_err());
+        assert_eq!(firewall.allowed_host_ports(), Some(vec![]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_forwarded_ports_only() {
+        let rules = [
+            PortForwardingRule { host_socket_name: "a".to_owned(), guest_port: 5000 },
+            PortForwardingRule { host_socket_name: "b".to_owned(), guest_port: 6000 },
+        ];
+        let firewall = VsockFirewall::for_forwarded_ports(&rules);
+        assert!(firewall.check_guest_port(5000).is_ok());
+        assert!(firewall.check_guest_port(6000).is_ok());
+        assert!(firewall.check_guest_port(5001).is_err());
+        assert_eq!(firewall.allowed_host_ports(), None);
+    }
+
+    #[test]
+    fn test_invalid_ports() {
+        assert!(VsockFirewall::new(&raw_firewall(&[1023], &[])).is_err());
+        assert!(VsockFirewall::new(&raw_firewall(&[-1], &[])).is_err());
+        assert!(VsockFirewall::new(&raw_firewall(&[], &[-1])).is_err());
+    }
+}
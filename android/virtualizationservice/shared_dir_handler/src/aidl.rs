@@ -0,0 +1,172 @@
+// Copyright 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Implementation of the AIDL interface of SharedDirHandler.
+
+use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::ISharedDirHandler::ISharedDirHandler;
+use anyhow::{ensure, Context, Result};
+use binder::{self, Interface, IntoBinderResult, ParcelFileDescriptor};
+use libc::{c_int, c_uint, AT_EMPTY_PATH, AT_RECURSIVE, O_CLOEXEC, O_DIRECTORY, O_NOFOLLOW};
+use log::error;
+use std::fs::{read_link, File};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+
+// From <linux/mount.h>.
+const OPEN_TREE_CLONE: c_uint = 1;
+const OPEN_TREE_CLOEXEC: c_uint = O_CLOEXEC as c_uint;
+const MOUNT_ATTR_RDONLY: u64 = 0x1;
+
+/// `struct mount_attr` of <linux/mount.h>.
+#[repr(C)]
+#[derive(Default)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct SharedDirHandler {}
+
+impl SharedDirHandler {
+    pub fn init() -> SharedDirHandler {
+        SharedDirHandler::default()
+    }
+}
+
+impl Interface for SharedDirHandler {}
+
+impl ISharedDirHandler for SharedDirHandler {
+    fn openReadOnly(
+        &self,
+        directory: &ParcelFileDescriptor,
+    ) -> binder::Result<ParcelFileDescriptor> {
+        let directory = open_read_only(directory.as_ref())
+            .context("Failed to open the directory read-only")
+            .inspect_err(|e| error!("{e:?}"))
+            .or_service_specific_exception(-1)?;
+        Ok(ParcelFileDescriptor::new(directory))
+    }
+}
+
+/// Returns the root of a read-only mount of `directory`, detached from any mount tree, so that
+/// nothing beneath it can be modified through it, whatever the permissions of the files.
+fn open_read_only(directory: &File) -> Result<File> {
+    // Only the mounts of the mount namespace of the service can be cloned, so the directory is
+    // opened again by path, and checked to be the same.
+    let path = read_link(format!("/proc/self/fd/{}", directory.as_raw_fd()))?;
+    let reopened = File::options()
+        .read(true)
+        .custom_flags(O_DIRECTORY | O_NOFOLLOW)
+        .open(&path)
+        .with_context(|| format!("Failed to open {path:?}"))?;
+    let (expected, actual) = (directory.metadata()?, reopened.metadata()?);
+    ensure!(
+        (actual.dev(), actual.ino()) == (expected.dev(), expected.ino()),
+        "{path:?} isn't the directory which was passed"
+    );
+
+    let tree = open_tree(&reopened, OPEN_TREE_CLONE | OPEN_TREE_CLOEXEC | AT_RECURSIVE as c_uint)
+        .context("Failed to clone the mount of the directory")?;
+    let attr = MountAttr { attr_set: MOUNT_ATTR_RDONLY, ..Default::default() };
+    mount_setattr(&tree, AT_RECURSIVE, &attr).context("Failed to make the mount read-only")?;
+    // The tree is an O_PATH file descriptor, which crosvm couldn't read the directory from.
+    let root = File::options()
+        .read(true)
+        .custom_flags(O_DIRECTORY | O_NOFOLLOW)
+        .open(format!("/proc/self/fd/{}", tree.as_raw_fd()))
+        .context("Failed to open the root of the mount")?;
+    Ok(root)
+}
+
+fn open_tree(directory: &File, flags: c_uint) -> io::Result<OwnedFd> {
+    // SAFETY: The path is a valid empty C string, and the call doesn't affect the memory of this
+    // process.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_open_tree,
+            directory.as_raw_fd(),
+            c"".as_ptr(),
+            flags | AT_EMPTY_PATH as c_uint,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: open_tree() returned a new file descriptor, which nothing else owns.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as c_int) })
+}
+
+fn mount_setattr(tree: &OwnedFd, flags: c_int, attr: &MountAttr) -> io::Result<()> {
+    // SAFETY: The path is a valid empty C string, and the kernel only reads `attr`, whose size is
+    // passed along.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            tree.as_raw_fd(),
+            c"".as_ptr(),
+            flags | AT_EMPTY_PATH,
+            attr as *const MountAttr,
+            std::mem::size_of::<MountAttr>(),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn directories_are_opened_read_only() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("file"), b"content")?;
+        let directory = File::open(dir.path())?;
+
+        let read_only = match open_read_only(&directory) {
+            Ok(read_only) => read_only,
+            // Cloning mounts needs CAP_SYS_ADMIN, which the test may not have.
+            Err(e) if e.root_cause().downcast_ref::<io::Error>().is_some_and(is_eperm) => {
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let root = format!("/proc/self/fd/{}", read_only.as_raw_fd());
+        assert_eq!(fs::read(format!("{root}/file"))?, b"content");
+        let error = fs::write(format!("{root}/file"), b"changed").unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::EROFS));
+        assert!(fs::create_dir(format!("{root}/subdir")).is_err());
+        assert_eq!(fs::read(dir.path().join("file"))?, b"content");
+        Ok(())
+    }
+
+    #[test]
+    fn other_files_are_rejected() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("file"), b"content")?;
+        assert!(open_read_only(&File::open(dir.path().join("file"))?).is_err());
+        Ok(())
+    }
+
+    fn is_eperm(e: &io::Error) -> bool {
+        e.raw_os_error() == Some(libc::EPERM)
+    }
+}
@@ -15,15 +15,22 @@
 //! Implementation of the AIDL interface of the VirtualizationService.
 
 use crate::atom::{forward_vm_booted_atom, forward_vm_creation_atom, forward_vm_exited_atom};
+use crate::cid_reservation::{CidReservation, CidReservations, InstanceId, RESERVED_CIDS};
+use crate::event_log::VmEventLogs;
+use crate::instance_registry::{self, InstanceRegistry};
 use crate::maintenance;
+use crate::memory_reservation::{MemoryReclaimers, MemoryReservations};
 use crate::remote_provisioning;
-use crate::rkpvm::{generate_ecdsa_p256_key_pair, request_attestation};
+use crate::rkpvm::{derive_sealed_key, generate_ecdsa_p256_key_pair, request_attestation};
+use crate::shutdown::{shutdown_vms_on_device_shutdown, VmShutdownHandlers, VM_SHUTDOWN_TIMEOUT};
+use crate::vhost_user::VhostUserBackends;
 use crate::{get_calling_pid, get_calling_uid, REMOTELY_PROVISIONED_COMPONENT_SERVICE_NAME};
 use android_os_permissions_aidl::aidl::android::os::IPermissionController;
 use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon;
 use android_system_virtualizationmaintenance::aidl::android::system::virtualizationmaintenance;
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice;
 use android_system_virtualizationservice_internal as android_vs_internal;
+use android_system_virtualmachinemanager::aidl::android::system::virtualmachinemanager;
 use android_system_virtualmachineservice::aidl::android::system::virtualmachineservice;
 use android_system_vmtethering::aidl::android::system::vmtethering;
 use android_vs_internal::aidl::android::system::virtualizationservice_internal;
@@ -31,13 +38,12 @@ use anyhow::{anyhow, ensure, Context, Result};
 use avflog::LogResult;
 use binder::{
     self, wait_for_interface, BinderFeatures, ExceptionCode, Interface, IntoBinderResult,
-    LazyServiceGuard, ParcelFileDescriptor, Status, Strong,
+    LazyServiceGuard, ParcelFileDescriptor, Status, Strong, ThreadState,
 };
 use libc::{VMADDR_CID_HOST, VMADDR_CID_HYPERVISOR, VMADDR_CID_LOCAL};
 use log::{error, info, warn};
 use nix::unistd::{chown, Uid};
 use openssl::x509::X509;
-use rand::Fill;
 use rkpd_client::get_rkpd_attestation_key;
 use rustutils::{
     system_properties,
@@ -45,21 +51,30 @@ use rustutils::{
 };
 use serde::Deserialize;
 use service_vm_comm::Response;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
 use std::fs::{self, create_dir, remove_dir_all, remove_file, set_permissions, File, Permissions};
 use std::io::{Read, Write};
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt};
 use std::os::unix::raw::{pid_t, uid_t};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Condvar, LazyLock, Mutex, Weak};
+use std::time::Instant;
 use tombstoned_client::{DebuggerdDumpType, TombstonedConnection};
 use virtualizationcommon::Certificate::Certificate;
 use virtualizationmaintenance::{
     IVirtualizationMaintenance::IVirtualizationMaintenance,
     IVirtualizationReconciliationCallback::IVirtualizationReconciliationCallback,
 };
+use virtualizationservice::IVirtualizationService::{
+    ERROR_MEMORY_CAP_EXCEEDED, ERROR_TOO_MANY_VMS,
+};
 use virtualizationservice::{
-    AssignableDevice::AssignableDevice, VirtualMachineDebugInfo::VirtualMachineDebugInfo,
+    AssignableDevice::AssignableDevice, IVirtualMachine::IVirtualMachine,
+    NetworkFirewall::NetworkFirewall, VirtualMachineDebugInfo::VirtualMachineDebugInfo,
+    VirtualMachineResourceUsage::VirtualMachineResourceUsage, VmEvent::VmEvent,
+    VmEventType::VmEventType, VmInstanceInfo::VmInstanceInfo, VmLabel::VmLabel,
+    VmOwnerInfo::VmOwnerInfo, VmQosClass::VmQosClass,
 };
 use virtualizationservice_internal::{
     AtomVmBooted::AtomVmBooted,
@@ -67,10 +82,22 @@ use virtualizationservice_internal::{
     AtomVmExited::AtomVmExited,
     IBoundDevice::IBoundDevice,
     IGlobalVmContext::{BnGlobalVmContext, IGlobalVmContext},
+    IMemoryReservation::IMemoryReservation,
+    IMemoryReservationCallback::IMemoryReservationCallback,
+    ISharedDirHandler::{BpSharedDirHandler, ISharedDirHandler},
     IVfioHandler::VfioDev::VfioDev,
     IVfioHandler::{BpVfioHandler, IVfioHandler},
+    IVhostUserBackend::IVhostUserBackend,
     IVirtualizationServiceInternal::IVirtualizationServiceInternal,
+    IVmMemoryReclaimer::IVmMemoryReclaimer,
+    IVmShutdownHandler::IVmShutdownHandler,
     IVmnic::{BpVmnic, IVmnic},
+    VhostUserConnection::VhostUserConnection,
+    VhostUserDeviceType::VhostUserDeviceType,
+};
+use virtualmachinemanager::{
+    IVirtualMachineManager::IVirtualMachineManager, VirtualMachineStats::VirtualMachineStats,
+    VirtualMachineSummary::VirtualMachineSummary,
 };
 use virtualmachineservice::IVirtualMachineService::VM_TOMBSTONES_SERVICE_PORT;
 use vmtethering::IVmTethering::{BpVmTethering, IVmTethering};
@@ -85,12 +112,37 @@ pub const TEMPORARY_DIRECTORY: &str = "/data/misc/virtualizationservice";
 /// The first CID to assign to a guest VM managed by the VirtualizationService. CIDs lower than this
 /// are reserved for the host or other usage.
 const GUEST_CID_MIN: Cid = 2048;
-const GUEST_CID_MAX: Cid = 65535;
+/// The last CID to assign dynamically. The CIDs above it are only assigned to the VM instances
+/// they are reserved for.
+const GUEST_CID_MAX: Cid = *RESERVED_CIDS.start() - 1;
+
+/// Name of the file holding the CID reservations, in the persistent directory.
+const CID_RESERVATIONS_FILENAME: &str = "cid_reservations";
+
+/// Name of the directory holding the event logs of VM instances, in the persistent directory.
+const VM_EVENT_LOGS_DIRNAME: &str = "vm_event_logs";
+
+/// Name of the file holding the VM instances and their owners, in the persistent directory.
+const VM_INSTANCES_FILENAME: &str = "vm_instances";
 
 const SYSPROP_LAST_CID: &str = "virtualizationservice.state.last_cid";
 
+/// Cap on the total memory of the VMs of each uid, in MiB. There is no cap if it isn't set.
+const SYSPROP_MEMORY_MIB_PER_UID: &str = "hypervisor.virtualizationservice.memory_mib_per_uid";
+
+/// Comma-separated SELinux domains of the daemons which may register vhost-user backends. None may
+/// if it isn't set.
+const SYSPROP_VHOST_USER_BACKEND_DOMAINS: &str =
+    "hypervisor.virtualizationservice.vhost_user_backend_domains";
+
+/// Cap on the number of VMs which each uid runs at once. There is no cap if it isn't set.
+const SYSPROP_MAX_VMS_PER_UID: &str = "hypervisor.virtualizationservice.max_vms_per_uid";
+
 const CHUNK_RECV_MAX_LEN: usize = 1024;
 
+/// Suffix of the name of the TAP interfaces created for VMs.
+const TAP_INTERFACE_NAME_SUFFIX: &str = "fixed";
+
 /// The fake certificate is used for testing only when a client VM requests attestation in test
 /// mode, it is a single certificate extracted on an unregistered device for testing.
 /// Here is the snapshot of the certificate:
@@ -164,11 +216,20 @@ static VFIO_SERVICE: LazyLock<Strong<dyn IVfioHandler>> = LazyLock::new(|| {
 static NETWORK_SERVICE: LazyLock<Strong<dyn IVmnic>> = LazyLock::new(|| {
     wait_for_interface(<BpVmnic as IVmnic>::get_descriptor()).expect("Could not connect to Vmnic")
 });
+static SHARED_DIR_SERVICE: LazyLock<Strong<dyn ISharedDirHandler>> = LazyLock::new(|| {
+    wait_for_interface(<BpSharedDirHandler as ISharedDirHandler>::get_descriptor())
+        .expect("Could not connect to SharedDirHandler")
+});
 static TETHERING_SERVICE: LazyLock<Strong<dyn IVmTethering>> = LazyLock::new(|| {
     wait_for_interface(<BpVmTethering as IVmTethering>::get_descriptor())
         .expect("Could not connect to VmTethering")
 });
 
+/// Name of the TAP interfaces created by vmnic for VMs.
+fn tap_interface_name() -> String {
+    format!("avf_tap_{TAP_INTERFACE_NAME_SUFFIX}")
+}
+
 fn is_valid_guest_cid(cid: Cid) -> bool {
     (GUEST_CID_MIN..=GUEST_CID_MAX).contains(&cid)
 }
@@ -183,13 +244,16 @@ pub struct VirtualizationServiceInternal {
 
 impl VirtualizationServiceInternal {
     pub fn init() -> VirtualizationServiceInternal {
+        let state = GlobalState::new();
+        shutdown_vms_on_device_shutdown(state.shutdown_handlers.clone());
         let service = VirtualizationServiceInternal {
-            state: Arc::new(Mutex::new(GlobalState::new())),
+            state: Arc::new(Mutex::new(state)),
             display_service_set: Arc::new(Condvar::new()),
         };
 
-        std::thread::spawn(|| {
-            if let Err(e) = handle_stream_connection_tombstoned() {
+        let tombstone_state = service.state.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = handle_stream_connection_tombstoned(&tombstone_state) {
                 warn!("Error receiving tombstone from guest or writing them. Error: {:?}", e);
             }
         });
@@ -252,17 +316,131 @@ impl IVirtualizationServiceInternal for VirtualizationServiceInternal {
     fn allocateGlobalVmContext(
         &self,
         requester_debug_pid: i32,
+        instance_id: &[u8; 64],
     ) -> binder::Result<Strong<dyn IGlobalVmContext>> {
         check_manage_access()?;
 
         let requester_uid = get_calling_uid();
         let requester_debug_pid = requester_debug_pid as pid_t;
+        let max_vms = max_vms_per_uid().with_log().or_service_specific_exception(-1)?;
         let state = &mut *self.state.lock().unwrap();
+        check_vm_count(&state.held_contexts, requester_uid, max_vms)
+            .with_log()
+            .or_service_specific_exception(ERROR_TOO_MANY_VMS)?;
         state
-            .allocate_vm_context(requester_uid, requester_debug_pid)
+            .allocate_vm_context(requester_uid, requester_debug_pid, instance_id)
             .or_binder_exception(ExceptionCode::ILLEGAL_STATE)
     }
 
+    fn reserveMemory(
+        &self,
+        memory_mib: i64,
+        qos_class: VmQosClass,
+        callback: &Strong<dyn IMemoryReservationCallback>,
+    ) -> binder::Result<Strong<dyn IMemoryReservation>> {
+        check_manage_access()?;
+
+        let memory_mib = u64::try_from(memory_mib)
+            .ok()
+            .filter(|&memory_mib| memory_mib > 0)
+            .with_context(|| format!("Invalid memory size {memory_mib}"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        let reservations = self.state.lock().unwrap().memory_reservations.clone();
+        Ok(reservations.reserve(memory_mib, qos_class, callback))
+    }
+
+    fn reserveCidForInstance(&self, instance_id: &[u8; 64], count: i32) -> binder::Result<i32> {
+        check_manage_access()?;
+        check_use_custom_virtual_machine()?;
+        check_cid_reservation_instance_id(instance_id)?;
+        let count = u32::try_from(count)
+            .with_context(|| format!("Invalid CID count {count}"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+
+        let uid = get_calling_uid();
+        let state = &mut *self.state.lock().unwrap();
+        check_cid_reservation_owner(state.cid_reservations.get(instance_id), uid)?;
+        let cid = state
+            .cid_reservations
+            .reserve(instance_id, uid, count)
+            .context("Failed to reserve CIDs")
+            .with_log()
+            .or_service_specific_exception(-1)?;
+        Ok(cid as i32)
+    }
+
+    fn releaseCidForInstance(&self, instance_id: &[u8; 64]) -> binder::Result<()> {
+        check_manage_access()?;
+        check_use_custom_virtual_machine()?;
+        check_cid_reservation_instance_id(instance_id)?;
+
+        let state = &mut *self.state.lock().unwrap();
+        check_cid_reservation_owner(state.cid_reservations.get(instance_id), get_calling_uid())?;
+        state
+            .cid_reservations
+            .release(instance_id)
+            .context("Failed to release CIDs")
+            .with_log()
+            .or_service_specific_exception(-1)
+    }
+
+    fn getCidForInstance(&self, instance_id: &[u8; 64]) -> binder::Result<i32> {
+        check_manage_access()?;
+        check_cid_reservation_instance_id(instance_id)?;
+
+        let state = &*self.state.lock().unwrap();
+        let reservation = state.cid_reservations.get(instance_id);
+        check_cid_reservation_owner(reservation, get_calling_uid())?;
+        Ok(reservation.map_or(-1, |reservation| reservation.first_cid as i32))
+    }
+
+    fn getVmEventLog(&self, instance_id: &[u8; 64]) -> binder::Result<Vec<VmEvent>> {
+        check_manage_access()?;
+
+        if *instance_id == [0; 64] {
+            return Err(anyhow!("Invalid all-zero instance ID"))
+                .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+        }
+        let vm_event_logs = self.state.lock().unwrap().vm_event_logs.clone();
+        vm_event_logs
+            .get(instance_id, get_calling_uid())
+            .context("Failed to read the event log")
+            .with_log()
+            .or_service_specific_exception(-1)
+    }
+
+    fn listVmInstances(&self, uid: i32) -> binder::Result<Vec<VmInstanceInfo>> {
+        check_manage_access()?;
+        let uid = uid_t::try_from(uid)
+            .with_context(|| format!("Invalid uid {uid}"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        if uid != get_calling_uid() {
+            check_use_custom_virtual_machine()?;
+        }
+
+        let state = &*self.state.lock().unwrap();
+        Ok(state
+            .instance_registry
+            .list(uid)
+            .into_iter()
+            .map(|(instance_id, instance)| VmInstanceInfo {
+                instanceId: instance_id,
+                createdMillis: instance.created_millis,
+            })
+            .collect())
+    }
+
+    fn getVmOwnerInfo(&self, cid: i32) -> binder::Result<VmOwnerInfo> {
+        // The owners of all the VMs would otherwise be revealed to any app which may run VMs.
+        check_native_caller()?;
+
+        let state = &*self.state.lock().unwrap();
+        let vm = running_vm(&state.held_contexts, cid)
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        let owner_info = vm.lock().unwrap().owner_info();
+        Ok(owner_info)
+    }
+
     fn atomVmBooted(&self, atom: &AtomVmBooted) -> Result<(), Status> {
         forward_vm_booted_atom(atom);
         Ok(())
@@ -278,7 +456,26 @@ impl IVirtualizationServiceInternal for VirtualizationServiceInternal {
         Ok(())
     }
 
+    fn lookupPersistentVm(
+        &self,
+        name: &str,
+    ) -> binder::Result<Option<Strong<dyn IVirtualMachine>>> {
+        check_manage_access()?;
+
+        // virtmgr runs with the UID of its client, which only gets its own VMs.
+        let requester_uid = get_calling_uid();
+        let state = &*self.state.lock().unwrap();
+        Ok(state.persistent_vms.get(requester_uid, name))
+    }
+
     fn debugListVms(&self) -> binder::Result<Vec<VirtualMachineDebugInfo>> {
+        self.debugListVmsWithLabels(&[])
+    }
+
+    fn debugListVmsWithLabels(
+        &self,
+        selector: &[VmLabel],
+    ) -> binder::Result<Vec<VirtualMachineDebugInfo>> {
         check_debug_access()?;
 
         let state = &mut *self.state.lock().unwrap();
@@ -286,20 +483,70 @@ impl IVirtualizationServiceInternal for VirtualizationServiceInternal {
             .held_contexts
             .iter()
             .filter_map(|(_, inst)| Weak::upgrade(inst))
-            .map(|vm| {
+            .filter_map(|vm| {
                 let vm = vm.lock().unwrap();
-                VirtualMachineDebugInfo {
+                if !vm.has_labels(selector) {
+                    return None;
+                }
+                Some(VirtualMachineDebugInfo {
                     cid: vm.cid as i32,
+                    name: vm.name.clone(),
                     temporaryDirectory: vm.get_temp_dir().to_string_lossy().to_string(),
                     requesterUid: vm.requester_uid as i32,
                     requesterPid: vm.requester_debug_pid,
                     hostConsoleName: vm.host_console_name.clone(),
-                }
+                    labels: vm.labels.clone(),
+                })
             })
             .collect();
         Ok(cids)
     }
 
+    fn debugListVmResourceUsage(&self) -> binder::Result<Vec<VirtualMachineResourceUsage>> {
+        check_debug_access()?;
+
+        let state = &*self.state.lock().unwrap();
+        let usage = state
+            .held_contexts
+            .values()
+            .filter_map(Weak::upgrade)
+            .map(|vm| {
+                let vm = vm.lock().unwrap();
+                vm.resource_usage(state.vm_memory.get(vm.cid).unwrap_or(0))
+            })
+            .collect();
+        Ok(usage)
+    }
+
+    fn debugShutdownVmsWithLabels(&self, selector: &[VmLabel]) -> binder::Result<Vec<i32>> {
+        check_debug_access()?;
+        if selector.is_empty() {
+            return Err(anyhow!("No labels to select the VMs with"))
+                .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+        }
+
+        let (cids, shutdown_handlers) = {
+            let state = &*self.state.lock().unwrap();
+            let cids: BTreeSet<_> = state
+                .held_contexts
+                .values()
+                .filter_map(Weak::upgrade)
+                .filter_map(|vm| {
+                    let vm = vm.lock().unwrap();
+                    vm.has_labels(selector).then_some(vm.cid)
+                })
+                .collect();
+            (cids, state.shutdown_handlers.clone())
+        };
+        info!("Shutting down VMs {cids:?} with labels {selector:?}");
+        // Don't hold the state while the VMs shut down, as they call back into it when they die.
+        let laggards = shutdown_handlers.shutdown(&cids, VM_SHUTDOWN_TIMEOUT);
+        if !laggards.is_empty() {
+            warn!("VMs {laggards:?} didn't shut down cleanly");
+        }
+        Ok(cids.into_iter().map(|cid| cid as i32).collect())
+    }
+
     fn enableTestAttestation(&self) -> binder::Result<()> {
         check_manage_access()?;
         check_use_custom_virtual_machine()?;
@@ -411,6 +658,30 @@ impl IVirtualizationServiceInternal for VirtualizationServiceInternal {
         Ok(certificate_chain)
     }
 
+    fn requestSealedKey(&self, csr: &[u8]) -> binder::Result<Vec<u8>> {
+        check_manage_access()?;
+        if !cfg!(remote_attestation) {
+            return Err(Status::new_exception_str(
+                ExceptionCode::UNSUPPORTED_OPERATION,
+                Some(
+                    "requestSealedKey is not supported with the remote_attestation feature \
+                     disabled",
+                ),
+            ))
+            .with_log();
+        }
+        info!("Received csr. Requesting a sealed key...");
+        derive_sealed_key(csr.to_vec())
+            .and_then(|sealed_key| {
+                sealed_key
+                    .into_cbor_vec()
+                    .map_err(|e| anyhow!("Failed to serialize the sealed key: {e:?}"))
+            })
+            .context("Failed to derive a sealed key")
+            .with_log()
+            .or_service_specific_exception(-1)
+    }
+
     fn isRemoteAttestationSupported(&self) -> binder::Result<bool> {
         Ok(is_remote_provisioning_hal_declared()?
             && remote_provisioning::is_remote_attestation_supported())
@@ -457,13 +728,15 @@ impl IVirtualizationServiceInternal for VirtualizationServiceInternal {
     }
 
     fn allocateInstanceId(&self) -> binder::Result<[u8; 64]> {
-        let mut id = [0u8; 64];
-        id.try_fill(&mut rand::thread_rng())
+        let uid = get_calling_uid();
+        let state = &mut *self.state.lock().unwrap();
+        let id = state
+            .instance_registry
+            .allocate(uid)
             .context("Failed to allocate instance_id")
+            .with_log()
             .or_service_specific_exception(-1)?;
-        let uid = get_calling_uid();
         info!("Allocated a VM's instance_id: {:?}..., for uid: {:?}", &hex::encode(id)[..8], uid);
-        let state = &mut *self.state.lock().unwrap();
         if let Some(sk_state) = &mut state.sk_state {
             let user_id = multiuser_get_user_id(uid);
             let app_id = multiuser_get_app_id(uid);
@@ -477,9 +750,22 @@ impl IVirtualizationServiceInternal for VirtualizationServiceInternal {
     }
 
     fn removeVmInstance(&self, instance_id: &[u8; 64]) -> binder::Result<()> {
+        let uid = get_calling_uid();
         let state = &mut *self.state.lock().unwrap();
+        // Instances allocated before the registry aren't in it, so they can't be checked.
+        if state
+            .instance_registry
+            .get(instance_id)
+            .is_some_and(|instance| instance.owner_uid != uid)
+        {
+            return Err(anyhow!("VM instance isn't owned by the caller"))
+                .or_binder_exception(ExceptionCode::SECURITY);
+        }
+        if let Err(e) = state.instance_registry.remove(instance_id) {
+            error!("Failed to remove the instance_id from the registry: {e:?}");
+        }
+        state.remove_instance_state(instance_id);
         if let Some(sk_state) = &mut state.sk_state {
-            let uid = get_calling_uid();
             info!(
                 "Removing a VM's instance_id: {:?}, for uid: {:?}",
                 hex::encode(instance_id),
@@ -495,10 +781,22 @@ impl IVirtualizationServiceInternal for VirtualizationServiceInternal {
         Ok(())
     }
 
-    fn claimVmInstance(&self, instance_id: &[u8; 64]) -> binder::Result<()> {
+    fn claimVmInstance(
+        &self,
+        instance_id: &[u8; 64],
+        transfer_token: Option<&[u8]>,
+    ) -> binder::Result<()> {
+        let uid = get_calling_uid();
         let state = &mut *self.state.lock().unwrap();
+        let transfer_allowed = transfer_token
+            .is_some_and(|token| state.instance_registry.is_transfer_allowed(instance_id, token));
+        if !transfer_allowed {
+            state.check_claim(instance_id, uid)?;
+        }
+        if let Err(e) = state.instance_registry.set_owner(instance_id, uid) {
+            error!("Failed to record the owner of the instance_id: {e:?}");
+        }
         if let Some(sk_state) = &mut state.sk_state {
-            let uid = get_calling_uid();
             info!(
                 "Claiming a VM's instance_id: {:?}, for uid: {:?}",
                 hex::encode(instance_id),
@@ -517,6 +815,20 @@ impl IVirtualizationServiceInternal for VirtualizationServiceInternal {
         Ok(())
     }
 
+    fn allowVmInstanceTransfer(&self, instance_id: &[u8; 64]) -> binder::Result<Vec<u8>> {
+        let uid = get_calling_uid();
+        let state = &mut *self.state.lock().unwrap();
+        state.check_claim(instance_id, uid)?;
+        let token = state
+            .instance_registry
+            .allow_transfer(instance_id, uid)
+            .context("Failed to allow the transfer of the instance_id")
+            .with_log()
+            .or_service_specific_exception(-1)?;
+        info!("Allowed the transfer of a VM's instance_id: {:?}", hex::encode(instance_id));
+        Ok(token.to_vec())
+    }
+
     fn createTapInterface(&self, _iface_name_suffix: &str) -> binder::Result<ParcelFileDescriptor> {
         check_internet_permission()?;
         check_use_custom_virtual_machine()?;
@@ -529,13 +841,17 @@ impl IVirtualizationServiceInternal for VirtualizationServiceInternal {
         }
         // TODO(340377643): Use iface_name_suffix after introducing bridge interface, not fixed
         // value.
-        let tap_fd = NETWORK_SERVICE.createTapInterface("fixed")?;
+        let tap_fd = NETWORK_SERVICE.createTapInterface(TAP_INTERFACE_NAME_SUFFIX)?;
 
         // TODO(340377643): Due to lack of implementation of creating bridge interface, tethering is
         // enabled for TAP interface instead of bridge interface. After introducing creation of
         // bridge interface in AVF, we should modify it.
         TETHERING_SERVICE.enableVmTethering()?;
 
+        // The caller is the virtmgr of the app owning the VM, which runs as the app's uid.
+        TETHERING_SERVICE
+            .startVmNetworkAccounting(&tap_interface_name(), get_calling_uid() as i32)?;
+
         Ok(tap_fd)
     }
 
@@ -550,16 +866,81 @@ impl IVirtualizationServiceInternal for VirtualizationServiceInternal {
             .with_log();
         }
 
+        // The counters of the interface are gone once it is deleted.
+        TETHERING_SERVICE.stopVmNetworkAccounting(&tap_interface_name())?;
+
         // TODO(340377643): Disabling tethering should be for bridge interface, not TAP interface.
         TETHERING_SERVICE.disableVmTethering()?;
 
         NETWORK_SERVICE.deleteTapInterface(tap_fd)
     }
+
+    fn openReadOnlyDirectory(
+        &self,
+        directory: &ParcelFileDescriptor,
+    ) -> binder::Result<ParcelFileDescriptor> {
+        check_manage_access()?;
+        SHARED_DIR_SERVICE.openReadOnly(directory)
+    }
+
+    fn setTapFirewall(
+        &self,
+        tap_fd: &ParcelFileDescriptor,
+        firewall: &NetworkFirewall,
+    ) -> binder::Result<()> {
+        check_internet_permission()?;
+        check_use_custom_virtual_machine()?;
+        if !cfg!(network) {
+            return Err(Status::new_exception_str(
+                ExceptionCode::UNSUPPORTED_OPERATION,
+                Some("setTapFirewall is not supported with the network feature disabled"),
+            ))
+            .with_log();
+        }
+        NETWORK_SERVICE.setTapFirewall(tap_fd, firewall)
+    }
+
+    fn registerVhostUserBackend(
+        &self,
+        name: &str,
+        device_type: VhostUserDeviceType,
+        backend: &Strong<dyn IVhostUserBackend>,
+        allowed_uids: &[i32],
+    ) -> binder::Result<()> {
+        check_use_custom_virtual_machine()?;
+        check_native_caller()?;
+        check_vhost_user_backend_caller()?;
+        let allowed_uids = allowed_uids
+            .iter()
+            .map(|&uid| uid_t::try_from(uid).with_context(|| format!("Invalid uid {uid}")))
+            .collect::<Result<_>>()
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        let vhost_user_backends = self.state.lock().unwrap().vhost_user_backends.clone();
+        vhost_user_backends
+            .register(name, get_calling_uid(), device_type, backend, allowed_uids)
+            .with_log()
+            .or_binder_exception(ExceptionCode::ILLEGAL_STATE)
+    }
+
+    fn unregisterVhostUserBackend(&self, name: &str) -> binder::Result<()> {
+        check_use_custom_virtual_machine()?;
+        check_native_caller()?;
+        check_vhost_user_backend_caller()?;
+        let vhost_user_backends = self.state.lock().unwrap().vhost_user_backends.clone();
+        vhost_user_backends
+            .unregister(name, get_calling_uid())
+            .with_log()
+            .or_binder_exception(ExceptionCode::ILLEGAL_STATE)
+    }
 }
 
 impl IVirtualizationMaintenance for VirtualizationServiceInternal {
     fn appRemoved(&self, user_id: i32, app_id: i32) -> binder::Result<()> {
         let state = &mut *self.state.lock().unwrap();
+        state.remove_instances_owned_by(|uid| {
+            multiuser_get_user_id(uid) as i32 == user_id
+                && multiuser_get_app_id(uid) as i32 == app_id
+        });
         if let Some(sk_state) = &mut state.sk_state {
             info!("packageRemoved(user_id={user_id}, app_id={app_id})");
             sk_state.delete_ids_for_app(user_id, app_id).or_service_specific_exception(-1)?;
@@ -571,6 +952,7 @@ impl IVirtualizationMaintenance for VirtualizationServiceInternal {
 
     fn userRemoved(&self, user_id: i32) -> binder::Result<()> {
         let state = &mut *self.state.lock().unwrap();
+        state.remove_instances_owned_by(|uid| multiuser_get_user_id(uid) as i32 == user_id);
         if let Some(sk_state) = &mut state.sk_state {
             info!("userRemoved({user_id})");
             sk_state.delete_ids_for_user(user_id).or_service_specific_exception(-1)?;
@@ -595,6 +977,200 @@ impl IVirtualizationMaintenance for VirtualizationServiceInternal {
     }
 }
 
+fn check_cid_reservation_instance_id(instance_id: &InstanceId) -> binder::Result<()> {
+    if *instance_id == [0; 64] {
+        return Err(anyhow!("CIDs can't be reserved for the zero instance ID"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+    }
+    Ok(())
+}
+
+fn check_cid_reservation_owner(
+    reservation: Option<&CidReservation>,
+    uid: uid_t,
+) -> binder::Result<()> {
+    if reservation.is_some_and(|reservation| reservation.owner_uid != uid) {
+        return Err(anyhow!("CIDs of the VM instance are reserved by another app"))
+            .or_binder_exception(ExceptionCode::SECURITY);
+    }
+    Ok(())
+}
+
+impl IVirtualMachineManager for VirtualizationServiceInternal {
+    fn listVirtualMachines(&self) -> binder::Result<Vec<VirtualMachineSummary>> {
+        check_manage_access()?;
+        let state = &*self.state.lock().unwrap();
+        let vms = owned_vms(&state.held_contexts, get_calling_uid());
+        Ok(vms.iter().map(|vm| vm.lock().unwrap().summary()).collect())
+    }
+
+    fn getStats(&self, cid: i32) -> binder::Result<VirtualMachineStats> {
+        check_manage_access()?;
+        let state = &*self.state.lock().unwrap();
+        let vm = owned_vm(&state.held_contexts, get_calling_uid(), cid)
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        let vm = vm.lock().unwrap();
+        let memory_mib = state.vm_memory.get(vm.cid).unwrap_or(0);
+        Ok(VirtualMachineStats {
+            uptimeMillis: vm.uptime_millis(),
+            memoryMib: memory_mib.try_into().unwrap_or(i32::MAX),
+        })
+    }
+
+    fn stopVirtualMachine(&self, cid: i32) -> binder::Result<()> {
+        check_manage_access()?;
+        let (cid, shutdown_handlers) = {
+            let state = &*self.state.lock().unwrap();
+            let vm = owned_vm(&state.held_contexts, get_calling_uid(), cid)
+                .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+            let cid = vm.lock().unwrap().cid;
+            (cid, state.shutdown_handlers.clone())
+        };
+        if !shutdown_handlers.contains(cid) {
+            return Err(anyhow!("VM {cid} has no shutdown handler"))
+                .or_binder_exception(ExceptionCode::ILLEGAL_STATE);
+        }
+        info!("Shutting down VM {cid} at the request of its owner");
+        // Shutting the VM down can take up to VM_SHUTDOWN_TIMEOUT, so don't hold the binder thread
+        // (nor the state, as the VM calls back into it when it dies) meanwhile.
+        std::thread::spawn(move || {
+            let laggards = shutdown_handlers.shutdown(&BTreeSet::from([cid]), VM_SHUTDOWN_TIMEOUT);
+            if !laggards.is_empty() {
+                warn!("VM {cid} didn't shut down cleanly at the request of its owner");
+            }
+        });
+        Ok(())
+    }
+
+    fn openConsole(&self, cid: i32) -> binder::Result<ParcelFileDescriptor> {
+        check_manage_access()?;
+        let (console_name, owner_uid) = {
+            let state = &*self.state.lock().unwrap();
+            let vm = owned_vm(&state.held_contexts, get_calling_uid(), cid)
+                .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+            let vm = vm.lock().unwrap();
+            (vm.console_name()?, vm.requester_uid)
+        };
+        let console = open_console(&console_name, owner_uid)
+            .with_context(|| format!("Failed to open console {console_name}"))
+            .with_log()
+            .or_service_specific_exception(-1)?;
+        Ok(ParcelFileDescriptor::new(console))
+    }
+}
+
+/// Returns whether `name` is the name of a pty in devpts, i.e. `/dev/pts/<N>`.
+fn is_pts_name(name: &str) -> bool {
+    name.strip_prefix("/dev/pts/")
+        .is_some_and(|index| !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Opens the host console of a VM, as named by its owner. The name isn't trusted, so it is only
+/// opened if it is a pty which belongs to the owner; otherwise the owner could have
+/// virtualizationservice open devices on its behalf which it can't open itself.
+fn open_console(console_name: &str, owner_uid: uid_t) -> Result<File> {
+    ensure!(is_pts_name(console_name), "{console_name} isn't a pty");
+    let expected = fs::symlink_metadata(console_name)?;
+    ensure!(expected.file_type().is_char_device(), "{console_name} isn't a character device");
+    ensure!(expected.uid() == owner_uid, "{console_name} isn't owned by the owner of the VM");
+    let console = File::options()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY | libc::O_NOFOLLOW)
+        .open(console_name)?;
+    let actual = console.metadata()?;
+    ensure!(
+        (actual.dev(), actual.ino()) == (expected.dev(), expected.ino()),
+        "{console_name} was replaced while it was opened"
+    );
+    Ok(console)
+}
+
+/// Returns the running VMs owned by `uid`, ordered by CID.
+fn owned_vms(
+    held_contexts: &HashMap<Cid, Weak<Mutex<GlobalVmInstance>>>,
+    uid: uid_t,
+) -> Vec<Arc<Mutex<GlobalVmInstance>>> {
+    let mut vms: Vec<_> = held_contexts
+        .iter()
+        .filter_map(|(cid, vm)| Some((*cid, vm.upgrade()?)))
+        .filter(|(_, vm)| vm.lock().unwrap().requester_uid == uid)
+        .collect();
+    vms.sort_by_key(|(cid, _)| *cid);
+    vms.into_iter().map(|(_, vm)| vm).collect()
+}
+
+/// Checks that `uid` runs fewer than `max_vms` VMs, so that it may start another one.
+fn check_vm_count(
+    held_contexts: &HashMap<Cid, Weak<Mutex<GlobalVmInstance>>>,
+    uid: uid_t,
+    max_vms: Option<usize>,
+) -> Result<()> {
+    let Some(max_vms) = max_vms else { return Ok(()) };
+    let vms = owned_vms(held_contexts, uid).len();
+    ensure!(vms < max_vms, "uid {uid} already runs {vms} VMs, the most it may run at once");
+    Ok(())
+}
+
+/// Returns the running VM with `cid` if it is owned by `uid`. The VMs of other owners are
+/// reported as missing, so that their existence isn't revealed.
+fn owned_vm(
+    held_contexts: &HashMap<Cid, Weak<Mutex<GlobalVmInstance>>>,
+    uid: uid_t,
+    cid: i32,
+) -> Result<Arc<Mutex<GlobalVmInstance>>> {
+    running_vm(held_contexts, cid)
+        .ok()
+        .filter(|vm| vm.lock().unwrap().requester_uid == uid)
+        .ok_or_else(|| anyhow!("No VM with CID {cid}"))
+}
+
+/// Checks that the app with `uid` may claim the VM instance, given the owners recorded for it by the
+/// instance registry and with the Secretkeeper state, so that it can't take over the state of an
+/// instance of another app.
+///
+/// The app must already own an instance known to the registry, unless the owner handed it a
+/// transfer token, which is checked separately. Otherwise, it must be recorded as
+/// the owner with the Secretkeeper state, which is how legacy IDs, allocated randomly before IDs
+/// were namespaced per user, are accepted. An instance with no recorded owner may only be claimed
+/// if it is in the namespace of the user of the app, e.g. as its record was evicted.
+fn check_claim(
+    instance_id: &InstanceId,
+    uid: uid_t,
+    registry_owner: Option<uid_t>,
+    sk_owner: Option<(i32, i32)>,
+) -> Result<()> {
+    if let Some(owner_uid) = registry_owner {
+        ensure!(owner_uid == uid, "VM instance is owned by uid {owner_uid}");
+        return Ok(());
+    }
+    let user_id = multiuser_get_user_id(uid);
+    let app_id = multiuser_get_app_id(uid);
+    if let Some((owner_user_id, owner_app_id)) = sk_owner {
+        ensure!(
+            (owner_user_id, owner_app_id) == (user_id as i32, app_id as i32),
+            "VM instance is owned by app {owner_app_id} of user {owner_user_id}"
+        );
+        return Ok(());
+    }
+    ensure!(
+        instance_registry::user_id_of(instance_id) == user_id,
+        "VM instance has no recorded owner, and isn't in the namespace of user {user_id}"
+    );
+    Ok(())
+}
+
+/// Returns the running VM with `cid`, whoever owns it.
+fn running_vm(
+    held_contexts: &HashMap<Cid, Weak<Mutex<GlobalVmInstance>>>,
+    cid: i32,
+) -> Result<Arc<Mutex<GlobalVmInstance>>> {
+    Cid::try_from(cid)
+        .ok()
+        .and_then(|cid| held_contexts.get(&cid)?.upgrade())
+        .ok_or_else(|| anyhow!("No VM with CID {cid}"))
+}
+
 #[derive(Debug, Deserialize)]
 struct Device {
     dtbo_label: String,
@@ -666,6 +1242,22 @@ struct GlobalVmInstance {
     requester_debug_pid: pid_t,
     /// Name of the host console.
     host_console_name: Option<String>,
+    /// Name of the VM, as given in its config.
+    name: String,
+    /// Name under which the VM is registered in `PersistentVms`, if any.
+    persistent_name: Option<String>,
+    /// Labels of the VM, as given in its config.
+    labels: Vec<VmLabel>,
+    /// Whether the VM is debuggable, which lets its owner open its console.
+    debuggable: bool,
+    /// Whether the VM is a protected VM.
+    protected: bool,
+    /// Host vsock ports which the VM may connect to, if it has a vsock firewall.
+    allowed_host_vsock_ports: Option<Vec<i32>>,
+    /// ID of the instance of the VM, as given in its config. Set when the context is allocated.
+    instance_id: Option<[u8; 64]>,
+    /// When the VM context was allocated.
+    start_time: Option<Instant>,
 }
 
 impl GlobalVmInstance {
@@ -673,6 +1265,160 @@ impl GlobalVmInstance {
         let cid = self.cid;
         format!("{TEMPORARY_DIRECTORY}/{cid}").into()
     }
+
+    /// Returns whether the VM has all the labels of `selector`.
+    fn has_labels(&self, selector: &[VmLabel]) -> bool {
+        selector.iter().all(|label| self.labels.contains(label))
+    }
+
+    fn summary(&self) -> VirtualMachineSummary {
+        VirtualMachineSummary {
+            cid: self.cid as i32,
+            name: self.name.clone(),
+            debuggable: self.debuggable,
+            persistentName: self.persistent_name.clone(),
+        }
+    }
+
+    /// Returns how long the VM has been running, in milliseconds.
+    fn uptime_millis(&self) -> i64 {
+        let uptime = self.start_time.map(|start_time| start_time.elapsed()).unwrap_or_default();
+        uptime.as_millis().try_into().unwrap_or(i64::MAX)
+    }
+
+    fn resource_usage(&self, accounted_memory_mib: u32) -> VirtualMachineResourceUsage {
+        VirtualMachineResourceUsage {
+            cid: self.cid as i32,
+            name: self.name.clone(),
+            ownerUid: self.requester_uid as i32,
+            isProtected: self.protected,
+            accountedMemoryMib: accounted_memory_mib.try_into().unwrap_or(i32::MAX),
+            uptimeMillis: self.uptime_millis(),
+        }
+    }
+
+    /// Returns whether the vsock firewall of the VM, if any, lets it connect to `port` of the host.
+    fn allows_host_vsock_port(&self, port: u32) -> bool {
+        self.allowed_host_vsock_ports
+            .as_ref()
+            .map_or(true, |ports| ports.iter().any(|&allowed| allowed as u32 == port))
+    }
+
+    fn owner_info(&self) -> VmOwnerInfo {
+        VmOwnerInfo {
+            ownerUid: self.requester_uid as i32,
+            instanceId: self.instance_id.unwrap_or([0; 64]),
+            isProtected: self.protected,
+            allowedHostVsockPorts: self.allowed_host_vsock_ports.clone(),
+        }
+    }
+
+    /// Returns the name of the host console, which its owner may only open if the VM is
+    /// debuggable.
+    fn console_name(&self) -> binder::Result<String> {
+        if !self.debuggable {
+            return Err(anyhow!("VM {} isn't debuggable", self.cid))
+                .or_binder_exception(ExceptionCode::SECURITY);
+        }
+        self.host_console_name
+            .clone()
+            .with_context(|| format!("VM {} has no console", self.cid))
+            .or_binder_exception(ExceptionCode::ILLEGAL_STATE)
+    }
+}
+
+/// VMs registered under a persistent name, keyed by the UID of their owner and their name. The
+/// registry holds a strong reference to the VMs, which keeps them running in their virtmgr after
+/// their client died, until they are unregistered.
+#[derive(Clone)]
+struct PersistentVms<T = Strong<dyn IVirtualMachine>>(Arc<Mutex<HashMap<(uid_t, String), T>>>);
+
+impl<T> Default for PersistentVms<T> {
+    fn default() -> Self {
+        Self(Default::default())
+    }
+}
+
+impl<T> fmt::Debug for PersistentVms<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = self.0.lock().unwrap().keys().cloned().collect();
+        f.debug_tuple("PersistentVms").field(&names).finish()
+    }
+}
+
+impl<T: Clone> PersistentVms<T> {
+    fn register(&self, uid: uid_t, name: &str, vm: &T) -> Result<()> {
+        let mut vms = self.0.lock().unwrap();
+        let key = (uid, name.to_owned());
+        ensure!(!vms.contains_key(&key), "Persistent VM name {name:?} is already in use");
+        vms.insert(key, vm.clone());
+        Ok(())
+    }
+
+    fn unregister(&self, uid: uid_t, name: &str) {
+        self.0.lock().unwrap().remove(&(uid, name.to_owned()));
+    }
+
+    fn get(&self, uid: uid_t, name: &str) -> Option<T> {
+        self.0.lock().unwrap().get(&(uid, name.to_owned())).cloned()
+    }
+}
+
+/// The memory of the VMs in MiB, keyed by their CID, along with the UID of their owner.
+#[derive(Clone, Debug, Default)]
+struct VmMemory(Arc<Mutex<HashMap<Cid, (uid_t, u32)>>>);
+
+impl VmMemory {
+    /// Accounts `memory_mib` for the VM, unless it grows the total memory of the VMs of `uid`
+    /// beyond `cap_mib`. Shrinking is always allowed, even if the cap was lowered in the meantime.
+    fn set(&self, cid: Cid, uid: uid_t, memory_mib: u32, cap_mib: Option<u64>) -> Result<()> {
+        let mut vms = self.0.lock().unwrap();
+        let previous_mib = vms.get(&cid).map_or(0, |&(_, memory_mib)| memory_mib);
+        if let Some(cap_mib) = cap_mib.filter(|_| memory_mib > previous_mib) {
+            let others_mib: u64 = vms
+                .iter()
+                .filter(|&(&other, &(owner, _))| other != cid && owner == uid)
+                .map(|(_, &(_, memory_mib))| u64::from(memory_mib))
+                .sum();
+            let total_mib = others_mib + u64::from(memory_mib);
+            ensure!(
+                total_mib <= cap_mib,
+                "VMs of uid {uid} would have {total_mib} MiB of memory, over the cap of {cap_mib} MiB"
+            );
+        }
+        vms.insert(cid, (uid, memory_mib));
+        Ok(())
+    }
+
+    fn remove(&self, cid: Cid) {
+        self.0.lock().unwrap().remove(&cid);
+    }
+
+    fn get(&self, cid: Cid) -> Option<u32> {
+        self.0.lock().unwrap().get(&cid).map(|&(_, memory_mib)| memory_mib)
+    }
+}
+
+fn memory_mib_cap() -> Result<Option<u64>> {
+    read_cap(SYSPROP_MEMORY_MIB_PER_UID)
+}
+
+fn max_vms_per_uid() -> Result<Option<usize>> {
+    read_cap(SYSPROP_MAX_VMS_PER_UID)
+}
+
+fn read_cap<T: std::str::FromStr>(property: &str) -> Result<Option<T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    system_properties::read(property)?
+        .filter(|value| !value.is_empty())
+        .map(|value| {
+            value
+                .parse()
+                .with_context(|| format!("Invalid value '{value}' of property '{property}'"))
+        })
+        .transpose()
 }
 
 /// The mutable state of the VirtualizationServiceInternal. There should only be one instance
@@ -689,6 +1435,30 @@ struct GlobalState {
     sk_state: Option<maintenance::State>,
 
     display_service: Option<binder::SpIBinder>,
+
+    /// CIDs reserved for VM instances.
+    cid_reservations: CidReservations,
+
+    /// VMs registered under a persistent name.
+    persistent_vms: PersistentVms,
+
+    /// Handlers shutting the running VMs down before the device.
+    shutdown_handlers: VmShutdownHandlers,
+
+    /// Memory of the VMs, accounted towards the cap of their owner.
+    vm_memory: VmMemory,
+
+    /// Reservations of memory for the VMs waiting to start.
+    memory_reservations: MemoryReservations,
+
+    /// vhost-user backends registered by host daemons.
+    vhost_user_backends: VhostUserBackends,
+
+    /// Event logs of the VM instances, kept once the VMs are gone.
+    vm_event_logs: VmEventLogs,
+
+    /// VM instances allocated to or claimed by apps.
+    instance_registry: InstanceRegistry,
 }
 
 impl GlobalState {
@@ -698,6 +1468,65 @@ impl GlobalState {
             dtbo_file: Mutex::new(None),
             sk_state: maintenance::State::new(),
             display_service: None,
+            cid_reservations: CidReservations::load(
+                Path::new(maintenance::PERSISTENT_DIRECTORY).join(CID_RESERVATIONS_FILENAME),
+            ),
+            persistent_vms: PersistentVms::default(),
+            shutdown_handlers: VmShutdownHandlers::default(),
+            vm_memory: VmMemory::default(),
+            memory_reservations: MemoryReservations::default(),
+            vhost_user_backends: VhostUserBackends::default(),
+            vm_event_logs: VmEventLogs::new(
+                Path::new(maintenance::PERSISTENT_DIRECTORY).join(VM_EVENT_LOGS_DIRNAME),
+            ),
+            instance_registry: InstanceRegistry::load(
+                Path::new(maintenance::PERSISTENT_DIRECTORY).join(VM_INSTANCES_FILENAME),
+            ),
+        }
+    }
+
+    /// Checks that the app with `uid` may claim the VM instance without a transfer token, i.e. that
+    /// it owns it.
+    fn check_claim(&mut self, instance_id: &InstanceId, uid: uid_t) -> binder::Result<()> {
+        let registry_owner =
+            self.instance_registry.get(instance_id).map(|instance| instance.owner_uid);
+        let sk_owner = match (registry_owner, &mut self.sk_state) {
+            (None, Some(sk_state)) => sk_state
+                .owner_of_id(instance_id)
+                .context("Failed to look up the owner of the instance_id")
+                .with_log()
+                .or_service_specific_exception(-1)?,
+            _ => None,
+        };
+        check_claim(instance_id, uid, registry_owner, sk_owner)
+            .with_log()
+            .or_binder_exception(ExceptionCode::SECURITY)
+    }
+
+    /// Releases the CID reserved for the VM instance and deletes its event log, if any. Its
+    /// secrets are deleted separately, through Secretkeeper.
+    fn remove_instance_state(&mut self, instance_id: &InstanceId) {
+        if let Err(e) = self.cid_reservations.release(instance_id) {
+            error!("Failed to release the CID reserved for the instance_id: {e:?}");
+        }
+        if let Err(e) = self.vm_event_logs.remove(instance_id) {
+            error!("Failed to remove the event log of the instance_id: {e:?}");
+        }
+    }
+
+    /// Removes the VM instances whose owner matches `predicate` from the registry, with the state
+    /// kept for them, e.g. when their app or user is removed.
+    fn remove_instances_owned_by(&mut self, predicate: impl Fn(uid_t) -> bool) {
+        let instance_ids = match self.instance_registry.remove_owned_by(predicate) {
+            Ok(instance_ids) => instance_ids,
+            Err(e) => {
+                error!("Failed to remove VM instances from the registry: {e:?}");
+                return;
+            }
+        };
+        info!("Removing the state of {} VM instances", instance_ids.len());
+        for instance_id in &instance_ids {
+            self.remove_instance_state(instance_id);
         }
     }
 
@@ -755,21 +1584,45 @@ impl GlobalState {
         &mut self,
         requester_uid: uid_t,
         requester_debug_pid: pid_t,
+        instance_id: &[u8; 64],
     ) -> Result<Strong<dyn IGlobalVmContext>> {
         // Garbage collect unused VM contexts.
         self.held_contexts.retain(|_, instance| instance.strong_count() > 0);
 
-        let cid = self.get_next_available_cid()?;
+        // Only the app which reserved CIDs for the instance gets them, so that another app can't
+        // take over the vsock address of its VM.
+        let reservation = self
+            .cid_reservations
+            .get(instance_id)
+            .filter(|reservation| reservation.owner_uid == requester_uid);
+        let cid = if let Some(reservation) = reservation {
+            let cid = reservation.first_cid;
+            ensure!(!self.held_contexts.contains_key(&cid), "Reserved CID {cid} is already in use");
+            cid
+        } else {
+            self.get_next_available_cid()?
+        };
         let instance = Arc::new(Mutex::new(GlobalVmInstance {
             cid,
             requester_uid,
             requester_debug_pid,
+            instance_id: Some(*instance_id),
+            start_time: Some(Instant::now()),
             ..Default::default()
         }));
         create_temporary_directory(&instance.lock().unwrap().get_temp_dir(), Some(requester_uid))?;
 
         self.held_contexts.insert(cid, Arc::downgrade(&instance));
-        let binder = GlobalVmContext { instance, ..Default::default() };
+        let binder = GlobalVmContext {
+            instance,
+            persistent_vms: self.persistent_vms.clone(),
+            shutdown_handlers: self.shutdown_handlers.clone(),
+            vm_memory: self.vm_memory.clone(),
+            memory_reclaimers: self.memory_reservations.reclaimers().clone(),
+            vhost_user_backends: self.vhost_user_backends.clone(),
+            vm_event_logs: self.vm_event_logs.clone(),
+            lazy_service_guard: Default::default(),
+        };
         Ok(BnGlobalVmContext::new_binder(binder, BinderFeatures::default()))
     }
 
@@ -847,11 +1700,36 @@ fn get_or_create_common_dir() -> Result<PathBuf> {
 struct GlobalVmContext {
     /// Strong reference to the context's instance data structure.
     instance: Arc<Mutex<GlobalVmInstance>>,
+    /// Registry of persistent VMs, which the VM is in if it has a persistent name.
+    persistent_vms: PersistentVms,
+    /// Registry of the shutdown handlers, which the handler of the VM is in once it is set.
+    shutdown_handlers: VmShutdownHandlers,
+    /// Memory of the VMs, which the VM is accounted in once its memory is set.
+    vm_memory: VmMemory,
+    /// Registry of the memory reclaimers, which the reclaimer of the VM is in once it is set.
+    memory_reclaimers: MemoryReclaimers,
+    /// Registry of the vhost-user backends, which the VM can be connected to.
+    vhost_user_backends: VhostUserBackends,
+    /// Event logs of the VM instances, which the events of the VM are recorded in.
+    vm_event_logs: VmEventLogs,
     /// Keeps our service process running as long as this VM context exists.
     #[allow(dead_code)]
     lazy_service_guard: LazyServiceGuard,
 }
 
+impl Drop for GlobalVmContext {
+    fn drop(&mut self) {
+        // The VM can't be running anymore, e.g. because its virtmgr died.
+        let instance = self.instance.lock().unwrap();
+        if let Some(name) = &instance.persistent_name {
+            self.persistent_vms.unregister(instance.requester_uid, name);
+        }
+        self.shutdown_handlers.remove(instance.cid);
+        self.vm_memory.remove(instance.cid);
+        self.memory_reclaimers.remove(instance.cid);
+    }
+}
+
 impl Interface for GlobalVmContext {}
 
 impl IGlobalVmContext for GlobalVmContext {
@@ -864,12 +1742,115 @@ impl IGlobalVmContext for GlobalVmContext {
     }
 
     fn setHostConsoleName(&self, pathname: &str) -> binder::Result<()> {
+        if !is_pts_name(pathname) {
+            return Err(anyhow!("Host console {pathname} isn't a pty"))
+                .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+        }
         self.instance.lock().unwrap().host_console_name = Some(pathname.to_string());
         Ok(())
     }
+
+    fn setName(&self, name: &str) -> binder::Result<()> {
+        self.instance.lock().unwrap().name = name.to_owned();
+        Ok(())
+    }
+
+    fn setLabels(&self, labels: &[VmLabel]) -> binder::Result<()> {
+        self.instance.lock().unwrap().labels = labels.to_vec();
+        Ok(())
+    }
+
+    fn setDebuggable(&self, debuggable: bool) -> binder::Result<()> {
+        self.instance.lock().unwrap().debuggable = debuggable;
+        Ok(())
+    }
+
+    fn setProtected(&self, protected: bool) -> binder::Result<()> {
+        self.instance.lock().unwrap().protected = protected;
+        Ok(())
+    }
+
+    fn setAllowedHostVsockPorts(&self, ports: &[i32]) -> binder::Result<()> {
+        self.instance.lock().unwrap().allowed_host_vsock_ports = Some(ports.to_vec());
+        Ok(())
+    }
+
+    fn setPersistentVm(&self, name: &str, vm: &Strong<dyn IVirtualMachine>) -> binder::Result<()> {
+        let mut instance = self.instance.lock().unwrap();
+        if instance.persistent_name.is_some() {
+            return Err(anyhow!("VM is already registered as a persistent VM"))
+                .or_binder_exception(ExceptionCode::ILLEGAL_STATE);
+        }
+        self.persistent_vms
+            .register(instance.requester_uid, name, vm)
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        instance.persistent_name = Some(name.to_owned());
+        Ok(())
+    }
+
+    fn clearPersistentVm(&self) -> binder::Result<()> {
+        let mut instance = self.instance.lock().unwrap();
+        if let Some(name) = instance.persistent_name.take() {
+            self.persistent_vms.unregister(instance.requester_uid, &name);
+        }
+        Ok(())
+    }
+
+    fn setShutdownHandler(&self, handler: &Strong<dyn IVmShutdownHandler>) -> binder::Result<()> {
+        self.shutdown_handlers.set(self.instance.lock().unwrap().cid, handler);
+        Ok(())
+    }
+
+    fn setMemory(&self, memory_mib: i32) -> binder::Result<()> {
+        let memory_mib = u32::try_from(memory_mib)
+            .with_context(|| format!("Invalid memory size {memory_mib}"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        let cap_mib = memory_mib_cap().with_log().or_service_specific_exception(-1)?;
+        let instance = self.instance.lock().unwrap();
+        self.vm_memory
+            .set(instance.cid, instance.requester_uid, memory_mib, cap_mib)
+            .with_log()
+            .or_service_specific_exception(ERROR_MEMORY_CAP_EXCEEDED)
+    }
+
+    fn setMemoryReclaimer(
+        &self,
+        reclaimer: &Strong<dyn IVmMemoryReclaimer>,
+        qos_class: VmQosClass,
+    ) -> binder::Result<()> {
+        self.memory_reclaimers.set(self.instance.lock().unwrap().cid, qos_class, reclaimer);
+        Ok(())
+    }
+
+    fn connectVhostUserBackend(&self, name: &str) -> binder::Result<VhostUserConnection> {
+        let (cid, requester_uid) = {
+            let instance = self.instance.lock().unwrap();
+            (instance.cid, instance.requester_uid)
+        };
+        self.vhost_user_backends
+            .connect(name, cid, requester_uid)
+            .with_log()
+            .or_service_specific_exception(-1)
+    }
+
+    fn recordEvent(&self, event_type: VmEventType, detail: &str) -> binder::Result<()> {
+        let (instance_id, requester_uid) = {
+            let instance = self.instance.lock().unwrap();
+            (instance.instance_id, instance.requester_uid)
+        };
+        // VMs without an instance, or with the all-zero ID of legacy configs, have no event log.
+        let Some(instance_id) = instance_id.filter(|instance_id| *instance_id != [0; 64]) else {
+            return Ok(());
+        };
+        self.vm_event_logs
+            .record(&instance_id, requester_uid, event_type, detail)
+            .context("Failed to record VM event")
+            .with_log()
+            .or_service_specific_exception(-1)
+    }
 }
 
-fn handle_stream_connection_tombstoned() -> Result<()> {
+fn handle_stream_connection_tombstoned(state: &Mutex<GlobalState>) -> Result<()> {
     // Should not listen for tombstones on a guest VM's port.
     assert!(!is_valid_guest_cid(VM_TOMBSTONES_SERVICE_PORT as Cid));
     let listener =
@@ -891,6 +1872,14 @@ fn handle_stream_connection_tombstoned() -> Result<()> {
                 }
                 _ => info!("Vsock Stream connected to cid={cid} for tombstones"),
             }
+            let allowed = running_vm(&state.lock().unwrap().held_contexts, cid as i32)
+                .map_or(true, |vm| {
+                    vm.lock().unwrap().allows_host_vsock_port(VM_TOMBSTONES_SERVICE_PORT as u32)
+                });
+            if !allowed {
+                warn!("Rejecting tombstone vsock connection blocked by the firewall of cid={cid}");
+                continue;
+            }
         }
         std::thread::spawn(move || {
             if let Err(e) = handle_tombstone(&mut incoming_stream) {
@@ -971,6 +1960,48 @@ fn check_internet_permission() -> binder::Result<()> {
     check_permission("android.permission.INTERNET")
 }
 
+/// Check whether the caller of the current Binder method is a native daemon of the platform,
+/// rather than an app, as only those may emulate devices for the VMs of other owners.
+fn check_native_caller() -> binder::Result<()> {
+    const FIRST_APPLICATION_UID: uid_t = 10000;
+    let uid = get_calling_uid();
+    if multiuser_get_app_id(uid) < FIRST_APPLICATION_UID {
+        Ok(())
+    } else {
+        Err(anyhow!("uid {uid} isn't a native daemon")).or_binder_exception(ExceptionCode::SECURITY)
+    }
+}
+
+/// Check whether the caller of the current Binder method runs in one of the SELinux domains which
+/// the device allows to serve vhost-user backends, see `SYSPROP_VHOST_USER_BACKEND_DOMAINS`.
+fn check_vhost_user_backend_caller() -> binder::Result<()> {
+    let context = ThreadState::with_calling_sid(|sid| {
+        sid.and_then(|sid| sid.to_str().ok()).map(str::to_owned)
+    })
+    .context("No SELinux context for the caller")
+    .or_binder_exception(ExceptionCode::SECURITY)?;
+    let allowed_domains = system_properties::read(SYSPROP_VHOST_USER_BACKEND_DOMAINS)
+        .context("Failed to read the domains allowed to serve vhost-user backends")
+        .or_service_specific_exception(-1)?
+        .unwrap_or_default();
+    if is_allowed_domain(&allowed_domains, &context) {
+        Ok(())
+    } else {
+        Err(anyhow!("{context} isn't allowed to serve vhost-user backends"))
+            .or_binder_exception(ExceptionCode::SECURITY)
+    }
+}
+
+/// Returns whether the type of the SELinux `context` is one of the comma-separated
+/// `allowed_domains`.
+fn is_allowed_domain(allowed_domains: &str, context: &str) -> bool {
+    let Some(domain) = context.split(':').nth(2) else { return false };
+    allowed_domains
+        .split(',')
+        .map(str::trim)
+        .any(|allowed| !allowed.is_empty() && allowed == domain)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -989,4 +2020,247 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn instances_are_only_claimed_from_their_owner() {
+        const APP_UID: uid_t = 10010;
+        const OTHER_APP_UID: uid_t = 10011;
+        const OTHER_USER_APP_UID: uid_t = 1010010;
+        let id = [0u8; 64];
+
+        assert!(check_claim(&id, APP_UID, Some(APP_UID), None).is_ok());
+        assert!(check_claim(&id, APP_UID, Some(OTHER_APP_UID), None).is_err());
+        // The registry is trusted over the Secretkeeper state.
+        assert!(check_claim(&id, APP_UID, Some(APP_UID), Some((0, 10011))).is_ok());
+        assert!(check_claim(&id, APP_UID, Some(OTHER_APP_UID), Some((0, 10010))).is_err());
+        // Unknown instances in the namespace of the user of the caller.
+        assert!(check_claim(&id, APP_UID, None, None).is_ok());
+        assert!(check_claim(&id, APP_UID, None, Some((0, 10011))).is_err());
+        assert!(check_claim(&id, OTHER_USER_APP_UID, None, None).is_err());
+    }
+
+    #[test]
+    fn legacy_instances_are_only_claimed_from_their_owner() {
+        const APP_UID: uid_t = 10010;
+        // Random, so not in the namespace of any user in practice.
+        let id = [0xa5u8; 64];
+
+        assert!(check_claim(&id, APP_UID, None, Some((0, 10010))).is_ok());
+        assert!(check_claim(&id, APP_UID, None, Some((0, 10011))).is_err());
+        assert!(check_claim(&id, APP_UID, None, Some((1, 10010))).is_err());
+        assert!(check_claim(&id, APP_UID, None, None).is_err());
+    }
+
+    #[test]
+    fn persistent_vms_are_found_by_owner_and_name() -> Result<()> {
+        let vms = PersistentVms::<&str>::default();
+        vms.register(10001, "vm", &"vm of 10001")?;
+        vms.register(10002, "vm", &"vm of 10002")?;
+
+        assert_eq!(Some("vm of 10001"), vms.get(10001, "vm"));
+        assert_eq!(Some("vm of 10002"), vms.get(10002, "vm"));
+        assert_eq!(None, vms.get(10001, "other"));
+        assert_eq!(None, vms.get(10003, "vm"));
+        Ok(())
+    }
+
+    #[test]
+    fn persistent_vm_name_is_unique_per_owner() -> Result<()> {
+        let vms = PersistentVms::<&str>::default();
+        vms.register(10001, "vm", &"first")?;
+        assert!(vms.register(10001, "vm", &"second").is_err());
+        assert_eq!(Some("first"), vms.get(10001, "vm"));
+
+        vms.unregister(10001, "vm");
+        assert_eq!(None, vms.get(10001, "vm"));
+        vms.register(10001, "vm", &"second")?;
+        assert_eq!(Some("second"), vms.get(10001, "vm"));
+        Ok(())
+    }
+
+    #[test]
+    fn unregistering_persistent_vm_of_other_owner_does_nothing() -> Result<()> {
+        let vms = PersistentVms::<&str>::default();
+        vms.register(10001, "vm", &"vm of 10001")?;
+        vms.unregister(10002, "vm");
+        assert_eq!(Some("vm of 10001"), vms.get(10001, "vm"));
+        Ok(())
+    }
+
+    #[test]
+    fn vm_memory_is_capped_per_uid() -> Result<()> {
+        let vm_memory = VmMemory::default();
+        vm_memory.set(2048, 10001, 512, Some(1024))?;
+        vm_memory.set(2049, 10001, 512, Some(1024))?;
+        vm_memory.set(2050, 10002, 1024, Some(1024))?;
+        assert!(vm_memory.set(2049, 10001, 513, Some(1024)).is_err());
+
+        vm_memory.remove(2048);
+        vm_memory.set(2049, 10001, 1024, Some(1024))?;
+        Ok(())
+    }
+
+    #[test]
+    fn vm_memory_may_shrink_over_cap() -> Result<()> {
+        let vm_memory = VmMemory::default();
+        vm_memory.set(2048, 10001, 2048, None)?;
+        vm_memory.set(2048, 10001, 1536, Some(1024))?;
+        assert!(vm_memory.set(2048, 10001, 1600, Some(1024)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn host_vsock_ports_are_allowed_by_firewall() {
+        let mut instance = GlobalVmInstance::default();
+        assert!(instance.allows_host_vsock_port(2000));
+
+        instance.allowed_host_vsock_ports = Some(vec![2000, 3000]);
+        assert!(instance.allows_host_vsock_port(2000));
+        assert!(!instance.allows_host_vsock_port(2001));
+
+        instance.allowed_host_vsock_ports = Some(vec![]);
+        assert!(!instance.allows_host_vsock_port(2000));
+    }
+
+    #[test]
+    fn vhost_user_backend_domains() {
+        let allowed = "vendor_vhost_user_gpu, hal_audio_default";
+        assert!(is_allowed_domain(allowed, "u:r:vendor_vhost_user_gpu:s0"));
+        assert!(is_allowed_domain(allowed, "u:r:hal_audio_default:s0"));
+        assert!(!is_allowed_domain(allowed, "u:r:untrusted_app:s0:c512,c768"));
+        assert!(!is_allowed_domain(allowed, "u:r:vendor_vhost_user:s0"));
+        assert!(!is_allowed_domain(allowed, "vendor_vhost_user_gpu"));
+        assert!(!is_allowed_domain("", "u:r:vendor_vhost_user_gpu:s0"));
+        assert!(!is_allowed_domain(",", "u:r::s0"));
+    }
+
+    #[test]
+    fn vm_count_is_capped_per_uid() {
+        let vms = [vm(2048, 10001), vm(2049, 10001), vm(2050, 10002)];
+        let held_contexts = held_contexts(&vms);
+
+        assert!(check_vm_count(&held_contexts, 10001, None).is_ok());
+        assert!(check_vm_count(&held_contexts, 10001, Some(2)).is_err());
+        assert!(check_vm_count(&held_contexts, 10002, Some(2)).is_ok());
+    }
+
+    fn held_contexts(
+        vms: &[Arc<Mutex<GlobalVmInstance>>],
+    ) -> HashMap<Cid, Weak<Mutex<GlobalVmInstance>>> {
+        vms.iter().map(|vm| (vm.lock().unwrap().cid, Arc::downgrade(vm))).collect()
+    }
+
+    fn vm(cid: Cid, requester_uid: uid_t) -> Arc<Mutex<GlobalVmInstance>> {
+        Arc::new(Mutex::new(GlobalVmInstance { cid, requester_uid, ..Default::default() }))
+    }
+
+    #[test]
+    fn owned_vms_are_those_of_the_caller() {
+        let vms = [vm(2050, 10001), vm(2048, 10001), vm(2049, 10002)];
+        let held_contexts = held_contexts(&vms);
+
+        let cids: Vec<_> =
+            owned_vms(&held_contexts, 10001).iter().map(|vm| vm.lock().unwrap().cid).collect();
+        assert_eq!(cids, [2048, 2050]);
+        assert!(owned_vms(&held_contexts, 10003).is_empty());
+    }
+
+    #[test]
+    fn vms_of_other_owners_look_missing() {
+        let vms = [vm(2048, 10001), vm(2049, 10002)];
+        let held_contexts = held_contexts(&vms);
+
+        assert!(owned_vm(&held_contexts, 10001, 2048).is_ok());
+        let other_owner = owned_vm(&held_contexts, 10001, 2049).unwrap_err();
+        let missing = owned_vm(&held_contexts, 10001, 2047).unwrap_err();
+        assert_eq!(other_owner.to_string(), "No VM with CID 2049");
+        assert_eq!(missing.to_string(), "No VM with CID 2047");
+        assert!(owned_vm(&held_contexts, 10001, -1).is_err());
+    }
+
+    #[test]
+    fn dead_vms_are_not_owned() {
+        let vms = [vm(2048, 10001)];
+        let held_contexts = held_contexts(&vms);
+        drop(vms);
+
+        assert!(owned_vms(&held_contexts, 10001).is_empty());
+        assert!(owned_vm(&held_contexts, 10001, 2048).is_err());
+    }
+
+    #[test]
+    fn console_is_only_opened_for_debuggable_vms() {
+        let mut vm = GlobalVmInstance {
+            cid: 2048,
+            host_console_name: Some("/dev/pts/1".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(vm.console_name().unwrap_err().exception_code(), ExceptionCode::SECURITY);
+
+        vm.debuggable = true;
+        assert_eq!(vm.console_name().unwrap(), "/dev/pts/1");
+
+        vm.host_console_name = None;
+        assert_eq!(vm.console_name().unwrap_err().exception_code(), ExceptionCode::ILLEGAL_STATE);
+    }
+
+    #[test]
+    fn only_ptys_are_console_names() {
+        assert!(is_pts_name("/dev/pts/0"));
+        assert!(is_pts_name("/dev/pts/123"));
+        assert!(!is_pts_name("/dev/pts/"));
+        assert!(!is_pts_name("/dev/pts/ptmx"));
+        assert!(!is_pts_name("/dev/pts/1/../../null"));
+        assert!(!is_pts_name("/dev/null"));
+        assert!(!is_pts_name("/data/local/tmp/pts/1"));
+    }
+
+    #[test]
+    fn console_must_be_a_pty_of_the_owner() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("1");
+        fs::write(&file, b"")?;
+
+        assert!(open_console(file.to_str().unwrap(), 0).is_err());
+        assert!(open_console("/dev/null", 0).is_err());
+        // There is no /dev/pts/<N> owned by this UID, whichever ptys are open.
+        assert!(open_console("/dev/pts/0", 99999).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn owner_info_is_found_for_any_owner() {
+        let owned = vm(2048, 10001);
+        {
+            let mut owned = owned.lock().unwrap();
+            owned.instance_id = Some([7; 64]);
+            owned.protected = true;
+        }
+        let vms = [owned, vm(2049, 10002)];
+        let held_contexts = held_contexts(&vms);
+
+        let owner_info = running_vm(&held_contexts, 2048).unwrap().lock().unwrap().owner_info();
+        assert_eq!(owner_info.ownerUid, 10001);
+        assert_eq!(owner_info.instanceId, [7; 64]);
+        assert!(owner_info.isProtected);
+        let owner_info = running_vm(&held_contexts, 2049).unwrap().lock().unwrap().owner_info();
+        assert_eq!(owner_info.ownerUid, 10002);
+        assert!(!owner_info.isProtected);
+        assert!(running_vm(&held_contexts, 2047).is_err());
+    }
+
+    #[test]
+    fn resource_usage_reports_owner_and_memory() {
+        let vm = vm(2048, 10001);
+        let mut vm = vm.lock().unwrap();
+        vm.protected = true;
+        vm.start_time = Some(Instant::now());
+
+        let usage = vm.resource_usage(1024);
+        assert_eq!(usage.cid, 2048);
+        assert_eq!(usage.ownerUid, 10001);
+        assert!(usage.isProtected);
+        assert_eq!(usage.accountedMemoryMib, 1024);
+        assert!(usage.uptimeMillis >= 0);
+    }
 }
@@ -13,12 +13,39 @@
 // limitations under the License.
 
 //! Functions for creating and collecting atoms.
+//!
+//! Like the other atoms of AVF, the atoms are defined in frameworks/proto_logging, in the
+//! virtualization atoms, and generated into statslog_virtualization_rust from there. VmExited
+//! gains these fields for the boot latency breakdown, the payload exit and the crash reported by
+//! the guest, which must land there before this module builds:
+//!
+//! ```proto
+//! optional int64 kernel_boot_time_millis = 9;
+//! optional int64 payload_start_time_millis = 10;
+//! optional int64 payload_ready_time_millis = 11;
+//! enum PayloadExitReason {
+//!     NOT_REPORTED = 0;
+//!     FINISHED = 1;
+//!     ERROR = 2;
+//! }
+//! optional PayloadExitReason payload_exit_reason = 12;
+//! optional int32 payload_exit_code = 13;
+//! enum GuestCrashKind {
+//!     GUEST_CRASH_NOT_REPORTED = 0;
+//!     GUEST_CRASH_UNKNOWN = 1;
+//!     PAYLOAD_CRASHED = 2;
+//!     BARE_METAL_PANIC = 3;
+//! }
+//! optional GuestCrashKind guest_crash_kind = 14;
+//! ```
 
 use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::DeathReason::DeathReason;
 use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::{
     AtomVmBooted::AtomVmBooted,
     AtomVmCreationRequested::AtomVmCreationRequested,
-    AtomVmExited::AtomVmExited,
+    AtomVmExited::{
+        AtomVmExited, GuestCrashKind::GuestCrashKind, PayloadExitReason::PayloadExitReason,
+    },
 };
 use anyhow::Result;
 use log::{trace, warn};
@@ -105,6 +132,19 @@ pub fn forward_vm_exited_atom(atom: &AtomVmExited) {
         _ => vm_exited::DeathReason::Unknown,
     };
 
+    let payload_exit_reason = match atom.payloadExitReason {
+        PayloadExitReason::FINISHED => vm_exited::PayloadExitReason::Finished,
+        PayloadExitReason::ERROR => vm_exited::PayloadExitReason::Error,
+        _ => vm_exited::PayloadExitReason::NotReported,
+    };
+
+    let guest_crash_kind = match atom.guestCrashKind {
+        GuestCrashKind::NOT_REPORTED => vm_exited::GuestCrashKind::GuestCrashNotReported,
+        GuestCrashKind::PAYLOAD_CRASHED => vm_exited::GuestCrashKind::PayloadCrashed,
+        GuestCrashKind::BARE_METAL_PANIC => vm_exited::GuestCrashKind::BareMetalPanic,
+        _ => vm_exited::GuestCrashKind::GuestCrashUnknown,
+    };
+
     let vm_exited = vm_exited::VmExited {
         uid: atom.uid,
         vm_identifier: &atom.vmIdentifier,
@@ -114,6 +154,12 @@ pub fn forward_vm_exited_atom(atom: &AtomVmExited) {
         rss_vm_kb: atom.rssVmKb,
         rss_crosvm_kb: atom.rssCrosvmKb,
         exit_signal: atom.exitSignal,
+        kernel_boot_time_millis: atom.kernelBootTimeMillis,
+        payload_start_time_millis: atom.payloadStartTimeMillis,
+        payload_ready_time_millis: atom.payloadReadyTimeMillis,
+        payload_exit_reason,
+        payload_exit_code: atom.payloadExitCode,
+        guest_crash_kind,
     };
 
     wait_for_statsd().unwrap_or_else(|e| warn!("failed to wait for statsd with error: {}", e));
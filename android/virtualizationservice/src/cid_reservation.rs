@@ -0,0 +1,291 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! CIDs reserved for VM instances, so that host services can keep using the same vsock address
+//! for a VM across its restarts and reboots of the device.
+
+use crate::aidl::Cid;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use log::{error, info};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::ops::RangeInclusive;
+use std::os::unix::raw::uid_t;
+use std::path::PathBuf;
+
+/// CIDs which are only assigned to VM instances holding a reservation. The other guest CIDs are
+/// assigned dynamically.
+pub const RESERVED_CIDS: RangeInclusive<Cid> = 61440..=65535;
+
+/// Maximum number of consecutive CIDs which can be reserved for a VM instance.
+pub const MAX_CIDS_PER_INSTANCE: u32 = 16;
+
+/// Identifier of a VM instance.
+pub type InstanceId = [u8; 64];
+
+/// Consecutive CIDs reserved for a VM instance by an app. The VM is given the first one.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CidReservation {
+    /// The app which made the reservation. Only it can use or release the reservation.
+    pub owner_uid: uid_t,
+    /// The first reserved CID.
+    pub first_cid: Cid,
+    /// The number of reserved CIDs.
+    pub count: u32,
+}
+
+impl CidReservation {
+    fn cids(&self) -> RangeInclusive<Cid> {
+        self.first_cid..=self.first_cid + self.count - 1
+    }
+}
+
+/// The CID reservations, persisted in a file with one `<hex instance ID> <owner uid> <first CID>
+/// <count>` line each.
+#[derive(Debug)]
+pub struct CidReservations {
+    path: PathBuf,
+    reservations: HashMap<InstanceId, CidReservation>,
+}
+
+impl CidReservations {
+    /// Loads the reservations persisted in the file at `path`. Malformed entries are dropped.
+    pub fn load(path: PathBuf) -> Self {
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    error!("Failed to read CID reservations from {path:?}: {e:?}");
+                }
+                String::new()
+            }
+        };
+        let mut reservations: HashMap<InstanceId, CidReservation> = HashMap::new();
+        for line in content.lines() {
+            match parse_reservation(line) {
+                Ok((instance_id, reservation))
+                    if reservations.values().any(|r| overlap(r, &reservation)) =>
+                {
+                    error!(
+                        "Ignoring CID reservation of {}..., overlapping another one",
+                        &hex::encode(instance_id)[..8]
+                    );
+                }
+                Ok((instance_id, reservation)) => {
+                    reservations.insert(instance_id, reservation);
+                }
+                Err(e) => error!("Ignoring CID reservation {line:?}: {e:?}"),
+            }
+        }
+        Self { path, reservations }
+    }
+
+    /// Returns the CIDs reserved for the VM instance, if any.
+    pub fn get(&self, instance_id: &InstanceId) -> Option<&CidReservation> {
+        self.reservations.get(instance_id)
+    }
+
+    /// Returns whether `cid` is reserved for a VM instance.
+    pub fn is_reserved(&self, cid: Cid) -> bool {
+        self.reservations.values().any(|reservation| reservation.cids().contains(&cid))
+    }
+
+    /// Reserves `count` consecutive CIDs for the VM instance on behalf of the app with the given
+    /// uid, or returns the reservation it already made for the instance. Returns the first CID.
+    pub fn reserve(
+        &mut self,
+        instance_id: &InstanceId,
+        owner_uid: uid_t,
+        count: u32,
+    ) -> Result<Cid> {
+        ensure!(*instance_id != [0; 64], "Invalid instance ID");
+        ensure!(
+            (1..=MAX_CIDS_PER_INSTANCE).contains(&count),
+            "Can't reserve {count} CIDs, the limit is {MAX_CIDS_PER_INSTANCE}"
+        );
+        if let Some(reservation) = self.get(instance_id) {
+            ensure!(reservation.owner_uid == owner_uid, "CIDs are reserved by another app");
+            ensure!(
+                reservation.count == count,
+                "{} CIDs are already reserved for the instance",
+                reservation.count
+            );
+            return Ok(reservation.first_cid);
+        }
+        let reservation = (*RESERVED_CIDS.start()..=*RESERVED_CIDS.end() + 1 - count)
+            .map(|first_cid| CidReservation { owner_uid, first_cid, count })
+            .find(|candidate| !self.reservations.values().any(|r| overlap(r, candidate)))
+            .ok_or_else(|| anyhow!("No {count} consecutive reservable CIDs left"))?;
+        let first_cid = reservation.first_cid;
+        self.reservations.insert(*instance_id, reservation);
+        if let Err(e) = self.save() {
+            self.reservations.remove(instance_id);
+            return Err(e);
+        }
+        info!(
+            "Reserved {count} CIDs from {first_cid} for VM instance {}... of uid {owner_uid}",
+            &hex::encode(instance_id)[..8]
+        );
+        Ok(first_cid)
+    }
+
+    /// Releases the CIDs reserved for the VM instance, if any.
+    pub fn release(&mut self, instance_id: &InstanceId) -> Result<()> {
+        let Some(reservation) = self.reservations.remove(instance_id) else {
+            return Ok(());
+        };
+        if let Err(e) = self.save() {
+            self.reservations.insert(*instance_id, reservation);
+            return Err(e);
+        }
+        info!(
+            "Released CIDs {:?} of VM instance {}...",
+            reservation.cids(),
+            &hex::encode(instance_id)[..8]
+        );
+        Ok(())
+    }
+
+    fn save(&self) -> Result<()> {
+        let content: String = self
+            .reservations
+            .iter()
+            .map(|(instance_id, reservation)| {
+                let CidReservation { owner_uid, first_cid, count } = reservation;
+                format!("{} {owner_uid} {first_cid} {count}\n", hex::encode(instance_id))
+            })
+            .collect();
+        // Write a new file and rename it, so that the reservations can't be lost to a crash.
+        let temp_path = self.path.with_extension("tmp");
+        fs::write(&temp_path, content).with_context(|| format!("Failed to write {temp_path:?}"))?;
+        fs::rename(&temp_path, &self.path)
+            .with_context(|| format!("Failed to rename {temp_path:?} to {:?}", self.path))
+    }
+}
+
+fn overlap(a: &CidReservation, b: &CidReservation) -> bool {
+    a.cids().start() <= b.cids().end() && b.cids().start() <= a.cids().end()
+}
+
+fn parse_reservation(line: &str) -> Result<(InstanceId, CidReservation)> {
+    let fields: Vec<_> = line.split(' ').collect();
+    let [instance_id, owner_uid, first_cid, count] = fields[..] else {
+        bail!("Expected 4 fields, found {}", fields.len());
+    };
+    let mut id = [0u8; 64];
+    hex::decode_to_slice(instance_id, &mut id).context("Invalid instance ID")?;
+    ensure!(id != [0; 64], "Invalid instance ID");
+    let owner_uid = owner_uid.parse().context("Invalid uid")?;
+    let first_cid = first_cid.parse().context("Invalid CID")?;
+    let count = count.parse().context("Invalid CID count")?;
+    ensure!((1..=MAX_CIDS_PER_INSTANCE).contains(&count), "Invalid CID count {count}");
+    let reservation = CidReservation { owner_uid, first_cid, count };
+    let cids = reservation.cids();
+    if !RESERVED_CIDS.contains(cids.start()) || !RESERVED_CIDS.contains(cids.end()) {
+        bail!("CIDs {cids:?} are outside of the reservable range");
+    }
+    Ok((id, reservation))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UID: uid_t = 10100;
+    const OTHER_UID: uid_t = 10101;
+
+    #[test]
+    fn reservations_are_stable_and_persisted() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("cid_reservations");
+        let mut reservations = CidReservations::load(path.clone());
+
+        let cid_a = reservations.reserve(&[1; 64], UID, 1)?;
+        let cid_b = reservations.reserve(&[2; 64], UID, 1)?;
+        assert_ne!(cid_a, cid_b);
+        assert!(RESERVED_CIDS.contains(&cid_a) && RESERVED_CIDS.contains(&cid_b));
+        assert_eq!(cid_a, reservations.reserve(&[1; 64], UID, 1)?);
+
+        let mut reloaded = CidReservations::load(path.clone());
+        assert_eq!(Some(cid_a), reloaded.get(&[1; 64]).map(|r| r.first_cid));
+        assert_eq!(Some(UID), reloaded.get(&[2; 64]).map(|r| r.owner_uid));
+        assert_eq!(None, reloaded.get(&[3; 64]));
+
+        reloaded.release(&[1; 64])?;
+        assert!(!CidReservations::load(path).is_reserved(cid_a));
+        Ok(())
+    }
+
+    #[test]
+    fn ranges_are_reserved() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut reservations = CidReservations::load(dir.path().join("cid_reservations"));
+
+        let first = reservations.reserve(&[1; 64], UID, 4)?;
+        let second = reservations.reserve(&[2; 64], UID, 2)?;
+        assert!((first..first + 4).all(|cid| reservations.is_reserved(cid)));
+        assert!(second >= first + 4 || second + 2 <= first);
+        assert!(reservations.reserve(&[1; 64], UID, 2).is_err());
+        assert!(reservations.reserve(&[3; 64], UID, 0).is_err());
+        assert!(reservations.reserve(&[3; 64], UID, MAX_CIDS_PER_INSTANCE + 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reservations_are_owned() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut reservations = CidReservations::load(dir.path().join("cid_reservations"));
+
+        reservations.reserve(&[1; 64], UID, 1)?;
+        assert!(reservations.reserve(&[1; 64], OTHER_UID, 1).is_err());
+        assert_eq!(Some(UID), reservations.get(&[1; 64]).map(|r| r.owner_uid));
+        Ok(())
+    }
+
+    #[test]
+    fn zero_instance_id_is_rejected() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut reservations = CidReservations::load(dir.path().join("cid_reservations"));
+
+        assert!(reservations.reserve(&[0; 64], UID, 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_reservations_are_ignored() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("cid_reservations");
+        let valid_id = hex::encode([1; 64]);
+        let other_id = hex::encode([2; 64]);
+        let zero_id = hex::encode([0; 64]);
+        fs::write(
+            &path,
+            format!(
+                "{valid_id} {UID} 61440 2\n{valid_id}\nabcd {UID} 61450 1\n{other_id} {UID} 2048 1\n\
+                 {other_id} {UID} 61441 1\n{zero_id} {UID} 61460 1\n{other_id} {UID} 65535 2\n"
+            ),
+        )?;
+
+        let reservations = CidReservations::load(path);
+        assert_eq!(
+            Some(&CidReservation { owner_uid: UID, first_cid: 61440, count: 2 }),
+            reservations.get(&[1; 64])
+        );
+        assert_eq!(None, reservations.get(&[2; 64]));
+        assert!(!reservations.is_reserved(61450));
+        assert!(!reservations.is_reserved(61460));
+        Ok(())
+    }
+}
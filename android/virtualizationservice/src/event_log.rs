@@ -0,0 +1,340 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Event logs of VM instances, which outlive the VMs so that their owners can find out what
+//! happened to them, e.g. why they died, once the VMs are gone.
+
+use crate::cid_reservation::InstanceId;
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
+    VmEvent::VmEvent, VmEventType::VmEventType,
+};
+use anyhow::{bail, ensure, Context, Result};
+use log::{error, info};
+use openssl::sha::sha256;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::raw::uid_t;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of events kept in the log of each instance. Older events are dropped.
+const MAX_EVENTS: usize = 64;
+
+/// Number of instances whose log is kept. The least recently updated logs are dropped, e.g. those
+/// of the instances of uninstalled apps.
+const MAX_LOGS: usize = 256;
+
+/// Number of instances whose log is kept for each owner, so that one app can't evict the logs of
+/// all the others by creating instances.
+const MAX_LOGS_PER_UID: usize = 32;
+
+/// Longest detail kept for an event, in bytes.
+const MAX_DETAIL_LEN: usize = 1024;
+
+/// The event logs, keyed by the owner of the instance and its ID. Each is persisted in the
+/// subdirectory of the directory named after the UID of the owner, in a file named after the hex
+/// SHA-256 digest of the instance ID, so that the IDs themselves aren't written to disk. A file
+/// holds one `<timestamp> <type> <detail>` line per event.
+#[derive(Clone, Debug, Default)]
+pub struct VmEventLogs(Arc<Mutex<PathBuf>>);
+
+impl VmEventLogs {
+    /// Returns the logs persisted in `dir`, which is created once an event is recorded.
+    pub fn new(dir: PathBuf) -> Self {
+        Self(Arc::new(Mutex::new(dir)))
+    }
+
+    /// Appends an event to the log which `owner_uid` keeps for the instance. If the instance is
+    /// claimed by another app, that app starts a log of its own.
+    pub fn record(
+        &self,
+        instance_id: &InstanceId,
+        owner_uid: uid_t,
+        event_type: VmEventType,
+        detail: &str,
+    ) -> Result<()> {
+        check_instance_id(instance_id)?;
+        let dir = self.0.lock().unwrap();
+        let path = log_path(&dir, owner_uid, instance_id);
+        let mut events = match read_log(&path)? {
+            Some(events) => events,
+            None => {
+                let owner_dir = path.parent().unwrap();
+                fs::create_dir_all(owner_dir)
+                    .with_context(|| format!("Failed to create {owner_dir:?}"))?;
+                evict_logs(list_logs(owner_dir), MAX_LOGS_PER_UID - 1);
+                evict_logs(list_all_logs(&dir), MAX_LOGS - 1);
+                vec![]
+            }
+        };
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        events.push(VmEvent {
+            timestampMillis: timestamp.as_millis().try_into()?,
+            r#type: event_type,
+            detail: sanitize_detail(detail),
+        });
+        let excess = events.len().saturating_sub(MAX_EVENTS);
+        events.drain(..excess);
+        write_log(&path, &events)
+    }
+
+    /// Returns the events which `uid` recorded for the instance, oldest first. The log is empty if
+    /// `uid` never ran the instance, whether or not another app did.
+    pub fn get(&self, instance_id: &InstanceId, uid: uid_t) -> Result<Vec<VmEvent>> {
+        check_instance_id(instance_id)?;
+        let dir = self.0.lock().unwrap();
+        Ok(read_log(&log_path(&dir, uid, instance_id))?.unwrap_or_default())
+    }
+
+    /// Deletes the logs of the instance, of all its owners.
+    pub fn remove(&self, instance_id: &InstanceId) -> Result<()> {
+        let dir = self.0.lock().unwrap();
+        let file_name = hex::encode(sha256(instance_id));
+        for owner_dir in list_owner_dirs(&dir) {
+            let path = owner_dir.join(&file_name);
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => {
+                    return Err(e).with_context(|| format!("Failed to remove {path:?}"));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Rejects the all-zero ID, which stands for VMs without an instance.
+fn check_instance_id(instance_id: &InstanceId) -> Result<()> {
+    ensure!(instance_id.iter().any(|&byte| byte != 0), "Invalid all-zero instance ID");
+    Ok(())
+}
+
+fn log_path(dir: &Path, owner_uid: uid_t, instance_id: &InstanceId) -> PathBuf {
+    dir.join(owner_uid.to_string()).join(hex::encode(sha256(instance_id)))
+}
+
+fn read_log(path: &Path) -> Result<Option<Vec<VmEvent>>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {path:?}")),
+    };
+    let mut events = vec![];
+    for line in content.lines() {
+        match parse_event(line) {
+            Ok(event) => events.push(event),
+            Err(e) => error!("Ignoring event {line:?} in {path:?}: {e:?}"),
+        }
+    }
+    Ok(Some(events))
+}
+
+fn parse_event(line: &str) -> Result<VmEvent> {
+    let mut fields = line.splitn(3, ' ');
+    let (Some(timestamp), Some(event_type)) = (fields.next(), fields.next()) else {
+        bail!("Missing separator");
+    };
+    Ok(VmEvent {
+        timestampMillis: timestamp.parse().context("Invalid timestamp")?,
+        r#type: VmEventType(event_type.parse().context("Invalid event type")?),
+        detail: fields.next().unwrap_or_default().to_owned(),
+    })
+}
+
+fn write_log(path: &Path, events: &[VmEvent]) -> Result<()> {
+    let mut content = String::new();
+    for event in events {
+        content += &format!("{} {} {}\n", event.timestampMillis, event.r#type.0, event.detail);
+    }
+    // Write a new file and rename it, so that the log can't be truncated by a crash.
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, content).with_context(|| format!("Failed to write {temp_path:?}"))?;
+    fs::rename(&temp_path, path)
+        .with_context(|| format!("Failed to rename {temp_path:?} to {path:?}"))
+}
+
+/// Returns the subdirectories of `dir` holding the logs of each owner.
+fn list_owner_dirs(dir: &Path) -> Vec<PathBuf> {
+    match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_dir()))
+            .map(|entry| entry.path())
+            .collect(),
+        Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+        Err(e) => {
+            error!("Failed to list event log owners in {dir:?}: {e:?}");
+            vec![]
+        }
+    }
+}
+
+/// Returns the logs of the owner directory `dir`, with the time they were last updated.
+fn list_logs(dir: &Path) -> Vec<(SystemTime, PathBuf)> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Failed to list event logs in {dir:?}: {e:?}");
+            return vec![];
+        }
+    };
+    entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let modified = entry.metadata().ok()?.modified().ok()?;
+            Some((modified, entry.path()))
+        })
+        .collect()
+}
+
+/// Returns the logs of all the owners in `dir`, with the time they were last updated.
+fn list_all_logs(dir: &Path) -> Vec<(SystemTime, PathBuf)> {
+    list_owner_dirs(dir).iter().flat_map(|owner_dir| list_logs(owner_dir)).collect()
+}
+
+/// Deletes the least recently updated of `logs`, so that at most `max_logs` are left.
+fn evict_logs(mut logs: Vec<(SystemTime, PathBuf)>, max_logs: usize) {
+    if logs.len() <= max_logs {
+        return;
+    }
+    logs.sort();
+    for (_, path) in &logs[..logs.len() - max_logs] {
+        info!("Dropping event log {path:?}");
+        if let Err(e) = fs::remove_file(path) {
+            error!("Failed to remove event log {path:?}: {e:?}");
+        }
+    }
+}
+
+/// Keeps the detail on a single line of the log, and bounds its length.
+fn sanitize_detail(detail: &str) -> String {
+    let mut detail = detail.replace(['\n', '\r'], " ");
+    if detail.len() > MAX_DETAIL_LEN {
+        let mut end = MAX_DETAIL_LEN;
+        while !detail.is_char_boundary(end) {
+            end -= 1;
+        }
+        detail.truncate(end);
+    }
+    detail
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_persisted_for_the_owner() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let logs = VmEventLogs::new(dir.path().join("logs"));
+        logs.record(&[1; 64], 10010, VmEventType::CREATED, "name=test")?;
+        logs.record(&[1; 64], 10010, VmEventType::DIED, "CRASH\nsignal 9")?;
+
+        let events = VmEventLogs::new(dir.path().join("logs")).get(&[1; 64], 10010)?;
+        let types: Vec<_> = events.iter().map(|event| event.r#type).collect();
+        assert_eq!(types, [VmEventType::CREATED, VmEventType::DIED]);
+        assert_eq!(events[1].detail, "CRASH signal 9");
+        assert!(events[0].timestampMillis <= events[1].timestampMillis);
+
+        assert_eq!(logs.get(&[2; 64], 10010)?, vec![]);
+
+        logs.remove(&[1; 64])?;
+        assert_eq!(logs.get(&[1; 64], 10010)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn logs_are_keyed_by_owner() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let logs = VmEventLogs::new(dir.path().to_owned());
+        logs.record(&[1; 64], 10010, VmEventType::CREATED, "first owner")?;
+        assert_eq!(logs.get(&[1; 64], 10011)?, vec![]);
+
+        // Another app claiming the instance doesn't take over the log of the first one.
+        logs.record(&[1; 64], 10011, VmEventType::CREATED, "second owner")?;
+        assert_eq!(logs.get(&[1; 64], 10010)?[0].detail, "first owner");
+        assert_eq!(logs.get(&[1; 64], 10011)?[0].detail, "second owner");
+
+        logs.remove(&[1; 64])?;
+        assert_eq!(logs.get(&[1; 64], 10010)?, vec![]);
+        assert_eq!(logs.get(&[1; 64], 10011)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn instance_ids_are_not_stored() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let logs = VmEventLogs::new(dir.path().to_owned());
+        logs.record(&[0xab; 64], 10010, VmEventType::CREATED, "")?;
+
+        let path = log_path(dir.path(), 10010, &[0xab; 64]);
+        assert!(path.exists());
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert_eq!(name.len(), 64);
+        assert!(!name.contains("abab"));
+        Ok(())
+    }
+
+    #[test]
+    fn zero_instance_id_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let logs = VmEventLogs::new(dir.path().to_owned());
+        assert!(logs.record(&[0; 64], 10010, VmEventType::CREATED, "").is_err());
+        assert!(logs.get(&[0; 64], 10010).is_err());
+    }
+
+    #[test]
+    fn old_events_are_dropped() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let logs = VmEventLogs::new(dir.path().to_owned());
+        for i in 0..MAX_EVENTS + 2 {
+            logs.record(&[1; 64], 10010, VmEventType::STARTED, &i.to_string())?;
+        }
+
+        let events = logs.get(&[1; 64], 10010)?;
+        assert_eq!(events.len(), MAX_EVENTS);
+        assert_eq!(events[0].detail, "2");
+        Ok(())
+    }
+
+    #[test]
+    fn logs_per_owner_are_capped() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let logs = VmEventLogs::new(dir.path().to_owned());
+        logs.record(&[1; 64], 10011, VmEventType::CREATED, "other owner")?;
+        for i in 1..=MAX_LOGS_PER_UID + 1 {
+            logs.record(&[i as u8; 64], 10010, VmEventType::CREATED, "")?;
+        }
+
+        assert_eq!(list_logs(&dir.path().join("10010")).len(), MAX_LOGS_PER_UID);
+        assert_eq!(logs.get(&[1; 64], 10011)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn least_recently_updated_logs_are_evicted() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        for i in 0..3 {
+            fs::write(dir.path().join(i.to_string()), "")?;
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        evict_logs(list_logs(dir.path()), 2);
+
+        assert!(!dir.path().join("0").exists());
+        assert!(dir.path().join("1").exists() && dir.path().join("2").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,404 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The VM instances allocated by the service and their owners, so that the state kept for the
+//! instances of an app can be enumerated and reclaimed, e.g. when the app is uninstalled.
+//!
+//! Instance IDs are namespaced per Android user: their first 4 bytes are the user ID of the app
+//! they were allocated to, big-endian, and the rest is random.
+//!
+//! Only the owner of an instance may claim it, unless the owner lets another app claim it by
+//! handing over a transfer token, e.g. in the descriptor of an exported VM.
+
+use crate::cid_reservation::InstanceId;
+use anyhow::{bail, Context, Result};
+use log::{error, info};
+use openssl::memcmp;
+use rand::Fill;
+use rustutils::users::multiuser_get_user_id;
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::raw::uid_t;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of instances recorded for each owner, as for the IDs tracked for Secretkeeper. The
+/// oldest instances are forgotten first.
+const MAX_INSTANCES_PER_OWNER: usize = 400;
+
+/// Size of the user ID at the start of instance IDs.
+const USER_ID_SIZE: usize = 4;
+
+/// A secret which lets another app claim an instance once.
+pub type TransferToken = [u8; 32];
+
+/// Returns the Android user ID in whose namespace the instance ID is. Instance IDs allocated before
+/// they were namespaced are random, so this is meaningless for them.
+pub fn user_id_of(instance_id: &InstanceId) -> u32 {
+    u32::from_be_bytes(instance_id[..USER_ID_SIZE].try_into().unwrap())
+}
+
+/// A VM instance known to the registry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InstanceRecord {
+    /// The uid of the app which allocated or last claimed the instance.
+    pub owner_uid: uid_t,
+    /// When the instance ID was allocated, in milliseconds since the Unix epoch.
+    pub created_millis: i64,
+    /// The token which lets another app claim the instance, if the owner allowed it.
+    pub transfer_token: Option<TransferToken>,
+}
+
+/// The VM instances, persisted in a file with one `<hex instance ID> <owner uid> <created millis>`
+/// line each, followed by the hex transfer token of the instance if any.
+#[derive(Debug)]
+pub struct InstanceRegistry {
+    path: PathBuf,
+    instances: HashMap<InstanceId, InstanceRecord>,
+}
+
+impl InstanceRegistry {
+    /// Loads the instances persisted in the file at `path`. Malformed entries are dropped.
+    pub fn load(path: PathBuf) -> Self {
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    error!("Failed to read VM instances from {path:?}: {e:?}");
+                }
+                String::new()
+            }
+        };
+        let mut instances = HashMap::new();
+        for line in content.lines() {
+            match parse_instance(line) {
+                Ok((instance_id, record)) => {
+                    instances.insert(instance_id, record);
+                }
+                Err(e) => error!("Ignoring VM instance {line:?}: {e:?}"),
+            }
+        }
+        Self { path, instances }
+    }
+
+    /// Allocates a new instance ID in the namespace of the user of `owner_uid`, owned by it.
+    pub fn allocate(&mut self, owner_uid: uid_t) -> Result<InstanceId> {
+        let mut instance_id = [0u8; 64];
+        instance_id.try_fill(&mut rand::thread_rng()).context("Failed to generate instance ID")?;
+        instance_id[..USER_ID_SIZE]
+            .copy_from_slice(&multiuser_get_user_id(owner_uid).to_be_bytes());
+        self.set_owner(&instance_id, owner_uid)?;
+        Ok(instance_id)
+    }
+
+    /// Returns the instance, if it is known.
+    pub fn get(&self, instance_id: &InstanceId) -> Option<InstanceRecord> {
+        self.instances.get(instance_id).copied()
+    }
+
+    /// Records that the instance is owned by `owner_uid`, e.g. because the app claimed it, which
+    /// revokes its transfer token. The instance is added if it isn't known yet, e.g. if it was
+    /// allocated before the registry.
+    pub fn set_owner(&mut self, instance_id: &InstanceId, owner_uid: uid_t) -> Result<()> {
+        self.set_record(instance_id, owner_uid, None)
+    }
+
+    /// Lets another app claim the instance owned by `owner_uid` with the returned token, which
+    /// replaces any token returned before. The instance is added if it isn't known yet.
+    pub fn allow_transfer(
+        &mut self,
+        instance_id: &InstanceId,
+        owner_uid: uid_t,
+    ) -> Result<TransferToken> {
+        let mut token = TransferToken::default();
+        token.try_fill(&mut rand::thread_rng()).context("Failed to generate transfer token")?;
+        self.set_record(instance_id, owner_uid, Some(token))?;
+        Ok(token)
+    }
+
+    /// Returns whether `token` lets another app claim the instance.
+    pub fn is_transfer_allowed(&self, instance_id: &InstanceId, token: &[u8]) -> bool {
+        self.get(instance_id)
+            .and_then(|record| record.transfer_token)
+            .is_some_and(|expected| token.len() == expected.len() && memcmp::eq(&expected, token))
+    }
+
+    fn set_record(
+        &mut self,
+        instance_id: &InstanceId,
+        owner_uid: uid_t,
+        transfer_token: Option<TransferToken>,
+    ) -> Result<()> {
+        let previous = self.instances.get(instance_id).copied();
+        let created_millis = previous.map_or_else(now_millis, |record| record.created_millis);
+        let record = InstanceRecord { owner_uid, created_millis, transfer_token };
+        self.instances.insert(*instance_id, record);
+        let evicted = self.evict_oldest(owner_uid);
+        if let Err(e) = self.save() {
+            self.instances.extend(evicted);
+            match previous {
+                Some(record) => self.instances.insert(*instance_id, record),
+                None => self.instances.remove(instance_id),
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Returns the instances owned by `owner_uid`, oldest first.
+    pub fn list(&self, owner_uid: uid_t) -> Vec<(InstanceId, InstanceRecord)> {
+        let mut instances: Vec<_> = self
+            .instances
+            .iter()
+            .filter(|(_, record)| record.owner_uid == owner_uid)
+            .map(|(instance_id, record)| (*instance_id, *record))
+            .collect();
+        instances.sort_by_key(|(instance_id, record)| (record.created_millis, *instance_id));
+        instances
+    }
+
+    /// Forgets the instance, if it is known.
+    pub fn remove(&mut self, instance_id: &InstanceId) -> Result<()> {
+        let Some(record) = self.instances.remove(instance_id) else {
+            return Ok(());
+        };
+        if let Err(e) = self.save() {
+            self.instances.insert(*instance_id, record);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Forgets the instances whose owner matches `predicate`, e.g. those of an uninstalled app,
+    /// and returns their IDs.
+    pub fn remove_owned_by(
+        &mut self,
+        predicate: impl Fn(uid_t) -> bool,
+    ) -> Result<Vec<InstanceId>> {
+        let removed: HashMap<_, _> = self
+            .instances
+            .iter()
+            .filter(|(_, record)| predicate(record.owner_uid))
+            .map(|(instance_id, record)| (*instance_id, *record))
+            .collect();
+        if removed.is_empty() {
+            return Ok(vec![]);
+        }
+        self.instances.retain(|instance_id, _| !removed.contains_key(instance_id));
+        if let Err(e) = self.save() {
+            self.instances.extend(removed);
+            return Err(e);
+        }
+        Ok(removed.into_keys().collect())
+    }
+
+    /// Forgets the oldest instances of `owner_uid` beyond the limit, and returns them.
+    fn evict_oldest(&mut self, owner_uid: uid_t) -> Vec<(InstanceId, InstanceRecord)> {
+        let instances = self.list(owner_uid);
+        let excess = instances.len().saturating_sub(MAX_INSTANCES_PER_OWNER);
+        let evicted = instances[..excess].to_vec();
+        for (instance_id, _) in &evicted {
+            info!(
+                "Forgetting VM instance {}... of uid {owner_uid}",
+                &hex::encode(instance_id)[..8]
+            );
+            self.instances.remove(instance_id);
+        }
+        evicted
+    }
+
+    fn save(&self) -> Result<()> {
+        let content: String = self
+            .instances
+            .iter()
+            .map(|(instance_id, record)| {
+                let transfer_token =
+                    record.transfer_token.map(|token| format!(" {}", hex::encode(token)));
+                format!(
+                    "{} {} {}{}\n",
+                    hex::encode(instance_id),
+                    record.owner_uid,
+                    record.created_millis,
+                    transfer_token.unwrap_or_default()
+                )
+            })
+            .collect();
+        // Write a new file and rename it, so that the instances can't be lost to a crash.
+        let temp_path = self.path.with_extension("tmp");
+        fs::write(&temp_path, content).with_context(|| format!("Failed to write {temp_path:?}"))?;
+        fs::rename(&temp_path, &self.path)
+            .with_context(|| format!("Failed to rename {temp_path:?} to {:?}", self.path))
+    }
+}
+
+fn now_millis() -> i64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_millis().try_into().unwrap_or(i64::MAX)
+}
+
+fn parse_instance(line: &str) -> Result<(InstanceId, InstanceRecord)> {
+    let (instance_id, owner_uid, created_millis, transfer_token) = match line
+        .split(' ')
+        .collect::<Vec<_>>()[..]
+    {
+        [instance_id, owner_uid, created_millis] => (instance_id, owner_uid, created_millis, None),
+        [instance_id, owner_uid, created_millis, token] => {
+            (instance_id, owner_uid, created_millis, Some(token))
+        }
+        _ => bail!("Expected 3 or 4 fields"),
+    };
+    let mut id = [0u8; 64];
+    hex::decode_to_slice(instance_id, &mut id).context("Invalid instance ID")?;
+    let owner_uid = owner_uid.parse().context("Invalid owner uid")?;
+    let created_millis = created_millis.parse().context("Invalid creation time")?;
+    let transfer_token = transfer_token
+        .map(|token| {
+            let mut decoded = TransferToken::default();
+            hex::decode_to_slice(token, &mut decoded).map(|()| decoded)
+        })
+        .transpose()
+        .context("Invalid transfer token")?;
+    Ok((id, InstanceRecord { owner_uid, created_millis, transfer_token }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APP_UID: uid_t = 10010;
+    const OTHER_USER_APP_UID: uid_t = 1010010;
+
+    #[test]
+    fn instances_are_namespaced_and_persisted() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("vm_instances");
+        let mut registry = InstanceRegistry::load(path.clone());
+
+        let id_a = registry.allocate(APP_UID)?;
+        let id_b = registry.allocate(OTHER_USER_APP_UID)?;
+        assert_eq!(id_a[..4], [0, 0, 0, 0]);
+        assert_eq!(id_b[..4], [0, 0, 0, 10]);
+        assert_eq!(0, user_id_of(&id_a));
+        assert_eq!(10, user_id_of(&id_b));
+
+        let reloaded = InstanceRegistry::load(path);
+        assert_eq!(APP_UID, reloaded.get(&id_a).unwrap().owner_uid);
+        assert_eq!(OTHER_USER_APP_UID, reloaded.get(&id_b).unwrap().owner_uid);
+        assert_eq!(
+            vec![id_a],
+            reloaded.list(APP_UID).iter().map(|(id, _)| *id).collect::<Vec<_>>()
+        );
+        assert_eq!(None, reloaded.get(&[3; 64]));
+        Ok(())
+    }
+
+    #[test]
+    fn claimed_instances_change_owner() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut registry = InstanceRegistry::load(dir.path().join("vm_instances"));
+
+        let id = registry.allocate(APP_UID)?;
+        let created_millis = registry.get(&id).unwrap().created_millis;
+        registry.set_owner(&id, APP_UID + 1)?;
+        assert_eq!(
+            InstanceRecord { owner_uid: APP_UID + 1, created_millis, transfer_token: None },
+            registry.get(&id).unwrap()
+        );
+        assert!(registry.list(APP_UID).is_empty());
+
+        registry.set_owner(&[3; 64], APP_UID)?;
+        assert_eq!(1, registry.list(APP_UID).len());
+        Ok(())
+    }
+
+    #[test]
+    fn transfer_tokens_are_persisted_and_revoked_by_claims() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("vm_instances");
+        let mut registry = InstanceRegistry::load(path.clone());
+
+        let id = registry.allocate(APP_UID)?;
+        assert!(!registry.is_transfer_allowed(&id, &[]));
+        let token = registry.allow_transfer(&id, APP_UID)?;
+        assert!(registry.is_transfer_allowed(&id, &token));
+        assert!(!registry.is_transfer_allowed(&id, &token[1..]));
+        assert!(!registry.is_transfer_allowed(&[3; 64], &token));
+
+        // A new token replaces the previous one.
+        let new_token = registry.allow_transfer(&id, APP_UID)?;
+        assert!(!registry.is_transfer_allowed(&id, &token));
+
+        let mut reloaded = InstanceRegistry::load(path);
+        assert!(reloaded.is_transfer_allowed(&id, &new_token));
+        assert_eq!(APP_UID, reloaded.get(&id).unwrap().owner_uid);
+
+        reloaded.set_owner(&id, APP_UID + 1)?;
+        assert!(!reloaded.is_transfer_allowed(&id, &new_token));
+        Ok(())
+    }
+
+    #[test]
+    fn instances_are_removed() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("vm_instances");
+        let mut registry = InstanceRegistry::load(path.clone());
+
+        let id_a = registry.allocate(APP_UID)?;
+        let id_b = registry.allocate(APP_UID)?;
+        let id_c = registry.allocate(OTHER_USER_APP_UID)?;
+        registry.remove(&id_a)?;
+        assert_eq!(vec![id_c], registry.remove_owned_by(|uid| uid == OTHER_USER_APP_UID)?);
+
+        let reloaded = InstanceRegistry::load(path);
+        assert_eq!(None, reloaded.get(&id_a));
+        assert!(reloaded.get(&id_b).is_some());
+        assert_eq!(None, reloaded.get(&id_c));
+        Ok(())
+    }
+
+    #[test]
+    fn oldest_instances_are_evicted() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut registry = InstanceRegistry::load(dir.path().join("vm_instances"));
+
+        let first = registry.allocate(APP_UID)?;
+        registry.instances.get_mut(&first).unwrap().created_millis = 0;
+        for _ in 0..MAX_INSTANCES_PER_OWNER {
+            registry.allocate(APP_UID)?;
+        }
+        assert_eq!(MAX_INSTANCES_PER_OWNER, registry.list(APP_UID).len());
+        assert_eq!(None, registry.get(&first));
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_instances_are_ignored() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("vm_instances");
+        let valid_id = hex::encode([1; 64]);
+        fs::write(
+            &path,
+            format!("{valid_id} 10010 1000\n{valid_id}\nabcd 10011 0\n{valid_id} x 0\n"),
+        )?;
+
+        let registry = InstanceRegistry::load(path);
+        assert_eq!(
+            Some(InstanceRecord { owner_uid: 10010, created_millis: 1000, transfer_token: None }),
+            registry.get(&[1; 64])
+        );
+        assert_eq!(1, registry.instances.len());
+        Ok(())
+    }
+}
@@ -16,9 +16,15 @@
 
 mod aidl;
 mod atom;
+mod cid_reservation;
+mod event_log;
+mod instance_registry;
 mod maintenance;
+mod memory_reservation;
 mod remote_provisioning;
 mod rkpvm;
+mod shutdown;
+mod vhost_user;
 
 use crate::aidl::{
     is_remote_provisioning_hal_declared, remove_temporary_dir, VirtualizationServiceInternal,
@@ -27,6 +33,7 @@ use crate::aidl::{
 use android_logger::{Config, FilterBuilder};
 use android_system_virtualizationmaintenance::aidl::android::system::virtualizationmaintenance;
 use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal;
+use android_system_virtualmachinemanager::aidl::android::system::virtualmachinemanager;
 use anyhow::{bail, Context, Error, Result};
 use binder::{register_lazy_service, BinderFeatures, ProcessState, ThreadState};
 use log::{error, info, LevelFilter};
@@ -35,6 +42,9 @@ use std::os::unix::raw::{pid_t, uid_t};
 use std::path::Path;
 use virtualizationmaintenance::IVirtualizationMaintenance::BnVirtualizationMaintenance;
 use virtualizationservice_internal::IVirtualizationServiceInternal::BnVirtualizationServiceInternal;
+use virtualmachinemanager::IVirtualMachineManager::{
+    BnVirtualMachineManager, SERVICE_NAME as VM_MANAGER_SERVICE_NAME,
+};
 
 const LOG_TAG: &str = "VirtualizationService";
 pub(crate) const REMOTELY_PROVISIONED_COMPONENT_SERVICE_NAME: &str =
@@ -77,13 +87,24 @@ fn try_main() -> Result<()> {
 
     ProcessState::start_thread_pool();
 
-    // One instance of `VirtualizationServiceInternal` implements both the internal interface
-    // and (optionally) the maintenance interface.
+    // One instance of `VirtualizationServiceInternal` implements the internal interface, the VM
+    // manager interface for apps and (optionally) the maintenance interface.
     let service = VirtualizationServiceInternal::init();
-    let internal_service =
-        BnVirtualizationServiceInternal::new_binder(service.clone(), BinderFeatures::default());
+    // The SELinux context of the callers identifies the daemons serving vhost-user backends.
+    let internal_service = BnVirtualizationServiceInternal::new_binder(
+        service.clone(),
+        BinderFeatures { set_requesting_sid: true, ..Default::default() },
+    );
     register(INTERNAL_SERVICE_NAME, internal_service)?;
 
+    let vm_manager_service =
+        BnVirtualMachineManager::new_binder(service.clone(), BinderFeatures::default());
+    // The VM manager interface is optional: without its service_contexts entry (see
+    // docs/platform_sepolicy.md), apps can't manage their VMs but everything else still works.
+    if let Err(e) = register(VM_MANAGER_SERVICE_NAME, vm_manager_service) {
+        error!("{e:?}");
+    }
+
     if is_remote_provisioning_hal_declared().unwrap_or(false) {
         // The IRemotelyProvisionedComponent service is only supposed to be triggered by rkpd for
         // RKP VM attestation.
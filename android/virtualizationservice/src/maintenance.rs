@@ -19,6 +19,7 @@ use android_system_virtualizationmaintenance::aidl::android::system::virtualizat
 use anyhow::{anyhow, Context, Result};
 use binder::Strong;
 use log::{error, info, warn};
+use openssl::sha::Sha512;
 use virtualizationmaintenance::IVirtualizationReconciliationCallback::IVirtualizationReconciliationCallback;
 
 mod vmdb;
@@ -28,13 +29,17 @@ use vmdb::{VmId, VmIdDb};
 const SECRETKEEPER_SERVICE: &str = "android.hardware.security.secretkeeper.ISecretkeeper/default";
 
 /// Directory in which to write persistent state.
-const PERSISTENT_DIRECTORY: &str = "/data/misc/apexdata/com.android.virt";
+pub(crate) const PERSISTENT_DIRECTORY: &str = "/data/misc/apexdata/com.android.virt";
 
 /// Maximum number of VM IDs to delete at once.  Needs to be smaller than both the maximum
 /// number of SQLite parameters (999) and also small enough that an ISecretkeeper::deleteIds
-/// parcel fits within max AIDL message size.
+/// parcel, which holds two secret IDs for each VM ID, fits within max AIDL message size.
 const DELETE_MAX_BATCH_SIZE: usize = 100;
 
+/// Prefix of the data hashed into the ID of the Secretkeeper entry holding the rollback counters
+/// of a VM. Microdroid derives the ID from the VM ID in the same way.
+const ROLLBACK_COUNTERS_ID_PREFIX: &[u8] = b"android.system.virtualization.rollback_counters:";
+
 /// Maximum number of VM IDs that a single app can have.
 const MAX_VM_IDS_PER_APP: usize = 400;
 
@@ -101,6 +106,11 @@ impl State {
         inner.delete_id_for_app(vm_id, user_id, app_id)
     }
 
+    /// Return the `(user_id, app_id)` which the VM ID is associated with, if any.
+    pub fn owner_of_id(&mut self, vm_id: &VmId) -> Result<Option<(i32, i32)>> {
+        self.get_inner()?.vm_id_db.owner_of_vm_id(vm_id)
+    }
+
     /// Perform reconciliation to allow for possibly missed notifications of user or app removal.
     pub fn reconcile(
         &mut self,
@@ -202,7 +212,12 @@ impl InnerState {
     /// - the corresponding limit for number of database parameters
     /// - the corresponding limit for maximum size of a single AIDL message for `ISecretkeeper`.
     fn delete_ids_batch(&mut self, vm_ids: &[VmId]) {
-        let secret_ids: Vec<SecretId> = vm_ids.iter().map(|id| SecretId { id: *id }).collect();
+        // The rollback counters of the VMs go along with their secrets.
+        let secret_ids: Vec<SecretId> = vm_ids
+            .iter()
+            .flat_map(|id| [*id, rollback_counters_id(id)])
+            .map(|id| SecretId { id })
+            .collect();
         if let Err(e) = self.sk.deleteIds(&secret_ids) {
             error!("failed to delete all secrets from Secretkeeper: {e:?}");
         }
@@ -285,6 +300,15 @@ impl InnerState {
 }
 
 /// Indicate whether an app ID belongs to a system core component.
+/// Return the ID of the Secretkeeper entry in which Microdroid keeps the rollback counters of the
+/// VM with ID `vm_id`.
+fn rollback_counters_id(vm_id: &VmId) -> VmId {
+    let mut hasher = Sha512::new();
+    hasher.update(ROLLBACK_COUNTERS_ID_PREFIX);
+    hasher.update(vm_id);
+    hasher.finish()
+}
+
 fn core_app_id(app_id: i32) -> bool {
     app_id < 10000
 }
@@ -346,6 +370,11 @@ mod tests {
         State { inner: Some(inner) }
     }
 
+    /// The operation deleting the secrets and rollback counters of `vm_ids`.
+    fn deleted(vm_ids: &[VmId]) -> SkOp {
+        SkOp::DeleteIds(vm_ids.iter().flat_map(|id| [*id, rollback_counters_id(id)]).collect())
+    }
+
     fn get_db(state: &mut State) -> &mut VmIdDb {
         &mut state.inner.as_mut().unwrap().vm_id_db
     }
@@ -387,11 +416,7 @@ mod tests {
         let got = (*history.lock().unwrap()).clone();
         assert_eq!(
             got,
-            vec![
-                SkOp::DeleteIds(vec![VM_ID1, VM_ID2]),
-                SkOp::DeleteIds(vec![VM_ID3, VM_ID4]),
-                SkOp::DeleteIds(vec![VM_ID5]),
-            ]
+            vec![deleted(&[VM_ID1, VM_ID2]), deleted(&[VM_ID3, VM_ID4]), deleted(&[VM_ID5])]
         );
     }
 
@@ -401,7 +426,18 @@ mod tests {
         let sk_state = new_test_state(history.clone(), 6);
         sk_state.inner.unwrap().delete_ids(&[VM_ID1, VM_ID2, VM_ID3, VM_ID4, VM_ID5]);
         let got = (*history.lock().unwrap()).clone();
-        assert_eq!(got, vec![SkOp::DeleteIds(vec![VM_ID1, VM_ID2, VM_ID3, VM_ID4, VM_ID5])]);
+        assert_eq!(got, vec![deleted(&[VM_ID1, VM_ID2, VM_ID3, VM_ID4, VM_ID5])]);
+    }
+
+    #[test]
+    fn test_rollback_counters_id() {
+        // Microdroid derives the same ID, see guest/microdroid_manager/src/vm_secret.rs.
+        let want = hex::decode(
+            "d23e5c49019a833fb49b49e285b35a1aefcf87bccfbe6fe2db26e1bd65a9c01e\
+             7b4c3ff5aaf34a56bb5fc908f0024a5fa78e000ef746942a5cc1bf98ac811e50",
+        )
+        .unwrap();
+        assert_eq!(rollback_counters_id(&VM_ID1).to_vec(), want);
     }
 
     #[test]
@@ -417,12 +453,12 @@ mod tests {
         assert_eq!((*history.lock().unwrap()).clone(), vec![]);
 
         sk_state.delete_ids_for_app(USER2, APP_B).unwrap();
-        assert_eq!((*history.lock().unwrap()).clone(), vec![SkOp::DeleteIds(vec![VM_ID3])]);
+        assert_eq!((*history.lock().unwrap()).clone(), vec![deleted(&[VM_ID3])]);
 
         sk_state.delete_ids_for_user(USER3).unwrap();
         assert_eq!(
             (*history.lock().unwrap()).clone(),
-            vec![SkOp::DeleteIds(vec![VM_ID3]), SkOp::DeleteIds(vec![VM_ID4, VM_ID5]),]
+            vec![deleted(&[VM_ID3]), deleted(&[VM_ID4, VM_ID5]),]
         );
 
         assert_eq!(vec![VM_ID1, VM_ID2], get_db(&mut sk_state).vm_ids_for_user(USER1).unwrap());
@@ -459,7 +495,7 @@ mod tests {
 
         // This porridge is just right.
         sk_state.delete_id(&VM_ID1, USER1 as u32, APP_A as u32);
-        assert_eq!((*history.lock().unwrap()).clone(), vec![SkOp::DeleteIds(vec![VM_ID1])]);
+        assert_eq!((*history.lock().unwrap()).clone(), vec![deleted(&[VM_ID1])]);
 
         assert_eq!(vec![VM_ID2], get_db(&mut sk_state).vm_ids_for_user(USER1).unwrap());
         assert_eq!(vec![VM_ID3], get_db(&mut sk_state).vm_ids_for_user(USER2).unwrap());
@@ -16,7 +16,7 @@
 
 use anyhow::{anyhow, Context, Result};
 use log::{debug, error, info, warn};
-use rusqlite::{params, params_from_iter, Connection, OpenFlags, Rows};
+use rusqlite::{params, params_from_iter, Connection, OpenFlags, OptionalExtension, Rows};
 use std::path::PathBuf;
 
 /// Subdirectory to hold the database.
@@ -287,6 +287,17 @@ impl VmIdDb {
             .map(|n: usize| n != 0)
     }
 
+    /// Return the `(user_id, app_id)` which the specified VM ID is associated with, if any.
+    pub fn owner_of_vm_id(&mut self, vm_id: &VmId) -> Result<Option<(i32, i32)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT user_id, app_id FROM main.vmids WHERE vm_id = ?;")
+            .context("failed to prepare SELECT stmt")?;
+        stmt.query_row(params![vm_id], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()
+            .context("query failed")
+    }
+
     /// Determine the number of VM IDs associated with `(user_id, app_id)`.
     pub fn count_vm_ids_for_app(&mut self, user_id: i32, app_id: i32) -> Result<usize> {
         let mut stmt = self
@@ -528,6 +539,9 @@ mod tests {
         assert!(!db.is_vm_id_for_app(&VM_ID_UNKNOWN, USER1 as u32, APP_A as u32).unwrap());
         assert!(!db.is_vm_id_for_app(&VM_ID5, USER3 as u32, APP_A as u32).unwrap());
         assert!(db.is_vm_id_for_app(&VM_ID5, USER3 as u32, APP_C as u32).unwrap());
+        assert_eq!(Some((USER1, APP_A)), db.owner_of_vm_id(&VM_ID1).unwrap());
+        assert_eq!(Some((USER3, APP_C)), db.owner_of_vm_id(&VM_ID5).unwrap());
+        assert_eq!(None, db.owner_of_vm_id(&VM_ID_UNKNOWN).unwrap());
 
         db.delete_vm_ids(&[VM_ID2, VM_ID3]).unwrap();
 
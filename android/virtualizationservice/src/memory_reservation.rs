@@ -0,0 +1,467 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reservations of host memory for the VMs which start as soon as there is enough of it.
+//!
+//! The reservations are granted in order of QoS class, then in the order they were made. While
+//! the first one waits, the running VMs of a lower QoS class are asked to give memory back. A
+//! granted reservation keeps its memory aside until it is released, i.e. once its VM started.
+
+use crate::aidl::Cid;
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::VmQosClass::VmQosClass;
+use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::{
+    IMemoryReservation::{BnMemoryReservation, IMemoryReservation},
+    IMemoryReservationCallback::IMemoryReservationCallback,
+    IVmMemoryReclaimer::IVmMemoryReclaimer,
+};
+use anyhow::{Context, Result};
+use binder::{BinderFeatures, Interface, Strong};
+use log::{error, info, warn};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs::read_to_string;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the free memory of the host is checked while reservations wait.
+const MONITOR_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Memory left to the host when granting reservations, so that it doesn't start killing apps.
+const HOST_MEMORY_HEADROOM_MIB: u64 = 512;
+
+/// Number of recent grants which the estimated delays are based on.
+const GRANT_HISTORY_LEN: usize = 8;
+
+/// How long the running VMs are given to give memory back for a reservation, all together.
+const RECLAIM_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Request {
+    id: u64,
+    memory_mib: u64,
+    qos_class: VmQosClass,
+    callback: Strong<dyn IMemoryReservationCallback>,
+}
+
+#[derive(Default)]
+struct Queue {
+    /// Waiting requests, in the order they are granted.
+    waiting: Vec<Request>,
+    /// Memory of the granted reservations which weren't released yet, keyed by their ID.
+    granted: HashMap<u64, u64>,
+    /// When the latest reservations were granted, oldest first.
+    grant_times: VecDeque<Instant>,
+    /// Requests which the running VMs were already asked to give memory back for.
+    reclaimed_for: HashSet<u64>,
+    next_id: u64,
+    monitor_running: bool,
+}
+
+impl Queue {
+    fn position(&self, id: u64) -> Option<usize> {
+        self.waiting.iter().position(|request| request.id == id)
+    }
+
+    /// Returns how long the request at `position` waits, extrapolated from the latest grants.
+    fn estimated_delay(&self, position: usize) -> Option<Duration> {
+        let (first, last) = (self.grant_times.front()?, self.grant_times.back()?);
+        let intervals = u32::try_from(self.grant_times.len() - 1).ok().filter(|&n| n > 0)?;
+        let interval = last.duration_since(*first) / intervals;
+        Some(interval * u32::try_from(position + 1).ok()?)
+    }
+}
+
+/// The reservations of host memory, and the reclaimers of the running VMs.
+#[derive(Clone)]
+pub struct MemoryReservations {
+    queue: Arc<(Mutex<Queue>, Condvar)>,
+    reclaimers: MemoryReclaimers,
+    /// Returns the memory which the host can spare, in MiB.
+    available_mib: fn() -> Result<u64>,
+}
+
+impl Default for MemoryReservations {
+    fn default() -> Self {
+        Self {
+            queue: Default::default(),
+            reclaimers: Default::default(),
+            available_mib: host_available_mib,
+        }
+    }
+}
+
+impl fmt::Debug for MemoryReservations {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let queue = self.queue.0.lock().unwrap();
+        f.debug_struct("MemoryReservations")
+            .field("waiting", &queue.waiting.len())
+            .field("granted", &queue.granted)
+            .field("reclaimers", &self.reclaimers)
+            .finish()
+    }
+}
+
+impl MemoryReservations {
+    pub fn reclaimers(&self) -> &MemoryReclaimers {
+        &self.reclaimers
+    }
+
+    /// Queues a reservation of `memory_mib`, notifying `callback` once it is granted.
+    pub fn reserve(
+        &self,
+        memory_mib: u64,
+        qos_class: VmQosClass,
+        callback: &Strong<dyn IMemoryReservationCallback>,
+    ) -> Strong<dyn IMemoryReservation> {
+        let (queue, changed) = &*self.queue;
+        let mut queue = queue.lock().unwrap();
+        let id = queue.next_id;
+        queue.next_id += 1;
+        // Higher QoS classes first, each in the order of the requests.
+        let position = queue
+            .waiting
+            .iter()
+            .position(|request| request.qos_class < qos_class)
+            .unwrap_or(queue.waiting.len());
+        queue
+            .waiting
+            .insert(position, Request { id, memory_mib, qos_class, callback: callback.clone() });
+        info!(
+            "Reserving {memory_mib} MiB of memory for a {qos_class:?} VM, at position {position}"
+        );
+        if !queue.monitor_running {
+            queue.monitor_running = true;
+            let reservations = self.clone();
+            thread::spawn(move || reservations.monitor());
+        }
+        changed.notify_all();
+        BnMemoryReservation::new_binder(
+            MemoryReservation { id, reservations: self.clone() },
+            BinderFeatures::default(),
+        )
+    }
+
+    /// Cancels the reservation `id` if it waits, or releases its memory if it was granted.
+    fn release(&self, id: u64) {
+        let (queue, changed) = &*self.queue;
+        let mut queue = queue.lock().unwrap();
+        queue.waiting.retain(|request| request.id != id);
+        queue.granted.remove(&id);
+        queue.reclaimed_for.remove(&id);
+        changed.notify_all();
+    }
+
+    /// Grants the waiting reservations as the host memory allows, until there is none left.
+    fn monitor(&self) {
+        let (queue, changed) = &*self.queue;
+        let mut queue = queue.lock().unwrap();
+        loop {
+            let Some(&Request { id, memory_mib, qos_class, .. }) = queue.waiting.first() else {
+                queue.monitor_running = false;
+                return;
+            };
+            let granted_mib: u64 = queue.granted.values().sum();
+            let available_mib = match (self.available_mib)() {
+                Ok(available_mib) => available_mib
+                    .saturating_sub(granted_mib)
+                    .saturating_sub(HOST_MEMORY_HEADROOM_MIB),
+                Err(e) => {
+                    error!("Failed to get the available memory: {e:?}");
+                    0
+                }
+            };
+
+            if memory_mib <= available_mib {
+                let request = queue.waiting.remove(0);
+                queue.granted.insert(request.id, request.memory_mib);
+                queue.reclaimed_for.remove(&request.id);
+                if queue.grant_times.len() == GRANT_HISTORY_LEN {
+                    queue.grant_times.pop_front();
+                }
+                queue.grant_times.push_back(Instant::now());
+                info!(
+                    "Granted {} MiB of memory to a {:?} VM",
+                    request.memory_mib, request.qos_class
+                );
+                // The callback is oneway, so it doesn't block while the queue is locked.
+                if let Err(e) = request.callback.onGranted() {
+                    warn!("Failed to notify a granted memory reservation: {e:?}");
+                }
+                continue;
+            }
+
+            if queue.reclaimed_for.insert(id) {
+                drop(queue);
+                self.reclaimers.reclaim_below(
+                    qos_class,
+                    memory_mib - available_mib,
+                    RECLAIM_TIMEOUT,
+                );
+                queue = self.queue.0.lock().unwrap();
+                continue;
+            }
+            queue = changed.wait_timeout(queue, MONITOR_INTERVAL).unwrap().0;
+        }
+    }
+}
+
+/// Implementation of the AIDL `IMemoryReservation` interface.
+struct MemoryReservation {
+    id: u64,
+    reservations: MemoryReservations,
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.reservations.release(self.id);
+    }
+}
+
+impl Interface for MemoryReservation {}
+
+impl IMemoryReservation for MemoryReservation {
+    fn getQueuePosition(&self) -> binder::Result<i32> {
+        let queue = self.reservations.queue.0.lock().unwrap();
+        Ok(queue.position(self.id).map_or(-1, |position| position.try_into().unwrap_or(i32::MAX)))
+    }
+
+    fn getEstimatedDelayMillis(&self) -> binder::Result<i64> {
+        let queue = self.reservations.queue.0.lock().unwrap();
+        let delay = queue.position(self.id).and_then(|position| queue.estimated_delay(position));
+        Ok(delay.map_or(-1, |delay| delay.as_millis().try_into().unwrap_or(i64::MAX)))
+    }
+}
+
+/// The memory reclaimers of the running VMs, keyed by their CID.
+#[derive(Clone, Default)]
+pub struct MemoryReclaimers(Arc<Mutex<HashMap<Cid, (VmQosClass, Strong<dyn IVmMemoryReclaimer>)>>>);
+
+impl fmt::Debug for MemoryReclaimers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cids: Vec<_> = self.0.lock().unwrap().keys().cloned().collect();
+        f.debug_tuple("MemoryReclaimers").field(&cids).finish()
+    }
+}
+
+impl MemoryReclaimers {
+    pub fn set(&self, cid: Cid, qos_class: VmQosClass, reclaimer: &Strong<dyn IVmMemoryReclaimer>) {
+        self.0.lock().unwrap().insert(cid, (qos_class, reclaimer.clone()));
+    }
+
+    pub fn remove(&self, cid: Cid) {
+        self.0.lock().unwrap().remove(&cid);
+    }
+
+    /// Asks the VMs of a QoS class lower than `qos_class` to give back up to `memory_mib`, the
+    /// least important VMs first. Gives up on the VMs which don't answer within `timeout`, all
+    /// together.
+    fn reclaim_below(&self, qos_class: VmQosClass, memory_mib: u64, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let mut reclaimers: Vec<_> = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, (vm_qos_class, _))| *vm_qos_class < qos_class)
+            .map(|(cid, (vm_qos_class, reclaimer))| (*vm_qos_class, *cid, reclaimer.clone()))
+            .collect();
+        reclaimers.sort_by_key(|(vm_qos_class, cid, _)| (*vm_qos_class, *cid));
+
+        let mut remaining_mib = memory_mib;
+        for (_, cid, reclaimer) in reclaimers {
+            if remaining_mib == 0 {
+                break;
+            }
+            // A VM which hangs must not hold up the queue, so the call is left behind if it takes
+            // too long.
+            let (sender, receiver) = mpsc::channel();
+            let request_mib = remaining_mib.try_into().unwrap_or(i64::MAX);
+            thread::spawn(move || {
+                // The receiver is gone if the call took too long.
+                let _ = sender.send(reclaimer.reclaimMemory(request_mib));
+            });
+            match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(Ok(reclaimed_mib)) => {
+                    info!("VM {cid} gave {reclaimed_mib} MiB of memory back");
+                    remaining_mib = remaining_mib.saturating_sub(reclaimed_mib.max(0) as u64);
+                }
+                Ok(Err(e)) => warn!("Failed to reclaim memory from VM {cid}: {e:?}"),
+                Err(_) => {
+                    warn!("VM {cid} didn't give memory back within {timeout:?}");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Returns the memory which the host can spare without swapping, in MiB.
+fn host_available_mib() -> Result<u64> {
+    let meminfo = read_to_string("/proc/meminfo").context("Failed to read /proc/meminfo")?;
+    let available_kib = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))
+        .and_then(|value| value.trim().strip_suffix("kB"))
+        .context("MemAvailable not found in /proc/meminfo")?
+        .trim()
+        .parse::<u64>()
+        .context("Invalid MemAvailable in /proc/meminfo")?;
+    Ok(available_kib / 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IMemoryReservationCallback::BnMemoryReservationCallback;
+    use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IVmMemoryReclaimer::BnVmMemoryReclaimer;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+
+    static AVAILABLE_MIB: Mutex<u64> = Mutex::new(0);
+
+    fn fake_available_mib() -> Result<u64> {
+        Ok(*AVAILABLE_MIB.lock().unwrap() + HOST_MEMORY_HEADROOM_MIB)
+    }
+
+    struct FakeCallback {
+        name: &'static str,
+        granted: Mutex<Sender<&'static str>>,
+    }
+
+    impl Interface for FakeCallback {}
+
+    impl IMemoryReservationCallback for FakeCallback {
+        fn onGranted(&self) -> binder::Result<()> {
+            let _ = self.granted.lock().unwrap().send(self.name);
+            Ok(())
+        }
+    }
+
+    fn reserve(
+        reservations: &MemoryReservations,
+        name: &'static str,
+        memory_mib: u64,
+        qos_class: VmQosClass,
+        granted: &Sender<&'static str>,
+    ) -> Strong<dyn IMemoryReservation> {
+        let callback = BnMemoryReservationCallback::new_binder(
+            FakeCallback { name, granted: Mutex::new(granted.clone()) },
+            BinderFeatures::default(),
+        );
+        reservations.reserve(memory_mib, qos_class, &callback)
+    }
+
+    /// Gives 100 MiB back after `delay`.
+    struct FakeReclaimer {
+        cid: Cid,
+        delay: Duration,
+        asked: Mutex<Sender<Cid>>,
+    }
+
+    impl Interface for FakeReclaimer {}
+
+    impl IVmMemoryReclaimer for FakeReclaimer {
+        fn reclaimMemory(&self, _memory_mib: i64) -> binder::Result<i64> {
+            let _ = self.asked.lock().unwrap().send(self.cid);
+            thread::sleep(self.delay);
+            Ok(100)
+        }
+    }
+
+    fn set_reclaimer(
+        reclaimers: &MemoryReclaimers,
+        cid: Cid,
+        qos_class: VmQosClass,
+        delay: Duration,
+        asked: &Sender<Cid>,
+    ) {
+        let reclaimer = FakeReclaimer { cid, delay, asked: Mutex::new(asked.clone()) };
+        let reclaimer = BnVmMemoryReclaimer::new_binder(reclaimer, BinderFeatures::default());
+        reclaimers.set(cid, qos_class, &reclaimer);
+    }
+
+    fn next_granted(granted: &Receiver<&'static str>) -> Option<&'static str> {
+        granted.recv_timeout(MONITOR_INTERVAL * 3).ok()
+    }
+
+    #[test]
+    fn grants_by_qos_class_then_in_order() {
+        *AVAILABLE_MIB.lock().unwrap() = 0;
+        let reservations =
+            MemoryReservations { available_mib: fake_available_mib, ..Default::default() };
+        let (sender, granted) = channel();
+        let first = reserve(&reservations, "first", 100, VmQosClass::DEFAULT, &sender);
+        let background = reserve(&reservations, "background", 100, VmQosClass::BACKGROUND, &sender);
+        let second = reserve(&reservations, "second", 100, VmQosClass::DEFAULT, &sender);
+        let foreground = reserve(&reservations, "foreground", 100, VmQosClass::FOREGROUND, &sender);
+        assert_eq!(foreground.getQueuePosition().unwrap(), 0);
+        assert_eq!(first.getQueuePosition().unwrap(), 1);
+        assert_eq!(second.getQueuePosition().unwrap(), 2);
+        assert_eq!(background.getQueuePosition().unwrap(), 3);
+
+        // The granted memory stays aside until it is released.
+        *AVAILABLE_MIB.lock().unwrap() = 250;
+        assert_eq!(next_granted(&granted), Some("foreground"));
+        assert_eq!(next_granted(&granted), Some("first"));
+        assert_eq!(next_granted(&granted), None);
+        assert_eq!(foreground.getQueuePosition().unwrap(), -1);
+
+        drop(foreground);
+        drop(first);
+        assert_eq!(next_granted(&granted), Some("second"));
+        assert_eq!(next_granted(&granted), Some("background"));
+    }
+
+    #[test]
+    fn cancelled_reservation_is_skipped() {
+        let reservations = MemoryReservations {
+            available_mib: || Ok(HOST_MEMORY_HEADROOM_MIB + 100),
+            ..Default::default()
+        };
+        let (sender, granted) = channel();
+        let large = reserve(&reservations, "large", 200, VmQosClass::DEFAULT, &sender);
+        let _small = reserve(&reservations, "small", 100, VmQosClass::DEFAULT, &sender);
+        assert_eq!(next_granted(&granted), None);
+
+        drop(large);
+        assert_eq!(next_granted(&granted), Some("small"));
+    }
+
+    #[test]
+    fn reclaims_from_lower_qos_classes_first() {
+        let reclaimers = MemoryReclaimers::default();
+        let (sender, asked) = channel();
+        set_reclaimer(&reclaimers, 3, VmQosClass::DEFAULT, Duration::ZERO, &sender);
+        set_reclaimer(&reclaimers, 4, VmQosClass::BACKGROUND, Duration::ZERO, &sender);
+        set_reclaimer(&reclaimers, 5, VmQosClass::FOREGROUND, Duration::ZERO, &sender);
+
+        reclaimers.reclaim_below(VmQosClass::FOREGROUND, 150, RECLAIM_TIMEOUT);
+        assert_eq!(asked.try_iter().collect::<Vec<_>>(), vec![4, 3]);
+
+        reclaimers.reclaim_below(VmQosClass::FOREGROUND, 50, RECLAIM_TIMEOUT);
+        assert_eq!(asked.try_iter().collect::<Vec<_>>(), vec![4]);
+    }
+
+    #[test]
+    fn reclaim_gives_up_on_hanging_vm() {
+        let reclaimers = MemoryReclaimers::default();
+        let (sender, asked) = channel();
+        set_reclaimer(&reclaimers, 3, VmQosClass::BACKGROUND, Duration::from_secs(2), &sender);
+        set_reclaimer(&reclaimers, 4, VmQosClass::DEFAULT, Duration::ZERO, &sender);
+
+        let start = Instant::now();
+        reclaimers.reclaim_below(VmQosClass::FOREGROUND, 150, Duration::from_millis(100));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(asked.try_iter().collect::<Vec<_>>(), vec![3]);
+    }
+}
@@ -19,7 +19,8 @@
 use android_hardware_security_rkp::aidl::android::hardware::security::keymint::MacedPublicKey::MacedPublicKey;
 use anyhow::{bail, Context, Result};
 use service_vm_comm::{
-    ClientVmAttestationParams, GenerateCertificateRequestParams, Request, Response,
+    ClientVmAttestationParams, DeriveSealedKeyParams, GenerateCertificateRequestParams, Request,
+    Response, SealedKey,
 };
 use service_vm_manager::process_request;
 
@@ -37,6 +38,14 @@ pub(crate) fn request_attestation(
     }
 }
 
+pub(crate) fn derive_sealed_key(csr: Vec<u8>) -> Result<SealedKey> {
+    let request = Request::DeriveSealedKey(DeriveSealedKeyParams { csr });
+    match process_request(request).context("Failed to process request")? {
+        Response::DeriveSealedKey(sealed_key) => Ok(sealed_key),
+        other => bail!("Incorrect response type {other:?}"),
+    }
+}
+
 pub(crate) fn generate_ecdsa_p256_key_pair() -> Result<Response> {
     let request = Request::GenerateEcdsaP256KeyPair;
     process_request(request).context("Failed to process request")
@@ -0,0 +1,280 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Shutdown of the running VMs before the device shuts down or reboots.
+//!
+//! Otherwise, the VMs are killed along with their virtmgr, possibly in the middle of writing their
+//! encrypted storage. All the VMs are shut down in parallel within a global timeout. Those which
+//! don't make it are killed by their virtmgr, which records a SHUTDOWN_TIMED_OUT event in their
+//! event log.
+//!
+//! Watching for the device shutdown needs the policy listed in docs/platform_sepolicy.md.
+
+use crate::aidl::Cid;
+use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IVmShutdownHandler::IVmShutdownHandler;
+use anyhow::Result;
+use binder::Strong;
+use log::{error, info, warn};
+use rustutils::system_properties::{self, PropertyWatcher};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Set by the framework when it starts shutting down or rebooting the device, including for
+/// thermal shutdowns.
+const SHUTDOWN_REQUESTED_PROPERTY: &str = "sys.shutdown.requested";
+
+/// How long all the VMs may take to shut down cleanly.
+pub const VM_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the handlers may take to return after the timeout, i.e. to kill their VM.
+const HANDLER_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// The shutdown handlers of the running VMs, keyed by their CID.
+#[derive(Clone, Default)]
+pub struct VmShutdownHandlers(Arc<Mutex<HashMap<Cid, Strong<dyn IVmShutdownHandler>>>>);
+
+impl fmt::Debug for VmShutdownHandlers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cids: Vec<_> = self.0.lock().unwrap().keys().cloned().collect();
+        f.debug_tuple("VmShutdownHandlers").field(&cids).finish()
+    }
+}
+
+impl VmShutdownHandlers {
+    pub fn set(&self, cid: Cid, handler: &Strong<dyn IVmShutdownHandler>) {
+        self.0.lock().unwrap().insert(cid, handler.clone());
+    }
+
+    pub fn remove(&self, cid: Cid) {
+        self.0.lock().unwrap().remove(&cid);
+    }
+
+    /// Returns whether the handler of the VM with `cid` is set.
+    pub fn contains(&self, cid: Cid) -> bool {
+        self.0.lock().unwrap().contains_key(&cid)
+    }
+
+    /// Shuts all the VMs down in parallel, each within `timeout`. Returns the CIDs of the VMs
+    /// which didn't shut down cleanly.
+    fn shutdown_all(&self, timeout: Duration) -> BTreeSet<Cid> {
+        self.shutdown_matching(|_| true, timeout)
+    }
+
+    /// Shuts the given VMs down in parallel, each within `timeout`. Returns the CIDs of the VMs
+    /// which didn't shut down cleanly.
+    pub fn shutdown(&self, cids: &BTreeSet<Cid>, timeout: Duration) -> BTreeSet<Cid> {
+        self.shutdown_matching(|cid| cids.contains(&cid), timeout)
+    }
+
+    fn shutdown_matching(&self, filter: impl Fn(Cid) -> bool, timeout: Duration) -> BTreeSet<Cid> {
+        let handlers: Vec<_> = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(cid, _)| filter(**cid))
+            .map(|(cid, handler)| (*cid, handler.clone()))
+            .collect();
+        if handlers.is_empty() {
+            return BTreeSet::new();
+        }
+        info!("Shutting down {} VMs", handlers.len());
+
+        let timeout_millis = timeout.as_millis().try_into().unwrap_or(i64::MAX);
+        let (sender, receiver) = channel();
+        let mut laggards = BTreeSet::new();
+        for (cid, handler) in handlers {
+            let sender = sender.clone();
+            thread::spawn(move || {
+                let _ = sender.send((cid, handler.shutdown(timeout_millis)));
+            });
+            laggards.insert(cid);
+        }
+        // Lets the receiver know once all the handlers returned.
+        drop(sender);
+
+        let deadline = Instant::now() + timeout + HANDLER_GRACE_PERIOD;
+        while let Ok((cid, result)) =
+            receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        {
+            match result {
+                Ok(true) => {
+                    laggards.remove(&cid);
+                }
+                Ok(false) => warn!("VM {cid} didn't shut down within {timeout:?} and was killed"),
+                Err(e) => error!("Failed to shut VM {cid} down: {e:?}"),
+            }
+        }
+        laggards
+    }
+}
+
+/// Shuts all the VMs down once the device starts shutting down.
+pub fn shutdown_vms_on_device_shutdown(handlers: VmShutdownHandlers) {
+    thread::spawn(move || {
+        if let Err(e) = wait_for_device_shutdown() {
+            error!("Failed to watch for device shutdown: {e:?}");
+            return;
+        }
+        let laggards = handlers.shutdown_all(VM_SHUTDOWN_TIMEOUT);
+        if laggards.is_empty() {
+            info!("All VMs shut down cleanly");
+        } else {
+            warn!("VMs {laggards:?} didn't shut down cleanly before the device");
+        }
+    });
+}
+
+fn wait_for_device_shutdown() -> Result<()> {
+    let mut prop = PropertyWatcher::new(SHUTDOWN_REQUESTED_PROPERTY)?;
+    loop {
+        if system_properties::read(SHUTDOWN_REQUESTED_PROPERTY)?.is_some_and(|v| !v.is_empty()) {
+            info!("Device is shutting down");
+            return Ok(());
+        }
+        prop.wait(None)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IVmShutdownHandler::BnVmShutdownHandler;
+    use binder::{BinderFeatures, Interface};
+    use std::sync::Condvar;
+
+    /// What the fake handlers were asked to do, and what they did.
+    #[derive(Default)]
+    struct Calls {
+        /// The CIDs of the handlers which were called, with the timeout they got.
+        started: Mutex<Vec<(Cid, i64)>>,
+        started_changed: Condvar,
+        /// The CIDs of the handlers which returned.
+        finished: Mutex<BTreeSet<Cid>>,
+    }
+
+    struct FakeHandler {
+        cid: Cid,
+        /// How many handlers must be running at once for this one to shut its VM down cleanly.
+        concurrent: usize,
+        delay: Duration,
+        clean: bool,
+        calls: Arc<Calls>,
+    }
+
+    impl Interface for FakeHandler {}
+
+    impl IVmShutdownHandler for FakeHandler {
+        fn shutdown(&self, timeout_millis: i64) -> binder::Result<bool> {
+            self.calls.started.lock().unwrap().push((self.cid, timeout_millis));
+            self.calls.started_changed.notify_all();
+            let (started, _) = self
+                .calls
+                .started_changed
+                .wait_timeout_while(
+                    self.calls.started.lock().unwrap(),
+                    Duration::from_secs(2),
+                    |started| started.len() < self.concurrent,
+                )
+                .unwrap();
+            let concurrent = started.len() >= self.concurrent;
+            drop(started);
+            thread::sleep(self.delay);
+            self.calls.finished.lock().unwrap().insert(self.cid);
+            Ok(self.clean && concurrent)
+        }
+    }
+
+    fn add_handler(
+        handlers: &VmShutdownHandlers,
+        calls: &Arc<Calls>,
+        cid: Cid,
+        delay: Duration,
+        clean: bool,
+    ) {
+        let handler = FakeHandler { cid, concurrent: 0, delay, clean, calls: calls.clone() };
+        handlers.set(cid, &BnVmShutdownHandler::new_binder(handler, BinderFeatures::default()));
+    }
+
+    fn started_cids(calls: &Calls) -> BTreeSet<Cid> {
+        calls.started.lock().unwrap().iter().map(|(cid, _)| *cid).collect()
+    }
+
+    #[test]
+    fn shuts_down_all_vms_in_parallel() {
+        let handlers = VmShutdownHandlers::default();
+        let calls = Arc::new(Calls::default());
+        let cids = BTreeSet::from([2048, 2049, 2050, 2051]);
+        for &cid in &cids {
+            // Each handler waits for all the others to be called, which they can't be if the
+            // handlers are called one after the other.
+            let handler = FakeHandler {
+                cid,
+                concurrent: cids.len(),
+                delay: Duration::ZERO,
+                clean: true,
+                calls: calls.clone(),
+            };
+            handlers.set(cid, &BnVmShutdownHandler::new_binder(handler, BinderFeatures::default()));
+        }
+
+        assert_eq!(handlers.shutdown_all(Duration::from_secs(5)), BTreeSet::new());
+        let started = calls.started.lock().unwrap().clone();
+        assert_eq!(started.len(), cids.len());
+        assert!(started.iter().all(|&(cid, timeout)| cids.contains(&cid) && timeout == 5000));
+    }
+
+    #[test]
+    fn shuts_down_given_vms() {
+        let handlers = VmShutdownHandlers::default();
+        let calls = Arc::new(Calls::default());
+        add_handler(&handlers, &calls, 2048, Duration::ZERO, true);
+        add_handler(&handlers, &calls, 2049, Duration::ZERO, true);
+        add_handler(&handlers, &calls, 2050, Duration::ZERO, false);
+
+        let laggards = handlers.shutdown(&BTreeSet::from([2048, 2050]), Duration::from_millis(100));
+        assert_eq!(laggards, BTreeSet::from([2050]));
+        assert_eq!(started_cids(&calls), BTreeSet::from([2048, 2050]));
+    }
+
+    #[test]
+    fn reports_laggards_without_waiting_for_them() {
+        let handlers = VmShutdownHandlers::default();
+        let calls = Arc::new(Calls::default());
+        add_handler(&handlers, &calls, 2048, Duration::ZERO, true);
+        add_handler(&handlers, &calls, 2049, Duration::ZERO, false);
+        add_handler(&handlers, &calls, 2050, Duration::from_secs(3), true);
+
+        let laggards = handlers.shutdown_all(Duration::from_millis(100));
+        assert_eq!(laggards, BTreeSet::from([2049, 2050]));
+        assert_eq!(started_cids(&calls), BTreeSet::from([2048, 2049, 2050]));
+        assert_eq!(*calls.finished.lock().unwrap(), BTreeSet::from([2048, 2049]));
+    }
+
+    #[test]
+    fn removed_handlers_are_not_called() {
+        let handlers = VmShutdownHandlers::default();
+        let calls = Arc::new(Calls::default());
+        add_handler(&handlers, &calls, 2048, Duration::ZERO, true);
+        add_handler(&handlers, &calls, 2049, Duration::ZERO, true);
+        handlers.remove(2049);
+
+        assert_eq!(handlers.shutdown_all(Duration::from_millis(100)), BTreeSet::new());
+        assert_eq!(started_cids(&calls), BTreeSet::from([2048]));
+    }
+}
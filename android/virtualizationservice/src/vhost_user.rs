@@ -0,0 +1,116 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Registry of the vhost-user backends which host daemons emulate devices of VMs with.
+//!
+//! A daemon registers a backend under a name, along with the owners whose VMs may use it. The VMs
+//! requesting the backend in their config are connected to it by their virtmgr, which passes the
+//! connection to crosvm. New device types thus don't need any support in virtmgr beyond the
+//! crosvm argument.
+//!
+//! The daemons need the policy listed in docs/platform_sepolicy.md.
+
+use crate::aidl::Cid;
+use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::{
+    IVhostUserBackend::IVhostUserBackend, VhostUserConnection::VhostUserConnection,
+    VhostUserDeviceType::VhostUserDeviceType,
+};
+use anyhow::{ensure, Context, Result};
+use binder::{IBinder, Strong};
+use log::info;
+use std::collections::HashMap;
+use std::fmt;
+use std::os::unix::raw::uid_t;
+use std::sync::{Arc, Mutex};
+
+struct Registration {
+    /// UID of the daemon which registered the backend.
+    owner_uid: uid_t,
+    device_type: VhostUserDeviceType,
+    backend: Strong<dyn IVhostUserBackend>,
+    /// UIDs of the owners whose VMs may use the backend.
+    allowed_uids: Vec<uid_t>,
+}
+
+/// The registered vhost-user backends, keyed by their name.
+#[derive(Clone, Default)]
+pub struct VhostUserBackends(Arc<Mutex<HashMap<String, Registration>>>);
+
+impl fmt::Debug for VhostUserBackends {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = self.0.lock().unwrap().keys().cloned().collect();
+        f.debug_tuple("VhostUserBackends").field(&names).finish()
+    }
+}
+
+impl VhostUserBackends {
+    /// Registers `backend` under `name`, replacing a backend whose daemon died, e.g. to be
+    /// restarted, as it couldn't unregister it.
+    pub fn register(
+        &self,
+        name: &str,
+        owner_uid: uid_t,
+        device_type: VhostUserDeviceType,
+        backend: &Strong<dyn IVhostUserBackend>,
+        allowed_uids: Vec<uid_t>,
+    ) -> Result<()> {
+        ensure!(!name.is_empty(), "The name of a vhost-user backend can't be empty");
+        ensure!(!allowed_uids.is_empty(), "No owner is allowed to use vhost-user backend {name:?}");
+        let mut backends = self.0.lock().unwrap();
+        if let Some(registration) = backends.get(name) {
+            ensure!(
+                !registration.backend.as_binder().is_binder_alive(),
+                "vhost-user backend {name:?} is already registered by uid {}",
+                registration.owner_uid
+            );
+        }
+        let registration =
+            Registration { owner_uid, device_type, backend: backend.clone(), allowed_uids };
+        backends.insert(name.to_owned(), registration);
+        info!("uid {owner_uid} registered {device_type:?} vhost-user backend {name:?}");
+        Ok(())
+    }
+
+    /// Unregisters the backend which `owner_uid` registered under `name`.
+    pub fn unregister(&self, name: &str, owner_uid: uid_t) -> Result<()> {
+        let mut backends = self.0.lock().unwrap();
+        let registration =
+            backends.get(name).with_context(|| format!("No vhost-user backend {name:?}"))?;
+        ensure!(
+            registration.owner_uid == owner_uid,
+            "vhost-user backend {name:?} isn't registered by uid {owner_uid}"
+        );
+        backends.remove(name);
+        Ok(())
+    }
+
+    /// Connects the VM with `cid`, owned by `uid`, to the backend registered under `name`.
+    pub fn connect(&self, name: &str, cid: Cid, uid: uid_t) -> Result<VhostUserConnection> {
+        let (device_type, backend) = {
+            let backends = self.0.lock().unwrap();
+            let registration =
+                backends.get(name).with_context(|| format!("No vhost-user backend {name:?}"))?;
+            ensure!(
+                registration.allowed_uids.contains(&uid),
+                "uid {uid} isn't allowed to use vhost-user backend {name:?}"
+            );
+            (registration.device_type, registration.backend.clone())
+        };
+        // The daemon isn't called with the registry locked, so that it can't block other VMs.
+        let socket = backend
+            .connect(cid.try_into()?)
+            .with_context(|| format!("Failed to connect to vhost-user backend {name:?}"))?;
+        Ok(VhostUserConnection { deviceType: device_type, socket })
+    }
+}
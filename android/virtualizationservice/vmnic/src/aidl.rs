@@ -14,11 +14,13 @@
 
 //! Implementation of the AIDL interface of Vmnic.
 
+use crate::firewall::{Firewall, Firewalls};
 use anyhow::{anyhow, Context, Result};
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::NetworkFirewall::NetworkFirewall;
 use android_system_virtualizationservice_internal::aidl::android::system::virtualizationservice_internal::IVmnic::IVmnic;
-use binder::{self, Interface, IntoBinderResult, ParcelFileDescriptor};
+use binder::{self, ExceptionCode, Interface, IntoBinderResult, ParcelFileDescriptor};
 use libc::{c_char, c_int, c_short, ifreq, IFF_NO_PI, IFF_TAP, IFF_UP, IFF_VNET_HDR, IFNAMSIZ};
-use log::info;
+use log::{error, info};
 use nix::ioctl_write_ptr_bad;
 use nix::sys::ioctl::ioctl_num_type;
 use nix::sys::socket::{socket, AddressFamily, SockFlag, SockType};
@@ -74,7 +76,9 @@ fn delete_tap_interface(sockfd: c_int, ifr: &mut ifreq) -> Result<()> {
 }
 
 #[derive(Debug, Default)]
-pub struct Vmnic {}
+pub struct Vmnic {
+    firewalls: Firewalls,
+}
 
 impl Vmnic {
     pub fn init() -> Vmnic {
@@ -111,6 +115,12 @@ impl IVmnic for Vmnic {
         create_tap_interface(tunfd.as_raw_fd(), sock.as_raw_fd(), ifname_bytes)
             .context(format!("Failed to create TAP interface: {ifname:#?}"))
             .or_service_specific_exception(-1)?;
+        // The VM may not send traffic beyond the host until it is given a firewall. The interface
+        // goes away with tunfd if this fails.
+        self.firewalls
+            .create(&ifname.to_string_lossy())
+            .context(format!("Failed to set the firewall of TAP interface: {ifname:#?}"))
+            .or_service_specific_exception(-1)?;
 
         info!("Created TAP network interface: {ifname:#?}");
         Ok(ParcelFileDescriptor::new(tunfd))
@@ -124,6 +134,10 @@ impl IVmnic for Vmnic {
         // TAP interface.
         let ifname = unsafe { CStr::from_ptr(tap_ifreq.ifr_name.as_ptr()) };
 
+        if let Err(e) = self.firewalls.remove(&ifname.to_string_lossy()) {
+            error!("Failed to remove the firewall of TAP interface {ifname:#?}: {e:?}");
+        }
+
         let sock = socket(AddressFamily::Inet, SockType::Datagram, SockFlag::empty(), None)
             .context("Failed to create socket")
             .or_service_specific_exception(-1)?;
@@ -134,4 +148,28 @@ impl IVmnic for Vmnic {
         info!("Deleted TAP network interface: {ifname:#?}");
         Ok(())
     }
+
+    fn setTapFirewall(
+        &self,
+        tapfd: &ParcelFileDescriptor,
+        firewall: &NetworkFirewall,
+    ) -> binder::Result<()> {
+        let firewall = Firewall::new(firewall)
+            .context("Invalid network firewall")
+            .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)?;
+        let tap_ifreq = get_tap_ifreq(tapfd.as_raw_fd())
+            .context("Failed to get ifreq of TAP interface")
+            .or_service_specific_exception(-1)?;
+        // SAFETY: tap_ifreq.ifr_name is null-terminated within IFNAMSIZ, validated when creating
+        // TAP interface.
+        let ifname = unsafe { CStr::from_ptr(tap_ifreq.ifr_name.as_ptr()) };
+
+        self.firewalls
+            .apply(&ifname.to_string_lossy(), firewall)
+            .context(format!("Failed to set the firewall of TAP interface: {ifname:#?}"))
+            .or_service_specific_exception(-1)?;
+
+        info!("Set the firewall of TAP network interface: {ifname:#?}");
+        Ok(())
+    }
 }
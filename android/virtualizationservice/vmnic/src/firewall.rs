@@ -0,0 +1,535 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Egress firewall of the TAP network interfaces of VMs, enforced by netd.
+//!
+//! netd owns the packet filtering and the routing of the host, so the destinations are expressed
+//! as routes rather than as rules of its own. Each TAP interface gets a network in netd, whose
+//! routing table is looked up first for the traffic forwarded from the interface. The table has
+//! unreachable default routes, so that all the traffic is dropped from the moment the interface is
+//! created, and a throw route for each allowed destination, for which the lookup falls through to
+//! the routing which the host set up for the VM. Traffic to the host itself, e.g. to the DHCP and
+//! DNS stub of virtmgr, is delivered locally before the table is looked up, so it isn't affected.
+//!
+//! Routes can't match ports, so the destinations which are only allowed for some ports are also
+//! filtered by a chain per interface, which the `oem_fwd` chain that netd reserves for the device
+//! in its FORWARD chain jumps to. The chain drops the traffic to these destinations, except to the
+//! allowed ports, and returns for everything else.
+
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::NetworkFirewall::{
+    EgressRule::EgressRule, NetworkFirewall,
+};
+use anyhow::{ensure, Context, Result};
+use binder::Strong;
+use log::error;
+use netd_aidl_interface::aidl::android::net::{
+    INetd::{INetd, NEXTHOP_THROW, NEXTHOP_UNREACHABLE},
+    NativeNetworkConfig::NativeNetworkConfig,
+    NativeNetworkType::NativeNetworkType,
+    NativePermission::NativePermission,
+    RouteInfoParcel::RouteInfoParcel,
+};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write as _;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+const NETD_SERVICE_NAME: &str = "netd";
+const IPTABLES_RESTORE_BIN: &str = "/system/bin/iptables-restore";
+const IP6TABLES_RESTORE_BIN: &str = "/system/bin/ip6tables-restore";
+
+/// The chain which netd reserves for the device in its FORWARD chain.
+const OEM_FORWARD_CHAIN: &str = "oem_fwd";
+
+/// The range of the IDs of the networks which netd leaves to the OEM, and which the TAP interfaces
+/// are given networks from.
+const NET_IDS: std::ops::RangeInclusive<i32> = 1..=50;
+
+/// The default destinations, which are unreachable unless the firewall allows them.
+const DEFAULT_DESTINATIONS: [&str; 2] = ["0.0.0.0/0", "::/0"];
+
+/// A destination network which a VM may send traffic to, in canonical CIDR notation.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+struct Destination(String);
+
+impl Destination {
+    fn new(raw_rule: &EgressRule) -> Result<Self> {
+        let (address, prefix_len) = raw_rule
+            .destination
+            .split_once('/')
+            .with_context(|| format!("{:?} is not in CIDR notation", raw_rule.destination))?;
+        let address: IpAddr =
+            address.parse().with_context(|| format!("Invalid address {address:?}"))?;
+        let prefix_len: u8 =
+            prefix_len.parse().with_context(|| format!("Invalid prefix length {prefix_len:?}"))?;
+        // The kernel rejects routes to addresses with bits set beyond the prefix.
+        let network = match address {
+            IpAddr::V4(address) => {
+                ensure!(prefix_len <= 32, "Invalid prefix length {prefix_len}");
+                let mask = u32::MAX.checked_shl(32 - u32::from(prefix_len)).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(address) & mask))
+            }
+            IpAddr::V6(address) => {
+                ensure!(prefix_len <= 128, "Invalid prefix length {prefix_len}");
+                let mask = u128::MAX.checked_shl(128 - u32::from(prefix_len)).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(address) & mask))
+            }
+        };
+        Ok(Self(format!("{network}/{prefix_len}")))
+    }
+
+    fn is_ipv4(&self) -> bool {
+        !self.0.contains(':')
+    }
+}
+
+/// A validated firewall of a TAP interface.
+#[derive(Debug, Default)]
+pub struct Firewall {
+    /// The destinations allowed for any port.
+    allowed: BTreeSet<Destination>,
+    /// The destinations only allowed for some TCP and UDP ports.
+    allowed_ports: BTreeMap<Destination, BTreeSet<u16>>,
+}
+
+impl Firewall {
+    pub fn new(raw_firewall: &NetworkFirewall) -> Result<Self> {
+        let mut firewall = Self::default();
+        for raw_rule in &raw_firewall.allowedEgress {
+            let destination = Destination::new(raw_rule)?;
+            match raw_rule.port {
+                0 => {
+                    firewall.allowed.insert(destination);
+                }
+                port => {
+                    let port =
+                        u16::try_from(port).with_context(|| format!("Invalid port {port}"))?;
+                    firewall.allowed_ports.entry(destination).or_default().insert(port);
+                }
+            }
+        }
+        // Allowing any port to a destination supersedes allowing some.
+        firewall.allowed_ports.retain(|destination, _| !firewall.allowed.contains(destination));
+        Ok(firewall)
+    }
+
+    /// Returns the next hop of the route of each destination in the table of the network.
+    fn routes(&self) -> BTreeMap<Destination, &'static str> {
+        let mut routes: BTreeMap<_, _> = DEFAULT_DESTINATIONS
+            .iter()
+            .map(|default| (Destination(default.to_string()), NEXTHOP_UNREACHABLE))
+            .collect();
+        for destination in self.allowed.iter().chain(self.allowed_ports.keys()) {
+            routes.insert(destination.clone(), NEXTHOP_THROW);
+        }
+        routes
+    }
+
+    /// Returns the iptables-restore script replacing the rules of `chain` with the port filter of
+    /// the IPv4 or IPv6 destinations.
+    fn port_filter(&self, chain: &str, ipv4: bool) -> String {
+        // Declaring the chain flushes it, and the table is committed at once, so the traffic is
+        // never left unfiltered while the rules are replaced.
+        let mut script = format!("*filter\n:{chain} - [0:0]\n");
+        let family = |destination: &&Destination| destination.is_ipv4() == ipv4;
+        // The destinations allowed for any port may contain those only allowed for some.
+        for destination in self.allowed.iter().filter(family) {
+            writeln!(script, "-A {chain} -d {} -j RETURN", destination.0).unwrap();
+        }
+        for (destination, ports) in self.allowed_ports.iter().filter(|(d, _)| family(d)) {
+            for port in ports {
+                for protocol in ["tcp", "udp"] {
+                    writeln!(
+                        script,
+                        "-A {chain} -d {} -p {protocol} --dport {port} -j RETURN",
+                        destination.0
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        for destination in self.allowed_ports.keys().filter(family) {
+            writeln!(script, "-A {chain} -d {} -j DROP", destination.0).unwrap();
+        }
+        script.push_str("COMMIT\n");
+        script
+    }
+}
+
+/// A change to the routing table of a network.
+#[derive(Debug, Eq, PartialEq)]
+enum RouteChange {
+    Add(Destination, &'static str),
+    Remove(Destination, &'static str),
+}
+
+/// Returns the changes turning the routes `old` into `new`, in order. Routes are added before
+/// others are removed, so that the traffic which both allow isn't dropped in between. netd can't
+/// add a route to a destination which already has one, so a route whose next hop changes is
+/// removed first. This only happens to the default routes, whose traffic is then routed as if the
+/// destination was allowed, which either the old or the new routes do.
+fn route_changes(
+    old: &BTreeMap<Destination, &'static str>,
+    new: &BTreeMap<Destination, &'static str>,
+) -> Vec<RouteChange> {
+    let mut changes = vec![];
+    for (destination, next_hop) in new {
+        match old.get(destination) {
+            None => changes.push(RouteChange::Add(destination.clone(), next_hop)),
+            Some(old_next_hop) if old_next_hop != next_hop => {
+                changes.push(RouteChange::Remove(destination.clone(), old_next_hop));
+                changes.push(RouteChange::Add(destination.clone(), next_hop));
+            }
+            Some(_) => {}
+        }
+    }
+    for (destination, next_hop) in old {
+        if !new.contains_key(destination) {
+            changes.push(RouteChange::Remove(destination.clone(), next_hop));
+        }
+    }
+    changes
+}
+
+/// The network which netd routes the traffic from a TAP interface with.
+#[derive(Debug)]
+struct TapNetwork {
+    net_id: i32,
+    /// The routes in the table of the network.
+    routes: BTreeMap<Destination, &'static str>,
+}
+
+/// The firewalls of the TAP interfaces which vmnic created.
+#[derive(Debug, Default)]
+pub struct Firewalls {
+    networks: Mutex<HashMap<String, TapNetwork>>,
+}
+
+impl Firewalls {
+    /// Drops all the traffic sent through the new interface `ifname`.
+    pub fn create(&self, ifname: &str) -> Result<()> {
+        let mut networks = self.networks.lock().unwrap();
+        ensure!(!networks.contains_key(ifname), "{ifname} already has a firewall");
+        let net_id = NET_IDS
+            .clone()
+            .find(|net_id| networks.values().all(|network| network.net_id != *net_id))
+            .context("Too many TAP interfaces with a firewall")?;
+        let netd = netd()?;
+        // Only system components may use the network, which nothing but the interface is in.
+        let config = NativeNetworkConfig {
+            netId: net_id,
+            networkType: NativeNetworkType::PHYSICAL,
+            permission: NativePermission::SYSTEM,
+            ..Default::default()
+        };
+        netd.networkCreate(&config).context("Failed to create the network")?;
+        let routes = Firewall::default().routes();
+        if let Err(e) = deny_all(&netd, net_id, ifname, &routes) {
+            if let Err(e) = destroy(&netd, net_id, ifname) {
+                error!("Failed to destroy the network of {ifname}: {e:?}");
+            }
+            return Err(e);
+        }
+        networks.insert(ifname.to_owned(), TapNetwork { net_id, routes });
+        Ok(())
+    }
+
+    /// Drops the traffic sent through the interface `ifname`, except to the destinations allowed
+    /// by `firewall`, replacing the firewall set before.
+    pub fn apply(&self, ifname: &str, firewall: Firewall) -> Result<()> {
+        let mut networks = self.networks.lock().unwrap();
+        let network = networks.get_mut(ifname).with_context(|| format!("Unknown TAP {ifname}"))?;
+        // The ports are filtered before the destinations are routed, so that a destination only
+        // allowed for some ports is never allowed for all of them in between.
+        let chain = chain_name(ifname);
+        for (restore_bin, ipv4) in [(IPTABLES_RESTORE_BIN, true), (IP6TABLES_RESTORE_BIN, false)] {
+            restore(restore_bin, &firewall.port_filter(&chain, ipv4))
+                .context("Failed to filter the ports")?;
+        }
+        let netd = netd()?;
+        for change in route_changes(&network.routes, &firewall.routes()) {
+            match change {
+                RouteChange::Add(destination, next_hop) => {
+                    netd.networkAddRouteParcel(
+                        network.net_id,
+                        &route(ifname, &destination, next_hop),
+                    )
+                    .with_context(|| format!("Failed to route {destination:?}"))?;
+                    network.routes.insert(destination, next_hop);
+                }
+                RouteChange::Remove(destination, next_hop) => {
+                    netd.networkRemoveRouteParcel(
+                        network.net_id,
+                        &route(ifname, &destination, next_hop),
+                    )
+                    .with_context(|| format!("Failed to unroute {destination:?}"))?;
+                    network.routes.remove(&destination);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the firewall of the interface `ifname`, if any, e.g. when the interface is deleted.
+    pub fn remove(&self, ifname: &str) -> Result<()> {
+        let Some(network) = self.networks.lock().unwrap().remove(ifname) else {
+            return Ok(());
+        };
+        destroy(&netd()?, network.net_id, ifname)
+    }
+}
+
+fn netd() -> Result<Strong<dyn INetd>> {
+    binder::wait_for_interface(NETD_SERVICE_NAME).context("Failed to connect to netd")
+}
+
+fn route(ifname: &str, destination: &Destination, next_hop: &str) -> RouteInfoParcel {
+    RouteInfoParcel {
+        destination: destination.0.clone(),
+        ifName: ifname.to_owned(),
+        nextHop: next_hop.to_owned(),
+        mtu: 0,
+    }
+}
+
+/// Adds `ifname` to the network `net_id` with the `routes`, which drop all the traffic, and makes
+/// netd look up its table for the traffic forwarded from the interface.
+fn deny_all(
+    netd: &Strong<dyn INetd>,
+    net_id: i32,
+    ifname: &str,
+    routes: &BTreeMap<Destination, &'static str>,
+) -> Result<()> {
+    netd.networkAddInterface(net_id, ifname).context("Failed to add the interface")?;
+    for (destination, next_hop) in routes {
+        let route = route(ifname, destination, next_hop);
+        netd.networkAddRouteParcel(net_id, &route).context("Failed to add the default route")?;
+    }
+    // The chain of the interface starts empty, so it doesn't filter anything.
+    let chain = chain_name(ifname);
+    let script = format!(
+        "*filter\n:{chain} - [0:0]\n-A {OEM_FORWARD_CHAIN} -i {ifname} -j {chain}\nCOMMIT\n"
+    );
+    for restore_bin in [IPTABLES_RESTORE_BIN, IP6TABLES_RESTORE_BIN] {
+        restore(restore_bin, &script).context("Failed to add the port filter")?;
+    }
+    netd.ipfwdAddInterfaceForward(ifname, ifname).context("Failed to route the interface")?;
+    Ok(())
+}
+
+fn destroy(netd: &Strong<dyn INetd>, net_id: i32, ifname: &str) -> Result<()> {
+    // The forwarding rule is removed first, as without it the traffic of the interface is routed
+    // as if it had no firewall. The network and its routes then go with the interface.
+    let forward = netd
+        .ipfwdRemoveInterfaceForward(ifname, ifname)
+        .context("Failed to remove the forwarding rule");
+    let network = netd.networkDestroy(net_id).context("Failed to destroy the network");
+    let chain = chain_name(ifname);
+    let script = format!(
+        "*filter\n-D {OEM_FORWARD_CHAIN} -i {ifname} -j {chain}\n-F {chain}\n-X {chain}\nCOMMIT\n"
+    );
+    let filter = [IPTABLES_RESTORE_BIN, IP6TABLES_RESTORE_BIN]
+        .into_iter()
+        .map(|restore_bin| restore(restore_bin, &script))
+        .collect::<Result<()>>()
+        .context("Failed to remove the port filter");
+    forward.and(network).and(filter)
+}
+
+/// The name of the chain filtering the ports of the interface. Interface names are shorter than
+/// IFNAMSIZ, so it fits the 28 characters which iptables allows.
+fn chain_name(ifname: &str) -> String {
+    format!("avf_fw_{ifname}")
+}
+
+fn restore(restore_bin: &str, script: &str) -> Result<()> {
+    let mut child = Command::new(restore_bin)
+        .args(["-w", "--noflush"])
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to execute {restore_bin}"))?;
+    child.stdin.take().unwrap().write_all(script.as_bytes())?;
+    let status = child.wait()?;
+    ensure!(status.success(), "{restore_bin} failed with {status}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn firewall(destinations: &[&str]) -> Result<Firewall> {
+        let allowed_egress = destinations
+            .iter()
+            .map(|destination| {
+                let (destination, port) = match destination.rsplit_once(" port ") {
+                    Some((destination, port)) => (destination, port.parse().unwrap()),
+                    None => (*destination, 0),
+                };
+                EgressRule { destination: destination.to_string(), port }
+            })
+            .collect();
+        Firewall::new(&NetworkFirewall { allowedEgress: allowed_egress })
+    }
+
+    fn allowed(firewall: &Firewall) -> Vec<&str> {
+        firewall.allowed.iter().map(|destination| destination.0.as_str()).collect()
+    }
+
+    fn destination(destination: &str) -> Destination {
+        Destination(destination.to_owned())
+    }
+
+    fn routes(firewall: &Firewall) -> Vec<String> {
+        firewall.routes().iter().map(|(d, next_hop)| format!("{} {next_hop}", d.0)).collect()
+    }
+
+    #[test]
+    fn canonicalizes_destinations() -> Result<()> {
+        let firewall = firewall(&["192.0.2.77/24", "2001:db8::1/32", "198.51.100.7/32"])?;
+        assert_eq!(allowed(&firewall), ["192.0.2.0/24", "198.51.100.7/32", "2001:db8::/32"]);
+        Ok(())
+    }
+
+    #[test]
+    fn allows_all_addresses_with_zero_prefix() -> Result<()> {
+        assert_eq!(allowed(&firewall(&["10.1.2.3/0", "::1/0"])?), ["0.0.0.0/0", "::/0"]);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_destinations() {
+        assert!(firewall(&["192.0.2.0"]).is_err());
+        assert!(firewall(&["192.0.2.0/33"]).is_err());
+        assert!(firewall(&["2001:db8::/129"]).is_err());
+        assert!(firewall(&["example.com/24"]).is_err());
+        assert!(firewall(&["192.0.2.0/-1"]).is_err());
+    }
+
+    #[test]
+    fn denies_everything_by_default() -> Result<()> {
+        assert!(firewall(&[])?.allowed.is_empty());
+        assert!(Firewall::default().allowed.is_empty());
+        assert_eq!(routes(&Firewall::default()), ["0.0.0.0/0 unreachable", "::/0 unreachable"]);
+        Ok(())
+    }
+
+    #[test]
+    fn allowing_all_replaces_the_default_route() -> Result<()> {
+        let allow_all = firewall(&["0.0.0.0/0", "::/0"])?;
+        assert_eq!(routes(&allow_all), ["0.0.0.0/0 throw", "::/0 throw"]);
+
+        let changes = route_changes(&Firewall::default().routes(), &allow_all.routes());
+        assert_eq!(
+            changes,
+            [
+                RouteChange::Remove(destination("0.0.0.0/0"), NEXTHOP_UNREACHABLE),
+                RouteChange::Add(destination("0.0.0.0/0"), NEXTHOP_THROW),
+                RouteChange::Remove(destination("::/0"), NEXTHOP_UNREACHABLE),
+                RouteChange::Add(destination("::/0"), NEXTHOP_THROW),
+            ]
+        );
+
+        let changes = route_changes(&allow_all.routes(), &Firewall::default().routes());
+        assert_eq!(
+            changes,
+            [
+                RouteChange::Remove(destination("0.0.0.0/0"), NEXTHOP_THROW),
+                RouteChange::Add(destination("0.0.0.0/0"), NEXTHOP_UNREACHABLE),
+                RouteChange::Remove(destination("::/0"), NEXTHOP_THROW),
+                RouteChange::Add(destination("::/0"), NEXTHOP_UNREACHABLE),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn allows_new_destinations_before_denying_old_ones() -> Result<()> {
+        let old = firewall(&["192.0.2.0/24", "198.51.100.0/24"])?;
+        let new = firewall(&["198.51.100.0/24", "203.0.113.0/24"])?;
+        assert_eq!(
+            route_changes(&old.routes(), &new.routes()),
+            [
+                RouteChange::Add(destination("203.0.113.0/24"), NEXTHOP_THROW),
+                RouteChange::Remove(destination("192.0.2.0/24"), NEXTHOP_THROW),
+            ]
+        );
+        assert!(route_changes(&new.routes(), &new.routes()).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn routes_destinations_only_allowed_for_some_ports() -> Result<()> {
+        let firewall = firewall(&["192.0.2.0/24 port 443", "2001:db8::/32 port 53"])?;
+        assert!(firewall.allowed.is_empty());
+        assert_eq!(
+            routes(&firewall),
+            [
+                "0.0.0.0/0 unreachable",
+                "192.0.2.0/24 throw",
+                "2001:db8::/32 throw",
+                "::/0 unreachable",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn filters_ports_of_each_family() -> Result<()> {
+        let firewall = firewall(&[
+            "192.0.2.0/24 port 443",
+            "192.0.2.0/24 port 80",
+            "198.51.100.0/24",
+            "2001:db8::/32 port 53",
+        ])?;
+        assert_eq!(
+            firewall.port_filter("avf_fw_tap0", true),
+            "*filter\n\
+             :avf_fw_tap0 - [0:0]\n\
+             -A avf_fw_tap0 -d 198.51.100.0/24 -j RETURN\n\
+             -A avf_fw_tap0 -d 192.0.2.0/24 -p tcp --dport 80 -j RETURN\n\
+             -A avf_fw_tap0 -d 192.0.2.0/24 -p udp --dport 80 -j RETURN\n\
+             -A avf_fw_tap0 -d 192.0.2.0/24 -p tcp --dport 443 -j RETURN\n\
+             -A avf_fw_tap0 -d 192.0.2.0/24 -p udp --dport 443 -j RETURN\n\
+             -A avf_fw_tap0 -d 192.0.2.0/24 -j DROP\n\
+             COMMIT\n"
+        );
+        assert_eq!(
+            firewall.port_filter("avf_fw_tap0", false),
+            "*filter\n\
+             :avf_fw_tap0 - [0:0]\n\
+             -A avf_fw_tap0 -d 2001:db8::/32 -p tcp --dport 53 -j RETURN\n\
+             -A avf_fw_tap0 -d 2001:db8::/32 -p udp --dport 53 -j RETURN\n\
+             -A avf_fw_tap0 -d 2001:db8::/32 -j DROP\n\
+             COMMIT\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn any_port_supersedes_some_ports() -> Result<()> {
+        let firewall = firewall(&["192.0.2.0/24 port 443", "192.0.2.0/24"])?;
+        assert_eq!(allowed(&firewall), ["192.0.2.0/24"]);
+        assert!(firewall.allowed_ports.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_ports() {
+        assert!(firewall(&["192.0.2.0/24 port 65536"]).is_err());
+        assert!(firewall(&["192.0.2.0/24 port -1"]).is_err());
+    }
+}
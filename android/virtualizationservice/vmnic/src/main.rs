@@ -15,6 +15,7 @@
 //! Android Vmnic (Virtual Machine Network Interface Creator)
 
 mod aidl;
+mod firewall;
 
 use crate::aidl::Vmnic;
 use android_logger::Config;
@@ -0,0 +1,210 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Command to attach the terminal to the console of a VM.
+
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
+    IVirtualizationService::IVirtualizationService,
+    VirtualMachineDebugInfo::VirtualMachineDebugInfo,
+};
+use anyhow::{bail, Context, Error};
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, IsTerminal, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, RawFd};
+
+/// Ctrl-], which detaches from the console, as in telnet.
+const DETACH_KEY: u8 = 0x1d;
+
+/// Attaches the terminal to the console of the VM with the given CID or name, or of the first VM
+/// with a console if none is given, until the VM exits or the user detaches.
+pub fn command_console(
+    service: &dyn IVirtualizationService,
+    vm: Option<&str>,
+) -> Result<(), Error> {
+    if !io::stdin().is_terminal() {
+        bail!("Stdin must be a terminal (tty). Use 'adb shell -t' to force allocate tty.");
+    }
+    let vms = service.debugListVms().context("Failed to get list of VMs")?;
+    let vm_info = find_vm_with_console(vms, vm)?;
+    let console_name = vm_info.hostConsoleName.unwrap();
+    let console = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&console_name)
+        .with_context(|| format!("Failed to open console {console_name}"))?;
+
+    eprintln!("Attached to the console of VM {}. Press Ctrl-] to detach.", vm_info.cid);
+    {
+        let _console_mode = RawMode::enable(console.as_raw_fd())?;
+        let _stdin_mode = RawMode::enable(io::stdin().as_raw_fd())?;
+        relay(console)?;
+    }
+    eprintln!("\nDetached from the console of VM {}.", vm_info.cid);
+    Ok(())
+}
+
+/// Finds the VM designated by `vm`, which is either a CID or a name, among those with a console.
+fn find_vm_with_console(
+    vms: Vec<VirtualMachineDebugInfo>,
+    vm: Option<&str>,
+) -> Result<VirtualMachineDebugInfo, Error> {
+    let mut vms = vms.into_iter().filter(|vm_info| vm_info.hostConsoleName.is_some());
+    let Some(vm) = vm else {
+        return vms.next().context("No running VM has a console");
+    };
+    let matches: Vec<_> = match vm.parse::<i32>() {
+        Ok(cid) => vms.filter(|vm_info| vm_info.cid == cid).collect(),
+        Err(_) => vms.filter(|vm_info| vm_info.name == vm).collect(),
+    };
+    match matches.len() {
+        0 => bail!("No running VM {vm} with a console"),
+        1 => Ok(matches.into_iter().next().unwrap()),
+        _ => {
+            let cids: Vec<_> = matches.iter().map(|vm_info| vm_info.cid).collect();
+            bail!("Several VMs are named {vm}, use one of their CIDs instead: {cids:?}")
+        }
+    }
+}
+
+/// Copies the console output to stdout and stdin to the console, until the console is closed or
+/// the detach key is pressed.
+fn relay(mut console: File) -> Result<(), Error> {
+    // Stdin is read without buffering, so that no input is left behind when polling it.
+    let mut stdin = File::from(io::stdin().as_fd().try_clone_to_owned()?);
+    let mut stdout = io::stdout().lock();
+    let mut buf = [0u8; 4096];
+    loop {
+        let mut fds = [
+            libc::pollfd { fd: console.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd: stdin.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+        ];
+        // SAFETY: fds is a valid array of pollfd, whose length is passed along.
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e).context("Failed to poll");
+        }
+
+        if fds[0].revents != 0 {
+            let n = match console.read(&mut buf) {
+                Ok(n) => n,
+                // The pty reports EIO once its other end, i.e. the VM, is gone.
+                Err(e) if e.raw_os_error() == Some(libc::EIO) => 0,
+                Err(e) => return Err(e).context("Failed to read from console"),
+            };
+            if n == 0 {
+                eprint!("\r\nConsole closed.");
+                return Ok(());
+            }
+            stdout.write_all(&buf[..n]).context("Failed to write to stdout")?;
+            stdout.flush().context("Failed to write to stdout")?;
+        }
+
+        if fds[1].revents != 0 {
+            let n = stdin.read(&mut buf).context("Failed to read from stdin")?;
+            if n == 0 {
+                return Ok(());
+            }
+            let input = &buf[..n];
+            let (input, detach) = match input.iter().position(|&c| c == DETACH_KEY) {
+                Some(i) => (&input[..i], true),
+                None => (input, false),
+            };
+            console.write_all(input).context("Failed to write to console")?;
+            if detach {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Puts a terminal in raw mode, until dropped.
+struct RawMode {
+    fd: RawFd,
+    original: libc::termios,
+}
+
+impl RawMode {
+    fn enable(fd: RawFd) -> Result<Self, Error> {
+        // SAFETY: All-zero is a valid value for the termios type.
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        // SAFETY: original is a valid termios to write to.
+        if unsafe { libc::tcgetattr(fd, &mut original) } != 0 {
+            return Err(io::Error::last_os_error()).context("Failed to get terminal attributes");
+        }
+        let mut raw = original;
+        // SAFETY: raw is a valid termios.
+        unsafe { libc::cfmakeraw(&mut raw) };
+        // SAFETY: raw is a valid termios, which is only read.
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error()).context("Failed to set terminal attributes");
+        }
+        Ok(Self { fd, original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        // SAFETY: original is a valid termios, which is only read.
+        unsafe { libc::tcsetattr(self.fd, libc::TCSANOW, &self.original) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vm(cid: i32, name: &str, console: Option<&str>) -> VirtualMachineDebugInfo {
+        VirtualMachineDebugInfo {
+            cid,
+            name: name.to_owned(),
+            hostConsoleName: console.map(str::to_owned),
+            ..Default::default()
+        }
+    }
+
+    fn vms() -> Vec<VirtualMachineDebugInfo> {
+        vec![
+            vm(2048, "no_console", None),
+            vm(2049, "microfuchsia", Some("/dev/pts/1")),
+            vm(2050, "test", Some("/dev/pts/2")),
+            vm(2051, "test", Some("/dev/pts/3")),
+        ]
+    }
+
+    #[test]
+    fn find_first_vm_with_console() {
+        assert_eq!(find_vm_with_console(vms(), None).unwrap().cid, 2049);
+        assert!(find_vm_with_console(vec![vm(2048, "", None)], None).is_err());
+    }
+
+    #[test]
+    fn find_vm_by_cid() {
+        assert_eq!(find_vm_with_console(vms(), Some("2050")).unwrap().cid, 2050);
+        assert!(find_vm_with_console(vms(), Some("2048")).is_err());
+        assert!(find_vm_with_console(vms(), Some("3000")).is_err());
+    }
+
+    #[test]
+    fn find_vm_by_name() {
+        assert_eq!(find_vm_with_console(vms(), Some("microfuchsia")).unwrap().cid, 2049);
+        assert!(find_vm_with_console(vms(), Some("no_console")).is_err());
+        assert!(find_vm_with_console(vms(), Some("unknown")).is_err());
+        // Ambiguous names are rejected.
+        assert!(find_vm_with_console(vms(), Some("test")).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Command to copy an APK or image to the working directory of a VM
+
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::IVirtualizationService::IVirtualizationService;
+use anyhow::{Context, Error};
+use apkverify::V4Signature;
+use binder::ParcelFileDescriptor;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use vmclient::{transfer_file, AtomicFile, TransferOptions, TransferProgress};
+
+/// Copies `source` to `destination`, printing the progress, and checks the copy against the
+/// expected SHA-256 digest and idsig, if any, before atomically replacing `destination`.
+pub fn command_copy_file(
+    service: &dyn IVirtualizationService,
+    source: &Path,
+    destination: &Path,
+    sha256: Option<[u8; 32]>,
+    idsig: Option<&Path>,
+) -> Result<(), Error> {
+    let mut source_file =
+        File::open(source).with_context(|| format!("Failed to open {:?}", source))?;
+    let total_size = source_file.metadata()?.len();
+    let expected_root_hash = idsig
+        .map(|idsig| V4Signature::from_idsig_path(idsig))
+        .transpose()
+        .with_context(|| format!("Failed to read idsig {:?}", idsig))?
+        .map(|idsig| idsig.hashing_info.raw_root_hash);
+
+    let mut on_progress = |progress: TransferProgress| print_progress(progress, total_size);
+    let mut verify = |file: &mut File| {
+        let Some(expected_root_hash) = &expected_root_hash else { return Ok(true) };
+        let root_hash = idsig_root_hash(service, file, destination).map_err(io::Error::other)?;
+        Ok(root_hash == *expected_root_hash)
+    };
+    let options = TransferOptions {
+        total_size: Some(total_size),
+        expected_sha256: sha256,
+        on_progress: Some(&mut on_progress),
+        verify: Some(&mut verify),
+    };
+    let result = transfer_file(&mut source_file, destination, options);
+    eprintln!();
+    let digest =
+        result.with_context(|| format!("Failed to copy {:?} to {:?}", source, destination))?;
+    println!("{}", digest.iter().map(|b| format!("{b:02x}")).collect::<String>());
+    Ok(())
+}
+
+fn print_progress(progress: TransferProgress, total_size: u64) {
+    const MIB: u64 = 1024 * 1024;
+    let percent = if total_size == 0 { 100 } else { progress.transferred * 100 / total_size };
+    eprint!("\rCopied {} / {} MiB ({percent}%)", progress.transferred / MIB, total_size / MIB);
+    let _ = io::stderr().flush();
+}
+
+/// Returns the root hash of the idsig which the service generates for `apk`, in a temporary file
+/// next to `destination`.
+fn idsig_root_hash(
+    service: &dyn IVirtualizationService,
+    apk: &File,
+    destination: &Path,
+) -> Result<Box<[u8]>, Error> {
+    let mut idsig_path = destination.as_os_str().to_owned();
+    idsig_path.push(".idsig");
+    // Never committed, so the file is removed when it is dropped.
+    let mut idsig = AtomicFile::create(Path::new(&idsig_path))?;
+    service
+        .createOrUpdateIdsigFile(
+            &ParcelFileDescriptor::new(apk.try_clone()?),
+            &ParcelFileDescriptor::new(idsig.file().try_clone()?),
+        )
+        .context("Failed to create idsig of the copy")?;
+    idsig.file().seek(SeekFrom::Start(0))?;
+    let idsig = V4Signature::from_idsig(idsig.file())?;
+    Ok(idsig.hashing_info.raw_root_hash)
+}
@@ -17,24 +17,84 @@
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::IVirtualizationService::IVirtualizationService;
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::PartitionType::PartitionType;
 use binder::ParcelFileDescriptor;
-use anyhow::{Context, Error};
+use anyhow::{bail, ensure, Context, Error};
 use std::convert::TryInto;
-use std::fs::OpenOptions;
+use std::fs::{remove_file, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
 use std::path::Path;
+use std::process::Command;
 
-/// Initialise an empty partition image of the given size to be used as a writable partition.
+const MKE2FS_BIN: &str = "/system/bin/mke2fs";
+const MAKE_F2FS_BIN: &str = "/system/bin/make_f2fs";
+
+/// Offset of the byte of the encrypted storage header which tells the VM how to create the
+/// filesystem, right after the magic written by virtmgr. Must match encryptedstore.
+const ENCRYPTEDSTORE_FORMAT_OFFSET: u64 = "UNFORMATTED-STORAGE".len() as u64;
+
+/// Asks the VM to create the filesystem of the encrypted storage without initializing its inode
+/// tables and journal. Must match encryptedstore.
+const ENCRYPTEDSTORE_FORMAT_LAZY: u8 = 1;
+
+/// Filesystem which a partition can be formatted with when it is created.
+#[derive(Clone, Copy, Debug)]
+pub enum Filesystem {
+    Ext4,
+    F2fs,
+}
+
+/// Initialise an empty partition image of the given size to be used as a writable partition, and
+/// format it with `filesystem` if given.
+///
+/// Raw partitions are formatted on the host. The encrypted storage is only ever formatted by the
+/// VM, which holds its key, so it is instead set up for the VM to format it quickly on first boot.
 pub fn command_create_partition(
     service: &dyn IVirtualizationService,
     image_path: &Path,
     size: u64,
     partition_type: PartitionType,
+    filesystem: Option<Filesystem>,
 ) -> Result<(), Error> {
+    check_filesystem(partition_type, filesystem)?;
     let image = OpenOptions::new()
         .create_new(true)
         .read(true)
         .write(true)
         .open(image_path)
         .with_context(|| format!("Failed to create {:?}", image_path))?;
+    let result = initialize(service, image, image_path, size, partition_type, filesystem);
+    if result.is_err() {
+        // Don't leave behind a partition which the VM can't use, and which would keep the
+        // command from being run again.
+        if let Err(e) = remove_file(image_path) {
+            eprintln!("Failed to remove {:?}: {}", image_path, e);
+        }
+    }
+    result
+}
+
+fn check_filesystem(
+    partition_type: PartitionType,
+    filesystem: Option<Filesystem>,
+) -> Result<(), Error> {
+    match (partition_type, filesystem) {
+        (_, None) | (PartitionType::RAW, Some(_)) => Ok(()),
+        // The VM mounts the encrypted storage as ext4.
+        (PartitionType::ENCRYPTEDSTORE, Some(Filesystem::Ext4)) => Ok(()),
+        (_, Some(filesystem)) => {
+            bail!("{:?} partitions can't be formatted as {:?}", partition_type, filesystem)
+        }
+    }
+}
+
+fn initialize(
+    service: &dyn IVirtualizationService,
+    image: File,
+    image_path: &Path,
+    size: u64,
+    partition_type: PartitionType,
+    filesystem: Option<Filesystem>,
+) -> Result<(), Error> {
+    let mut header = image.try_clone().context("Failed to duplicate the image fd")?;
     service
         .initializeWritablePartition(
             &ParcelFileDescriptor::new(image),
@@ -45,5 +105,87 @@ pub fn command_create_partition(
             "Failed to initialize partition type: {:?}, size: {}",
             partition_type, size
         ))?;
+    let Some(filesystem) = filesystem else {
+        return Ok(());
+    };
+    if partition_type == PartitionType::ENCRYPTEDSTORE {
+        request_lazy_format(&mut header).context("Failed to write the encrypted storage header")
+    } else {
+        format(image_path, filesystem)
+            .with_context(|| format!("Failed to format {:?} as {:?}", image_path, filesystem))
+    }
+}
+
+/// Makes the VM create the filesystem of the encrypted storage without writing its inode tables
+/// and journal, which take most of the time on large partitions. The kernel of the VM then
+/// initializes the inode tables in the background.
+fn request_lazy_format(header: &mut (impl Write + Seek)) -> Result<(), Error> {
+    header.seek(SeekFrom::Start(ENCRYPTEDSTORE_FORMAT_OFFSET))?;
+    header.write_all(&[ENCRYPTEDSTORE_FORMAT_LAZY])?;
+    header.flush()?;
+    Ok(())
+}
+
+fn format(image_path: &Path, filesystem: Filesystem) -> Result<(), Error> {
+    let mut command = match filesystem {
+        Filesystem::Ext4 => {
+            let mut command = Command::new(MKE2FS_BIN);
+            // The inode tables and journal are initialized now rather than lazily, so that the VM
+            // doesn't spend its first boot doing so in the background.
+            command.args([
+                "-q",
+                "-F",
+                "-t",
+                "ext4",
+                "-b",
+                "4096",
+                "-O",
+                "metadata_csum,extents,64bit",
+                "-E",
+                "lazy_itable_init=0,lazy_journal_init=0,nodiscard",
+            ]);
+            command
+        }
+        Filesystem::F2fs => {
+            let mut command = Command::new(MAKE_F2FS_BIN);
+            command.args(["-f", "-q"]);
+            command
+        }
+    };
+    let status = command
+        .arg(image_path)
+        .status()
+        .with_context(|| format!("Failed to execute {:?}", command.get_program()))?;
+    ensure!(status.success(), "{:?} failed with {}", command.get_program(), status);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn only_raw_partitions_take_any_filesystem() {
+        assert!(check_filesystem(PartitionType::RAW, Some(Filesystem::Ext4)).is_ok());
+        assert!(check_filesystem(PartitionType::RAW, Some(Filesystem::F2fs)).is_ok());
+        assert!(check_filesystem(PartitionType::ENCRYPTEDSTORE, Some(Filesystem::Ext4)).is_ok());
+        assert!(check_filesystem(PartitionType::ENCRYPTEDSTORE, Some(Filesystem::F2fs)).is_err());
+        assert!(
+            check_filesystem(PartitionType::ANDROID_VM_INSTANCE, Some(Filesystem::Ext4)).is_err()
+        );
+        assert!(check_filesystem(PartitionType::ANDROID_VM_INSTANCE, None).is_ok());
+    }
+
+    #[test]
+    fn lazy_format_is_requested_after_the_magic() -> Result<(), Error> {
+        let mut header = Cursor::new(b"UNFORMATTED-STORAGE".to_vec());
+        header.get_mut().resize(32, 0);
+        request_lazy_format(&mut header)?;
+        let header = header.into_inner();
+        assert_eq!(&header[..19], b"UNFORMATTED-STORAGE");
+        assert_eq!(header[19], ENCRYPTEDSTORE_FORMAT_LAZY);
+        assert!(header[20..].iter().all(|b| *b == 0));
+        Ok(())
+    }
+}
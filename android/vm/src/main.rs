@@ -14,28 +14,32 @@
 
 //! Android VM control tool.
 
+mod console;
+mod copy_file;
 mod create_idsig;
 mod create_partition;
+mod record;
 mod run;
 
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
-    CpuTopology::CpuTopology, IVirtualizationService::IVirtualizationService,
-    PartitionType::PartitionType, VirtualMachineAppConfig::DebugLevel::DebugLevel,
+    CpuTopology::CpuTopology, GuestPanicPolicy::GuestPanicPolicy,
+    IVirtualizationService::IVirtualizationService, PartitionType::PartitionType,
+    VirtualMachineAppConfig::DebugLevel::DebugLevel, VmLabel::VmLabel, VmLabelKey::VmLabelKey,
 };
 #[cfg(not(llpvm_changes))]
 use anyhow::anyhow;
-use anyhow::{bail, Context, Error};
+use anyhow::{Context, Error};
 use binder::{ProcessState, Strong};
 use clap::{Args, Parser};
+use console::command_console;
+use copy_file::command_copy_file;
 use create_idsig::command_create_idsig;
-use create_partition::command_create_partition;
+use create_partition::{command_create_partition, Filesystem};
+use record::{command_record, command_replay};
 use run::{command_run, command_run_app, command_run_microdroid};
 use serde::Serialize;
-use std::io::{self, IsTerminal};
 use std::num::NonZeroU16;
-use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
-use std::process::Command;
 
 #[derive(Args, Default)]
 /// Collection of flags that are at VM level and therefore applicable to all subcommands
@@ -53,6 +57,11 @@ pub struct CommonConfig {
     #[arg(short, long)]
     mem: Option<u32>,
 
+    /// Size (in MiB) of the bounce buffer of a protected VM, which bounds the throughput of its
+    /// virtio devices. If unspecified, it is sized from the number of devices.
+    #[arg(long)]
+    swiotlb_mib: Option<u32>,
+
     /// Run VM in protected mode.
     #[arg(short, long)]
     protected: bool,
@@ -72,6 +81,11 @@ pub struct CommonConfig {
     /// Boost uclamp to stablise results for benchmarks.
     #[arg(short, long)]
     boost_uclamp: bool,
+
+    /// Label of the VM, as key=value with key one of test-run, test-name or group, to tell it
+    /// apart in `vm list` and `vm stop`. May be repeated.
+    #[arg(long = "label", value_parser = parse_label)]
+    labels: Vec<VmLabel>,
 }
 
 impl CommonConfig {
@@ -110,6 +124,16 @@ pub struct DebugConfig {
     #[arg(long)]
     gdb: Option<NonZeroU16>,
 
+    /// Whether to make adb available in the VM even if its debug level is "none". Only supported
+    /// on debuggable builds.
+    #[arg(long)]
+    enable_adb: bool,
+
+    /// What happens to the VM when its kernel panics. Supported values: "reboot" (default),
+    /// "notify", "halt" (debuggable VMs only, e.g. to attach gdb), and "restart".
+    #[arg(long, default_value = "reboot", value_parser = parse_panic_policy)]
+    panic_policy: GuestPanicPolicy,
+
     /// Whether to enable earlycon. Only supported for debuggable Linux-based VMs.
     #[cfg(debuggable_vms_improvements)]
     #[arg(long)]
@@ -141,6 +165,10 @@ pub struct MicrodroidConfig {
     #[arg(long)]
     storage_size: Option<u64>,
 
+    /// Size up to which the payload may grow the storage. It can't grow by default.
+    #[arg(long)]
+    storage_max_size: Option<u64>,
+
     /// Path to disk image containing vendor-specific modules.
     #[cfg(vendor_modules)]
     #[arg(long)]
@@ -204,6 +232,11 @@ pub struct RunAppConfig {
     /// Path to VM Payload APK
     apk: PathBuf,
 
+    /// How long the payload may take to be ready, in milliseconds, before the VM is killed and
+    /// its last console output is reported. No deadline by default.
+    #[arg(long)]
+    boot_deadline_ms: Option<u32>,
+
     /// Path to idsig of the APK
     idsig: PathBuf,
 
@@ -233,6 +266,33 @@ pub struct RunAppConfig {
     /// Paths to extra idsig files.
     #[arg(long = "extra-idsig")]
     extra_idsigs: Vec<PathBuf>,
+
+    /// Index of the extra APK holding the payload binary, e.g. a split APK, if it isn't in the
+    /// main APK
+    #[arg(long, requires = "payload_binary_name")]
+    payload_extra_apk: Option<usize>,
+
+    /// Share the system CA certificates of the host with the payload
+    #[arg(long)]
+    share_host_ca_certificates: bool,
+
+    /// Share a directory of the host with the payload, as <path>:<tag>[:rw]. It is mounted at
+    /// /mnt/shared/<tag> in the VM, read-only unless rw is given.
+    #[arg(long = "shared-dir", value_parser = parse_shared_dir)]
+    shared_dirs: Vec<SharedDirArg>,
+
+    /// Let the payload read the host system properties whose names start with this prefix, e.g.
+    /// ro.build.version.
+    #[arg(long = "expose-host-property")]
+    exposed_host_property_prefixes: Vec<String>,
+}
+
+/// A directory to share with the payload, see `--shared-dir`.
+#[derive(Clone, Debug)]
+struct SharedDirArg {
+    path: PathBuf,
+    tag: String,
+    writable: bool,
 }
 
 impl RunAppConfig {
@@ -321,7 +381,21 @@ enum Opt {
         config: RunCustomVmConfig,
     },
     /// List running virtual machines
-    List,
+    List {
+        /// Only list the VMs with this label, as key=value. May be repeated, to list the VMs with
+        /// all the labels.
+        #[arg(long = "label", value_parser = parse_label)]
+        labels: Vec<VmLabel>,
+    },
+    /// List running virtual machines with the memory accounted to their owner and their uptime
+    Ps,
+    /// Shut down the running virtual machines with the given labels
+    Stop {
+        /// Label of the VMs to shut down, as key=value. May be repeated, to shut down the VMs with
+        /// all the labels.
+        #[arg(long = "label", value_parser = parse_label, required = true)]
+        labels: Vec<VmLabel>,
+    },
     /// Print information about virtual machine support
     Info,
     /// Create a new empty partition to be used as a writable partition for a VM
@@ -336,6 +410,13 @@ enum Opt {
         #[arg(short = 't', long = "type", default_value = "raw",
                value_parser = parse_partition_type)]
         partition_type: PartitionType,
+
+        /// Filesystem to format a raw partition with, so that the VM doesn't have to format it on
+        /// first boot. Supported values: "ext4" and "f2fs". The encrypted storage only supports
+        /// "ext4", which the VM still creates, but without initializing the inode tables and
+        /// journal.
+        #[arg(long, value_parser = parse_filesystem)]
+        filesystem: Option<Filesystem>,
     },
     /// Creates or update the idsig file by digesting the input APK file.
     CreateIdsig {
@@ -345,10 +426,43 @@ enum Opt {
         /// Path to idsig of the APK
         path: PathBuf,
     },
-    /// Connect to the serial console of a VM
+    /// Copy an APK or image to the working directory of a VM, checking the copy before atomically
+    /// replacing the destination. Prints the SHA-256 digest of the copy.
+    CopyFile {
+        /// Path to the file to copy
+        source: PathBuf,
+
+        /// Path of the copy
+        destination: PathBuf,
+
+        /// Expected SHA-256 digest of the file, in hex
+        #[arg(long, value_parser = parse_sha256)]
+        sha256: Option<[u8; 32]>,
+
+        /// Path to the idsig of the APK to copy, whose root hash the copy must match
+        #[arg(long)]
+        idsig: Option<PathBuf>,
+    },
+    /// Attach the terminal to the serial console of a VM. Press Ctrl-] to detach.
     Console {
-        /// CID of the VM
-        cid: Option<i32>,
+        /// CID or name of the VM. Defaults to the first VM with a console.
+        vm: Option<String>,
+    },
+    /// Run a virtual machine, and record its config and lifecycle events to a trace which can be
+    /// attached to a bug report. Console input isn't recorded
+    Record {
+        /// Path at which to write the trace
+        trace: PathBuf,
+
+        /// The run, run-app or run-microdroid command to record, with its arguments
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Run a virtual machine again from a trace written by the record command, and check that
+    /// its lifecycle events match the recorded ones
+    Replay {
+        /// Path to the trace
+        trace: PathBuf,
     },
 }
 
@@ -360,6 +474,16 @@ fn parse_debug_level(s: &str) -> Result<DebugLevel, String> {
     }
 }
 
+fn parse_panic_policy(s: &str) -> Result<GuestPanicPolicy, String> {
+    match s {
+        "reboot" => Ok(GuestPanicPolicy::REBOOT),
+        "notify" => Ok(GuestPanicPolicy::NOTIFY),
+        "halt" => Ok(GuestPanicPolicy::HALT),
+        "restart" => Ok(GuestPanicPolicy::RESTART),
+        _ => Err(format!("Invalid panic policy {}", s)),
+    }
+}
+
 fn parse_partition_type(s: &str) -> Result<PartitionType, String> {
     match s {
         "raw" => Ok(PartitionType::RAW),
@@ -368,6 +492,55 @@ fn parse_partition_type(s: &str) -> Result<PartitionType, String> {
     }
 }
 
+fn parse_filesystem(s: &str) -> Result<Filesystem, String> {
+    match s {
+        "ext4" => Ok(Filesystem::Ext4),
+        "f2fs" => Ok(Filesystem::F2fs),
+        _ => Err(format!("Invalid filesystem {}", s)),
+    }
+}
+
+fn parse_label(s: &str) -> Result<VmLabel, String> {
+    let Some((key, value)) = s.split_once('=') else {
+        return Err(format!("Invalid label {}, expected key=value", s));
+    };
+    let key = match key {
+        "test-run" => VmLabelKey::TEST_RUN,
+        "test-name" => VmLabelKey::TEST_NAME,
+        "group" => VmLabelKey::GROUP,
+        _ => {
+            return Err(format!("Invalid label key {}, expected test-run, test-name or group", key))
+        }
+    };
+    Ok(VmLabel { key, value: value.to_owned() })
+}
+
+fn parse_shared_dir(s: &str) -> Result<SharedDirArg, String> {
+    let invalid = || format!("Invalid shared directory {}, expected <path>:<tag>[:rw]", s);
+    let (path, tag) = s.split_once(':').ok_or_else(invalid)?;
+    let (tag, writable) = match tag.split_once(':') {
+        None => (tag, false),
+        Some((tag, "rw")) => (tag, true),
+        Some(_) => return Err(invalid()),
+    };
+    if path.is_empty() || tag.is_empty() {
+        return Err(invalid());
+    }
+    Ok(SharedDirArg { path: path.into(), tag: tag.to_owned(), writable })
+}
+
+fn parse_sha256(s: &str) -> Result<[u8; 32], String> {
+    let invalid = || format!("Invalid SHA-256 digest {}, expected 64 hex digits", s);
+    if s.len() != 64 || !s.is_ascii() {
+        return Err(invalid());
+    }
+    let mut digest = [0; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(digest)
+}
+
 fn parse_cpu_topology(s: &str) -> Result<CpuTopology, String> {
     match s {
         "one_cpu" => Ok(CpuTopology::ONE_CPU),
@@ -401,28 +574,82 @@ fn main() -> Result<(), Error> {
             command_check_feature_enabled(&feature);
             Ok(())
         }
-        Opt::RunApp { config } => command_run_app(config),
-        Opt::RunMicrodroid { config } => command_run_microdroid(config),
-        Opt::Run { config } => command_run(config),
-        Opt::List => command_list(get_service()?.as_ref()),
+        Opt::RunApp { config } => command_run_app(config, None),
+        Opt::RunMicrodroid { config } => command_run_microdroid(config, None),
+        Opt::Run { config } => command_run(config, None),
+        Opt::List { labels } => command_list(get_service()?.as_ref(), &labels),
+        Opt::Ps => command_ps(get_service()?.as_ref()),
+        Opt::Stop { labels } => command_stop(get_service()?.as_ref(), &labels),
         Opt::Info => command_info(),
-        Opt::CreatePartition { path, size, partition_type } => {
-            command_create_partition(get_service()?.as_ref(), &path, size, partition_type)
+        Opt::CreatePartition { path, size, partition_type, filesystem } => {
+            command_create_partition(
+                get_service()?.as_ref(),
+                &path,
+                size,
+                partition_type,
+                filesystem,
+            )
         }
         Opt::CreateIdsig { apk, path } => {
             command_create_idsig(get_service()?.as_ref(), &apk, &path)
         }
-        Opt::Console { cid } => command_console(cid),
+        Opt::CopyFile { source, destination, sha256, idsig } => command_copy_file(
+            get_service()?.as_ref(),
+            &source,
+            &destination,
+            sha256,
+            idsig.as_deref(),
+        ),
+        Opt::Console { vm } => command_console(get_service()?.as_ref(), vm.as_deref()),
+        Opt::Record { trace, command } => command_record(&trace, command),
+        Opt::Replay { trace } => command_replay(&trace),
     }
 }
 
-/// List the VMs currently running.
-fn command_list(service: &dyn IVirtualizationService) -> Result<(), Error> {
-    let vms = service.debugListVms().context("Failed to get list of VMs")?;
+/// List the VMs currently running, with all the given labels.
+fn command_list(service: &dyn IVirtualizationService, labels: &[VmLabel]) -> Result<(), Error> {
+    let vms = service.debugListVmsWithLabels(labels).context("Failed to get list of VMs")?;
     println!("Running VMs: {:#?}", vms);
     Ok(())
 }
 
+/// List the VMs currently running, with the resources they use.
+fn command_ps(service: &dyn IVirtualizationService) -> Result<(), Error> {
+    let mut vms = service.debugListVmResourceUsage().context("Failed to get list of VMs")?;
+    vms.sort_by_key(|vm| vm.cid);
+    println!(
+        "{:>6} {:>6} {:>10} {:>10} {:>9}  NAME",
+        "CID", "UID", "ACCOUNTED", "UPTIME", "PROTECTED"
+    );
+    for vm in vms {
+        let uptime_secs = vm.uptimeMillis / 1000;
+        let uptime =
+            format!("{}:{:02}:{:02}", uptime_secs / 3600, uptime_secs / 60 % 60, uptime_secs % 60);
+        let memory = format!("{} MiB", vm.accountedMemoryMib);
+        println!(
+            "{:>6} {:>6} {:>10} {:>10} {:>9}  {}",
+            vm.cid,
+            vm.ownerUid,
+            memory,
+            uptime,
+            if vm.isProtected { "yes" } else { "no" },
+            vm.name
+        );
+    }
+    Ok(())
+}
+
+/// Shut down the VMs currently running with all the given labels.
+fn command_stop(service: &dyn IVirtualizationService, labels: &[VmLabel]) -> Result<(), Error> {
+    let cids = service.debugShutdownVmsWithLabels(labels).context("Failed to shut down VMs")?;
+    if cids.is_empty() {
+        println!("No running VM has the labels");
+    } else {
+        println!("Shut down VMs with CIDs {:?}", cids);
+    }
+    Ok(())
+}
+
 /// Print information about supported VM types.
 fn command_info() -> Result<(), Error> {
     let non_protected_vm_supported = hypervisor_props::is_vm_supported()?;
@@ -477,21 +704,6 @@ fn command_info() -> Result<(), Error> {
     Ok(())
 }
 
-fn command_console(cid: Option<i32>) -> Result<(), Error> {
-    if !io::stdin().is_terminal() {
-        bail!("Stdin must be a terminal (tty). Use 'adb shell -t' to force allocate tty.");
-    }
-    let mut vms = get_service()?.debugListVms().context("Failed to get list of VMs")?;
-    if let Some(cid) = cid {
-        vms.retain(|vm_info| vm_info.cid == cid);
-    }
-    let host_console_name = vms
-        .into_iter()
-        .find_map(|vm_info| vm_info.hostConsoleName)
-        .context("Failed to get VM with console")?;
-    Err(Command::new("microcom").arg(host_console_name).exec().into())
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -502,4 +714,27 @@ mod tests {
         // Check that the command parsing has been configured in a valid way.
         Opt::command().debug_assert();
     }
+
+    #[test]
+    fn parse_sha256_digest() {
+        let mut expected = [0xab; 32];
+        expected[0] = 0x01;
+        let hex = format!("01{}", "AB".repeat(31));
+        assert_eq!(parse_sha256(&hex), Ok(expected));
+        assert!(parse_sha256(&hex[2..]).is_err());
+        assert!(parse_sha256(&format!("{}zz", &hex[2..])).is_err());
+        assert!(parse_sha256(&format!("{}é", &hex[4..])).is_err());
+    }
+
+    #[test]
+    fn parse_shared_dir_flags() {
+        let shared_dir = parse_shared_dir("/data/local/tmp/dir:files").unwrap();
+        assert_eq!(shared_dir.path, PathBuf::from("/data/local/tmp/dir"));
+        assert_eq!(shared_dir.tag, "files");
+        assert!(!shared_dir.writable);
+        assert!(parse_shared_dir("/data/local/tmp/dir:files:rw").unwrap().writable);
+        assert!(parse_shared_dir("/data/local/tmp/dir:files:ro").is_err());
+        assert!(parse_shared_dir("/data/local/tmp/dir").is_err());
+        assert!(parse_shared_dir(":files").is_err());
+    }
 }
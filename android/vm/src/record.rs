@@ -0,0 +1,307 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Commands to record a VM session to a trace, and to replay it on a device.
+//!
+//! A trace holds the arguments of the `run`, `run-app` or `run-microdroid` command, a description
+//! of the config passed to `createVm`, and the lifecycle events of the VM with their time since
+//! the VM was created. Only what the VM reports is recorded, never the console input, which may
+//! hold secrets typed by the user. The trace holds paths rather than the content of files, and the
+//! instance ID of the VM is left out, so that it can be attached to a bug report.
+
+use crate::run::{command_run, command_run_app, command_run_microdroid};
+use crate::{RunAppConfig, RunCustomVmConfig, RunMicrodroidConfig};
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
+    VirtualMachineConfig::VirtualMachineConfig,
+};
+use anyhow::{bail, Context, Error};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::iter;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// The commands which can be recorded.
+#[derive(Parser)]
+#[command(no_binary_name = true)]
+enum RecordedCommand {
+    /// Run a virtual machine with a config in APK
+    RunApp {
+        #[command(flatten)]
+        config: RunAppConfig,
+    },
+    /// Run a virtual machine with Microdroid inside
+    RunMicrodroid {
+        #[command(flatten)]
+        config: RunMicrodroidConfig,
+    },
+    /// Run a virtual machine
+    Run {
+        #[command(flatten)]
+        config: RunCustomVmConfig,
+    },
+}
+
+impl RecordedCommand {
+    fn run(self, session: Arc<Session>) -> Result<(), Error> {
+        match self {
+            Self::RunApp { config } => command_run_app(config, Some(session)),
+            Self::RunMicrodroid { config } => command_run_microdroid(config, Some(session)),
+            Self::Run { config } => command_run(config, Some(session)),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Trace {
+    /// Arguments of the recorded command, e.g. `["run-microdroid", "--protected"]`.
+    args: Vec<String>,
+    /// Description of the config passed to `createVm`, for information only.
+    config: String,
+    events: Vec<Event>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Event {
+    /// Time since the VM was created.
+    time_ms: u64,
+    #[serde(flatten)]
+    kind: EventKind,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum EventKind {
+    Created { cid: i32 },
+    Started,
+    PayloadStarted,
+    PayloadReady,
+    PayloadFinished { exit_code: i32 },
+    Error { code: String, message: String },
+    Died { reason: String },
+}
+
+impl EventKind {
+    /// Returns whether two lifecycle events match. The CID is allocated anew on each run.
+    fn matches(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Created { .. }, Self::Created { .. }) => true,
+            _ => self == other,
+        }
+    }
+}
+
+/// A VM session which is being recorded or replayed.
+pub struct Session {
+    /// The trace being recorded, or the events which happened so far when replaying.
+    trace: Mutex<Trace>,
+    created: Mutex<Instant>,
+}
+
+impl Session {
+    fn new(args: Vec<String>) -> Self {
+        Self {
+            trace: Mutex::new(Trace { args, ..Default::default() }),
+            created: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn record(&self, kind: EventKind) {
+        let time_ms = self.created.lock().unwrap().elapsed().as_millis() as u64;
+        self.trace.lock().unwrap().events.push(Event { time_ms, kind });
+    }
+
+    /// Records the config of the VM, which is about to be created.
+    pub fn set_config(&self, config: &VirtualMachineConfig) {
+        self.trace.lock().unwrap().config = describe_config(config);
+    }
+
+    /// Records the creation of the VM, which all times are relative to.
+    pub fn on_created(&self, cid: i32) {
+        *self.created.lock().unwrap() = Instant::now();
+        self.record(EventKind::Created { cid });
+    }
+
+    pub fn on_started(&self) {
+        self.record(EventKind::Started);
+    }
+
+    pub fn on_payload_started(&self) {
+        self.record(EventKind::PayloadStarted);
+    }
+
+    pub fn on_payload_ready(&self) {
+        self.record(EventKind::PayloadReady);
+    }
+
+    pub fn on_payload_finished(&self, exit_code: i32) {
+        self.record(EventKind::PayloadFinished { exit_code });
+    }
+
+    pub fn on_error(&self, code: String, message: &str) {
+        self.record(EventKind::Error { code, message: message.to_owned() });
+    }
+
+    pub fn on_died(&self, reason: String) {
+        self.record(EventKind::Died { reason });
+    }
+}
+
+/// Describes the config passed to `createVm`, leaving out the instance ID.
+fn describe_config(config: &VirtualMachineConfig) -> String {
+    redact_instance_ids(&format!("{config:?}"))
+}
+
+/// Replaces the instance IDs in the debug representation of a config.
+fn redact_instance_ids(description: &str) -> String {
+    const INSTANCE_ID: &str = "instanceId: [";
+    let mut redacted = String::with_capacity(description.len());
+    let mut rest = description;
+    while let Some(start) = rest.find(INSTANCE_ID) {
+        redacted.push_str(&rest[..start]);
+        redacted.push_str("instanceId: <redacted>");
+        rest = &rest[start + INSTANCE_ID.len()..];
+        // The ID is an array of integers, so it ends at the first closing bracket.
+        rest = rest.find(']').map_or("", |end| &rest[end + 1..]);
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+/// Runs a VM with the given `run`, `run-app` or `run-microdroid` arguments, and records the
+/// session to `trace_path`.
+pub fn command_record(trace_path: &Path, args: Vec<String>) -> Result<(), Error> {
+    let command = RecordedCommand::try_parse_from(&args)?;
+    // Create the file first, so that a bad path doesn't go unnoticed until the VM is done.
+    let trace_file = File::create(trace_path)
+        .with_context(|| format!("Failed to create trace file {trace_path:?}"))?;
+    let session = Arc::new(Session::new(args));
+    let result = command.run(session.clone());
+
+    let trace = session.trace.lock().unwrap();
+    serde_json::to_writer_pretty(BufWriter::new(trace_file), &*trace)
+        .context("Failed to write trace")?;
+    println!("Recorded {} events to {:?}", trace.events.len(), trace_path);
+    result
+}
+
+/// Runs a VM again with the arguments recorded in the trace at `trace_path`, and checks that the
+/// same lifecycle events happen.
+pub fn command_replay(trace_path: &Path) -> Result<(), Error> {
+    let trace_file = File::open(trace_path)
+        .with_context(|| format!("Failed to open trace file {trace_path:?}"))?;
+    let trace: Trace =
+        serde_json::from_reader(BufReader::new(trace_file)).context("Failed to parse trace")?;
+    let command = RecordedCommand::try_parse_from(&trace.args)?;
+    let session = Arc::new(Session::new(trace.args));
+    command.run(session.clone())?;
+
+    let replayed = session.trace.lock().unwrap();
+    compare_lifecycles(
+        trace.events.iter().map(|e| &e.kind),
+        replayed.events.iter().map(|e| &e.kind),
+    )
+}
+
+/// Checks that the lifecycle events of the replayed session match the recorded ones.
+fn compare_lifecycles<'a>(
+    recorded: impl Iterator<Item = &'a EventKind>,
+    replayed: impl Iterator<Item = &'a EventKind>,
+) -> Result<(), Error> {
+    let mut recorded = recorded.map(Some).chain(iter::repeat(None));
+    let mut replayed = replayed.map(Some).chain(iter::repeat(None));
+    for i in 0.. {
+        match (recorded.next().unwrap(), replayed.next().unwrap()) {
+            (None, None) => break,
+            (Some(expected), Some(actual)) if expected.matches(actual) => {}
+            (expected, actual) => {
+                bail!("Replay diverged at event {i}: recorded {expected:?}, replayed {actual:?}")
+            }
+        }
+    }
+    println!("Replay matched the recording");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_system_virtualizationservice::aidl::android::system::virtualizationservice::VirtualMachineRawConfig::VirtualMachineRawConfig;
+
+    #[test]
+    fn parse_recorded_command() {
+        assert!(RecordedCommand::try_parse_from(["run-microdroid", "--protected"]).is_ok());
+        assert!(RecordedCommand::try_parse_from(["list"]).is_err());
+    }
+
+    #[test]
+    fn trace_round_trip() {
+        let json = r#"{
+            "args": ["run", "vm_config.json"],
+            "config": "RawConfig(..)",
+            "events": [
+                { "time_ms": 0, "event": "created", "cid": 2048 },
+                { "time_ms": 120, "event": "payload_finished", "exit_code": 1 },
+                { "time_ms": 500, "event": "died", "reason": "Shutdown" }
+            ]
+        }"#;
+        let trace: Trace = serde_json::from_str(json).unwrap();
+        assert_eq!(trace.events[1].kind, EventKind::PayloadFinished { exit_code: 1 });
+        let trace: Trace = serde_json::from_str(&serde_json::to_string(&trace).unwrap()).unwrap();
+        assert_eq!(trace.events[2].kind, EventKind::Died { reason: "Shutdown".to_owned() });
+    }
+
+    #[test]
+    fn console_input_is_not_recorded() {
+        let json = r#"{
+            "args": ["run", "vm_config.json"],
+            "config": "RawConfig(..)",
+            "events": [{ "time_ms": 120, "event": "console_input", "data": [108, 115, 10] }]
+        }"#;
+        assert!(serde_json::from_str::<Trace>(json).is_err());
+    }
+
+    #[test]
+    fn instance_ids_are_redacted() {
+        let config = VirtualMachineConfig::RawConfig(VirtualMachineRawConfig {
+            name: "vm".to_owned(),
+            instanceId: [0xab; 64],
+            ..Default::default()
+        });
+        let description = describe_config(&config);
+        assert!(description.contains("instanceId: <redacted>"), "{description}");
+        assert!(!description.contains("171"), "{description}");
+        assert!(description.contains(r#"name: "vm""#), "{description}");
+
+        assert_eq!(
+            "A { instanceId: <redacted>, b: 1 } C { instanceId: <redacted> }",
+            redact_instance_ids("A { instanceId: [1, 2], b: 1 } C { instanceId: [3] }")
+        );
+    }
+
+    #[test]
+    fn lifecycles_are_compared_regardless_of_cid() {
+        let recorded = [EventKind::Created { cid: 2048 }, EventKind::Started];
+        let replayed = [EventKind::Created { cid: 2049 }, EventKind::Started];
+        assert!(compare_lifecycles(recorded.iter(), replayed.iter()).is_ok());
+
+        let replayed = [EventKind::Created { cid: 2049 }];
+        assert!(compare_lifecycles(recorded.iter(), replayed.iter()).is_err());
+        let replayed = [EventKind::Created { cid: 2049 }, EventKind::PayloadReady];
+        assert!(compare_lifecycles(recorded.iter(), replayed.iter()).is_err());
+    }
+}
@@ -15,10 +15,13 @@
 //! Command to run a VM.
 
 use crate::create_partition::command_create_partition;
+use crate::record::Session;
 use crate::{get_service, RunAppConfig, RunCustomVmConfig, RunMicrodroidConfig};
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice::{
+    GuestCrashReason::GuestCrashReason,
     IVirtualizationService::IVirtualizationService,
     PartitionType::PartitionType,
+    SharedDirectory::SharedDirectory,
     VirtualMachineAppConfig::{
         CustomConfig::CustomConfig, DebugLevel::DebugLevel, Payload::Payload,
         VirtualMachineAppConfig,
@@ -26,6 +29,7 @@ use android_system_virtualizationservice::aidl::android::system::virtualizations
     VirtualMachineConfig::VirtualMachineConfig,
     VirtualMachinePayloadConfig::VirtualMachinePayloadConfig,
     VirtualMachineState::VirtualMachineState,
+    VmQosClass::VmQosClass,
 };
 use anyhow::{anyhow, bail, Context, Error};
 use binder::ParcelFileDescriptor;
@@ -35,15 +39,16 @@ use rand::{distributions::Alphanumeric, Rng};
 use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::{Read, Write};
+use std::io::Read;
 use std::os::fd::AsFd;
 use std::path::{Path, PathBuf};
-use vmclient::{ErrorCode, VmInstance};
+use std::sync::Arc;
+use vmclient::{write_file_atomically, AtomicFile, ErrorCode, VmInstance};
 use vmconfig::{get_debug_level, open_parcel_file, VmConfig};
 use zip::ZipArchive;
 
-/// Run a VM from the given APK, idsig, and config.
-pub fn command_run_app(config: RunAppConfig) -> Result<(), Error> {
+/// Run a VM from the given APK, idsig, and config, recording or replaying the session if given.
+pub fn command_run_app(config: RunAppConfig, session: Option<Arc<Session>>) -> Result<(), Error> {
     let service = get_service()?;
     let apk = File::open(&config.apk).context("Failed to open APK file")?;
 
@@ -66,11 +71,13 @@ pub fn command_run_app(config: RunAppConfig) -> Result<(), Error> {
         service.createOrUpdateIdsigFile(&extra_apk_fd, &extra_idsig_fd)?;
     }
 
-    let idsig = File::create(&config.idsig).context("Failed to create idsig file")?;
+    // Don't leave a truncated idsig behind if interrupted.
+    let mut idsig = AtomicFile::create(&config.idsig).context("Failed to create idsig file")?;
 
     let apk_fd = ParcelFileDescriptor::new(apk);
-    let idsig_fd = ParcelFileDescriptor::new(idsig);
+    let idsig_fd = ParcelFileDescriptor::new(idsig.file().try_clone()?);
     service.createOrUpdateIdsigFile(&apk_fd, &idsig_fd)?;
+    idsig.commit().context("Failed to write idsig file")?;
 
     let idsig = File::open(&config.idsig).context("Failed to open idsig file")?;
     let idsig_fd = ParcelFileDescriptor::new(idsig);
@@ -82,6 +89,7 @@ pub fn command_run_app(config: RunAppConfig) -> Result<(), Error> {
             &config.instance,
             INSTANCE_FILE_SIZE,
             PartitionType::ANDROID_VM_INSTANCE,
+            None,
         )?;
     }
 
@@ -94,8 +102,8 @@ pub fn command_run_app(config: RunAppConfig) -> Result<(), Error> {
             id
         } else {
             let id = service.allocateInstanceId().context("Failed to allocate instance_id")?;
-            let mut instance_id_file = File::create(id_file)?;
-            instance_id_file.write_all(&id)?;
+            // A truncated instance_id file would prevent the VM from ever starting again.
+            write_file_atomically(&id_file, &id)?;
             id
         }
     } else {
@@ -110,6 +118,7 @@ pub fn command_run_app(config: RunAppConfig) -> Result<(), Error> {
                 path,
                 config.microdroid.storage_size.unwrap_or(10 * 1024 * 1024),
                 PartitionType::ENCRYPTEDSTORE,
+                None,
             )?;
         }
         Some(open_parcel_file(path, true)?)
@@ -132,14 +141,34 @@ pub fn command_run_app(config: RunAppConfig) -> Result<(), Error> {
         let extra_apk_files: Result<Vec<_>, _> = extra_apks.iter().map(File::open).collect();
         let extra_apk_fds = extra_apk_files?.into_iter().map(ParcelFileDescriptor::new).collect();
 
+        let payload_extra_apk_index = match config.payload_extra_apk {
+            Some(index) => index.try_into().context("Invalid --payload-extra-apk")?,
+            None => -1,
+        };
+
         Payload::PayloadConfig(VirtualMachinePayloadConfig {
             payloadBinaryName: payload_binary_name,
             extraApks: extra_apk_fds,
+            payloadExtraApkIndex: payload_extra_apk_index,
         })
     } else {
         bail!("Either --config-path or --payload-binary-name must be defined")
     };
 
+    let shared_directories = config
+        .shared_dirs
+        .iter()
+        .map(|shared_dir| {
+            let directory = File::open(&shared_dir.path)
+                .with_context(|| format!("Failed to open {:?}", shared_dir.path))?;
+            Ok(SharedDirectory {
+                directory: ParcelFileDescriptor::new(directory),
+                tag: shared_dir.tag.clone(),
+                writable: shared_dir.writable,
+            })
+        })
+        .collect::<Result<_, Error>>()?;
+
     let os_name = if let Some(ver) = config.microdroid.gki() {
         format!("microdroid_gki-{ver}")
     } else {
@@ -150,6 +179,8 @@ pub fn command_run_app(config: RunAppConfig) -> Result<(), Error> {
 
     let mut custom_config = CustomConfig {
         gdbPort: config.debug.gdb.map(u16::from).unwrap_or(0) as i32, // 0 means no gdb
+        // Images supplied by the vendor are isolated from the app VMs.
+        vendorDomain: vendor.is_some(),
         vendorImage: vendor,
         devices: config
             .microdroid
@@ -160,6 +191,7 @@ pub fn command_run_app(config: RunAppConfig) -> Result<(), Error> {
             })
             .collect::<Result<_, _>>()?,
         networkSupported: config.common.network_supported(),
+        enableAdb: config.debug.enable_adb,
         ..Default::default()
     };
 
@@ -178,23 +210,40 @@ pub fn command_run_app(config: RunAppConfig) -> Result<(), Error> {
         }
     }
 
+    let boot_deadline_ms =
+        i32::try_from(config.boot_deadline_ms.unwrap_or(0)).context("Boot deadline is too long")?;
+
     let vm_config = VirtualMachineConfig::AppConfig(VirtualMachineAppConfig {
         name: config.common.name.unwrap_or_else(|| String::from("VmRunApp")),
+        persistentName: None,
         apk: apk_fd.into(),
         idsig: idsig_fd.into(),
         extraIdsigs: extra_idsig_fds,
         instanceImage: open_parcel_file(&config.instance, true /* writable */)?.into(),
         instanceId: instance_id,
         encryptedStorageImage: storage,
+        encryptedStorageSnapshots: None,
+        encryptedStorageMaxBytes: config.microdroid.storage_max_size.unwrap_or(0) as i64,
         payload,
         debugLevel: config.debug.debug,
         protectedVm: config.common.protected,
         memoryMib: config.common.mem.unwrap_or(0) as i32, // 0 means use the VM default
+        maxMemoryMib: 0,                                  // 0 disables memory hotplug
+        swiotlbMib: config.common.swiotlb_mib.unwrap_or(0) as i32,
         cpuTopology: config.common.cpu_topology,
         customConfig: Some(custom_config),
         osName: os_name,
         hugePages: config.common.hugepages,
         boostUclamp: config.common.boost_uclamp,
+        qosClass: VmQosClass::DEFAULT,
+        panicPolicy: config.debug.panic_policy,
+        labels: config.common.labels,
+        bootDeadlineMs: boot_deadline_ms,
+        shareHostCaCertificates: config.share_host_ca_certificates,
+        sharedDirectories: shared_directories,
+        pmemDisks: vec![],
+        hostLocales: vec![],
+        exposedHostPropertyPrefixes: config.exposed_host_property_prefixes,
     });
     run(
         service.as_ref(),
@@ -203,6 +252,7 @@ pub fn command_run_app(config: RunAppConfig) -> Result<(), Error> {
         config.debug.console.as_ref().map(|p| p.as_ref()),
         config.debug.console_in.as_ref().map(|p| p.as_ref()),
         config.debug.log.as_ref().map(|p| p.as_ref()),
+        session,
     )
 }
 
@@ -229,7 +279,10 @@ fn create_work_dir() -> Result<PathBuf, Error> {
 }
 
 /// Run a VM with Microdroid
-pub fn command_run_microdroid(config: RunMicrodroidConfig) -> Result<(), Error> {
+pub fn command_run_microdroid(
+    config: RunMicrodroidConfig,
+    session: Option<Arc<Session>>,
+) -> Result<(), Error> {
     let apk = find_empty_payload_apk_path()?;
     println!("found path {}", apk.display());
 
@@ -255,17 +308,20 @@ pub fn command_run_microdroid(config: RunMicrodroidConfig) -> Result<(), Error>
         println!("instance_id file path: {}", app_config.instance_id()?.display());
     }
 
-    command_run_app(app_config)
+    command_run_app(app_config, session)
 }
 
 /// Run a VM from the given configuration file.
-pub fn command_run(config: RunCustomVmConfig) -> Result<(), Error> {
+pub fn command_run(config: RunCustomVmConfig, session: Option<Arc<Session>>) -> Result<(), Error> {
     let config_file = File::open(&config.config).context("Failed to open config file")?;
     let mut vm_config =
         VmConfig::load(&config_file).context("Failed to parse config file")?.to_parcelable()?;
     if let Some(mem) = config.common.mem {
         vm_config.memoryMib = mem as i32;
     }
+    if let Some(swiotlb_mib) = config.common.swiotlb_mib {
+        vm_config.swiotlbMib = swiotlb_mib as i32;
+    }
     if let Some(name) = config.common.name {
         vm_config.name = name;
     } else {
@@ -274,9 +330,11 @@ pub fn command_run(config: RunCustomVmConfig) -> Result<(), Error> {
     if let Some(gdb) = config.debug.gdb {
         vm_config.gdbPort = gdb.get() as i32;
     }
+    vm_config.panicPolicy = config.debug.panic_policy;
     vm_config.cpuTopology = config.common.cpu_topology;
     vm_config.hugePages = config.common.hugepages;
     vm_config.boostUclamp = config.common.boost_uclamp;
+    vm_config.labels = config.common.labels;
     run(
         get_service()?.as_ref(),
         &VirtualMachineConfig::RawConfig(vm_config),
@@ -284,6 +342,7 @@ pub fn command_run(config: RunCustomVmConfig) -> Result<(), Error> {
         config.debug.console.as_ref().map(|p| p.as_ref()),
         config.debug.console_in.as_ref().map(|p| p.as_ref()),
         config.debug.log.as_ref().map(|p| p.as_ref()),
+        session,
     )
 }
 
@@ -306,6 +365,7 @@ fn run(
     console_out_path: Option<&Path>,
     console_in_path: Option<&Path>,
     log_path: Option<&Path>,
+    session: Option<Arc<Session>>,
 ) -> Result<(), Error> {
     let console_out = if let Some(console_out_path) = console_out_path {
         Some(File::create(console_out_path).with_context(|| {
@@ -330,10 +390,19 @@ fn run(
     } else {
         Some(duplicate_fd(io::stdout())?)
     };
-    let callback = Box::new(Callback {});
+    if let Some(session) = &session {
+        session.set_config(config);
+    }
+    let callback = Box::new(Callback { session: session.clone() });
     let vm = VmInstance::create(service, config, console_out, console_in, log, Some(callback))
         .context("Failed to create VM")?;
+    if let Some(session) = &session {
+        session.on_created(vm.cid());
+    }
     vm.start().context("Failed to start VM")?;
+    if let Some(session) = &session {
+        session.on_started();
+    }
 
     let debug_level = get_debug_level(config).unwrap_or(DebugLevel::NONE);
 
@@ -349,6 +418,9 @@ fn run(
     // IVirtualMachine Binder object would be dropped and the VM would be killed.
     let death_reason = vm.wait_for_death();
     println!("VM ended: {:?}", death_reason);
+    if let Some(session) = &session {
+        session.on_died(format!("{death_reason:?}"));
+    }
     Ok(())
 }
 
@@ -359,23 +431,45 @@ fn parse_extra_apk_list(apk: &Path, config_path: &str) -> Result<Vec<PathBuf>, E
     Ok(config.extra_apks.into_iter().map(|x| x.path.into()).collect())
 }
 
-struct Callback {}
+struct Callback {
+    session: Option<Arc<Session>>,
+}
 
 impl vmclient::VmCallback for Callback {
     fn on_payload_started(&self, _cid: i32) {
         eprintln!("payload started");
+        if let Some(session) = &self.session {
+            session.on_payload_started();
+        }
     }
 
     fn on_payload_ready(&self, _cid: i32) {
         eprintln!("payload is ready");
+        if let Some(session) = &self.session {
+            session.on_payload_ready();
+        }
     }
 
     fn on_payload_finished(&self, _cid: i32, exit_code: i32) {
         eprintln!("payload finished with exit code {}", exit_code);
+        if let Some(session) = &self.session {
+            session.on_payload_finished(exit_code);
+        }
     }
 
     fn on_error(&self, _cid: i32, error_code: ErrorCode, message: &str) {
         eprintln!("VM encountered an error: code={:?}, message={}", error_code, message);
+        if let Some(session) = &self.session {
+            session.on_error(format!("{error_code:?}"), message);
+        }
+    }
+
+    fn on_guest_crash_reported(&self, _cid: i32, reason: &GuestCrashReason) {
+        eprintln!("guest crashed: kind={:?}, message={}", reason.kind, reason.message);
+    }
+
+    fn on_restarted(&self, _cid: i32) {
+        eprintln!("VM restarted after a guest panic");
     }
 }
 
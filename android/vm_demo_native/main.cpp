@@ -16,6 +16,7 @@
 #include <aidl/android/system/virtualizationcommon/DeathReason.h>
 #include <aidl/android/system/virtualizationcommon/ErrorCode.h>
 #include <aidl/android/system/virtualizationservice/BnVirtualMachineCallback.h>
+#include <aidl/android/system/virtualizationservice/GuestCrashReason.h>
 #include <aidl/android/system/virtualizationservice/IVirtualMachine.h>
 #include <aidl/android/system/virtualizationservice/IVirtualMachineCallback.h>
 #include <aidl/android/system/virtualizationservice/IVirtualizationService.h>
@@ -54,6 +55,7 @@ using ndk::SpAIBinder;
 using aidl::android::system::virtualizationcommon::DeathReason;
 using aidl::android::system::virtualizationcommon::ErrorCode;
 using aidl::android::system::virtualizationservice::BnVirtualMachineCallback;
+using aidl::android::system::virtualizationservice::GuestCrashReason;
 using aidl::android::system::virtualizationservice::IVirtualizationService;
 using aidl::android::system::virtualizationservice::IVirtualMachine;
 using aidl::android::system::virtualizationservice::PartitionType;
@@ -264,6 +266,19 @@ public:
         return ScopedAStatus::ok();
     }
 
+    ScopedAStatus onRamdumpAvailable(int32_t) { return ScopedAStatus::ok(); }
+
+    ScopedAStatus onRestarted(int32_t) { return ScopedAStatus::ok(); }
+
+    ScopedAStatus onGuestCrashReported(int32_t, const GuestCrashReason&) {
+        return ScopedAStatus::ok();
+    }
+
+    ScopedAStatus onHostFileRequested(int32_t, int32_t requestId, const std::string&) {
+        // This demo has no user to pick a file, so decline.
+        return mVm->provideHostFile(requestId, std::nullopt);
+    }
+
     ScopedAStatus onDied(int32_t, DeathReason) {
         std::unique_lock lock(mMutex);
         mCv.notify_all();
@@ -39,6 +39,9 @@ PRODUCT_FSVERITY_GENERATE_METADATA := true
 
 PRODUCT_AVF_ENABLED := true
 
+# Declares the build flags of AVF which aren't declared in build/release.
+PRODUCT_RELEASE_CONFIG_MAPS += packages/modules/Virtualization/build/release/release_config_map.textproto
+
 # The cheap build flags dependency management system until there is a proper one.
 ifdef RELEASE_AVF_ENABLE_DEVICE_ASSIGNMENT
   ifndef RELEASE_AVF_ENABLE_VENDOR_MODULES
@@ -3,7 +3,7 @@ mod dir;
 mod remote_file;
 
 pub use attr::Attr;
-pub use dir::{InMemoryDir, RemoteDirEditor};
+pub use dir::{read_remote_dir_by_page, InMemoryDir, RemoteDirEditor};
 pub use remote_file::{RemoteFileEditor, RemoteFileReader, RemoteMerkleTreeReader};
 
 use crate::common::{divide_roundup, CHUNK_SIZE};
@@ -14,6 +14,9 @@
  * limitations under the License.
  */
 
+use authfs_aidl_interface::aidl::com::android::virt::fs::IVirtFdService::{
+    DirectoryEntry::DirectoryEntry, MAX_REQUESTING_DIRECTORY_ENTRIES,
+};
 use log::warn;
 use nix::sys::stat::Mode;
 use std::collections::{hash_map, HashMap};
@@ -263,6 +266,32 @@ fn path_to_cstring(path: &Path) -> io::Result<CString> {
     CString::new(bytes).map_err(|_| io::Error::from_raw_os_error(libc::EILSEQ))
 }
 
+/// Calls `for_each_page` with the entries of the remote directory at `path` under `remote_dir_fd`,
+/// a page at a time, so that a large directory doesn't have to be held at once. The entries come
+/// from the untrusted fd_server, so they may only narrow a trusted view, never extend it.
+pub fn read_remote_dir_by_page<F>(
+    service: &VirtFdService,
+    remote_dir_fd: i32,
+    path: &Path,
+    mut for_each_page: F,
+) -> io::Result<()>
+where
+    F: FnMut(Vec<DirectoryEntry>),
+{
+    let path = path.to_str().ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+    let mut offset = 0;
+    loop {
+        let page = service
+            .readDirectory(remote_dir_fd, path, offset, MAX_REQUESTING_DIRECTORY_ENTRIES)
+            .map_err(into_io_error)?;
+        for_each_page(page.entries);
+        if page.end {
+            return Ok(());
+        }
+        offset = page.nextOffset;
+    }
+}
+
 fn into_io_error(e: VirtFdServiceStatus) -> io::Error {
     let maybe_errno = e.service_specific_error();
     if maybe_errno > 0 {
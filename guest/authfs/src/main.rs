@@ -34,9 +34,11 @@
 
 use anyhow::{anyhow, bail, Result};
 use clap::Parser;
-use log::error;
+use log::{error, warn};
 use protobuf::Message;
+use std::collections::{BTreeMap, HashSet};
 use std::convert::TryInto;
+use std::ffi::OsStr;
 use std::fs::File;
 use std::num::NonZeroU8;
 use std::path::{Path, PathBuf};
@@ -47,7 +49,9 @@ mod fsstat;
 mod fsverity;
 mod fusefs;
 
-use file::{Attr, InMemoryDir, RemoteDirEditor, RemoteFileEditor, RemoteFileReader};
+use file::{
+    read_remote_dir_by_page, Attr, InMemoryDir, RemoteDirEditor, RemoteFileEditor, RemoteFileReader,
+};
 use fsstat::RemoteFsStatsReader;
 use fsverity::VerifiedFileEditor;
 use fsverity_digests_proto::fsverity_digests::FSVerityDigests;
@@ -98,7 +102,8 @@ struct Args {
     /// essentially provides <file path, fs-verity digest> mappings of exported files). The mapping
     /// file is supposed to come from a trusted location in order to provide a trusted view as well
     /// as verified access of included files with their fs-verity digest. Not all files on the
-    /// remote host may be included in the mapping file, so the directory view may be partial. The
+    /// remote host may be included in the mapping file, so the directory view may be partial.
+    /// Conversely, files of the mapping file which the remote doesn't list are left out. The
     /// directory structure won't change throughout the filesystem lifetime.
     ///
     /// For example, `--remote-ro-dir 5:/path/to/mapping:prefix/` tells the filesystem to
@@ -261,23 +266,34 @@ fn prepare_root_dir_entries(
         // Build the directory tree based on the mapping file.
         let mut reader = File::open(&config.mapping_file_path)?;
         let proto = FSVerityDigests::parse_from_reader(&mut reader)?;
+        let mut files = Vec::new();
         for (path_str, digest) in &proto.digests {
             if digest.hash_alg != "sha256" {
                 bail!("Unsupported hash algorithm: {}", digest.hash_alg);
             }
+            let remote_path_str = path_str.strip_prefix(&config.prefix).ok_or_else(|| {
+                anyhow!("Expect path {} to match prefix {}", path_str, config.prefix)
+            })?;
+            files.push((path_str, Path::new(remote_path_str), digest));
+        }
 
-            let file_entry = {
-                let remote_path_str = path_str.strip_prefix(&config.prefix).ok_or_else(|| {
-                    anyhow!("Expect path {} to match prefix {}", path_str, config.prefix)
-                })?;
-                AuthFsEntry::VerifiedReadonly {
-                    reader: LazyVerifiedReadonlyFile::prepare_by_path(
-                        service.clone(),
-                        config.remote_dir_fd,
-                        PathBuf::from(remote_path_str),
-                        digest.digest.clone(),
-                    ),
-                }
+        let missing = find_missing_remote_files(
+            &service,
+            config.remote_dir_fd,
+            files.iter().map(|(_, remote_path, _)| *remote_path),
+        );
+        for (path_str, remote_path, digest) in files {
+            if missing.contains(remote_path) {
+                warn!("Leaving out {}, which doesn't exist on the remote", path_str);
+                continue;
+            }
+            let file_entry = AuthFsEntry::VerifiedReadonly {
+                reader: LazyVerifiedReadonlyFile::prepare_by_path(
+                    service.clone(),
+                    config.remote_dir_fd,
+                    remote_path.to_owned(),
+                    digest.digest.clone(),
+                ),
             };
             authfs.add_entry_at_ro_dir_by_path(dir_root_inode, Path::new(path_str), file_entry)?;
         }
@@ -286,6 +302,37 @@ fn prepare_root_dir_entries(
     Ok(())
 }
 
+/// Returns those of `remote_paths` which aren't files in the remote directory, e.g. because they
+/// weren't installed on the remote. Each directory is listed once, page by page. The listing isn't
+/// trusted, so it is only used to leave files out of the view built from the manifest. If a
+/// directory can't be listed, its files are assumed to exist.
+fn find_missing_remote_files<'a>(
+    service: &file::VirtFdService,
+    remote_dir_fd: i32,
+    remote_paths: impl Iterator<Item = &'a Path>,
+) -> HashSet<PathBuf> {
+    let mut wanted: BTreeMap<&Path, HashSet<&OsStr>> = BTreeMap::new();
+    for path in remote_paths {
+        if let (Some(parent), Some(name)) = (path.parent(), path.file_name()) {
+            wanted.entry(parent).or_default().insert(name);
+        }
+    }
+
+    let mut missing = HashSet::new();
+    for (parent, mut names) in wanted {
+        let result = read_remote_dir_by_page(service, remote_dir_fd, parent, |entries| {
+            for entry in entries.iter().filter(|entry| !entry.isDirectory) {
+                names.remove(OsStr::new(&entry.name));
+            }
+        });
+        match result {
+            Ok(()) => missing.extend(names.into_iter().map(|name| parent.join(name))),
+            Err(e) => warn!("Failed to list {:?} of remote dir {}: {}", parent, remote_dir_fd, e),
+        }
+    }
+    missing
+}
+
 fn remote_fd_to_path_buf(fd: i32) -> PathBuf {
     PathBuf::from(fd.to_string())
 }
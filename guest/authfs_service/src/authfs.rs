@@ -20,7 +20,7 @@ use nix::mount::{umount2, MntFlags};
 use nix::sys::statfs::{statfs, FsType};
 use shared_child::SharedChild;
 use std::ffi::{OsStr, OsString};
-use std::fs::{remove_dir, OpenOptions};
+use std::fs::{self, remove_dir, remove_file, OpenOptions};
 use std::path::PathBuf;
 use std::process::Command;
 use std::thread::sleep;
@@ -44,6 +44,8 @@ const FUSE_SUPER_MAGIC: FsType = FsType(0x65735546);
 pub struct AuthFs {
     mountpoint: OsString,
     process: SharedChild,
+    /// The manifests which the client passed by value, written next to the mount directory.
+    manifests: Vec<PathBuf>,
 }
 
 impl Interface for AuthFs {}
@@ -81,6 +83,7 @@ impl AuthFs {
         config: &AuthFsConfig,
         debuggable: bool,
     ) -> Result<Strong<dyn IAuthFs>> {
+        let manifests = write_manifests(&mountpoint, &config.inputDirFdAnnotations)?;
         let child = run_authfs(
             &mountpoint,
             &config.inputFdAnnotations,
@@ -88,13 +91,17 @@ impl AuthFs {
             &config.inputDirFdAnnotations,
             &config.outputDirFdAnnotations,
             debuggable,
-        )?;
-        wait_until_authfs_ready(&child, &mountpoint).inspect_err(|_| match child.wait() {
-            Ok(status) => debug!("Wait for authfs: {}", status),
-            Err(e) => warn!("Failed to wait for child: {}", e),
-        })?;
+        )
+        .and_then(|child| {
+            wait_until_authfs_ready(&child, &mountpoint).inspect_err(|_| match child.wait() {
+                Ok(status) => debug!("Wait for authfs: {}", status),
+                Err(e) => warn!("Failed to wait for child: {}", e),
+            })?;
+            Ok(child)
+        })
+        .inspect_err(|_| remove_manifests(&manifests))?;
 
-        let authfs = AuthFs { mountpoint, process: child };
+        let authfs = AuthFs { mountpoint, process: child, manifests };
         Ok(BnAuthFs::new_binder(authfs, BinderFeatures::default()))
     }
 }
@@ -120,6 +127,40 @@ impl Drop for AuthFs {
         if let Err(e) = remove_dir(&self.mountpoint) {
             error!("Failed to clean up mount directory {:?}: {}", &self.mountpoint, e)
         }
+        remove_manifests(&self.manifests);
+    }
+}
+
+/// Returns the path of the manifest of the input directory `fd`, when passed by value.
+fn manifest_path(mountpoint: &OsStr, fd: i32) -> PathBuf {
+    let mut path = mountpoint.to_owned();
+    path.push(format!(".{fd}.manifest"));
+    PathBuf::from(path)
+}
+
+/// Writes the manifests which the client passed by value to files, for authfs to read them.
+fn write_manifests(
+    mountpoint: &OsStr,
+    in_dir_fds: &[InputDirFdAnnotation],
+) -> Result<Vec<PathBuf>> {
+    let mut manifests = Vec::new();
+    for conf in in_dir_fds {
+        let Some(manifest) = &conf.manifest else { continue };
+        let path = manifest_path(mountpoint, conf.fd);
+        if let Err(e) = fs::write(&path, manifest) {
+            remove_manifests(&manifests);
+            return Err(e).with_context(|| format!("Failed to write manifest {path:?}"));
+        }
+        manifests.push(path);
+    }
+    Ok(manifests)
+}
+
+fn remove_manifests(manifests: &[PathBuf]) {
+    for path in manifests {
+        if let Err(e) = remove_file(path) {
+            error!("Failed to clean up manifest {:?}: {}", path, e)
+        }
     }
 }
 
@@ -145,8 +186,18 @@ fn run_authfs(
         args.push(OsString::from(conf.fd.to_string()));
     }
     for conf in in_dir_fds {
+        let manifest_path = if conf.manifest.is_some() {
+            manifest_path(mountpoint, conf.fd)
+        } else {
+            PathBuf::from(&conf.manifestPath)
+        };
         args.push(OsString::from("--remote-ro-dir"));
-        args.push(OsString::from(format!("{}:{}:{}", conf.fd, conf.manifestPath, conf.prefix)));
+        args.push(OsString::from(format!(
+            "{}:{}:{}",
+            conf.fd,
+            manifest_path.display(),
+            conf.prefix
+        )));
     }
     for conf in out_dir_fds {
         args.push(OsString::from("--remote-new-rw-dir"));
@@ -19,6 +19,7 @@
 #include <unistd.h>
 #include <vm_payload_restricted.h>
 
+#include <algorithm>
 #include <string_view>
 #include <vector>
 
@@ -36,6 +37,9 @@ namespace {
 
 constexpr const char* kSigningKeySeedIdentifier = "CompOS signing key seed";
 
+// Distinguishes an invalid signature from a failure to check it.
+constexpr int kInvalidSignatureExitCode = 2;
+
 Result<Ed25519KeyPair> getSigningKey() {
     Seed seed;
     AVmPayload_getVmInstanceSecret(kSigningKeySeedIdentifier, strlen(kSigningKeySeedIdentifier),
@@ -96,6 +100,33 @@ int sign_input() {
     }
     return 0;
 }
+
+int verify_input() {
+    std::string input;
+    if (!ReadFdToString(STDIN_FILENO, &input)) {
+        PLOG(ERROR) << "Read failed";
+        return 1;
+    }
+    compos_key::Signature signature;
+    if (input.size() < signature.size()) {
+        LOG(ERROR) << "Input is too short to hold a signature";
+        return 1;
+    }
+
+    auto key_pair = getSigningKey();
+    if (!key_pair.ok()) {
+        LOG(ERROR) << key_pair.error();
+        return 1;
+    }
+
+    auto data = reinterpret_cast<const uint8_t*>(input.data());
+    std::copy_n(data, signature.size(), signature.begin());
+    if (!compos_key::verify(key_pair->public_key, signature, data + signature.size(),
+                            input.size() - signature.size())) {
+        return kInvalidSignatureExitCode;
+    }
+    return 0;
+}
 } // namespace
 
 int main(int argc, char** argv) {
@@ -108,11 +139,15 @@ int main(int argc, char** argv) {
             return write_bcc();
         } else if (argv[1] == "sign"sv) {
             return sign_input();
+        } else if (argv[1] == "verify"sv) {
+            return verify_input();
         }
     }
 
     LOG(INFO) << "Usage: compos_key_helper <command>. Available commands are:\n"
                  "public_key   Write current public key to stdout\n"
-                 "sign         Consume stdin, sign it and write signature to stdout\n";
+                 "sign         Consume stdin, sign it and write signature to stdout\n"
+                 "verify       Consume stdin, a signature followed by the signed data, and exit\n"
+                 "             with 0 if the signature is valid, 2 otherwise\n";
     return 1;
 }
@@ -15,21 +15,39 @@
  */
 
 //! Support for generating and signing an info file listing names and digests of generated
-//! artifacts.
+//! artifacts, and for verifying it later.
 
 use crate::compos_key;
 use crate::fsverity;
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, ensure, Context, Result};
+use compos_aidl_interface::aidl::com::android::compos::ICompOsService::{
+    ArtifactDigest::ArtifactDigest, ArtifactVerification::ArtifactVerification,
+};
 use odsign_proto::odsign_info::OdsignInfo;
 use protobuf::Message;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::io::Write;
 use std::os::unix::io::AsFd;
-use std::path::Path;
+use std::path::{Component, Path};
 
 const TARGET_DIRECTORY: &str = "/data/misc/apexdata/com.android.art/dalvik-cache";
 const SIGNATURE_EXTENSION: &str = ".signature";
 
+/// Prefixed to the signed bytes of a checkpoint, so that it can never be taken for a complete
+/// info file, which is an `OdsignInfo` alone. The leading byte isn't a valid protobuf tag (field 0,
+/// wire type 7), so odsign doesn't parse a checkpoint either.
+const CHECKPOINT_HEADER: &[u8] = b"\x07CompOS checkpoint v1\n";
+
+/// What a signed info file lists.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InfoKind {
+    /// All the artifacts of a compilation, to be checked by odsign (`compos.info`).
+    Artifacts,
+    /// The artifacts compiled so far, from which an interrupted compilation can resume.
+    Checkpoint,
+}
+
 /// Accumulates and then signs information about generated artifacts.
 pub struct ArtifactSigner<'a> {
     base_directory: &'a Path,
@@ -61,10 +79,13 @@ impl<'a> ArtifactSigner<'a> {
 
     /// Consume this ArtifactSigner and write details of all its artifacts to the given path,
     /// with accompanying sigature file.
-    pub fn write_info_and_signature(self, info_path: &Path) -> Result<()> {
+    pub fn write_info_and_signature(self, info_path: &Path, kind: InfoKind) -> Result<()> {
         let mut info = OdsignInfo::new();
         info.file_hashes.extend(self.file_digests);
-        let bytes = info.write_to_bytes()?;
+        let bytes = match kind {
+            InfoKind::Artifacts => info.write_to_bytes()?,
+            InfoKind::Checkpoint => [CHECKPOINT_HEADER, &info.write_to_bytes()?].concat(),
+        };
 
         let signature = compos_key::sign(&bytes)?;
 
@@ -82,3 +103,127 @@ impl<'a> ArtifactSigner<'a> {
         Ok(())
     }
 }
+
+/// Checks that `info` was signed with `signature` by the current signing key, and compares the
+/// digests of the artifacts it lists with `digests`, as measured by the host. The artifacts aren't
+/// read, so they can be checked without recompiling them.
+pub fn verify_info(
+    info: &[u8],
+    signature: &[u8],
+    digests: &[ArtifactDigest],
+) -> Result<ArtifactVerification> {
+    ensure!(!info.starts_with(CHECKPOINT_HEADER), "A checkpoint isn't a complete info file");
+    let signature_valid = compos_key::verify(signature, info)?;
+    let info = OdsignInfo::parse_from_bytes(info).context("Failed to parse info")?;
+
+    let measured: HashMap<&str, String> =
+        digests.iter().map(|d| (d.path.as_str(), hex::encode(&d.digest))).collect();
+    let mut mismatched: Vec<_> = info
+        .file_hashes
+        .iter()
+        .filter(|(path, digest)| measured.get(path.as_str()) != Some(*digest))
+        .map(|(path, _)| path.clone())
+        .collect();
+    mismatched.sort();
+    let mut unlisted: Vec<_> = digests
+        .iter()
+        .filter(|d| !info.file_hashes.contains_key(&d.path))
+        .map(|d| d.path.clone())
+        .collect();
+    unlisted.sort();
+
+    Ok(ArtifactVerification {
+        signatureValid: signature_valid,
+        mismatchedArtifacts: mismatched,
+        unlistedArtifacts: unlisted,
+    })
+}
+
+/// Checks that the checkpoint `info` was signed with `signature` by the current signing key, and
+/// returns the fs-verity digests of the artifacts it lists, by their path relative to the target
+/// directory.
+pub fn checkpoint_digests(info: &[u8], signature: &[u8]) -> Result<BTreeMap<String, Vec<u8>>> {
+    ensure!(compos_key::verify(signature, info)?, "The checkpoint isn't signed by this instance");
+    let info = parse_checkpoint(info)?;
+    relative_digests(&info)
+}
+
+fn parse_checkpoint(info: &[u8]) -> Result<OdsignInfo> {
+    let info = info.strip_prefix(CHECKPOINT_HEADER).context("Not a checkpoint")?;
+    OdsignInfo::parse_from_bytes(info).context("Failed to parse checkpoint")
+}
+
+fn relative_digests(info: &OdsignInfo) -> Result<BTreeMap<String, Vec<u8>>> {
+    info.file_hashes
+        .iter()
+        .map(|(path, digest)| {
+            let relative_path = Path::new(path)
+                .strip_prefix(TARGET_DIRECTORY)
+                .with_context(|| format!("{path} isn't an artifact"))?;
+            ensure!(
+                relative_path.components().all(|c| matches!(c, Component::Normal(_))),
+                "Invalid artifact path {path}"
+            );
+            let relative_path = relative_path.to_str().context("Invalid artifact path")?;
+            let digest =
+                hex::decode(digest).with_context(|| format!("Invalid digest of {path}"))?;
+            Ok((relative_path.to_owned(), digest))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(file_hashes: &[(&str, &str)]) -> OdsignInfo {
+        let mut info = OdsignInfo::new();
+        info.file_hashes.extend(
+            file_hashes.iter().map(|(path, digest)| (path.to_string(), digest.to_string())),
+        );
+        info
+    }
+
+    #[test]
+    fn relative_digests_of_artifacts() -> Result<()> {
+        let info = info(&[
+            ("/data/misc/apexdata/com.android.art/dalvik-cache/cache-info.xml", "00ff"),
+            ("/data/misc/apexdata/com.android.art/dalvik-cache/arm64/boot.art", "1234"),
+        ]);
+        let digests = relative_digests(&info)?;
+        assert_eq!(
+            digests,
+            BTreeMap::from([
+                ("arm64/boot.art".to_owned(), vec![0x12, 0x34]),
+                ("cache-info.xml".to_owned(), vec![0x00, 0xff]),
+            ])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn relative_digests_rejects_paths_outside_target_directory() {
+        assert!(relative_digests(&info(&[("/data/local/tmp/boot.art", "00")])).is_err());
+        let escaping = "/data/misc/apexdata/com.android.art/dalvik-cache/../compos-pending/x";
+        assert!(relative_digests(&info(&[(escaping, "00")])).is_err());
+    }
+
+    #[test]
+    fn checkpoints_and_complete_infos_are_distinct() -> Result<()> {
+        let info = info(&[("/data/misc/apexdata/com.android.art/dalvik-cache/boot.art", "00")]);
+        let bytes = info.write_to_bytes()?;
+        let checkpoint = [CHECKPOINT_HEADER, &bytes].concat();
+
+        assert_eq!(parse_checkpoint(&checkpoint)?, info);
+        assert!(parse_checkpoint(&bytes).is_err());
+        assert!(OdsignInfo::parse_from_bytes(&checkpoint).is_err());
+        assert!(verify_info(&checkpoint, &[], &[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn relative_digests_rejects_invalid_digests() {
+        let path = "/data/misc/apexdata/com.android.art/dalvik-cache/arm64/boot.art";
+        assert!(relative_digests(&info(&[(path, "not hex")])).is_err());
+    }
+}
@@ -19,12 +19,15 @@ use log::{debug, info, warn};
 use minijail::{self, Minijail};
 use regex::Regex;
 use rustutils::system_properties;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::ffi::OsString;
+use std::fs::{create_dir_all, remove_dir_all, File};
+use std::io::{self, ErrorKind};
 use std::path::{self, Path, PathBuf};
 use std::process::Command;
 
+use crate::artifact_signer::InfoKind;
 use authfs_aidl_interface::aidl::com::android::virt::fs::{
     AuthFsConfig::{
         AuthFsConfig, InputDirFdAnnotation::InputDirFdAnnotation,
@@ -36,10 +39,29 @@ use binder::Strong;
 use compos_aidl_interface::aidl::com::android::compos::ICompOsService::{
     CompilationMode::CompilationMode, OdrefreshArgs::OdrefreshArgs,
 };
-use compos_common::odrefresh::ExitCode;
+use compos_common::odrefresh::{ExitCode, CHECKPOINT_SUFFIX};
+use fsverity_digests_proto::fsverity_digests::{fsverity_digests::FSVerityDigest, FSVerityDigests};
+use protobuf::Message;
 
 const FD_SERVER_PORT: i32 = 3264; // TODO: support dynamic port
 
+/// The targets which odrefresh compiles in turn, as the arguments selecting them. The artifacts
+/// compiled so far are checkpointed after each target but the last, which is signed in full.
+const TARGETS: &[&[&str]] = &[&["--only-boot-images"], &[]];
+
+/// A test compilation recompiles everything regardless of earlier artifacts, so it is done in a
+/// single run and never checkpointed.
+const TEST_TARGETS: &[&[&str]] = &[&[]];
+
+/// The artifacts of an earlier run of the compilation, as listed by a checkpoint which the VM
+/// signed.
+pub struct Checkpoint {
+    /// The remote FD of the directory of the artifacts.
+    pub dir_fd: i32,
+    /// The fs-verity digests of the artifacts, by their path relative to the directory.
+    pub digests: BTreeMap<String, Vec<u8>>,
+}
+
 fn validate_args(args: &OdrefreshArgs) -> Result<()> {
     if args.compilationMode != CompilationMode::NORMAL_COMPILE {
         // Conservatively check debuggability.
@@ -53,6 +75,9 @@ fn validate_args(args: &OdrefreshArgs) -> Result<()> {
     if args.systemDirFd < 0 || args.outputDirFd < 0 || args.stagingDirFd < 0 {
         bail!("The remote FDs are expected to be non-negative");
     }
+    if args.checkpoint.as_ref().is_some_and(|checkpoint| checkpoint.dirFd < 0) {
+        bail!("The remote FD of the checkpoint is expected to be non-negative");
+    }
     if !matches!(&args.zygoteArch[..], "zygote64" | "zygote64_32") {
         bail!("Invalid zygote arch");
     }
@@ -69,14 +94,18 @@ fn validate_args(args: &OdrefreshArgs) -> Result<()> {
     Ok(())
 }
 
+/// Runs odrefresh, target by target, resuming from `checkpoint` if any. `sign_fn` is called with
+/// the target directory, the path of an info file and what it lists, to sign the artifacts
+/// compiled so far after each target, and all of them once they are compiled.
 pub fn odrefresh<F>(
     odrefresh_path: &Path,
     args: &OdrefreshArgs,
+    checkpoint: Option<&Checkpoint>,
     authfs_service: Strong<dyn IAuthFsService>,
-    success_fn: F,
+    sign_fn: F,
 ) -> Result<ExitCode>
 where
-    F: FnOnce(PathBuf) -> Result<()>,
+    F: Fn(&Path, &Path, InfoKind) -> Result<()>,
 {
     validate_args(args)?;
 
@@ -88,6 +117,7 @@ where
         // Use the 0th APK of the extra_apks in compos/apk/assets/vm_config*.json
         manifestPath: "/mnt/extra-apk/0/assets/build_manifest.pb".to_string(),
         prefix: "system/".to_string(),
+        ..Default::default()
     }];
     if args.systemExtDirFd >= 0 {
         input_dir_fd_annotations.push(InputDirFdAnnotation {
@@ -95,6 +125,16 @@ where
             // Use the 1st APK of the extra_apks in compos/apk/assets/vm_config_system_ext_*.json
             manifestPath: "/mnt/extra-apk/1/assets/build_manifest.pb".to_string(),
             prefix: "system_ext/".to_string(),
+            ..Default::default()
+        });
+    }
+    if let Some(checkpoint) = checkpoint {
+        // authfs verifies the artifacts against the digests which the VM signed as it reads them.
+        input_dir_fd_annotations.push(InputDirFdAnnotation {
+            fd: checkpoint.dir_fd,
+            prefix: String::new(),
+            manifest: Some(checkpoint_manifest(&checkpoint.digests)?),
+            ..Default::default()
         });
     }
 
@@ -134,8 +174,33 @@ where
     let staging_dir = mountpoint.join(args.stagingDirFd.to_string());
 
     set_classpaths(&mut odrefresh_vars, &android_root)?;
+    let odrefresh_env = odrefresh_vars.into_env();
+
+    let (compile_flag, targets) = compile_targets(args.compilationMode)?;
+
+    let target_dir = art_apex_data.join(&args.targetDirName);
+    let mut restored = false;
+    // A single run, as in a test compilation, has nothing to resume.
+    if let Some(checkpoint) = checkpoint.filter(|_| targets.len() > 1) {
+        let checkpoint_dir = mountpoint.join(checkpoint.dir_fd.to_string());
+        match restore_checkpoint(&checkpoint_dir, &checkpoint.digests, &target_dir) {
+            Ok(()) => {
+                info!("Restored {} artifacts from the checkpoint", checkpoint.digests.len());
+                restored = true;
+            }
+            Err(e) => {
+                warn!("Failed to restore the checkpoint, compiling everything: {:?}", e);
+                match remove_dir_all(&target_dir) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => {
+                        return Err(e).context("Failed to delete the restored artifacts")
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
 
-    let mut command_line_args = vec![
+    let mut base_args = vec![
         "odrefresh".to_string(),
         "--compilation-os-mode".to_string(),
         format!("--zygote-arch={}", args.zygoteArch),
@@ -145,19 +210,55 @@ where
     ];
 
     if !args.systemServerCompilerFilter.is_empty() {
-        command_line_args
+        base_args
             .push(format!("--system-server-compiler-filter={}", args.systemServerCompilerFilter));
     }
 
-    let compile_flag = match args.compilationMode {
-        CompilationMode::NORMAL_COMPILE => "--compile",
-        CompilationMode::TEST_COMPILE => "--force-compile",
+    let mut checkpoint_name = args.targetDirName.clone();
+    checkpoint_name.push_str(CHECKPOINT_SUFFIX);
+    let checkpoint_path = art_apex_data.join(checkpoint_name);
+    let mut compiled = false;
+    for (i, target_args) in targets.iter().enumerate() {
+        let mut command_line_args = base_args.clone();
+        command_line_args.extend(target_args.iter().map(|arg| arg.to_string()));
+        command_line_args.push(compile_flag.to_string());
+
+        match run_odrefresh(odrefresh_path, &command_line_args, &odrefresh_env)? {
+            ExitCode::CompilationSuccess => compiled = true,
+            ExitCode::Okay => {}
+            exit_code => return Ok(exit_code),
+        }
+        if (compiled || restored) && i + 1 < targets.len() {
+            sign_fn(&target_dir, &checkpoint_path, InfoKind::Checkpoint)
+                .context("Failed to sign the checkpoint")?;
+        }
+    }
+
+    if !compiled && !restored {
+        return Ok(ExitCode::Okay);
+    }
+    sign_fn(&target_dir, &target_dir.join("compos.info"), InfoKind::Artifacts)?;
+    Ok(ExitCode::CompilationSuccess)
+}
+
+/// Returns the flag passed to every run of odrefresh in `mode`, and the targets it compiles.
+fn compile_targets(
+    mode: CompilationMode,
+) -> Result<(&'static str, &'static [&'static [&'static str]])> {
+    match mode {
+        CompilationMode::NORMAL_COMPILE => Ok(("--compile", TARGETS)),
+        CompilationMode::TEST_COMPILE => Ok(("--force-compile", TEST_TARGETS)),
         other => bail!("Unknown compilation mode {:?}", other),
-    };
-    command_line_args.push(compile_flag.to_string());
+    }
+}
 
-    debug!("Running odrefresh with args: {:?}", &command_line_args);
-    let jail = spawn_jailed_task(odrefresh_path, &command_line_args, &odrefresh_vars.into_env())
+fn run_odrefresh(
+    odrefresh_path: &Path,
+    command_line_args: &[String],
+    odrefresh_env: &[String],
+) -> Result<ExitCode> {
+    debug!("Running odrefresh with args: {:?}", command_line_args);
+    let jail = spawn_jailed_task(odrefresh_path, command_line_args, odrefresh_env)
         .context("Spawn odrefresh")?;
     let exit_code = match jail.wait() {
         Ok(_) => 0,
@@ -167,13 +268,40 @@ where
 
     let exit_code = ExitCode::from_i32(exit_code.into())?;
     info!("odrefresh exited with {:?}", exit_code);
+    Ok(exit_code)
+}
 
-    if exit_code == ExitCode::CompilationSuccess {
-        let target_dir = art_apex_data.join(&args.targetDirName);
-        success_fn(target_dir)?;
+/// Returns the manifest of the checkpointed artifacts, for authfs to verify them against.
+fn checkpoint_manifest(digests: &BTreeMap<String, Vec<u8>>) -> Result<Vec<u8>> {
+    let mut manifest = FSVerityDigests::new();
+    for (path, digest) in digests {
+        let mut entry = FSVerityDigest::new();
+        entry.digest = digest.clone();
+        entry.hash_alg = "sha256".to_owned();
+        manifest.digests.insert(path.clone(), entry);
     }
+    Ok(manifest.write_to_bytes()?)
+}
 
-    Ok(exit_code)
+/// Copies the artifacts listed in `digests` from `checkpoint_dir` to `target_dir`. authfs fails
+/// the reads of the artifacts which don't match their digest.
+fn restore_checkpoint(
+    checkpoint_dir: &Path,
+    digests: &BTreeMap<String, Vec<u8>>,
+    target_dir: &Path,
+) -> Result<()> {
+    for path in digests.keys() {
+        let target_path = target_dir.join(path);
+        if let Some(parent) = target_path.parent() {
+            create_dir_all(parent)?;
+        }
+        let mut source = File::open(checkpoint_dir.join(path))
+            .with_context(|| format!("Failed to open checkpointed {path}"))?;
+        let mut target = File::create(&target_path)
+            .with_context(|| format!("Failed to create {}", target_path.display()))?;
+        io::copy(&mut source, &mut target).with_context(|| format!("Failed to restore {path}"))?;
+    }
+    Ok(())
 }
 
 fn path_to_str(path: &Path) -> Result<&str> {
@@ -251,3 +379,62 @@ impl EnvMap {
         self.0.into_iter().map(|(k, v)| k + "=" + &v).collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read, write};
+
+    fn digests(paths: &[&str]) -> BTreeMap<String, Vec<u8>> {
+        paths.iter().enumerate().map(|(i, path)| (path.to_string(), vec![i as u8; 32])).collect()
+    }
+
+    #[test]
+    fn test_compilation_forces_a_single_run() -> Result<()> {
+        assert_eq!(compile_targets(CompilationMode::NORMAL_COMPILE)?, ("--compile", TARGETS));
+        let (compile_flag, targets) = compile_targets(CompilationMode::TEST_COMPILE)?;
+        assert_eq!(compile_flag, "--force-compile");
+        assert_eq!(targets, &[&[] as &[&str]]);
+        Ok(())
+    }
+
+    #[test]
+    fn checkpoint_manifest_lists_digests() -> Result<()> {
+        let digests = digests(&["arm64/boot.art", "cache-info.xml"]);
+        let manifest = FSVerityDigests::parse_from_bytes(&checkpoint_manifest(&digests)?)?;
+        assert_eq!(manifest.digests.len(), 2);
+        for (path, digest) in &digests {
+            let entry = &manifest.digests[path];
+            assert_eq!(&entry.digest, digest);
+            assert_eq!(entry.hash_alg, "sha256");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn restore_checkpoint_copies_listed_artifacts() -> Result<()> {
+        let checkpoint_dir = tempfile::tempdir()?;
+        create_dir_all(checkpoint_dir.path().join("arm64"))?;
+        write(checkpoint_dir.path().join("arm64/boot.art"), "boot")?;
+        write(checkpoint_dir.path().join("cache-info.xml"), "info")?;
+        write(checkpoint_dir.path().join("unlisted"), "unlisted")?;
+        let target_dir = tempfile::tempdir()?;
+
+        let digests = digests(&["arm64/boot.art", "cache-info.xml"]);
+        restore_checkpoint(checkpoint_dir.path(), &digests, target_dir.path())?;
+
+        assert_eq!(read(target_dir.path().join("arm64/boot.art"))?, b"boot");
+        assert_eq!(read(target_dir.path().join("cache-info.xml"))?, b"info");
+        assert!(!target_dir.path().join("unlisted").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn restore_checkpoint_fails_on_missing_artifact() -> Result<()> {
+        let checkpoint_dir = tempfile::tempdir()?;
+        let target_dir = tempfile::tempdir()?;
+        let digests = digests(&["arm64/boot.art"]);
+        assert!(restore_checkpoint(checkpoint_dir.path(), &digests, target_dir.path()).is_err());
+        Ok(())
+    }
+}
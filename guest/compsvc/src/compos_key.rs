@@ -16,10 +16,16 @@
 
 use anyhow::{bail, Context, Result};
 use std::io::Write;
-use std::process::{Command, Stdio};
+use std::process::{Command, Output, Stdio};
 
 const COMPOS_KEY_HELPER_PATH: &str = "/apex/com.android.compos/bin/compos_key_helper";
 
+/// Size of an Ed25519 signature.
+const SIGNATURE_SIZE: usize = 64;
+
+/// Exit code of the helper when asked to verify an invalid signature.
+const INVALID_SIGNATURE_EXIT_CODE: i32 = 2;
+
 pub fn get_public_key() -> Result<Vec<u8>> {
     get_data_from_helper("public_key")
 }
@@ -43,22 +49,38 @@ fn get_data_from_helper(command: &str) -> Result<Vec<u8>> {
 }
 
 pub fn sign(data: &[u8]) -> Result<Vec<u8>> {
+    let result = run_helper_with_input("sign", data)?;
+    if !result.status.success() {
+        bail!("Helper failed: {}", result.status);
+    }
+    Ok(result.stdout)
+}
+
+/// Returns whether `signature` is a valid signature of `data` by the current signing key.
+pub fn verify(signature: &[u8], data: &[u8]) -> Result<bool> {
+    if signature.len() != SIGNATURE_SIZE {
+        return Ok(false);
+    }
+    let result = run_helper_with_input("verify", &[signature, data].concat())?;
+    match result.status.code() {
+        Some(0) => Ok(true),
+        Some(INVALID_SIGNATURE_EXIT_CODE) => Ok(false),
+        _ => bail!("Helper failed: {}", result.status),
+    }
+}
+
+fn run_helper_with_input(command: &str, input: &[u8]) -> Result<Output> {
     let mut child = Command::new(COMPOS_KEY_HELPER_PATH)
-        .arg("sign")
+        .arg(command)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()?;
 
     // No output is written until the entire input is consumed, so this shouldn't deadlock.
-    let result =
-        child.stdin.take().unwrap().write_all(data).context("Failed to write data to be signed");
+    let result = child.stdin.take().unwrap().write_all(input).context("Failed to write input");
     if result.is_ok() {
-        let result = child.wait_with_output()?;
-        if !result.status.success() {
-            bail!("Helper failed: {}", result.status);
-        }
-        return Ok(result.stdout);
+        return Ok(child.wait_with_output()?);
     }
 
     // The child may have exited already, but if it hasn't then we need to make sure it does.
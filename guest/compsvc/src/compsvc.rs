@@ -19,7 +19,7 @@
 //! actual compiler.
 
 use anyhow::{bail, Context, Result};
-use log::{error, info};
+use log::{error, info, warn};
 use rustutils::system_properties;
 use std::default::Default;
 use std::fs::read_dir;
@@ -27,8 +27,8 @@ use std::iter::zip;
 use std::path::{Path, PathBuf};
 use std::sync::RwLock;
 
-use crate::artifact_signer::ArtifactSigner;
-use crate::compilation::odrefresh;
+use crate::artifact_signer::{checkpoint_digests, verify_info, ArtifactSigner, InfoKind};
+use crate::compilation::{odrefresh, Checkpoint};
 use crate::compos_key;
 use authfs_aidl_interface::aidl::com::android::virt::fs::IAuthFsService::{
     IAuthFsService, AUTHFS_SERVICE_SOCKET_NAME,
@@ -37,7 +37,8 @@ use binder::{
     BinderFeatures, ExceptionCode, Interface, IntoBinderResult, Result as BinderResult, Strong,
 };
 use compos_aidl_interface::aidl::com::android::compos::ICompOsService::{
-    BnCompOsService, ICompOsService, OdrefreshArgs::OdrefreshArgs,
+    ArtifactDigest::ArtifactDigest, ArtifactVerification::ArtifactVerification, BnCompOsService,
+    ICompOsService, OdrefreshArgs::OdrefreshArgs,
 };
 use compos_common::binder::to_binder_result;
 use compos_common::odrefresh::{is_system_property_interesting, ODREFRESH_PATH};
@@ -106,6 +107,15 @@ impl ICompOsService for CompOsService {
         to_binder_result(self.do_odrefresh(args))
     }
 
+    fn verifyArtifacts(
+        &self,
+        info: &[u8],
+        signature: &[u8],
+        digests: &[ArtifactDigest],
+    ) -> BinderResult<ArtifactVerification> {
+        to_binder_result(verify_info(info, signature, digests))
+    }
+
     fn getPublicKey(&self) -> BinderResult<Vec<u8>> {
         to_binder_result(compos_key::get_public_key())
     }
@@ -127,14 +137,30 @@ impl CompOsService {
         let authfs_service: Strong<dyn IAuthFsService> = RpcSession::new()
             .setup_unix_domain_client(AUTHFS_SERVICE_SOCKET_NAME)
             .with_context(|| format!("Failed to connect to {}", AUTHFS_SERVICE_SOCKET_NAME))?;
-        let exit_code = odrefresh(&self.odrefresh_path, args, authfs_service, |output_dir| {
-            // authfs only shows us the files we created, so it's ok to just sign everything
-            // under the output directory.
-            let mut artifact_signer = ArtifactSigner::new(&output_dir);
-            add_artifacts(&output_dir, &mut artifact_signer)?;
-
-            artifact_signer.write_info_and_signature(&output_dir.join("compos.info"))
-        })
+        // A checkpoint which doesn't verify is ignored, as if the compilation started afresh.
+        let checkpoint = args.checkpoint.as_ref().and_then(|checkpoint| {
+            match checkpoint_digests(&checkpoint.info, &checkpoint.signature) {
+                Ok(digests) => Some(Checkpoint { dir_fd: checkpoint.dirFd, digests }),
+                Err(e) => {
+                    warn!("Ignoring the checkpoint: {:?}", e);
+                    None
+                }
+            }
+        });
+        let exit_code = odrefresh(
+            &self.odrefresh_path,
+            args,
+            checkpoint.as_ref(),
+            authfs_service,
+            |output_dir, info_path, kind| {
+                // authfs only shows us the files we created, so it's ok to just sign everything
+                // under the output directory.
+                let mut artifact_signer = ArtifactSigner::new(output_dir);
+                add_artifacts(output_dir, &mut artifact_signer)?;
+
+                artifact_signer.write_info_and_signature(info_path, kind)
+            },
+        )
         .context("odrefresh failed")?;
         Ok(exit_code as i8)
     }
@@ -21,17 +21,25 @@
 use anyhow::{ensure, Context, Result};
 use clap::arg;
 use dm::{crypt::CipherType, util};
-use log::{error, info};
+use log::{error, info, warn};
 use std::ffi::CString;
-use std::fs::{create_dir_all, OpenOptions};
-use std::io::{Error, Read, Write};
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{Error, Read, Seek, Write};
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
 const MK2FS_BIN: &str = "/system/bin/mke2fs";
 const UNFORMATTED_STORAGE_MAGIC: &str = "UNFORMATTED-STORAGE";
+/// Value of the byte after UNFORMATTED_STORAGE_MAGIC with which the host asks for the filesystem
+/// to be created without initializing the inode tables and journal. Must match the vm tool.
+const FORMAT_LAZY: u8 = 1;
+const EXT4_BLOCK_SIZE: u64 = 4096;
+
+// EXT4_IOC_RESIZE_FS, from linux/ext4.h.
+nix::ioctl_write_ptr!(ext4_ioc_resize_fs, b'f', 16, u64);
 
 fn main() {
     android_logger::init_once(
@@ -82,21 +90,43 @@ fn encryptedstore_init(blkdevice: &Path, key: &str, mountpoint: &Path) -> Result
         blkdevice
     );
 
-    let needs_formatting =
+    let format =
         needs_formatting(blkdevice).context("Unable to check if formatting is required")?;
+    let needs_formatting = format.is_some();
     let crypt_device =
         enable_crypt(blkdevice, key, "cryptdev").context("Unable to map crypt device")?;
 
     // We might need to format it with filesystem if this is a "seen-for-the-first-time" device.
-    if needs_formatting {
-        info!("Freshly formatting the crypt device");
-        format_ext4(&crypt_device)?;
+    if let Some(format) = format {
+        info!("Freshly formatting the crypt device ({format:?})");
+        format_ext4(&crypt_device, format)?;
     }
     mount(&crypt_device, mountpoint)
         .with_context(|| format!("Unable to mount {:?}", crypt_device))?;
     if cfg!(multi_tenant) && needs_formatting {
         set_root_dir_permissions(mountpoint)?;
     }
+    // The host may have grown the storage since the last boot, at the request of the payload.
+    // The filesystem is usable as it is, so failing to grow it mustn't keep the VM from booting.
+    if !needs_formatting {
+        if let Err(e) = grow_ext4(&crypt_device, mountpoint) {
+            warn!("Unable to grow the filesystem, keeping its size: {e:?}");
+        }
+    }
+    Ok(())
+}
+
+/// Grows the filesystem mounted at `mountpoint` to fill `device`, if it is larger. This is done
+/// online, so that it doesn't delay the boot.
+fn grow_ext4(device: &Path, mountpoint: &Path) -> Result<()> {
+    let blocks = util::blkgetsize64(device)? / EXT4_BLOCK_SIZE;
+    let root =
+        File::open(mountpoint).with_context(|| format!("Failed to open {:?}", mountpoint))?;
+    // SAFETY: The ioctl only reads the block count, which outlives the call, and doesn't retain
+    // the pointer. The kernel does nothing if the filesystem already has that many blocks.
+    unsafe { ext4_ioc_resize_fs(root.as_raw_fd(), &blocks) }
+        .context("EXT4_IOC_RESIZE_FS failed")?;
+    info!("Grew the filesystem to {blocks} blocks");
     Ok(())
 }
 
@@ -126,32 +156,56 @@ fn enable_crypt(data_device: &Path, key: &str, name: &str) -> Result<PathBuf> {
     dm.create_crypt_device(name, &target).context("Failed to create dm-crypt device")
 }
 
+/// How the host asked for the filesystem to be created.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    /// The inode tables and journal are initialized by mke2fs.
+    Default,
+    /// The inode tables and journal aren't initialized, which makes the first boot faster with
+    /// large storage. The kernel initializes the inode tables in the background.
+    Lazy,
+}
+
+/// Returns how to format the storage given the start of the disk, if it needs to be formatted.
+fn parse_header(header: &[u8; UNFORMATTED_STORAGE_MAGIC.len() + 1]) -> Option<Format> {
+    let (magic, format) = header.split_at(UNFORMATTED_STORAGE_MAGIC.len());
+    if magic != UNFORMATTED_STORAGE_MAGIC.as_bytes() {
+        return None;
+    }
+    // Older hosts leave the byte zeroed.
+    Some(if format[0] == FORMAT_LAZY { Format::Lazy } else { Format::Default })
+}
+
 // The disk contains UNFORMATTED_STORAGE_MAGIC to indicate we need to format the crypt device.
 // This function looks for it, zeroing it, if present.
-fn needs_formatting(data_device: &Path) -> Result<bool> {
+fn needs_formatting(data_device: &Path) -> Result<Option<Format>> {
     let mut file = OpenOptions::new()
         .read(true)
         .write(true)
         .open(data_device)
         .with_context(|| format!("Failed to open {:?}", data_device))?;
 
-    let mut buf = [0; UNFORMATTED_STORAGE_MAGIC.len()];
+    let mut buf = [0; UNFORMATTED_STORAGE_MAGIC.len() + 1];
     file.read_exact(&mut buf)?;
 
-    if buf == UNFORMATTED_STORAGE_MAGIC.as_bytes() {
+    let format = parse_header(&buf);
+    if format.is_some() {
         buf.fill(0);
+        file.rewind()?;
         file.write_all(&buf)?;
-        return Ok(true);
     }
-    Ok(false)
+    Ok(format)
 }
 
-fn format_ext4(device: &Path) -> Result<()> {
-    let root_dir_uid_gid = format!(
+fn format_ext4(device: &Path, format: Format) -> Result<()> {
+    let mut extended_options = format!(
         "root_owner={}:{}",
         microdroid_uids::ROOT_UID,
         microdroid_uids::MICRODROID_PAYLOAD_GID
     );
+    if format == Format::Lazy {
+        extended_options.push_str(",lazy_itable_init=1,lazy_journal_init=1");
+    }
     let mkfs_options = [
         "-j", // Create appropriate sized journal
         /* metadata_csum: enabled for filesystem integrity
@@ -161,7 +215,7 @@ fn format_ext4(device: &Path) -> Result<()> {
         "-O metadata_csum, extents, 64bit",
         "-b 4096", // block size in the filesystem,
         "-E",
-        &root_dir_uid_gid,
+        &extended_options,
     ];
     let mut cmd = Command::new(MK2FS_BIN);
     let status = cmd
@@ -211,4 +265,26 @@ mod tests {
         // Check that the command parsing has been configured in a valid way.
         clap_command().debug_assert();
     }
+
+    fn header(magic: &str, format: u8) -> [u8; UNFORMATTED_STORAGE_MAGIC.len() + 1] {
+        let mut header = [0; UNFORMATTED_STORAGE_MAGIC.len() + 1];
+        header[..magic.len()].copy_from_slice(magic.as_bytes());
+        header[magic.len()] = format;
+        header
+    }
+
+    #[test]
+    fn formatted_storage_is_left_as_is() {
+        assert_eq!(parse_header(&[0; UNFORMATTED_STORAGE_MAGIC.len() + 1]), None);
+        assert_eq!(parse_header(&header("UNFORMATTED-STORAGF", FORMAT_LAZY)), None);
+    }
+
+    #[test]
+    fn unformatted_storage_is_formatted_as_requested() {
+        let magic = UNFORMATTED_STORAGE_MAGIC;
+        assert_eq!(parse_header(&header(magic, 0)), Some(Format::Default));
+        assert_eq!(parse_header(&header(magic, FORMAT_LAZY)), Some(Format::Lazy));
+        // Unknown requests fall back to what older VMs did.
+        assert_eq!(parse_header(&header(magic, 0xff)), Some(Format::Default));
+    }
 }
@@ -147,7 +147,12 @@ fn format_payload_config_descriptor(
             (cbor!(-71000)?, cbor!(payload_config_path)?)
         }
         PayloadMetadata::Config(payload_config) => {
-            (cbor!(-71001)?, cbor!({1 => payload_config.payload_binary_name})?)
+            let mut config = vec![(cbor!(1)?, cbor!(payload_config.payload_binary_name)?)];
+            // Only present if set, so that the descriptor of payloads in the main APK is unchanged.
+            if let Some(index) = payload_config.payload_extra_apk_index {
+                config.push((cbor!(2)?, cbor!(index)?));
+            }
+            (cbor!(-71001)?, Value::Map(config))
         }
         _ => bail!("Failed to match the payload against a config type: {:?}", payload),
     });
@@ -212,6 +217,27 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn payload_metadata_with_config_in_extra_apk_formats_correctly() -> Result<()> {
+        let payload_config = PayloadConfig {
+            payload_binary_name: "payload_binary".to_string(),
+            extra_apk_count: 2,
+            payload_extra_apk_index: Some(1),
+            ..Default::default()
+        };
+        let payload_metadata = PayloadMetadata::Config(payload_config);
+        let config_descriptor =
+            format_payload_config_descriptor(&payload_metadata, NO_SUBCOMPONENTS)?;
+        static EXPECTED_CONFIG_DESCRIPTOR: &[u8] = &[
+            0xa2, 0x3a, 0x00, 0x01, 0x11, 0x71, 0x72, 0x4d, 0x69, 0x63, 0x72, 0x6f, 0x64, 0x72,
+            0x6f, 0x69, 0x64, 0x20, 0x70, 0x61, 0x79, 0x6c, 0x6f, 0x61, 0x64, 0x3a, 0x00, 0x01,
+            0x15, 0x58, 0xa2, 0x01, 0x6e, 0x70, 0x61, 0x79, 0x6c, 0x6f, 0x61, 0x64, 0x5f, 0x62,
+            0x69, 0x6e, 0x61, 0x72, 0x79, 0x02, 0x01,
+        ];
+        assert_eq_bytes(EXPECTED_CONFIG_DESCRIPTOR, &config_descriptor);
+        Ok(())
+    }
+
     #[test]
     fn payload_metadata_with_subcomponents_formats_correctly() -> Result<()> {
         let payload_metadata = PayloadMetadata::ConfigPath("/config_path".to_string());
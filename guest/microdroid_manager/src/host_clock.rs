@@ -0,0 +1,107 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bounds on the time reported by the host.
+//!
+//! The time can't be authenticated, as the host is its only source, but the host can be kept from
+//! rewinding it: it can't be before Microdroid was built, and, for protected VMs, it can't go back
+//! from what the host reported earlier, by more than the tolerance of the clocks. This keeps a
+//! payload from being made to accept e.g. a certificate which expired since it last checked.
+
+use anyhow::{ensure, Result};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How far the time reported by the host may go back from the earlier reports, given how much
+/// time passed in the VM since, e.g. as the estimates of the round trips differ.
+const MAX_REWIND: Duration = Duration::from_secs(1);
+
+/// Checks the times reported by the host against the earliest times they can be.
+#[derive(Debug)]
+pub struct HostClock {
+    /// The earliest time the host may report, in milliseconds since the Unix epoch.
+    floor_millis: i64,
+    /// Whether the host is trusted, in which case its clock may be set back.
+    host_trusted: bool,
+    /// The latest time reported by the host, and when the VM got it.
+    last: Mutex<Option<(i64, Instant)>>,
+}
+
+impl HostClock {
+    /// Creates a `HostClock` for times which can't be before `floor_millis`, e.g. the build time.
+    pub fn new(floor_millis: i64, host_trusted: bool) -> HostClock {
+        HostClock { floor_millis, host_trusted, last: Mutex::new(None) }
+    }
+
+    /// Checks `millis`, the time which the host reported, as the VM got it at `now`.
+    pub fn check(&self, millis: i64, now: Instant) -> Result<()> {
+        ensure!(
+            millis >= self.floor_millis,
+            "The host reported time {millis}, before the build time {}",
+            self.floor_millis
+        );
+        let mut last = self.last.lock().unwrap();
+        if let (false, Some((last_millis, last_now))) = (self.host_trusted, *last) {
+            let elapsed = now.saturating_duration_since(last_now).saturating_sub(MAX_REWIND);
+            let earliest =
+                last_millis.saturating_add(elapsed.as_millis().try_into().unwrap_or(i64::MAX));
+            ensure!(
+                millis >= earliest,
+                "The host reported time {millis}, before {earliest} given its earlier reports"
+            );
+        }
+        *last = Some((millis, now));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUILD_MILLIS: i64 = 1_700_000_000_000;
+
+    #[test]
+    fn rejects_time_before_build() {
+        let clock = HostClock::new(BUILD_MILLIS, true);
+        assert!(clock.check(BUILD_MILLIS - 1, Instant::now()).is_err());
+        assert!(clock.check(BUILD_MILLIS, Instant::now()).is_ok());
+    }
+
+    #[test]
+    fn untrusted_host_cannot_rewind_time() -> Result<()> {
+        let clock = HostClock::new(BUILD_MILLIS, false);
+        let start = Instant::now();
+        let millis = BUILD_MILLIS + 1_000_000;
+        clock.check(millis, start)?;
+
+        // Ten seconds later in the VM, the host must report at least nine seconds later.
+        let later = start + Duration::from_secs(10);
+        assert!(clock.check(millis + 8_000, later).is_err());
+        clock.check(millis + 9_500, later)?;
+        // The time may jump forward, e.g. as the clock of the host is corrected.
+        clock.check(millis + 3_600_000, later)?;
+        assert!(clock.check(millis + 9_500, later).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn trusted_host_may_set_time_back() -> Result<()> {
+        let clock = HostClock::new(BUILD_MILLIS, true);
+        let start = Instant::now();
+        clock.check(BUILD_MILLIS + 1_000_000, start)?;
+        clock.check(BUILD_MILLIS + 1_000, start + Duration::from_secs(10))?;
+        Ok(())
+    }
+}
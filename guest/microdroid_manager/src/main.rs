@@ -15,6 +15,7 @@
 //! Microdroid Manager
 
 mod dice;
+mod host_clock;
 mod instance;
 mod ioutil;
 mod payload;
@@ -22,6 +23,7 @@ mod swap;
 mod verify;
 mod vm_payload_service;
 mod vm_secret;
+mod vm_service_connection;
 
 use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::ErrorCode::ErrorCode;
 use android_system_virtualmachineservice::aidl::android::system::virtualmachineservice::IVirtualMachineService::IVirtualMachineService;
@@ -29,39 +31,42 @@ use android_system_virtualization_payload::aidl::android::system::virtualization
     VM_APK_CONTENTS_PATH,
     VM_PAYLOAD_SERVICE_SOCKET_NAME,
     ENCRYPTEDSTORE_MOUNTPOINT,
+    HOST_CA_CERTIFICATES_PATH,
+    SHARED_DIRECTORIES_PATH,
 };
 
 use crate::dice::dice_derivation;
 use crate::instance::{InstanceDisk, MicrodroidData};
 use crate::verify::verify_payload;
 use crate::vm_payload_service::register_vm_payload_service;
+use crate::vm_service_connection::VmServiceConnection;
 use anyhow::{anyhow, bail, ensure, Context, Error, Result};
 use binder::Strong;
 use dice_driver::DiceDriver;
 use keystore2_crypto::ZVec;
-use libc::VMADDR_CID_HOST;
 use log::{error, info};
-use microdroid_metadata::{Metadata, PayloadMetadata};
+use microdroid_metadata::{Metadata, PayloadMetadata, SharedDirectory};
 use microdroid_payload_config::{ApkConfig, OsConfig, Task, TaskType, VmPayloadConfig};
-use nix::mount::{umount2, MntFlags};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
 use nix::sys::signal::Signal;
 use payload::load_metadata;
-use rpcbinder::RpcSession;
 use rustutils::sockets::android_get_control_socket;
 use rustutils::system_properties;
 use rustutils::system_properties::PropertyWatcher;
 use secretkeeper_comm::data_types::ID_SIZE;
 use std::borrow::Cow::{Borrowed, Owned};
 use std::env;
-use std::ffi::CString;
-use std::fs::{self, create_dir, File, OpenOptions};
+use std::ffi::{CString, OsStr};
+use std::fs::{self, create_dir, create_dir_all, File, OpenOptions};
 use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
 use std::os::unix::io::OwnedFd;
 use std::os::unix::process::CommandExt;
 use std::os::unix::process::ExitStatusExt;
 use std::path::Path;
 use std::process::{Child, Command, Stdio};
 use std::str;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use vm_secret::VmSecret;
 
@@ -101,6 +106,11 @@ enum MicrodroidError {
     PayloadInvalidConfig(String),
 }
 
+/// The payload was killed by a signal, with the given number and name.
+#[derive(thiserror::Error, Debug)]
+#[error("Payload exited due to signal: {0} ({1})")]
+struct PayloadCrashed(i32, &'static str);
+
 fn translate_error(err: &Error) -> (ErrorCode, String) {
     if let Some(e) = err.downcast_ref::<MicrodroidError>() {
         match e {
@@ -139,6 +149,11 @@ fn write_death_reason_to_serial(err: &Error) -> Result<()> {
         // information.
         Owned(format!("MICRODROID_UNKNOWN_RUNTIME_ERROR|{:?}", err))
     };
+    // The crash of the payload is reported on a line of its own, before the death reason.
+    let death_reason = match err.downcast_ref::<PayloadCrashed>() {
+        Some(crash) => Owned(format!("GUEST_CRASH|PAYLOAD_CRASHED|{crash}\n{death_reason}")),
+        None => death_reason,
+    };
 
     for chunk in death_reason.as_bytes().chunks(16) {
         // TODO(b/220071963): Sometimes, sending more than 16 bytes at once makes MM hang.
@@ -210,11 +225,15 @@ fn try_main() -> Result<()> {
     swap::init_swap().context("Failed to initialize swap")?;
     info!("swap enabled.");
 
-    let service = get_vms_rpc_binder()
+    let vm_service = VmServiceConnection::connect()
         .context("cannot connect to VirtualMachineService")
         .map_err(|e| MicrodroidError::FailedToConnectToVirtualizationService(e.to_string()))?;
+    if let Err(e) = vm_service.get().notifyKernelBooted() {
+        // Only used for metrics, so not fatal.
+        error!("Failed to notify kernel booted: {e:?}");
+    }
 
-    match try_run_payload(&service, vm_payload_service_fd) {
+    match try_run_payload(&vm_service, vm_payload_service_fd) {
         Ok(code) => {
             if code == 0 {
                 info!("task successfully finished");
@@ -230,12 +249,12 @@ fn try_main() -> Result<()> {
             };
 
             info!("notifying payload finished");
-            service.notifyPayloadFinished(code)?;
+            vm_service.get().notifyPayloadFinished(code)?;
             Ok(())
         }
         Err(err) => {
             let (error_code, message) = translate_error(&err);
-            service.notifyError(error_code, &message)?;
+            vm_service.get().notifyError(error_code, &message)?;
             Err(err)
         }
     }
@@ -298,9 +317,10 @@ fn verify_payload_with_instance_img(
 }
 
 fn try_run_payload(
-    service: &Strong<dyn IVirtualMachineService>,
+    vm_service: &Arc<VmServiceConnection>,
     vm_payload_service_fd: OwnedFd,
 ) -> Result<i32> {
+    let service = &vm_service.get();
     let metadata = load_metadata().context("Failed to load payload metadata")?;
     let dice = if Path::new(DICE_CHAIN_FILE).exists() {
         DiceDriver::from_file(Path::new(DICE_CHAIN_FILE))
@@ -319,6 +339,10 @@ fn try_run_payload(
         verify_payload_with_instance_img(&metadata, &dice)?
     };
 
+    // Not part of the DICE derivation; the certificates are untrusted input from the host.
+    let share_host_ca_certificates = metadata.share_host_ca_certificates;
+    let shared_directories = metadata.shared_directories;
+    let host_locales = metadata.host_locales;
     let payload_metadata = metadata.payload.ok_or_else(|| {
         MicrodroidError::PayloadInvalidConfig("No payload config in metadata".to_string())
     })?;
@@ -382,10 +406,20 @@ fn try_run_payload(
     );
     mount_extra_apks(&config, &mut zipfuse)?;
 
+    if share_host_ca_certificates {
+        install_host_ca_certificates(service).context("Failed to install host CA certificates")?;
+    }
+    mount_shared_directories(&shared_directories)?;
+
+    let requested_exit_code = Arc::new(Mutex::new(None));
     register_vm_payload_service(
         allow_restricted_apis,
-        service.clone(),
+        vm_service.clone(),
         vm_secret,
+        host_locales,
+        // Strict boot is only set by pvmfw, for protected VMs, which don't trust the host.
+        !is_strict_boot(),
+        requested_exit_code.clone(),
         vm_payload_service_fd,
     )?;
 
@@ -425,8 +459,14 @@ fn try_run_payload(
     system_properties::write("microdroid_manager.init_done", "1")
         .context("set microdroid_manager.init_done")?;
 
+    // A warm VM waits here until it is claimed by a client.
+    vm_service
+        .get()
+        .waitUntilPayloadReleased()
+        .context("Failed to wait for the payload to be released")?;
+
     info!("boot completed, time to run payload");
-    exec_task(task, service).context("Failed to run payload")
+    exec_task(task, &vm_service.get(), &requested_exit_code).context("Failed to run payload")
 }
 
 fn post_payload_work() -> Result<()> {
@@ -455,17 +495,27 @@ fn post_payload_work() -> Result<()> {
 }
 
 fn mount_extra_apks(config: &VmPayloadConfig, zipfuse: &mut Zipfuse) -> Result<()> {
+    // The extra APK holding the payload binary, if any, is mounted like the main APK, so that the
+    // binary can be executed.
+    let payload_apk_index = config.task.as_ref().and_then(|task| task.extra_apk_index);
     // For now, only the number of apks is important, as the mount point and dm-verity name is fixed
     for i in 0..config.extra_apks.len() {
         let mount_dir = format!("/mnt/extra-apk/{i}");
         create_dir(Path::new(&mount_dir)).context("Failed to create mount dir for extra apks")?;
 
-        let mount_for_exec =
-            if cfg!(multi_tenant) { MountForExec::Allowed } else { MountForExec::Disallowed };
+        let (mount_for_exec, context) = if payload_apk_index == Some(i) {
+            (MountForExec::Allowed, "system_file")
+        } else if cfg!(multi_tenant) {
+            (MountForExec::Allowed, "extra_apk_file")
+        } else {
+            (MountForExec::Disallowed, "extra_apk_file")
+        };
+        // The APKs are read through their dm-verity device, set up by verify_payload with their
+        // idsig, like the main APK.
         // These run asynchronously in parallel - we wait later for them to complete.
         zipfuse.mount(
             mount_for_exec,
-            "fscontext=u:object_r:zipfusefs:s0,context=u:object_r:extra_apk_file:s0",
+            &format!("fscontext=u:object_r:zipfusefs:s0,context=u:object_r:{context}:s0"),
             Path::new(&format!("/dev/block/mapper/extra-apk-{i}")),
             Path::new(&mount_dir),
             format!("microdroid_manager.extra_apk.mounted.{i}"),
@@ -475,13 +525,56 @@ fn mount_extra_apks(config: &VmPayloadConfig, zipfuse: &mut Zipfuse) -> Result<(
     Ok(())
 }
 
-fn get_vms_rpc_binder() -> Result<Strong<dyn IVirtualMachineService>> {
-    // The host is running a VirtualMachineService for this VM on a port equal
-    // to the CID of this VM.
-    let port = vsock::get_local_cid().context("Could not determine local CID")?;
-    RpcSession::new()
-        .setup_vsock_client(VMADDR_CID_HOST, port)
-        .context("Could not connect to IVirtualMachineService")
+/// Copies the current system CA certificates of the host into a read-only directory for the
+/// payload.
+fn install_host_ca_certificates(service: &Strong<dyn IVirtualMachineService>) -> Result<()> {
+    let certificates = service.getHostCaCertificates()?;
+    let dir = Path::new(HOST_CA_CERTIFICATES_PATH);
+    create_dir(dir).with_context(|| format!("Failed to create {dir:?}"))?;
+    for certificate in certificates {
+        let name = &certificate.name;
+        // Don't let the host write outside of the directory.
+        ensure!(
+            Path::new(name).file_name() == Some(OsStr::new(name)),
+            "Invalid certificate name {name:?}"
+        );
+        let path = dir.join(name);
+        fs::write(&path, &certificate.contents)
+            .with_context(|| format!("Failed to write {path:?}"))?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o444))?;
+    }
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o555))?;
+    info!("Installed host CA certificates to {dir:?}");
+    Ok(())
+}
+
+/// Mounts the directories which the host shares through virtio-fs for the payload.
+fn mount_shared_directories(shared_directories: &[SharedDirectory]) -> Result<()> {
+    for shared_directory in shared_directories {
+        let tag = shared_directory.tag.as_str();
+        // Don't let the host mount outside of the directory.
+        ensure!(
+            Path::new(tag).file_name() == Some(OsStr::new(tag)),
+            "Invalid shared directory tag {tag:?}"
+        );
+        let mount_point = Path::new(SHARED_DIRECTORIES_PATH).join(tag);
+        create_dir_all(&mount_point)
+            .with_context(|| format!("Failed to create {mount_point:?}"))?;
+        let mut flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC;
+        if !shared_directory.writable {
+            flags |= MsFlags::MS_RDONLY;
+        }
+        mount(
+            Some(tag),
+            &mount_point,
+            Some("virtiofs"),
+            flags,
+            Some("context=u:object_r:shared_directory_file:s0"),
+        )
+        .with_context(|| format!("Failed to mount shared directory {tag}"))?;
+        info!("Mounted shared directory {tag} to {mount_point:?}");
+    }
+    Ok(())
 }
 
 fn is_strict_boot() -> bool {
@@ -592,9 +685,17 @@ fn load_config(payload_metadata: PayloadMetadata) -> Result<VmPayloadConfig> {
             Ok(serde_json::from_reader(file)?)
         }
         PayloadMetadata::Config(payload_config) => {
+            let extra_apk_index = payload_config.payload_extra_apk_index;
+            if let Some(i) = extra_apk_index {
+                ensure!(
+                    i < payload_config.extra_apk_count,
+                    "Payload binary is in missing extra APK #{i}"
+                );
+            }
             let task = Task {
                 type_: TaskType::MicrodroidLauncher,
                 command: payload_config.payload_binary_name,
+                extra_apk_index: extra_apk_index.map(|i| i as usize),
             };
             // We don't care about the paths, only the number of extra APKs really matters.
             let extra_apks = (0..payload_config.extra_apk_count)
@@ -640,8 +741,13 @@ fn load_crashkernel_if_supported() -> Result<()> {
     Ok(())
 }
 
-/// Executes the given task.
-fn exec_task(task: &Task, service: &Strong<dyn IVirtualMachineService>) -> Result<i32> {
+/// Executes the given task. Returns the exit code which the payload requested to exit with, if
+/// any, or else the exit code of its process.
+fn exec_task(
+    task: &Task,
+    service: &Strong<dyn IVirtualMachineService>,
+    requested_exit_code: &Mutex<Option<i32>>,
+) -> Result<i32> {
     info!("executing main task {:?}...", task);
     let mut command = match task.type_ {
         TaskType::Executable => {
@@ -650,7 +756,7 @@ fn exec_task(task: &Task, service: &Strong<dyn IVirtualMachineService>) -> Resul
         }
         TaskType::MicrodroidLauncher => {
             let mut command = Command::new("/system/bin/microdroid_launcher");
-            command.arg(find_library_path(&task.command)?);
+            command.arg(find_library_path(&task.command, task.extra_apk_index)?);
             command.uid(microdroid_uids::MICRODROID_PAYLOAD_UID);
             command.gid(microdroid_uids::MICRODROID_PAYLOAD_GID);
             command
@@ -676,24 +782,33 @@ fn exec_task(task: &Task, service: &Strong<dyn IVirtualMachineService>) -> Resul
     service.notifyPayloadStarted()?;
 
     let exit_status = command.spawn()?.wait()?;
+    if let Some(exit_code) = *requested_exit_code.lock().unwrap() {
+        info!("Payload requested to exit with code {exit_code}, and ended with {exit_status}");
+        return Ok(exit_code);
+    }
     match exit_status.code() {
         Some(exit_code) => Ok(exit_code),
         None => Err(match exit_status.signal() {
-            Some(signal) => anyhow!(
-                "Payload exited due to signal: {} ({})",
+            Some(signal) => anyhow!(PayloadCrashed(
                 signal,
                 Signal::try_from(signal).map_or("unknown", |s| s.as_str())
-            ),
+            )),
             None => anyhow!("Payload has neither exit code nor signal"),
         }),
     }
 }
 
-fn find_library_path(name: &str) -> Result<String> {
+/// Returns the path of the library `name` in the main APK, or in the extra APK with the given
+/// index, e.g. a split APK.
+fn find_library_path(name: &str, extra_apk_index: Option<usize>) -> Result<String> {
     let mut watcher = PropertyWatcher::new("ro.product.cpu.abilist")?;
     let value = watcher.read(|_name, value| Ok(value.trim().to_string()))?;
     let abi = value.split(',').next().ok_or_else(|| anyhow!("no abilist"))?;
-    let path = format!("{}/lib/{}/{}", VM_APK_CONTENTS_PATH, abi, name);
+    let apk_contents_path = match extra_apk_index {
+        Some(i) => format!("/mnt/extra-apk/{i}"),
+        None => VM_APK_CONTENTS_PATH.to_owned(),
+    };
+    let path = format!("{}/lib/{}/{}", apk_contents_path, abi, name);
 
     let metadata = fs::metadata(&path).with_context(|| format!("Unable to access {}", path))?;
     if !metadata.is_file() {
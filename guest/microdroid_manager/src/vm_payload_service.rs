@@ -16,28 +16,78 @@
 
 use android_system_virtualization_payload::aidl::android::system::virtualization::payload::IVmPayloadService::{
     BnVmPayloadService, IVmPayloadService, VM_PAYLOAD_SERVICE_SOCKET_NAME, AttestationResult::AttestationResult,
-    STATUS_FAILED_TO_PREPARE_CSR_AND_KEY
+    STATUS_FAILED_TO_PREPARE_CSR_AND_KEY, SEALING_POLICY_EXACT_IMAGES, SEALING_POLICY_SAME_AUTHORITY,
+    CPU_VULNERABILITY_MELTDOWN, CPU_VULNERABILITY_SPECTRE_V1, CPU_VULNERABILITY_SPECTRE_V2,
+    CPU_VULNERABILITY_SPEC_STORE_BYPASS, CPU_MITIGATION_STATE_UNKNOWN,
+    CPU_MITIGATION_STATE_NOT_AFFECTED, CPU_MITIGATION_STATE_MITIGATED,
+    CPU_MITIGATION_STATE_VULNERABLE, CAPABILITY_REMOTE_ATTESTATION, CAPABILITY_ENCRYPTED_STORAGE,
+    CAPABILITY_NETWORK, ENCRYPTEDSTORE_MOUNTPOINT, CurrentTime::CurrentTime,
+    TIME_TRUST_LEVEL_TRUSTED_HOST, TIME_TRUST_LEVEL_UNTRUSTED_HOST,
+};
+use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::IShutdownCallback::{
+    BnShutdownCallback, IShutdownCallback,
+};
+use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::ISnapshotCallback::{
+    BnSnapshotCallback, ISnapshotCallback,
+};
+use android_system_virtualmachineservice::aidl::android::system::virtualmachineservice::IVirtualMachineService::{
+    IVirtualMachineService, HOST_FILE_PROVIDED,
 };
-use android_system_virtualmachineservice::aidl::android::system::virtualmachineservice::IVirtualMachineService::IVirtualMachineService;
 use anyhow::{anyhow, Context, Result};
 use avflog::LogResult;
-use binder::{Interface, BinderFeatures, ExceptionCode, Strong, IntoBinderResult, Status};
-use client_vm_csr::{generate_attestation_key_and_csr, ClientVmAttestationData};
-use log::info;
+use binder::{
+    Interface, BinderFeatures, ExceptionCode, ParcelFileDescriptor, Strong, IntoBinderResult,
+    Status,
+};
+use client_vm_csr::{generate_attestation_key_and_csr, open_sealed_key, ClientVmAttestationData};
+use log::{info, warn};
 use rpcbinder::RpcServer;
-use crate::vm_secret::VmSecret;
-use std::os::unix::io::OwnedFd;
+use rustutils::system_properties;
+use service_vm_comm::SealingPolicy;
+use crate::host_clock::HostClock;
+use crate::vm_secret::{VmSecret, ROLLBACK_COUNTERS};
+use crate::vm_service_connection::VmServiceConnection;
+use libc::VMADDR_CID_HOST;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read};
+use std::net::Shutdown;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use vsock::VsockStream;
+
+/// String list of `<vulnerability>=<state>` entries, set by virtmgr.
+const CPU_MITIGATIONS_PATH: &str = "/proc/device-tree/avf/untrusted/cpu-mitigations";
+
+/// String list of the features enabled by the host, set by virtmgr.
+const CAPABILITIES_PATH: &str = "/proc/device-tree/avf/untrusted/capabilities";
+
+/// Virtio console whose input and output are connected to the console fds given by the host.
+/// Opening it for the payload needs the policy listed in docs/platform_sepolicy.md.
+const CONSOLE_PATH: &str = "/dev/hvc0";
 
 /// Implementation of `IVmPayloadService`.
 struct VmPayloadService {
     allow_restricted_apis: bool,
-    virtual_machine_service: Strong<dyn IVirtualMachineService>,
+    virtual_machine_service: Arc<VmServiceConnection>,
+    /// The callbacks registered with the host, to register them again if the session to the host
+    /// is set up again.
+    host_callbacks: Arc<Mutex<HostCallbacks>>,
     secret: VmSecret,
+    host_locales: Vec<String>,
+    /// Whether the host is trusted, i.e. the VM isn't protected.
+    host_trusted: bool,
+    /// Bounds on the time reported by the host.
+    host_clock: HostClock,
+    /// The exit code which the payload requested to exit with, if any.
+    requested_exit_code: Arc<Mutex<Option<i32>>>,
 }
 
 impl IVmPayloadService for VmPayloadService {
     fn notifyPayloadReady(&self) -> binder::Result<()> {
-        self.virtual_machine_service.notifyPayloadReady()
+        self.virtual_machine_service.get().notifyPayloadReady()
     }
 
     fn getVmInstanceSecret(&self, identifier: &[u8], size: i32) -> binder::Result<Vec<u8>> {
@@ -91,24 +141,413 @@ impl IVmPayloadService for VmPayloadService {
                 )
             })
             .with_log()?;
-        let cert_chain = self.virtual_machine_service.requestAttestation(&csr, test_mode)?;
+        let cert_chain = self.virtual_machine_service.get().requestAttestation(&csr, test_mode)?;
         Ok(AttestationResult {
             privateKey: private_key.as_slice().to_vec(),
             certificateChain: cert_chain,
         })
     }
+
+    fn requestHostFile(&self, mime_type: &str) -> binder::Result<Option<ParcelFileDescriptor>> {
+        let port = self.virtual_machine_service.get().requestHostFile(mime_type)?;
+        let mut stream = VsockStream::connect_with_cid_port(VMADDR_CID_HOST, port as u32)
+            .context("Failed to connect to the host file stream")
+            .with_log()
+            .or_service_specific_exception(-1)?;
+        stream
+            .shutdown(Shutdown::Write)
+            .context("Failed to make the host file stream read-only")
+            .with_log()
+            .or_service_specific_exception(-1)?;
+        // Blocks until the user has made a choice.
+        let mut answer = [0u8];
+        stream
+            .read_exact(&mut answer)
+            .context("Failed to read the answer to the host file request")
+            .with_log()
+            .or_service_specific_exception(-1)?;
+        if answer[0] != HOST_FILE_PROVIDED as u8 {
+            info!("No host file was chosen");
+            return Ok(None);
+        }
+        // SAFETY: ownership is transferred from stream to the new OwnedFd.
+        let fd = unsafe { OwnedFd::from_raw_fd(stream.into_raw_fd()) };
+        Ok(Some(ParcelFileDescriptor::new(fd)))
+    }
+
+    fn requestSealedKey(&self, policy: i32) -> binder::Result<Vec<u8>> {
+        let policy = match policy {
+            SEALING_POLICY_EXACT_IMAGES => SealingPolicy::ExactImages,
+            SEALING_POLICY_SAME_AUTHORITY => SealingPolicy::SameAuthority,
+            _ => {
+                return Err(anyhow!("Unknown sealing policy {policy}"))
+                    .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+            }
+        };
+        // The policy is passed as the challenge of the CSR, so that it is signed by this VM.
+        let ClientVmAttestationData { private_key, csr } =
+            generate_attestation_key_and_csr(&policy.to_challenge(), self.secret.dice_artifacts())
+                .map_err(|e| {
+                    Status::new_service_specific_error_str(
+                        STATUS_FAILED_TO_PREPARE_CSR_AND_KEY,
+                        Some(format!("Failed to prepare the CSR and key pair: {e:?}")),
+                    )
+                })
+                .with_log()?;
+        let csr = csr
+            .into_cbor_vec()
+            .map_err(|e| {
+                Status::new_service_specific_error_str(
+                    STATUS_FAILED_TO_PREPARE_CSR_AND_KEY,
+                    Some(format!("Failed to serialize CSR into CBOR: {e:?}")),
+                )
+            })
+            .with_log()?;
+        let sealed_key = self.virtual_machine_service.get().requestSealedKey(&csr)?;
+        let key = open_sealed_key(&private_key, self.secret.dice_artifacts(), &sealed_key)
+            .context("Failed to open the sealed key")
+            .with_log()
+            .or_service_specific_exception(-1)?;
+        Ok(key.to_vec())
+    }
+
+    fn setSnapshotCallback(
+        &self,
+        callback: Option<&Strong<dyn ISnapshotCallback>>,
+    ) -> binder::Result<()> {
+        // Binder objects of the payload can't be passed on to the host, so relay the calls.
+        let relay = callback.map(|payload_callback| {
+            BnSnapshotCallback::new_binder(
+                SnapshotCallbackRelay { payload_callback: payload_callback.clone() },
+                BinderFeatures::default(),
+            )
+        });
+        let mut host_callbacks = self.host_callbacks.lock().unwrap();
+        self.virtual_machine_service.get().setSnapshotCallback(relay.as_ref())?;
+        host_callbacks.snapshot = relay;
+        Ok(())
+    }
+
+    fn setShutdownCallback(
+        &self,
+        callback: Option<&Strong<dyn IShutdownCallback>>,
+    ) -> binder::Result<()> {
+        // Binder objects of the payload can't be passed on to the host, so relay the calls.
+        let relay = callback.map(|payload_callback| {
+            BnShutdownCallback::new_binder(
+                ShutdownCallbackRelay { payload_callback: payload_callback.clone() },
+                BinderFeatures::default(),
+            )
+        });
+        let mut host_callbacks = self.host_callbacks.lock().unwrap();
+        self.virtual_machine_service.get().setShutdownCallback(relay.as_ref())?;
+        host_callbacks.shutdown = relay;
+        Ok(())
+    }
+
+    fn getCpuMitigationState(&self, vulnerability: i32) -> binder::Result<i32> {
+        let name = match vulnerability {
+            CPU_VULNERABILITY_MELTDOWN => "meltdown",
+            CPU_VULNERABILITY_SPECTRE_V1 => "spectre_v1",
+            CPU_VULNERABILITY_SPECTRE_V2 => "spectre_v2",
+            CPU_VULNERABILITY_SPEC_STORE_BYPASS => "spec_store_bypass",
+            _ => {
+                return Err(anyhow!("Unknown CPU vulnerability {vulnerability}"))
+                    .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+            }
+        };
+        let cpu_mitigations = match fs::read(CPU_MITIGATIONS_PATH) {
+            Ok(cpu_mitigations) => cpu_mitigations,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(CPU_MITIGATION_STATE_UNKNOWN),
+            Err(e) => {
+                return Err(e)
+                    .context("Failed to read CPU mitigations")
+                    .with_log()
+                    .or_service_specific_exception(-1);
+            }
+        };
+        Ok(find_cpu_mitigation_state(&cpu_mitigations, name))
+    }
+
+    fn getCapabilities(&self) -> binder::Result<i32> {
+        let host_capabilities = match fs::read(CAPABILITIES_PATH) {
+            Ok(host_capabilities) => host_capabilities,
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => {
+                return Err(e)
+                    .context("Failed to read capabilities")
+                    .with_log()
+                    .or_service_specific_exception(-1);
+            }
+        };
+        let mut capabilities = parse_host_capabilities(&host_capabilities);
+        if Path::new(ENCRYPTEDSTORE_MOUNTPOINT).exists() {
+            capabilities |= CAPABILITY_ENCRYPTED_STORAGE;
+        }
+        Ok(capabilities)
+    }
+
+    fn openConsole(&self) -> binder::Result<Option<ParcelFileDescriptor>> {
+        // The payload must not get the console as its controlling terminal.
+        let console = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(CONSOLE_PATH)
+        {
+            Ok(console) => console,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                info!("The VM has no console");
+                return Ok(None);
+            }
+            Err(e) => {
+                return Err(e)
+                    .context("Failed to open the console")
+                    .with_log()
+                    .or_service_specific_exception(-1);
+            }
+        };
+        Ok(Some(ParcelFileDescriptor::new(console)))
+    }
+
+    fn startWatchdog(&self, interval_millis: i64) -> binder::Result<()> {
+        self.virtual_machine_service.get().startPayloadWatchdog(interval_millis)
+    }
+
+    fn kickWatchdog(&self) -> binder::Result<()> {
+        self.virtual_machine_service.get().kickPayloadWatchdog()
+    }
+
+    fn getHostLocales(&self) -> binder::Result<Vec<String>> {
+        Ok(self.host_locales.clone())
+    }
+
+    fn getCurrentTime(&self) -> binder::Result<CurrentTime> {
+        // The host samples its clock while the request is in flight, so the current time is at
+        // most a round trip away from it, and most likely half a round trip after it.
+        let start = Instant::now();
+        let host_millis = self.virtual_machine_service.get().getHostTimeMillis()?;
+        let now = Instant::now();
+        let round_trip_millis = i64::try_from((now - start).as_millis()).unwrap_or(i64::MAX);
+        let half_round_trip_millis = round_trip_millis.div_ceil(2);
+        let epoch_millis = host_millis.saturating_add(half_round_trip_millis);
+        self.host_clock
+            .check(epoch_millis, now)
+            .context("Rejected the time reported by the host")
+            .with_log()
+            .or_service_specific_exception(-1)?;
+        let trust_level = if self.host_trusted {
+            TIME_TRUST_LEVEL_TRUSTED_HOST
+        } else {
+            TIME_TRUST_LEVEL_UNTRUSTED_HOST
+        };
+        Ok(CurrentTime {
+            epochMillis: epoch_millis,
+            uncertaintyMillis: half_round_trip_millis,
+            trustLevel: trust_level,
+        })
+    }
+
+    fn readHostProperty(&self, name: &str) -> binder::Result<Option<String>> {
+        self.virtual_machine_service.get().readHostProperty(name)
+    }
+
+    fn growEncryptedStorage(&self, size_bytes: i64) -> binder::Result<i64> {
+        self.virtual_machine_service.get().growEncryptedStorage(size_bytes)
+    }
+
+    fn openPayloadLog(&self) -> binder::Result<ParcelFileDescriptor> {
+        let port = self.virtual_machine_service.get().openPayloadLog()?;
+        let stream = VsockStream::connect_with_cid_port(VMADDR_CID_HOST, port as u32)
+            .context("Failed to connect to the payload log stream")
+            .with_log()
+            .or_service_specific_exception(-1)?;
+        stream
+            .shutdown(Shutdown::Read)
+            .context("Failed to make the payload log stream write-only")
+            .with_log()
+            .or_service_specific_exception(-1)?;
+        // SAFETY: ownership is transferred from stream to the new OwnedFd.
+        let fd = unsafe { OwnedFd::from_raw_fd(stream.into_raw_fd()) };
+        Ok(ParcelFileDescriptor::new(fd))
+    }
+
+    fn readRollbackCounter(&self, counter: i32) -> binder::Result<i64> {
+        let counter = check_rollback_counter(counter)?;
+        let value = self
+            .secret
+            .read_rollback_counter(&self.virtual_machine_service.get(), counter)
+            .context("Failed to read the rollback counter")
+            .with_log()
+            .or_service_specific_exception(-1)?
+            .context("Rollback counters need Secretkeeper")
+            .or_binder_exception(ExceptionCode::UNSUPPORTED_OPERATION)?;
+        Ok(value.into())
+    }
+
+    fn incrementRollbackCounter(&self, counter: i32) -> binder::Result<i64> {
+        let counter = check_rollback_counter(counter)?;
+        let value = self
+            .secret
+            .increment_rollback_counter(&self.virtual_machine_service.get(), counter)
+            .context("Failed to increment the rollback counter")
+            .with_log()
+            .or_service_specific_exception(-1)?
+            .context("Rollback counters need Secretkeeper")
+            .or_binder_exception(ExceptionCode::UNSUPPORTED_OPERATION)?;
+        Ok(value.into())
+    }
+
+    fn requestExit(&self, exit_code: i32) -> binder::Result<()> {
+        info!("Payload is exiting with code {exit_code}");
+        *self.requested_exit_code.lock().unwrap() = Some(exit_code);
+        Ok(())
+    }
+}
+
+/// Returns the index of a rollback counter if it is valid.
+fn check_rollback_counter(counter: i32) -> binder::Result<usize> {
+    usize::try_from(counter)
+        .ok()
+        .filter(|&counter| counter < ROLLBACK_COUNTERS)
+        .with_context(|| format!("Invalid rollback counter {counter}"))
+        .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)
+}
+
+/// Converts the `capabilities` string list to CAPABILITY_* bits. Entries which aren't understood
+/// are ignored, as the host is untrusted.
+fn parse_host_capabilities(host_capabilities: &[u8]) -> i32 {
+    host_capabilities
+        .split(|&c| c == 0)
+        .map(|name| match name {
+            b"remote-attestation" => CAPABILITY_REMOTE_ATTESTATION,
+            b"network" => CAPABILITY_NETWORK,
+            _ => 0,
+        })
+        .fold(0, |capabilities, capability| capabilities | capability)
+}
+
+/// Finds the state of the given vulnerability in the `cpu-mitigations` string list. Entries which
+/// aren't understood are ignored, as the host is untrusted.
+fn find_cpu_mitigation_state(cpu_mitigations: &[u8], name: &str) -> i32 {
+    cpu_mitigations
+        .split(|&c| c == 0)
+        .filter_map(|entry| std::str::from_utf8(entry).ok()?.split_once('='))
+        .find(|(entry_name, _)| *entry_name == name)
+        .map_or(CPU_MITIGATION_STATE_UNKNOWN, |(_, state)| match state {
+            "not-affected" => CPU_MITIGATION_STATE_NOT_AFFECTED,
+            "mitigated" => CPU_MITIGATION_STATE_MITIGATED,
+            "vulnerable" => CPU_MITIGATION_STATE_VULNERABLE,
+            _ => CPU_MITIGATION_STATE_UNKNOWN,
+        })
+}
+
+/// Writes back the dirty data of the encrypted storage, if the VM has one, to its disk.
+fn sync_encrypted_storage() -> Result<()> {
+    let storage = match File::open(ENCRYPTEDSTORE_MOUNTPOINT) {
+        Ok(storage) => storage,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    // SAFETY: The file descriptor is valid, and syncfs doesn't touch memory.
+    if unsafe { libc::syncfs(storage.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Relays the snapshot notifications of the host to the callback of the payload.
+struct SnapshotCallbackRelay {
+    payload_callback: Strong<dyn ISnapshotCallback>,
+}
+
+impl Interface for SnapshotCallbackRelay {}
+
+impl ISnapshotCallback for SnapshotCallbackRelay {
+    fn onPreSnapshot(&self) -> binder::Result<()> {
+        info!("Notifying payload of snapshot");
+        self.payload_callback.onPreSnapshot()?;
+        // Write back what the payload flushed to the encrypted storage, which the host may be
+        // about to snapshot.
+        sync_encrypted_storage()
+            .context("Failed to sync the encrypted storage")
+            .with_log()
+            .or_service_specific_exception(-1)
+    }
+
+    fn onPostRestore(&self) -> binder::Result<()> {
+        info!("Notifying payload of restore");
+        self.payload_callback.onPostRestore()
+    }
+}
+
+/// Relays the shutdown requests of the host to the callback of the payload.
+struct ShutdownCallbackRelay {
+    payload_callback: Strong<dyn IShutdownCallback>,
+}
+
+impl Interface for ShutdownCallbackRelay {}
+
+impl IShutdownCallback for ShutdownCallbackRelay {
+    fn onShutdownRequested(&self) -> binder::Result<()> {
+        info!("Notifying payload of shutdown request");
+        self.payload_callback.onShutdownRequested()
+    }
+}
+
+/// The relays of the callbacks of the payload which are registered with the host.
+#[derive(Default)]
+struct HostCallbacks {
+    snapshot: Option<Strong<dyn ISnapshotCallback>>,
+    shutdown: Option<Strong<dyn IShutdownCallback>>,
+}
+
+impl HostCallbacks {
+    /// Registers the callbacks with `vm_service` of a new session to the host, which is only set
+    /// up again once the VM was migrated to a new crosvm process and restored.
+    fn register_again(&self, vm_service: &Strong<dyn IVirtualMachineService>) {
+        if let Err(e) = vm_service.setShutdownCallback(self.shutdown.as_ref()) {
+            warn!("Failed to register the shutdown callback again: {e:?}");
+        }
+        if let Err(e) = vm_service.setSnapshotCallback(self.snapshot.as_ref()) {
+            warn!("Failed to register the snapshot callback again: {e:?}");
+        }
+        // The host couldn't notify the payload of the restore, as the session was lost by then.
+        if let Some(snapshot) = &self.snapshot {
+            if let Err(e) = snapshot.onPostRestore() {
+                warn!("Failed to notify the payload of the restore: {e:?}");
+            }
+        }
+    }
 }
 
 impl Interface for VmPayloadService {}
 
 impl VmPayloadService {
-    /// Creates a new `VmPayloadService` instance from the `IVirtualMachineService` reference.
+    /// Creates a new `VmPayloadService` instance from the connection to `IVirtualMachineService`.
     fn new(
         allow_restricted_apis: bool,
-        vm_service: Strong<dyn IVirtualMachineService>,
+        vm_service: Arc<VmServiceConnection>,
         secret: VmSecret,
+        host_locales: Vec<String>,
+        host_trusted: bool,
+        requested_exit_code: Arc<Mutex<Option<i32>>>,
     ) -> VmPayloadService {
-        Self { allow_restricted_apis, virtual_machine_service: vm_service, secret }
+        let host_callbacks = Arc::new(Mutex::new(HostCallbacks::default()));
+        let callbacks = host_callbacks.clone();
+        vm_service.on_reconnect(Box::new(move |vm_service| {
+            callbacks.lock().unwrap().register_again(vm_service)
+        }));
+        Self {
+            allow_restricted_apis,
+            virtual_machine_service: vm_service,
+            host_callbacks,
+            secret,
+            host_locales,
+            host_trusted,
+            host_clock: HostClock::new(build_time_millis(), host_trusted),
+            requested_exit_code,
+        }
     }
 
     fn check_restricted_apis_allowed(&self) -> binder::Result<()> {
@@ -122,15 +561,32 @@ impl VmPayloadService {
     }
 }
 
+/// Returns when Microdroid was built, in milliseconds since the Unix epoch, or 0 if unknown.
+fn build_time_millis() -> i64 {
+    let build_time = system_properties::read("ro.build.date.utc").ok().flatten();
+    let build_secs = build_time.and_then(|secs| secs.parse::<i64>().ok()).unwrap_or(0);
+    build_secs.saturating_mul(1000)
+}
+
 /// Registers the `IVmPayloadService` service.
 pub(crate) fn register_vm_payload_service(
     allow_restricted_apis: bool,
-    vm_service: Strong<dyn IVirtualMachineService>,
+    vm_service: Arc<VmServiceConnection>,
     secret: VmSecret,
+    host_locales: Vec<String>,
+    host_trusted: bool,
+    requested_exit_code: Arc<Mutex<Option<i32>>>,
     vm_payload_service_fd: OwnedFd,
 ) -> Result<()> {
     let vm_payload_binder = BnVmPayloadService::new_binder(
-        VmPayloadService::new(allow_restricted_apis, vm_service, secret),
+        VmPayloadService::new(
+            allow_restricted_apis,
+            vm_service,
+            secret,
+            host_locales,
+            host_trusted,
+            requested_exit_code,
+        ),
         BinderFeatures::default(),
     );
 
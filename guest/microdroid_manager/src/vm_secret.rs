@@ -35,6 +35,8 @@ use secretkeeper_comm::data_types::request_response_impl::{
     StoreSecretRequest, GetSecretResponse, GetSecretRequest};
 use secretkeeper_comm::data_types::error::SecretkeeperError;
 use std::fs;
+use std::mem::size_of;
+use std::sync::Mutex;
 use zeroize::Zeroizing;
 
 const ENCRYPTEDSTORE_KEY_IDENTIFIER: &str = "encryptedstore_key";
@@ -60,6 +62,14 @@ const SALT_PAYLOAD_SERVICE: &[u8] = &[
     0x55, 0xF8, 0x08, 0x23, 0x81, 0x5F, 0xF5, 0x16, 0x20, 0x3E, 0xBE, 0xBA, 0xB7, 0xA8, 0x43, 0x92,
 ];
 
+/// Number of rollback counters of a VM instance, which fill a Secretkeeper secret.
+pub const ROLLBACK_COUNTERS: usize = SECRET_SIZE / size_of::<u32>();
+
+/// Prefix of the data hashed into the ID of the Secretkeeper entry holding the rollback counters.
+/// virtualizationservice derives the same ID from the instance ID, to delete the entry along with
+/// the secret of the instance.
+const ROLLBACK_COUNTERS_ID_PREFIX: &[u8] = b"android.system.virtualization.rollback_counters:";
+
 pub enum VmSecret {
     // V2 secrets are derived from 2 independently secured secrets:
     //      1. Secretkeeper protected secrets (skp secret).
@@ -69,13 +79,21 @@ pub enum VmSecret {
     // with downgraded images will not have access to VM's secret.
     // V2 secrets require hardware support - Secretkeeper HAL, which (among other things)
     // is backed by tamper-evident storage, providing rollback protection to these secrets.
-    V2 { dice_artifacts: OwnedDiceArtifactsWithExplicitKey, skp_secret: ZVec },
+    //
+    // The rollback counters of the VM instance, if it is protected, are kept in Secretkeeper too.
+    V2 {
+        dice_artifacts: OwnedDiceArtifactsWithExplicitKey,
+        skp_secret: ZVec,
+        rollback_counters: Option<RollbackCounters>,
+    },
     // V1 secrets are not protected against rollback of boot images.
     // They are reliable only if rollback of images was prevented by verified boot ie,
     // each stage (including pvmfw/Microdroid/Microdroid Manager) prevents downgrade of next
     // stage. These are now legacy secrets & used only when Secretkeeper HAL is not supported
     // by device.
-    V1 { dice_artifacts: OwnedDiceArtifacts },
+    V1 {
+        dice_artifacts: OwnedDiceArtifacts,
+    },
 }
 
 // For supporting V2 secrets, guest expects the public key to be present in the Linux device tree.
@@ -100,16 +118,16 @@ impl VmSecret {
             .context("Failed to get Dice artifacts in explicit key format")?;
         // For pVM, skp_secret are stored in Secretkeeper. For non-protected it is all 0s.
         let mut skp_secret = Zeroizing::new([0u8; SECRET_SIZE]);
+        let mut rollback_counters = None;
         if super::is_strict_boot() {
-            let sk_service = get_secretkeeper_service(vm_service)?;
-            let mut session =
-                SkSession::new(sk_service, &explicit_dice, Some(get_secretkeeper_identity()?))?;
+            let mut session = new_sk_session(vm_service, &explicit_dice)?;
             let id = super::get_instance_id()?.ok_or(anyhow!("Missing instance_id"))?;
             let explicit_dice_chain = explicit_dice
                 .explicit_key_dice_chain()
                 .ok_or(anyhow!("Missing explicit dice chain, this is unusual"))?;
             let policy = sealing_policy(explicit_dice_chain)
                 .map_err(|e| anyhow!("Failed to build a sealing_policy: {e}"))?;
+            rollback_counters = Some(RollbackCounters::new(&id, policy.clone()));
             if let Some(secret) = get_secret(&mut session, id, Some(policy.clone()))? {
                 *skp_secret = secret;
             } else {
@@ -123,6 +141,7 @@ impl VmSecret {
         Ok(Self::V2 {
             dice_artifacts: explicit_dice,
             skp_secret: ZVec::try_from(skp_secret.to_vec())?,
+            rollback_counters,
         })
     }
 
@@ -135,7 +154,7 @@ impl VmSecret {
 
     fn get_vm_secret(&self, salt: &[u8], identifier: &[u8], key: &mut [u8]) -> Result<()> {
         match self {
-            Self::V2 { dice_artifacts, skp_secret } => {
+            Self::V2 { dice_artifacts, skp_secret, .. } => {
                 let mut hasher = sha::Sha256::new();
                 hasher.update(dice_artifacts.cdi_seal());
                 hasher.update(skp_secret);
@@ -157,6 +176,93 @@ impl VmSecret {
     pub fn derive_encryptedstore_key(&self, key: &mut [u8]) -> Result<()> {
         self.get_vm_secret(SALT_ENCRYPTED_STORE, ENCRYPTEDSTORE_KEY_IDENTIFIER.as_bytes(), key)
     }
+
+    /// Returns rollback counter `counter`, below `ROLLBACK_COUNTERS`, which is 0 until it is first
+    /// incremented. Returns `None` if the VM instance has no rollback counters, as it isn't
+    /// protected with Secretkeeper.
+    pub fn read_rollback_counter(
+        &self,
+        vm_service: &Strong<dyn IVirtualMachineService>,
+        counter: usize,
+    ) -> Result<Option<u32>> {
+        let Self::V2 { dice_artifacts, rollback_counters: Some(counters), .. } = self else {
+            return Ok(None);
+        };
+        let _guard = counters.lock.lock().unwrap();
+        let mut session = new_sk_session(vm_service, dice_artifacts)?;
+        Ok(Some(counters.read(&mut session)?[counter]))
+    }
+
+    /// Increments rollback counter `counter`, below `ROLLBACK_COUNTERS`, and returns its new value.
+    /// Returns `None` if the VM instance has no rollback counters.
+    pub fn increment_rollback_counter(
+        &self,
+        vm_service: &Strong<dyn IVirtualMachineService>,
+        counter: usize,
+    ) -> Result<Option<u32>> {
+        let Self::V2 { dice_artifacts, rollback_counters: Some(counters), .. } = self else {
+            return Ok(None);
+        };
+        let _guard = counters.lock.lock().unwrap();
+        let mut session = new_sk_session(vm_service, dice_artifacts)?;
+        let mut values = counters.read(&mut session)?;
+        values[counter] =
+            values[counter].checked_add(1).context("Rollback counter can't be incremented")?;
+        counters.store(&mut session, &values)?;
+        Ok(Some(values[counter]))
+    }
+}
+
+/// The rollback counters of a VM instance, kept in a Secretkeeper entry of their own as
+/// little-endian `u32`s in place of a secret. Secretkeeper is only reached over a session
+/// authenticated with its identity, so unlike state kept by the host, the host can't roll them
+/// back.
+pub struct RollbackCounters {
+    /// ID of the entry, derived from the instance ID.
+    id: [u8; ID_SIZE],
+    /// The sealing policy of the secret of the instance, which the entry is stored with too.
+    sealing_policy: Vec<u8>,
+    /// Serializes the accesses, as an increment reads and then stores the entry.
+    lock: Mutex<()>,
+}
+
+impl RollbackCounters {
+    fn new(instance_id: &[u8; ID_SIZE], sealing_policy: Vec<u8>) -> Self {
+        Self { id: rollback_counters_id(instance_id), sealing_policy, lock: Mutex::new(()) }
+    }
+
+    fn read(&self, session: &mut SkSession) -> Result<[u32; ROLLBACK_COUNTERS]> {
+        let secret = get_secret(session, self.id, Some(self.sealing_policy.clone()))?;
+        Ok(secret.map(|secret| decode_rollback_counters(&secret)).unwrap_or_default())
+    }
+
+    fn store(&self, session: &mut SkSession, values: &[u32; ROLLBACK_COUNTERS]) -> Result<()> {
+        let secret = Zeroizing::new(encode_rollback_counters(values));
+        store_secret(session, self.id, secret, self.sealing_policy.clone())
+    }
+}
+
+fn rollback_counters_id(instance_id: &[u8; ID_SIZE]) -> [u8; ID_SIZE] {
+    let mut hasher = sha::Sha512::new();
+    hasher.update(ROLLBACK_COUNTERS_ID_PREFIX);
+    hasher.update(instance_id);
+    hasher.finish()
+}
+
+fn encode_rollback_counters(values: &[u32; ROLLBACK_COUNTERS]) -> [u8; SECRET_SIZE] {
+    let mut secret = [0; SECRET_SIZE];
+    for (chunk, value) in secret.chunks_exact_mut(size_of::<u32>()).zip(values) {
+        chunk.copy_from_slice(&value.to_le_bytes());
+    }
+    secret
+}
+
+fn decode_rollback_counters(secret: &[u8; SECRET_SIZE]) -> [u32; ROLLBACK_COUNTERS] {
+    let mut values = [0; ROLLBACK_COUNTERS];
+    for (value, chunk) in values.iter_mut().zip(secret.chunks_exact(size_of::<u32>())) {
+        *value = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+    values
 }
 
 // Construct a sealing policy on the dice chain. VMs uses the following set of constraint for
@@ -281,6 +387,14 @@ fn anyhow_err<E: core::fmt::Debug>(err: E) -> anyhow::Error {
     anyhow!("{:?}", err)
 }
 
+fn new_sk_session(
+    vm_service: &Strong<dyn IVirtualMachineService>,
+    dice_artifacts: &OwnedDiceArtifactsWithExplicitKey,
+) -> Result<SkSession> {
+    let sk_service = get_secretkeeper_service(vm_service)?;
+    Ok(SkSession::new(sk_service, dice_artifacts, Some(get_secretkeeper_identity()?))?)
+}
+
 fn get_secretkeeper_service(
     host: &Strong<dyn IVirtualMachineService>,
 ) -> Result<Strong<dyn ISecretkeeper>> {
@@ -293,3 +407,29 @@ fn get_secretkeeper_service(
             ))
         })?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rollback_counters_id_matches_virtualizationservice() {
+        // virtualizationservice derives the same ID to delete the entry, see
+        // android/virtualizationservice/src/maintenance.rs.
+        let want = hex::decode(
+            "d23e5c49019a833fb49b49e285b35a1aefcf87bccfbe6fe2db26e1bd65a9c01e\
+             7b4c3ff5aaf34a56bb5fc908f0024a5fa78e000ef746942a5cc1bf98ac811e50",
+        )
+        .unwrap();
+        assert_eq!(rollback_counters_id(&[1u8; ID_SIZE]).to_vec(), want);
+    }
+
+    #[test]
+    fn rollback_counters_round_trip() {
+        let values = [0, 1, 2, 0x100, 0x10000, 0x1000000, u32::MAX - 1, u32::MAX];
+        let secret = encode_rollback_counters(&values);
+        assert_eq!(secret[4..8], [1, 0, 0, 0]);
+        assert_eq!(decode_rollback_counters(&secret), values);
+        assert_eq!(decode_rollback_counters(&[0; SECRET_SIZE]), [0; ROLLBACK_COUNTERS]);
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The session to the `IVirtualMachineService` which the host runs for this VM.
+//!
+//! The session is lost when the host migrates the VM to a new crosvm process, as that resets the
+//! vsock connections of the VM, while the host keeps serving it. It is then set up again, and the
+//! users of the session are told to register their callbacks with the host again.
+
+use android_system_virtualmachineservice::aidl::android::system::virtualmachineservice::IVirtualMachineService::IVirtualMachineService;
+use anyhow::{Context, Result};
+use binder::{DeathRecipient, IBinder, Strong};
+use libc::VMADDR_CID_HOST;
+use log::{error, info, warn};
+use rpcbinder::RpcSession;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Threads serving calls from the host: one for each callback interface, plus one for the host
+/// calling back while handling a call from the VM.
+const HOST_CALLBACK_THREADS: usize = 3;
+
+/// How long to try connecting to the host again for, once the session was lost. This covers the
+/// restore of the VM in a new crosvm process.
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait between attempts to connect to the host again.
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Called with the service of each new session.
+pub type ReconnectHandler = Box<dyn Fn(&Strong<dyn IVirtualMachineService>) + Send + Sync>;
+
+/// The session to the `IVirtualMachineService` of the host, which is set up again if it is lost.
+pub struct VmServiceConnection {
+    port: u32,
+    service: Mutex<Strong<dyn IVirtualMachineService>>,
+    /// Keeps the death notification of the current session registered.
+    death_recipient: Mutex<Option<DeathRecipient>>,
+    reconnect_handlers: Mutex<Vec<ReconnectHandler>>,
+}
+
+impl VmServiceConnection {
+    /// Connects to the `IVirtualMachineService` of the host.
+    pub fn connect() -> Result<Arc<VmServiceConnection>> {
+        // The host is running a VirtualMachineService for this VM on a port equal
+        // to the CID of this VM.
+        let port = vsock::get_local_cid().context("Could not determine local CID")?;
+        let service = connect_to_host(port)?;
+        let connection = Arc::new(VmServiceConnection {
+            port,
+            service: Mutex::new(service.clone()),
+            death_recipient: Mutex::new(None),
+            reconnect_handlers: Mutex::new(Vec::new()),
+        });
+        connection.watch(&service)?;
+        Ok(connection)
+    }
+
+    /// Returns the service of the current session. Callers which outlive a call shouldn't keep
+    /// it, as it dies with the session.
+    pub fn get(&self) -> Strong<dyn IVirtualMachineService> {
+        self.service.lock().unwrap().clone()
+    }
+
+    /// Registers `handler` to be called once the session was set up again, e.g. to register
+    /// callbacks with the host again.
+    pub fn on_reconnect(&self, handler: ReconnectHandler) {
+        self.reconnect_handlers.lock().unwrap().push(handler);
+    }
+
+    /// Sets up the session again once `service` dies.
+    fn watch(self: &Arc<Self>, service: &Strong<dyn IVirtualMachineService>) -> Result<()> {
+        let connection = Arc::downgrade(self);
+        let mut death_recipient = DeathRecipient::new(move || {
+            let connection = connection.clone();
+            // Don't hold up the thread delivering the notification while reconnecting.
+            thread::spawn(move || {
+                if let Some(connection) = connection.upgrade() {
+                    connection.reconnect();
+                }
+            });
+        });
+        service
+            .as_binder()
+            .link_to_death(&mut death_recipient)
+            .context("Failed to watch the session to IVirtualMachineService")?;
+        *self.death_recipient.lock().unwrap() = Some(death_recipient);
+        Ok(())
+    }
+
+    fn reconnect(self: &Arc<Self>) {
+        warn!("Lost the session to IVirtualMachineService, connecting again");
+        let deadline = Instant::now() + RECONNECT_TIMEOUT;
+        let service = loop {
+            match connect_to_host(self.port) {
+                Ok(service) => break service,
+                Err(e) if Instant::now() >= deadline => {
+                    error!("Failed to connect to IVirtualMachineService again: {e:?}");
+                    return;
+                }
+                Err(_) => thread::sleep(RECONNECT_INTERVAL),
+            }
+        };
+        *self.service.lock().unwrap() = service.clone();
+        if let Err(e) = self.watch(&service) {
+            error!("{e:?}");
+        }
+        info!("Connected to IVirtualMachineService again");
+        for handler in self.reconnect_handlers.lock().unwrap().iter() {
+            handler(&service);
+        }
+    }
+}
+
+fn connect_to_host(port: u32) -> Result<Strong<dyn IVirtualMachineService>> {
+    let session = RpcSession::new();
+    // The host calls back into the VM to notify it of snapshots and shutdown requests. These are
+    // relayed to the payload, which may take a while to return or call back into the host, so a
+    // single incoming thread would leave one notification waiting for another. Incoming threads
+    // are also needed for the death of the session to be notified.
+    session.set_max_incoming_threads(HOST_CALLBACK_THREADS);
+    session
+        .setup_vsock_client(VMADDR_CID_HOST, port)
+        .context("Could not connect to IVirtualMachineService")
+}
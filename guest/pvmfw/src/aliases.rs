@@ -0,0 +1,111 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Clean-up of the /aliases of the DT once pvmfw has removed nodes from it.
+
+#[cfg(test)]
+extern crate alloc;
+
+use alloc::ffi::CString;
+use alloc::vec::Vec;
+use libfdt::{Fdt, FdtError};
+use log::debug;
+
+/// Patch the DT by removing the aliases which refer to nodes that were deleted.
+pub fn patch_aliases(fdt: &mut Fdt) -> libfdt::Result<()> {
+    let Some(aliases) = fdt.aliases()? else {
+        return Ok(());
+    };
+    let mut dangling = Vec::new();
+    for prop in aliases.properties()? {
+        let name = prop.name()?;
+        // Aliases with invalid paths don't refer to any node either.
+        match fdt.resolve_alias(name) {
+            Ok(Some(_)) => {}
+            Ok(None) | Err(FdtError::BadPath) => dangling.push(CString::from(name)),
+            Err(e) => return Err(e),
+        }
+    }
+
+    let mut aliases = fdt.aliases_mut()?.ok_or(FdtError::Internal)?;
+    for name in dangling {
+        debug!("Removing dangling alias {name:?}");
+        aliases.nop_property(&name)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cstr::cstr;
+    use std::fs;
+
+    const FDT_FILE_PATH: &str = "test_pvmfw_aliases.dtb";
+
+    fn alias_names(fdt: &Fdt) -> Vec<CString> {
+        let aliases = fdt.aliases().unwrap().unwrap();
+        aliases.properties().unwrap().map(|prop| prop.name().unwrap().into()).collect()
+    }
+
+    #[test]
+    fn patch_aliases_keeps_aliases_of_existing_nodes() {
+        let mut data = fs::read(FDT_FILE_PATH).unwrap();
+        let fdt = Fdt::from_mut_slice(&mut data).unwrap();
+
+        patch_aliases(fdt).unwrap();
+
+        let serial0 = fdt.resolve_alias(cstr!("serial0")).unwrap().unwrap();
+        assert_eq!(serial0.name(), Ok(cstr!("serial@1000")));
+        assert_eq!(fdt.resolve_alias(cstr!("console")), Ok(Some(serial0)));
+        let expected: Vec<CString> =
+            vec![cstr!("serial0").into(), cstr!("serial1").into(), cstr!("console").into()];
+        assert_eq!(alias_names(fdt), expected);
+    }
+
+    #[test]
+    fn patch_aliases_removes_aliases_of_deleted_nodes() {
+        let mut data = fs::read(FDT_FILE_PATH).unwrap();
+        let fdt = Fdt::from_mut_slice(&mut data).unwrap();
+        fdt.node_mut(cstr!("/soc/serial@2000")).unwrap().unwrap().nop().unwrap();
+
+        patch_aliases(fdt).unwrap();
+
+        assert_eq!(fdt.resolve_alias(cstr!("serial1")), Err(FdtError::BadPath));
+        let expected: Vec<CString> = vec![cstr!("serial0").into(), cstr!("console").into()];
+        assert_eq!(alias_names(fdt), expected);
+    }
+
+    #[test]
+    fn patch_aliases_removes_aliases_with_invalid_paths() {
+        let mut data = fs::read(FDT_FILE_PATH).unwrap();
+        let fdt = Fdt::from_mut_slice(&mut data).unwrap();
+
+        patch_aliases(fdt).unwrap();
+
+        assert_eq!(fdt.resolve_alias(cstr!("dangling")), Err(FdtError::BadPath));
+        assert_eq!(fdt.resolve_alias(cstr!("relative")), Err(FdtError::BadPath));
+    }
+
+    #[test]
+    fn patch_aliases_without_aliases() {
+        let mut data = vec![0_u8; 256];
+        let fdt = Fdt::create_empty_tree(&mut data).unwrap();
+        let original = fdt.as_slice().to_vec();
+
+        patch_aliases(fdt).unwrap();
+
+        assert_eq!(fdt.as_slice(), &original[..]);
+    }
+}
@@ -29,11 +29,12 @@ use log::warn;
 use log::LevelFilter;
 use vmbase::util::RangeExt as _;
 use vmbase::{
-    configure_heap, console_writeln,
+    arch::cache::min_dcache_line_size,
+    configure_heap, console, console_writeln,
     hyp::{get_mem_sharer, get_mmio_guard},
-    layout::{self, crosvm, UART_PAGE_ADDR},
+    layout::{self, crosvm},
     main,
-    memory::{min_dcache_line_size, MemoryTracker, MEMORY, SIZE_128KB, SIZE_4KB},
+    memory::{MemoryTracker, MEMORY, SIZE_128KB, SIZE_4KB},
     power::reboot,
 };
 use zeroize::Zeroize;
@@ -272,7 +273,7 @@ fn main_wrapper(
         if cfg!(debuggable_vms_improvements) && debuggable_payload {
             // Keep UART MMIO_GUARD-ed for debuggable payloads, to enable earlycon.
         } else {
-            mmio_guard.unmap(UART_PAGE_ADDR).map_err(|e| {
+            mmio_guard.unmap(console::uart_page()).map_err(|e| {
                 error!("Failed to unshare the UART: {e}");
                 RebootReason::InternalError
             })?;
@@ -15,6 +15,7 @@
 //! Exception handlers.
 
 use vmbase::{
+    backtrace::print_backtrace,
     eprintln,
     exceptions::{ArmException, Esr, HandleExceptionError},
     logger,
@@ -63,6 +64,7 @@ extern "C" fn serr_current(_elr: u64, _spsr: u64) {
     let esr = read_sysreg!("esr_el1");
     eprintln!("serr_current");
     eprintln!("esr={esr:#08x}");
+    print_backtrace();
     reboot();
 }
 
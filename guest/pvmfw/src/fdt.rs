@@ -14,6 +14,7 @@
 
 //! High-level FDT functions.
 
+use crate::aliases::patch_aliases;
 use crate::bootargs::BootArgsIterator;
 use crate::device_assignment::{self, DeviceAssignmentInfo, VmDtbo};
 use crate::helpers::GUEST_PAGE_SIZE;
@@ -953,11 +954,7 @@ fn patch_timer(fdt: &mut Fdt, num_cpus: usize) -> libfdt::Result<()> {
 }
 
 fn patch_untrusted_props(fdt: &mut Fdt, props: &BTreeMap<CString, Vec<u8>>) -> libfdt::Result<()> {
-    let avf_node = if let Some(node) = fdt.node_mut(cstr!("/avf"))? {
-        node
-    } else {
-        fdt.root_mut().add_subnode(cstr!("avf"))?
-    };
+    let avf_node = fdt.add_node_path(cstr!("/avf"))?;
 
     // The node shouldn't already be present; if it is, return the error.
     let mut node = avf_node.add_subnode(cstr!("untrusted"))?;
@@ -1255,6 +1252,10 @@ fn patch_device_tree(fdt: &mut Fdt, info: &DeviceTreeInfo) -> Result<(), RebootR
             RebootReason::InvalidFdt
         })?;
     }
+    patch_aliases(fdt).map_err(|e| {
+        error!("Failed to patch aliases to DT: {e}");
+        RebootReason::InvalidFdt
+    })?;
     patch_untrusted_props(fdt, &info.untrusted_props).map_err(|e| {
         error!("Failed to patch untrusted properties: {e}");
         RebootReason::InvalidFdt
@@ -19,6 +19,7 @@
 
 extern crate alloc;
 
+mod aliases;
 mod bcc;
 mod bootargs;
 mod config;
@@ -20,12 +20,16 @@ use diced_open_dice::DiceError;
 use fdtpci::PciError;
 use libfdt::FdtError;
 use service_vm_comm::RequestProcessingError;
-use vmbase::{hyp::Error as HypervisorError, memory::MemoryTrackerError, virtio::pci};
+use vmbase::{
+    hyp::Error as HypervisorError,
+    memory::MemoryTrackerError,
+    virtio::{pci, vsock::VsockError},
+};
 
 pub type Result<T> = result::Result<T, Error>;
 
-type CiboriumSerError = ciborium::ser::Error<virtio_drivers::Error>;
-type CiboriumDeError = ciborium::de::Error<virtio_drivers::Error>;
+type CiboriumSerError = ciborium::ser::Error<VsockError>;
+type CiboriumDeError = ciborium::de::Error<VsockError>;
 
 #[derive(Debug)]
 pub enum Error {
@@ -41,12 +45,8 @@ pub enum Error {
     MemoryOperationFailed(MemoryTrackerError),
     /// Failed to initialize PCI.
     PciInitializationFailed(pci::PciError),
-    /// Failed to create VirtIO Socket device.
-    VirtIOSocketCreationFailed(virtio_drivers::Error),
-    /// Missing socket device.
-    MissingVirtIOSocketDevice,
-    /// Failed VirtIO driver operation.
-    VirtIODriverOperationFailed(virtio_drivers::Error),
+    /// Failed vsock operation.
+    VsockOperationFailed(VsockError),
     /// Failed to serialize.
     SerializationFailed(CiboriumSerError),
     /// Failed to deserialize.
@@ -68,13 +68,7 @@ impl fmt::Display for Error {
             Self::InvalidPci(e) => write!(f, "Invalid PCI: {e}"),
             Self::MemoryOperationFailed(e) => write!(f, "Failed memory operation: {e}"),
             Self::PciInitializationFailed(e) => write!(f, "Failed to initialize PCI: {e}"),
-            Self::VirtIOSocketCreationFailed(e) => {
-                write!(f, "Failed to create VirtIO Socket device: {e}")
-            }
-            Self::MissingVirtIOSocketDevice => write!(f, "Missing VirtIO Socket device."),
-            Self::VirtIODriverOperationFailed(e) => {
-                write!(f, "Failed VirtIO driver operation: {e}")
-            }
+            Self::VsockOperationFailed(e) => write!(f, "Failed vsock operation: {e}"),
             Self::SerializationFailed(e) => write!(f, "Failed to serialize: {e}"),
             Self::DeserializationFailed(e) => write!(f, "Failed to deserialize: {e}"),
             Self::DiceOperationFailed(e) => write!(f, "Failed DICE operation: {e}"),
@@ -113,9 +107,9 @@ impl From<MemoryTrackerError> for Error {
     }
 }
 
-impl From<virtio_drivers::Error> for Error {
-    fn from(e: virtio_drivers::Error) -> Self {
-        Self::VirtIODriverOperationFailed(e)
+impl From<VsockError> for Error {
+    fn from(e: VsockError) -> Self {
+        Self::VsockOperationFailed(e)
     }
 }
 
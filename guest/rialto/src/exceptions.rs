@@ -15,6 +15,7 @@
 //! Exception handlers.
 
 use vmbase::{
+    backtrace::print_backtrace,
     eprintln,
     exceptions::{ArmException, Esr, HandleExceptionError},
     logger,
@@ -62,6 +63,7 @@ extern "C" fn fiq_current() {
 extern "C" fn serr_current() {
     eprintln!("serr_current");
     print_esr();
+    print_backtrace();
     reboot();
 }
 
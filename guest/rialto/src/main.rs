@@ -24,11 +24,10 @@ mod fdt;
 
 extern crate alloc;
 
-use crate::communication::VsockStream;
+use crate::communication::{read_request, write_response};
 use crate::error::{Error, Result};
 use crate::fdt::{read_dice_range_from, read_is_strict_boot, read_vendor_hashtree_root_digest};
 use alloc::boxed::Box;
-use ciborium_io::Write;
 use core::num::NonZeroUsize;
 use core::slice;
 use diced_open_dice::{bcc_handover_parse, DiceArtifacts};
@@ -38,23 +37,19 @@ use log::{debug, error, info};
 use service_vm_comm::{ServiceVmRequest, VmType};
 use service_vm_fake_chain::service_vm;
 use service_vm_requests::{process_request, RequestContext};
-use virtio_drivers::{
-    device::socket::{VsockAddr, VMADDR_CID_HOST},
-    transport::{pci::bus::PciRoot, DeviceType, Transport},
-    Hal,
-};
+use virtio_drivers::device::socket::{VsockAddr, VMADDR_CID_HOST};
 use vmbase::{
-    configure_heap,
+    configure_heap, console,
     fdt::SwiotlbInfo,
     generate_image_header,
     hyp::{get_mem_sharer, get_mmio_guard},
-    layout::{self, crosvm, UART_PAGE_ADDR},
+    layout::{self, crosvm},
     main,
     memory::{MemoryTracker, PageTable, MEMORY, PAGE_SIZE, SIZE_128KB},
     power::reboot,
     virtio::{
-        pci::{self, PciTransportIterator, VirtIOSocket},
-        HalImpl,
+        pci,
+        vsock::{self, VsockStream},
     },
 };
 
@@ -160,18 +155,20 @@ unsafe fn try_main(fdt_addr: usize) -> Result<()> {
     let mut pci_root = pci::initialize(pci_info, MEMORY.lock().as_mut().unwrap())
         .map_err(Error::PciInitializationFailed)?;
     debug!("PCI root: {pci_root:#x?}");
-    let socket_device = find_socket_device::<HalImpl>(&mut pci_root)?;
+    let socket_device = vsock::find_pci_device(&mut pci_root)?;
     debug!("Found socket device: guest cid = {:?}", socket_device.guest_cid());
     let vendor_hashtree_root_digest = read_vendor_hashtree_root_digest(fdt)?;
     let request_context =
         RequestContext { dice_artifacts: bcc_handover.as_ref(), vendor_hashtree_root_digest };
 
-    let mut vsock_stream = VsockStream::new(socket_device, host_addr(fdt)?)?;
-    while let ServiceVmRequest::Process(req) = vsock_stream.read_request()? {
+    // The same port is used on rialto and host for convenience.
+    let host_addr = host_addr(fdt)?;
+    let mut vsock_stream = VsockStream::connect(socket_device, host_addr, host_addr.port)?;
+    while let ServiceVmRequest::Process(req) = read_request(&mut vsock_stream)? {
         info!("Received request: {}", req.name());
         let response = process_request(req, &request_context);
         info!("Sending response: {}", response.name());
-        vsock_stream.write_response(&response)?;
+        write_response(&mut vsock_stream, &response)?;
         vsock_stream.flush()?;
     }
     vsock_stream.shutdown()?;
@@ -179,21 +176,12 @@ unsafe fn try_main(fdt_addr: usize) -> Result<()> {
     Ok(())
 }
 
-fn find_socket_device<T: Hal>(pci_root: &mut PciRoot) -> Result<VirtIOSocket<T>> {
-    PciTransportIterator::<T>::new(pci_root)
-        .find(|t| DeviceType::Socket == t.device_type())
-        .map(VirtIOSocket::<T>::new)
-        .transpose()
-        .map_err(Error::VirtIOSocketCreationFailed)?
-        .ok_or(Error::MissingVirtIOSocketDevice)
-}
-
 fn try_unshare_all_memory() -> Result<()> {
     info!("Starting unsharing memory...");
 
     // No logging after unmapping UART.
     if let Some(mmio_guard) = get_mmio_guard() {
-        mmio_guard.unmap(UART_PAGE_ADDR)?;
+        mmio_guard.unmap(console::uart_page())?;
     }
     // Unshares all memory and deactivates page table.
     drop(MEMORY.lock().take());
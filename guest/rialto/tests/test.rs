@@ -23,13 +23,14 @@ use android_system_virtualizationservice::{
 };
 use anyhow::{bail, Context, Result};
 use bssl_avf::{rand_bytes, sha256, EcKey, PKey};
-use client_vm_csr::generate_attestation_key_and_csr;
+use client_vm_csr::{generate_attestation_key_and_csr, open_sealed_key};
 use coset::{CborSerializable, CoseMac0, CoseSign};
 use hwtrust::{rkp, session::Session};
 use log::{info, warn};
 use service_vm_comm::{
-    ClientVmAttestationParams, Csr, CsrPayload, EcdsaP256KeyPair, GenerateCertificateRequestParams,
-    Request, RequestProcessingError, Response, VmType,
+    ClientVmAttestationParams, Csr, CsrPayload, DeriveSealedKeyParams, EcdsaP256KeyPair,
+    GenerateCertificateRequestParams, Request, RequestProcessingError, Response, SealingPolicy,
+    VmType, SEALED_KEY_SIZE,
 };
 use service_vm_fake_chain::client_vm::{
     fake_client_vm_dice_artifacts, fake_sub_components, SubComponent,
@@ -80,6 +81,7 @@ fn check_processing_requests(vm_type: VmType, vm_memory_mb: Option<i32>) -> Resu
     let key_pair = check_processing_generating_key_pair_request(&mut vm)?;
     check_processing_generating_certificate_request(&mut vm, &key_pair.maced_public_key)?;
     check_attestation_request(&mut vm, &key_pair, vm_type)?;
+    check_sealed_key_request(&mut vm, vm_type)?;
     Ok(())
 }
 
@@ -193,6 +195,60 @@ fn check_attestation_request(
     }
 }
 
+fn check_sealed_key_request(vm: &mut ServiceVm, vm_type: VmType) -> Result<()> {
+    let exact_images_key = request_sealed_key(vm, SealingPolicy::ExactImages)?;
+    let same_authority_key = request_sealed_key(vm, SealingPolicy::SameAuthority)?;
+    match (exact_images_key, same_authority_key) {
+        (Some(exact_images_key), Some(same_authority_key)) => {
+            // The end-to-end test for non-protected VM works because both the service VM and
+            // the client VM use the same fake DICE chain.
+            assert_eq!(vm_type, VmType::NonProtectedVm);
+            assert_eq!(SEALED_KEY_SIZE, exact_images_key.len());
+            assert_array_has_nonzero(&exact_images_key);
+            assert_ne!(exact_images_key, same_authority_key);
+            // The sealed key only depends on the client VM, not on the ephemeral keys.
+            assert_eq!(Some(exact_images_key), request_sealed_key(vm, SealingPolicy::ExactImages)?);
+            Ok(())
+        }
+        (None, None) => {
+            // See `check_attestation_request` for why this fails for protected VMs.
+            assert_eq!(vm_type, VmType::ProtectedVm);
+            Ok(())
+        }
+        _ => bail!("Inconsistent responses to the sealed key requests"),
+    }
+}
+
+/// Requests a sealed key and opens it as the client VM would, or returns `None` if the service VM
+/// rejected the DICE chain of the client VM.
+fn request_sealed_key(vm: &mut ServiceVm, policy: SealingPolicy) -> Result<Option<Vec<u8>>> {
+    let dice_artifacts = fake_client_vm_dice_artifacts()?;
+    let attestation_data =
+        generate_attestation_key_and_csr(&policy.to_challenge(), &dice_artifacts)?;
+    let params = DeriveSealedKeyParams { csr: attestation_data.csr.into_cbor_vec()? };
+    let request = Request::DeriveSealedKey(params);
+
+    let response = vm.process_request(request)?;
+    info!("Received response: {response:?}.");
+
+    match response {
+        Response::DeriveSealedKey(sealed_key) => {
+            // The client VM must reject keys whose signature by the service VM doesn't verify.
+            let mut tampered_key = sealed_key.clone();
+            *tampered_key.signed_public_keys.last_mut().unwrap() ^= 1;
+            let tampered_key = tampered_key.into_cbor_vec()?;
+            assert!(open_sealed_key(&attestation_data.private_key, &dice_artifacts, &tampered_key)
+                .is_err());
+
+            let sealed_key = sealed_key.into_cbor_vec()?;
+            let key = open_sealed_key(&attestation_data.private_key, &dice_artifacts, &sealed_key)?;
+            Ok(Some(key.to_vec()))
+        }
+        Response::Err(RequestProcessingError::InvalidDiceChain) => Ok(None),
+        _ => bail!("Incorrect response type: {response:?}"),
+    }
+}
+
 fn check_vm_components(vm_components: &asn1::SequenceOf<asn1::Any, 4>) -> Result<()> {
     let expected_components = fake_sub_components();
     assert_eq!(expected_components.len(), vm_components.len());
@@ -330,8 +386,8 @@ fn nonprotected_vm_instance(memory_mib: i32) -> Result<VmInstance> {
         instanceId: instance_id,
         ..Default::default()
     });
-    let console = Some(service_vm_manager::android_log_fd()?);
-    let log = Some(service_vm_manager::android_log_fd()?);
+    let console = Some(vmclient::log_forwarder("rialto")?);
+    let log = Some(vmclient::log_forwarder("rialto")?);
     let virtmgr = vmclient::VirtualizationService::new().context("Failed to spawn VirtMgr")?;
     let service = virtmgr.connect().context("Failed to connect to VirtMgr")?;
     info!("Connected to VirtMgr for service VM");
@@ -14,12 +14,13 @@
 
 //! Exception handlers.
 
-use vmbase::{eprintln, power::reboot, read_sysreg};
+use vmbase::{backtrace::print_backtrace, eprintln, power::reboot, read_sysreg};
 
 #[no_mangle]
 extern "C" fn sync_exception_current(_elr: u64, _spsr: u64) {
     eprintln!("sync_exception_current");
     print_esr();
+    print_backtrace();
     reboot();
 }
 
@@ -39,6 +40,7 @@ extern "C" fn fiq_current(_elr: u64, _spsr: u64) {
 extern "C" fn serr_current(_elr: u64, _spsr: u64) {
     eprintln!("serr_current");
     print_esr();
+    print_backtrace();
     reboot();
 }
 
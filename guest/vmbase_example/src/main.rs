@@ -27,18 +27,22 @@ use crate::layout::{boot_stack_range, print_addresses, DEVICE_REGION};
 use crate::pci::{check_pci, get_bar_region};
 use aarch64_paging::paging::VirtualAddress;
 use aarch64_paging::MapError;
-use alloc::{vec, vec::Vec};
+use alloc::{string::String, vec, vec::Vec};
+use core::fmt::Write as _;
 use core::mem;
+use core::num::NonZeroUsize;
 use core::ptr::addr_of_mut;
 use cstr::cstr;
 use fdtpci::PciInfo;
 use libfdt::Fdt;
 use log::{debug, error, info, trace, warn, LevelFilter};
 use vmbase::{
-    bionic, configure_heap, generate_image_header,
+    bionic, configure_heap,
+    console::SingleLine,
+    generate_image_header,
     layout::{crosvm::FDT_MAX_SIZE, rodata_range, scratch_range, text_range},
     linker, logger, main,
-    memory::{PageTable, SIZE_64KB},
+    memory::{PageTable, SharedPages, PAGE_SIZE, SIZE_64KB},
     util::RangeExt as _,
 };
 
@@ -97,6 +101,8 @@ pub fn main(arg0: u64, arg1: u64, arg2: u64, arg3: u64) {
     modify_fdt(fdt);
 
     check_alloc();
+    check_shared_pages();
+    check_single_line();
 
     let bar_region = get_bar_region(&pci_info);
     if bar_region.is_within(&DEVICE_REGION) {
@@ -111,7 +117,7 @@ pub fn main(arg0: u64, arg1: u64, arg2: u64, arg3: u64) {
 
     // SAFETY: This is the only place where `make_pci_root` is called.
     let mut pci_root = unsafe { pci_info.make_pci_root() };
-    check_pci(&mut pci_root);
+    check_pci(&pci_info, &mut pci_root);
 
     emit_suppressed_log();
 
@@ -229,6 +235,40 @@ fn check_alloc() {
     info!("Vec seems to work.");
 }
 
+fn check_shared_pages() {
+    info!("Sharing pages with the host...");
+    let pages = SharedPages::new(NonZeroUsize::new(1).unwrap()).unwrap();
+    assert!(pages.len() >= PAGE_SIZE);
+    assert_eq!(pages.len() % PAGE_SIZE, 0);
+    assert_eq!(pages.as_ptr().as_ptr() as usize % PAGE_SIZE, 0);
+    assert_eq!(pages.phys_addr() % PAGE_SIZE, 0);
+    let ptr = pages.as_ptr().as_ptr();
+    for offset in 0..pages.len() {
+        // SAFETY: The offset is within the pages, which are only accessed through volatile
+        // operations.
+        assert_eq!(unsafe { ptr.add(offset).read_volatile() }, 0);
+    }
+    // SAFETY: The offset is within the pages.
+    unsafe { ptr.add(pages.len() - 1).write_volatile(42) };
+    // SAFETY: The offset is within the pages.
+    assert_eq!(unsafe { ptr.add(pages.len() - 1).read_volatile() }, 42);
+    mem::drop(pages);
+
+    assert!(SharedPages::new(NonZeroUsize::new(usize::MAX).unwrap()).is_err());
+    info!("Shared pages seem to work.");
+}
+
+fn check_single_line() {
+    info!("Testing SingleLine...");
+    let mut line = String::new();
+    write!(SingleLine(&mut line), "panicked at src/main.rs:1:1:\n{}", "multi\nline\n").unwrap();
+    assert_eq!(line, "panicked at src/main.rs:1:1: multi line ");
+    line.clear();
+    write!(SingleLine(&mut line), "no newline").unwrap();
+    assert_eq!(line, "no newline");
+    info!("SingleLine seems to work.");
+}
+
 fn check_dice() {
     info!("Testing DICE integration...");
     let hash = diced_open_dice::hash("hello world".as_bytes()).expect("DiceHash failed");
@@ -27,7 +27,7 @@ use virtio_drivers::{
     },
     BufferDirection, Error, Hal, PhysAddr, PAGE_SIZE,
 };
-use vmbase::virtio::pci::{self, PciTransportIterator};
+use vmbase::virtio::pci;
 
 /// The standard sector size of a VirtIO block device, in bytes.
 const SECTOR_SIZE_BYTES: usize = 512;
@@ -35,11 +35,15 @@ const SECTOR_SIZE_BYTES: usize = 512;
 /// The size in sectors of the test block device we expect.
 const EXPECTED_SECTOR_COUNT: usize = 4;
 
-pub fn check_pci(pci_root: &mut PciRoot) {
+pub fn check_pci(pci_info: &PciInfo, pci_root: &mut PciRoot) {
     let mut checked_virtio_device_count = 0;
     let mut block_device_count = 0;
     let mut socket_device_count = 0;
-    for mut transport in PciTransportIterator::<HalImpl>::new(pci_root) {
+    let devices = vmbase::pci::enumerate(pci_info, pci_root).expect("Failed to enumerate PCI bus");
+    for device in devices.iter().filter(|device| device.virtio_type().is_some()) {
+        assert!(!device.bars.is_empty(), "VirtIO PCI device {} has no BAR", device.device_function);
+        let mut transport =
+            device.transport::<HalImpl>(pci_root).expect("Failed to create VirtIO transport");
         info!(
             "Detected virtio PCI device with device type {:?}, features {:#018x}",
             transport.device_type(),
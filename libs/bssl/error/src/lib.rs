@@ -94,6 +94,7 @@ pub enum ApiName {
     ECDSA_sign,
     ECDSA_size,
     ECDSA_verify,
+    ECDH_compute_key,
     ED25519_verify,
     EVP_AEAD_CTX_new,
     EVP_AEAD_CTX_open,
@@ -23,8 +23,8 @@ use alloc::vec::Vec;
 use bssl_avf_error::{ApiName, Error, Result};
 use bssl_sys::{
     i2d_ECDSA_SIG, BN_bin2bn, BN_bn2bin_padded, BN_clear_free, BN_new, CBB_flush, CBB_len,
-    ECDSA_SIG_free, ECDSA_SIG_from_bytes, ECDSA_SIG_get0_r, ECDSA_SIG_get0_s, ECDSA_SIG_new,
-    ECDSA_SIG_set0, ECDSA_sign, ECDSA_size, ECDSA_verify, EC_GROUP_get_curve_name,
+    ECDH_compute_key, ECDSA_SIG_free, ECDSA_SIG_from_bytes, ECDSA_SIG_get0_r, ECDSA_SIG_get0_s,
+    ECDSA_SIG_new, ECDSA_SIG_set0, ECDSA_sign, ECDSA_size, ECDSA_verify, EC_GROUP_get_curve_name,
     EC_GROUP_new_by_curve_name, EC_KEY_check_key, EC_KEY_free, EC_KEY_generate_key,
     EC_KEY_get0_group, EC_KEY_get0_public_key, EC_KEY_marshal_private_key,
     EC_KEY_new_by_curve_name, EC_KEY_parse_private_key, EC_KEY_set_public_key_affine_coordinates,
@@ -214,6 +214,32 @@ impl EcKey {
         ec_der_signature_to_cose(&signature, coord_bytes)
     }
 
+    /// Computes the ECDH shared secret between the private key of the current `EcKey` and the
+    /// public key of `peer`, which must be on the same curve.
+    ///
+    /// Returns the x-coordinate of the shared point. It should be passed through a KDF before
+    /// being used as a key.
+    pub fn ecdh(&self, peer: &EcKey) -> Result<Zeroizing<Vec<u8>>> {
+        let mut secret = Zeroizing::new(vec![0u8; self.ec_group()?.affine_coordinate_size()?]);
+        let peer_public_key = peer.public_key_ec_point()?;
+        let kdf = None;
+        // SAFETY: This function only writes to the given buffer within its bounds and reads the
+        // `EC_POINT` and `EC_KEY`, which have been initialized and checked non-null.
+        let ret = unsafe {
+            ECDH_compute_key(
+                secret.as_mut_ptr().cast(),
+                secret.len(),
+                peer_public_key,
+                self.0.as_ptr(),
+                kdf,
+            )
+        };
+        if usize::try_from(ret).ok() != Some(secret.len()) {
+            return Err(to_call_failed_error(ApiName::ECDH_compute_key));
+        }
+        Ok(secret)
+    }
+
     /// Returns the maximum size of an ECDSA signature using the current `EcKey`.
     fn ecdsa_size(&self) -> Result<usize> {
         // SAFETY: This function only reads the `EC_KEY` that has been initialized
@@ -108,6 +108,22 @@ fn ecdsa_p384_signing_and_verification_succeed() -> Result<()> {
     pkey.verify(&signature, MESSAGE1, Some(digester))
 }
 
+#[test]
+fn ecdh_p256_shared_secrets_match() -> Result<()> {
+    let mut ec_key1 = EcKey::new_p256()?;
+    ec_key1.generate_key()?;
+    let mut ec_key2 = EcKey::new_p256()?;
+    ec_key2.generate_key()?;
+    let peer_key2 = EcKey::from_cose_public_key(&ec_key2.cose_public_key()?)?;
+
+    let secret1 = ec_key1.ecdh(&peer_key2)?;
+    let secret2 = ec_key2.ecdh(&ec_key1)?;
+
+    assert_eq!(32, secret1.len());
+    assert_eq!(secret1, secret2);
+    Ok(())
+}
+
 #[test]
 fn verifying_ecdsa_p256_signed_with_a_different_key_fails() -> Result<()> {
     let mut ec_key1 = EcKey::new_p256()?;
@@ -19,7 +19,7 @@
 use core::{
     ffi::CStr,
     fmt::{self, Display, Formatter},
-    ops::Range,
+    ops::{Range, RangeInclusive},
 };
 use libfdt::{AddressRange, Fdt, FdtError, FdtNode};
 use log::debug;
@@ -58,6 +58,10 @@ pub enum PciError {
     },
     /// No suitable PCI memory range found.
     NoSuitableRange,
+    /// Error getting `bus-range` property from PCI node.
+    FdtErrorBusRange(FdtError),
+    /// PCI `bus-range` property is not a valid range of bus numbers.
+    InvalidBusRange,
 }
 
 impl Display for PciError {
@@ -86,6 +90,10 @@ impl Display for PciError {
                 )
             }
             Self::NoSuitableRange => write!(f, "No suitable PCI memory range found."),
+            Self::FdtErrorBusRange(e) => {
+                write!(f, "Error getting bus-range property from PCI node: {}", e)
+            }
+            Self::InvalidBusRange => write!(f, "Invalid bus-range property on PCI node."),
         }
     }
 }
@@ -97,6 +105,8 @@ pub struct PciInfo {
     pub cam_range: Range<usize>,
     /// The MMIO range from which 32-bit PCI BARs should be allocated.
     pub bar_range: Range<u32>,
+    /// The numbers of the buses behind the host bridge, from the first to the last.
+    pub bus_range: RangeInclusive<u8>,
 }
 
 impl PciInfo {
@@ -106,8 +116,9 @@ impl PciInfo {
 
         let cam_range = parse_cam_range(&pci_node)?;
         let bar_range = parse_ranges(&pci_node)?;
+        let bus_range = parse_bus_range(&pci_node)?;
 
-        Ok(Self { cam_range, bar_range })
+        Ok(Self { cam_range, bar_range, bus_range })
     }
 
     /// Returns the `PciRoot` for the memory-mapped CAM found in the FDT. The CAM should be mapped
@@ -154,6 +165,27 @@ fn parse_cam_range(pci_node: &FdtNode) -> Result<Range<usize>, PciError> {
     Ok(cam_addr..cam_addr + cam_size)
 }
 
+/// Parses the "bus-range" property of the given PCI FDT node, which defaults to all the buses.
+fn parse_bus_range(pci_node: &FdtNode) -> Result<RangeInclusive<u8>, PciError> {
+    let Some(mut cells) = pci_node
+        .getprop_cells(CStr::from_bytes_with_nul(b"bus-range\0").unwrap())
+        .map_err(PciError::FdtErrorBusRange)?
+    else {
+        return Ok(0..=u8::MAX);
+    };
+    let (Some(first), Some(last), None) = (cells.next(), cells.next(), cells.next()) else {
+        return Err(PciError::InvalidBusRange);
+    };
+    let first = u8::try_from(first).map_err(|_| PciError::InvalidBusRange)?;
+    let last = u8::try_from(last).map_err(|_| PciError::InvalidBusRange)?;
+    if first > last {
+        return Err(PciError::InvalidBusRange);
+    }
+    debug!("Found PCI buses {:#04x}-{:#04x}", first, last);
+
+    Ok(first..=last)
+}
+
 /// Parses the "ranges" property of the given PCI FDT node, and returns the largest suitable range
 /// to use for non-prefetchable 32-bit memory BARs.
 fn parse_ranges(pci_node: &FdtNode) -> Result<Range<u32>, PciError> {
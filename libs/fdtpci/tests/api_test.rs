@@ -0,0 +1,53 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Integration tests of the library fdtpci.
+
+use fdtpci::{PciError, PciInfo};
+use libfdt::Fdt;
+use std::fs;
+
+const TEST_TREE_PCI_PATH: &str = "data/test_tree_pci.dtb";
+const TEST_TREE_PCI_NO_BUS_RANGE_PATH: &str = "data/test_tree_pci_no_bus_range.dtb";
+const TEST_TREE_PCI_INVALID_BUS_RANGE_PATH: &str = "data/test_tree_pci_invalid_bus_range.dtb";
+
+fn pci_info(path: &str) -> Result<PciInfo, PciError> {
+    let data = fs::read(path).unwrap();
+    let fdt = Fdt::from_slice(&data).unwrap();
+    PciInfo::from_fdt(fdt)
+}
+
+#[test]
+fn pci_info_from_fdt() {
+    let pci_info = pci_info(TEST_TREE_PCI_PATH).unwrap();
+
+    assert_eq!(pci_info.cam_range, 0x10000..0x1010000);
+    assert_eq!(pci_info.bar_range, 0x2000000..0x4000000);
+    assert_eq!(pci_info.bus_range, 0..=3);
+}
+
+#[test]
+fn bus_range_defaults_to_all_buses() {
+    let pci_info = pci_info(TEST_TREE_PCI_NO_BUS_RANGE_PATH).unwrap();
+
+    assert_eq!(pci_info.bus_range, 0..=u8::MAX);
+}
+
+#[test]
+fn invalid_bus_range_is_rejected() {
+    assert_eq!(
+        pci_info(TEST_TREE_PCI_INVALID_BUS_RANGE_PATH).err(),
+        Some(PciError::InvalidBusRange)
+    );
+}
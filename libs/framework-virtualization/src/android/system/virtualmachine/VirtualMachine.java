@@ -24,6 +24,7 @@ import static android.system.virtualmachine.VirtualMachineCallback.ERROR_PAYLOAD
 import static android.system.virtualmachine.VirtualMachineCallback.ERROR_PAYLOAD_VERIFICATION_FAILED;
 import static android.system.virtualmachine.VirtualMachineCallback.ERROR_UNKNOWN;
 import static android.system.virtualmachine.VirtualMachineCallback.STOP_REASON_CRASH;
+import static android.system.virtualmachine.VirtualMachineCallback.STOP_REASON_GUEST_PANIC;
 import static android.system.virtualmachine.VirtualMachineCallback.STOP_REASON_HANGUP;
 import static android.system.virtualmachine.VirtualMachineCallback.STOP_REASON_INFRASTRUCTURE_ERROR;
 import static android.system.virtualmachine.VirtualMachineCallback.STOP_REASON_KILLED;
@@ -32,6 +33,7 @@ import static android.system.virtualmachine.VirtualMachineCallback.STOP_REASON_M
 import static android.system.virtualmachine.VirtualMachineCallback.STOP_REASON_MICRODROID_PAYLOAD_HAS_CHANGED;
 import static android.system.virtualmachine.VirtualMachineCallback.STOP_REASON_MICRODROID_PAYLOAD_VERIFICATION_FAILED;
 import static android.system.virtualmachine.VirtualMachineCallback.STOP_REASON_MICRODROID_UNKNOWN_RUNTIME_ERROR;
+import static android.system.virtualmachine.VirtualMachineCallback.STOP_REASON_PAYLOAD_WATCHDOG_EXPIRED;
 import static android.system.virtualmachine.VirtualMachineCallback.STOP_REASON_PVM_FIRMWARE_INSTANCE_IMAGE_CHANGED;
 import static android.system.virtualmachine.VirtualMachineCallback.STOP_REASON_PVM_FIRMWARE_PUBLIC_KEY_MISMATCH;
 import static android.system.virtualmachine.VirtualMachineCallback.STOP_REASON_REBOOT;
@@ -67,10 +69,13 @@ import android.system.ErrnoException;
 import android.system.OsConstants;
 import android.system.virtualizationcommon.DeathReason;
 import android.system.virtualizationcommon.ErrorCode;
+import android.system.virtualizationservice.DeprecationWarning;
+import android.system.virtualizationservice.GuestCrashReason;
 import android.system.virtualizationservice.IVirtualMachine;
 import android.system.virtualizationservice.IVirtualMachineCallback;
 import android.system.virtualizationservice.IVirtualizationService;
 import android.system.virtualizationservice.InputDevice;
+import android.system.virtualizationservice.NetworkFirewall;
 import android.system.virtualizationservice.PartitionType;
 import android.system.virtualizationservice.VirtualMachineAppConfig;
 import android.system.virtualizationservice.VirtualMachineRawConfig;
@@ -513,7 +518,7 @@ public class VirtualMachine implements AutoCloseable {
                 }
                 if (vm.mInstanceIdPath != null) {
                     vm.importInstanceIdFrom(vmDescriptor.getInstanceIdFd());
-                    vm.claimInstance();
+                    vm.claimInstance(vmDescriptor.getInstanceTransferToken());
                 }
             }
             return vm;
@@ -680,13 +685,14 @@ public class VirtualMachine implements AutoCloseable {
     }
 
     // Claim the instance. This notifies the global VS about the ownership of this
-    // instance_id for housekeeping purpose.
-    void claimInstance() throws VirtualMachineException {
+    // instance_id for housekeeping purpose. The transfer token, handed over by the app which
+    // exported the VM, lets another app claim it.
+    void claimInstance(@Nullable byte[] transferToken) throws VirtualMachineException {
         if (mInstanceIdPath != null) {
             IVirtualizationService service = mVirtualizationService.getBinder();
             try {
                 byte[] instanceId = Files.readAllBytes(mInstanceIdPath.toPath());
-                service.claimVmInstance(instanceId);
+                service.claimVmInstance(instanceId, transferToken);
             } catch (IOException e) {
                 throw new VirtualMachineException("failed to read instance_id", e);
             } catch (RemoteException e) {
@@ -695,6 +701,20 @@ public class VirtualMachine implements AutoCloseable {
         }
     }
 
+    // Lets the app which imports the VM from a descriptor claim the instance, with the returned
+    // token.
+    private byte[] allowInstanceTransfer() throws VirtualMachineException {
+        IVirtualizationService service = mVirtualizationService.getBinder();
+        try {
+            byte[] instanceId = Files.readAllBytes(mInstanceIdPath.toPath());
+            return service.allowVmInstanceTransfer(instanceId);
+        } catch (IOException e) {
+            throw new VirtualMachineException("failed to read instance_id", e);
+        } catch (RemoteException e) {
+            throw e.rethrowAsRuntimeException();
+        }
+    }
+
     @GuardedBy("VirtualMachineManager.sCreateLock")
     @NonNull
     private static File createVmDir(@NonNull Context context, @NonNull String name)
@@ -964,11 +984,26 @@ public class VirtualMachine implements AutoCloseable {
         // Handle network support
         if (vmConfig.getCustomImageConfig() != null) {
             rawConfig.networkSupported = vmConfig.getCustomImageConfig().useNetwork();
+            if (rawConfig.networkSupported) {
+                // Custom images run general purpose OSes, which may reach any destination.
+                rawConfig.networkFirewall = allowAllEgress();
+            }
         }
 
         return android.system.virtualizationservice.VirtualMachineConfig.rawConfig(rawConfig);
     }
 
+    private static NetworkFirewall allowAllEgress() {
+        NetworkFirewall firewall = new NetworkFirewall();
+        String[] destinations = {"0.0.0.0/0", "::/0"};
+        firewall.allowedEgress = new NetworkFirewall.EgressRule[destinations.length];
+        for (int i = 0; i < destinations.length; i++) {
+            firewall.allowedEgress[i] = new NetworkFirewall.EgressRule();
+            firewall.allowedEgress[i].destination = destinations[i];
+        }
+        return firewall;
+    }
+
     private static record InputEvent(short type, short code, int value) {}
 
     /** @hide */
@@ -1579,6 +1614,17 @@ public class VirtualMachine implements AutoCloseable {
 
                 mVirtualMachine =
                         service.createVm(vmConfigParcel, consoleOutFd, consoleInFd, mLogWriter);
+                for (DeprecationWarning warning : mVirtualMachine.getDeprecationWarnings()) {
+                    Log.w(
+                            TAG,
+                            "VM config is deprecated: "
+                                    + warning.message
+                                    + " ("
+                                    + warning.id
+                                    + "). "
+                                    + warning.replacement
+                                    + ".");
+                }
                 mVirtualMachine.registerCallback(new CallbackTranslator(service));
                 if (mMemoryManagementCallbacks != null) {
                     mContext.registerComponentCallbacks(mMemoryManagementCallbacks);
@@ -1867,6 +1913,57 @@ public class VirtualMachine implements AutoCloseable {
         }
     }
 
+    /**
+     * Answers a request of the payload for a host file, notified with {@link
+     * VirtualMachineCallback#onHostFileRequested}. Only the content of the file is sent to the VM,
+     * as a read-only stream.
+     *
+     * @param requestId the ID of the request being answered.
+     * @param file the file chosen by the user, or {@code null} to decline the request.
+     * @throws VirtualMachineException if the VM is not running or the request is not pending.
+     * @hide
+     */
+    public void provideHostFile(int requestId, @Nullable ParcelFileDescriptor file)
+            throws VirtualMachineException {
+        synchronized (mLock) {
+            if (mVirtualMachine == null) {
+                throw new VirtualMachineException("VM is not running");
+            }
+            try {
+                mVirtualMachine.provideHostFile(requestId, file);
+            } catch (RemoteException e) {
+                throw e.rethrowAsRuntimeException();
+            } catch (IllegalStateException e) {
+                throw new VirtualMachineException(e);
+            }
+        }
+    }
+
+    /**
+     * Sets the file which the ramdump is written to if the guest kernel crashes. {@link
+     * VirtualMachineCallback#onRamdumpAvailable} is called once it has been written.
+     *
+     * <p>Only available if the VM is debuggable and collection of ramdumps is enabled.
+     *
+     * @hide
+     */
+    public void setRamdumpOutput(@NonNull ParcelFileDescriptor output)
+            throws VirtualMachineException {
+        requireNonNull(output, "output must not be null");
+        synchronized (mLock) {
+            if (mVirtualMachine == null) {
+                throw new VirtualMachineException("VM is not running");
+            }
+            try {
+                mVirtualMachine.setRamdumpOutput(output);
+            } catch (RemoteException e) {
+                throw e.rethrowAsRuntimeException();
+            } catch (IllegalStateException e) {
+                throw new VirtualMachineException(e);
+            }
+        }
+    }
+
     /**
      * Stops this virtual machine, if it is running.
      *
@@ -2094,7 +2191,8 @@ public class VirtualMachine implements AutoCloseable {
                         ParcelFileDescriptor.open(mInstanceFilePath, MODE_READ_ONLY),
                         mEncryptedStoreFilePath != null
                                 ? ParcelFileDescriptor.open(mEncryptedStoreFilePath, MODE_READ_ONLY)
-                                : null);
+                                : null,
+                        mInstanceIdPath != null ? allowInstanceTransfer() : null);
             } catch (IOException e) {
                 throw new VirtualMachineException(e);
             }
@@ -2284,6 +2382,30 @@ public class VirtualMachine implements AutoCloseable {
             executeCallback((cb) -> cb.onError(VirtualMachine.this, translatedError, message));
         }
 
+        @Override
+        public void onHostFileRequested(int cid, int requestId, String mimeType) {
+            executeCallback(
+                    (cb) -> cb.onHostFileRequested(VirtualMachine.this, requestId, mimeType));
+        }
+
+        @Override
+        public void onRamdumpAvailable(int cid) {
+            executeCallback((cb) -> cb.onRamdumpAvailable(VirtualMachine.this));
+        }
+
+        @Override
+        public void onRestarted(int cid) {
+            executeCallback((cb) -> cb.onRestarted(VirtualMachine.this));
+        }
+
+        @Override
+        public void onGuestCrashReported(int cid, GuestCrashReason reason) {
+            executeCallback(
+                    (cb) ->
+                            cb.onGuestCrashReported(
+                                    VirtualMachine.this, reason.kind, reason.message));
+        }
+
         @Override
         public void onDied(int cid, int reason) {
             int translatedReason = getTranslatedReason(reason);
@@ -2342,6 +2464,10 @@ public class VirtualMachine implements AutoCloseable {
                     return STOP_REASON_MICRODROID_UNKNOWN_RUNTIME_ERROR;
                 case DeathReason.HANGUP:
                     return STOP_REASON_HANGUP;
+                case DeathReason.GUEST_PANIC:
+                    return STOP_REASON_GUEST_PANIC;
+                case DeathReason.PAYLOAD_WATCHDOG_EXPIRED:
+                    return STOP_REASON_PAYLOAD_WATCHDOG_EXPIRED;
                 default:
                     return STOP_REASON_UNKNOWN;
             }
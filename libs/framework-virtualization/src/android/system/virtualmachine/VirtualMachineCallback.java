@@ -83,6 +83,8 @@ public interface VirtualMachineCallback {
                 STOP_REASON_MICRODROID_INVALID_PAYLOAD_CONFIG,
                 STOP_REASON_MICRODROID_UNKNOWN_RUNTIME_ERROR,
                 STOP_REASON_HANGUP,
+                STOP_REASON_GUEST_PANIC,
+                STOP_REASON_PAYLOAD_WATCHDOG_EXPIRED,
             })
     @interface StopReason {}
 
@@ -142,6 +144,20 @@ public interface VirtualMachineCallback {
     /** The VM killed due to hangup */
     int STOP_REASON_HANGUP = 16;
 
+    /**
+     * The guest kernel panicked and notified it, as configured by the panic policy of the VM.
+     *
+     * @hide
+     */
+    int STOP_REASON_GUEST_PANIC = 18;
+
+    /**
+     * The payload started its watchdog, then stopped kicking it in time, so the VM was killed.
+     *
+     * @hide
+     */
+    int STOP_REASON_PAYLOAD_WATCHDOG_EXPIRED = 19;
+
     /** Called when the payload starts in the VM. */
     void onPayloadStarted(@NonNull VirtualMachine vm);
 
@@ -157,6 +173,49 @@ public interface VirtualMachineCallback {
     /** Called when an error occurs in the VM. */
     void onError(@NonNull VirtualMachine vm, @ErrorCode int errorCode, @NonNull String message);
 
+    /**
+     * Called when the guest kernel has crashed and its ramdump has been written to the file set
+     * with {@link VirtualMachine#setRamdumpOutput}. This is called before {@link #onStopped}.
+     *
+     * @hide
+     */
+    default void onRamdumpAvailable(@NonNull VirtualMachine vm) {}
+
+    /**
+     * Called when the payload requests a host file of the given MIME type. The app should let the
+     * user pick a file, e.g. with {@link android.content.Intent#ACTION_OPEN_DOCUMENT}, and answer
+     * with {@link VirtualMachine#provideHostFile}. The request is declined by default.
+     *
+     * @hide
+     */
+    default void onHostFileRequested(
+            @NonNull VirtualMachine vm, int requestId, @NonNull String mimeType) {
+        try {
+            vm.provideHostFile(requestId, null);
+        } catch (VirtualMachineException e) {
+            // The request is no longer pending.
+        }
+    }
+
+    /**
+     * Called when the guest kernel panicked and the VM was started again, as configured by its
+     * panic policy. The payload starts over, so {@link #onPayloadStarted} and the following
+     * callbacks are called again.
+     *
+     * @hide
+     */
+    default void onRestarted(@NonNull VirtualMachine vm) {}
+
+    /**
+     * Called when the guest reported why it crashed, before {@link #onStopped}. {@code kind} is one
+     * of the values of {@code GuestCrashReason.Kind}, and {@code message} gives details for
+     * debugging.
+     *
+     * @hide
+     */
+    default void onGuestCrashReported(
+            @NonNull VirtualMachine vm, int kind, @NonNull String message) {}
+
     /** Called when the VM has stopped. */
     void onStopped(@NonNull VirtualMachine vm, @StopReason int reason);
 }
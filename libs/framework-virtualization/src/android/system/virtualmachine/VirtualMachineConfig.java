@@ -35,11 +35,15 @@ import android.content.Context;
 import android.content.pm.ApplicationInfo;
 import android.content.pm.PackageManager;
 import android.os.Build;
+import android.os.LocaleList;
 import android.os.ParcelFileDescriptor;
 import android.os.PersistableBundle;
 import android.sysprop.HypervisorProperties;
 import android.system.virtualizationservice.DiskImage;
 import android.system.virtualizationservice.Partition;
+import android.system.virtualizationservice.PmemDisk;
+import android.system.virtualizationservice.PortForwardingRule;
+import android.system.virtualizationservice.SharedDirectory;
 import android.system.virtualizationservice.UsbConfig;
 import android.system.virtualizationservice.VirtualMachineAppConfig;
 import android.system.virtualizationservice.VirtualMachinePayloadConfig;
@@ -96,6 +100,7 @@ public final class VirtualMachineConfig {
     private static final String KEY_CPU_TOPOLOGY = "cpuTopology";
     private static final String KEY_CONSOLE_INPUT_DEVICE = "consoleInputDevice";
     private static final String KEY_ENCRYPTED_STORAGE_BYTES = "encryptedStorageBytes";
+    private static final String KEY_ENCRYPTED_STORAGE_MAX_BYTES = "encryptedStorageMaxBytes";
     private static final String KEY_VM_OUTPUT_CAPTURED = "vmOutputCaptured";
     private static final String KEY_VM_CONSOLE_INPUT_SUPPORTED = "vmConsoleInputSupported";
     private static final String KEY_CONNECT_VM_CONSOLE = "connectVmConsole";
@@ -104,6 +109,7 @@ public final class VirtualMachineConfig {
     private static final String KEY_EXTRA_APKS = "extraApks";
     private static final String KEY_SHOULD_BOOST_UCLAMP = "shouldBoostUclamp";
     private static final String KEY_SHOULD_USE_HUGEPAGES = "shouldUseHugepages";
+    private static final String KEY_EXPOSED_HOST_PROPERTY_PREFIXES = "exposedHostPropertyPrefixes";
 
     /** @hide */
     @Retention(RetentionPolicy.SOURCE)
@@ -192,6 +198,9 @@ public final class VirtualMachineConfig {
     /** The size of storage in bytes. 0 indicates that encryptedStorage is not required */
     private final long mEncryptedStorageBytes;
 
+    /** The size up to which the payload may grow the storage in bytes, or 0 if it may not. */
+    private final long mEncryptedStorageMaxBytes;
+
     /** Whether the app can read console and log output. */
     private final boolean mVmOutputCaptured;
 
@@ -210,6 +219,9 @@ public final class VirtualMachineConfig {
 
     private final boolean mShouldUseHugepages;
 
+    /** Prefixes of the names of the host system properties which the payload may read. */
+    private final List<String> mExposedHostPropertyPrefixes;
+
     @Retention(RetentionPolicy.SOURCE)
     @StringDef(
             prefix = "MICRODROID",
@@ -240,13 +252,15 @@ public final class VirtualMachineConfig {
             @CpuTopology int cpuTopology,
             @Nullable String consoleInputDevice,
             long encryptedStorageBytes,
+            long encryptedStorageMaxBytes,
             boolean vmOutputCaptured,
             boolean vmConsoleInputSupported,
             boolean connectVmConsole,
             @Nullable File vendorDiskImage,
             @NonNull @OsName String os,
             boolean shouldBoostUclamp,
-            boolean shouldUseHugepages) {
+            boolean shouldUseHugepages,
+            List<String> exposedHostPropertyPrefixes) {
         // This is only called from Builder.build(); the builder handles parameter validation.
         mPackageName = packageName;
         mApkPath = apkPath;
@@ -264,6 +278,7 @@ public final class VirtualMachineConfig {
         mCpuTopology = cpuTopology;
         mConsoleInputDevice = consoleInputDevice;
         mEncryptedStorageBytes = encryptedStorageBytes;
+        mEncryptedStorageMaxBytes = encryptedStorageMaxBytes;
         mVmOutputCaptured = vmOutputCaptured;
         mVmConsoleInputSupported = vmConsoleInputSupported;
         mConnectVmConsole = connectVmConsole;
@@ -271,6 +286,11 @@ public final class VirtualMachineConfig {
         mOs = os;
         mShouldBoostUclamp = shouldBoostUclamp;
         mShouldUseHugepages = shouldUseHugepages;
+        mExposedHostPropertyPrefixes =
+                exposedHostPropertyPrefixes.isEmpty()
+                        ? Collections.emptyList()
+                        : Collections.unmodifiableList(
+                                Arrays.asList(exposedHostPropertyPrefixes.toArray(new String[0])));
     }
 
     /** Loads a config from a file. */
@@ -353,6 +373,10 @@ public final class VirtualMachineConfig {
         if (encryptedStorageBytes != 0) {
             builder.setEncryptedStorageBytes(encryptedStorageBytes);
         }
+        long encryptedStorageMaxBytes = b.getLong(KEY_ENCRYPTED_STORAGE_MAX_BYTES);
+        if (encryptedStorageMaxBytes != 0) {
+            builder.setEncryptedStorageMaxBytes(encryptedStorageMaxBytes);
+        }
         builder.setVmOutputCaptured(b.getBoolean(KEY_VM_OUTPUT_CAPTURED));
         builder.setVmConsoleInputSupported(b.getBoolean(KEY_VM_CONSOLE_INPUT_SUPPORTED));
         builder.setConnectVmConsole(b.getBoolean(KEY_CONNECT_VM_CONSOLE));
@@ -374,6 +398,13 @@ public final class VirtualMachineConfig {
         builder.setShouldBoostUclamp(b.getBoolean(KEY_SHOULD_BOOST_UCLAMP));
         builder.setShouldUseHugepages(b.getBoolean(KEY_SHOULD_USE_HUGEPAGES));
 
+        String[] exposedHostPropertyPrefixes = b.getStringArray(KEY_EXPOSED_HOST_PROPERTY_PREFIXES);
+        if (exposedHostPropertyPrefixes != null) {
+            for (String prefix : exposedHostPropertyPrefixes) {
+                builder.addExposedHostPropertyPrefix(prefix);
+            }
+        }
+
         return builder.build();
     }
 
@@ -413,6 +444,9 @@ public final class VirtualMachineConfig {
         if (mEncryptedStorageBytes > 0) {
             b.putLong(KEY_ENCRYPTED_STORAGE_BYTES, mEncryptedStorageBytes);
         }
+        if (mEncryptedStorageMaxBytes > 0) {
+            b.putLong(KEY_ENCRYPTED_STORAGE_MAX_BYTES, mEncryptedStorageMaxBytes);
+        }
         b.putBoolean(KEY_VM_OUTPUT_CAPTURED, mVmOutputCaptured);
         b.putBoolean(KEY_VM_CONSOLE_INPUT_SUPPORTED, mVmConsoleInputSupported);
         b.putBoolean(KEY_CONNECT_VM_CONSOLE, mConnectVmConsole);
@@ -426,6 +460,10 @@ public final class VirtualMachineConfig {
         }
         b.putBoolean(KEY_SHOULD_BOOST_UCLAMP, mShouldBoostUclamp);
         b.putBoolean(KEY_SHOULD_USE_HUGEPAGES, mShouldUseHugepages);
+        if (!mExposedHostPropertyPrefixes.isEmpty()) {
+            String[] prefixes = mExposedHostPropertyPrefixes.toArray(new String[0]);
+            b.putStringArray(KEY_EXPOSED_HOST_PROPERTY_PREFIXES, prefixes);
+        }
         b.writeToStream(output);
     }
 
@@ -454,6 +492,19 @@ public final class VirtualMachineConfig {
         return mExtraApks;
     }
 
+    /**
+     * Returns the prefixes of the names of the host system properties which the payload may read,
+     * in the order in which they were added via {@link Builder#addExposedHostPropertyPrefix}.
+     *
+     * @hide
+     */
+    @TestApi
+    @FlaggedApi(Flags.FLAG_AVF_V_TEST_APIS)
+    @NonNull
+    public List<String> getExposedHostPropertyPrefixes() {
+        return mExposedHostPropertyPrefixes;
+    }
+
     /**
      * Returns the path within the APK to the payload config file that defines software aspects of
      * the VM.
@@ -554,6 +605,20 @@ public final class VirtualMachineConfig {
         return mEncryptedStorageBytes;
     }
 
+    /**
+     * Returns the size (in bytes) up to which the payload may grow the encrypted storage, or 0 if
+     * it may not grow it.
+     *
+     * @see Builder#setEncryptedStorageMaxBytes
+     * @hide
+     */
+    @TestApi
+    @FlaggedApi(Flags.FLAG_AVF_V_TEST_APIS)
+    @IntRange(from = 0)
+    public long getEncryptedStorageMaxBytes() {
+        return mEncryptedStorageMaxBytes;
+    }
+
     /**
      * Returns whether the app can read the VM console or log output. If not, the VM output is
      * automatically forwarded to the host logcat.
@@ -725,6 +790,11 @@ public final class VirtualMachineConfig {
         config.cpuTopology = (byte) this.mCpuTopology;
         config.consoleInputDevice = mConsoleInputDevice;
         config.devices = EMPTY_STRING_ARRAY;
+        config.portForwardingRules = new PortForwardingRule[0];
+        config.vendorDtOverlays = new ParcelFileDescriptor[0];
+        config.vhostUserDevices = EMPTY_STRING_ARRAY;
+        config.sharedDirectories = new SharedDirectory[0];
+        config.pmemDisks = new PmemDisk[0];
         config.platformVersion = "~1.0";
         config.audioConfig =
                 Optional.ofNullable(customImageConfig.getAudioConfig())
@@ -805,8 +875,19 @@ public final class VirtualMachineConfig {
             vsConfig.customConfig = customConfig;
         }
 
+        vsConfig.encryptedStorageMaxBytes = mEncryptedStorageMaxBytes;
         vsConfig.boostUclamp = mShouldBoostUclamp;
         vsConfig.hugePages = mShouldUseHugepages;
+        vsConfig.sharedDirectories = new SharedDirectory[0];
+        vsConfig.pmemDisks = new PmemDisk[0];
+
+        // Lets the payload localize the strings it returns to the app.
+        LocaleList locales = LocaleList.getDefault();
+        vsConfig.hostLocales = new String[locales.size()];
+        for (int i = 0; i < locales.size(); i++) {
+            vsConfig.hostLocales[i] = locales.get(i).toLanguageTag();
+        }
+        vsConfig.exposedHostPropertyPrefixes = mExposedHostPropertyPrefixes.toArray(new String[0]);
 
         return vsConfig;
     }
@@ -882,6 +963,7 @@ public final class VirtualMachineConfig {
         @CpuTopology private int mCpuTopology = CPU_TOPOLOGY_ONE_CPU;
         @Nullable private String mConsoleInputDevice;
         private long mEncryptedStorageBytes;
+        private long mEncryptedStorageMaxBytes;
         private boolean mVmOutputCaptured = false;
         private boolean mVmConsoleInputSupported = false;
         private boolean mConnectVmConsole = false;
@@ -889,6 +971,7 @@ public final class VirtualMachineConfig {
         @NonNull @OsName private String mOs = DEFAULT_OS;
         private boolean mShouldBoostUclamp = false;
         private boolean mShouldUseHugepages = false;
+        private final List<String> mExposedHostPropertyPrefixes = new ArrayList<>();
 
         /**
          * Creates a builder for the given context.
@@ -965,6 +1048,12 @@ public final class VirtualMachineConfig {
                         "debug level must be FULL to connect to the console");
             }
 
+            if (mEncryptedStorageMaxBytes != 0
+                    && mEncryptedStorageMaxBytes < mEncryptedStorageBytes) {
+                throw new IllegalStateException(
+                        "maximum encrypted storage size must not be below its size");
+            }
+
             return new VirtualMachineConfig(
                     packageName,
                     apkPath,
@@ -978,13 +1067,15 @@ public final class VirtualMachineConfig {
                     mCpuTopology,
                     mConsoleInputDevice,
                     mEncryptedStorageBytes,
+                    mEncryptedStorageMaxBytes,
                     mVmOutputCaptured,
                     mVmConsoleInputSupported,
                     mConnectVmConsole,
                     mVendorDiskImage,
                     mOs,
                     mShouldBoostUclamp,
-                    mShouldUseHugepages);
+                    mShouldUseHugepages,
+                    mExposedHostPropertyPrefixes);
         }
 
         /**
@@ -1020,6 +1111,25 @@ public final class VirtualMachineConfig {
             return this;
         }
 
+        /**
+         * Lets the payload read the host system properties whose names start with {@code prefix},
+         * e.g. {@code "ro.build.version."}. No property is exposed unless it matches one of the
+         * added prefixes.
+         *
+         * <p>The prefix must be within the namespaces which the owner of the VM may expose, e.g.
+         * the version of the build or the model of the device. Otherwise creating the VM fails.
+         *
+         * @hide
+         */
+        @TestApi
+        @FlaggedApi(Flags.FLAG_AVF_V_TEST_APIS)
+        @NonNull
+        public Builder addExposedHostPropertyPrefix(@NonNull String prefix) {
+            mExposedHostPropertyPrefixes.add(
+                    requireNonNull(prefix, "host property prefix must not be null"));
+            return this;
+        }
+
         /**
          * Sets the path within the APK to the payload config file that defines software aspects of
          * the VM. The file is a JSON file; see
@@ -1199,6 +1309,29 @@ public final class VirtualMachineConfig {
             return this;
         }
 
+        /**
+         * Sets the size (in bytes) up to which the payload may grow the encrypted storage, with
+         * {@code AVmPayload_growEncryptedStorage}. If not set, the storage keeps the size set with
+         * {@link #setEncryptedStorageBytes}, which must not exceed this.
+         *
+         * <p>The backing file is extended while the VM runs, but the payload only sees the larger
+         * storage from the next boot of the VM.
+         *
+         * @hide
+         */
+        @TestApi
+        @FlaggedApi(Flags.FLAG_AVF_V_TEST_APIS)
+        @NonNull
+        public Builder setEncryptedStorageMaxBytes(
+                @IntRange(from = 1) long encryptedStorageMaxBytes) {
+            if (encryptedStorageMaxBytes <= 0) {
+                throw new IllegalArgumentException(
+                        "Maximum encrypted storage size must be positive");
+            }
+            mEncryptedStorageMaxBytes = encryptedStorageMaxBytes;
+            return this;
+        }
+
         /**
          * Sets whether to allow the app to read the VM outputs (console / log). Default is {@code
          * false}.
@@ -47,6 +47,9 @@ public final class VirtualMachineDescriptor implements Parcelable, AutoCloseable
     // File descriptor of the image backing the encrypted storage - Will be null if encrypted
     // storage is not enabled. */
     @Nullable private final ParcelFileDescriptor mEncryptedStoreFd;
+    // Token which lets the importing app claim the instance of the VM - will be null iff
+    // FEATURE_LLPVM_CHANGES is disabled.
+    @Nullable private final byte[] mInstanceTransferToken;
 
     @Override
     public int describeContents() {
@@ -60,6 +63,7 @@ public final class VirtualMachineDescriptor implements Parcelable, AutoCloseable
         out.writeParcelable(mInstanceIdFd, flags);
         out.writeParcelable(mInstanceImgFd, flags);
         out.writeParcelable(mEncryptedStoreFd, flags);
+        out.writeByteArray(mInstanceTransferToken);
     }
 
     @NonNull
@@ -111,15 +115,28 @@ public final class VirtualMachineDescriptor implements Parcelable, AutoCloseable
         return mEncryptedStoreFd;
     }
 
+    /**
+     * @return Token which lets the importing app claim the instance of the VM from the exporting
+     *     app.
+     *     <p>This method will return null iff FEATURE_LLPVM_CHANGES is disabled.
+     */
+    @Nullable
+    byte[] getInstanceTransferToken() {
+        checkNotClosed();
+        return mInstanceTransferToken;
+    }
+
     VirtualMachineDescriptor(
             @NonNull ParcelFileDescriptor configFd,
             @Nullable ParcelFileDescriptor instanceIdFd,
             @NonNull ParcelFileDescriptor instanceImgFd,
-            @Nullable ParcelFileDescriptor encryptedStoreFd) {
+            @Nullable ParcelFileDescriptor encryptedStoreFd,
+            @Nullable byte[] instanceTransferToken) {
         mConfigFd = requireNonNull(configFd);
         mInstanceIdFd = instanceIdFd;
         mInstanceImgFd = requireNonNull(instanceImgFd);
         mEncryptedStoreFd = encryptedStoreFd;
+        mInstanceTransferToken = instanceTransferToken;
     }
 
     private VirtualMachineDescriptor(Parcel in) {
@@ -127,6 +144,7 @@ public final class VirtualMachineDescriptor implements Parcelable, AutoCloseable
         mInstanceIdFd = readParcelFileDescriptor(in);
         mInstanceImgFd = requireNonNull(readParcelFileDescriptor(in));
         mEncryptedStoreFd = readParcelFileDescriptor(in);
+        mInstanceTransferToken = in.createByteArray();
     }
 
     private ParcelFileDescriptor readParcelFileDescriptor(Parcel in) {
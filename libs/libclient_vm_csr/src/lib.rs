@@ -15,6 +15,10 @@
 //! Generate the attestation key and CSR for client VM in the remote
 //! attestation.
 
+mod sealed_key;
+
+pub use sealed_key::open_sealed_key;
+
 use anyhow::{anyhow, Context, Result};
 use coset::{
     iana, CborSerializable, CoseKey, CoseKeyBuilder, CoseSign, CoseSignBuilder, CoseSignature,
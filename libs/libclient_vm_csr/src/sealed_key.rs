@@ -0,0 +1,164 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Decrypt the sealed keys derived by the service VM for the client VM.
+
+use crate::{to_cose_public_key, ATTESTATION_KEY_NID};
+use anyhow::{anyhow, ensure, Context, Result};
+use cbor_util::get_label_value_as_bytes;
+use ciborium::Value;
+use coset::{iana, iana::EnumI64, CborSerializable, CoseKey, CoseSign1, Label};
+use diced_open_dice::DiceArtifacts;
+use hwtrust::{dice, session::Session};
+use openssl::{
+    bn::BigNum,
+    derive::Deriver,
+    ec::{EcGroup, EcKey},
+    hkdf::hkdf,
+    md::Md,
+    pkey::{PKey, Public},
+    symm::{decrypt_aead, Cipher},
+};
+use service_vm_comm::{SealedKey, SignedPublicKeys, SEALED_KEY_KEK_INFO, SEALED_KEY_SIZE};
+use zeroize::Zeroizing;
+
+const AES_GCM_NONCE_LENGTH: usize = 12;
+const AES_GCM_TAG_LENGTH: usize = 16;
+
+/// Decrypts the CBOR-encoded `sealed_key` returned by the service VM for a CSR built with the
+/// DER-encoded ECPrivateKey `private_key` and `dice_artifacts`.
+///
+/// The sealed key is only decrypted if it was encrypted by a service VM running on the same
+/// device and firmware as this VM, see `SealedKey`.
+pub fn open_sealed_key(
+    private_key: &[u8],
+    dice_artifacts: &dyn DiceArtifacts,
+    sealed_key: &[u8],
+) -> Result<Zeroizing<Vec<u8>>> {
+    let SealedKey { service_vm_dice_chain, signed_public_keys, encrypted_key } =
+        SealedKey::from_cbor_slice(sealed_key)
+            .map_err(|e| anyhow!("Failed to deserialize the sealed key: {e:?}"))?;
+    ensure!(
+        encrypted_key.len() == SEALED_KEY_SIZE + AES_GCM_TAG_LENGTH,
+        "Invalid encrypted sealed key size: {}",
+        encrypted_key.len()
+    );
+    let private_key = EcKey::private_key_from_der(private_key)?;
+    let client_vm_public_key = to_cose_public_key(&private_key)?
+        .to_vec()
+        .map_err(|e| anyhow!("Failed to serialize the public key: {e:?}"))?;
+    let dice_chain = dice_artifacts.bcc().context("The DICE chain of the VM is missing")?;
+    let service_vm_public_key = verify_service_vm_public_key(
+        &signed_public_keys,
+        &service_vm_dice_chain,
+        dice_chain,
+        &client_vm_public_key,
+    )?;
+    let service_vm_public_key = PKey::from_ec_key(to_ec_public_key(&service_vm_public_key)?)?;
+    let private_key = PKey::from_ec_key(private_key)?;
+
+    let mut deriver = Deriver::new(&private_key)?;
+    deriver.set_peer(&service_vm_public_key)?;
+    let shared_secret = Zeroizing::new(deriver.derive_to_vec()?);
+    let mut kek = Zeroizing::new([0u8; 32]);
+    hkdf(kek.as_mut(), Md::sha512(), &shared_secret, &[], SEALED_KEY_KEK_INFO)
+        .context("Failed to derive the key encryption key")?;
+
+    let (ciphertext, tag) = encrypted_key.split_at(SEALED_KEY_SIZE);
+    let nonce = [0u8; AES_GCM_NONCE_LENGTH];
+    let aad = &[];
+    let key = decrypt_aead(Cipher::aes_256_gcm(), kek.as_ref(), Some(&nonce), aad, ciphertext, tag)
+        .context("Failed to decrypt the sealed key")?;
+    Ok(Zeroizing::new(key))
+}
+
+/// Checks that the ephemeral public key of the service VM in `signed_public_keys` is signed, for
+/// `client_vm_public_key`, by the leaf of `service_vm_dice_chain`, and that this DICE chain
+/// describes a service VM loaded by the same device and firmware as `dice_chain` of this VM.
+/// Returns the public key of the service VM.
+fn verify_service_vm_public_key(
+    signed_public_keys: &[u8],
+    service_vm_dice_chain: &[u8],
+    dice_chain: &[u8],
+    client_vm_public_key: &[u8],
+) -> Result<CoseKey> {
+    // The DICE chains of both VMs share the root public key and the entries up to pvmfw. Only
+    // their last entries, describing the service VM on one side, and the kernel and the payload
+    // of this VM on the other side, differ.
+    let service_vm_entries = dice_chain_entries(service_vm_dice_chain)?;
+    let entries = dice_chain_entries(dice_chain)?;
+    let (_, firmware_entries) =
+        service_vm_entries.split_last().context("The DICE chain of the service VM is empty")?;
+    ensure!(
+        entries.len() > service_vm_entries.len() && entries.starts_with(firmware_entries),
+        "The service VM isn't loaded by the same device and firmware as this VM"
+    );
+
+    let session = Session::default();
+    let service_vm_chain = dice::Chain::from_cbor(&session, service_vm_dice_chain)
+        .context("Invalid DICE chain of the service VM")?;
+    let chain = dice::Chain::from_cbor(&session, dice_chain).context("Invalid DICE chain")?;
+    let service_vm = service_vm_chain.leaf();
+    // The entry of this VM loaded by pvmfw, i.e. its kernel.
+    let kernel = &chain.payloads()[service_vm_chain.payloads().len() - 1];
+    ensure!(
+        service_vm.authority_hash() == kernel.authority_hash(),
+        "The service VM isn't signed with the same key as the kernel of this VM"
+    );
+    ensure!(
+        kernel.mode() != dice::DiceMode::Normal || service_vm.mode() == dice::DiceMode::Normal,
+        "The service VM is debuggable but this VM isn't"
+    );
+
+    let signed_public_keys = CoseSign1::from_slice(signed_public_keys)
+        .map_err(|e| anyhow!("Failed to deserialize the signed public keys: {e:?}"))?;
+    signed_public_keys
+        .verify_signature(&[], |signature, message| {
+            service_vm.subject_public_key().verify(signature, message)
+        })
+        .context("The public keys aren't signed by the service VM")?;
+    let payload = signed_public_keys.payload.context("The signed public keys are missing")?;
+    let SignedPublicKeys { service_vm_public_key, client_vm_public_key: signed_client_key } =
+        SignedPublicKeys::from_cbor_slice(&payload)
+            .map_err(|e| anyhow!("Failed to deserialize the signed public keys: {e:?}"))?;
+    ensure!(
+        signed_client_key == client_vm_public_key,
+        "The sealed key was encrypted for another public key"
+    );
+    CoseKey::from_slice(&service_vm_public_key)
+        .map_err(|e| anyhow!("Failed to deserialize the service VM public key: {e:?}"))
+}
+
+fn dice_chain_entries(dice_chain: &[u8]) -> Result<Vec<Value>> {
+    match Value::from_slice(dice_chain) {
+        Ok(Value::Array(entries)) => Ok(entries),
+        Ok(_) => Err(anyhow!("The DICE chain isn't an array")),
+        Err(e) => Err(anyhow!("Failed to deserialize the DICE chain: {e:?}")),
+    }
+}
+
+fn to_ec_public_key(cose_key: &CoseKey) -> Result<EcKey<Public>> {
+    let group = EcGroup::from_curve_name(ATTESTATION_KEY_NID)?;
+    let x = get_label_value_as_bytes(cose_key, Label::Int(iana::Ec2KeyParameter::X.to_i64()))
+        .map_err(|e| anyhow!("Failed to get the x coordinate: {e:?}"))?;
+    let y = get_label_value_as_bytes(cose_key, Label::Int(iana::Ec2KeyParameter::Y.to_i64()))
+        .map_err(|e| anyhow!("Failed to get the y coordinate: {e:?}"))?;
+    let key = EcKey::from_public_key_affine_coordinates(
+        &group,
+        &BigNum::from_slice(x)?,
+        &BigNum::from_slice(y)?,
+    )?;
+    key.check_key()?;
+    Ok(key)
+}
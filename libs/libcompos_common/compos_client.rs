@@ -38,6 +38,7 @@ use log::{info, warn};
 use platformproperties::hypervisorproperties;
 use std::fs::File;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use vmclient::{DeathReason, ErrorCode, VmInstance, VmWaitError};
 
 /// This owns an instance of the CompOS VM.
@@ -178,6 +179,17 @@ impl ComposClient {
         self.0.connect_service(COMPOS_VSOCK_PORT).context("Connecting to CompOS service")
     }
 
+    /// Returns whether the VM dies within `timeout`, e.g. to tell whether a call to the service
+    /// failed because the VM died.
+    pub fn died_within(&self, timeout: Duration) -> bool {
+        self.0.wait_for_death_with_timeout(timeout).is_some()
+    }
+
+    /// Returns the highest resident memory of the VM seen so far, in KiB, if it is known.
+    pub fn peak_rss_kb(&self) -> Option<i64> {
+        self.0.vm.getPeakRssKb().ok().filter(|rss_kb| *rss_kb >= 0)
+    }
+
     /// Shut down the VM cleanly, by sending a quit request to the service, giving time for any
     /// relevant logs to be written.
     pub fn shutdown(self, service: Strong<dyn ICompOsService>) {
@@ -35,6 +35,10 @@ pub const TEST_ARTIFACTS_SUBDIR: &str = "test-artifacts";
 /// The directory under ODREFRESH_OUTPUT_ROOT_DIR where the current (active) artifacts are stored
 pub const CURRENT_ARTIFACTS_SUBDIR: &str = "dalvik-cache";
 
+/// The suffix of the name of the checkpoint of a compilation, which the VM signs in
+/// ODREFRESH_OUTPUT_ROOT_DIR, next to the target directory, see ICompOsService.odrefresh.
+pub const CHECKPOINT_SUFFIX: &str = ".checkpoint";
+
 /// Prefixes of system properties that are interested to odrefresh and dex2oat.
 const ALLOWLIST_SYSTEM_PROPERTY_PREFIXES: &[&str] =
     &["dalvik.vm.", "ro.dalvik.vm.", "persist.device_config.runtime_native_boot."];
@@ -14,6 +14,7 @@
 
 //! Iterators over cells, and various layers on top of them.
 
+use crate::libfdt::Libfdt;
 use crate::Fdt;
 use crate::FdtError;
 use crate::FdtNode;
@@ -74,6 +75,32 @@ impl<'a> Iterator for CellIterator<'a> {
     }
 }
 
+/// Iterator over the strings of a <stringlist> DT property.
+#[derive(Debug)]
+pub struct StringListIterator<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StringListIterator<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Result<Self, FdtError> {
+        match bytes.last() {
+            None | Some(0) => Ok(Self { bytes }),
+            Some(_) => Err(FdtError::BadValue),
+        }
+    }
+}
+
+impl<'a> Iterator for StringListIterator<'a> {
+    type Item = &'a CStr;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let len = self.bytes.iter().position(|&b| b == 0)?;
+        let (string, rest) = self.bytes.split_at(len + 1);
+        self.bytes = rest;
+        CStr::from_bytes_with_nul(string).ok()
+    }
+}
+
 /// Iterator over a 'reg' property of a DT node.
 #[derive(Debug)]
 pub struct RegIterator<'a> {
@@ -173,6 +200,44 @@ impl<'a> Iterator for MemRegIterator<'a> {
     }
 }
 
+/// Iterator over the memory reservation block of a DT, i.e. its /memreserve/ entries.
+#[derive(Debug)]
+pub struct MemReservationIterator<'a> {
+    fdt: &'a Fdt,
+    index: usize,
+    count: usize,
+}
+
+impl<'a> MemReservationIterator<'a> {
+    pub(crate) fn new(fdt: &'a Fdt) -> Result<Self, FdtError> {
+        let count = fdt.num_mem_rsv()?;
+
+        Ok(Self { fdt, index: 0, count })
+    }
+}
+
+impl<'a> Iterator for MemReservationIterator<'a> {
+    type Item = Range<u64>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.count {
+            return None;
+        }
+        let (address, size) = self.fdt.get_mem_rsv(self.index).ok()?;
+        self.index += 1;
+
+        Some(address..address.checked_add(size)?)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.count - self.index;
+
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a> ExactSizeIterator for MemReservationIterator<'a> {}
+
 /// Iterator over the 'ranges' property of a DT node.
 #[derive(Debug)]
 pub struct RangesIterator<'a, A, P, S> {
@@ -359,3 +424,42 @@ impl<'a> Iterator for PropertyIterator<'a> {
         res
     }
 }
+
+/// Iterator over the names of the aliases which refer to a node.
+#[derive(Debug)]
+pub struct AliasIterator<'a> {
+    node: FdtNode<'a>,
+    prop: Option<FdtProperty<'a>>,
+}
+
+impl<'a> AliasIterator<'a> {
+    pub(crate) fn new(node: FdtNode<'a>) -> Result<Self, FdtError> {
+        let prop = match node.fdt.aliases()? {
+            Some(aliases) => aliases.first_property()?,
+            None => None,
+        };
+
+        Ok(Self { node, prop })
+    }
+
+    fn refers_to_node(&self, prop: &FdtProperty<'a>) -> Result<bool, FdtError> {
+        let path = CStr::from_bytes_with_nul(prop.value()?).map_err(|_| FdtError::BadValue)?;
+        Ok(self.node.fdt.node(path)? == Some(self.node))
+    }
+}
+
+impl<'a> Iterator for AliasIterator<'a> {
+    type Item = &'a CStr;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(prop) = self.prop {
+            self.prop = prop.next_property().ok()?;
+            // Aliases which can't be resolved don't refer to any node, so they are skipped.
+            if self.refers_to_node(&prop).unwrap_or(false) {
+                return prop.name().ok();
+            }
+        }
+
+        None
+    }
+}
@@ -23,13 +23,15 @@ mod result;
 mod safe_types;
 
 pub use iterators::{
-    AddressRange, CellIterator, CompatibleIterator, DescendantsIterator, MemRegIterator,
-    PropertyIterator, RangesIterator, Reg, RegIterator, SubnodeIterator,
+    AddressRange, AliasIterator, CellIterator, CompatibleIterator, DescendantsIterator,
+    MemRegIterator, MemReservationIterator, PropertyIterator, RangesIterator, Reg, RegIterator,
+    StringListIterator, SubnodeIterator,
 };
 pub use result::{FdtError, Result};
 pub use safe_types::{FdtHeader, NodeOffset, Phandle, PropOffset, StringOffset};
 
 use core::ffi::{c_void, CStr};
+use core::hash::Hasher;
 use core::ops::Range;
 use cstr::cstr;
 use libfdt::get_slice_at_ptr;
@@ -217,6 +219,20 @@ impl<'a> FdtNode<'a> {
         }
     }
 
+    /// Returns the value of a given <stringlist> property, e.g. `compatible` or `clock-names`.
+    pub fn getprop_string_list(&self, name: &CStr) -> Result<Option<StringListIterator<'a>>> {
+        if let Some(bytes) = self.getprop(name)? {
+            Ok(Some(StringListIterator::new(bytes)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns whether a given <stringlist> property exists and contains `value`.
+    pub fn prop_contains_string(&self, name: &CStr, value: &CStr) -> Result<bool> {
+        Ok(self.fdt.stringlist_search(self.offset, name, value)?.is_some())
+    }
+
     /// Returns the value of a given property as an array of cells.
     pub fn getprop_cells(&self, name: &CStr) -> Result<Option<CellIterator<'a>>> {
         if let Some(cells) = self.getprop(name)? {
@@ -343,6 +359,61 @@ impl<'a> FdtNode<'a> {
 
         Ok(offset.map(|offset| Self { fdt: self.fdt, offset }))
     }
+
+    /// Returns an iterator over the names of the aliases in /aliases which refer to this node.
+    pub fn aliases(&self) -> Result<AliasIterator<'a>> {
+        AliasIterator::new(*self)
+    }
+
+    /// Returns the subnode with exactly the given name, as `subnode()` would also return a
+    /// "name@unit" node when looking up "name".
+    fn subnode_with_exact_name(&self, name: &[u8]) -> Result<Option<Self>> {
+        for subnode in self.subnodes()? {
+            if subnode.name()?.to_bytes() == name {
+                return Ok(Some(subnode));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reports the differences between this node, from the old tree, and `new` to `f`, then
+    /// recurses into their common subnodes.
+    fn diff(&self, new: &Self, f: &mut impl FnMut(FdtDiff<'a>)) -> Result<()> {
+        let mut next = self.first_property()?;
+        while let Some(property) = next {
+            let name = property.name()?;
+            match new.getprop(name)? {
+                None => f(FdtDiff::PropertyRemoved(*self, name)),
+                Some(value) if value != property.value()? => {
+                    f(FdtDiff::PropertyChanged(*new, name))
+                }
+                Some(_) => {}
+            }
+            next = property.next_property()?;
+        }
+        let mut next = new.first_property()?;
+        while let Some(property) = next {
+            let name = property.name()?;
+            if self.getprop(name)?.is_none() {
+                f(FdtDiff::PropertyAdded(*new, name));
+            }
+            next = property.next_property()?;
+        }
+
+        for subnode in self.subnodes()? {
+            match new.subnode_with_exact_name(subnode.name()?.to_bytes())? {
+                Some(new_subnode) => subnode.diff(&new_subnode, f)?,
+                None => f(FdtDiff::NodeRemoved(subnode)),
+            }
+        }
+        for subnode in new.subnodes()? {
+            if self.subnode_with_exact_name(subnode.name()?.to_bytes())?.is_none() {
+                f(FdtDiff::NodeAdded(subnode));
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl<'a> PartialEq for FdtNode<'a> {
@@ -351,6 +422,21 @@ impl<'a> PartialEq for FdtNode<'a> {
     }
 }
 
+/// A difference between two trees, as reported by [`Fdt::diff`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FdtDiff<'a> {
+    /// The node, with its properties and subnodes, is only in the new tree.
+    NodeAdded(FdtNode<'a>),
+    /// The node, with its properties and subnodes, is only in the old tree.
+    NodeRemoved(FdtNode<'a>),
+    /// The property is only in the given node of the new tree.
+    PropertyAdded(FdtNode<'a>, &'a CStr),
+    /// The property is only in the given node of the old tree.
+    PropertyRemoved(FdtNode<'a>, &'a CStr),
+    /// The property has a different value in the given node of the new tree.
+    PropertyChanged(FdtNode<'a>, &'a CStr),
+}
+
 /// Mutable FDT node.
 #[derive(Debug)]
 pub struct FdtNodeMut<'a> {
@@ -364,6 +450,11 @@ impl<'a> FdtNodeMut<'a> {
         self.fdt.appendprop(self.offset, name, value.as_ref())
     }
 
+    /// Appends a string to the given <stringlist> property of the node, creating it if needed.
+    pub fn appendprop_string(&mut self, name: &CStr, value: &CStr) -> Result<()> {
+        self.fdt.appendprop(self.offset, name, value.to_bytes_with_nul())
+    }
+
     /// Appends a (address, size) pair property to the given node.
     pub fn appendprop_addrrange(&mut self, name: &CStr, addr: u64, size: u64) -> Result<()> {
         let parent = self.parent()?.offset;
@@ -664,6 +755,28 @@ impl Fdt {
         self.open_into_self()
     }
 
+    /// Copies the DT into `dest`, unpacked to cover the whole slice, and returns the copy.
+    ///
+    /// This allows growing a DT beyond its current buffer. The copy keeps the original DT borrowed,
+    /// so that the stale DT can't be used while the copy is:
+    ///
+    ///     let fdt = fdt.open_into(&mut larger_buffer)?;
+    ///
+    /// Fails with `FdtError::NoSpace` if `dest` is too small to hold the DT.
+    pub fn open_into<'a>(&'a mut self, dest: &'a mut [u8]) -> Result<&'a mut Self> {
+        Libfdt::open_into(self, dest)?;
+
+        Self::from_mut_slice(dest)
+    }
+
+    /// Resizes the DT to the first `size` bytes of the slice it is contained in, e.g. to leave room
+    /// after it in the slice or to use more of the slice.
+    ///
+    /// Fails with `FdtError::NoSpace` if `size` is larger than the slice or too small for the DT.
+    pub fn resize(&mut self, size: usize) -> Result<()> {
+        self.open_into_self_sized(size)
+    }
+
     /// Packs the DT to take a minimum amount of memory.
     ///
     /// Doesn't shrink the underlying memory slice.
@@ -705,6 +818,22 @@ impl Fdt {
         self.memory()?.next().ok_or(FdtError::NotFound)
     }
 
+    /// Returns an iterator over the memory reservation block, i.e. the /memreserve/ entries.
+    pub fn mem_reservations(&self) -> Result<MemReservationIterator> {
+        MemReservationIterator::new(self)
+    }
+
+    /// Appends an entry reserving `size` bytes at `addr` to the memory reservation block.
+    pub fn add_mem_reservation(&mut self, addr: u64, size: u64) -> Result<()> {
+        self.add_mem_rsv(addr, size)
+    }
+
+    /// Removes the entry at `index` from the memory reservation block. The following entries are
+    /// moved down by one.
+    pub fn del_mem_reservation(&mut self, index: usize) -> Result<()> {
+        self.del_mem_rsv(index)
+    }
+
     /// Returns the standard /chosen node.
     pub fn chosen(&self) -> Result<Option<FdtNode>> {
         self.root().subnode(cstr!("chosen"))
@@ -730,6 +859,28 @@ impl Fdt {
         self.node_mut(cstr!("/__symbols__"))
     }
 
+    /// Returns the standard /aliases node.
+    pub fn aliases(&self) -> Result<Option<FdtNode>> {
+        self.root().subnode(cstr!("aliases"))
+    }
+
+    /// Returns the standard /aliases node as mutable.
+    pub fn aliases_mut(&mut self) -> Result<Option<FdtNodeMut>> {
+        self.node_mut(cstr!("/aliases"))
+    }
+
+    /// Returns the node referred to by an alias of /aliases, optionally followed by a path relative
+    /// to that node e.g. "serial0" or "i2c1/eeprom@50".
+    ///
+    /// Fails with [`FdtError::BadPath`] if the alias is not defined.
+    pub fn resolve_alias(&self, name: &CStr) -> Result<Option<FdtNode>> {
+        if matches!(name.to_bytes().first(), None | Some(b'/')) {
+            return Err(FdtError::BadPath);
+        }
+        // libfdt resolves the leading component of relative paths as an alias.
+        self.node(name)
+    }
+
     /// Returns a tree node by its full path.
     pub fn node(&self, path: &CStr) -> Result<Option<FdtNode>> {
         let offset = self.path_offset_namelen(path.to_bytes())?;
@@ -747,6 +898,76 @@ impl Fdt {
         self.find_max_phandle()
     }
 
+    /// Returns a phandle which isn't used by any node of the tree, above its max phandle.
+    pub fn allocate_phandle(&self) -> Result<Phandle> {
+        self.generate_phandle()
+    }
+
+    /// Sets the property `name` of the node at path `node` to the phandle of the node at path
+    /// `target`, giving the target a new phandle if it doesn't have one yet.
+    ///
+    /// Returns the phandle of the target.
+    pub fn setprop_phandle_ref(
+        &mut self,
+        node: &CStr,
+        name: &CStr,
+        target: &CStr,
+    ) -> Result<Phandle> {
+        let target_node = self.node(target)?.ok_or(FdtError::NotFound)?;
+        let phandle = if let Some(phandle) = target_node.get_phandle()? {
+            phandle
+        } else {
+            let phandle = self.allocate_phandle()?;
+            let mut target_node = self.node_mut(target)?.ok_or(FdtError::NotFound)?;
+            target_node.setprop(cstr!("phandle"), &u32::from(phandle).to_be_bytes())?;
+            phandle
+        };
+        // Nodes are looked up again as adding the phandle may have moved them.
+        let mut node = self.node_mut(node)?.ok_or(FdtError::NotFound)?;
+        node.setprop(name, &u32::from(phandle).to_be_bytes())?;
+
+        Ok(phandle)
+    }
+
+    /// Feeds a canonical representation of the structure block to the given hasher.
+    ///
+    /// Only the nodes and properties of the tree, in order, are hashed so that equivalent
+    /// encodings (e.g. with NOP tags, free space or a different strings block layout) have the same
+    /// digest, which can be used to detect when data derived from the tree becomes stale.
+    pub fn digest<H: Hasher>(&self, state: &mut H) -> Result<()> {
+        let mut next = Some((NodeOffset::ROOT, 0));
+        while let Some((node, depth)) = next {
+            state.write_u32(libfdt_bindgen::FDT_BEGIN_NODE);
+            state.write_usize(depth);
+            // The name includes its NUL terminator, delimiting it from what follows.
+            state.write(self.get_name(node)?);
+
+            let mut prop = self.first_property_offset(node)?;
+            while let Some(offset) = prop {
+                let property = FdtProperty::new(self, offset)?;
+                let value = property.value()?;
+                state.write_u32(libfdt_bindgen::FDT_PROP);
+                state.write(property.name()?.to_bytes_with_nul());
+                state.write_usize(value.len());
+                state.write(value);
+                prop = self.next_property_offset(offset)?;
+            }
+
+            next = self.next_node(node, depth)?;
+        }
+
+        Ok(())
+    }
+
+    /// Compares the tree, as the old tree, to `new` and reports each node or property which was
+    /// added, removed or changed to `f`.
+    ///
+    /// Nodes and properties are matched by name, so that their order and the encoding of the trees
+    /// don't matter. Content of added or removed nodes isn't reported separately.
+    pub fn diff<'a>(&'a self, new: &'a Self, mut f: impl FnMut(FdtDiff<'a>)) -> Result<()> {
+        self.root().diff(&new.root(), &mut f)
+    }
+
     /// Returns a node with the phandle
     pub fn node_with_phandle(&self, phandle: Phandle) -> Result<Option<FdtNode>> {
         let offset = self.node_offset_by_phandle(phandle)?;
@@ -773,6 +994,24 @@ impl Fdt {
         Ok(offset.map(|offset| FdtNodeMut { fdt: self, offset }))
     }
 
+    /// Returns a mutable tree node by its full path, first adding it and any missing ancestor like
+    /// `mkdir -p` would, e.g. "/a/b/c" adds "/a/b" as well if needed.
+    ///
+    /// Fails with [`FdtError::BadPath`] if the path isn't absolute.
+    pub fn add_node_path(&mut self, path: &CStr) -> Result<FdtNodeMut> {
+        let path = path.to_bytes().strip_prefix(b"/").ok_or(FdtError::BadPath)?;
+        let mut offset = NodeOffset::ROOT;
+        for name in path.split(|&c| c == b'/').filter(|name| !name.is_empty()) {
+            let node = FdtNode { fdt: self, offset };
+            offset = match node.subnode_with_exact_name(name)?.map(|subnode| subnode.offset) {
+                Some(subnode) => subnode,
+                None => self.add_subnode_namelen(offset, name)?,
+            };
+        }
+
+        Ok(FdtNodeMut { fdt: self, offset })
+    }
+
     fn next_node_skip_subnodes(
         &self,
         node: NodeOffset,
@@ -239,6 +239,23 @@ pub(crate) unsafe trait Libfdt {
         }
     }
 
+    /// Safe wrapper around `fdt_stringlist_search()` (C function).
+    fn stringlist_search(
+        &self,
+        node: NodeOffset,
+        property: &CStr,
+        string: &CStr,
+    ) -> Result<Option<usize>> {
+        let fdt = self.as_fdt_slice().as_ptr().cast();
+        let node = node.into();
+        let property = property.as_ptr();
+        let string = string.as_ptr();
+        // SAFETY: Accesses (read-only) are constrained to the DT totalsize.
+        let ret = unsafe { libfdt_bindgen::fdt_stringlist_search(fdt, node, property, string) };
+
+        FdtRawResult::from(ret).try_into()
+    }
+
     /// Safe wrapper around `fdt_get_property_by_offset()` (C function).
     fn get_property_by_offset(&self, offset: PropOffset) -> Result<&libfdt_bindgen::fdt_property> {
         let mut len = 0;
@@ -297,6 +314,18 @@ pub(crate) unsafe trait Libfdt {
         phandle.try_into()
     }
 
+    /// Safe wrapper around `fdt_generate_phandle()` (C function).
+    fn generate_phandle(&self) -> Result<Phandle> {
+        let fdt = self.as_fdt_slice().as_ptr().cast();
+        let mut phandle = 0;
+        // SAFETY: Accesses (read-only) are constrained to the DT totalsize.
+        let ret = unsafe { libfdt_bindgen::fdt_generate_phandle(fdt, &mut phandle) };
+
+        () = FdtRawResult::from(ret).try_into()?;
+
+        phandle.try_into()
+    }
+
     /// Safe wrapper around `fdt_string()` (C function).
     fn string(&self, offset: StringOffset) -> Result<&CStr> {
         let fdt = self.as_fdt_slice().as_ptr().cast();
@@ -309,8 +338,30 @@ pub(crate) unsafe trait Libfdt {
         CStr::from_bytes_until_nul(bytes).map_err(|_| FdtError::Internal)
     }
 
+    /// Safe wrapper around `fdt_num_mem_rsv()` (C function).
+    fn num_mem_rsv(&self) -> Result<usize> {
+        let fdt = self.as_fdt_slice().as_ptr().cast();
+        // SAFETY: Accesses (read-only) are constrained to the DT totalsize.
+        let ret = unsafe { libfdt_bindgen::fdt_num_mem_rsv(fdt) };
+
+        FdtRawResult::from(ret).try_into()
+    }
+
+    /// Safe wrapper around `fdt_get_mem_rsv()` (C function).
+    fn get_mem_rsv(&self, n: usize) -> Result<(u64, u64)> {
+        let fdt = self.as_fdt_slice().as_ptr().cast();
+        let n = n.try_into().map_err(|_| FdtError::NotFound)?;
+        let mut address = 0;
+        let mut size = 0;
+        // SAFETY: Accesses (read-only) are constrained to the DT totalsize.
+        let ret = unsafe { libfdt_bindgen::fdt_get_mem_rsv(fdt, n, &mut address, &mut size) };
+
+        () = FdtRawResult::from(ret).try_into()?;
+
+        Ok((address, size))
+    }
+
     /// Safe wrapper around `fdt_open_into()` (C function).
-    #[allow(dead_code)]
     fn open_into(&self, dest: &mut [u8]) -> Result<()> {
         let fdt = self.as_fdt_slice().as_ptr().cast();
 
@@ -469,6 +520,29 @@ pub(crate) unsafe trait LibfdtMut {
         FdtRawResult::from(ret).try_into()
     }
 
+    /// Safe wrapper around `fdt_add_mem_rsv()` (C function).
+    fn add_mem_rsv(&mut self, address: u64, size: u64) -> Result<()> {
+        let fdt = self.as_fdt_slice_mut().as_mut_ptr().cast();
+        // SAFETY: Accesses are constrained to the DT totalsize (validated by ctor). Adding the
+        // entry shifts the structure block, but the borrow checker should prevent this function
+        // from being called when FdtNode instances are in use.
+        let ret = unsafe { libfdt_bindgen::fdt_add_mem_rsv(fdt, address, size) };
+
+        FdtRawResult::from(ret).try_into()
+    }
+
+    /// Safe wrapper around `fdt_del_mem_rsv()` (C function).
+    fn del_mem_rsv(&mut self, n: usize) -> Result<()> {
+        let fdt = self.as_fdt_slice_mut().as_mut_ptr().cast();
+        let n = n.try_into().map_err(|_| FdtError::NotFound)?;
+        // SAFETY: Accesses are constrained to the DT totalsize (validated by ctor). Removing the
+        // entry shifts the structure block, but the borrow checker should prevent this function
+        // from being called when FdtNode instances are in use.
+        let ret = unsafe { libfdt_bindgen::fdt_del_mem_rsv(fdt, n) };
+
+        FdtRawResult::from(ret).try_into()
+    }
+
     /// Safe and aliasing-compatible wrapper around `fdt_open_into()` (C function).
     ///
     /// The C API allows both input (`const void*`) and output (`void *`) to point to the same
@@ -483,6 +557,15 @@ pub(crate) unsafe trait LibfdtMut {
         open_into(fdt.as_ptr().cast(), fdt)
     }
 
+    /// Same as `open_into_self()`, but only covers the first `size` bytes of the buffer.
+    fn open_into_self_sized(&mut self, size: usize) -> Result<()> {
+        let fdt = self.as_fdt_slice_mut();
+        let src = fdt.as_ptr().cast();
+        let dest = fdt.get_mut(..size).ok_or(FdtError::NoSpace)?;
+
+        open_into(src, dest)
+    }
+
     /// Safe wrapper around `fdt_pack()` (C function).
     fn pack(&mut self) -> Result<()> {
         let fdt = self.as_fdt_slice_mut().as_mut_ptr().cast();
@@ -0,0 +1,606 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pure-Rust implementation of the read-only libfdt.h functions.
+//!
+//! This parser doesn't call into the C libfdt, so that host tools and fuzzers can read device
+//! trees without it. Its methods return the same values and errors as the wrappers of `Libfdt` in
+//! the `libfdt` crate for valid device trees. Only version 17 device trees, as generated by
+//! dtc and libfdt, are supported.
+
+use core::ffi::{c_int, CStr};
+
+use crate::result::FdtRawResult;
+use crate::{FdtError, NodeOffset, Phandle, PropOffset, Result, StringOffset};
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+/// Size of the version 1 header, the smallest one.
+const FDT_V1_SIZE: usize = 7 * FDT_TAGSIZE;
+/// Size of the version 17 header.
+const FDT_V17_SIZE: usize = 10 * FDT_TAGSIZE;
+const FDT_TAGSIZE: usize = 4;
+/// Size of the tag, length and name offset preceding the value of a property.
+const FDT_PROP_HEADER_SIZE: usize = 3 * FDT_TAGSIZE;
+const FDT_RESERVE_ENTRY_SIZE: usize = 16;
+const FDT_MAX_NCELLS: u32 = 4;
+/// Number of aliases which may refer to each other before a path is deemed bad, so that aliases
+/// referring to themselves can't exhaust the stack.
+const MAX_ALIAS_DEPTH: usize = 8;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+/// Indices of the header fields, in 32-bit words.
+const HEADER_MAGIC: usize = 0;
+const HEADER_TOTALSIZE: usize = 1;
+const HEADER_OFF_DT_STRUCT: usize = 2;
+const HEADER_OFF_DT_STRINGS: usize = 3;
+const HEADER_OFF_MEM_RSVMAP: usize = 4;
+const HEADER_VERSION: usize = 5;
+const HEADER_LAST_COMP_VERSION: usize = 6;
+const HEADER_SIZE_DT_STRINGS: usize = 8;
+const HEADER_SIZE_DT_STRUCT: usize = 9;
+
+/// Read-only device tree, parsed without the C libfdt.
+#[derive(Clone, Copy, Debug)]
+pub struct Fdt<'a> {
+    mem_rsvmap: &'a [u8],
+    dt_struct: &'a [u8],
+    dt_strings: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    /// Wraps a slice containing a Flattened Device Tree.
+    ///
+    /// Fails if the FDT does not pass the validation of `fdt_check_full()` (C function).
+    pub fn from_slice(fdt: &'a [u8]) -> Result<Self> {
+        let header = |field| read_u32(fdt, field * FDT_TAGSIZE).ok_or(FdtError::Truncated);
+        if fdt.len() < FDT_V1_SIZE {
+            return Err(FdtError::Truncated);
+        }
+        if header(HEADER_MAGIC)? != FDT_MAGIC {
+            return Err(FdtError::BadMagic);
+        }
+        let version = header(HEADER_VERSION)?;
+        if version < FDT_VERSION || header(HEADER_LAST_COMP_VERSION)? > FDT_VERSION {
+            return Err(FdtError::BadVersion);
+        }
+        if fdt.len() < FDT_V17_SIZE {
+            return Err(FdtError::Truncated);
+        }
+
+        let totalsize = to_usize(header(HEADER_TOTALSIZE)?);
+        if totalsize < FDT_V17_SIZE || totalsize > c_int::MAX as usize {
+            return Err(FdtError::Truncated);
+        }
+        let fdt = fdt.get(..totalsize).ok_or(FdtError::Truncated)?;
+        let block = |offset, size| {
+            let offset = to_usize(header(offset)?);
+            let end = offset.checked_add(to_usize(header(size)?)).ok_or(FdtError::Truncated)?;
+            if offset < FDT_V17_SIZE {
+                return Err(FdtError::Truncated);
+            }
+            fdt.get(offset..end).ok_or(FdtError::Truncated)
+        };
+        let mem_rsvmap_offset = to_usize(header(HEADER_OFF_MEM_RSVMAP)?);
+        if !(FDT_V17_SIZE..=totalsize).contains(&mem_rsvmap_offset) {
+            return Err(FdtError::Truncated);
+        }
+
+        let this = Self {
+            mem_rsvmap: &fdt[mem_rsvmap_offset..],
+            dt_struct: block(HEADER_OFF_DT_STRUCT, HEADER_SIZE_DT_STRUCT)?,
+            dt_strings: block(HEADER_OFF_DT_STRINGS, HEADER_SIZE_DT_STRINGS)?,
+        };
+        this.check_structure()?;
+
+        Ok(this)
+    }
+
+    /// Equivalent of `fdt_path_offset_namelen()` (C function), except that aliases may only refer
+    /// to other aliases up to `MAX_ALIAS_DEPTH` deep.
+    pub fn path_offset_namelen(&self, path: &[u8]) -> Result<Option<NodeOffset>> {
+        self.path_offset_at_alias_depth(path, 0)
+    }
+
+    fn path_offset_at_alias_depth(&self, path: &[u8], depth: usize) -> Result<Option<NodeOffset>> {
+        let (mut offset, mut path) = if path.first() == Some(&b'/') {
+            (NodeOffset::ROOT, path)
+        } else {
+            if depth == MAX_ALIAS_DEPTH {
+                return Err(FdtError::BadPath);
+            }
+            // The leading component of a relative path is an alias.
+            let end = path.iter().position(|&c| c == b'/').unwrap_or(path.len());
+            let (alias, rest) = path.split_at(end);
+            let alias = self.get_alias_namelen(alias)?.ok_or(FdtError::BadPath)?;
+            let Some(offset) = self.path_offset_at_alias_depth(alias, depth + 1)? else {
+                return Ok(None);
+            };
+            (offset, rest)
+        };
+
+        loop {
+            while let [b'/', rest @ ..] = path {
+                path = rest;
+            }
+            if path.is_empty() {
+                return Ok(Some(offset));
+            }
+            let end = path.iter().position(|&c| c == b'/').unwrap_or(path.len());
+            let (name, rest) = path.split_at(end);
+            let Some(subnode) = self.subnode_offset_namelen(offset, name)? else {
+                return Ok(None);
+            };
+            offset = subnode;
+            path = rest;
+        }
+    }
+
+    /// Equivalent of `fdt_node_offset_by_phandle()` (C function).
+    pub fn node_offset_by_phandle(&self, phandle: Phandle) -> Result<Option<NodeOffset>> {
+        let phandle = u32::from(phandle);
+        let mut node = Some(NodeOffset::ROOT);
+        while let Some(offset) = node {
+            if self.get_phandle(offset)? == phandle {
+                return Ok(Some(offset));
+            }
+            node = self.next_node_offset(offset, None)?;
+        }
+
+        Ok(None)
+    }
+
+    /// Equivalent of `fdt_node_offset_by_compatible()` (C function).
+    pub fn node_offset_by_compatible(
+        &self,
+        prev: NodeOffset,
+        compatible: &CStr,
+    ) -> Result<Option<NodeOffset>> {
+        let compatible = compatible.to_bytes_with_nul();
+        let mut node = self.next_node_offset(prev, None)?;
+        while let Some(offset) = node {
+            if let Some(list) = self.getprop_namelen(offset, b"compatible")? {
+                if list.split_inclusive(|&c| c == b'\0').any(|s| s == compatible) {
+                    return Ok(Some(offset));
+                }
+            }
+            node = self.next_node_offset(offset, None)?;
+        }
+
+        Ok(None)
+    }
+
+    /// Equivalent of `fdt_next_node()` (C function).
+    pub fn next_node(&self, node: NodeOffset, depth: usize) -> Result<Option<(NodeOffset, usize)>> {
+        let mut depth = depth;
+        let next = self.next_node_offset(node, Some(&mut depth))?;
+
+        Ok(next.map(|offset| (offset, depth)))
+    }
+
+    /// Equivalent of `fdt_parent_offset()` (C function).
+    ///
+    /// Note that this function returns a `Err` when called on a root.
+    pub fn parent_offset(&self, node: NodeOffset) -> Result<NodeOffset> {
+        let (_, depth) = self.supernode_and_depth(node, 0)?;
+        let depth = depth.checked_sub(1).ok_or(FdtError::NotFound)?;
+
+        self.supernode_atdepth_offset(node, depth)
+    }
+
+    /// Equivalent of `fdt_supernode_atdepth_offset()` (C function).
+    ///
+    /// Note that this function returns a `Err` when called on a node at a depth shallower than
+    /// the provided `depth`.
+    pub fn supernode_atdepth_offset(&self, node: NodeOffset, depth: usize) -> Result<NodeOffset> {
+        let (supernode, _) = self.supernode_and_depth(node, depth)?;
+
+        supernode.ok_or(FdtError::NotFound)
+    }
+
+    /// Equivalent of `fdt_subnode_offset_namelen()` (C function).
+    pub fn subnode_offset_namelen(
+        &self,
+        parent: NodeOffset,
+        name: &[u8],
+    ) -> Result<Option<NodeOffset>> {
+        let mut depth = 0;
+        let mut node = self.next_node_offset(parent, Some(&mut depth))?;
+        while let Some(offset) = node {
+            if depth == 1 && node_name_eq(self.get_name(offset)?, name) {
+                return Ok(Some(offset));
+            }
+            node = self.next_node_offset(offset, Some(&mut depth))?;
+        }
+
+        Ok(None)
+    }
+
+    /// Equivalent of `fdt_first_subnode()` (C function).
+    pub fn first_subnode(&self, node: NodeOffset) -> Result<Option<NodeOffset>> {
+        let mut depth = 0;
+        let subnode = self.next_node_offset(node, Some(&mut depth))?;
+
+        Ok(subnode.filter(|_| depth == 1))
+    }
+
+    /// Equivalent of `fdt_next_subnode()` (C function).
+    pub fn next_subnode(&self, node: NodeOffset) -> Result<Option<NodeOffset>> {
+        let mut depth = 1;
+        let mut node = self.next_node_offset(node, Some(&mut depth))?;
+        // Skips the subnodes of the nodes in between.
+        while let Some(offset) = node {
+            if depth == 1 {
+                return Ok(Some(offset));
+            }
+            node = self.next_node_offset(offset, Some(&mut depth))?;
+        }
+
+        Ok(None)
+    }
+
+    /// Equivalent of `fdt_address_cells()` (C function).
+    pub fn address_cells(&self, node: NodeOffset) -> Result<usize> {
+        match self.cells(node, b"#address-cells")? {
+            Some(0) => Err(FdtError::BadNCells),
+            Some(cells) => Ok(cells),
+            None => Ok(2),
+        }
+    }
+
+    /// Equivalent of `fdt_size_cells()` (C function).
+    pub fn size_cells(&self, node: NodeOffset) -> Result<usize> {
+        Ok(self.cells(node, b"#size-cells")?.unwrap_or(1))
+    }
+
+    /// Equivalent of `fdt_get_name()` (C function), including the nul terminator.
+    pub fn get_name(&self, node: NodeOffset) -> Result<&'a [u8]> {
+        let offset = to_offset(node)?;
+        self.check_node_offset(node)?;
+
+        self.name_at(offset)
+    }
+
+    /// Equivalent of `fdt_getprop_namelen()` (C function).
+    pub fn getprop_namelen(&self, node: NodeOffset, name: &[u8]) -> Result<Option<&'a [u8]>> {
+        let mut prop = self.first_property_offset(node)?;
+        while let Some(offset) = prop {
+            let (prop_name, value) = self.getprop_by_offset(offset)?;
+            if prop_name.to_bytes() == name {
+                return Ok(Some(value));
+            }
+            prop = self.next_property_offset(offset)?;
+        }
+
+        Ok(None)
+    }
+
+    /// Equivalent of `fdt_getprop_by_offset()` (C function), returning the name and the value of
+    /// the property.
+    pub fn getprop_by_offset(&self, offset: PropOffset) -> Result<(&'a CStr, &'a [u8])> {
+        self.check_prop_offset(offset)?;
+        let offset = to_offset(offset)?;
+        // Both reads were bounds-checked by check_prop_offset().
+        let len = to_usize(read_u32(self.dt_struct, offset + FDT_TAGSIZE).unwrap());
+        let name_offset = read_u32(self.dt_struct, offset + 2 * FDT_TAGSIZE).unwrap();
+        let name_offset = StringOffset(name_offset.try_into().map_err(|_| FdtError::BadOffset)?);
+        let value_offset = offset + FDT_PROP_HEADER_SIZE;
+        let value = &self.dt_struct[value_offset..value_offset + len];
+
+        Ok((self.string(name_offset)?, value))
+    }
+
+    /// Equivalent of `fdt_first_property_offset()` (C function).
+    pub fn first_property_offset(&self, node: NodeOffset) -> Result<Option<PropOffset>> {
+        let next = self.check_node_offset(node)?;
+
+        self.next_property_at(next)
+    }
+
+    /// Equivalent of `fdt_next_property_offset()` (C function).
+    pub fn next_property_offset(&self, prev: PropOffset) -> Result<Option<PropOffset>> {
+        let next = self.check_prop_offset(prev)?;
+
+        self.next_property_at(next)
+    }
+
+    /// Equivalent of `fdt_find_max_phandle()` (C function).
+    pub fn find_max_phandle(&self) -> Result<Phandle> {
+        let mut max_phandle = 0;
+        let mut node = Some(NodeOffset::ROOT);
+        while let Some(offset) = node {
+            let phandle = self.get_phandle(offset)?;
+            if phandle == u32::MAX {
+                return Err(FdtError::BadPhandle);
+            }
+            max_phandle = max_phandle.max(phandle);
+            node = self.next_node_offset(offset, None)?;
+        }
+
+        max_phandle.try_into()
+    }
+
+    /// Equivalent of `fdt_string()` (C function).
+    pub fn string(&self, offset: StringOffset) -> Result<&'a CStr> {
+        let offset = usize::try_from(offset.0).map_err(|_| FdtError::BadOffset)?;
+        let bytes = self.dt_strings.get(offset..).filter(|b| !b.is_empty());
+
+        CStr::from_bytes_until_nul(bytes.ok_or(FdtError::BadOffset)?)
+            .map_err(|_| FdtError::Truncated)
+    }
+
+    /// Equivalent of `fdt_num_mem_rsv()` (C function).
+    pub fn num_mem_rsv(&self) -> Result<usize> {
+        let mut n = 0;
+        loop {
+            let (_, size) = self.get_mem_rsv(n).map_err(|_| FdtError::Truncated)?;
+            if size == 0 {
+                return Ok(n);
+            }
+            n += 1;
+        }
+    }
+
+    /// Equivalent of `fdt_get_mem_rsv()` (C function).
+    pub fn get_mem_rsv(&self, n: usize) -> Result<(u64, u64)> {
+        let offset = n.checked_mul(FDT_RESERVE_ENTRY_SIZE).ok_or(FdtError::BadOffset)?;
+        let entry = offset
+            .checked_add(FDT_RESERVE_ENTRY_SIZE)
+            .and_then(|end| self.mem_rsvmap.get(offset..end))
+            .ok_or(FdtError::BadOffset)?;
+        let (address, size) = entry.split_at(FDT_RESERVE_ENTRY_SIZE / 2);
+
+        Ok((
+            u64::from_be_bytes(address.try_into().unwrap()),
+            u64::from_be_bytes(size.try_into().unwrap()),
+        ))
+    }
+
+    /// Validates the structure block, as `fdt_check_full()` (C function).
+    fn check_structure(&self) -> Result<()> {
+        let mut depth = 0usize;
+        let mut expect_end = false;
+        let mut next = 0;
+        loop {
+            let offset = next;
+            let (tag, next_offset) = self.next_tag(offset)?;
+            next = next_offset;
+            // If we see two root nodes, something is wrong.
+            if expect_end && tag != FDT_END {
+                return Err(FdtError::BadStructure);
+            }
+            match tag {
+                FDT_NOP => {}
+                FDT_END if depth == 0 => return Ok(()),
+                FDT_END => return Err(FdtError::BadStructure),
+                FDT_BEGIN_NODE => {
+                    depth += 1;
+                    // The root node must have an empty name.
+                    if depth == 1 && self.name_at(offset)? != b"\0" {
+                        return Err(FdtError::BadStructure);
+                    }
+                }
+                FDT_END_NODE => {
+                    depth = depth.checked_sub(1).ok_or(FdtError::BadStructure)?;
+                    expect_end = depth == 0;
+                }
+                FDT_PROP => {
+                    self.getprop_by_offset(to_prop_offset(offset)?)?;
+                }
+                _ => return Err(FdtError::Internal),
+            }
+        }
+    }
+
+    /// Returns the tag at `offset` in the structure block and the offset of the next tag, as
+    /// `fdt_next_tag()` (C function).
+    fn next_tag(&self, offset: usize) -> Result<(u32, usize)> {
+        let tag = read_u32(self.dt_struct, offset).ok_or(FdtError::Truncated)?;
+        let end = match tag {
+            FDT_BEGIN_NODE => offset + FDT_TAGSIZE + self.name_at(offset)?.len(),
+            FDT_PROP => {
+                let len = read_u32(self.dt_struct, offset + FDT_TAGSIZE);
+                let len = to_usize(len.ok_or(FdtError::Truncated)?);
+                (offset + FDT_PROP_HEADER_SIZE).checked_add(len).ok_or(FdtError::Truncated)?
+            }
+            FDT_END | FDT_END_NODE | FDT_NOP => offset + FDT_TAGSIZE,
+            _ => return Err(FdtError::BadStructure),
+        };
+        if end > self.dt_struct.len() {
+            return Err(FdtError::Truncated);
+        }
+
+        Ok((tag, end.next_multiple_of(FDT_TAGSIZE)))
+    }
+
+    /// Returns the name of the node at `offset`, including the nul terminator.
+    fn name_at(&self, offset: usize) -> Result<&'a [u8]> {
+        let name = self.dt_struct.get(offset + FDT_TAGSIZE..).ok_or(FdtError::Truncated)?;
+        let len = name.iter().position(|&c| c == b'\0').ok_or(FdtError::Truncated)?;
+
+        Ok(&name[..=len])
+    }
+
+    /// Returns the offset of the tag following the node, as `fdt_check_node_offset_()`.
+    fn check_node_offset(&self, node: NodeOffset) -> Result<usize> {
+        self.check_offset(to_offset(node)?, FDT_BEGIN_NODE)
+    }
+
+    /// Returns the offset of the tag following the property, as `fdt_check_prop_offset_()`.
+    fn check_prop_offset(&self, prop: PropOffset) -> Result<usize> {
+        self.check_offset(to_offset(prop)?, FDT_PROP)
+    }
+
+    fn check_offset(&self, offset: usize, expected_tag: u32) -> Result<usize> {
+        if offset % FDT_TAGSIZE != 0 {
+            return Err(FdtError::BadOffset);
+        }
+        match self.next_tag(offset) {
+            Ok((tag, next)) if tag == expected_tag => Ok(next),
+            _ => Err(FdtError::BadOffset),
+        }
+    }
+
+    /// Returns the next node after `node` as `fdt_next_node()` (C function), which updates the
+    /// depth if one is given and then stops at the end of the subtree of depth 0.
+    fn next_node_offset(
+        &self,
+        node: NodeOffset,
+        mut depth: Option<&mut usize>,
+    ) -> Result<Option<NodeOffset>> {
+        let mut next = self.check_node_offset(node)?;
+        loop {
+            let offset = next;
+            let (tag, next_offset) = self.next_tag(offset)?;
+            next = next_offset;
+            match tag {
+                FDT_BEGIN_NODE => {
+                    if let Some(depth) = depth.as_deref_mut() {
+                        *depth += 1;
+                    }
+                    return to_node_offset(offset).map(Some);
+                }
+                FDT_END_NODE => {
+                    if let Some(depth) = depth.as_deref_mut() {
+                        if *depth == 0 {
+                            return Ok(None);
+                        }
+                        *depth -= 1;
+                    }
+                }
+                FDT_END => return Ok(None),
+                _ => {}
+            }
+        }
+    }
+
+    /// Returns the node at `depth` above `node`, if `node` isn't shallower, and the depth of
+    /// `node`.
+    fn supernode_and_depth(
+        &self,
+        node: NodeOffset,
+        supernode_depth: usize,
+    ) -> Result<(Option<NodeOffset>, usize)> {
+        let mut supernode = None;
+        let mut depth = 0;
+        let mut next = Some(NodeOffset::ROOT);
+        while let Some(offset) = next.filter(|&offset| offset <= node) {
+            if depth == supernode_depth {
+                supernode = Some(offset);
+            }
+            if offset == node {
+                return Ok((supernode.filter(|_| supernode_depth <= depth), depth));
+            }
+            next = self.next_node_offset(offset, Some(&mut depth))?;
+        }
+
+        Err(FdtError::BadOffset)
+    }
+
+    fn next_property_at(&self, offset: usize) -> Result<Option<PropOffset>> {
+        let mut offset = offset;
+        loop {
+            let (tag, next) = self.next_tag(offset)?;
+            match tag {
+                FDT_PROP => return to_prop_offset(offset).map(Some),
+                FDT_NOP => offset = next,
+                FDT_END => return Err(FdtError::BadStructure),
+                _ => return Ok(None),
+            }
+        }
+    }
+
+    /// Returns the phandle of the node, or 0 if it has none, as `fdt_get_phandle()` (C function).
+    fn get_phandle(&self, node: NodeOffset) -> Result<u32> {
+        for name in [&b"phandle"[..], b"linux,phandle"] {
+            if let Some(value) = self.getprop_namelen(node, name)? {
+                if let Ok(value) = value.try_into() {
+                    return Ok(u32::from_be_bytes(value));
+                }
+            }
+        }
+
+        Ok(0)
+    }
+
+    /// Returns the path of the alias, as `fdt_get_alias_namelen()` (C function).
+    fn get_alias_namelen(&self, name: &[u8]) -> Result<Option<&'a [u8]>> {
+        let Some(aliases) = self.path_offset_namelen(b"/aliases")? else {
+            return Ok(None);
+        };
+        let Some(path) = self.getprop_namelen(aliases, name)? else {
+            return Ok(None);
+        };
+
+        Ok(CStr::from_bytes_until_nul(path).ok().map(CStr::to_bytes))
+    }
+
+    /// Returns the value of the property holding a number of cells, as `fdt_cells()`.
+    fn cells(&self, node: NodeOffset, name: &[u8]) -> Result<Option<usize>> {
+        let Some(value) = self.getprop_namelen(node, name)? else {
+            return Ok(None);
+        };
+        let cells = u32::from_be_bytes(value.try_into().map_err(|_| FdtError::BadNCells)?);
+        if cells > FDT_MAX_NCELLS {
+            return Err(FdtError::BadNCells);
+        }
+
+        Ok(Some(to_usize(cells)))
+    }
+}
+
+/// Returns whether `name`, from the tree, matches `s`, which may omit the unit address.
+fn node_name_eq(name: &[u8], s: &[u8]) -> bool {
+    let Some(rest) = name.strip_prefix(s) else {
+        return false;
+    };
+    match rest {
+        [b'\0', ..] => true,
+        [b'@', ..] => !s.contains(&b'@'),
+        _ => false,
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset.checked_add(FDT_TAGSIZE)?)?;
+
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn to_usize(value: u32) -> usize {
+    value.try_into().unwrap()
+}
+
+fn to_offset<T: Into<c_int>>(offset: T) -> Result<usize> {
+    offset.into().try_into().map_err(|_| FdtError::BadOffset)
+}
+
+fn to_raw_result(offset: usize) -> Result<FdtRawResult> {
+    let offset = c_int::try_from(offset).map_err(|_| FdtError::BadOffset)?;
+
+    Ok(offset.into())
+}
+
+fn to_node_offset(offset: usize) -> Result<NodeOffset> {
+    to_raw_result(offset)?.try_into()
+}
+
+fn to_prop_offset(offset: usize) -> Result<PropOffset> {
+    to_raw_result(offset)?.try_into()
+}
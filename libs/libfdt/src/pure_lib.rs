@@ -0,0 +1,26 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pure-Rust parser of Flattened Device Trees, for host tools and fuzzers which can't link the C
+//! libfdt. Built from the sources of the `libfdt` crate with the `pure_rust` feature, which
+//! leaves out everything that needs the C library.
+
+#![no_std]
+
+pub mod pure;
+mod result;
+mod safe_types;
+
+pub use result::{FdtError, Result};
+pub use safe_types::{FdtHeader, NodeOffset, Phandle, PropOffset, StringOffset};
@@ -18,6 +18,40 @@ use core::ffi::{c_int, c_uint};
 use core::fmt;
 use core::result;
 
+#[cfg(feature = "pure_rust")]
+use self::errors::*;
+#[cfg(not(feature = "pure_rust"))]
+use libfdt_bindgen::{
+    FDT_ERR_ALIGNMENT, FDT_ERR_BADFLAGS, FDT_ERR_BADLAYOUT, FDT_ERR_BADMAGIC, FDT_ERR_BADNCELLS,
+    FDT_ERR_BADOFFSET, FDT_ERR_BADOVERLAY, FDT_ERR_BADPATH, FDT_ERR_BADPHANDLE, FDT_ERR_BADSTATE,
+    FDT_ERR_BADSTRUCTURE, FDT_ERR_BADVALUE, FDT_ERR_BADVERSION, FDT_ERR_EXISTS, FDT_ERR_INTERNAL,
+    FDT_ERR_NOPHANDLES, FDT_ERR_NOSPACE, FDT_ERR_NOTFOUND, FDT_ERR_TRUNCATED,
+};
+
+/// The error codes of libfdt.h, for the pure-Rust parser which doesn't link the C libfdt.
+#[cfg(feature = "pure_rust")]
+mod errors {
+    pub const FDT_ERR_NOTFOUND: u32 = 1;
+    pub const FDT_ERR_EXISTS: u32 = 2;
+    pub const FDT_ERR_NOSPACE: u32 = 3;
+    pub const FDT_ERR_BADOFFSET: u32 = 4;
+    pub const FDT_ERR_BADPATH: u32 = 5;
+    pub const FDT_ERR_BADPHANDLE: u32 = 6;
+    pub const FDT_ERR_BADSTATE: u32 = 7;
+    pub const FDT_ERR_TRUNCATED: u32 = 8;
+    pub const FDT_ERR_BADMAGIC: u32 = 9;
+    pub const FDT_ERR_BADVERSION: u32 = 10;
+    pub const FDT_ERR_BADSTRUCTURE: u32 = 11;
+    pub const FDT_ERR_BADLAYOUT: u32 = 12;
+    pub const FDT_ERR_INTERNAL: u32 = 13;
+    pub const FDT_ERR_BADNCELLS: u32 = 14;
+    pub const FDT_ERR_BADVALUE: u32 = 15;
+    pub const FDT_ERR_BADOVERLAY: u32 = 16;
+    pub const FDT_ERR_NOPHANDLES: u32 = 17;
+    pub const FDT_ERR_BADFLAGS: u32 = 18;
+    pub const FDT_ERR_ALIGNMENT: u32 = 19;
+}
+
 /// Error type corresponding to libfdt error codes.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum FdtError {
@@ -107,13 +141,6 @@ impl TryFrom<FdtRawResult> for c_int {
     type Error = FdtError;
 
     fn try_from(res: FdtRawResult) -> Result<Self> {
-        use libfdt_bindgen::{
-            FDT_ERR_ALIGNMENT, FDT_ERR_BADFLAGS, FDT_ERR_BADLAYOUT, FDT_ERR_BADMAGIC,
-            FDT_ERR_BADNCELLS, FDT_ERR_BADOFFSET, FDT_ERR_BADOVERLAY, FDT_ERR_BADPATH,
-            FDT_ERR_BADPHANDLE, FDT_ERR_BADSTATE, FDT_ERR_BADSTRUCTURE, FDT_ERR_BADVALUE,
-            FDT_ERR_BADVERSION, FDT_ERR_EXISTS, FDT_ERR_INTERNAL, FDT_ERR_NOPHANDLES,
-            FDT_ERR_NOSPACE, FDT_ERR_NOTFOUND, FDT_ERR_TRUNCATED,
-        };
         match res.0 {
             x if x >= 0 => Ok(x),
             x if x == -(FDT_ERR_NOTFOUND as c_int) => Err(FdtError::NotFound),
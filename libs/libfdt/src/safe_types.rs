@@ -15,7 +15,6 @@
 //! Safe zero-cost wrappers around integer values used by libfdt.
 
 use core::ffi::c_int;
-use core::mem::offset_of;
 
 use crate::result::FdtRawResult;
 use crate::{FdtError, Result};
@@ -23,13 +22,6 @@ use crate::{FdtError, Result};
 use zerocopy::byteorder::big_endian;
 use zerocopy::{FromBytes, FromZeroes};
 
-macro_rules! assert_offset_eq {
-    // TODO(const_feature(assert_eq)): assert_eq!()
-    ($t:ty, $u:ty, $id:ident) => {
-        static_assertions::const_assert_eq!(offset_of!($t, $id), offset_of!($u, $id));
-    };
-}
-
 /// Thin wrapper around `libfdt_bindgen::fdt_header` for transparent endianness handling.
 #[repr(C)]
 #[derive(Debug, FromZeroes, FromBytes)]
@@ -58,25 +50,46 @@ pub struct FdtHeader {
     /// size of the structure block
     pub size_dt_struct: big_endian::U32,
 }
-assert_offset_eq!(libfdt_bindgen::fdt_header, FdtHeader, magic);
-assert_offset_eq!(libfdt_bindgen::fdt_header, FdtHeader, totalsize);
-assert_offset_eq!(libfdt_bindgen::fdt_header, FdtHeader, off_dt_struct);
-assert_offset_eq!(libfdt_bindgen::fdt_header, FdtHeader, off_dt_strings);
-assert_offset_eq!(libfdt_bindgen::fdt_header, FdtHeader, off_mem_rsvmap);
-assert_offset_eq!(libfdt_bindgen::fdt_header, FdtHeader, version);
-assert_offset_eq!(libfdt_bindgen::fdt_header, FdtHeader, last_comp_version);
-assert_offset_eq!(libfdt_bindgen::fdt_header, FdtHeader, boot_cpuid_phys);
-assert_offset_eq!(libfdt_bindgen::fdt_header, FdtHeader, size_dt_strings);
-assert_offset_eq!(libfdt_bindgen::fdt_header, FdtHeader, size_dt_struct);
-
-impl AsRef<FdtHeader> for libfdt_bindgen::fdt_header {
-    fn as_ref(&self) -> &FdtHeader {
-        let ptr = self as *const _ as *const _;
-        // SAFETY: Types have the same layout (u32 and U32 have the same storage) and alignment.
-        unsafe { &*ptr }
+
+/// Checks that `FdtHeader` has the layout of the C `fdt_header`, which the pure-Rust parser
+/// doesn't link.
+#[cfg(not(feature = "pure_rust"))]
+mod header_layout {
+    use super::FdtHeader;
+    use core::mem::offset_of;
+
+    macro_rules! assert_offset_eq {
+        // TODO(const_feature(assert_eq)): assert_eq!()
+        ($t:ty, $u:ty, $id:ident) => {
+            static_assertions::const_assert_eq!(offset_of!($t, $id), offset_of!($u, $id));
+        };
+    }
+
+    assert_offset_eq!(libfdt_bindgen::fdt_header, FdtHeader, magic);
+    assert_offset_eq!(libfdt_bindgen::fdt_header, FdtHeader, totalsize);
+    assert_offset_eq!(libfdt_bindgen::fdt_header, FdtHeader, off_dt_struct);
+    assert_offset_eq!(libfdt_bindgen::fdt_header, FdtHeader, off_dt_strings);
+    assert_offset_eq!(libfdt_bindgen::fdt_header, FdtHeader, off_mem_rsvmap);
+    assert_offset_eq!(libfdt_bindgen::fdt_header, FdtHeader, version);
+    assert_offset_eq!(libfdt_bindgen::fdt_header, FdtHeader, last_comp_version);
+    assert_offset_eq!(libfdt_bindgen::fdt_header, FdtHeader, boot_cpuid_phys);
+    assert_offset_eq!(libfdt_bindgen::fdt_header, FdtHeader, size_dt_strings);
+    assert_offset_eq!(libfdt_bindgen::fdt_header, FdtHeader, size_dt_struct);
+
+    impl AsRef<FdtHeader> for libfdt_bindgen::fdt_header {
+        fn as_ref(&self) -> &FdtHeader {
+            let ptr = self as *const _ as *const _;
+            // SAFETY: Types have the same layout (u32 and U32 have the same storage) and alignment.
+            unsafe { &*ptr }
+        }
     }
 }
 
+/// Largest phandle, as FDT_MAX_PHANDLE of libfdt.h.
+const FDT_MAX_PHANDLE: u32 = 0xffff_fffe;
+#[cfg(not(feature = "pure_rust"))]
+static_assertions::const_assert_eq!(FDT_MAX_PHANDLE, libfdt_bindgen::FDT_MAX_PHANDLE);
+
 /// Wrapper guaranteed to contain a valid phandle.
 #[repr(transparent)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
@@ -86,7 +99,7 @@ impl Phandle {
     /// Minimum valid value for device tree phandles.
     pub const MIN: Self = Self(1);
     /// Maximum valid value for device tree phandles.
-    pub const MAX: Self = Self(libfdt_bindgen::FDT_MAX_PHANDLE);
+    pub const MAX: Self = Self(FDT_MAX_PHANDLE);
 
     /// Creates a new Phandle
     pub const fn new(value: u32) -> Option<Self> {
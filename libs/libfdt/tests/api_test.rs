@@ -18,10 +18,11 @@
 
 use core::ffi::CStr;
 use cstr::cstr;
-use libfdt::{Fdt, FdtError, FdtNodeMut, Phandle};
+use libfdt::{Fdt, FdtDiff, FdtError, FdtNodeMut, Phandle};
 use std::collections::HashSet;
 use std::ffi::CString;
 use std::fs;
+use std::hash::{DefaultHasher, Hasher};
 use std::ops::Range;
 
 const TEST_TREE_WITH_ONE_MEMORY_RANGE_PATH: &str = "data/test_tree_one_memory_range.dtb";
@@ -30,6 +31,7 @@ const TEST_TREE_WITH_MULTIPLE_MEMORY_RANGES_PATH: &str =
 const TEST_TREE_WITH_EMPTY_MEMORY_RANGE_PATH: &str = "data/test_tree_empty_memory_range.dtb";
 const TEST_TREE_WITH_NO_MEMORY_NODE_PATH: &str = "data/test_tree_no_memory_node.dtb";
 const TEST_TREE_PHANDLE_PATH: &str = "data/test_tree_phandle.dtb";
+const TEST_TREE_ALIASES_PATH: &str = "data/test_tree_aliases.dtb";
 
 #[test]
 fn retrieving_memory_from_fdt_with_one_memory_range_succeeds() {
@@ -179,6 +181,45 @@ fn max_phandle() {
     assert_eq!(fdt.max_phandle(), Ok(phandle));
 }
 
+#[test]
+fn allocate_phandle() {
+    let data = fs::read(TEST_TREE_PHANDLE_PATH).unwrap();
+    let fdt = Fdt::from_slice(&data).unwrap();
+    assert_eq!(fdt.allocate_phandle(), Ok(Phandle::new(0x100).unwrap()));
+
+    let mut data = vec![0_u8; 1000];
+    let fdt = Fdt::create_empty_tree(&mut data).unwrap();
+    assert_eq!(fdt.allocate_phandle(), Ok(Phandle::MIN));
+}
+
+#[test]
+fn setprop_phandle_ref() {
+    let mut data = vec![0_u8; 1000];
+    let fdt = Fdt::create_empty_tree(&mut data).unwrap();
+    fdt.root_mut().add_subnodes(&[cstr!("dev_a"), cstr!("dev_b"), cstr!("iommu")]).unwrap();
+
+    let phandle =
+        fdt.setprop_phandle_ref(cstr!("/dev_a"), cstr!("iommus"), cstr!("/iommu")).unwrap();
+    assert_eq!(phandle, Phandle::MIN);
+    // The target keeps its phandle when referenced again.
+    assert_eq!(
+        fdt.setprop_phandle_ref(cstr!("/dev_b"), cstr!("iommus"), cstr!("/iommu")),
+        Ok(phandle)
+    );
+
+    let iommu = fdt.node(cstr!("/iommu")).unwrap().unwrap();
+    assert_eq!(iommu.get_phandle(), Ok(Some(phandle)));
+    for path in [cstr!("/dev_a"), cstr!("/dev_b")] {
+        let node = fdt.node(path).unwrap().unwrap();
+        assert_eq!(node.getprop_u32(cstr!("iommus")), Ok(Some(phandle.into())));
+    }
+    assert_eq!(fdt.allocate_phandle(), Ok(Phandle::new(2).unwrap()));
+    assert_eq!(
+        fdt.setprop_phandle_ref(cstr!("/dev_a"), cstr!("iommus"), cstr!("/missing")),
+        Err(FdtError::NotFound)
+    );
+}
+
 #[test]
 fn node_with_phandle() {
     let data = fs::read(TEST_TREE_PHANDLE_PATH).unwrap();
@@ -284,6 +325,88 @@ fn node_add_subnode_with_namelen() {
     }
 }
 
+#[test]
+fn fdt_open_into() {
+    let mut data = fs::read(TEST_TREE_PHANDLE_PATH).unwrap();
+    let len = data.len();
+    let fdt = Fdt::from_mut_slice(&mut data).unwrap();
+
+    let mut larger = vec![0_u8; len * 2];
+    let fdt = fdt.open_into(&mut larger).unwrap();
+    assert_eq!(fdt.as_slice().len(), len * 2);
+
+    let root = fdt.root_mut();
+    let mut node = root.add_subnode(cstr!("new_node")).unwrap();
+    node.setprop(cstr!("prop"), &[0_u8; 64]).unwrap();
+    assert!(fdt.node(cstr!("/new_node")).unwrap().is_some());
+    assert!(fdt.node(cstr!("/node_a")).unwrap().is_some());
+}
+
+#[test]
+fn fdt_open_into_too_small() {
+    let mut data = fs::read(TEST_TREE_PHANDLE_PATH).unwrap();
+    let len = data.len();
+    let fdt = Fdt::from_mut_slice(&mut data).unwrap();
+
+    let mut smaller = vec![0_u8; len / 2];
+    assert_eq!(fdt.open_into(&mut smaller).err(), Some(FdtError::NoSpace));
+}
+
+#[test]
+fn fdt_resize() {
+    let mut data = fs::read(TEST_TREE_PHANDLE_PATH).unwrap();
+    let len = data.len();
+    data.resize(len * 2, 0_u8);
+    let fdt = Fdt::from_mut_slice(&mut data).unwrap();
+
+    fdt.resize(len + len / 2).unwrap();
+    assert_eq!(fdt.as_slice().len(), len + len / 2);
+    let mut node = fdt.root_mut().add_subnode(cstr!("new_node")).unwrap();
+    node.setprop(cstr!("prop"), &[0_u8; 64]).unwrap();
+
+    fdt.resize(len * 2).unwrap();
+    assert_eq!(fdt.as_slice().len(), len * 2);
+    assert!(fdt.node(cstr!("/new_node")).unwrap().is_some());
+    assert!(fdt.node(cstr!("/node_a")).unwrap().is_some());
+}
+
+#[test]
+fn fdt_resize_out_of_bounds() {
+    let mut data = fs::read(TEST_TREE_PHANDLE_PATH).unwrap();
+    let len = data.len();
+    let fdt = Fdt::from_mut_slice(&mut data).unwrap();
+
+    assert_eq!(fdt.resize(len + 1), Err(FdtError::NoSpace));
+    assert_eq!(fdt.resize(len / 2), Err(FdtError::NoSpace));
+    assert_eq!(fdt.as_slice().len(), len);
+}
+
+#[test]
+fn fdt_mem_reservations() {
+    let mut data = fs::read(TEST_TREE_PHANDLE_PATH).unwrap();
+    data.resize(data.len() * 2, 0_u8);
+    let fdt = Fdt::from_mut_slice(&mut data).unwrap();
+    fdt.unpack().unwrap();
+    assert_eq!(fdt.mem_reservations().unwrap().len(), 0);
+
+    fdt.add_mem_reservation(0x8000_0000, 0x1000).unwrap();
+    fdt.add_mem_reservation(0x9000_0000, 0x2000).unwrap();
+    fdt.add_mem_reservation(0xa000_0000, 0x3000).unwrap();
+    let reservations: Vec<_> = fdt.mem_reservations().unwrap().collect();
+    assert_eq!(
+        reservations,
+        [0x8000_0000..0x8000_1000, 0x9000_0000..0x9000_2000, 0xa000_0000..0xa000_3000]
+    );
+
+    fdt.del_mem_reservation(1).unwrap();
+    let reservations: Vec<_> = fdt.mem_reservations().unwrap().collect();
+    assert_eq!(reservations, [0x8000_0000..0x8000_1000, 0xa000_0000..0xa000_3000]);
+    assert_eq!(fdt.del_mem_reservation(2), Err(FdtError::NotFound));
+
+    // The nodes are still reachable after the structure block moved.
+    assert!(fdt.node(cstr!("/node_a")).unwrap().is_some());
+}
+
 #[test]
 fn node_subnode() {
     let data = fs::read(TEST_TREE_PHANDLE_PATH).unwrap();
@@ -338,6 +461,82 @@ fn fdt_symbols() {
     let _symbols: FdtNodeMut = fdt.symbols_mut().unwrap().unwrap();
 }
 
+#[test]
+fn fdt_aliases() {
+    let mut data = fs::read(TEST_TREE_ALIASES_PATH).unwrap();
+    let fdt = Fdt::from_mut_slice(&mut data).unwrap();
+
+    let aliases = fdt.aliases().unwrap().unwrap();
+    assert_eq!(aliases.name(), Ok(cstr!("aliases")));
+
+    // Validates type.
+    let _aliases: FdtNodeMut = fdt.aliases_mut().unwrap().unwrap();
+
+    let data = fs::read(TEST_TREE_PHANDLE_PATH).unwrap();
+    let fdt = Fdt::from_slice(&data).unwrap();
+    assert_eq!(fdt.aliases(), Ok(None));
+}
+
+#[test]
+fn resolve_alias() {
+    let data = fs::read(TEST_TREE_ALIASES_PATH).unwrap();
+    let fdt = Fdt::from_slice(&data).unwrap();
+
+    let serial0 = fdt.resolve_alias(cstr!("serial0")).unwrap().unwrap();
+    assert_eq!(serial0.name(), Ok(cstr!("serial@1000")));
+    assert_eq!(fdt.resolve_alias(cstr!("console")), Ok(Some(serial0)));
+    let serial1 = fdt.resolve_alias(cstr!("serial1")).unwrap().unwrap();
+    assert_eq!(serial1.name(), Ok(cstr!("serial@2000")));
+}
+
+#[test]
+fn resolve_alias_with_suffix() {
+    let data = fs::read(TEST_TREE_ALIASES_PATH).unwrap();
+    let fdt = Fdt::from_slice(&data).unwrap();
+
+    let eeprom = fdt.resolve_alias(cstr!("i2c0/eeprom@50")).unwrap().unwrap();
+    assert_eq!(eeprom.name(), Ok(cstr!("eeprom@50")));
+    assert_eq!(fdt.node(cstr!("/soc/i2c@3000/eeprom@50")), Ok(Some(eeprom)));
+    assert_eq!(fdt.resolve_alias(cstr!("i2c0/missing")), Ok(None));
+}
+
+#[test]
+fn resolve_missing_alias() {
+    let data = fs::read(TEST_TREE_ALIASES_PATH).unwrap();
+    let fdt = Fdt::from_slice(&data).unwrap();
+
+    assert_eq!(fdt.resolve_alias(cstr!("serial2")), Err(FdtError::BadPath));
+    assert_eq!(fdt.resolve_alias(cstr!("serial2/foo")), Err(FdtError::BadPath));
+    assert_eq!(fdt.resolve_alias(cstr!("dangling")), Ok(None));
+    // Full paths and empty names aren't aliases.
+    assert_eq!(fdt.resolve_alias(cstr!("/soc")), Err(FdtError::BadPath));
+    assert_eq!(fdt.resolve_alias(cstr!("")), Err(FdtError::BadPath));
+
+    let data = fs::read(TEST_TREE_PHANDLE_PATH).unwrap();
+    let fdt = Fdt::from_slice(&data).unwrap();
+    assert_eq!(fdt.resolve_alias(cstr!("serial0")), Err(FdtError::BadPath));
+}
+
+#[test]
+fn node_aliases() {
+    let data = fs::read(TEST_TREE_ALIASES_PATH).unwrap();
+    let fdt = Fdt::from_slice(&data).unwrap();
+
+    let serial = fdt.node(cstr!("/soc/serial@1000")).unwrap().unwrap();
+    let aliases: Vec<_> = serial.aliases().unwrap().collect();
+    assert_eq!(aliases, vec![cstr!("serial0"), cstr!("console")]);
+
+    let serial = fdt.node(cstr!("/soc/serial@2000")).unwrap().unwrap();
+    assert_eq!(serial.aliases().unwrap().collect::<Vec<_>>(), vec![cstr!("serial1")]);
+
+    let chosen = fdt.chosen().unwrap().unwrap();
+    assert_eq!(chosen.aliases().unwrap().count(), 0);
+
+    let data = fs::read(TEST_TREE_PHANDLE_PATH).unwrap();
+    let fdt = Fdt::from_slice(&data).unwrap();
+    assert_eq!(fdt.root().aliases().unwrap().count(), 0);
+}
+
 #[test]
 fn node_mut_as_node() {
     let mut data = fs::read(TEST_TREE_WITH_ONE_MEMORY_RANGE_PATH).unwrap();
@@ -453,6 +652,52 @@ fn node_mut_delete_and_next_node_with_last_node() {
     assert!(all_descendants.is_empty(), "{all_descendants:?}");
 }
 
+fn digest(fdt: &Fdt) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    fdt.digest(&mut hasher).unwrap();
+    hasher.finish()
+}
+
+#[test]
+fn digest_is_stable_across_encodings() {
+    let data = fs::read(TEST_TREE_PHANDLE_PATH).unwrap();
+    let fdt = Fdt::from_slice(&data).unwrap();
+    let expected_digest = digest(fdt);
+
+    let mut unpacked_data = data.clone();
+    unpacked_data.resize(data.len() * 2, 0_u8);
+    let unpacked_fdt = Fdt::from_mut_slice(&mut unpacked_data).unwrap();
+    unpacked_fdt.unpack().unwrap();
+    assert_eq!(digest(unpacked_fdt), expected_digest);
+
+    let name = cstr!("digest-test-prop");
+    let mut node = unpacked_fdt.node_mut(cstr!("/node_a")).unwrap().unwrap();
+    node.setprop(name, b"value").unwrap();
+    node.nop_property(name).unwrap();
+    assert_eq!(digest(unpacked_fdt), expected_digest);
+}
+
+#[test]
+fn digest_changes_with_content() {
+    let mut data = fs::read(TEST_TREE_PHANDLE_PATH).unwrap();
+    data.resize(data.len() * 2, 0_u8);
+    let fdt = Fdt::from_mut_slice(&mut data).unwrap();
+    fdt.unpack().unwrap();
+    let original_digest = digest(fdt);
+
+    let mut node = fdt.node_mut(cstr!("/node_a")).unwrap().unwrap();
+    node.setprop(cstr!("digest-test-prop"), b"value").unwrap();
+    let new_prop_digest = digest(fdt);
+    assert_ne!(new_prop_digest, original_digest);
+
+    let mut node = fdt.node_mut(cstr!("/node_a")).unwrap().unwrap();
+    node.setprop(cstr!("digest-test-prop"), b"other").unwrap();
+    assert_ne!(digest(fdt), new_prop_digest);
+
+    fdt.root_mut().add_subnode(cstr!("digest-test-node")).unwrap();
+    assert_ne!(digest(fdt), original_digest);
+}
+
 #[test]
 #[ignore] // Borrow checker test. Compilation success is sufficient.
 fn node_name_lifetime() {
@@ -483,6 +728,112 @@ fn node_mut_add_subnodes() {
     assert_eq!(expected, names);
 }
 
+#[test]
+fn add_node_path() {
+    let mut data = vec![0_u8; 1000];
+    let fdt = Fdt::create_empty_tree(&mut data).unwrap();
+
+    let mut node = fdt.add_node_path(cstr!("/a/b/c")).unwrap();
+    node.setprop_empty(cstr!("leaf")).unwrap();
+    let node = fdt.add_node_path(cstr!("/a/b/d")).unwrap();
+    assert_eq!(Ok(cstr!("d")), node.as_node().name());
+
+    let b = fdt.node(cstr!("/a/b")).unwrap().unwrap();
+    let names: HashSet<_> = b.subnodes().unwrap().map(|node| node.name().unwrap()).collect();
+    assert_eq!(names, HashSet::from([cstr!("c"), cstr!("d")]));
+    assert_eq!(fdt.root().subnodes().unwrap().count(), 1);
+
+    // Existing nodes are returned as they are.
+    let node = fdt.add_node_path(cstr!("/a/b/c")).unwrap();
+    assert_eq!(Ok(Some(&[][..])), node.as_node().getprop(cstr!("leaf")));
+    assert_eq!(Ok(cstr!("")), fdt.add_node_path(cstr!("/")).unwrap().as_node().name());
+    // Unlike node(), "/a" doesn't match an "a@1" node.
+    fdt.add_node_path(cstr!("/a@1")).unwrap();
+    assert_eq!(Ok(cstr!("a")), fdt.add_node_path(cstr!("/a")).unwrap().as_node().name());
+    assert_eq!(fdt.root().subnodes().unwrap().count(), 2);
+    assert_eq!(fdt.add_node_path(cstr!("a/b")).unwrap_err(), FdtError::BadPath);
+}
+
+#[test]
+fn diff() {
+    let mut old_data = vec![0_u8; 1000];
+    let old = Fdt::create_empty_tree(&mut old_data).unwrap();
+    let mut node = old.add_node_path(cstr!("/a/b")).unwrap();
+    node.setprop(cstr!("same"), b"1").unwrap();
+    node.setprop(cstr!("changed"), b"1").unwrap();
+    node.setprop(cstr!("removed"), b"1").unwrap();
+    old.add_node_path(cstr!("/gone/child")).unwrap();
+    old.add_node_path(cstr!("/dev@1")).unwrap();
+
+    let mut new_data = old_data.clone();
+    let new = Fdt::from_mut_slice(&mut new_data).unwrap();
+    let mut node = new.node_mut(cstr!("/a/b")).unwrap().unwrap();
+    node.setprop(cstr!("changed"), b"2").unwrap();
+    node.delprop(cstr!("removed")).unwrap();
+    node.setprop(cstr!("added"), b"1").unwrap();
+    new.node_mut(cstr!("/gone")).unwrap().unwrap().nop().unwrap();
+    new.add_node_path(cstr!("/a/c")).unwrap();
+    new.add_node_path(cstr!("/dev")).unwrap();
+
+    let old = Fdt::from_slice(&old_data).unwrap();
+    let mut diffs = Vec::new();
+    old.diff(new, |diff| {
+        diffs.push(match diff {
+            FdtDiff::NodeAdded(node) => ("+", node.name().unwrap(), None),
+            FdtDiff::NodeRemoved(node) => ("-", node.name().unwrap(), None),
+            FdtDiff::PropertyAdded(node, name) => ("+", node.name().unwrap(), Some(name)),
+            FdtDiff::PropertyRemoved(node, name) => ("-", node.name().unwrap(), Some(name)),
+            FdtDiff::PropertyChanged(node, name) => ("~", node.name().unwrap(), Some(name)),
+        })
+    })
+    .unwrap();
+    diffs.sort();
+
+    assert_eq!(
+        diffs,
+        [
+            ("+", cstr!("b"), Some(cstr!("added"))),
+            ("+", cstr!("c"), None),
+            ("+", cstr!("dev"), None),
+            ("-", cstr!("b"), Some(cstr!("removed"))),
+            ("-", cstr!("gone"), None),
+            ("~", cstr!("b"), Some(cstr!("changed"))),
+        ]
+    );
+}
+
+#[test]
+fn node_string_list() {
+    let mut data = vec![0_u8; 1000];
+    let fdt = Fdt::create_empty_tree(&mut data).unwrap();
+    let name = cstr!("compatible");
+
+    let mut root = fdt.root_mut();
+    root.appendprop_string(name, cstr!("vendor,device-v2")).unwrap();
+    root.appendprop_string(name, cstr!("vendor,device")).unwrap();
+
+    let root = fdt.root();
+    let strings: Vec<_> = root.getprop_string_list(name).unwrap().unwrap().collect();
+    assert_eq!(strings, [cstr!("vendor,device-v2"), cstr!("vendor,device")]);
+    assert_eq!(root.prop_contains_string(name, cstr!("vendor,device")), Ok(true));
+    assert_eq!(root.prop_contains_string(name, cstr!("vendor,dev")), Ok(false));
+    assert!(root.getprop_string_list(cstr!("clock-names")).unwrap().is_none());
+    assert_eq!(root.prop_contains_string(cstr!("clock-names"), cstr!("apb_pclk")), Ok(false));
+}
+
+#[test]
+fn node_string_list_without_nul_terminator() {
+    let mut data = vec![0_u8; 1000];
+    let fdt = Fdt::create_empty_tree(&mut data).unwrap();
+    let name = cstr!("clock-names");
+
+    fdt.root_mut().setprop(name, b"apb_pclk\0uart").unwrap();
+
+    let root = fdt.root();
+    assert!(matches!(root.getprop_string_list(name), Err(FdtError::BadValue)));
+    assert_eq!(root.prop_contains_string(name, cstr!("uart")), Err(FdtError::BadValue));
+}
+
 #[test]
 #[ignore] // Borrow checker test. Compilation success is sufficient.
 fn node_subnode_lifetime() {
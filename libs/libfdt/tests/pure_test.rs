@@ -0,0 +1,227 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Tests of the pure-Rust parser of libfdt, validated against the C libfdt.
+
+use core::ffi::CStr;
+use core::fmt::Debug;
+use cstr::cstr;
+use libfdt::{Fdt, FdtNode};
+use libfdt_pure::{pure, FdtError, NodeOffset, Phandle};
+use std::fs;
+
+const TEST_TREE_PATHS: [&str; 6] = [
+    "data/test_tree_one_memory_range.dtb",
+    "data/test_tree_multiple_memory_ranges.dtb",
+    "data/test_tree_empty_memory_range.dtb",
+    "data/test_tree_no_memory_node.dtb",
+    "data/test_tree_phandle.dtb",
+    "data/test_tree_aliases.dtb",
+];
+const TEST_TREE_PHANDLE_PATH: &str = "data/test_tree_phandle.dtb";
+const TEST_TREE_ALIASES_PATH: &str = "data/test_tree_aliases.dtb";
+
+/// Checks that the results of both parsers are the same, although their types come from different
+/// crates.
+fn assert_same_result<T: Debug, U: Debug>(pure: T, c: U, context: &str) {
+    assert_eq!(format!("{pure:?}"), format!("{c:?}"), "{context}");
+}
+
+/// Checks that the pure-Rust parser finds at `offset` the same subtree as the C libfdt at `node`.
+fn assert_same_subtree(fdt: &pure::Fdt, offset: NodeOffset, node: &FdtNode, path: &str) {
+    let name = fdt.get_name(offset).unwrap();
+    assert_eq!(CStr::from_bytes_with_nul(name).unwrap(), node.name().unwrap(), "{path}");
+    assert_eq!(fdt.path_offset_namelen(path.as_bytes()), Ok(Some(offset)), "{path}");
+    if let Some(phandle) = node.get_phandle().unwrap() {
+        let phandle = Phandle::new(phandle.into()).unwrap();
+        assert_eq!(fdt.node_offset_by_phandle(phandle), Ok(Some(offset)), "{path}");
+    }
+    match node.parent() {
+        Ok(parent) => {
+            let parent_offset = fdt.parent_offset(offset).unwrap();
+            let parent_name = fdt.get_name(parent_offset).unwrap();
+            assert_eq!(CStr::from_bytes_with_nul(parent_name), Ok(parent.name().unwrap()));
+        }
+        Err(e) => assert_same_result(fdt.parent_offset(offset).err(), Some(e), path),
+    }
+
+    let properties: Vec<_> =
+        node.properties().unwrap().map(|p| (p.name().unwrap(), p.value().unwrap())).collect();
+    let mut pure_properties = vec![];
+    let mut prop = fdt.first_property_offset(offset).unwrap();
+    while let Some(prop_offset) = prop {
+        pure_properties.push(fdt.getprop_by_offset(prop_offset).unwrap());
+        prop = fdt.next_property_offset(prop_offset).unwrap();
+    }
+    assert_eq!(pure_properties, properties, "{path}");
+    for (name, value) in properties {
+        assert_eq!(fdt.getprop_namelen(offset, name.to_bytes()), Ok(Some(value)), "{path}");
+    }
+    assert_eq!(fdt.getprop_namelen(offset, b"missing"), Ok(None), "{path}");
+
+    let mut subnode_offset = fdt.first_subnode(offset).unwrap();
+    for subnode in node.subnodes().unwrap() {
+        let name = subnode.name().unwrap().to_str().unwrap();
+        let subnode_path = format!("{}/{name}", path.trim_end_matches('/'));
+        let Some(offset) = subnode_offset else {
+            panic!("Missing subnode {subnode_path}");
+        };
+        assert_same_subtree(fdt, offset, &subnode, &subnode_path);
+        subnode_offset = fdt.next_subnode(offset).unwrap();
+    }
+    assert_eq!(subnode_offset, None, "{path}");
+}
+
+fn assert_same_tree(data: &[u8]) {
+    let fdt = Fdt::from_slice(data).unwrap();
+    let pure_fdt = pure::Fdt::from_slice(data).unwrap();
+
+    assert_same_subtree(&pure_fdt, NodeOffset::ROOT, &fdt.root(), "/");
+    assert_same_result(pure_fdt.find_max_phandle(), fdt.max_phandle(), "max phandle");
+
+    let mut depth = 0;
+    let mut pure_node = Some(NodeOffset::ROOT);
+    for (node, node_depth) in fdt.root().descendants() {
+        let (next, next_depth) = pure_fdt.next_node(pure_node.unwrap(), depth).unwrap().unwrap();
+        assert_eq!(next_depth, node_depth);
+        depth = next_depth;
+        let name = CStr::from_bytes_with_nul(pure_fdt.get_name(next).unwrap()).unwrap();
+        assert_eq!(name, node.name().unwrap());
+        assert_eq!(pure_fdt.supernode_atdepth_offset(next, 0), Ok(NodeOffset::ROOT));
+        pure_node = Some(next);
+    }
+
+    let reservations: Vec<_> = fdt.mem_reservations().unwrap().collect();
+    assert_eq!(pure_fdt.num_mem_rsv(), Ok(reservations.len()));
+    for (n, reservation) in reservations.into_iter().enumerate() {
+        let (address, size) = pure_fdt.get_mem_rsv(n).unwrap();
+        assert_eq!(address..address + size, reservation);
+    }
+}
+
+#[test]
+fn same_trees_as_libfdt() {
+    for path in TEST_TREE_PATHS {
+        let data = fs::read(path).unwrap();
+        assert_same_tree(&data);
+    }
+}
+
+#[test]
+fn same_tree_as_libfdt_after_modifications() {
+    let mut data = fs::read(TEST_TREE_PHANDLE_PATH).unwrap();
+    data.resize(data.len() * 2, 0_u8);
+    let fdt = Fdt::from_mut_slice(&mut data).unwrap();
+    fdt.unpack().unwrap();
+
+    // Leaves NOP tags in the structure block.
+    fdt.node_mut(cstr!("/node_z/node_zz")).unwrap().unwrap().nop().unwrap();
+    fdt.node_mut(cstr!("/node_a")).unwrap().unwrap().nop_property(cstr!("phandle")).unwrap();
+    let mut node = fdt.node_mut(cstr!("/node_b")).unwrap().unwrap();
+    node.setprop(cstr!("compatible"), b"vendor,node-b\0vendor,node\0").unwrap();
+    node.add_subnode(cstr!("node_ba@1000")).unwrap();
+    fdt.add_mem_reservation(0x8000_0000, 0x1000).unwrap();
+    fdt.add_mem_reservation(0x9000_0000, 0x2000).unwrap();
+
+    assert_same_tree(fdt.as_slice());
+}
+
+#[test]
+fn compatible_nodes() {
+    let mut data = fs::read(TEST_TREE_PHANDLE_PATH).unwrap();
+    data.resize(data.len() * 2, 0_u8);
+    let fdt = Fdt::from_mut_slice(&mut data).unwrap();
+    fdt.unpack().unwrap();
+    for path in [cstr!("/node_b"), cstr!("/node_z/node_za"), cstr!("/node_c")] {
+        let mut node = fdt.node_mut(path).unwrap().unwrap();
+        node.setprop(cstr!("compatible"), b"vendor,other\0vendor,node\0").unwrap();
+    }
+    let pure_fdt = pure::Fdt::from_slice(fdt.as_slice()).unwrap();
+
+    let compatible = cstr!("vendor,node");
+    let mut offset = NodeOffset::ROOT;
+    for node in fdt.compatible_nodes(compatible).unwrap() {
+        offset = pure_fdt.node_offset_by_compatible(offset, compatible).unwrap().unwrap();
+        let name = CStr::from_bytes_with_nul(pure_fdt.get_name(offset).unwrap()).unwrap();
+        assert_eq!(name, node.name().unwrap());
+    }
+    assert_eq!(pure_fdt.node_offset_by_compatible(offset, compatible), Ok(None));
+    assert_eq!(pure_fdt.node_offset_by_compatible(NodeOffset::ROOT, cstr!("vendor")), Ok(None));
+}
+
+#[test]
+fn resolve_alias() {
+    let data = fs::read(TEST_TREE_ALIASES_PATH).unwrap();
+    let fdt = Fdt::from_slice(&data).unwrap();
+    let pure_fdt = pure::Fdt::from_slice(&data).unwrap();
+
+    for alias in [cstr!("serial0"), cstr!("console"), cstr!("i2c0/eeprom@50"), cstr!("i2c0/eeprom")]
+    {
+        let node = fdt.resolve_alias(alias).unwrap().unwrap();
+        let offset = pure_fdt.path_offset_namelen(alias.to_bytes()).unwrap().unwrap();
+        let name = CStr::from_bytes_with_nul(pure_fdt.get_name(offset).unwrap()).unwrap();
+        assert_eq!(name, node.name().unwrap());
+    }
+    for alias in [cstr!("serial2"), cstr!("i2c0/missing"), cstr!("dangling")] {
+        let expected = fdt.resolve_alias(alias).map(|node| node.map(|_| ()));
+        let offset = pure_fdt.path_offset_namelen(alias.to_bytes());
+        let offset = offset.map(|offset| offset.map(|_| ()));
+        assert_same_result(offset, expected, &format!("{alias:?}"));
+    }
+}
+
+#[test]
+fn same_errors_as_libfdt() {
+    let data = fs::read(TEST_TREE_PHANDLE_PATH).unwrap();
+    for len in [0, 27, 39, data.len() / 2, data.len() - 1] {
+        let error = pure::Fdt::from_slice(&data[..len]).err();
+        assert_same_result(error, Fdt::from_slice(&data[..len]).err(), &format!("{len}"));
+        assert_eq!(error, Some(FdtError::Truncated));
+    }
+
+    let mut bad_magic = data.clone();
+    bad_magic[0] ^= 0xff;
+    let error = pure::Fdt::from_slice(&bad_magic).err();
+    assert_same_result(error, Fdt::from_slice(&bad_magic).err(), "bad magic");
+
+    let fdt = pure::Fdt::from_slice(&data).unwrap();
+    let node = fdt.path_offset_namelen(b"/node_a").unwrap().unwrap();
+    let prop = fdt.first_property_offset(node).unwrap().unwrap();
+    assert_eq!(fdt.path_offset_namelen(b"missing"), Err(FdtError::BadPath));
+    assert_eq!(fdt.path_offset_namelen(b"/node_a/missing"), Ok(None));
+    assert_eq!(fdt.parent_offset(NodeOffset::ROOT), Err(FdtError::NotFound));
+    assert_eq!(fdt.supernode_atdepth_offset(node, 2), Err(FdtError::NotFound));
+    assert_eq!(fdt.address_cells(NodeOffset::ROOT), Ok(2));
+    assert_eq!(fdt.size_cells(NodeOffset::ROOT), Ok(1));
+    assert_eq!(fdt.next_property_offset(prop), Ok(None));
+}
+
+#[test]
+fn alias_loops_are_bad_paths() {
+    let mut data = fs::read(TEST_TREE_ALIASES_PATH).unwrap();
+    data.resize(data.len() * 2, 0_u8);
+    let fdt = Fdt::from_mut_slice(&mut data).unwrap();
+    fdt.unpack().unwrap();
+    let mut aliases = fdt.aliases_mut().unwrap().unwrap();
+    aliases.setprop(cstr!("self"), b"self\0").unwrap();
+    aliases.setprop(cstr!("ping"), b"pong/node\0").unwrap();
+    aliases.setprop(cstr!("pong"), b"ping/node\0").unwrap();
+    let pure_fdt = pure::Fdt::from_slice(fdt.as_slice()).unwrap();
+
+    assert_eq!(pure_fdt.path_offset_namelen(b"self"), Err(FdtError::BadPath));
+    assert_eq!(pure_fdt.path_offset_namelen(b"ping"), Err(FdtError::BadPath));
+    assert!(pure_fdt.path_offset_namelen(b"serial0").unwrap().is_some());
+}
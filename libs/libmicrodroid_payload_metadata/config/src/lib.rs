@@ -95,6 +95,11 @@ pub struct Task {
     /// - For executable task, this is the path to the executable.
     /// - For microdroid_launcher task, this is the name of .so
     pub command: String,
+
+    /// For microdroid_launcher task, the index of the extra APK holding the .so, e.g. a split APK.
+    /// The .so is in the main APK if unset.
+    #[serde(default)]
+    pub extra_apk_index: Option<usize>,
 }
 
 /// APEX config
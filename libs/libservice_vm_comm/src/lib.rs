@@ -21,6 +21,7 @@ extern crate alloc;
 
 mod csr;
 mod message;
+mod sealing;
 mod vsock;
 
 pub use csr::{Csr, CsrPayload};
@@ -28,4 +29,8 @@ pub use message::{
     ClientVmAttestationParams, EcdsaP256KeyPair, GenerateCertificateRequestParams, Request,
     RequestProcessingError, Response, ServiceVmRequest,
 };
+pub use sealing::{
+    DeriveSealedKeyParams, SealedKey, SealingPolicy, SignedPublicKeys, SEALED_KEY_KEK_INFO,
+    SEALED_KEY_SIZE,
+};
 pub use vsock::VmType;
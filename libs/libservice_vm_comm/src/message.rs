@@ -15,6 +15,7 @@
 //! This module contains the requests and responses definitions exchanged
 //! between the host and the service VM.
 
+use crate::sealing::{DeriveSealedKeyParams, SealedKey};
 use alloc::vec::Vec;
 use core::fmt;
 use log::error;
@@ -54,6 +55,10 @@ pub enum Request {
     /// Requests the service VM to attest the client VM and issue a certificate
     /// if the attestation succeeds.
     RequestClientVmAttestation(ClientVmAttestationParams),
+
+    /// Requests the service VM to derive a key bound to the DICE chain of the client VM, which
+    /// the client VM can use to seal its data.
+    DeriveSealedKey(DeriveSealedKeyParams),
 }
 
 impl Request {
@@ -64,6 +69,7 @@ impl Request {
             Self::GenerateEcdsaP256KeyPair => "GenerateEcdsaP256KeyPair",
             Self::GenerateCertificateRequest(_) => "GenerateCertificateRequest",
             Self::RequestClientVmAttestation(_) => "RequestClientVmAttestation",
+            Self::DeriveSealedKey(_) => "DeriveSealedKey",
         }
     }
 }
@@ -106,6 +112,9 @@ pub enum Response {
     /// includes an extension that describes the attested client VM.
     RequestClientVmAttestation(Vec<u8>),
 
+    /// Returns the sealed key derived for the client VM, encrypted for it.
+    DeriveSealedKey(SealedKey),
+
     /// Encountered an error during the request processing.
     Err(RequestProcessingError),
 }
@@ -118,6 +127,7 @@ impl Response {
             Self::GenerateEcdsaP256KeyPair(_) => "GenerateEcdsaP256KeyPair",
             Self::GenerateCertificateRequest(_) => "GenerateCertificateRequest",
             Self::RequestClientVmAttestation(_) => "RequestClientVmAttestation",
+            Self::DeriveSealedKey(_) => "DeriveSealedKey",
             Self::Err(_) => "Err",
         }
     }
@@ -164,6 +174,9 @@ pub enum RequestProcessingError {
 
     /// The vendor partition loaded by the client VM is invalid.
     InvalidVendorPartition,
+
+    /// The sealing policy requested by the client VM is invalid.
+    InvalidSealingPolicy,
 }
 
 impl fmt::Display for RequestProcessingError {
@@ -198,6 +211,9 @@ impl fmt::Display for RequestProcessingError {
             Self::InvalidVendorPartition => {
                 write!(f, "The vendor partition loaded by the client VM is invalid")
             }
+            Self::InvalidSealingPolicy => {
+                write!(f, "The sealing policy requested by the client VM is invalid")
+            }
         }
     }
 }
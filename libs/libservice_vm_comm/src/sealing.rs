@@ -0,0 +1,161 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module contains the structs related to the sealed keys derived by the service VM
+//! for the client VMs.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use cbor_util::{cbor_value_type, value_to_bytes};
+use ciborium::Value;
+use coset::{self, CborSerializable, CoseError};
+use serde::{Deserialize, Serialize};
+
+/// Size in bytes of a sealed key.
+pub const SEALED_KEY_SIZE: usize = 32;
+
+/// The info used to derive with HKDF, from the ECDH shared secret of the ephemeral keys of the
+/// client VM and the service VM, the key encrypting the sealed key on its way to the client VM.
+pub const SEALED_KEY_KEK_INFO: &[u8] = b"rialto sealed key kek";
+
+/// The measurements of the client VM a sealed key is bound to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum SealingPolicy {
+    /// The code hashes of the kernel, the vendor partition if any, and the payload with its
+    /// APKs and APEXes. Any update of these components changes the key.
+    ExactImages = 0,
+
+    /// The authority hashes of the same components. The key stays the same across updates
+    /// signed with the same keys.
+    SameAuthority = 1,
+}
+
+impl SealingPolicy {
+    /// Encodes the policy as the challenge of the CSR requesting a sealed key, so that it is
+    /// signed together with the rest of the request.
+    pub fn to_challenge(self) -> Vec<u8> {
+        vec![self as u8]
+    }
+
+    /// Decodes the policy from the challenge of the CSR requesting a sealed key.
+    pub fn from_challenge(challenge: &[u8]) -> Option<Self> {
+        match challenge {
+            [0] => Some(Self::ExactImages),
+            [1] => Some(Self::SameAuthority),
+            _ => None,
+        }
+    }
+}
+
+/// Represents the params passed to `Request::DeriveSealedKey`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeriveSealedKeyParams {
+    /// The CBOR-encoded CSR signed by the CDI_Leaf_Priv of the client VM's DICE chain and
+    /// the private key of an ephemeral key pair of the client VM.
+    ///
+    /// The challenge of the CSR payload encodes the `SealingPolicy`, and its public key is the
+    /// one the sealed key is encrypted for.
+    pub csr: Vec<u8>,
+}
+
+/// A sealed key, encrypted for the client VM which requested it.
+///
+/// The client VM only accepts the key if `signed_public_keys` is signed by a service VM whose
+/// DICE chain shares the root and the firmware entries of its own DICE chain, so that the host
+/// can't substitute an ephemeral key pair of its own to the one of the service VM.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SealedKey {
+    /// The CBOR-encoded DICE chain of the service VM.
+    pub service_vm_dice_chain: Vec<u8>,
+
+    /// COSE_Sign1 signed with the CDI_Leaf_Priv of the service VM, whose payload is
+    /// `SignedPublicKeys`.
+    pub signed_public_keys: Vec<u8>,
+
+    /// The sealed key, encrypted with AES-256-GCM and an all-zero nonce. The encryption key is
+    /// derived with `SEALED_KEY_KEK_INFO` from the ECDH shared secret of the ephemeral keys,
+    /// which are never reused.
+    pub encrypted_key: Vec<u8>,
+}
+
+impl SealedKey {
+    /// Serializes this object to a CBOR-encoded vector.
+    pub fn into_cbor_vec(self) -> coset::Result<Vec<u8>> {
+        let value = Value::Array(vec![
+            Value::Bytes(self.service_vm_dice_chain),
+            Value::Bytes(self.signed_public_keys),
+            Value::Bytes(self.encrypted_key),
+        ]);
+        value.to_vec()
+    }
+
+    /// Creates an object instance from the provided CBOR-encoded slice.
+    pub fn from_cbor_slice(data: &[u8]) -> coset::Result<Self> {
+        let value = Value::from_slice(data)?;
+        let Value::Array(mut arr) = value else {
+            return Err(CoseError::UnexpectedItem(cbor_value_type(&value), "array"));
+        };
+        if arr.len() != 3 {
+            return Err(CoseError::UnexpectedItem("array", "array with 3 items"));
+        }
+        Ok(Self {
+            encrypted_key: value_to_bytes(arr.remove(2), "encrypted_key")?,
+            signed_public_keys: value_to_bytes(arr.remove(1), "signed_public_keys")?,
+            service_vm_dice_chain: value_to_bytes(arr.remove(0), "service_vm_dice_chain")?,
+        })
+    }
+}
+
+/// The ephemeral public keys of the service VM and the client VM used to encrypt a sealed key,
+/// signed by the service VM.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignedPublicKeys {
+    /// COSE_Key encoded EC P-256 public key of the ephemeral key pair of the service VM.
+    pub service_vm_public_key: Vec<u8>,
+
+    /// COSE_Key encoded EC P-256 public key of the ephemeral key pair of the client VM, as in the
+    /// CSR requesting the sealed key. This binds the signature to the request.
+    pub client_vm_public_key: Vec<u8>,
+}
+
+impl SignedPublicKeys {
+    /// Converts this object to a CBOR value.
+    pub fn into_cbor_value(self) -> Value {
+        Value::Array(vec![
+            Value::Bytes(self.service_vm_public_key),
+            Value::Bytes(self.client_vm_public_key),
+        ])
+    }
+
+    /// Serializes this object to a CBOR-encoded vector.
+    pub fn into_cbor_vec(self) -> coset::Result<Vec<u8>> {
+        self.into_cbor_value().to_vec()
+    }
+
+    /// Creates an object instance from the provided CBOR-encoded slice.
+    pub fn from_cbor_slice(data: &[u8]) -> coset::Result<Self> {
+        let value = Value::from_slice(data)?;
+        let Value::Array(mut arr) = value else {
+            return Err(CoseError::UnexpectedItem(cbor_value_type(&value), "array"));
+        };
+        if arr.len() != 2 {
+            return Err(CoseError::UnexpectedItem("array", "array with 2 items"));
+        }
+        Ok(Self {
+            client_vm_public_key: value_to_bytes(arr.remove(1), "client_vm_public_key")?,
+            service_vm_public_key: value_to_bytes(arr.remove(0), "service_vm_public_key")?,
+        })
+    }
+}
@@ -28,12 +28,12 @@ use anyhow::{anyhow, ensure, Context, Result};
 use log::{info, warn};
 use service_vm_comm::{Request, Response, ServiceVmRequest, VmType};
 use std::fs::{self, File, OpenOptions};
-use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::io::{BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{Condvar, Mutex};
 use std::thread;
 use std::time::Duration;
-use vmclient::{DeathReason, VmInstance};
+use vmclient::{log_forwarder, DeathReason, VmInstance};
 use vsock::{VsockListener, VsockStream, VMADDR_CID_HOST};
 
 /// Size of virtual memory allocated to the Service VM.
@@ -43,6 +43,7 @@ const VIRT_DATA_DIR: &str = "/data/misc/apexdata/com.android.virt";
 const RIALTO_PATH: &str = "/apex/com.android.virt/etc/rialto.bin";
 const INSTANCE_IMG_NAME: &str = "service_vm_instance.img";
 const INSTANCE_ID_FILENAME: &str = "service_vm_instance_id";
+const SERVICE_VM_LOG_TAG: &str = "service_vm";
 const INSTANCE_IMG_SIZE_BYTES: i64 = 1 << 20; // 1MB
 const WRITE_BUFFER_CAPACITY: usize = 512;
 const READ_TIMEOUT: Duration = Duration::from_secs(10);
@@ -225,6 +226,7 @@ pub fn protected_vm_instance(instance_img_path: PathBuf) -> Result<VmInstance> {
         image: Some(instance_img),
         writable: true,
         guid: None,
+        typeGuid: None,
     }];
     let rialto = File::open(RIALTO_PATH).context("Failed to open Rialto kernel binary")?;
     let instance_id_file = Path::new(VIRT_DATA_DIR).join(INSTANCE_ID_FILENAME);
@@ -232,7 +234,12 @@ pub fn protected_vm_instance(instance_img_path: PathBuf) -> Result<VmInstance> {
     let config = VirtualMachineConfig::RawConfig(VirtualMachineRawConfig {
         name: String::from("Service VM"),
         kernel: Some(ParcelFileDescriptor::new(rialto)),
-        disks: vec![DiskImage { image: None, partitions: writable_partitions, writable: true }],
+        disks: vec![DiskImage {
+            image: None,
+            partitions: writable_partitions,
+            writable: true,
+            directIo: false,
+        }],
         instanceId: instance_id,
         protectedVm: true,
         memoryMib: VM_MEMORY_MB,
@@ -241,9 +248,9 @@ pub fn protected_vm_instance(instance_img_path: PathBuf) -> Result<VmInstance> {
         gdbPort: 0, // No gdb
         ..Default::default()
     });
-    let console_out = Some(android_log_fd()?);
+    let console_out = Some(log_forwarder(SERVICE_VM_LOG_TAG)?);
     let console_in = None;
-    let log = Some(android_log_fd()?);
+    let log = Some(log_forwarder(SERVICE_VM_LOG_TAG)?);
     let callback = None;
     VmInstance::create(service.as_ref(), &config, console_out, console_in, log, callback)
         .context("Failed to create service VM")
@@ -293,24 +300,3 @@ fn instance_img(
     )?;
     Ok(instance_img)
 }
-
-/// This function is only exposed for testing.
-pub fn android_log_fd() -> io::Result<File> {
-    let (reader_fd, writer_fd) = nix::unistd::pipe()?;
-
-    let reader = File::from(reader_fd);
-    let writer = File::from(writer_fd);
-
-    thread::spawn(|| {
-        for line in BufReader::new(reader).lines() {
-            match line {
-                Ok(l) => info!("{}", l),
-                Err(e) => {
-                    warn!("Failed to read line: {e:?}");
-                    break;
-                }
-            }
-        }
-    });
-    Ok(writer)
-}
@@ -16,6 +16,7 @@
 
 use crate::client_vm;
 use crate::rkp;
+use crate::sealing;
 use alloc::vec::Vec;
 use diced_open_dice::DiceArtifacts;
 use service_vm_comm::{Request, Response};
@@ -39,6 +40,12 @@ pub fn process_request(request: Request, context: &RequestContext) -> Response {
             context.vendor_hashtree_root_digest,
         )
         .map_or_else(Response::Err, Response::RequestClientVmAttestation),
+        Request::DeriveSealedKey(p) => sealing::derive_sealed_key(
+            p,
+            context.dice_artifacts,
+            context.vendor_hashtree_root_digest,
+        )
+        .map_or_else(Response::Err, Response::DeriveSealedKey),
     }
 }
 
@@ -36,39 +36,26 @@ type Result<T> = result::Result<T, RequestProcessingError>;
 const DICE_CDI_LEAF_SIGNATURE_INDEX: usize = 0;
 const ATTESTATION_KEY_SIGNATURE_INDEX: usize = 1;
 
+/// A CSR from the client VM whose DICE chain and signatures have been validated.
+pub(crate) struct ValidatedCsr {
+    /// The DICE chain of the client VM.
+    pub(crate) dice_chain: ClientVmDiceChain,
+    /// The signed payload of the CSR.
+    pub(crate) payload: CsrPayload,
+    /// The public key in the CSR payload.
+    pub(crate) public_key: EcKey,
+}
+
 pub(super) fn request_attestation(
     params: ClientVmAttestationParams,
     dice_artifacts: &dyn DiceArtifacts,
     vendor_hashtree_root_digest_from_dt: Option<&[u8]>,
 ) -> Result<Vec<u8>> {
-    let csr = Csr::from_cbor_slice(&params.csr)?;
-    let cose_sign = CoseSign::from_slice(&csr.signed_csr_payload)?;
-    let csr_payload = cose_sign.payload.as_ref().ok_or_else(|| {
-        error!("No CsrPayload found in the CSR");
-        RequestProcessingError::InternalError
-    })?;
-    let csr_payload = CsrPayload::from_cbor_slice(csr_payload)?;
-
-    let client_vm_dice_chain = validate_client_vm_dice_chain(
-        &csr.dice_cert_chain,
-        dice_artifacts.bcc().ok_or(RequestProcessingError::MissingDiceChain)?,
-        vendor_hashtree_root_digest_from_dt,
-    )?;
-
-    // AAD is empty as defined in libs/libservice_vm_comm/client_vm_csr.cddl.
-    let aad = &[];
-
-    // Verifies the first signature with the leaf private key in the DICE chain.
-    cose_sign.verify_signature(DICE_CDI_LEAF_SIGNATURE_INDEX, aad, |signature, message| {
-        client_vm_dice_chain.microdroid_payload().subject_public_key.verify(signature, message)
-    })?;
-
-    // Verifies the second signature with the public key in the CSR payload.
-    let ec_public_key = EcKey::from_cose_public_key_slice(&csr_payload.public_key)?;
-    cose_sign.verify_signature(ATTESTATION_KEY_SIGNATURE_INDEX, aad, |signature, message| {
-        ecdsa_verify_cose(&ec_public_key, signature, message)
-    })?;
-
+    let ValidatedCsr {
+        dice_chain: client_vm_dice_chain,
+        payload: csr_payload,
+        public_key: ec_public_key,
+    } = validate_csr(&params.csr, dice_artifacts, vendor_hashtree_root_digest_from_dt)?;
     let subject_public_key_info = PKey::try_from(ec_public_key)?.subject_public_key_info()?;
 
     // Builds the TBSCertificate.
@@ -115,6 +102,48 @@ pub(super) fn request_attestation(
     Ok(certificate.to_der()?)
 }
 
+/// Validates the DICE chain of the client VM in the CSR against the reference DICE chain of the
+/// service VM and `vendor_hashtree_root_digest_from_dt`, then both signatures of the CSR payload.
+pub(crate) fn validate_csr(
+    csr: &[u8],
+    dice_artifacts: &dyn DiceArtifacts,
+    vendor_hashtree_root_digest_from_dt: Option<&[u8]>,
+) -> Result<ValidatedCsr> {
+    let csr = Csr::from_cbor_slice(csr)?;
+    let cose_sign = CoseSign::from_slice(&csr.signed_csr_payload)?;
+    let csr_payload = cose_sign.payload.as_ref().ok_or_else(|| {
+        error!("No CsrPayload found in the CSR");
+        RequestProcessingError::InternalError
+    })?;
+    let csr_payload = CsrPayload::from_cbor_slice(csr_payload)?;
+
+    let client_vm_dice_chain = validate_client_vm_dice_chain(
+        &csr.dice_cert_chain,
+        dice_artifacts.bcc().ok_or(RequestProcessingError::MissingDiceChain)?,
+        vendor_hashtree_root_digest_from_dt,
+    )?;
+
+    // AAD is empty as defined in libs/libservice_vm_comm/client_vm_csr.cddl.
+    let aad = &[];
+
+    // Verifies the first signature with the leaf private key in the DICE chain.
+    cose_sign.verify_signature(DICE_CDI_LEAF_SIGNATURE_INDEX, aad, |signature, message| {
+        client_vm_dice_chain.microdroid_payload().subject_public_key.verify(signature, message)
+    })?;
+
+    // Verifies the second signature with the public key in the CSR payload.
+    let ec_public_key = EcKey::from_cose_public_key_slice(&csr_payload.public_key)?;
+    cose_sign.verify_signature(ATTESTATION_KEY_SIGNATURE_INDEX, aad, |signature, message| {
+        ecdsa_verify_cose(&ec_public_key, signature, message)
+    })?;
+
+    Ok(ValidatedCsr {
+        dice_chain: client_vm_dice_chain,
+        payload: csr_payload,
+        public_key: ec_public_key,
+    })
+}
+
 fn ecdsa_verify_cose(key: &EcKey, signature: &[u8], message: &[u8]) -> bssl_avf::Result<()> {
     // The message was signed with ECDSA with curve P-256 and SHA-256 at the signature generation.
     let digest = sha256(message)?;
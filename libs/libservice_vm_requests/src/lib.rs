@@ -25,5 +25,6 @@ mod dice;
 mod keyblob;
 mod pub_key;
 mod rkp;
+mod sealing;
 
 pub use api::{process_request, RequestContext};
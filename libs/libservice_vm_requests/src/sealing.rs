@@ -0,0 +1,116 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module contains functions related to the derivation of sealed keys for the
+//! client VM.
+
+use crate::client_vm::{validate_csr, ValidatedCsr};
+use crate::dice::ClientVmDiceChain;
+use crate::rkp::build_signed_data;
+use alloc::vec;
+use alloc::vec::Vec;
+use bssl_avf::{hkdf, Aead, AeadContext, Digester, EcKey, AES_GCM_NONCE_LENGTH};
+use core::result;
+use coset::CborSerializable;
+use diced_open_dice::DiceArtifacts;
+use log::{error, info};
+use service_vm_comm::{
+    DeriveSealedKeyParams, RequestProcessingError, SealedKey, SealingPolicy, SignedPublicKeys,
+    SEALED_KEY_KEK_INFO, SEALED_KEY_SIZE,
+};
+
+type Result<T> = result::Result<T, RequestProcessingError>;
+
+/// The info used to derive the sealed key from the CDI_Seal of the service VM.
+const SEALED_KEY_INFO: &[u8] = b"rialto sealed key";
+
+/// An all-zero nonce is used to encrypt the sealed key, as each sealed key is encrypted with
+/// a distinct key derived from fresh ephemeral keys.
+const SEALED_KEY_NONCE: &[u8; AES_GCM_NONCE_LENGTH] = &[0; AES_GCM_NONCE_LENGTH];
+
+/// No additional data is needed, as the ephemeral public key of the client VM is signed in the
+/// CSR.
+const SEALED_KEY_AD: &[u8] = &[];
+
+pub(super) fn derive_sealed_key(
+    params: DeriveSealedKeyParams,
+    dice_artifacts: &dyn DiceArtifacts,
+    vendor_hashtree_root_digest_from_dt: Option<&[u8]>,
+) -> Result<SealedKey> {
+    let ValidatedCsr { dice_chain, payload, public_key: client_vm_public_key } =
+        validate_csr(&params.csr, dice_artifacts, vendor_hashtree_root_digest_from_dt)?;
+    let policy = SealingPolicy::from_challenge(&payload.challenge).ok_or_else(|| {
+        error!("Invalid sealing policy: {:?}", payload.challenge);
+        RequestProcessingError::InvalidSealingPolicy
+    })?;
+
+    let measurements = measurements(&dice_chain, policy)?;
+    let sealed_key = hkdf::<SEALED_KEY_SIZE>(
+        dice_artifacts.cdi_seal(),
+        &measurements,
+        SEALED_KEY_INFO,
+        Digester::sha512(),
+    )?;
+
+    let mut ephemeral_key = EcKey::new_p256()?;
+    ephemeral_key.generate_key()?;
+    let shared_secret = ephemeral_key.ecdh(&client_vm_public_key)?;
+    let kek = hkdf::<32>(&shared_secret, &[], SEALED_KEY_KEK_INFO, Digester::sha512())?;
+    let tag_len = None;
+    let aead_ctx = AeadContext::new(Aead::aes_256_gcm(), kek.as_slice(), tag_len)?;
+    let mut out = vec![0u8; sealed_key.len() + aead_ctx.aead().max_overhead()];
+    let encrypted_key =
+        aead_ctx.seal(sealed_key.as_slice(), SEALED_KEY_NONCE, SEALED_KEY_AD, &mut out)?;
+
+    // Signs the ephemeral public keys with the DICE leaf key of the service VM, so that the
+    // client VM can check that its key was encrypted by the service VM and not by the host.
+    let signed_public_keys = SignedPublicKeys {
+        service_vm_public_key: ephemeral_key.cose_public_key()?.to_vec()?,
+        client_vm_public_key: payload.public_key,
+    };
+    let signed_public_keys =
+        build_signed_data(&signed_public_keys.into_cbor_value(), dice_artifacts)?.to_vec()?;
+    let service_vm_dice_chain =
+        dice_artifacts.bcc().ok_or(RequestProcessingError::MissingDiceChain)?.to_vec();
+
+    info!("Derived a sealed key for the client VM with policy {policy:?}");
+    Ok(SealedKey {
+        service_vm_dice_chain,
+        signed_public_keys,
+        encrypted_key: encrypted_key.to_vec(),
+    })
+}
+
+/// Returns the measurements of the client VM which the sealed key is bound to, according to
+/// `policy`. The DICE mode of the VM is always included, so that a debuggable VM can't derive
+/// the keys of a non-debuggable one.
+fn measurements(dice_chain: &ClientVmDiceChain, policy: SealingPolicy) -> Result<Vec<u8>> {
+    let mut entries = vec![dice_chain.microdroid_kernel()];
+    entries.extend(dice_chain.vendor_partition());
+    entries.push(dice_chain.microdroid_payload());
+    let components = dice_chain.microdroid_payload_components()?;
+
+    let mut measurements = vec![policy as u8, dice_chain.all_entries_are_secure().into()];
+    match policy {
+        SealingPolicy::ExactImages => {
+            entries.iter().for_each(|e| measurements.extend_from_slice(&e.code_hash));
+            components.iter().for_each(|c| measurements.extend_from_slice(&c.code_hash));
+        }
+        SealingPolicy::SameAuthority => {
+            entries.iter().for_each(|e| measurements.extend_from_slice(&e.authority_hash));
+            components.iter().for_each(|c| measurements.extend_from_slice(&c.authority_hash));
+        }
+    }
+    Ok(measurements)
+}
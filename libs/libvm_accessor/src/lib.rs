@@ -0,0 +1,120 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Serves a service running in a VM to clients on the host through `IAccessor`, so that they can
+//! get it from servicemanager as if it were running on the host.
+//!
+//! The process serving the accessor starts the VM with a supplier callback, and connects clients
+//! to the service over vsock once the payload is ready. The VM is started again if it dies.
+
+use android_os_accessor::aidl::android::os::IAccessor::{BnAccessor, IAccessor};
+use anyhow::{anyhow, Context, Result};
+use avflog::LogResult;
+use binder::{self, BinderFeatures, Interface, IntoBinderResult, ParcelFileDescriptor, Strong};
+use log::{info, warn};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use vmclient::VmInstance;
+
+/// How long a client waits for the payload to be ready, by default.
+pub const DEFAULT_READY_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Starts the VM serving the service, e.g. with `VmInstance::create` and `VmInstance::start`.
+pub type VmSupplier = Box<dyn Fn() -> Result<VmInstance> + Send + Sync>;
+
+/// An `IAccessor` connecting clients to a service served by the payload of a VM on a vsock port.
+///
+/// It doesn't hold a `LazyServiceGuard`, so that the process serving it, and the VM, quit once
+/// nobody references the accessor.
+// TODO(b/353492849): Do not use IAccessor directly.
+pub struct VmAccessor {
+    instance: String,
+    port: u32,
+    ready_timeout: Duration,
+    supplier: VmSupplier,
+    vm: Mutex<Option<Arc<VmInstance>>>,
+}
+
+impl VmAccessor {
+    /// Creates an accessor for the service `instance`, served by the payload on vsock `port` of
+    /// the VM which `supplier` starts. The VM is only started when the first client connects.
+    pub fn new(
+        instance: &str,
+        port: u32,
+        supplier: impl Fn() -> Result<VmInstance> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            instance: instance.to_owned(),
+            port,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            supplier: Box::new(supplier),
+            vm: Mutex::new(None),
+        }
+    }
+
+    /// Sets how long a client waits for the payload to be ready before its connection fails.
+    pub fn with_ready_timeout(mut self, ready_timeout: Duration) -> Self {
+        self.ready_timeout = ready_timeout;
+        self
+    }
+
+    /// Returns the binder object of the accessor, e.g. to register it with servicemanager.
+    pub fn into_binder(self) -> Strong<dyn IAccessor> {
+        BnAccessor::new_binder(self, BinderFeatures::default())
+    }
+
+    /// Registers the accessor with servicemanager as a lazy service, under its instance name.
+    pub fn register_lazy_service(self) -> Result<()> {
+        let instance = self.instance.clone();
+        binder::register_lazy_service(&instance, self.into_binder().as_binder())
+            .map_err(|e| anyhow!("Failed to register lazy service {instance}: {e:?}"))?;
+        info!("{instance} is registered as a lazy service");
+        Ok(())
+    }
+
+    /// Returns the running VM, starting it if it isn't running yet or died.
+    fn vm(&self) -> Result<Arc<VmInstance>> {
+        let mut vm = self.vm.lock().unwrap();
+        if let Some(current) = vm.as_ref() {
+            match current.wait_for_death_with_timeout(Duration::ZERO) {
+                None => return Ok(current.clone()),
+                Some(reason) => {
+                    warn!("VM serving {} died ({reason:?}), restarting it", self.instance)
+                }
+            }
+        }
+        let new_vm = Arc::new((self.supplier)().context("Failed to start VM")?);
+        info!("Started VM with CID {} to serve {}", new_vm.cid(), self.instance);
+        Ok(vm.insert(new_vm).clone())
+    }
+}
+
+impl Interface for VmAccessor {}
+
+impl IAccessor for VmAccessor {
+    fn addConnection(&self) -> binder::Result<ParcelFileDescriptor> {
+        let vm = self.vm().with_log().or_service_specific_exception(-1)?;
+        vm.wait_until_ready(self.ready_timeout)
+            .with_context(|| format!("VM serving {} isn't ready", self.instance))
+            .with_log()
+            .or_service_specific_exception(-1)?;
+
+        info!("VM is ready. Connecting to service via port {}", self.port);
+        vm.vm.connectVsock(self.port as i32)
+    }
+
+    fn getInstanceName(&self) -> binder::Result<String> {
+        Ok(self.instance.clone())
+    }
+}
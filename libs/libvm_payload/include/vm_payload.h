@@ -20,6 +20,7 @@
 #include <stddef.h>
 #include <stdint.h>
 #include <sys/cdefs.h>
+#include <sys/types.h>
 
 #include "vm_main.h"
 
@@ -49,8 +50,115 @@ typedef enum AVmAttestationStatus : int32_t {
 
     /** Remote attestation is not supported in the current environment. */
     ATTESTATION_ERROR_UNSUPPORTED = -10003,
+
+    /** Introduced in API 36. The attestation of the peer VM is invalid. */
+    ATTESTATION_ERROR_VERIFICATION_FAILED = -10004,
 } AVmAttestationStatus;
 
+/**
+ * Introduced in API 36.
+ * Measurements of the VM which a sealed key is bound to.
+ */
+typedef enum AVmSealingPolicy : int32_t {
+    /** The key changes whenever the kernel, vendor partition, APK or APEXes are updated. */
+    SEALING_POLICY_EXACT_IMAGES = 0,
+
+    /** The key stays the same across updates signed with the same keys. */
+    SEALING_POLICY_SAME_AUTHORITY = 1,
+} AVmSealingPolicy;
+
+/**
+ * Introduced in API 36.
+ * CPU vulnerabilities whose mitigation state the host reports to the VM.
+ */
+typedef enum AVmCpuVulnerability : int32_t {
+    /** Rogue data cache load (CVE-2017-5754). */
+    CPU_VULNERABILITY_MELTDOWN = 0,
+
+    /** Bounds check bypass (CVE-2017-5753). */
+    CPU_VULNERABILITY_SPECTRE_V1 = 1,
+
+    /** Branch target injection (CVE-2017-5715). */
+    CPU_VULNERABILITY_SPECTRE_V2 = 2,
+
+    /** Speculative store bypass (CVE-2018-3639). */
+    CPU_VULNERABILITY_SPEC_STORE_BYPASS = 3,
+} AVmCpuVulnerability;
+
+/**
+ * Introduced in API 36.
+ * State of the mitigation of a CPU vulnerability by the host.
+ */
+typedef enum AVmCpuMitigationState : int32_t {
+    /** The host didn't report the state of the vulnerability. */
+    CPU_MITIGATION_STATE_UNKNOWN = 0,
+
+    /** The CPU isn't affected by the vulnerability. */
+    CPU_MITIGATION_STATE_NOT_AFFECTED = 1,
+
+    /** The CPU is affected by the vulnerability, but the host mitigates it. */
+    CPU_MITIGATION_STATE_MITIGATED = 2,
+
+    /** The CPU is affected by the vulnerability, and the host doesn't mitigate it. */
+    CPU_MITIGATION_STATE_VULNERABLE = 3,
+} AVmCpuMitigationState;
+
+/**
+ * Introduced in API 36.
+ * Features which the host enabled for the VM, see AVmPayload_getCapabilities.
+ */
+typedef enum AVmCapability : uint32_t {
+    /** The payload can request remote attestation with AVmPayload_requestAttestation. */
+    VM_CAPABILITY_REMOTE_ATTESTATION = 1 << 0,
+
+    /** The VM has encrypted storage, see AVmPayload_getEncryptedStoragePath. */
+    VM_CAPABILITY_ENCRYPTED_STORAGE = 1 << 1,
+
+    /** The VM has a network interface. */
+    VM_CAPABILITY_NETWORK = 1 << 2,
+} AVmCapability;
+
+/**
+ * Introduced in API 36.
+ * How the console processes the input from the host, see AVmPayload_openConsole.
+ */
+typedef enum AVmConsoleMode : int32_t {
+    /** Input is echoed back and delivered a line at a time, as expected by a shell prompt. */
+    CONSOLE_MODE_LINE = 0,
+
+    /** Input is delivered byte by byte, without echo or any other processing. */
+    CONSOLE_MODE_RAW = 1,
+} AVmConsoleMode;
+
+/**
+ * Introduced in API 36.
+ * Priority of a log record of the payload, see AVmPayload_writeLog. The values match those of
+ * android_LogPriority.
+ */
+typedef enum AVmLogPriority : int32_t {
+    LOG_PRIORITY_VERBOSE = 2,
+    LOG_PRIORITY_DEBUG = 3,
+    LOG_PRIORITY_INFO = 4,
+    LOG_PRIORITY_WARN = 5,
+    LOG_PRIORITY_ERROR = 6,
+    LOG_PRIORITY_FATAL = 7,
+} AVmLogPriority;
+
+/**
+ * Introduced in API 36.
+ * How far the time returned by AVmPayload_getCurrentTime can be trusted.
+ */
+typedef enum AVmTimeTrustLevel : int32_t {
+    /** The time comes from the host, which is trusted as the VM isn't protected. */
+    TIME_TRUST_LEVEL_TRUSTED_HOST = 0,
+
+    /**
+     * The time comes from the host, which isn't trusted by the protected VM. It may be wrong, so
+     * it shouldn't be relied on where a wrong time would compromise the payload.
+     */
+    TIME_TRUST_LEVEL_UNTRUSTED_HOST = 1,
+} AVmTimeTrustLevel;
+
 /**
  * Notifies the host that the payload is ready.
  *
@@ -134,6 +242,279 @@ const char* _Nonnull AVmPayload_getApkContentsPath(void);
  */
 const char* _Nullable AVmPayload_getEncryptedStoragePath(void);
 
+/**
+ * Gets the path to the directory holding a read-only copy of the system CA certificates of the
+ * host, if any. The copy is refreshed from the host every time the VM starts.
+ *
+ * The certificates are provided by the host and are not part of the measured configuration of
+ * the VM; a protected VM must not rely on them to establish trust in anything the host controls.
+ *
+ * \return the path to the CA certificates directory, or NULL if sharing of the host CA
+ * certificates was not requested in the VM configuration. If non-null the returned string should
+ * not be deleted or freed by the application and remains valid for the lifetime of the VM.
+ */
+const char* _Nullable AVmPayload_getHostCaCertificatesPath(void)
+        __INTRODUCED_IN(__ANDROID_API_B__);
+
+/**
+ * Requests a host file which the user picks through the app owning the VM, e.g. with the system
+ * file picker. This blocks until the user has made a choice, and the app may decline the request.
+ *
+ * Only the content of the chosen file is made available to the VM, as a read-only stream.
+ *
+ * \param mimeType the MIME type of the requested file, e.g. "image/*".
+ *
+ * \return a file descriptor for reading the content of the chosen file, which the caller owns
+ * and must close, or -1 if no file was chosen.
+ */
+int AVmPayload_requestHostFile(const char* _Nonnull mimeType) __INTRODUCED_IN(__ANDROID_API_B__);
+
+/**
+ * Requests all or part of a 32-byte key derived by the service VM from its own secrets and the
+ * measurements of this VM selected by `policy`.
+ *
+ * Unlike the VM instance secret, the key is the same for every instance of the same payload on
+ * the device, and it survives the deletion of the VM instance. It is never revealed to the host.
+ *
+ * This function will abort if `policy` is not one of the `AVmSealingPolicy` values.
+ *
+ * \param policy the `AVmSealingPolicy` selecting the measurements of this VM which the key is
+ * bound to.
+ * \param key pointer to size bytes where the key is written.
+ * \param size number of bytes of the key to get, <= 32.
+ *
+ * \return true on success, or false if the key could not be derived, e.g. because the service VM
+ * is not supported on this device.
+ */
+bool AVmPayload_requestSealedKey(int32_t policy, void* _Nonnull key, size_t size)
+        __INTRODUCED_IN(__ANDROID_API_B__);
+
+/**
+ * Sets the callbacks notified around host-initiated snapshots of the VM, replacing the previous
+ * ones. Pass null callbacks to stop being notified.
+ *
+ * `onPreSnapshot` is called before the host snapshots the VM, so that the payload can make its
+ * persisted state consistent, e.g. by flushing pending writes. Returning acknowledges that the
+ * payload is ready to be snapshotted. The host only waits for a bounded quiesce window, after
+ * which it snapshots the VM anyway.
+ *
+ * `onPostRestore` is called after the VM has been restored from a snapshot. Time may have passed
+ * since the snapshot was taken, so time-sensitive caches should be invalidated.
+ *
+ * The callbacks are called on a binder thread, with the `context` parameter. They are called one at
+ * a time, but may run concurrently with the callback set by `AVmPayload_setShutdownCallback`.
+ *
+ * \param onPreSnapshot the callback called before a snapshot.
+ * \param onPostRestore the callback called after a restore.
+ * \param context parameter passed to the callbacks. It must remain valid until the callbacks are
+ *        replaced.
+ */
+void AVmPayload_setSnapshotCallbacks(void (*_Nullable onPreSnapshot)(void* _Nullable context),
+                                     void (*_Nullable onPostRestore)(void* _Nullable context),
+                                     void* _Nullable context) __INTRODUCED_IN(__ANDROID_API_B__);
+
+/**
+ * Sets the callback notified when the host shuts the VM down gracefully, replacing the previous
+ * one. Pass a null callback to stop being notified.
+ *
+ * `onShutdownRequested` is called when the owner of the VM asks it to shut down, so that the
+ * payload can flush its state, e.g. its encrypted storage, and exit cleanly rather than being
+ * killed mid-write. Returning acknowledges that the payload's state is consistent. The host only
+ * waits for a bounded time, after which it stops the VM anyway.
+ *
+ * The callback is called with the `context` parameter on one of the threads which the connection
+ * of libvm_payload to Microdroid Manager dedicates to incoming calls, so the payload doesn't need
+ * to start a binder thread pool.
+ *
+ * \param onShutdownRequested the callback called when the host asks the VM to shut down.
+ * \param context parameter passed to the callback. It must remain valid until the callback is
+ *        replaced.
+ */
+void AVmPayload_setShutdownCallback(void (*_Nullable onShutdownRequested)(void* _Nullable context),
+                                    void* _Nullable context) __INTRODUCED_IN(__ANDROID_API_B__);
+
+/**
+ * Gets the state of the mitigation of a CPU vulnerability by the host, so that the payload can
+ * adapt, e.g. by avoiding secret-dependent branches when it isn't mitigated.
+ *
+ * Only a summary of the state is reported, without details of the mitigation, and the host may
+ * not report some vulnerabilities.
+ *
+ * This function will abort if `vulnerability` is not one of the `AVmCpuVulnerability` values.
+ *
+ * \param vulnerability the `AVmCpuVulnerability` to query.
+ *
+ * \return the state of the mitigation, or CPU_MITIGATION_STATE_UNKNOWN if the host didn't report
+ * it.
+ */
+AVmCpuMitigationState AVmPayload_getCpuMitigationState(int32_t vulnerability)
+        __INTRODUCED_IN(__ANDROID_API_B__);
+
+/**
+ * Gets the set of features which the host enabled for the VM, so that the payload can branch on
+ * them instead of probing for them.
+ *
+ * \return a bitwise-or of AVmCapability values.
+ */
+uint32_t AVmPayload_getCapabilities(void) __INTRODUCED_IN(__ANDROID_API_B__);
+
+/**
+ * Opens the console which the host connected to the VM, e.g. with `vm run --console`, so that a
+ * CLI-style payload can interact with the user without opening a vsock connection.
+ *
+ * The same file descriptor is used to read the input from the host and to write the output to
+ * the host. Opening the console again changes its mode for all the open file descriptors.
+ *
+ * This function will abort if `mode` is not one of the `AVmConsoleMode` values.
+ *
+ * \param mode the `AVmConsoleMode` in which the console processes the input from the host.
+ *
+ * \return a file descriptor for the console, which the caller owns and must close, or -1 if the
+ * VM has no console.
+ */
+int AVmPayload_openConsole(int32_t mode) __INTRODUCED_IN(__ANDROID_API_B__);
+
+/**
+ * Gets the locales of the app owning the VM when the VM was started, so that the payload can
+ * localize the strings it returns to the app, e.g. error messages shown to the user.
+ *
+ * The locales are provided by the host and are not part of the measured configuration of the VM.
+ *
+ * \return a comma-separated list of BCP 47 language tags, most preferred first, e.g.
+ * "fr-CA,en-US", or an empty string if the locales are unknown, or NULL if they couldn't be
+ * obtained from the host. If non-null the returned string should not be deleted or freed by the
+ * application and remains valid for the lifetime of the VM.
+ */
+const char* _Nullable AVmPayload_getHostLocales(void) __INTRODUCED_IN(__ANDROID_API_B__);
+
+/**
+ * Starts the watchdog of the payload, or changes its interval if it is started already, so that
+ * the host kills the VM if the payload hangs. Once started, the watchdog must be kicked with
+ * AVmPayload_kickWatchdog at least once per interval, or the VM is killed and its owner is told
+ * that its payload stopped kicking the watchdog.
+ *
+ * Starting the watchdog counts as a kick. There is no way to stop it.
+ *
+ * \param interval_ms the interval, in milliseconds, of at least 100 ms.
+ */
+void AVmPayload_startWatchdog(int64_t interval_ms) __INTRODUCED_IN(__ANDROID_API_B__);
+
+/**
+ * Postpones the expiry of the watchdog of the payload by its interval. The watchdog must have been
+ * started with AVmPayload_startWatchdog.
+ */
+void AVmPayload_kickWatchdog(void) __INTRODUCED_IN(__ANDROID_API_B__);
+
+/**
+ * Gets the current time from the host, as the clock of the VM can't be relied on, e.g. to check
+ * the validity period of certificates.
+ *
+ * The time is estimated from the time reported by the host and the round trip to the host, so the
+ * actual time is within `uncertainty_ms` of the returned time. The host can't be made to rewind
+ * the time: the process is terminated if the host reports a time before Microdroid was built or,
+ * for protected VMs, going back from what it reported earlier.
+ *
+ * \param uncertainty_ms if not null, where how far the actual time may be from the returned time
+ *        is written, in milliseconds.
+ * \param trust_level if not null, where how far the host can be trusted is written.
+ *
+ * \return the estimated time, in milliseconds since the Unix epoch.
+ */
+int64_t AVmPayload_getCurrentTime(int64_t* _Nullable uncertainty_ms,
+                                  AVmTimeTrustLevel* _Nullable trust_level)
+        __INTRODUCED_IN(__ANDROID_API_B__);
+
+/**
+ * Reads a host system property which the app owning the VM exposed to it when creating it. The
+ * value is provided by the host and is not part of the measured configuration of the VM.
+ *
+ * \param name the name of the property, e.g. "ro.build.version.sdk".
+ * \param value pointer to size bytes where the nul-terminated value is written, truncated to fit.
+ *        May be null if size is 0.
+ * \param size number of bytes which can be written to value.
+ *
+ * \return the length of the value, excluding the nul terminator, or -1 if the property isn't set
+ * or isn't exposed to the VM. The value was truncated if the length is size or more.
+ */
+ssize_t AVmPayload_readHostProperty(const char* _Nonnull name, char* _Nullable value, size_t size)
+        __INTRODUCED_IN(__ANDROID_API_B__);
+
+/**
+ * Extends the encrypted storage to at least the given size, up to the maximum which the app
+ * owning the VM allows. The storage is never shrunk.
+ *
+ * The backing image is extended right away, but the payload only sees the larger storage from the
+ * next boot of the VM, when its filesystem is grown to fill it. A payload running short of space
+ * should therefore ask for more well before the storage is full.
+ *
+ * \param size_bytes the size which the storage needs, in bytes.
+ *
+ * \return the new size of the storage, in bytes, or -1 if the owner of the VM doesn't allow the
+ * storage to grow to that size.
+ */
+int64_t AVmPayload_growEncryptedStorage(uint64_t size_bytes) __INTRODUCED_IN(__ANDROID_API_B__);
+
+/**
+ * Sends a log record to the host, which writes it to its logcat with the given priority and tag,
+ * as logged by the app owning the VM. Unlike the output of the payload on the console, the record
+ * keeps its priority and tag, and doesn't depend on the debug level of the VM.
+ *
+ * The connection to the host is set up on the first call and reused by the next ones. Messages
+ * longer than 4000 bytes and tags longer than 255 bytes are truncated.
+ *
+ * This function will abort if `priority` is not one of the `AVmLogPriority` values.
+ *
+ * \param priority the `AVmLogPriority` of the record.
+ * \param tag the tag of the record, as a nul-terminated string.
+ * \param message the message of the record, as a nul-terminated string.
+ *
+ * \return true if the record was sent, false if the host couldn't be reached.
+ */
+bool AVmPayload_writeLog(int32_t priority, const char* _Nonnull tag, const char* _Nonnull message)
+        __INTRODUCED_IN(__ANDROID_API_B__);
+
+/**
+ * Gets the value of a rollback counter of this VM instance, which is 0 until it is first
+ * incremented with AVmPayload_incrementRollbackCounter.
+ *
+ * The counters are kept in Secretkeeper next to the secret of the VM instance, rather than in the
+ * encrypted storage, and the host can't roll them back. A payload which stores the value of a counter in its encrypted
+ * storage, and increments both whenever it updates the storage, can tell on boot that the storage
+ * was rolled back to an older state if the stored value is behind the counter.
+ *
+ * \param counter the index of the counter, below 8.
+ *
+ * \return the value of the counter, or -1 if the VM has no rollback counters, as it isn't a
+ * protected VM on a device with Secretkeeper. Panics on other failures, e.g. if the index is out
+ * of range.
+ */
+int64_t AVmPayload_readRollbackCounter(uint32_t counter) __INTRODUCED_IN(__ANDROID_API_B__);
+
+/**
+ * Increments a rollback counter of this VM instance, see AVmPayload_readRollbackCounter. Counters
+ * never decrease.
+ *
+ * \param counter the index of the counter, below 8.
+ *
+ * \return the new value of the counter, or -1 if the VM has no rollback counters. Panics on other
+ * failures, e.g. if the index is out of range.
+ */
+int64_t AVmPayload_incrementRollbackCounter(uint32_t counter) __INTRODUCED_IN(__ANDROID_API_B__);
+
+/**
+ * Stops the payload with the given exit code, which the host reports in onPayloadFinished. This
+ * lets a long-running payload, e.g. a service, end with a meaningful code when it decides to stop.
+ *
+ * The exit code is sent to the host before the process exits, so it is reported as is even though
+ * a process exit status only keeps its lowest 8 bits. The process then exits as with exit(3).
+ *
+ * Note that this function does not return.
+ *
+ * \param exit_code the exit code of the payload.
+ */
+__attribute__((noreturn)) void AVmPayload_exit(int32_t exit_code)
+        __INTRODUCED_IN(__ANDROID_API_B__);
+
 /**
  * Requests the remote attestation of the client VM.
  *
@@ -155,6 +536,27 @@ AVmAttestationStatus AVmPayload_requestAttestation(const void* _Nonnull challeng
                                                    AVmAttestationResult* _Nullable* _Nonnull result)
         __INTRODUCED_IN(__ANDROID_API_V__);
 
+/**
+ * Requests the remote attestation of the client VM like `AVmPayload_requestAttestation`, but
+ * returns a copy of the result of an earlier call, if any, unless its leaf certificate is due to
+ * expire within the next hour. The age of the result is measured with the boot time of the VM, so
+ * it doesn't depend on the time reported by the host.
+ *
+ * This saves the remotely provisioned keys of the device when the payload needs an attested key
+ * often, e.g. to sign each message it sends. As the key pair and certificates are reused, a cached
+ * result proves no freshness, so it isn't bound to a challenge: the attestation is made with an
+ * empty one. Payloads which need to prove the freshness of the key to a peer should use
+ * `AVmPayload_requestAttestation` instead.
+ *
+ * \param result The remote attestation result will be filled here if the attestation
+ *               succeeds. The result remains valid until it is freed with
+ *               `AVmAttestationResult_free`, independently of the cached result.
+ *
+ * \return ATTESTATION_OK upon successful attestation.
+ */
+AVmAttestationStatus AVmPayload_requestAttestationCached(
+        AVmAttestationResult* _Nullable* _Nonnull result) __INTRODUCED_IN(__ANDROID_API_B__);
+
 /**
  * Converts the return value from `AVmPayload_requestAttestation` to a text string
  * representing the status code.
@@ -260,4 +662,45 @@ size_t AVmAttestationResult_getCertificateAt(const AVmAttestationResult* _Nonnul
                                              size_t index, void* _Nullable data, size_t size)
         __INTRODUCED_IN(__ANDROID_API_V__);
 
+/**
+ * Gets when the leaf certificate of the attestation result expires, i.e. the end of its validity
+ * period, after which verifiers may reject the attested key.
+ *
+ * \param result A pointer to the attestation result obtained from `AVmPayload_requestAttestation`
+ *               or `AVmPayload_requestAttestationCached` when the attestation succeeds.
+ *
+ * \return The expiry time in milliseconds since the Unix epoch, or -1 if the leaf certificate
+ * can't be parsed.
+ */
+int64_t AVmAttestationResult_getExpiryTime(const AVmAttestationResult* _Nonnull result)
+        __INTRODUCED_IN(__ANDROID_API_B__);
+
+/**
+ * Verifies the attestation of another VM, e.g. whose payload sent the certificate chain of its
+ * `AVmAttestationResult` to this one, so that payloads can authenticate each other.
+ *
+ * The chain is valid if each certificate is signed by the next one, if it ends with the same root
+ * certificate as the attestation of this VM, and if its leaf certificate attests the given
+ * challenge. The validity periods are not checked, as the clock of the VM can't be trusted. This
+ * VM is attested to learn the root certificate if it wasn't already.
+ *
+ * The leaf certificate describes the peer VM, e.g. whether it runs in a secure mode and with which
+ * components, and holds the public key of its attested key pair.
+ *
+ * \param certificates pointers to the DER-encoded X.509 certificates of the chain, starting with
+ *                     the leaf certificate and ending with the root certificate.
+ * \param certificate_sizes sizes of the certificates, in bytes.
+ * \param certificate_count number of certificates of the chain.
+ * \param challenge pointer to the challenge which the peer VM was attested with.
+ * \param challenge_size size of the challenge, between 0 and 64.
+ *
+ * \return ATTESTATION_OK if the attestation of the peer VM is valid,
+ * ATTESTATION_ERROR_VERIFICATION_FAILED if it is not, or another error if this VM couldn't be
+ * attested.
+ */
+AVmAttestationStatus AVmPayload_verifyPeerAttestation(
+        const uint8_t* _Nonnull const* _Nullable certificates,
+        const size_t* _Nullable certificate_sizes, size_t certificate_count,
+        const void* _Nullable challenge, size_t challenge_size) __INTRODUCED_IN(__ANDROID_API_B__);
+
 __END_DECLS
@@ -0,0 +1,360 @@
+/*
+ * Copyright 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Mutually attested key agreement between two VM payloads, on top of the VM Payload API.
+//!
+//! Each payload proves to the other that it runs in a protected VM of the same device: it sends
+//! the certificate chain of its attestation key, issued by the service VM, and signs the handshake
+//! transcript with that key. The peer's chain must end with the same root as our own, and its leaf
+//! certificate must attest the handshake transcript as challenge and a secure VM, i.e. one which
+//! isn't debuggable. The session keys are derived from ephemeral ECDH keys, so they can't be
+//! recovered from the attestation keys.
+//!
+//! Unlike `libvm_payload_rs`, this library links libcrypto, which is available in Microdroid.
+
+use anyhow::{anyhow, ensure, Context, Result};
+use der::{
+    asn1::{AnyRef, ObjectIdentifier},
+    Decode, Sequence,
+};
+use openssl::{
+    bn::BigNumContext,
+    derive::Deriver,
+    ec::{EcGroup, EcKey, EcPoint, PointConversionForm},
+    ecdsa::EcdsaSig,
+    hkdf::hkdf,
+    md::Md,
+    nid::Nid,
+    pkey::{PKey, Private, Public},
+    rand::rand_bytes,
+    sha::{sha256, Sha256},
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+    x509::X509,
+};
+use std::io::{Read, Write};
+use vm_payload::{request_attestation, verify_peer_attestation};
+use x509_cert::Certificate;
+use zeroize::Zeroizing;
+
+const PROTOCOL_LABEL: &[u8] = b"AVF payload secure channel v1";
+const SESSION_KEYS_INFO: &[u8] = b"AVF payload secure channel v1 session keys";
+
+const NONCE_SIZE: usize = 32;
+/// Size of an uncompressed SEC1 P-256 public key.
+const PUBLIC_KEY_SIZE: usize = 65;
+const SESSION_KEY_SIZE: usize = 32;
+const AES_GCM_NONCE_LENGTH: usize = 12;
+const AES_GCM_TAG_LENGTH: usize = 16;
+
+/// Upper bounds on what the peer may send during the handshake.
+const MAX_CERTIFICATE_COUNT: usize = 8;
+const MAX_CERTIFICATE_SIZE: usize = 4096;
+const MAX_SIGNATURE_SIZE: usize = 72;
+
+/// OID value for the protected VM remote attestation extension, as issued by the service VM.
+const AVF_ATTESTATION_EXTENSION_V1: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.3.6.1.4.1.11129.2.1.29.1");
+
+/// Attestation extension contents
+///
+/// ```asn1
+/// AttestationExtension ::= SEQUENCE {
+///     attestationChallenge       OCTET_STRING,
+///     isVmSecure                 BOOLEAN,
+///     vmComponents               SEQUENCE OF VmComponent,
+/// }
+/// ```
+#[derive(Debug, Sequence)]
+struct AttestationExtension<'a> {
+    #[asn1(type = "OCTET STRING")]
+    attestation_challenge: &'a [u8],
+    is_vm_secure: bool,
+    vm_components: AnyRef<'a>,
+}
+
+/// The part taken by a payload in the handshake. The two payloads must take opposite roles, e.g.
+/// the one which connected is the initiator and the one which accepted the connection is the
+/// responder.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// The payload which initiated the connection.
+    Initiator,
+    /// The payload which accepted the connection.
+    Responder,
+}
+
+impl Role {
+    fn peer(self) -> Self {
+        match self {
+            Self::Initiator => Self::Responder,
+            Self::Responder => Self::Initiator,
+        }
+    }
+
+    fn label(self) -> &'static [u8] {
+        match self {
+            Self::Initiator => b"initiator",
+            Self::Responder => b"responder",
+        }
+    }
+}
+
+/// What each payload contributes to the session before being attested.
+struct Contribution {
+    nonce: [u8; NONCE_SIZE],
+    public_key: Vec<u8>,
+}
+
+/// An established secure channel with another payload, sealing and opening its messages with
+/// AES-256-GCM.
+///
+/// The messages must be opened in the order they were sealed by the peer: each one is bound to
+/// its position in the sequence, so that they can't be replayed, reordered or dropped unnoticed.
+pub struct Session {
+    sealing_key: Zeroizing<[u8; SESSION_KEY_SIZE]>,
+    opening_key: Zeroizing<[u8; SESSION_KEY_SIZE]>,
+    sealed_count: u64,
+    opened_count: u64,
+    peer_certificate_chain: Vec<Vec<u8>>,
+}
+
+impl Session {
+    /// Returns the DER-encoded certificate chain of the peer, starting with the leaf certificate.
+    /// The leaf certificate describes the attested payload of the peer, e.g. to check which
+    /// APK it runs.
+    pub fn peer_certificate_chain(&self) -> &[Vec<u8>] {
+        &self.peer_certificate_chain
+    }
+
+    /// Encrypts and authenticates `plaintext` for the peer. Returns the ciphertext followed by the
+    /// authentication tag.
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = message_nonce(self.sealed_count);
+        self.sealed_count = self.sealed_count.checked_add(1).context("Too many sealed messages")?;
+        let mut tag = [0u8; AES_GCM_TAG_LENGTH];
+        let mut ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            self.sealing_key.as_ref(),
+            Some(&nonce),
+            &[],
+            plaintext,
+            &mut tag,
+        )
+        .context("Failed to seal the message")?;
+        ciphertext.extend_from_slice(&tag);
+        Ok(ciphertext)
+    }
+
+    /// Authenticates and decrypts the next message sealed by the peer.
+    pub fn open(&mut self, sealed: &[u8]) -> Result<Vec<u8>> {
+        ensure!(sealed.len() >= AES_GCM_TAG_LENGTH, "Sealed message too short: {}", sealed.len());
+        let (ciphertext, tag) = sealed.split_at(sealed.len() - AES_GCM_TAG_LENGTH);
+        let nonce = message_nonce(self.opened_count);
+        let plaintext = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            self.opening_key.as_ref(),
+            Some(&nonce),
+            &[],
+            ciphertext,
+            tag,
+        )
+        .context("Failed to open the message")?;
+        // Only move on once the message is authentic, so that a forged one can't desynchronize
+        // the session.
+        self.opened_count = self.opened_count.checked_add(1).context("Too many opened messages")?;
+        Ok(plaintext)
+    }
+}
+
+/// Establishes a secure channel with the payload at the other end of `stream`, which must run the
+/// same handshake with the opposite `role`.
+///
+/// This attests the VM with a challenge bound to the handshake, so it takes as long as
+/// [`request_attestation`] and must only be called once the payload is allowed to attest.
+pub fn establish<S: Read + Write>(stream: &mut S, role: Role) -> Result<Session> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let ephemeral_key = EcKey::generate(&group)?;
+    let mut nonce = [0u8; NONCE_SIZE];
+    rand_bytes(&mut nonce)?;
+    let ours = Contribution { nonce, public_key: encode_public_key(&ephemeral_key, &group)? };
+
+    write_message(stream, &ours.nonce)?;
+    write_message(stream, &ours.public_key)?;
+    stream.flush().context("Failed to send our contribution")?;
+    let theirs = Contribution {
+        nonce: read_message(stream, NONCE_SIZE)?
+            .try_into()
+            .map_err(|nonce: Vec<u8>| anyhow!("Invalid peer nonce size: {}", nonce.len()))?,
+        public_key: read_message(stream, PUBLIC_KEY_SIZE)?,
+    };
+    let peer_ephemeral_key = decode_public_key(&theirs.public_key, &group)?;
+
+    let transcript = match role {
+        Role::Initiator => transcript_hash(&ours, &theirs),
+        Role::Responder => transcript_hash(&theirs, &ours),
+    };
+    let attestation = request_attestation(&transcript).context("Failed to attest this VM")?;
+    let certificate_chain: Vec<_> = attestation.certificate_chain().collect();
+    let signature = attestation.sign_message(&signed_message(role, &transcript));
+
+    write_message(stream, &u32::try_from(certificate_chain.len())?.to_be_bytes())?;
+    for certificate in &certificate_chain {
+        write_message(stream, certificate)?;
+    }
+    write_message(stream, &signature)?;
+    stream.flush().context("Failed to send our attestation")?;
+
+    let count = u32::from_be_bytes(
+        read_message(stream, 4)?
+            .try_into()
+            .map_err(|_| anyhow!("Invalid peer certificate count"))?,
+    );
+    let count = usize::try_from(count)?;
+    ensure!(count <= MAX_CERTIFICATE_COUNT, "Too many peer certificates: {count}");
+    let peer_certificate_chain = (0..count)
+        .map(|_| read_message(stream, MAX_CERTIFICATE_SIZE))
+        .collect::<Result<Vec<_>>>()?;
+    let peer_signature = read_message(stream, MAX_SIGNATURE_SIZE)?;
+
+    // The peer must be attested by the same root as this VM, with the handshake as challenge.
+    verify_peer_attestation(&peer_certificate_chain, &transcript)
+        .context("Invalid peer attestation")?;
+    check_peer_attestation_extension(&peer_certificate_chain, &transcript)?;
+    let peer_attestation_key = leaf_public_key(&peer_certificate_chain)?;
+    let peer_signature =
+        EcdsaSig::from_der(&peer_signature).context("Failed to parse the peer signature")?;
+    ensure!(
+        peer_signature
+            .verify(&sha256(&signed_message(role.peer(), &transcript)), &peer_attestation_key)?,
+        "Invalid peer signature of the handshake"
+    );
+
+    let (sealing_key, opening_key) =
+        derive_session_keys(ephemeral_key, peer_ephemeral_key, &transcript, role)?;
+    Ok(Session {
+        sealing_key,
+        opening_key,
+        sealed_count: 0,
+        opened_count: 0,
+        peer_certificate_chain,
+    })
+}
+
+/// Returns the public key of the leaf certificate of a verified chain.
+fn leaf_public_key(chain: &[Vec<u8>]) -> Result<EcKey<Public>> {
+    let leaf = chain.first().context("Empty peer certificate chain")?;
+    let leaf = X509::from_der(leaf).context("Failed to parse the peer leaf certificate")?;
+    leaf.public_key()?.ec_key().context("The peer attestation key isn't an EC key")
+}
+
+/// Checks that the leaf certificate of a verified chain attests a secure VM with the handshake
+/// `transcript` as challenge. A payload in a debuggable VM can't be trusted with the session keys.
+fn check_peer_attestation_extension(chain: &[Vec<u8>], transcript: &[u8]) -> Result<()> {
+    let leaf = chain.first().context("Empty peer certificate chain")?;
+    let leaf = Certificate::from_der(leaf).context("Failed to parse the peer leaf certificate")?;
+    let extension = leaf
+        .tbs_certificate
+        .extensions
+        .iter()
+        .flatten()
+        .find(|extension| extension.extn_id == AVF_ATTESTATION_EXTENSION_V1)
+        .context("The peer leaf certificate has no attestation extension")?;
+    let extension = AttestationExtension::from_der(extension.extn_value.as_bytes())
+        .context("Failed to parse the peer attestation extension")?;
+    ensure!(
+        extension.attestation_challenge == transcript,
+        "The peer wasn't attested with the handshake transcript"
+    );
+    ensure!(extension.is_vm_secure, "The peer VM isn't secure");
+    Ok(())
+}
+
+fn transcript_hash(initiator: &Contribution, responder: &Contribution) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(PROTOCOL_LABEL);
+    for contribution in [initiator, responder] {
+        hasher.update(&contribution.nonce);
+        hasher.update(&contribution.public_key);
+    }
+    hasher.finish()
+}
+
+/// The message signed by the payload taking `role`, which prevents the peer from reflecting the
+/// signature back.
+fn signed_message(role: Role, transcript: &[u8]) -> Vec<u8> {
+    [role.label(), transcript].concat()
+}
+
+/// Returns the keys sealing and opening the messages of the payload taking `role`.
+fn derive_session_keys(
+    ephemeral_key: EcKey<Private>,
+    peer_ephemeral_key: EcKey<Public>,
+    transcript: &[u8],
+    role: Role,
+) -> Result<(Zeroizing<[u8; SESSION_KEY_SIZE]>, Zeroizing<[u8; SESSION_KEY_SIZE]>)> {
+    let ephemeral_key = PKey::from_ec_key(ephemeral_key)?;
+    let mut deriver = Deriver::new(&ephemeral_key)?;
+    deriver.set_peer(&PKey::from_ec_key(peer_ephemeral_key)?)?;
+    let shared_secret = Zeroizing::new(deriver.derive_to_vec()?);
+    let mut keys = Zeroizing::new([0u8; 2 * SESSION_KEY_SIZE]);
+    hkdf(keys.as_mut(), Md::sha256(), &shared_secret, transcript, SESSION_KEYS_INFO)
+        .context("Failed to derive the session keys")?;
+
+    let mut initiator_key = Zeroizing::new([0u8; SESSION_KEY_SIZE]);
+    let mut responder_key = Zeroizing::new([0u8; SESSION_KEY_SIZE]);
+    initiator_key.copy_from_slice(&keys[..SESSION_KEY_SIZE]);
+    responder_key.copy_from_slice(&keys[SESSION_KEY_SIZE..]);
+    Ok(match role {
+        Role::Initiator => (initiator_key, responder_key),
+        Role::Responder => (responder_key, initiator_key),
+    })
+}
+
+fn encode_public_key(key: &EcKey<Private>, group: &EcGroup) -> Result<Vec<u8>> {
+    let mut ctx = BigNumContext::new()?;
+    Ok(key.public_key().to_bytes(group, PointConversionForm::UNCOMPRESSED, &mut ctx)?)
+}
+
+fn decode_public_key(bytes: &[u8], group: &EcGroup) -> Result<EcKey<Public>> {
+    ensure!(bytes.len() == PUBLIC_KEY_SIZE, "Invalid peer public key size: {}", bytes.len());
+    let mut ctx = BigNumContext::new()?;
+    let point = EcPoint::from_bytes(group, bytes, &mut ctx).context("Invalid peer public key")?;
+    let key = EcKey::from_public_key(group, &point)?;
+    key.check_key().context("Invalid peer public key")?;
+    Ok(key)
+}
+
+/// Each direction has its own key, so the nonces only need to be unique within a direction.
+fn message_nonce(count: u64) -> [u8; AES_GCM_NONCE_LENGTH] {
+    let mut nonce = [0u8; AES_GCM_NONCE_LENGTH];
+    nonce[AES_GCM_NONCE_LENGTH - 8..].copy_from_slice(&count.to_be_bytes());
+    nonce
+}
+
+fn write_message<S: Write>(stream: &mut S, message: &[u8]) -> Result<()> {
+    stream.write_all(&u32::try_from(message.len())?.to_be_bytes())?;
+    stream.write_all(message).context("Failed to send a handshake message")
+}
+
+fn read_message<S: Read>(stream: &mut S, max_size: usize) -> Result<Vec<u8>> {
+    let mut size = [0u8; 4];
+    stream.read_exact(&mut size).context("Failed to receive a handshake message")?;
+    let size = usize::try_from(u32::from_be_bytes(size))?;
+    ensure!(size <= max_size, "Handshake message too large: {size} bytes");
+    let mut message = vec![0u8; size];
+    stream.read_exact(&mut message).context("Failed to receive a handshake message")?;
+    Ok(message)
+}
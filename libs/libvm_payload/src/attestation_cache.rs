@@ -0,0 +1,158 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cache of the attestation result of this VM, so that payloads which need an attested key often
+//! don't have the service VM attest them each time, which is slow and uses up the remotely
+//! provisioned keys of the device.
+//!
+//! A cached result proves no freshness, so it isn't bound to a challenge: a single result is
+//! shared by all the callers, and attested with an empty challenge. It is reused until its leaf
+//! certificate is about to expire. As neither the clock of the VM nor the time reported by the
+//! host can be relied on to tell that, the age of the result is measured with the boot time of the
+//! VM, against the validity period of the certificate, which starts when it is issued.
+
+use android_system_virtualization_payload::aidl::android::system::virtualization::payload::IVmPayloadService::AttestationResult::AttestationResult;
+use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::Certificate::Certificate as EncodedCertificate;
+use anyhow::{Context, Result};
+use der::Decode;
+use std::time::Duration;
+use x509_cert::Certificate;
+
+/// How long before its leaf certificate expires the cached result is replaced, so that the results
+/// handed out stay valid for a while after the payload gets them.
+const REFRESH_MARGIN: Duration = Duration::from_secs(60 * 60);
+
+struct CachedResult {
+    result: AttestationResult,
+    /// The boot time of the VM from which the result is replaced.
+    refresh_time: Duration,
+}
+
+/// The attestation result of this VM which is reused.
+#[derive(Default)]
+pub struct AttestationCache {
+    cached: Option<CachedResult>,
+}
+
+impl AttestationCache {
+    /// Returns a copy of the cached result, unless it is due to be replaced at `now`, the boot
+    /// time of the VM.
+    pub fn get(&self, now: Duration) -> Option<AttestationResult> {
+        let cached = self.cached.as_ref()?;
+        (now < cached.refresh_time).then(|| copy_result(&cached.result))
+    }
+
+    /// Caches a copy of `result`, which was attested at `now`, the boot time of the VM, replacing
+    /// any result cached before.
+    pub fn insert(&mut self, result: &AttestationResult, now: Duration) -> Result<()> {
+        let lifetime = validity_period(result)?.saturating_sub(REFRESH_MARGIN);
+        let refresh_time = now.saturating_add(lifetime);
+        self.cached = Some(CachedResult { result: copy_result(result), refresh_time });
+        Ok(())
+    }
+}
+
+fn leaf_certificate(result: &AttestationResult) -> Result<Certificate> {
+    let leaf = result.certificateChain.first().context("Empty certificate chain")?;
+    Certificate::from_der(&leaf.encodedCertificate).context("Failed to parse the leaf certificate")
+}
+
+/// Returns how long the leaf certificate of `result` is valid for, from when it was issued.
+fn validity_period(result: &AttestationResult) -> Result<Duration> {
+    let validity = leaf_certificate(result)?.tbs_certificate.validity;
+    let not_before = validity.not_before.to_unix_duration();
+    let not_after = validity.not_after.to_unix_duration();
+    Ok(not_after.saturating_sub(not_before))
+}
+
+/// Returns when the leaf certificate of `result` expires, in milliseconds since the Unix epoch.
+pub fn expiry_time_ms(result: &AttestationResult) -> Result<i64> {
+    let not_after = leaf_certificate(result)?.tbs_certificate.validity.not_after.to_unix_duration();
+    not_after.as_millis().try_into().context("Invalid expiry time")
+}
+
+fn copy_result(result: &AttestationResult) -> AttestationResult {
+    AttestationResult {
+        privateKey: result.privateKey.clone(),
+        certificateChain: result
+            .certificateChain
+            .iter()
+            .map(|certificate| EncodedCertificate {
+                encodedCertificate: certificate.encodedCertificate.clone(),
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::X509Builder;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// Returns a result whose leaf certificate is valid for `days` from now.
+    fn result(days: u32) -> AttestationResult {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(days).unwrap()).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let leaf = builder.build().to_der().unwrap();
+        AttestationResult {
+            privateKey: key.private_key_to_der().unwrap(),
+            certificateChain: vec![EncodedCertificate { encodedCertificate: leaf }],
+        }
+    }
+
+    #[test]
+    fn reuses_result_until_refresh_margin() -> Result<()> {
+        let mut cache = AttestationCache::default();
+        let attested_at = Duration::from_secs(1000);
+        let result = result(2);
+        cache.insert(&result, attested_at)?;
+
+        let refresh_time = attested_at + 2 * DAY - REFRESH_MARGIN;
+        let cached = cache.get(refresh_time - Duration::from_secs(1)).unwrap();
+        assert_eq!(cached.privateKey, result.privateKey);
+        assert!(cache.get(refresh_time).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn replaces_cached_result() -> Result<()> {
+        let mut cache = AttestationCache::default();
+        assert!(cache.get(Duration::ZERO).is_none());
+        cache.insert(&result(1), Duration::ZERO)?;
+        let newer = result(1);
+        cache.insert(&newer, Duration::ZERO)?;
+        assert_eq!(cache.get(Duration::ZERO).unwrap().privateKey, newer.privateKey);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_result_without_certificate() {
+        let mut cache = AttestationCache::default();
+        let result = AttestationResult { privateKey: vec![], certificateChain: vec![] };
+        assert!(cache.insert(&result, Duration::ZERO).is_err());
+        assert!(cache.get(Duration::ZERO).is_none());
+    }
+}
@@ -14,14 +14,31 @@
 
 //! This module handles the interaction with virtual machine payload service.
 
+mod attestation_cache;
+mod payload_log;
+mod peer_attestation;
+
 use android_system_virtualization_payload::aidl::android::system::virtualization::payload:: IVmPayloadService::{
-    IVmPayloadService, ENCRYPTEDSTORE_MOUNTPOINT, VM_APK_CONTENTS_PATH,
-    VM_PAYLOAD_SERVICE_SOCKET_NAME, AttestationResult::AttestationResult,
+    IVmPayloadService, ENCRYPTEDSTORE_MOUNTPOINT, HOST_CA_CERTIFICATES_PATH, VM_APK_CONTENTS_PATH,
+    VM_PAYLOAD_SERVICE_SOCKET_NAME, AttestationResult::AttestationResult, CurrentTime::CurrentTime,
+    SEALING_POLICY_EXACT_IMAGES, SEALING_POLICY_SAME_AUTHORITY,
+    CPU_VULNERABILITY_MELTDOWN, CPU_VULNERABILITY_SPECTRE_V1, CPU_VULNERABILITY_SPECTRE_V2,
+    CPU_VULNERABILITY_SPEC_STORE_BYPASS, CPU_MITIGATION_STATE_NOT_AFFECTED,
+    CPU_MITIGATION_STATE_MITIGATED, CPU_MITIGATION_STATE_VULNERABLE,
+    CAPABILITY_REMOTE_ATTESTATION, CAPABILITY_ENCRYPTED_STORAGE, CAPABILITY_NETWORK,
+    TIME_TRUST_LEVEL_TRUSTED_HOST,
+};
+use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::IShutdownCallback::{
+    BnShutdownCallback, IShutdownCallback,
+};
+use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::ISnapshotCallback::{
+    BnSnapshotCallback, ISnapshotCallback,
 };
 use anyhow::{bail, ensure, Context, Result};
+use attestation_cache::AttestationCache;
 use binder::{
     unstable_api::{new_spibinder, AIBinder},
-    Strong, ExceptionCode,
+    BinderFeatures, Interface, Strong, ExceptionCode,
 };
 use log::{error, info, LevelFilter};
 use rpcbinder::{RpcServer, RpcSession};
@@ -29,27 +46,56 @@ use openssl::{ec::EcKey, sha::sha256, ecdsa::EcdsaSig};
 use std::convert::Infallible;
 use std::ffi::{CString, CStr};
 use std::fmt::Debug;
-use std::os::raw::{c_char, c_void};
+use std::fs::File;
+use std::os::raw::{c_char, c_int, c_void};
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, IntoRawFd, OwnedFd};
 use std::path::Path;
+use std::process;
 use std::ptr::{self, NonNull};
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     LazyLock,
     Mutex,
+    OnceLock,
+};
+use std::time::Duration;
+use vm_payload_status_bindgen::{
+    AVmAttestationStatus, AVmCapability, AVmConsoleMode, AVmCpuMitigationState,
+    AVmCpuVulnerability, AVmLogPriority, AVmSealingPolicy, AVmTimeTrustLevel,
 };
-use vm_payload_status_bindgen::AVmAttestationStatus;
 
 /// Maximum size of an ECDSA signature for EC P-256 key is 72 bytes.
 const MAX_ECDSA_P256_SIGNATURE_SIZE: usize = 72;
 
+/// Threads serving calls from Microdroid Manager: one for each callback interface, plus one for
+/// Microdroid Manager calling back while handling a call from the payload.
+const CALLBACK_THREADS: usize = 3;
+
+/// Maximum size of the challenge of an attestation.
+const MAX_CHALLENGE_SIZE: usize = 64;
+
 static VM_APK_CONTENTS_PATH_C: LazyLock<CString> =
     LazyLock::new(|| CString::new(VM_APK_CONTENTS_PATH).expect("CString::new failed"));
 static PAYLOAD_CONNECTION: Mutex<Option<Strong<dyn IVmPayloadService>>> = Mutex::new(None);
 static VM_ENCRYPTED_STORAGE_PATH_C: LazyLock<CString> =
     LazyLock::new(|| CString::new(ENCRYPTEDSTORE_MOUNTPOINT).expect("CString::new failed"));
+static VM_HOST_CA_CERTIFICATES_PATH_C: LazyLock<CString> =
+    LazyLock::new(|| CString::new(HOST_CA_CERTIFICATES_PATH).expect("CString::new failed"));
+static HOST_LOCALES_C: OnceLock<CString> = OnceLock::new();
 
 static ALREADY_NOTIFIED: AtomicBool = AtomicBool::new(false);
 
+/// Root certificate of the attestation of this VM, which the attestations of peers must end with.
+static ATTESTATION_ROOT: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+/// Attestation result reused by `AVmPayload_requestAttestationCached`.
+static ATTESTATION_CACHE: LazyLock<Mutex<AttestationCache>> = LazyLock::new(Default::default);
+/// Held while replacing the cached attestation result, so that concurrent requests are served by a
+/// single attestation. The cache itself isn't locked meanwhile, so that it can still be read.
+static ATTESTATION_REFRESH: Mutex<()> = Mutex::new(());
+/// Stream of the log records written by `AVmPayload_writeLog`, opened on first use.
+static PAYLOAD_LOG: Mutex<Option<File>> = Mutex::new(None);
+
 /// Return a connection to the payload service in Microdroid Manager. Uses the existing connection
 /// if there is one, otherwise attempts to create a new one.
 fn get_vm_payload_service() -> Result<Strong<dyn IVmPayloadService>> {
@@ -57,7 +103,12 @@ fn get_vm_payload_service() -> Result<Strong<dyn IVmPayloadService>> {
     if let Some(strong) = &*connection {
         Ok(strong.clone())
     } else {
-        let new_connection: Strong<dyn IVmPayloadService> = RpcSession::new()
+        let session = RpcSession::new();
+        // Microdroid Manager calls back into the payload to notify it of snapshots and shutdown
+        // requests. The payload may take a while to handle them, or register new callbacks while
+        // doing so, so a single incoming thread would leave one notification waiting for another.
+        session.set_max_incoming_threads(CALLBACK_THREADS);
+        let new_connection: Strong<dyn IVmPayloadService> = session
             .setup_unix_domain_client(VM_PAYLOAD_SERVICE_SOCKET_NAME)
             .context(format!("Failed to connect to service: {}", VM_PAYLOAD_SERVICE_SOCKET_NAME))?;
         *connection = Some(new_connection.clone());
@@ -337,7 +388,6 @@ unsafe fn request_attestation(
     res: &mut *mut AttestationResult,
 ) -> AVmAttestationStatus {
     initialize_logging();
-    const MAX_CHALLENGE_SIZE: usize = 64;
     if challenge_size > MAX_CHALLENGE_SIZE {
         return AVmAttestationStatus::ATTESTATION_ERROR_INVALID_CHALLENGE;
     }
@@ -348,15 +398,153 @@ unsafe fn request_attestation(
         // `challenge_size` bytes and `challenge_size` is not zero.
         unsafe { std::slice::from_raw_parts(challenge, challenge_size) }
     };
-    let service = unwrap_or_abort(get_vm_payload_service());
-    match service.requestAttestation(challenge, test_mode) {
+    match attest(challenge, test_mode) {
+        Ok(attestation_res) => {
+            *res = Box::into_raw(Box::new(attestation_res));
+            AVmAttestationStatus::ATTESTATION_OK
+        }
+        Err(status) => status,
+    }
+}
+
+/// Requests the remote attestation of the client VM, reusing the result of an earlier request
+/// unless its leaf certificate is about to expire.
+#[no_mangle]
+pub extern "C" fn AVmPayload_requestAttestationCached(
+    res: &mut *mut AttestationResult,
+) -> AVmAttestationStatus {
+    initialize_logging();
+    match attest_cached() {
         Ok(attestation_res) => {
             *res = Box::into_raw(Box::new(attestation_res));
             AVmAttestationStatus::ATTESTATION_OK
         }
+        Err(status) => status,
+    }
+}
+
+fn attest_cached() -> Result<AttestationResult, AVmAttestationStatus> {
+    if let Some(cached) = ATTESTATION_CACHE.lock().unwrap().get(boot_time()) {
+        return Ok(cached);
+    }
+    let _refresh = ATTESTATION_REFRESH.lock().unwrap();
+    // Another request may have replaced the result while this one waited.
+    if let Some(cached) = ATTESTATION_CACHE.lock().unwrap().get(boot_time()) {
+        return Ok(cached);
+    }
+    let attested_at = boot_time();
+    let attestation_res = attest(&[], false)?;
+    if let Err(e) = ATTESTATION_CACHE.lock().unwrap().insert(&attestation_res, attested_at) {
+        error!("Failed to cache the attestation result: {e:?}");
+    }
+    Ok(attestation_res)
+}
+
+/// Returns the time since the VM booted, including the time it was suspended.
+fn boot_time() -> Duration {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // SAFETY: `ts` is valid for writes, and the kernel doesn't retain the pointer.
+    let ret = unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts) };
+    assert_eq!(ret, 0, "CLOCK_BOOTTIME is always available");
+    Duration::new(ts.tv_sec.try_into().unwrap(), ts.tv_nsec.try_into().unwrap())
+}
+
+/// Has the service VM attest this VM with `challenge`.
+fn attest(challenge: &[u8], test_mode: bool) -> Result<AttestationResult, AVmAttestationStatus> {
+    let service = unwrap_or_abort(get_vm_payload_service());
+    let attestation_res = service.requestAttestation(challenge, test_mode).map_err(|e| {
+        error!("Remote attestation failed: {e:?}");
+        binder_status_to_attestation_status(e)
+    })?;
+    // The root of test attestations isn't trusted.
+    if !test_mode {
+        remember_attestation_root(&attestation_res);
+    }
+    Ok(attestation_res)
+}
+
+fn remember_attestation_root(attestation_res: &AttestationResult) {
+    if let Some(root) = attestation_res.certificateChain.last() {
+        *ATTESTATION_ROOT.lock().unwrap() = Some(root.encodedCertificate.clone());
+    }
+}
+
+/// Returns the root certificate of the attestation of this VM, attesting it if it wasn't already.
+fn attestation_root() -> Result<Vec<u8>, AVmAttestationStatus> {
+    if let Some(root) = &*ATTESTATION_ROOT.lock().unwrap() {
+        return Ok(root.clone());
+    }
+    attest(&[], false)?;
+    ATTESTATION_ROOT
+        .lock()
+        .unwrap()
+        .clone()
+        .ok_or(AVmAttestationStatus::ATTESTATION_ERROR_ATTESTATION_FAILED)
+}
+
+/// Verifies the attestation of a peer VM from its certificate chain, which must end with the same
+/// root certificate as the attestation of this VM and attest `challenge`.
+///
+/// # Safety
+///
+/// Behavior is undefined if any of the following conditions are violated:
+///
+/// * `certificates` and `certificate_sizes` must be [valid] for reads of `certificate_count`
+///   elements, if `certificate_count` > 0.
+/// * Each certificate must be [valid] for reads of the number of bytes given by its size.
+/// * `challenge` must be [valid] for reads of `challenge_size` bytes, if `challenge_size` > 0.
+///
+/// [valid]: ptr#safety
+#[no_mangle]
+pub unsafe extern "C" fn AVmPayload_verifyPeerAttestation(
+    certificates: *const *const u8,
+    certificate_sizes: *const usize,
+    certificate_count: usize,
+    challenge: *const u8,
+    challenge_size: usize,
+) -> AVmAttestationStatus {
+    initialize_logging();
+    if challenge_size > MAX_CHALLENGE_SIZE {
+        return AVmAttestationStatus::ATTESTATION_ERROR_INVALID_CHALLENGE;
+    }
+    let challenge = if challenge_size == 0 {
+        &[]
+    } else {
+        // SAFETY: The caller guarantees that `challenge` is valid for reads of
+        // `challenge_size` bytes and `challenge_size` is not zero.
+        unsafe { std::slice::from_raw_parts(challenge, challenge_size) }
+    };
+    let chain: Vec<&[u8]> = if certificate_count == 0 {
+        Vec::new()
+    } else {
+        // SAFETY: The caller guarantees that both arrays are valid for reads of
+        // `certificate_count` elements, which is not zero, and that each certificate is valid for
+        // reads of its size.
+        unsafe {
+            let certificates = std::slice::from_raw_parts(certificates, certificate_count);
+            let sizes = std::slice::from_raw_parts(certificate_sizes, certificate_count);
+            certificates
+                .iter()
+                .zip(sizes)
+                .map(|(&certificate, &size)| {
+                    if size == 0 {
+                        &[][..]
+                    } else {
+                        std::slice::from_raw_parts(certificate, size)
+                    }
+                })
+                .collect()
+        }
+    };
+    let trusted_root = match attestation_root() {
+        Ok(root) => root,
+        Err(status) => return status,
+    };
+    match peer_attestation::verify_peer_attestation(&chain, challenge, &trusted_root) {
+        Ok(()) => AVmAttestationStatus::ATTESTATION_OK,
         Err(e) => {
-            error!("Remote attestation failed: {e:?}");
-            binder_status_to_attestation_status(e)
+            error!("Invalid peer attestation: {e:?}");
+            AVmAttestationStatus::ATTESTATION_ERROR_VERIFICATION_FAILED
         }
     }
 }
@@ -387,6 +575,9 @@ pub extern "C" fn AVmAttestationStatus_toString(status: AVmAttestationStatus) ->
             b"Remote attestation is not supported in the current environment.\0",
         )
         .unwrap(),
+        AVmAttestationStatus::ATTESTATION_ERROR_VERIFICATION_FAILED => {
+            CStr::from_bytes_with_nul(b"The attestation of the peer VM is invalid.\0").unwrap()
+        }
     };
     message.as_ptr()
 }
@@ -535,6 +726,18 @@ pub unsafe extern "C" fn AVmAttestationResult_getCertificateAt(
     certificate.len()
 }
 
+/// Gets when the leaf certificate of the attestation result expires, in milliseconds since the
+/// Unix epoch, or -1 if it can't be determined.
+#[no_mangle]
+pub extern "C" fn AVmAttestationResult_getExpiryTime(res: &AttestationResult) -> i64 {
+    initialize_logging();
+
+    attestation_cache::expiry_time_ms(res).unwrap_or_else(|e| {
+        error!("Failed to get the expiry time of the attestation result: {e:?}");
+        -1
+    })
+}
+
 /// Frees all the data owned by given attestation result and result itself.
 ///
 /// # Safety
@@ -566,3 +769,630 @@ pub extern "C" fn AVmPayload_getEncryptedStoragePath() -> *const c_char {
         ptr::null()
     }
 }
+
+/// Requests a host file chosen by the user. Returns a file descriptor for reading the content of the
+/// file, or -1 if no file was chosen.
+/// Panics on failure.
+///
+/// # Safety
+///
+/// Behavior is undefined if `mime_type` is not a valid nul-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn AVmPayload_requestHostFile(mime_type: *const c_char) -> c_int {
+    initialize_logging();
+
+    // SAFETY: See the requirements on `mime_type` above.
+    let mime_type = unsafe { CStr::from_ptr(mime_type) };
+    match unwrap_or_abort(try_request_host_file(mime_type)) {
+        Some(fd) => fd.into_raw_fd(),
+        None => -1,
+    }
+}
+
+fn try_request_host_file(mime_type: &CStr) -> Result<Option<OwnedFd>> {
+    let mime_type = mime_type.to_str().context("MIME type is not valid UTF-8")?;
+    let file =
+        get_vm_payload_service()?.requestHostFile(mime_type).context("Cannot request host file")?;
+    Ok(file.map(OwnedFd::from))
+}
+
+/// Requests a key derived by the service VM and bound to the measurements of this VM selected by
+/// `policy`. Returns false if the key could not be derived. Panics on other failures.
+///
+/// # Safety
+///
+/// Behavior is undefined if `key` is not [valid] for writes of `size` bytes.
+///
+/// [valid]: ptr#safety
+#[no_mangle]
+pub unsafe extern "C" fn AVmPayload_requestSealedKey(
+    policy: i32,
+    key: *mut u8,
+    size: usize,
+) -> bool {
+    initialize_logging();
+
+    let sealed_key = match unwrap_or_abort(try_request_sealed_key(policy, size)) {
+        Some(sealed_key) => sealed_key,
+        None => return false,
+    };
+    // SAFETY: See the requirements on `key` above; `sealed_key` is known to have at least `size`
+    // bytes, and cannot overlap `key` because we just allocated it.
+    unsafe {
+        ptr::copy_nonoverlapping(sealed_key.as_ptr(), key, size);
+    }
+    true
+}
+
+fn try_request_sealed_key(policy: i32, size: usize) -> Result<Option<Vec<u8>>> {
+    ensure!((1..=32).contains(&size), "Sealed keys can be up to 32 bytes long, not {size}");
+    // The policy comes from C as a plain integer, so it may not be a valid `AVmSealingPolicy`.
+    const EXACT_IMAGES: i32 = AVmSealingPolicy::SEALING_POLICY_EXACT_IMAGES as i32;
+    const SAME_AUTHORITY: i32 = AVmSealingPolicy::SEALING_POLICY_SAME_AUTHORITY as i32;
+    let policy = match policy {
+        EXACT_IMAGES => SEALING_POLICY_EXACT_IMAGES,
+        SAME_AUTHORITY => SEALING_POLICY_SAME_AUTHORITY,
+        _ => bail!("Unknown sealing policy {policy}"),
+    };
+    let sealed_key = match get_vm_payload_service()?.requestSealedKey(policy) {
+        Ok(sealed_key) => sealed_key,
+        Err(e) => {
+            error!("Failed to request a sealed key: {e:?}");
+            return Ok(None);
+        }
+    };
+    ensure!(sealed_key.len() >= size, "Returned sealed key has only {} bytes", sealed_key.len());
+    Ok(Some(sealed_key))
+}
+
+/// The snapshot callbacks of the payload, and the context they are called with.
+struct SnapshotCallbacks {
+    on_pre_snapshot: Option<unsafe extern "C" fn(context: *mut c_void)>,
+    on_post_restore: Option<unsafe extern "C" fn(context: *mut c_void)>,
+    context: *mut c_void,
+}
+
+// SAFETY: The caller of `AVmPayload_setSnapshotCallbacks` guarantees that the callbacks can be
+// called with the context from any thread.
+unsafe impl Send for SnapshotCallbacks {}
+// SAFETY: As above.
+unsafe impl Sync for SnapshotCallbacks {}
+
+impl Interface for SnapshotCallbacks {}
+
+impl ISnapshotCallback for SnapshotCallbacks {
+    fn onPreSnapshot(&self) -> binder::Result<()> {
+        if let Some(on_pre_snapshot) = self.on_pre_snapshot {
+            // SAFETY: See the requirements of `AVmPayload_setSnapshotCallbacks`.
+            unsafe { on_pre_snapshot(self.context) };
+        }
+        Ok(())
+    }
+
+    fn onPostRestore(&self) -> binder::Result<()> {
+        if let Some(on_post_restore) = self.on_post_restore {
+            // SAFETY: See the requirements of `AVmPayload_setSnapshotCallbacks`.
+            unsafe { on_post_restore(self.context) };
+        }
+        Ok(())
+    }
+}
+
+/// Sets the callbacks notified around host-initiated snapshots of the VM, replacing the previous
+/// ones. Panics on failure.
+///
+/// # Safety
+///
+/// If present, the callbacks must be valid function pointers, which can be called from any thread
+/// with the `context` parameter until they are replaced.
+#[no_mangle]
+pub unsafe extern "C" fn AVmPayload_setSnapshotCallbacks(
+    on_pre_snapshot: Option<unsafe extern "C" fn(context: *mut c_void)>,
+    on_post_restore: Option<unsafe extern "C" fn(context: *mut c_void)>,
+    context: *mut c_void,
+) {
+    initialize_logging();
+
+    let callbacks = (on_pre_snapshot.is_some() || on_post_restore.is_some()).then(|| {
+        BnSnapshotCallback::new_binder(
+            SnapshotCallbacks { on_pre_snapshot, on_post_restore, context },
+            BinderFeatures::default(),
+        )
+    });
+    unwrap_or_abort(try_set_snapshot_callbacks(callbacks.as_ref()))
+}
+
+fn try_set_snapshot_callbacks(callbacks: Option<&Strong<dyn ISnapshotCallback>>) -> Result<()> {
+    get_vm_payload_service()?
+        .setSnapshotCallback(callbacks)
+        .context("Cannot set snapshot callbacks")
+}
+
+/// The shutdown callback of the payload, and the context it is called with.
+struct ShutdownCallback {
+    on_shutdown_requested: unsafe extern "C" fn(context: *mut c_void),
+    context: *mut c_void,
+}
+
+// SAFETY: The caller of `AVmPayload_setShutdownCallback` guarantees that the callback can be
+// called with the context from any thread.
+unsafe impl Send for ShutdownCallback {}
+// SAFETY: As above.
+unsafe impl Sync for ShutdownCallback {}
+
+impl Interface for ShutdownCallback {}
+
+impl IShutdownCallback for ShutdownCallback {
+    fn onShutdownRequested(&self) -> binder::Result<()> {
+        // SAFETY: See the requirements of `AVmPayload_setShutdownCallback`.
+        unsafe { (self.on_shutdown_requested)(self.context) };
+        Ok(())
+    }
+}
+
+/// Sets the callback notified when the host shuts the VM down gracefully, replacing the previous
+/// one. Panics on failure.
+///
+/// # Safety
+///
+/// If present, the callback must be a valid function pointer, which can be called from any thread
+/// with the `context` parameter until it is replaced.
+#[no_mangle]
+pub unsafe extern "C" fn AVmPayload_setShutdownCallback(
+    on_shutdown_requested: Option<unsafe extern "C" fn(context: *mut c_void)>,
+    context: *mut c_void,
+) {
+    initialize_logging();
+
+    let callback = on_shutdown_requested.map(|on_shutdown_requested| {
+        BnShutdownCallback::new_binder(
+            ShutdownCallback { on_shutdown_requested, context },
+            BinderFeatures::default(),
+        )
+    });
+    unwrap_or_abort(try_set_shutdown_callback(callback.as_ref()))
+}
+
+fn try_set_shutdown_callback(callback: Option<&Strong<dyn IShutdownCallback>>) -> Result<()> {
+    get_vm_payload_service()?.setShutdownCallback(callback).context("Cannot set shutdown callback")
+}
+
+/// Gets the state of the mitigation of a CPU vulnerability by the host. Panics on failure.
+#[no_mangle]
+pub extern "C" fn AVmPayload_getCpuMitigationState(vulnerability: i32) -> AVmCpuMitigationState {
+    initialize_logging();
+
+    unwrap_or_abort(try_get_cpu_mitigation_state(vulnerability))
+}
+
+fn try_get_cpu_mitigation_state(vulnerability: i32) -> Result<AVmCpuMitigationState> {
+    // The vulnerability comes from C as a plain integer, so it may not be a valid
+    // `AVmCpuVulnerability`.
+    const MELTDOWN: i32 = AVmCpuVulnerability::CPU_VULNERABILITY_MELTDOWN as i32;
+    const SPECTRE_V1: i32 = AVmCpuVulnerability::CPU_VULNERABILITY_SPECTRE_V1 as i32;
+    const SPECTRE_V2: i32 = AVmCpuVulnerability::CPU_VULNERABILITY_SPECTRE_V2 as i32;
+    const SPEC_STORE_BYPASS: i32 = AVmCpuVulnerability::CPU_VULNERABILITY_SPEC_STORE_BYPASS as i32;
+    let vulnerability = match vulnerability {
+        MELTDOWN => CPU_VULNERABILITY_MELTDOWN,
+        SPECTRE_V1 => CPU_VULNERABILITY_SPECTRE_V1,
+        SPECTRE_V2 => CPU_VULNERABILITY_SPECTRE_V2,
+        SPEC_STORE_BYPASS => CPU_VULNERABILITY_SPEC_STORE_BYPASS,
+        _ => bail!("Unknown CPU vulnerability {vulnerability}"),
+    };
+    let state = get_vm_payload_service()?
+        .getCpuMitigationState(vulnerability)
+        .context("Cannot get CPU mitigation state")?;
+    Ok(match state {
+        CPU_MITIGATION_STATE_NOT_AFFECTED => {
+            AVmCpuMitigationState::CPU_MITIGATION_STATE_NOT_AFFECTED
+        }
+        CPU_MITIGATION_STATE_MITIGATED => AVmCpuMitigationState::CPU_MITIGATION_STATE_MITIGATED,
+        CPU_MITIGATION_STATE_VULNERABLE => AVmCpuMitigationState::CPU_MITIGATION_STATE_VULNERABLE,
+        _ => AVmCpuMitigationState::CPU_MITIGATION_STATE_UNKNOWN,
+    })
+}
+
+/// Gets the set of features which the host enabled for the VM. Panics on failure.
+#[no_mangle]
+pub extern "C" fn AVmPayload_getCapabilities() -> u32 {
+    initialize_logging();
+
+    unwrap_or_abort(try_get_capabilities())
+}
+
+fn try_get_capabilities() -> Result<u32> {
+    const CAPABILITIES: [(i32, AVmCapability); 3] = [
+        (CAPABILITY_REMOTE_ATTESTATION, AVmCapability::VM_CAPABILITY_REMOTE_ATTESTATION),
+        (CAPABILITY_ENCRYPTED_STORAGE, AVmCapability::VM_CAPABILITY_ENCRYPTED_STORAGE),
+        (CAPABILITY_NETWORK, AVmCapability::VM_CAPABILITY_NETWORK),
+    ];
+    let capabilities =
+        get_vm_payload_service()?.getCapabilities().context("Cannot get capabilities")?;
+    Ok(CAPABILITIES
+        .iter()
+        .filter(|(bit, _)| capabilities & bit != 0)
+        .fold(0, |result, (_, capability)| result | *capability as u32))
+}
+
+/// Opens the console which the host connected to the VM in the given mode. Returns -1 if the VM
+/// has no console. Panics on failure.
+#[no_mangle]
+pub extern "C" fn AVmPayload_openConsole(mode: i32) -> c_int {
+    initialize_logging();
+
+    match unwrap_or_abort(try_open_console(mode)) {
+        Some(fd) => fd.into_raw_fd(),
+        None => -1,
+    }
+}
+
+fn try_open_console(mode: i32) -> Result<Option<OwnedFd>> {
+    // The mode comes from C as a plain integer, so it may not be a valid `AVmConsoleMode`.
+    const LINE: i32 = AVmConsoleMode::CONSOLE_MODE_LINE as i32;
+    const RAW: i32 = AVmConsoleMode::CONSOLE_MODE_RAW as i32;
+    let mode = match mode {
+        LINE => AVmConsoleMode::CONSOLE_MODE_LINE,
+        RAW => AVmConsoleMode::CONSOLE_MODE_RAW,
+        _ => bail!("Unknown console mode {mode}"),
+    };
+    let Some(console) = get_vm_payload_service()?.openConsole().context("Cannot open console")?
+    else {
+        return Ok(None);
+    };
+    let console = OwnedFd::from(console);
+    set_console_mode(&console, mode)?;
+    Ok(Some(console))
+}
+
+fn set_console_mode(console: &OwnedFd, mode: AVmConsoleMode) -> Result<()> {
+    // SAFETY: termios is a plain C struct, for which all zeroes is a valid value.
+    let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+    // SAFETY: The file descriptor is valid and `termios` is valid for writes.
+    if unsafe { libc::tcgetattr(console.as_raw_fd(), &mut termios) } != 0 {
+        return Err(io::Error::last_os_error()).context("Failed to get the console attributes");
+    }
+    match mode {
+        AVmConsoleMode::CONSOLE_MODE_LINE => {
+            termios.c_iflag |= libc::ICRNL;
+            termios.c_oflag |= libc::OPOST | libc::ONLCR;
+            termios.c_lflag |= libc::ICANON | libc::ECHO | libc::ECHOE;
+        }
+        AVmConsoleMode::CONSOLE_MODE_RAW => {
+            // SAFETY: `termios` is valid for reads and writes.
+            unsafe { libc::cfmakeraw(&mut termios) };
+        }
+    }
+    // SAFETY: The file descriptor is valid and `termios` is valid for reads.
+    if unsafe { libc::tcsetattr(console.as_raw_fd(), libc::TCSANOW, &termios) } != 0 {
+        return Err(io::Error::last_os_error()).context("Failed to set the console attributes");
+    }
+    Ok(())
+}
+
+/// Gets the path to the copy of the host's CA certificates.
+#[no_mangle]
+pub extern "C" fn AVmPayload_getHostCaCertificatesPath() -> *const c_char {
+    if Path::new(HOST_CA_CERTIFICATES_PATH).exists() {
+        VM_HOST_CA_CERTIFICATES_PATH_C.as_ptr()
+    } else {
+        ptr::null()
+    }
+}
+
+/// Gets the locales of the app owning the VM, as a comma-separated list. Returns null if they
+/// can't be obtained from the host.
+#[no_mangle]
+pub extern "C" fn AVmPayload_getHostLocales() -> *const c_char {
+    initialize_logging();
+
+    // Failures aren't remembered, so that the payload can try again.
+    if let Some(locales) = HOST_LOCALES_C.get() {
+        return locales.as_ptr();
+    }
+    match try_get_host_locales() {
+        Ok(locales) => HOST_LOCALES_C.get_or_init(|| locales).as_ptr(),
+        Err(e) => {
+            error!("Cannot get host locales: {e:?}");
+            ptr::null()
+        }
+    }
+}
+
+fn try_get_host_locales() -> Result<CString> {
+    let locales = get_vm_payload_service()?.getHostLocales().context("Cannot get host locales")?;
+    join_language_tags(&locales)
+}
+
+/// Joins the plausible language tags of `locales` with commas. The host is untrusted, so anything
+/// else is dropped.
+fn join_language_tags(locales: &[String]) -> Result<CString> {
+    let tags: Vec<_> = locales
+        .iter()
+        .map(String::as_str)
+        .filter(|tag| {
+            !tag.is_empty() && tag.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+        .collect();
+    Ok(CString::new(tags.join(","))?)
+}
+
+/// Starts the watchdog of the payload, or changes its interval. Panics on failure.
+#[no_mangle]
+pub extern "C" fn AVmPayload_startWatchdog(interval_ms: i64) {
+    initialize_logging();
+
+    unwrap_or_abort(try_start_watchdog(interval_ms));
+    info!("Started the payload watchdog, every {interval_ms} ms");
+}
+
+fn try_start_watchdog(interval_ms: i64) -> Result<()> {
+    get_vm_payload_service()?.startWatchdog(interval_ms).context("Cannot start watchdog")
+}
+
+/// Kicks the watchdog of the payload. Panics on failure.
+#[no_mangle]
+pub extern "C" fn AVmPayload_kickWatchdog() {
+    initialize_logging();
+
+    unwrap_or_abort(try_kick_watchdog())
+}
+
+fn try_kick_watchdog() -> Result<()> {
+    get_vm_payload_service()?.kickWatchdog().context("Cannot kick watchdog")
+}
+
+/// Gets the current time from the host, in milliseconds since the Unix epoch, along with its
+/// uncertainty and trust level. Panics on failure.
+///
+/// # Safety
+///
+/// Behavior is undefined if `uncertainty_ms` or `trust_level` is neither null nor [valid] for
+/// writes.
+///
+/// [valid]: ptr#safety
+#[no_mangle]
+pub unsafe extern "C" fn AVmPayload_getCurrentTime(
+    uncertainty_ms: *mut i64,
+    trust_level: *mut AVmTimeTrustLevel,
+) -> i64 {
+    initialize_logging();
+
+    let current_time = unwrap_or_abort(try_get_current_time());
+    if !uncertainty_ms.is_null() {
+        // SAFETY: See the requirements on `uncertainty_ms` above.
+        unsafe { *uncertainty_ms = current_time.uncertaintyMillis };
+    }
+    if !trust_level.is_null() {
+        // Anything but the trusted host is treated as untrusted, to fail safe.
+        let level = if current_time.trustLevel == TIME_TRUST_LEVEL_TRUSTED_HOST {
+            AVmTimeTrustLevel::TIME_TRUST_LEVEL_TRUSTED_HOST
+        } else {
+            AVmTimeTrustLevel::TIME_TRUST_LEVEL_UNTRUSTED_HOST
+        };
+        // SAFETY: See the requirements on `trust_level` above.
+        unsafe { *trust_level = level };
+    }
+    current_time.epochMillis
+}
+
+fn try_get_current_time() -> Result<CurrentTime> {
+    get_vm_payload_service()?.getCurrentTime().context("Cannot get current time")
+}
+
+/// Reads a host system property exposed to the VM into `value`, truncated to `size` bytes
+/// including the nul terminator. Returns the length of the value, or -1 if the property isn't set
+/// or isn't exposed to the VM. Panics on other failures.
+///
+/// # Safety
+///
+/// Behavior is undefined if `name` is not a valid nul-terminated C string, or if `value` is not
+/// [valid] for writes of `size` bytes.
+///
+/// [valid]: ptr#safety
+#[no_mangle]
+pub unsafe extern "C" fn AVmPayload_readHostProperty(
+    name: *const c_char,
+    value: *mut c_char,
+    size: usize,
+) -> isize {
+    initialize_logging();
+
+    // SAFETY: See the requirements on `name` above.
+    let name = unsafe { CStr::from_ptr(name) };
+    let Some(property) = unwrap_or_abort(try_read_host_property(name)) else {
+        return -1;
+    };
+    let property = property.as_bytes();
+    if size > 0 {
+        let copied = property.len().min(size - 1);
+        // SAFETY: See the requirements on `value` above; `copied + 1` is at most `size`, and
+        // `property` cannot overlap `value` because we just allocated it.
+        unsafe {
+            ptr::copy_nonoverlapping(property.as_ptr(), value as *mut u8, copied);
+            *value.add(copied) = 0;
+        }
+    }
+    property.len().try_into().unwrap_or(isize::MAX)
+}
+
+fn try_read_host_property(name: &CStr) -> Result<Option<String>> {
+    let name = name.to_str().context("Host property name is not valid UTF-8")?;
+    match get_vm_payload_service()?.readHostProperty(name) {
+        Ok(value) => Ok(value),
+        Err(e) if e.exception_code() == ExceptionCode::SECURITY => {
+            error!("Host property {name:?} isn't exposed to the VM");
+            Ok(None)
+        }
+        Err(e) => Err(e).context("Cannot read host property"),
+    }
+}
+
+/// Extends the encrypted storage to at least `size_bytes`, and returns its new size, or -1 if the
+/// owner of the VM doesn't allow the storage to grow that large. Panics on other failures.
+#[no_mangle]
+pub extern "C" fn AVmPayload_growEncryptedStorage(size_bytes: u64) -> i64 {
+    initialize_logging();
+
+    let Some(size) = unwrap_or_abort(try_grow_encrypted_storage(size_bytes)) else {
+        return -1;
+    };
+    info!("Grew the encrypted storage to {size} bytes, from the next boot");
+    size
+}
+
+fn try_grow_encrypted_storage(size_bytes: u64) -> Result<Option<i64>> {
+    let size_bytes = i64::try_from(size_bytes).context("Storage size is too large")?;
+    match get_vm_payload_service()?.growEncryptedStorage(size_bytes) {
+        Ok(size) => Ok(Some(size)),
+        Err(e)
+            if e.exception_code() == ExceptionCode::UNSUPPORTED_OPERATION
+                || e.exception_code() == ExceptionCode::ILLEGAL_ARGUMENT =>
+        {
+            error!("Cannot grow the encrypted storage to {size_bytes} bytes: {e:?}");
+            Ok(None)
+        }
+        Err(e) => Err(e).context("Cannot grow encrypted storage"),
+    }
+}
+
+/// Gets the value of a rollback counter of this VM instance, or -1 if the VM has no rollback
+/// counters. Panics on other failures.
+#[no_mangle]
+pub extern "C" fn AVmPayload_readRollbackCounter(counter: u32) -> i64 {
+    initialize_logging();
+
+    unwrap_or_abort(try_rollback_counter(counter, false)).unwrap_or(-1)
+}
+
+/// Increments a rollback counter of this VM instance and returns its new value, or -1 if the VM
+/// has no rollback counters. Panics on other failures.
+#[no_mangle]
+pub extern "C" fn AVmPayload_incrementRollbackCounter(counter: u32) -> i64 {
+    initialize_logging();
+
+    let value = unwrap_or_abort(try_rollback_counter(counter, true));
+    if let Some(value) = value {
+        info!("Incremented rollback counter {counter} to {value}");
+    }
+    value.unwrap_or(-1)
+}
+
+fn try_rollback_counter(counter: u32, increment: bool) -> Result<Option<i64>> {
+    let counter = i32::try_from(counter).context("Rollback counter index is too large")?;
+    let service = get_vm_payload_service()?;
+    let result = if increment {
+        service.incrementRollbackCounter(counter)
+    } else {
+        service.readRollbackCounter(counter)
+    };
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.exception_code() == ExceptionCode::UNSUPPORTED_OPERATION => {
+            error!("Rollback counters aren't supported: {e:?}");
+            Ok(None)
+        }
+        Err(e) => Err(e).context("Cannot access rollback counter"),
+    }
+}
+
+/// Tells the host the exit code of the payload, then exits the process with it. The host still
+/// gets the exit status of the process if it can't be told.
+#[no_mangle]
+pub extern "C" fn AVmPayload_exit(exit_code: i32) -> ! {
+    initialize_logging();
+
+    if let Err(e) = try_request_exit(exit_code) {
+        error!("Failed to send the exit code to the host: {e:?}");
+    }
+    info!("Exiting with code {exit_code}");
+    process::exit(exit_code)
+}
+
+fn try_request_exit(exit_code: i32) -> Result<()> {
+    get_vm_payload_service()?.requestExit(exit_code).context("Cannot request exit")
+}
+
+/// Sends a log record to the host, which writes it to logcat with the given priority and tag on
+/// behalf of the owner of the VM. Returns false if the record couldn't be sent. Panics if the
+/// priority is unknown.
+///
+/// # Safety
+///
+/// Behavior is undefined if `tag` or `message` is not a valid nul-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn AVmPayload_writeLog(
+    priority: i32,
+    tag: *const c_char,
+    message: *const c_char,
+) -> bool {
+    initialize_logging();
+
+    let priority = unwrap_or_abort(log_priority(priority));
+    // SAFETY: See the requirements on `tag` and `message` above.
+    let (tag, message) = unsafe { (CStr::from_ptr(tag), CStr::from_ptr(message)) };
+    let frame = payload_log::encode_record(priority, tag.to_bytes(), message.to_bytes());
+
+    let mut payload_log = PAYLOAD_LOG.lock().unwrap();
+    match try_write_log(&mut payload_log, &frame) {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Failed to write to the payload log: {e:?}");
+            // Reconnect on the next record, in case the host only dropped this connection.
+            *payload_log = None;
+            false
+        }
+    }
+}
+
+fn log_priority(priority: i32) -> Result<u8> {
+    // The priority comes from C as a plain integer, so it may not be a valid `AVmLogPriority`.
+    const VERBOSE: i32 = AVmLogPriority::LOG_PRIORITY_VERBOSE as i32;
+    const FATAL: i32 = AVmLogPriority::LOG_PRIORITY_FATAL as i32;
+    match priority {
+        // The AVmLogPriority values are contiguous and equal to those of android_LogPriority.
+        VERBOSE..=FATAL => Ok(priority as u8),
+        _ => bail!("Unknown log priority {priority}"),
+    }
+}
+
+fn try_write_log(payload_log: &mut Option<File>, frame: &[u8]) -> Result<()> {
+    let file = match payload_log {
+        Some(file) => file,
+        None => {
+            let fd =
+                get_vm_payload_service()?.openPayloadLog().context("Cannot open payload log")?;
+            payload_log.insert(File::from(OwnedFd::from(fd)))
+        }
+    };
+    file.write_all(frame).context("Cannot send log record")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn join(locales: &[&str]) -> CString {
+        let locales: Vec<_> = locales.iter().map(|tag| tag.to_string()).collect();
+        join_language_tags(&locales).unwrap()
+    }
+
+    #[test]
+    fn host_locales_are_joined() {
+        assert_eq!(join(&["fr-CA", "en-US"]).as_bytes(), b"fr-CA,en-US");
+        assert_eq!(join(&[]).as_bytes(), b"");
+    }
+
+    #[test]
+    fn invalid_host_locales_are_dropped() {
+        assert_eq!(join(&["", "../../etc", "fr,de", "en\0", "ja"]).as_bytes(), b"ja");
+    }
+
+    #[test]
+    fn log_priorities_are_checked() {
+        assert_eq!(log_priority(AVmLogPriority::LOG_PRIORITY_VERBOSE as i32).unwrap(), 2);
+        assert_eq!(log_priority(AVmLogPriority::LOG_PRIORITY_FATAL as i32).unwrap(), 7);
+        assert!(log_priority(1).is_err());
+        assert!(log_priority(8).is_err());
+        assert!(log_priority(-1).is_err());
+    }
+}
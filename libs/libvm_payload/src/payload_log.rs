@@ -0,0 +1,61 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Framing of the log records which the payload sends to the host, to be written to logcat. See
+//! IVirtualMachineService.openPayloadLog for the format.
+
+// SYNC WITH android/virtmgr/src/payload_log.rs
+const MAX_TAG_LEN: usize = u8::MAX as usize;
+const MAX_MESSAGE_LEN: usize = 4000;
+
+/// Returns the frame of a log record, truncating the tag and message if they are too long.
+pub fn encode_record(priority: u8, tag: &[u8], message: &[u8]) -> Vec<u8> {
+    let tag = &tag[..tag.len().min(MAX_TAG_LEN)];
+    let message = &message[..message.len().min(MAX_MESSAGE_LEN)];
+    let mut frame = Vec::with_capacity(4 + tag.len() + message.len());
+    frame.push(priority);
+    frame.push(tag.len() as u8);
+    frame.extend_from_slice(&(message.len() as u16).to_le_bytes());
+    frame.extend_from_slice(tag);
+    frame.extend_from_slice(message);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_record() {
+        let frame = encode_record(6, b"tag", b"message");
+        assert_eq!(frame, b"\x06\x03\x07\x00tagmessage");
+    }
+
+    #[test]
+    fn encodes_empty_record() {
+        assert_eq!(encode_record(4, b"", b""), [4, 0, 0, 0]);
+    }
+
+    #[test]
+    fn truncates_long_tag_and_message() {
+        let tag = [b't'; MAX_TAG_LEN + 1];
+        let message = [b'm'; MAX_MESSAGE_LEN + 1];
+        let frame = encode_record(4, &tag, &message);
+        assert_eq!(frame[1], u8::MAX);
+        assert_eq!(u16::from_le_bytes([frame[2], frame[3]]), MAX_MESSAGE_LEN as u16);
+        assert_eq!(frame.len(), 4 + MAX_TAG_LEN + MAX_MESSAGE_LEN);
+        assert!(frame[4..4 + MAX_TAG_LEN].iter().all(|b| *b == b't'));
+        assert!(frame[4 + MAX_TAG_LEN..].iter().all(|b| *b == b'm'));
+    }
+}
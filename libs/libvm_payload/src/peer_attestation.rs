@@ -0,0 +1,210 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Verification of the attestation results of other VMs, e.g. exchanged between payloads.
+
+use anyhow::{ensure, Context, Result};
+use der::{
+    asn1::{AnyRef, ObjectIdentifier},
+    Decode, Sequence,
+};
+use openssl::x509::{X509VerifyResult, X509};
+use x509_cert::Certificate;
+
+/// OID value for the protected VM remote attestation extension, as issued by the service VM.
+const AVF_ATTESTATION_EXTENSION_V1: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.3.6.1.4.1.11129.2.1.29.1");
+
+/// Attestation extension contents
+///
+/// ```asn1
+/// AttestationExtension ::= SEQUENCE {
+///     attestationChallenge       OCTET_STRING,
+///     isVmSecure                 BOOLEAN,
+///     vmComponents               SEQUENCE OF VmComponent,
+/// }
+/// ```
+#[derive(Debug, Sequence)]
+struct AttestationExtension<'a> {
+    #[asn1(type = "OCTET STRING")]
+    attestation_challenge: &'a [u8],
+    is_vm_secure: bool,
+    vm_components: AnyRef<'a>,
+}
+
+/// Checks that each certificate of `chain`, starting with the leaf, is issued by the next one,
+/// that the last one is `trusted_root` and that the leaf certificate attests `challenge`.
+///
+/// The validity periods aren't checked, as the clock of the VM can't be trusted.
+pub fn verify_peer_attestation(
+    chain: &[&[u8]],
+    challenge: &[u8],
+    trusted_root: &[u8],
+) -> Result<()> {
+    let root = chain.last().context("Empty certificate chain")?;
+    ensure!(*root == trusted_root, "The peer isn't attested by the same root as this VM");
+    let certificates = chain
+        .iter()
+        .map(|der| X509::from_der(der))
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to parse the certificate chain")?;
+    for (i, certificate) in certificates.iter().enumerate() {
+        // The root is self-signed.
+        let issuer = certificates.get(i + 1).unwrap_or(certificate);
+        ensure!(
+            issuer.issued(certificate) == X509VerifyResult::OK
+                && certificate.verify(&issuer.public_key()?)?,
+            "Certificate {i} of the chain isn't issued by the next one"
+        );
+    }
+
+    let leaf = Certificate::from_der(chain[0]).context("Failed to parse the leaf certificate")?;
+    let extension = leaf
+        .tbs_certificate
+        .extensions
+        .iter()
+        .flatten()
+        .find(|extension| extension.extn_id == AVF_ATTESTATION_EXTENSION_V1)
+        .context("The leaf certificate has no attestation extension")?;
+    let extension = AttestationExtension::from_der(extension.extn_value.as_bytes())
+        .context("Failed to parse the attestation extension")?;
+    ensure!(
+        extension.attestation_challenge == challenge,
+        "The peer wasn't attested with the expected challenge"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use der::{Encode, Tag};
+    use openssl::asn1::{Asn1Object, Asn1OctetString, Asn1Time};
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::{X509Builder, X509Extension, X509NameBuilder};
+
+    const CHALLENGE: &[u8] = b"challenge";
+
+    fn key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn attestation_extension(challenge: &[u8]) -> X509Extension {
+        let extension = AttestationExtension {
+            attestation_challenge: challenge,
+            is_vm_secure: true,
+            vm_components: AnyRef::new(Tag::Sequence, &[]).unwrap(),
+        };
+        let oid = Asn1Object::from_str(&AVF_ATTESTATION_EXTENSION_V1.to_string()).unwrap();
+        let value = Asn1OctetString::new_from_bytes(&extension.to_der().unwrap()).unwrap();
+        X509Extension::new_from_der(&oid, false, &value).unwrap()
+    }
+
+    /// Returns the certificate of `key` named `subject`, issued by `issuer_key` named `issuer`.
+    fn certificate(
+        subject: &str,
+        key: &PKey<Private>,
+        issuer: &str,
+        issuer_key: &PKey<Private>,
+        extension: Option<X509Extension>,
+    ) -> Vec<u8> {
+        let name = |common_name| {
+            let mut name = X509NameBuilder::new().unwrap();
+            name.append_entry_by_nid(Nid::COMMONNAME, common_name).unwrap();
+            name.build()
+        };
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        builder.set_serial_number(&serial).unwrap();
+        builder.set_subject_name(&name(subject)).unwrap();
+        builder.set_issuer_name(&name(issuer)).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        if let Some(extension) = extension {
+            builder.append_extension(extension).unwrap();
+        }
+        builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+        builder.build().to_der().unwrap()
+    }
+
+    /// Returns a chain of a leaf attesting `challenge`, if any, an intermediate and a root
+    /// certificate.
+    fn chain_attesting(challenge: Option<&[u8]>) -> [Vec<u8>; 3] {
+        let (root_key, intermediate_key, leaf_key) = (key(), key(), key());
+        let extension = challenge.map(attestation_extension);
+        [
+            certificate("leaf", &leaf_key, "intermediate", &intermediate_key, extension),
+            certificate("intermediate", &intermediate_key, "root", &root_key, None),
+            certificate("root", &root_key, "root", &root_key, None),
+        ]
+    }
+
+    fn chain(challenge: &[u8]) -> [Vec<u8>; 3] {
+        chain_attesting(Some(challenge))
+    }
+
+    fn verify(chain: &[Vec<u8>], challenge: &[u8], trusted_root: &[u8]) -> Result<()> {
+        let chain: Vec<_> = chain.iter().map(Vec::as_slice).collect();
+        verify_peer_attestation(&chain, challenge, trusted_root)
+    }
+
+    #[test]
+    fn accepts_attested_peer() {
+        let chain = chain(CHALLENGE);
+        verify(&chain, CHALLENGE, &chain[2]).unwrap();
+    }
+
+    #[test]
+    fn rejects_other_challenge() {
+        let chain = chain(b"other challenge");
+        assert!(verify(&chain, CHALLENGE, &chain[2]).is_err());
+    }
+
+    #[test]
+    fn rejects_other_root() {
+        let chain = chain(CHALLENGE);
+        let other_root = key();
+        let other_root = certificate("root", &other_root, "root", &other_root, None);
+        assert!(verify(&chain, CHALLENGE, &other_root).is_err());
+    }
+
+    #[test]
+    fn rejects_broken_chain() {
+        let [_, intermediate, root] = chain(CHALLENGE);
+        // Same names, but not signed by the key of the intermediate certificate.
+        let extension = Some(attestation_extension(CHALLENGE));
+        let leaf = certificate("leaf", &key(), "intermediate", &key(), extension);
+        let chain = [leaf, intermediate, root];
+        assert!(verify(&chain, CHALLENGE, &chain[2]).is_err());
+    }
+
+    #[test]
+    fn rejects_leaf_without_attestation_extension() {
+        let chain = chain_attesting(None);
+        assert!(verify(&chain, CHALLENGE, &chain[2]).is_err());
+    }
+
+    #[test]
+    fn rejects_empty_chain() {
+        let chain = chain(CHALLENGE);
+        assert!(verify(&[], CHALLENGE, &chain[2]).is_err());
+    }
+}
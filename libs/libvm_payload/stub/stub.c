@@ -13,3 +13,24 @@ void AVmAttestationResult_free() {}
 void AVmAttestationStatus_toString() {}
 void AVmAttestationResult_getCertificateCount() {}
 void AVmAttestationResult_getCertificateAt() {}
+void AVmPayload_getHostCaCertificatesPath() {}
+void AVmPayload_requestHostFile() {}
+void AVmPayload_requestSealedKey() {}
+void AVmPayload_setSnapshotCallbacks() {}
+void AVmPayload_getCpuMitigationState() {}
+void AVmPayload_getCapabilities() {}
+void AVmPayload_openConsole() {}
+void AVmPayload_verifyPeerAttestation() {}
+void AVmPayload_getHostLocales() {}
+void AVmPayload_startWatchdog() {}
+void AVmPayload_kickWatchdog() {}
+void AVmPayload_setShutdownCallback() {}
+void AVmPayload_getCurrentTime() {}
+void AVmPayload_readHostProperty() {}
+void AVmPayload_growEncryptedStorage() {}
+void AVmPayload_requestAttestationCached() {}
+void AVmAttestationResult_getExpiryTime() {}
+void AVmPayload_writeLog() {}
+void AVmPayload_readRollbackCounter() {}
+void AVmPayload_incrementRollbackCounter() {}
+void AVmPayload_exit() {}
@@ -19,12 +19,15 @@ use std::ffi::{c_void, CStr};
 use std::fmt::{self, Display};
 use std::iter::FusedIterator;
 use std::ptr::{self, NonNull};
+use std::time::{Duration, SystemTime};
 
 use vm_payload_bindgen::{
     AVmAttestationResult, AVmAttestationResult_free, AVmAttestationResult_getCertificateAt,
-    AVmAttestationResult_getCertificateCount, AVmAttestationResult_getPrivateKey,
-    AVmAttestationResult_sign, AVmAttestationStatus, AVmAttestationStatus_toString,
-    AVmPayload_requestAttestation, AVmPayload_requestAttestationForTesting,
+    AVmAttestationResult_getCertificateCount, AVmAttestationResult_getExpiryTime,
+    AVmAttestationResult_getPrivateKey, AVmAttestationResult_sign, AVmAttestationStatus,
+    AVmAttestationStatus_toString, AVmPayload_requestAttestation,
+    AVmPayload_requestAttestationCached, AVmPayload_requestAttestationForTesting,
+    AVmPayload_verifyPeerAttestation,
 };
 
 /// Holds the result of a successful Virtual Machine attestation request.
@@ -44,6 +47,8 @@ pub enum AttestationError {
     AttestationFailed,
     /// VM attestation is not supported in the current environment.
     AttestationUnsupported,
+    /// The attestation of the peer VM is invalid. See [`verify_peer_attestation`].
+    VerificationFailed,
 }
 
 impl Error for AttestationError {}
@@ -54,6 +59,7 @@ impl Display for AttestationError {
             Self::InvalidChallenge => AVmAttestationStatus::ATTESTATION_ERROR_INVALID_CHALLENGE,
             Self::AttestationFailed => AVmAttestationStatus::ATTESTATION_ERROR_ATTESTATION_FAILED,
             Self::AttestationUnsupported => AVmAttestationStatus::ATTESTATION_ERROR_UNSUPPORTED,
+            Self::VerificationFailed => AVmAttestationStatus::ATTESTATION_ERROR_VERIFICATION_FAILED,
         };
         // SAFETY: AVmAttestationStatus_toString always returns a non-null pointer to a
         // nul-terminated C string with static lifetime (which is valid UTF-8).
@@ -103,6 +109,20 @@ pub fn request_attestation(challenge: &[u8]) -> Result<AttestationResult, Attest
     AttestationResult::new(status, result)
 }
 
+/// Requests the remote attestation of this VM like [`request_attestation`], but returns a copy of
+/// the result of an earlier request, if any, unless its leaf certificate is due to expire within
+/// the next hour.
+///
+/// This saves the remotely provisioned keys of the device when the payload needs an attested key
+/// often. As the key pair and certificates are reused, a cached result proves no freshness, so it
+/// isn't bound to a challenge.
+pub fn request_attestation_cached() -> Result<AttestationResult, AttestationError> {
+    let mut result: *mut AVmAttestationResult = ptr::null_mut();
+    // SAFETY: The function only writes the result, and doesn't retain any reference to it.
+    let status = unsafe { AVmPayload_requestAttestationCached(&mut result) };
+    AttestationResult::new(status, result)
+}
+
 /// A variant of [`request_attestation`] used for testing purposes. This should not be used by
 /// normal VMs, and is not available to app owned VMs.
 pub fn request_attestation_for_testing(
@@ -121,27 +141,62 @@ pub fn request_attestation_for_testing(
     AttestationResult::new(status, result)
 }
 
+/// Verifies the attestation of another VM, e.g. whose payload sent the
+/// [certificate chain](AttestationResult::certificate_chain) of its [`AttestationResult`] to this
+/// one, so that payloads can authenticate each other.
+///
+/// The chain is valid if each certificate is signed by the next one, if it ends with the same root
+/// certificate as the attestation of this VM, and if its leaf certificate attests `challenge`. This
+/// VM is attested to learn the root certificate if it wasn't already.
+///
+/// The leaf certificate describes the peer VM and holds the public key of its attested key pair.
+pub fn verify_peer_attestation(
+    certificate_chain: &[Vec<u8>],
+    challenge: &[u8],
+) -> Result<(), AttestationError> {
+    let certificates: Vec<_> = certificate_chain.iter().map(|cert| cert.as_ptr()).collect();
+    let sizes: Vec<_> = certificate_chain.iter().map(Vec::len).collect();
+    // SAFETY: We only read the certificates and the challenge within their bounds and the function
+    // does not retain any reference to them.
+    let status = unsafe {
+        AVmPayload_verifyPeerAttestation(
+            certificates.as_ptr(),
+            sizes.as_ptr(),
+            certificate_chain.len(),
+            challenge.as_ptr() as *const c_void,
+            challenge.len(),
+        )
+    };
+    check_status(status)
+}
+
+fn check_status(status: AVmAttestationStatus) -> Result<(), AttestationError> {
+    match status {
+        AVmAttestationStatus::ATTESTATION_OK => Ok(()),
+        AVmAttestationStatus::ATTESTATION_ERROR_INVALID_CHALLENGE => {
+            Err(AttestationError::InvalidChallenge)
+        }
+        AVmAttestationStatus::ATTESTATION_ERROR_ATTESTATION_FAILED => {
+            Err(AttestationError::AttestationFailed)
+        }
+        AVmAttestationStatus::ATTESTATION_ERROR_UNSUPPORTED => {
+            Err(AttestationError::AttestationUnsupported)
+        }
+        AVmAttestationStatus::ATTESTATION_ERROR_VERIFICATION_FAILED => {
+            Err(AttestationError::VerificationFailed)
+        }
+    }
+}
+
 impl AttestationResult {
     fn new(
         status: AVmAttestationStatus,
         result: *mut AVmAttestationResult,
     ) -> Result<AttestationResult, AttestationError> {
-        match status {
-            AVmAttestationStatus::ATTESTATION_ERROR_INVALID_CHALLENGE => {
-                Err(AttestationError::InvalidChallenge)
-            }
-            AVmAttestationStatus::ATTESTATION_ERROR_ATTESTATION_FAILED => {
-                Err(AttestationError::AttestationFailed)
-            }
-            AVmAttestationStatus::ATTESTATION_ERROR_UNSUPPORTED => {
-                Err(AttestationError::AttestationUnsupported)
-            }
-            AVmAttestationStatus::ATTESTATION_OK => {
-                let result = NonNull::new(result)
-                    .expect("Attestation succeeded but the attestation result is null");
-                Ok(AttestationResult { result })
-            }
-        }
+        check_status(status)?;
+        let result =
+            NonNull::new(result).expect("Attestation succeeded but the attestation result is null");
+        Ok(AttestationResult { result })
     }
 
     fn as_const_ptr(&self) -> *const AVmAttestationResult {
@@ -231,6 +286,15 @@ impl AttestationResult {
         CertIterator { result: self, count, current: 0 }
     }
 
+    /// Returns when the leaf certificate of the [certificate chain](Self::certificate_chain)
+    /// expires, after which verifiers may reject the attested key, or `None` if it can't be parsed.
+    pub fn expiry_time(&self) -> Option<SystemTime> {
+        // SAFETY: We own the `AVmAttestationResult` pointer, so it is valid.
+        let expiry_time_ms = unsafe { AVmAttestationResult_getExpiryTime(self.as_const_ptr()) };
+        let expiry_time_ms = u64::try_from(expiry_time_ms).ok()?;
+        SystemTime::UNIX_EPOCH.checked_add(Duration::from_millis(expiry_time_ms))
+    }
+
     fn certificate(&self, index: usize) -> Vec<u8> {
         let ptr = self.as_const_ptr();
 
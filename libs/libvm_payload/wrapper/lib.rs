@@ -21,17 +21,41 @@
 //! for more information on the VM Payload API.
 
 mod attestation;
+mod locale;
+mod logger;
+mod sandbox;
+mod time;
+pub mod watchdog;
 
-pub use attestation::{request_attestation, AttestationError, AttestationResult};
+pub use attestation::{
+    request_attestation, request_attestation_cached, verify_peer_attestation, AttestationError,
+    AttestationResult,
+};
 use binder::unstable_api::AsNative;
 use binder::{FromIBinder, Strong};
-use std::ffi::{c_void, CStr, OsStr};
+pub use locale::{host_locales, StringTable};
+pub use logger::{log_writer, LogWriter};
+pub use sandbox::{sandbox_info, MountInfo, SandboxInfo};
+use std::ffi::{c_char, c_void, CStr, OsStr};
+use std::fs::File;
+use std::io;
+use std::ops::BitOr;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{FromRawFd, OwnedFd};
 use std::path::Path;
 use std::ptr;
+use std::sync::{Arc, Mutex};
+pub use time::{current_time, CurrentTime, TimeTrustLevel};
 use vm_payload_bindgen::{
-    AIBinder, AVmPayload_getApkContentsPath, AVmPayload_getEncryptedStoragePath,
-    AVmPayload_getVmInstanceSecret, AVmPayload_notifyPayloadReady, AVmPayload_runVsockRpcServer,
+    AIBinder, AVmCapability, AVmConsoleMode, AVmCpuMitigationState, AVmCpuVulnerability,
+    AVmPayload_exit, AVmPayload_getApkContentsPath, AVmPayload_getCapabilities,
+    AVmPayload_getCpuMitigationState, AVmPayload_getEncryptedStoragePath,
+    AVmPayload_getHostCaCertificatesPath, AVmPayload_getVmInstanceSecret,
+    AVmPayload_growEncryptedStorage, AVmPayload_incrementRollbackCounter,
+    AVmPayload_notifyPayloadReady, AVmPayload_openConsole, AVmPayload_readHostProperty,
+    AVmPayload_readRollbackCounter, AVmPayload_requestHostFile, AVmPayload_requestSealedKey,
+    AVmPayload_runVsockRpcServer, AVmPayload_setShutdownCallback, AVmPayload_setSnapshotCallbacks,
+    AVmSealingPolicy,
 };
 
 /// The functions declared here are restricted to VMs created with a config file;
@@ -159,6 +183,357 @@ pub fn encrypted_storage_path() -> Option<&'static Path> {
     }
 }
 
+/// Gets the path to a read-only copy of the host's system CA certificates, refreshed every time
+/// the VM starts. The certificates are host-provided and not part of the measured configuration of
+/// the VM.
+///
+/// Returns `None` if sharing of the host CA certificates was not requested in the VM
+/// configuration.
+pub fn host_ca_certificates_path() -> Option<&'static Path> {
+    // SAFETY: AVmPayload_getHostCaCertificatesPath returns either null or a pointer to a
+    // nul-terminated C string with static lifetime.
+    let ptr = unsafe { AVmPayload_getHostCaCertificatesPath() };
+    if ptr.is_null() {
+        None
+    } else {
+        // SAFETY: We know the pointer is not null, and so it is a valid C string.
+        let c_str = unsafe { CStr::from_ptr(ptr) };
+        Some(Path::new(OsStr::from_bytes(c_str.to_bytes())))
+    }
+}
+
+/// Requests a host file which the user picks through the app owning the VM, e.g. with the system
+/// file picker. Blocks until the user has made a choice.
+///
+/// Only the content of the chosen file is made available to the VM. Returns a file descriptor for
+/// reading it as a stream, or `None` if no file was chosen.
+pub fn request_host_file(mime_type: &CStr) -> Option<OwnedFd> {
+    // SAFETY: The function only reads from `mime_type`, which is a valid C string, and doesn't
+    // retain it.
+    let fd = unsafe { AVmPayload_requestHostFile(mime_type.as_ptr()) };
+    if fd < 0 {
+        None
+    } else {
+        // SAFETY: The function returns a new file descriptor owned by the caller.
+        Some(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+}
+
+/// Reads a host system property which the app owning the VM exposed to it when creating it, e.g.
+/// `ro.build.version.sdk`. The value is provided by the host and is not part of the measured
+/// configuration of the VM.
+///
+/// Returns `None` if the property isn't set or isn't exposed to the VM.
+pub fn read_host_property(name: &CStr) -> Option<String> {
+    let mut value: Vec<u8> = vec![];
+    loop {
+        // SAFETY: The function only reads from `name`, which is a valid C string, and only writes
+        // to `[value]` within its bounds. Neither reference is retained.
+        let length = unsafe {
+            AVmPayload_readHostProperty(
+                name.as_ptr(),
+                value.as_mut_ptr() as *mut c_char,
+                value.len(),
+            )
+        };
+        let length = usize::try_from(length).ok()?;
+        if length < value.len() {
+            value.truncate(length);
+            return String::from_utf8(value).ok();
+        }
+        // The value didn't fit, e.g. because it changed since it was last read.
+        value.resize(length + 1, 0);
+    }
+}
+
+/// Extends the encrypted storage to at least `size` bytes, up to the maximum which the owner of
+/// the VM allows, and returns its new size. The larger storage is only visible from the next boot
+/// of the VM. Returns `None` if the owner doesn't allow the storage to grow to `size`.
+pub fn grow_encrypted_storage(size: u64) -> Option<u64> {
+    // SAFETY: Invokes a method from the bindgen library `vm_payload_bindgen` which is safe to
+    // call at any time.
+    let new_size = unsafe { AVmPayload_growEncryptedStorage(size) };
+    u64::try_from(new_size).ok()
+}
+
+/// Gets the value of rollback counter `counter` of this VM instance, which is 0 until it is first
+/// incremented with [`increment_rollback_counter`]. There are 8 counters, and `counter` must be
+/// below 8.
+///
+/// The counters are kept in Secretkeeper next to the secret of the VM instance, rather than in the
+/// encrypted storage, and the host can't roll them back. Storing the value of a counter in the encrypted storage, and
+/// incrementing both on each update, lets the payload tell on boot that its storage was rolled
+/// back to an older state.
+///
+/// Returns `None` if the VM has no rollback counters, as it isn't a protected VM on a device with
+/// Secretkeeper.
+pub fn read_rollback_counter(counter: u32) -> Option<u64> {
+    // SAFETY: Invokes a method from the bindgen library `vm_payload_bindgen` which is safe to
+    // call at any time.
+    let value = unsafe { AVmPayload_readRollbackCounter(counter) };
+    u64::try_from(value).ok()
+}
+
+/// Increments rollback counter `counter` of this VM instance, see [`read_rollback_counter`], and
+/// returns its new value. Counters never decrease.
+///
+/// Returns `None` if the VM has no rollback counters.
+pub fn increment_rollback_counter(counter: u32) -> Option<u64> {
+    // SAFETY: Invokes a method from the bindgen library `vm_payload_bindgen` which is safe to
+    // call at any time.
+    let value = unsafe { AVmPayload_incrementRollbackCounter(counter) };
+    u64::try_from(value).ok()
+}
+
+/// Stops the payload with exit code `code`, which the host reports in `onPayloadFinished`, e.g. when
+/// a long-running payload decides to stop.
+///
+/// The code is sent to the host before the process exits, so it is reported as is even though a
+/// process exit status only keeps its lowest 8 bits.
+pub fn exit_vm(code: i32) -> ! {
+    // SAFETY: Invokes a method from the bindgen library `vm_payload_bindgen` which is safe to
+    // call at any time.
+    unsafe { AVmPayload_exit(code) }
+}
+
+/// The measurements of the VM which a key returned by [`request_sealed_key`] is bound to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SealingPolicy {
+    /// The key changes whenever the kernel, vendor partition, APK or APEXes are updated.
+    ExactImages,
+    /// The key stays the same across updates signed with the same keys.
+    SameAuthority,
+}
+
+/// Requests a 32-byte key derived by the service VM from its own secrets and the measurements of
+/// this VM selected by `policy`.
+///
+/// Unlike the VM instance secret, the key is the same for every instance of the same payload on
+/// the device, and it survives the deletion of the VM instance. It is never revealed to the host.
+///
+/// Returns `None` if the key could not be derived, e.g. because the service VM is not supported
+/// on this device.
+pub fn request_sealed_key(policy: SealingPolicy) -> Option<[u8; 32]> {
+    let policy = match policy {
+        SealingPolicy::ExactImages => AVmSealingPolicy::SEALING_POLICY_EXACT_IMAGES,
+        SealingPolicy::SameAuthority => AVmSealingPolicy::SEALING_POLICY_SAME_AUTHORITY,
+    } as i32;
+    let mut key = [0u8; 32];
+    // SAFETY: The function only writes to `[key]` within its bounds, and doesn't retain it.
+    let success =
+        unsafe { AVmPayload_requestSealedKey(policy, key.as_mut_ptr() as *mut c_void, key.len()) };
+    success.then_some(key)
+}
+
+/// A CPU vulnerability whose mitigation state the host reports to the VM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuVulnerability {
+    /// Rogue data cache load (CVE-2017-5754).
+    Meltdown,
+    /// Bounds check bypass (CVE-2017-5753).
+    SpectreV1,
+    /// Branch target injection (CVE-2017-5715).
+    SpectreV2,
+    /// Speculative store bypass (CVE-2018-3639).
+    SpecStoreBypass,
+}
+
+impl CpuVulnerability {
+    /// All the vulnerabilities which the host can report.
+    pub const ALL: [Self; 4] =
+        [Self::Meltdown, Self::SpectreV1, Self::SpectreV2, Self::SpecStoreBypass];
+}
+
+/// The state of the mitigation of a CPU vulnerability by the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuMitigationState {
+    /// The host didn't report the state of the vulnerability.
+    Unknown,
+    /// The CPU isn't affected by the vulnerability.
+    NotAffected,
+    /// The CPU is affected by the vulnerability, but the host mitigates it.
+    Mitigated,
+    /// The CPU is affected by the vulnerability, and the host doesn't mitigate it.
+    Vulnerable,
+}
+
+/// Gets the state of the mitigation of a CPU vulnerability by the host, so that the payload can
+/// adapt, e.g. by avoiding secret-dependent branches when it isn't mitigated.
+pub fn cpu_mitigation_state(vulnerability: CpuVulnerability) -> CpuMitigationState {
+    let vulnerability = match vulnerability {
+        CpuVulnerability::Meltdown => AVmCpuVulnerability::CPU_VULNERABILITY_MELTDOWN,
+        CpuVulnerability::SpectreV1 => AVmCpuVulnerability::CPU_VULNERABILITY_SPECTRE_V1,
+        CpuVulnerability::SpectreV2 => AVmCpuVulnerability::CPU_VULNERABILITY_SPECTRE_V2,
+        CpuVulnerability::SpecStoreBypass => {
+            AVmCpuVulnerability::CPU_VULNERABILITY_SPEC_STORE_BYPASS
+        }
+    } as i32;
+    // SAFETY: The function has no preconditions.
+    match unsafe { AVmPayload_getCpuMitigationState(vulnerability) } {
+        AVmCpuMitigationState::CPU_MITIGATION_STATE_UNKNOWN => CpuMitigationState::Unknown,
+        AVmCpuMitigationState::CPU_MITIGATION_STATE_NOT_AFFECTED => CpuMitigationState::NotAffected,
+        AVmCpuMitigationState::CPU_MITIGATION_STATE_MITIGATED => CpuMitigationState::Mitigated,
+        AVmCpuMitigationState::CPU_MITIGATION_STATE_VULNERABLE => CpuMitigationState::Vulnerable,
+    }
+}
+
+/// Gets the state of the mitigation by the host of each CPU vulnerability it can report.
+///
+/// Only a summary of the state is reported, without details of the mitigation, and the host may
+/// not report some vulnerabilities, whose state is then [`CpuMitigationState::Unknown`].
+pub fn cpu_mitigations() -> Vec<(CpuVulnerability, CpuMitigationState)> {
+    CpuVulnerability::ALL
+        .iter()
+        .map(|&vulnerability| (vulnerability, cpu_mitigation_state(vulnerability)))
+        .collect()
+}
+
+/// A set of features which the host enabled for the VM.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// The payload can request remote attestation, see [`request_attestation`].
+    pub const REMOTE_ATTESTATION: Self =
+        Self(AVmCapability::VM_CAPABILITY_REMOTE_ATTESTATION as u32);
+    /// The VM has encrypted storage, see [`encrypted_storage_path`].
+    pub const ENCRYPTED_STORAGE: Self = Self(AVmCapability::VM_CAPABILITY_ENCRYPTED_STORAGE as u32);
+    /// The VM has a network interface.
+    pub const NETWORK: Self = Self(AVmCapability::VM_CAPABILITY_NETWORK as u32);
+
+    /// Returns whether all the capabilities of `other` are in this set.
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// Gets the features which the host enabled for the VM, so that the payload can branch on them
+/// instead of probing for them.
+pub fn capabilities() -> Capabilities {
+    // SAFETY: The function has no preconditions.
+    Capabilities(unsafe { AVmPayload_getCapabilities() })
+}
+
+/// How the console processes the input from the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConsoleMode {
+    /// Input is echoed back and delivered a line at a time, as expected by a shell prompt.
+    Line,
+    /// Input is delivered byte by byte, without echo or any other processing.
+    Raw,
+}
+
+/// The console which the host connected to the VM, e.g. with `vm run --console`.
+#[derive(Debug)]
+pub struct Console {
+    /// Reads the input from the host.
+    pub input: File,
+    /// Writes the output to the host.
+    pub output: File,
+}
+
+/// Opens the console which the host connected to the VM, so that a CLI-style payload can interact
+/// with the user without opening a vsock connection. Returns `None` if the VM has no console.
+///
+/// Opening the console again changes its mode for all the existing handles.
+pub fn console(mode: ConsoleMode) -> io::Result<Option<Console>> {
+    let mode = match mode {
+        ConsoleMode::Line => AVmConsoleMode::CONSOLE_MODE_LINE,
+        ConsoleMode::Raw => AVmConsoleMode::CONSOLE_MODE_RAW,
+    } as i32;
+    // SAFETY: The function has no preconditions.
+    let fd = unsafe { AVmPayload_openConsole(mode) };
+    if fd < 0 {
+        return Ok(None);
+    }
+    // SAFETY: The function returns a new file descriptor owned by the caller.
+    let input = unsafe { File::from_raw_fd(fd) };
+    let output = input.try_clone()?;
+    Ok(Some(Console { input, output }))
+}
+
+/// Callbacks notified around host-initiated snapshots of the VM. They are called on a binder
+/// thread.
+pub trait SnapshotCallbacks: Send + Sync {
+    /// Called before the host snapshots the VM, so that the payload can make its persisted state
+    /// consistent, e.g. by flushing pending writes. Returning acknowledges that the payload is
+    /// ready to be snapshotted. The host only waits for a bounded quiesce window.
+    fn on_pre_snapshot(&self);
+
+    /// Called after the VM has been restored from a snapshot. Time may have passed since the
+    /// snapshot was taken, so time-sensitive caches should be invalidated.
+    fn on_post_restore(&self);
+}
+
+/// Sets the callbacks notified around host-initiated snapshots of the VM, replacing the previous
+/// ones.
+pub fn set_snapshot_callbacks(callbacks: &'static dyn SnapshotCallbacks) {
+    unsafe extern "C" fn on_pre_snapshot(context: *mut c_void) {
+        // SAFETY: The context is a leaked `Box<&'static dyn SnapshotCallbacks>`, see below.
+        let callbacks = unsafe { *(context as *const &'static dyn SnapshotCallbacks) };
+        callbacks.on_pre_snapshot();
+    }
+
+    unsafe extern "C" fn on_post_restore(context: *mut c_void) {
+        // SAFETY: The context is a leaked `Box<&'static dyn SnapshotCallbacks>`, see below.
+        let callbacks = unsafe { *(context as *const &'static dyn SnapshotCallbacks) };
+        callbacks.on_post_restore();
+    }
+
+    // The context is never freed, as the previous callbacks may still be running when they are
+    // replaced.
+    let context = Box::into_raw(Box::new(callbacks)) as *mut c_void;
+    // SAFETY: The callbacks are valid function pointers which can be called from any thread, and
+    // the context they are called with is never freed.
+    unsafe {
+        AVmPayload_setSnapshotCallbacks(Some(on_pre_snapshot), Some(on_post_restore), context)
+    };
+}
+
+/// The callback set by [`on_shutdown_requested`]. It is reference counted so that replacing it
+/// frees it once the calls in progress return.
+type ShutdownCallback = Arc<dyn Fn() + Send + Sync>;
+
+static SHUTDOWN_CALLBACK: Mutex<Option<ShutdownCallback>> = Mutex::new(None);
+
+/// Sets the callback notified when the host shuts the VM down gracefully, replacing the previous
+/// one, so that the payload can flush its state, e.g. its encrypted storage, and exit cleanly
+/// rather than being killed mid-write. Returning acknowledges that the payload's state is
+/// consistent. The host only waits for a bounded time.
+///
+/// The callback is called on one of the threads which the connection to Microdroid Manager
+/// dedicates to incoming calls, so the payload doesn't need to start a binder thread pool.
+pub fn on_shutdown_requested(callback: impl Fn() + Send + Sync + 'static) {
+    unsafe extern "C" fn on_shutdown_requested(_context: *mut c_void) {
+        // Don't hold the lock while calling back, as the callback may replace itself.
+        let callback = SHUTDOWN_CALLBACK.lock().unwrap().clone();
+        if let Some(callback) = callback {
+            callback();
+        }
+    }
+
+    *SHUTDOWN_CALLBACK.lock().unwrap() = Some(Arc::new(callback));
+    // SAFETY: The callback is a valid function pointer which can be called from any thread, and
+    // doesn't use its context.
+    unsafe { AVmPayload_setShutdownCallback(Some(on_shutdown_requested), ptr::null_mut()) };
+}
+
+/// Stops notifying the payload of shutdown requests, and frees the callback set by
+/// [`on_shutdown_requested`] once any call in progress returns.
+pub fn clear_shutdown_callback() {
+    // SAFETY: There is no callback to call.
+    unsafe { AVmPayload_setShutdownCallback(None, ptr::null_mut()) };
+    *SHUTDOWN_CALLBACK.lock().unwrap() = None;
+}
+
 /// Retrieves all or part of a 32-byte secret that is bound to this unique VM
 /// instance and the supplied identifier. The secret can be used e.g. as an
 /// encryption key.
@@ -0,0 +1,168 @@
+/*
+ * Copyright 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Localization of the strings which the payload returns to the app owning the VM.
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use vm_payload_bindgen::AVmPayload_getHostLocales;
+
+/// Gets the locales of the app owning the VM when the VM was started, most preferred first, as
+/// BCP 47 language tags, e.g. `fr-CA`. The list is empty if the locales are unknown.
+///
+/// The locales are provided by the host and are not part of the measured configuration of the VM.
+pub fn host_locales() -> io::Result<Vec<String>> {
+    // SAFETY: AVmPayload_getHostLocales has no preconditions.
+    let locales = unsafe { AVmPayload_getHostLocales() };
+    if locales.is_null() {
+        return Err(io::Error::other("Failed to get the locales from the host"));
+    }
+    // SAFETY: AVmPayload_getHostLocales returns a pointer to a nul-terminated C string with static
+    // lifetime, if not null.
+    let locales = unsafe { CStr::from_ptr(locales) };
+    Ok(split_locales(&locales.to_string_lossy()))
+}
+
+fn split_locales(locales: &str) -> Vec<String> {
+    locales.split(',').filter(|tag| !tag.is_empty()).map(str::to_owned).collect()
+}
+
+/// A table of strings bundled in the APK in several languages, e.g. to localize the error messages
+/// which the payload returns to the app.
+///
+/// The directory of the table holds a file for each language, named after its BCP 47 language tag,
+/// e.g. `fr-CA.txt` or `fr.txt`, and `default.txt` for the fallback language. Each line of a file
+/// is either empty, a comment starting with `#`, or `<key>=<string>`, the string running to the end
+/// of the line.
+#[derive(Clone, Debug, Default)]
+pub struct StringTable {
+    /// The strings of each language found, most preferred first.
+    languages: Vec<HashMap<String, String>>,
+}
+
+impl StringTable {
+    /// Loads the table in `dir`, e.g. `apk_contents_path().join("assets/strings")`, for the
+    /// locales of the app owning the VM. See [`host_locales`].
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        Self::load_for_locales(dir, &host_locales()?)
+    }
+
+    /// Loads the table in `dir` for `locales`, most preferred first. A string missing for a
+    /// locale is looked up in its language without region, then for the next locales, and finally
+    /// in the fallback language.
+    pub fn load_for_locales(dir: &Path, locales: &[String]) -> io::Result<Self> {
+        let mut languages = vec![];
+        for name in file_names(locales) {
+            match fs::read_to_string(dir.join(format!("{name}.txt"))) {
+                Ok(contents) => languages.push(parse_strings(&contents)),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Self { languages })
+    }
+
+    /// Returns the string with `key` in the most preferred language which has it.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.languages.iter().find_map(|strings| strings.get(key)).map(String::as_str)
+    }
+}
+
+/// Returns the names of the files to look strings up in for `locales`, most preferred first.
+fn file_names(locales: &[String]) -> Vec<&str> {
+    let mut names = vec![];
+    // Tags which could reach outside of the directory are ignored.
+    let tags = locales.iter().filter(|tag| {
+        !tag.is_empty() && tag.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    });
+    for tag in tags {
+        let language = tag.split('-').next().unwrap_or(tag);
+        for name in [tag.as_str(), language] {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    names.push("default");
+    names
+}
+
+fn parse_strings(contents: &str) -> HashMap<String, String> {
+    contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, string)| (key.trim().to_owned(), string.to_owned()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locales(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn split_host_locales() {
+        assert_eq!(split_locales("fr-CA,en-US"), locales(&["fr-CA", "en-US"]));
+        assert!(split_locales("").is_empty());
+    }
+
+    #[test]
+    fn file_names_fall_back_to_language_then_default() {
+        assert_eq!(
+            file_names(&locales(&["fr-CA", "fr-FR", "en"])),
+            ["fr-CA", "fr", "fr-FR", "en", "default"]
+        );
+        assert_eq!(file_names(&[]), ["default"]);
+    }
+
+    #[test]
+    fn file_names_ignore_paths() {
+        assert_eq!(file_names(&locales(&["../secret", "a/b", "", "de"])), ["de", "default"]);
+    }
+
+    #[test]
+    fn parse_string_file() {
+        let strings = parse_strings("# comment\n\ngreeting = Bonjour, monde\nbad line\nempty=\n");
+        assert_eq!(strings.len(), 2);
+        assert_eq!(strings["greeting"], " Bonjour, monde");
+        assert_eq!(strings["empty"], "");
+    }
+
+    #[test]
+    fn lookup_follows_preferences() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("fr.txt"), "hello=Bonjour\n")?;
+        fs::write(dir.path().join("fr-CA.txt"), "bye=Salut\n")?;
+        fs::write(dir.path().join("default.txt"), "hello=Hello\nbye=Bye\nthanks=Thanks\n")?;
+
+        let table = StringTable::load_for_locales(dir.path(), &locales(&["fr-CA", "en"]))?;
+        assert_eq!(table.get("bye"), Some("Salut"));
+        assert_eq!(table.get("hello"), Some("Bonjour"));
+        assert_eq!(table.get("thanks"), Some("Thanks"));
+        assert_eq!(table.get("missing"), None);
+
+        let table = StringTable::load_for_locales(dir.path(), &locales(&["de"]))?;
+        assert_eq!(table.get("hello"), Some("Hello"));
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+/*
+ * Copyright 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Logger which sends the log records of the payload to the host, where they are written to logcat
+//! with their level and tag, attributed to the app owning the VM.
+//!
+//! ```rust,no_run
+//! use log::{info, LevelFilter};
+//!
+//! log::set_boxed_logger(Box::new(vm_payload::log_writer().with_tag("my_payload")))
+//!     .expect("Logger already set");
+//! log::set_max_level(LevelFilter::Info);
+//! info!("Hello from the VM");
+//! ```
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::ffi::CString;
+use vm_payload_bindgen::{AVmLogPriority, AVmPayload_writeLog};
+
+/// Returns a logger which sends the records of the payload to the host. See [`LogWriter`].
+pub fn log_writer() -> LogWriter {
+    LogWriter { tag: None, max_level: LevelFilter::Trace }
+}
+
+/// A [`Log`] implementation writing the records of the payload to logcat on the host, rather than
+/// to its console, where they would lose their level and tag.
+#[derive(Clone, Debug)]
+pub struct LogWriter {
+    tag: Option<String>,
+    max_level: LevelFilter,
+}
+
+impl LogWriter {
+    /// Uses `tag` for all the records, instead of their target.
+    pub fn with_tag(mut self, tag: impl Into<String>) -> Self {
+        self.tag = Some(tag.into());
+        self
+    }
+
+    /// Drops the records less severe than `max_level` before sending them to the host.
+    pub fn with_max_level(mut self, max_level: LevelFilter) -> Self {
+        self.max_level = max_level;
+        self
+    }
+}
+
+impl Log for LogWriter {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.max_level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let priority = match record.level() {
+            Level::Error => AVmLogPriority::LOG_PRIORITY_ERROR,
+            Level::Warn => AVmLogPriority::LOG_PRIORITY_WARN,
+            Level::Info => AVmLogPriority::LOG_PRIORITY_INFO,
+            Level::Debug => AVmLogPriority::LOG_PRIORITY_DEBUG,
+            Level::Trace => AVmLogPriority::LOG_PRIORITY_VERBOSE,
+        } as i32;
+        let tag = to_c_string(self.tag.as_deref().unwrap_or(record.target()));
+        let message = to_c_string(&record.args().to_string());
+        // SAFETY: The function only reads from `tag` and `message`, which are valid C strings, and
+        // doesn't retain them. If the host can't be reached there is nowhere to report it, so the
+        // record is dropped.
+        unsafe { AVmPayload_writeLog(priority, tag.as_ptr(), message.as_ptr()) };
+    }
+
+    fn flush(&self) {}
+}
+
+fn to_c_string(s: &str) -> CString {
+    CString::new(s.replace('\0', "")).unwrap()
+}
@@ -0,0 +1,223 @@
+/*
+ * Copyright 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Introspection of the sandbox which microdroid_manager runs the payload in.
+
+use crate::{apk_contents_path, encrypted_storage_path, host_ca_certificates_path};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+
+/// The confinement of the payload process, as configured by microdroid_manager before starting
+/// it. See [`sandbox_info`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SandboxInfo {
+    /// The real user ID of the process.
+    pub uid: u32,
+    /// The effective user ID of the process.
+    pub euid: u32,
+    /// The effective capabilities of the process, as a bit mask indexed by `CAP_*` number.
+    pub effective_capabilities: u64,
+    /// The SELinux context of the process, e.g. `u:r:microdroid_payload:s0`.
+    pub selinux_context: String,
+    /// The mount flags of the paths provided to the payload, in a fixed order.
+    pub mounts: Vec<MountInfo>,
+}
+
+/// The flags of the mount holding a path. See [`SandboxInfo::mounts`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MountInfo {
+    /// The path provided to the payload, e.g. [`apk_contents_path`].
+    pub path: PathBuf,
+    /// The mount point of the filesystem holding `path`.
+    pub mount_point: PathBuf,
+    /// Whether the mount is read-only.
+    pub read_only: bool,
+    /// Whether set-user-ID and set-group-ID bits are ignored on the mount.
+    pub no_suid: bool,
+    /// Whether device files on the mount can't be opened.
+    pub no_dev: bool,
+    /// Whether files on the mount can't be executed.
+    pub no_exec: bool,
+}
+
+/// Returns the user IDs, effective capabilities, SELinux context and mount flags of the payload,
+/// so that security-sensitive payloads can check that they are confined as expected and fail
+/// closed otherwise, e.g. before handling secrets.
+///
+/// The information is read from the kernel rather than reported by microdroid_manager, so it
+/// reflects the current state of the process. Only the paths which the VM configuration provides
+/// to the payload are described in [`SandboxInfo::mounts`].
+pub fn sandbox_info() -> io::Result<SandboxInfo> {
+    let status = fs::read_to_string("/proc/self/status")?;
+    let uids = status_field(&status, "Uid")?;
+    let mut uids = uids.split_whitespace().map(|uid| uid.parse::<u32>());
+    let (Some(Ok(uid)), Some(Ok(euid))) = (uids.next(), uids.next()) else {
+        return Err(invalid_data("Invalid Uid field in /proc/self/status"));
+    };
+    let effective_capabilities = u64::from_str_radix(status_field(&status, "CapEff")?, 16)
+        .map_err(|_| invalid_data("Invalid CapEff field in /proc/self/status"))?;
+
+    let selinux_context = fs::read("/proc/self/attr/current")?;
+    let selinux_context = String::from_utf8_lossy(&selinux_context);
+    // The context is terminated by a NUL, and sometimes a newline.
+    let selinux_context = selinux_context.trim_end_matches(['\0', '\n']).to_owned();
+
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    let paths = [Some(apk_contents_path()), encrypted_storage_path(), host_ca_certificates_path()];
+    let mounts = paths
+        .into_iter()
+        .flatten()
+        .map(|path| mount_info(&mountinfo, path))
+        .collect::<io::Result<_>>()?;
+
+    Ok(SandboxInfo { uid, euid, effective_capabilities, selinux_context, mounts })
+}
+
+fn status_field<'a>(status: &'a str, name: &str) -> io::Result<&'a str> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+        .map(str::trim)
+        .ok_or_else(|| invalid_data(&format!("No {name} field in /proc/self/status")))
+}
+
+/// Finds the mount holding `path` in the contents of `/proc/self/mountinfo`, i.e. the last one
+/// mounted at the longest prefix of `path`, as the others are shadowed by it.
+fn mount_info(mountinfo: &str, path: &Path) -> io::Result<MountInfo> {
+    let (mount_point, options) = mountinfo
+        .lines()
+        .filter_map(|line| {
+            // Fields: mount ID, parent ID, major:minor, root, mount point, mount options, ...
+            let mut fields = line.split(' ').skip(4);
+            Some((unescape(fields.next()?), fields.next()?))
+        })
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        .max_by_key(|(mount_point, _)| mount_point.components().count())
+        .ok_or_else(|| invalid_data(&format!("No mount holds {path:?}")))?;
+    let has_option = |option| options.split(',').any(|o| o == option);
+    Ok(MountInfo {
+        path: path.to_owned(),
+        mount_point,
+        read_only: has_option("ro"),
+        no_suid: has_option("nosuid"),
+        no_dev: has_option("nodev"),
+        no_exec: has_option("noexec"),
+    })
+}
+
+/// Decodes the octal escapes, e.g. `\040` for a space, of a path in `/proc/self/mountinfo`.
+fn unescape(field: &str) -> PathBuf {
+    let bytes = field.as_bytes();
+    let mut path = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 4).filter(|_| bytes[i] == b'\\');
+        match escaped
+            .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok())
+        {
+            Some(byte) => {
+                path.push(byte);
+                i += 4;
+            }
+            None => {
+                path.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(OsString::from_vec(path))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUS: &str =
+        "Name:\tpayload\nUid:\t1000\t1001\t1001\t1001\nCapEff:\t0000000000000400\n";
+
+    const MOUNTINFO: &str = "\
+1 0 253:0 / / ro,relatime shared:1 - ext4 /dev/root ro
+2 1 0:5 / /mnt rw,nosuid,nodev shared:2 - tmpfs tmpfs rw
+3 2 7:0 / /mnt/apk ro,nosuid,nodev,relatime shared:3 - ext4 /dev/block/dm-0 ro
+4 2 253:1 / /mnt/encryptedstore rw,nosuid,nodev,noexec shared:4 - ext4 /dev/block/dm-1 rw
+5 2 0:6 / /mnt/apk rw,relatime shared:5 - tmpfs tmpfs rw
+6 2 0:7 / /mnt/with\040space ro,noexec - tmpfs tmpfs ro
+";
+
+    #[test]
+    fn status_fields() {
+        assert_eq!(status_field(STATUS, "Uid").unwrap(), "1000\t1001\t1001\t1001");
+        assert_eq!(status_field(STATUS, "CapEff").unwrap(), "0000000000000400");
+        assert!(status_field(STATUS, "CapPrm").is_err());
+        // Names are matched whole.
+        assert!(status_field(STATUS, "Ui").is_err());
+    }
+
+    #[test]
+    fn mount_flags() {
+        let mount = mount_info(MOUNTINFO, Path::new("/mnt/encryptedstore/data")).unwrap();
+        assert_eq!(
+            mount,
+            MountInfo {
+                path: PathBuf::from("/mnt/encryptedstore/data"),
+                mount_point: PathBuf::from("/mnt/encryptedstore"),
+                read_only: false,
+                no_suid: true,
+                no_dev: true,
+                no_exec: true,
+            }
+        );
+        let mount = mount_info(MOUNTINFO, Path::new("/system/bin")).unwrap();
+        assert_eq!(mount.mount_point, Path::new("/"));
+        assert!(mount.read_only && !mount.no_suid && !mount.no_dev && !mount.no_exec);
+    }
+
+    #[test]
+    fn longest_and_last_mount_point_wins() {
+        // /mnt/apk is mounted twice, the last mount shadows the first.
+        let mount = mount_info(MOUNTINFO, Path::new("/mnt/apk/lib")).unwrap();
+        assert_eq!(mount.mount_point, Path::new("/mnt/apk"));
+        assert!(!mount.read_only && !mount.no_suid);
+        // Path components are compared whole.
+        let mount = mount_info(MOUNTINFO, Path::new("/mnt/apkx")).unwrap();
+        assert_eq!(mount.mount_point, Path::new("/mnt"));
+    }
+
+    #[test]
+    fn escaped_mount_points() {
+        let mount = mount_info(MOUNTINFO, Path::new("/mnt/with space/file")).unwrap();
+        assert_eq!(mount.mount_point, Path::new("/mnt/with space"));
+        assert!(mount.read_only && mount.no_exec);
+
+        assert_eq!(unescape(r"/a\134b\011"), Path::new("/a\\b\t"));
+        // Incomplete or invalid escapes are kept as they are.
+        assert_eq!(unescape(r"/a\04"), Path::new(r"/a\04"));
+        assert_eq!(unescape(r"/a\999"), Path::new(r"/a\999"));
+    }
+
+    #[test]
+    fn no_mount_holds_relative_path() {
+        assert!(mount_info(MOUNTINFO, Path::new("relative")).is_err());
+        assert!(mount_info("", Path::new("/")).is_err());
+    }
+}
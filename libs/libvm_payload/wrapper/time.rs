@@ -0,0 +1,70 @@
+/*
+ * Copyright 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Time provided by the host, as the clock of the VM can't be relied on.
+
+use std::time::{Duration, SystemTime};
+use vm_payload_bindgen::{AVmPayload_getCurrentTime, AVmTimeTrustLevel};
+
+/// How far the time returned by [`current_time`] can be trusted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeTrustLevel {
+    /// The time comes from the host, which is trusted as the VM isn't protected.
+    TrustedHost,
+    /// The time comes from the host, which isn't trusted by the protected VM. It may be wrong, so
+    /// it shouldn't be relied on where a wrong time would compromise the payload.
+    UntrustedHost,
+}
+
+/// The current time, as estimated from the time reported by the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CurrentTime {
+    /// The estimated time.
+    pub time: SystemTime,
+    /// How far the actual time may be from `time`, in either direction.
+    pub uncertainty: Duration,
+    /// How far the host reporting the time can be trusted.
+    pub trust_level: TimeTrustLevel,
+}
+
+impl CurrentTime {
+    /// Returns whether the actual time is certainly within `not_before..=not_after`, e.g. the
+    /// validity period of a certificate.
+    pub fn is_within(&self, not_before: SystemTime, not_after: SystemTime) -> bool {
+        let earliest = self.time.checked_sub(self.uncertainty).unwrap_or(SystemTime::UNIX_EPOCH);
+        let Some(latest) = self.time.checked_add(self.uncertainty) else { return false };
+        not_before <= earliest && latest <= not_after
+    }
+}
+
+/// Gets the current time from the host, as the clock of the VM can't be relied on, e.g. to check
+/// the validity period of certificates.
+pub fn current_time() -> CurrentTime {
+    let mut uncertainty_ms = 0;
+    let mut trust_level = AVmTimeTrustLevel::TIME_TRUST_LEVEL_UNTRUSTED_HOST;
+    // SAFETY: The function only writes to `uncertainty_ms` and `trust_level`, which are valid, and
+    // doesn't retain them.
+    let epoch_ms = unsafe { AVmPayload_getCurrentTime(&mut uncertainty_ms, &mut trust_level) };
+    let trust_level = match trust_level {
+        AVmTimeTrustLevel::TIME_TRUST_LEVEL_TRUSTED_HOST => TimeTrustLevel::TrustedHost,
+        AVmTimeTrustLevel::TIME_TRUST_LEVEL_UNTRUSTED_HOST => TimeTrustLevel::UntrustedHost,
+    };
+    CurrentTime {
+        time: SystemTime::UNIX_EPOCH + Duration::from_millis(epoch_ms.try_into().unwrap_or(0)),
+        uncertainty: Duration::from_millis(uncertainty_ms.try_into().unwrap_or(0)),
+        trust_level,
+    }
+}
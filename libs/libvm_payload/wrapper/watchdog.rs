@@ -0,0 +1,51 @@
+/*
+ * Copyright 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Watchdog of the payload, which the host kills the VM on if the payload stops kicking it, e.g.
+//! because it hangs.
+//!
+//! ```rust,no_run
+//! use std::time::Duration;
+//!
+//! vm_payload::watchdog::start(Duration::from_secs(10));
+//! loop {
+//!     // Do some work, taking less than 10 seconds.
+//!     vm_payload::watchdog::kick();
+//! }
+//! ```
+
+use std::time::Duration;
+use vm_payload_bindgen::{AVmPayload_kickWatchdog, AVmPayload_startWatchdog};
+
+/// Starts the watchdog of the payload, or changes its interval if it is started already. Once
+/// started, [`kick`] must be called at least once per `interval`, or the host kills the VM and
+/// tells its owner that the payload stopped kicking the watchdog. There is no way to stop it.
+///
+/// Starting the watchdog counts as a kick. `interval` must be at least 100 ms.
+pub fn start(interval: Duration) {
+    let interval_ms = interval.as_millis().try_into().unwrap_or(i64::MAX);
+    // SAFETY: Invokes a method from the bindgen library `vm_payload_bindgen` which is safe to
+    // call at any time.
+    unsafe { AVmPayload_startWatchdog(interval_ms) };
+}
+
+/// Postpones the expiry of the watchdog by its interval. The watchdog must have been started with
+/// [`start`].
+pub fn kick() {
+    // SAFETY: Invokes a method from the bindgen library `vm_payload_bindgen` which is safe to
+    // call at any time.
+    unsafe { AVmPayload_kickWatchdog() };
+}
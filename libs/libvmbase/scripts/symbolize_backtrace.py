@@ -0,0 +1,74 @@
+#!/usr/bin/env python3
+#
+# Copyright 2024 The Android Open Source Project
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+"""Symbolizes the backtraces printed by vmbase binaries, e.g. pvmfw, on panics and exceptions.
+
+Reads the console output of a VM, e.g. as captured in a bug report, and prints it back with the
+return addresses of the backtraces resolved to functions and source lines, using the unstripped
+ELF of the binary, e.g. out/target/product/<device>/symbols/.../pvmfw.
+
+vmbase binaries aren't relocated, so the printed addresses are those of the ELF.
+"""
+
+import argparse
+import re
+import subprocess
+import sys
+
+FRAME_RE = re.compile(r'^(?P<prefix>.*\s#(?P<index>\d+): )(?P<address>0x[0-9a-fA-F]+)\s*$')
+# Size of an A64 instruction: a return address follows the call, which is what we symbolize.
+INSTRUCTION_SIZE = 4
+
+
+def symbolize(symbolizer, elf, addresses):
+    """Returns the 'function at file:line' descriptions of the call sites of addresses."""
+    call_sites = [f'{address - INSTRUCTION_SIZE:#x}' for address in addresses]
+    output = subprocess.run(
+        [symbolizer, f'--obj={elf}', '--functions=linkage', '--demangle', '--inlining=false'] +
+        call_sites,
+        check=True,
+        capture_output=True,
+        text=True,
+    ).stdout
+    # Each address is described by a function line and a location line, then an empty line.
+    blocks = [block.splitlines() for block in output.strip().split('\n\n')]
+    return [f'{block[0]} at {block[1]}' if len(block) >= 2 else '??' for block in blocks]
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__,
+                                     formatter_class=argparse.RawDescriptionHelpFormatter)
+    parser.add_argument('--elf', required=True, help='Unstripped ELF of the vmbase binary')
+    parser.add_argument('--symbolizer', default='llvm-symbolizer',
+                        help='Path to llvm-symbolizer (default: from PATH)')
+    parser.add_argument('log', nargs='?', type=argparse.FileType('r', errors='replace'),
+                        default=sys.stdin, help='Console output to symbolize (default: stdin)')
+    args = parser.parse_args()
+
+    lines = args.log.read().splitlines()
+    frames = [(i, FRAME_RE.match(line)) for i, line in enumerate(lines)]
+    frames = [(i, match) for i, match in frames if match]
+    if frames:
+        addresses = [int(match.group('address'), 16) for _, match in frames]
+        descriptions = symbolize(args.symbolizer, args.elf, addresses)
+        for (i, match), description in zip(frames, descriptions):
+            lines[i] = f'{match.group("prefix")}{match.group("address")} {description}'
+
+    for line in lines:
+        print(line)
+
+
+if __name__ == '__main__':
+    main()
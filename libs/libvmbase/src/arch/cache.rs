@@ -0,0 +1,139 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cache maintenance operations and memory barriers.
+//!
+//! Maintenance by virtual address applies to every cache line overlapping the given range. It is
+//! only guaranteed to be complete after a DSB, which the functions of this module execute before
+//! returning, so that callers don't have to. See ARM ARM D7.5.
+
+use crate::util::unchecked_align_down;
+use crate::{dsb, isb, read_sysreg};
+use core::arch::asm;
+use core::mem::size_of;
+use core::ops::Range;
+
+/// Shareability domain which a data synchronization barrier waits for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Domain {
+    /// The inner shareable domain, i.e. all the CPUs of the VM.
+    InnerShareable,
+    /// The full system, including observers such as devices or the host.
+    FullSystem,
+}
+
+/// Executes a data synchronization barrier, waiting for the completion of all the memory accesses
+/// and cache and TLB maintenance operations issued before it, as observed in `domain`.
+#[inline]
+pub fn data_sync_barrier(domain: Domain) {
+    match domain {
+        Domain::InnerShareable => dsb!("ish"),
+        Domain::FullSystem => dsb!("sy"),
+    }
+}
+
+/// Executes an instruction synchronization barrier, so that instructions fetched afterwards see
+/// the effects of the context-changing operations completed before it.
+#[inline]
+pub fn instruction_sync_barrier() {
+    isb!()
+}
+
+/// Returns the size in bytes of the smallest cache line of all the data caches and unified caches.
+#[inline]
+pub fn min_dcache_line_size() -> usize {
+    const DMINLINE_SHIFT: usize = 16;
+    const DMINLINE_MASK: usize = 0xf;
+    let ctr_el0 = read_sysreg!("ctr_el0");
+
+    // DminLine: log2 of the number of words in the smallest cache line of all the data caches.
+    let dminline = (ctr_el0 >> DMINLINE_SHIFT) & DMINLINE_MASK;
+
+    size_of::<u32>() << dminline
+}
+
+/// Returns the size in bytes of the smallest cache line of all the instruction caches.
+#[inline]
+fn min_icache_line_size() -> usize {
+    const IMINLINE_MASK: usize = 0xf;
+    let ctr_el0 = read_sysreg!("ctr_el0");
+
+    // IminLine: log2 of the number of words in the smallest cache line of all the instruction
+    // caches.
+    let iminline = ctr_el0 & IMINLINE_MASK;
+
+    size_of::<u32>() << iminline
+}
+
+/// Returns the start addresses of the cache lines of size `line_size` overlapping `range`.
+fn cache_lines(range: Range<usize>, line_size: usize) -> impl Iterator<Item = usize> {
+    (unchecked_align_down(range.start, line_size)..range.end).step_by(line_size)
+}
+
+/// Cleans the data cache lines overlapping `range` to the point of unification, where the
+/// instruction and data caches and the translation table walks of the CPU see the same copy.
+#[inline]
+pub fn clean_dcache_to_pou(range: Range<usize>) {
+    for line in cache_lines(range, min_dcache_line_size()) {
+        // SAFETY: Cleaning cache lines doesn't change their content, so has no Rust-visible side
+        // effects.
+        unsafe { asm!("dc cvau, {x}", x = in(reg) line, options(nomem, nostack, preserves_flags)) }
+    }
+    data_sync_barrier(Domain::InnerShareable);
+}
+
+/// Cleans and invalidates the data cache lines overlapping `range` to the point of coherency, so
+/// that observers which don't snoop the caches, such as the host, see the latest content.
+#[inline]
+pub fn clean_invalidate_dcache_to_poc(range: Range<usize>) {
+    for line in cache_lines(range, min_dcache_line_size()) {
+        // SAFETY: Cleaning cache lines before invalidating them doesn't change their content, so
+        // has no Rust-visible side effects.
+        unsafe { asm!("dc civac, {x}", x = in(reg) line, options(nomem, nostack, preserves_flags)) }
+    }
+    data_sync_barrier(Domain::FullSystem);
+}
+
+/// Invalidates the data cache lines covering `range` to the point of coherency, discarding any
+/// data not yet written back, so that the next reads fetch what other observers wrote to memory.
+///
+/// # Safety
+///
+/// `range` must be aligned to [`min_dcache_line_size`], as the content of any other data sharing
+/// its first or last cache line would be lost too. The caller must not rely on writes to `range`
+/// that weren't cleaned to the point of coherency.
+#[inline]
+pub unsafe fn invalidate_dcache_to_poc(range: Range<usize>) {
+    let line_size = min_dcache_line_size();
+    debug_assert_eq!(range.start % line_size, 0, "{range:#x?} isn't aligned to cache lines");
+    debug_assert_eq!(range.end % line_size, 0, "{range:#x?} isn't aligned to cache lines");
+    for line in cache_lines(range, line_size) {
+        // SAFETY: The caller guarantees that the discarded data isn't needed.
+        unsafe { asm!("dc ivac, {x}", x = in(reg) line, options(nostack, preserves_flags)) }
+    }
+    data_sync_barrier(Domain::FullSystem);
+}
+
+/// Makes the instructions written to `range` visible to the instruction fetches of all the CPUs
+/// of the VM, e.g. after loading code.
+#[inline]
+pub fn sync_icache(range: Range<usize>) {
+    clean_dcache_to_pou(range.clone());
+    for line in cache_lines(range, min_icache_line_size()) {
+        // SAFETY: Invalidating instruction cache lines has no Rust-visible side effects.
+        unsafe { asm!("ic ivau, {x}", x = in(reg) line, options(nomem, nostack, preserves_flags)) }
+    }
+    data_sync_barrier(Domain::InnerShareable);
+    instruction_sync_barrier();
+}
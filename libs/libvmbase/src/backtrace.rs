@@ -0,0 +1,110 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Backtraces, unwound through the frame records which vmbase binaries are built to keep.
+//!
+//! The return addresses are printed unsymbolized, as the binaries don't embed their symbols. As
+//! they aren't relocated, the addresses can be resolved against the unstripped ELF by
+//! `scripts/symbolize_backtrace.py`.
+
+use crate::eprintln;
+use crate::layout::{eh_stack_range, mapped_stack_range, text_range};
+use aarch64_paging::paging::VirtualAddress;
+use core::arch::asm;
+use core::mem::size_of;
+use core::ops::Range;
+
+/// Maximum number of frames unwound, in case of a (corrupted) loop of frame records.
+const MAX_FRAMES: usize = 64;
+
+/// A frame record, as pushed by the prologue of functions with frame pointers.
+#[repr(C)]
+struct FrameRecord {
+    /// Frame pointer of the caller, i.e. address of its frame record.
+    fp: usize,
+    /// Return address into the caller.
+    lr: usize,
+}
+
+/// Iterator over the return addresses of the frames of a call stack, innermost first.
+///
+/// The frame records are only read if they are within the exception handler stack or the mapped
+/// top of the main stack, as the rest of the stack region would fault, and the unwinding stops
+/// at the first return address which isn't in the text section, so corrupted records end the
+/// backtrace rather than raising an exception.
+pub struct Frames {
+    fp: usize,
+    depth: usize,
+    text: Range<usize>,
+    stacks: [Range<usize>; 2],
+}
+
+impl Frames {
+    /// Starts unwinding from the frame of the caller.
+    #[inline(always)]
+    pub fn current() -> Self {
+        let fp: usize;
+        // SAFETY: Reading the frame pointer register does not affect memory.
+        unsafe { asm!("mov {}, x29", out(reg) fp, options(nomem, nostack, preserves_flags)) };
+        Self::from_fp(fp)
+    }
+
+    /// Starts unwinding from the frame record at `fp`, e.g. as saved by an exception.
+    pub fn from_fp(fp: usize) -> Self {
+        let stacks = [eh_stack_range(), mapped_stack_range()].map(to_usize_range);
+        Self { fp, depth: 0, text: to_usize_range(text_range()), stacks }
+    }
+
+    fn is_valid_record(&self, fp: usize) -> bool {
+        let Some(end) = fp.checked_add(size_of::<FrameRecord>()) else { return false };
+        fp % size_of::<usize>() == 0
+            && self.stacks.iter().any(|stack| stack.start <= fp && end <= stack.end)
+    }
+}
+
+impl Iterator for Frames {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.depth >= MAX_FRAMES || !self.is_valid_record(self.fp) {
+            return None;
+        }
+        // SAFETY: The record is aligned and within the exception handler stack or the mapped part
+        // of the main stack, so reading it can't fault.
+        let record = unsafe { (self.fp as *const FrameRecord).read_volatile() };
+        if !self.text.contains(&record.lr) {
+            return None;
+        }
+        // Frames are pushed downwards, so a caller's record is always at a higher address. The
+        // exception handler stack is below the main stack, so this holds across both.
+        self.fp = if record.fp > self.fp { record.fp } else { 0 };
+        self.depth += 1;
+        Some(record.lr)
+    }
+}
+
+/// Prints the return addresses of the frames of the caller, for `symbolize_backtrace.py`.
+///
+/// This uses `eprintln!`, so it is safe to call from exception handlers.
+#[inline(always)]
+pub fn print_backtrace() {
+    eprintln!("Backtrace:");
+    for (i, lr) in Frames::current().enumerate() {
+        eprintln!("  #{i:02}: {lr:#018x}");
+    }
+}
+
+fn to_usize_range(range: Range<VirtualAddress>) -> Range<usize> {
+    range.start.0..range.end.0
+}
@@ -14,8 +14,10 @@
 
 //! Console driver for 8250 UART.
 
+use crate::layout::UART_PAGE_ADDR;
+use crate::memory::page_4kb_of;
 use crate::uart::Uart;
-use core::fmt::{write, Arguments, Write};
+use core::fmt::{self, write, Arguments, Write};
 use spin::{mutex::SpinMutex, Once};
 
 // Arbitrary limit on the number of consoles that can be registered.
@@ -34,6 +36,9 @@ pub const DEFAULT_CONSOLE_INDEX: usize = 0;
 /// Index of the console used by default for emergency logging.
 pub const DEFAULT_EMERGENCY_CONSOLE_INDEX: usize = DEFAULT_CONSOLE_INDEX;
 
+/// Index of the console from which crosvm passes the failure reason of the VM to the host.
+pub const FAILURE_CONSOLE_INDEX: usize = 1;
+
 /// Initialises the global instance(s) of the UART driver.
 ///
 /// This must be called before using the `print!` and `println!` macros.
@@ -55,11 +60,19 @@ pub unsafe fn init(base_addresses: &[usize]) {
     }
 }
 
+/// Returns the address of the 4KiB page holding the UARTs of the consoles, which defaults to the
+/// one of crosvm until [`init`] is called.
+pub fn uart_page() -> usize {
+    ADDRESSES[DEFAULT_CONSOLE_INDEX].get().map_or(UART_PAGE_ADDR, |&addr| page_4kb_of(addr))
+}
+
 /// Writes a formatted string followed by a newline to the n-th console.
 ///
-/// Panics if the n-th console was not initialized by calling [`init`] first.
+/// Does nothing if the n-th console was not initialized by [`init`], e.g. because it only got the
+/// UART selected by the device tree.
 pub fn writeln(n: usize, format_args: Arguments) {
-    let uart = &mut *CONSOLES[n].get().unwrap().lock();
+    let Some(uart) = CONSOLES[n].get() else { return };
+    let uart = &mut *uart.lock();
 
     write(uart, format_args).unwrap();
     let _ = uart.write_str("\n");
@@ -79,9 +92,41 @@ pub fn ewriteln(n: usize, format_args: Arguments) {
     let _ = uart.write_str("\n");
 }
 
+/// Reports to the host that the guest crashed, with the given message, on the failure console.
+///
+/// The host passes the report to the owner of the VM, before telling it that the VM died. Does
+/// nothing if the failure console wasn't initialized, e.g. because [`init`] only got the UART
+/// selected by the device tree. Never panics.
+pub fn report_crash(message: Arguments) {
+    let Some(addr) = ADDRESSES[FAILURE_CONSOLE_INDEX].get() else { return };
+
+    // SAFETY: addr contains the base of a mapped UART, passed in init().
+    let mut uart = unsafe { Uart::new(*addr) };
+
+    // The report must fit on a single line, see GuestCrashReason.aidl.
+    let _ = uart.write_str("GUEST_CRASH|BARE_METAL_PANIC|");
+    let _ = write(&mut SingleLine(&mut uart), message);
+    let _ = uart.write_str("\n");
+}
+
+/// Writes to the inner writer with newlines replaced by spaces.
+pub struct SingleLine<'a, W: Write>(pub &'a mut W);
+
+impl<W: Write> Write for SingleLine<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, line) in s.split('\n').enumerate() {
+            if i > 0 {
+                self.0.write_char(' ')?;
+            }
+            self.0.write_str(line)?;
+        }
+        Ok(())
+    }
+}
+
 /// Prints the given formatted string to the n-th console, followed by a newline.
 ///
-/// Panics if the console has not yet been initialized. May hang if used in an exception context;
+/// Does nothing if the console has not been initialized. May hang if used in an exception context;
 /// use `eprintln!` instead.
 #[macro_export]
 macro_rules! console_writeln {
@@ -94,7 +139,7 @@ pub(crate) use console_writeln;
 
 /// Prints the given formatted string to the console, followed by a newline.
 ///
-/// Panics if the console has not yet been initialized. May hang if used in an exception context;
+/// Does nothing if the console has not been initialized. May hang if used in an exception context;
 /// use `eprintln!` instead.
 macro_rules! println {
     ($($arg:tt)*) => ({
@@ -15,23 +15,32 @@
 //! Rust entry point.
 
 use crate::{
-    bionic, console, heap, hyp,
-    layout::{UART_ADDRESSES, UART_PAGE_ADDR},
+    bionic, console, fdt, heap, hyp,
+    layout::{crosvm::MMIO_RANGE, UART_ADDRESSES, UART_PAGE_ADDR},
     logger,
-    memory::{PAGE_SIZE, SIZE_16KB, SIZE_4KB},
+    memory::{page_4kb_of, PAGE_SIZE, SIZE_16KB, SIZE_4KB},
     power::{reboot, shutdown},
     rand,
 };
 use core::mem::size_of;
 use static_assertions::const_assert_eq;
 
-fn try_console_init() -> Result<(), hyp::Error> {
+fn try_console_init(fdt_addr: usize) -> Result<(), hyp::Error> {
+    // Prefer the UART which the device tree selects, if it is within the device mappings of the
+    // boot page tables, so that early failures are visible on other layouts than crosvm's.
+    let stdout_uart =
+        fdt::early::find_stdout_uart(fdt_addr).filter(|addr| MMIO_RANGE.contains(addr));
+    let uart_addresses = stdout_uart.as_slice();
+    let uart_addresses =
+        if uart_addresses.is_empty() { &UART_ADDRESSES[..] } else { uart_addresses };
+
     if let Some(mmio_guard) = hyp::get_mmio_guard() {
         mmio_guard.enroll()?;
 
         // TODO(ptosi): Use MmioSharer::share() to properly track this MMIO_GUARD_MAP.
         //
-        // The following call shares the UART but also anything else present in 0..granule.
+        // The following call shares the crosvm UARTs but also anything else present in
+        // 0..granule.
         //
         // For 4KiB, that's only the UARTs. For 16KiB, it also covers the RTC and watchdog but, as
         // neither is used by vmbase clients (and as both are outside of the UART page), they
@@ -44,11 +53,12 @@ fn try_console_init() -> Result<(), hyp::Error> {
         });
         // Validate the assumption above by ensuring that the UART is not moved to another page:
         const_assert_eq!(UART_PAGE_ADDR, 0);
-        mmio_guard.map(UART_PAGE_ADDR)?;
+        mmio_guard.map(stdout_uart.map_or(UART_PAGE_ADDR, page_4kb_of))?;
     }
 
-    // SAFETY: UART_PAGE is mapped at stage-1 (see entry.S) and was just MMIO-guarded.
-    unsafe { console::init(&UART_ADDRESSES) };
+    // SAFETY: The page of the UARTs is mapped at stage-1 (see entry.S and MMIO_RANGE) and was just
+    // MMIO-guarded.
+    unsafe { console::init(uart_addresses) };
 
     Ok(())
 }
@@ -59,7 +69,9 @@ extern "C" fn rust_entry(x0: u64, x1: u64, x2: u64, x3: u64) -> ! {
     // SAFETY: Only called once, from here, and inaccessible to client code.
     unsafe { heap::init() };
 
-    if try_console_init().is_err() {
+    // x0 holds the address of the device tree when booted as a Linux kernel, and is otherwise
+    // ignored by the console unless it points to a valid one.
+    if try_console_init(x0 as usize).is_err() {
         // Don't panic (or log) here to avoid accessing the console.
         reboot()
     }
@@ -15,8 +15,7 @@
 //! Helper functions and structs for exception handlers.
 
 use crate::{
-    eprintln,
-    layout::UART_PAGE_ADDR,
+    backtrace, console, eprintln,
     memory::{page_4kb_of, MemoryTrackerError},
     read_sysreg,
 };
@@ -129,10 +128,12 @@ impl ArmException {
             eprintln!("{exception_name}");
             eprintln!("{obj}");
             eprintln!("{}, elr={:#08x}", self, elr);
+            backtrace::print_backtrace();
         }
     }
 
     fn is_uart_exception(&self) -> bool {
-        self.esr == Esr::DataAbortSyncExternalAbort && page_4kb_of(self.far.0) == UART_PAGE_ADDR
+        self.esr == Esr::DataAbortSyncExternalAbort
+            && page_4kb_of(self.far.0) == console::uart_page()
     }
 }
@@ -14,10 +14,32 @@
 
 //! High-level FDT functions.
 
+pub(crate) mod early;
+
+use crate::memory::MemoryRange;
 use core::ops::Range;
 use cstr::cstr;
 use libfdt::{self, Fdt, FdtError};
 
+/// Represents information about the PL031 real time clock.
+#[derive(Debug)]
+pub struct RtcInfo {
+    /// The MMIO range of the registers of the RTC.
+    pub range: MemoryRange,
+}
+
+impl RtcInfo {
+    /// Creates a `RtcInfo` struct from the given device tree.
+    pub fn new_from_fdt(fdt: &Fdt) -> libfdt::Result<RtcInfo> {
+        let node = fdt.compatible_nodes(cstr!("arm,pl031"))?.next().ok_or(FdtError::NotFound)?;
+        let reg = node.first_reg()?;
+        let size = reg.size.ok_or(FdtError::NotFound)?;
+        let start: usize = reg.addr.try_into().map_err(|_| FdtError::BadValue)?;
+        let size: usize = size.try_into().map_err(|_| FdtError::BadValue)?;
+        Ok(Self { range: start..start + size })
+    }
+}
+
 /// Represents information about a SWIOTLB buffer.
 #[derive(Debug)]
 pub struct SwiotlbInfo {
@@ -0,0 +1,189 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Minimal FDT scanner, usable before the heap and the page tables of the client are set up.
+//!
+//! It only extracts what is needed to select the console, and gives up on anything unexpected.
+
+use crate::layout::crosvm::FDT_MAX_SIZE;
+use crate::memory::{page_4kb_of, SIZE_4KB};
+use core::arch::asm;
+use core::slice;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+
+/// Compatible string of the UARTs supported by the console driver.
+const UART_COMPATIBLE: &[u8] = b"ns16550a";
+
+/// Returns the base address of the UART which /chosen/stdout-path of the device tree at
+/// `fdt_addr` points to, or `None` if there is none or if `fdt_addr` isn't a readable FDT.
+pub(crate) fn find_stdout_uart(fdt_addr: usize) -> Option<usize> {
+    let fdt = EarlyFdt::from_addr(fdt_addr)?;
+
+    let stdout_path = fdt.property(b"/chosen", b"stdout-path")?;
+    let stdout_path = until_nul(stdout_path);
+    // Drop the options of the console, e.g. "serial0:115200n8".
+    let stdout_path = stdout_path.split(|&c| c == b':').next()?;
+    let path = if stdout_path.starts_with(b"/") {
+        stdout_path
+    } else {
+        until_nul(fdt.property(b"/aliases", stdout_path)?)
+    };
+
+    let compatible = fdt.property(path, b"compatible")?;
+    if !compatible.split(|&c| c == 0).any(|s| s == UART_COMPATIBLE) {
+        return None;
+    }
+
+    let parent = match path.iter().rposition(|&c| c == b'/')? {
+        0 => &b"/"[..],
+        i => &path[..i],
+    };
+    let address_cells = match fdt.property(parent, b"#address-cells") {
+        Some(cells) => be32(cells, 0)?,
+        None => 2,
+    };
+    let reg = fdt.property(path, b"reg")?;
+    let addr = match address_cells {
+        1 => u64::from(be32(reg, 0)?),
+        2 => (u64::from(be32(reg, 0)?) << 32) | u64::from(be32(reg, 4)?),
+        _ => return None,
+    };
+    addr.try_into().ok()
+}
+
+struct EarlyFdt<'a> {
+    blob: &'a [u8],
+    structs: usize,
+    strings: usize,
+}
+
+impl<'a> EarlyFdt<'a> {
+    fn from_addr(addr: usize) -> Option<Self> {
+        if addr == 0 || addr % 8 != 0 || !is_range_readable(addr, FDT_HEADER_SIZE) {
+            return None;
+        }
+        // SAFETY: The header was just checked to be mapped, and isn't written by anyone else.
+        let header = unsafe { slice::from_raw_parts(addr as *const u8, FDT_HEADER_SIZE) };
+        if be32(header, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let size = usize::try_from(be32(header, 4)?).ok()?;
+        if !(FDT_HEADER_SIZE..=FDT_MAX_SIZE).contains(&size) || !is_range_readable(addr, size) {
+            return None;
+        }
+        // SAFETY: The whole blob was just checked to be mapped, and isn't written by anyone else.
+        let blob = unsafe { slice::from_raw_parts(addr as *const u8, size) };
+        let structs = usize::try_from(be32(blob, 8)?).ok()?;
+        let strings = usize::try_from(be32(blob, 12)?).ok()?;
+        Some(Self { blob, structs, strings })
+    }
+
+    /// Returns the value of the property `name` of the node at the absolute `path`.
+    fn property(&self, path: &[u8], name: &[u8]) -> Option<&'a [u8]> {
+        let target_depth = components(path).count() + 1;
+        // Depth of the current node, the root node being at depth 1.
+        let mut depth = 0;
+        // Number of the ancestors of the current node, itself included, which match `path`.
+        let mut matched = 0;
+        let mut offset = self.structs;
+        loop {
+            let token = be32(self.blob, offset)?;
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let node_name = until_nul(self.blob.get(offset..)?);
+                    offset = align4(offset.checked_add(node_name.len() + 1)?);
+                    depth += 1;
+                    if matched + 1 == depth
+                        && (depth == 1 || components(path).nth(depth - 2) == Some(node_name))
+                    {
+                        matched = depth;
+                    }
+                }
+                FDT_END_NODE => {
+                    depth = usize::checked_sub(depth, 1)?;
+                    matched = matched.min(depth);
+                    if depth == 0 {
+                        return None;
+                    }
+                }
+                FDT_PROP => {
+                    let len = usize::try_from(be32(self.blob, offset)?).ok()?;
+                    let name_offset = usize::try_from(be32(self.blob, offset + 4)?).ok()?;
+                    let value_offset = offset + 8;
+                    let value = self.blob.get(value_offset..value_offset.checked_add(len)?)?;
+                    offset = align4(value_offset + len);
+                    if matched == depth && depth == target_depth {
+                        let prop_name = self.blob.get(self.strings.checked_add(name_offset)?..)?;
+                        if until_nul(prop_name) == name {
+                            return Some(value);
+                        }
+                    }
+                }
+                FDT_NOP => {}
+                _ => return None,
+            }
+        }
+    }
+}
+
+fn components(path: &[u8]) -> impl Iterator<Item = &[u8]> {
+    path.split(|&c| c == b'/').filter(|c| !c.is_empty())
+}
+
+fn until_nul(bytes: &[u8]) -> &[u8] {
+    bytes.split(|&c| c == 0).next().unwrap_or(bytes)
+}
+
+fn align4(offset: usize) -> usize {
+    offset.next_multiple_of(4)
+}
+
+fn be32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(offset..offset.checked_add(4)?)?.try_into().ok()?))
+}
+
+/// Returns whether the whole range can be read through the current stage-1 translation.
+fn is_range_readable(addr: usize, size: usize) -> bool {
+    let Some(last) = addr.checked_add(size - 1) else {
+        return false;
+    };
+    (page_4kb_of(addr)..=last).step_by(SIZE_4KB).all(is_readable)
+}
+
+/// Returns whether `addr` can be read through the current stage-1 translation, without faulting.
+fn is_readable(addr: usize) -> bool {
+    let par: usize;
+    // SAFETY: Address translation only updates PAR_EL1, which isn't used by the rest of vmbase,
+    // and doesn't access the translated address.
+    unsafe {
+        asm!(
+            "at s1e1r, {addr}",
+            "isb",
+            "mrs {par}, par_el1",
+            addr = in(reg) addr,
+            par = out(reg) par,
+            options(nostack, preserves_flags),
+        );
+    }
+    // PAR_EL1.F is set if the translation failed.
+    par & 1 == 0
+}
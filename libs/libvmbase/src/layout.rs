@@ -16,16 +16,21 @@
 
 pub mod crosvm;
 
+use crate::console;
 use crate::linker::__stack_chk_guard;
 use crate::memory::{page_4kb_of, PAGE_SIZE};
 use aarch64_paging::paging::VirtualAddress;
 use core::ops::Range;
 use core::ptr::addr_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use static_assertions::const_assert_eq;
 
 /// First address that can't be translated by a level 1 TTBR0_EL1.
 pub const MAX_VIRT_ADDR: usize = 1 << 40;
 
+/// Size of the top of the stack region which the binary mapped, through `stack_range`.
+static MAPPED_STACK_SIZE: AtomicUsize = AtomicUsize::new(0);
+
 /// Base memory-mapped addresses of the UART devices.
 ///
 /// See SERIAL_ADDR in https://crosvm.dev/book/appendix/memory_layout.html#common-layout.
@@ -85,18 +90,34 @@ pub fn stack_range(stack_size: usize) -> Range<VirtualAddress> {
     let end = linker_addr!(init_stack_pointer);
     let start = VirtualAddress(end.0.checked_sub(stack_size).unwrap());
     assert!(start >= linker_addr!(stack_limit));
+    MAPPED_STACK_SIZE.fetch_max(stack_size, Ordering::Relaxed);
+
+    start..end
+}
+
+/// Top of the stack region which was mapped, i.e. the largest range returned by `stack_range`, or
+/// an empty range if it was never called.
+pub fn mapped_stack_range() -> Range<VirtualAddress> {
+    let end = linker_addr!(init_stack_pointer);
+    let start = VirtualAddress(end.0 - MAPPED_STACK_SIZE.load(Ordering::Relaxed));
 
     start..end
 }
 
+/// Writable data region for the exception handler stack.
+pub fn eh_stack_range() -> Range<VirtualAddress> {
+    linker_region!(eh_stack_limit, init_eh_stack_pointer)
+}
+
 /// All writable sections, excluding the stack.
 pub fn scratch_range() -> Range<VirtualAddress> {
     linker_region!(eh_stack_limit, bss_end)
 }
 
-/// Range of the page at UART_PAGE_ADDR of PAGE_SIZE.
+/// Range of the page holding the UARTs of the consoles, of PAGE_SIZE.
 pub fn console_uart_page() -> Range<VirtualAddress> {
-    VirtualAddress(UART_PAGE_ADDR)..VirtualAddress(UART_PAGE_ADDR + PAGE_SIZE)
+    let uart_page = console::uart_page();
+    VirtualAddress(uart_page)..VirtualAddress(uart_page + PAGE_SIZE)
 }
 
 /// Read-write data (original).
@@ -19,6 +19,7 @@
 extern crate alloc;
 
 pub mod arch;
+pub mod backtrace;
 pub mod bionic;
 pub mod console;
 mod entry;
@@ -31,8 +32,11 @@ pub mod layout;
 pub mod linker;
 pub mod logger;
 pub mod memory;
+pub mod pci;
 pub mod power;
 pub mod rand;
+pub mod rtc;
+pub mod time;
 pub mod uart;
 pub mod util;
 pub mod virtio;
@@ -43,5 +47,8 @@ use power::reboot;
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     eprintln!("{}", info);
+    // Report the crash first, in case walking the stack faults.
+    console::report_crash(format_args!("{}", info));
+    backtrace::print_backtrace();
     reboot()
 }
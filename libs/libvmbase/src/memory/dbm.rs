@@ -15,8 +15,10 @@
 //! Hardware management of the access flag and dirty state.
 
 use super::page_table::PageTable;
-use super::util::flush_region;
-use crate::{dsb, isb, read_sysreg, tlbi, write_sysreg};
+use crate::arch::cache::{
+    clean_dcache_to_pou, data_sync_barrier, instruction_sync_barrier, Domain,
+};
+use crate::{read_sysreg, tlbi, write_sysreg};
 use aarch64_paging::paging::{Attributes, Descriptor, MemoryRegion};
 
 /// Sets whether the hardware management of access and dirty state is enabled with
@@ -36,7 +38,7 @@ pub(super) fn set_dbm_enabled(enabled: bool) {
     };
     // SAFETY: Changing this bit in TCR doesn't affect Rust's view of memory.
     unsafe { write_sysreg!("tcr_el1", tcr) }
-    isb!();
+    instruction_sync_barrier();
 }
 
 /// Returns `true` if hardware dirty state management is available.
@@ -57,7 +59,7 @@ pub(super) fn flush_dirty_range(
 ) -> Result<(), ()> {
     let flags = desc.flags().ok_or(())?;
     if !flags.contains(Attributes::READ_ONLY) {
-        flush_region(va_range.start().0, va_range.len());
+        clean_dcache_to_pou(va_range.start().0..va_range.end().0);
     }
     Ok(())
 }
@@ -79,8 +81,8 @@ pub(super) fn mark_dirty_block(
         // instructions are visible to instructions fetched afterwards.
         // See ARM ARM E2.3.10, and G5.9.
         tlbi!("vale1", PageTable::ASID, va_range.start().0);
-        dsb!("ish");
-        isb!();
+        data_sync_barrier(Domain::InnerShareable);
+        instruction_sync_barrier();
         Ok(())
     } else {
         Err(())
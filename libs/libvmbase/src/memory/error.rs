@@ -53,6 +53,10 @@ pub enum MemoryTrackerError {
     DuplicateMmioShare(usize),
     /// The MMIO_GUARD granule used by the hypervisor is not supported.
     UnsupportedMmioGuardGranule(usize),
+    /// The size of the memory to share with the host is not supported.
+    InvalidSharedSize(usize),
+    /// Memory shared with the host couldn't be unshared.
+    FailedToUnshare,
 }
 
 impl fmt::Display for MemoryTrackerError {
@@ -78,6 +82,10 @@ impl fmt::Display for MemoryTrackerError {
             Self::UnsupportedMmioGuardGranule(g) => {
                 write!(f, "Unsupported MMIO guard granule: {g}")
             }
+            Self::InvalidSharedSize(size) => {
+                write!(f, "Unsupported size of memory to share with the host: {size:#x}")
+            }
+            Self::FailedToUnshare => write!(f, "Failed to unshare memory from the host"),
         }
     }
 }
@@ -18,10 +18,10 @@ use super::dbm::{flush_dirty_range, mark_dirty_block, set_dbm_enabled};
 use super::error::MemoryTrackerError;
 use super::page_table::{PageTable, MMIO_LAZY_MAP_FLAG};
 use super::util::virt_to_phys;
-use crate::dsb;
+use crate::arch::cache::{data_sync_barrier, Domain};
+use crate::console;
 use crate::exceptions::HandleExceptionError;
 use crate::hyp::{self, get_mem_sharer, get_mmio_guard};
-use crate::layout;
 use crate::util::unchecked_align_down;
 use crate::util::RangeExt as _;
 use aarch64_paging::paging::{
@@ -351,7 +351,7 @@ impl MemoryTracker {
             self.regions.iter().filter(|r| r.mem_type == MemoryType::ReadWrite).map(|r| &r.range);
         // Execute a barrier instruction to ensure all hardware updates to the page table have been
         // observed before reading PTE flags to determine dirty state.
-        dsb!("ish");
+        data_sync_barrier(Domain::InnerShareable);
         // Now flush writable-dirty pages in those regions.
         for range in writable_regions.chain(self.payload_range.as_ref().into_iter()) {
             self.page_table
@@ -412,7 +412,8 @@ impl MmioSharer {
         let base = unchecked_align_down(phys, self.granule);
 
         // TODO(ptosi): Share the UART using this method and remove the hardcoded check.
-        if self.frames.contains(&base) || base == layout::UART_PAGE_ADDR {
+        let uart_base = unchecked_align_down(console::uart_page(), self.granule);
+        if self.frames.contains(&base) || base == uart_base {
             return Err(MemoryTrackerError::DuplicateMmioShare(base));
         }
 
@@ -516,13 +517,7 @@ impl MemorySharer {
         let base = shared.as_ptr() as usize;
         let end = base.checked_add(layout.size()).unwrap();
 
-        if let Some(mem_sharer) = get_mem_sharer() {
-            trace!("Sharing memory region {:#x?}", base..end);
-            for vaddr in (base..end).step_by(self.granule) {
-                let vaddr = NonNull::new(vaddr as *mut _).unwrap();
-                mem_sharer.share(virt_to_phys(vaddr).try_into().unwrap()).unwrap();
-            }
-        }
+        share_range(&(base..end), self.granule).unwrap();
 
         self.frames.push((base, layout));
         pool.add_frame(base, end);
@@ -532,14 +527,8 @@ impl MemorySharer {
 impl Drop for MemorySharer {
     fn drop(&mut self) {
         while let Some((base, layout)) = self.frames.pop() {
-            if let Some(mem_sharer) = get_mem_sharer() {
-                let end = base.checked_add(layout.size()).unwrap();
-                trace!("Unsharing memory region {:#x?}", base..end);
-                for vaddr in (base..end).step_by(self.granule) {
-                    let vaddr = NonNull::new(vaddr as *mut _).unwrap();
-                    mem_sharer.unshare(virt_to_phys(vaddr).try_into().unwrap()).unwrap();
-                }
-            }
+            let end = base.checked_add(layout.size()).unwrap();
+            unshare_range(&(base..end), self.granule).unwrap();
 
             // SAFETY: The region was obtained from alloc_zeroed() with the recorded layout.
             unsafe { dealloc(base as *mut _, layout) };
@@ -547,6 +536,135 @@ impl Drop for MemorySharer {
     }
 }
 
+/// Shares with the host each granule of the given granule-aligned range of virtual addresses, if
+/// the hypervisor supports MEM_SHARE.
+///
+/// If sharing a granule fails, the granules shared before it are unshared. If that fails too,
+/// `MemoryTrackerError::FailedToUnshare` is returned, as the host may still access part of the
+/// range, which must then never be reused.
+fn share_range(range: &MemoryRange, granule: usize) -> Result<()> {
+    let Some(mem_sharer) = get_mem_sharer() else {
+        return Ok(());
+    };
+    trace!("Sharing memory region {range:#x?}");
+    for vaddr in range.clone().step_by(granule) {
+        let ipa = virt_to_phys(NonNull::new(vaddr as *mut _).unwrap()).try_into().unwrap();
+        if let Err(e) = mem_sharer.share(ipa) {
+            let shared = range.start..vaddr;
+            if let Err(unshare_err) = unshare_range(&shared, granule) {
+                error!("Failed to unshare memory region {shared:#x?}: {unshare_err}");
+                return Err(MemoryTrackerError::FailedToUnshare);
+            }
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+/// Revokes the access of the host to each granule of a range shared with `share_range()`.
+fn unshare_range(range: &MemoryRange, granule: usize) -> hyp::Result<()> {
+    let Some(mem_sharer) = get_mem_sharer() else {
+        return Ok(());
+    };
+    trace!("Unsharing memory region {range:#x?}");
+    for vaddr in range.clone().step_by(granule) {
+        let ipa = virt_to_phys(NonNull::new(vaddr as *mut _).unwrap()).try_into().unwrap();
+        mem_sharer.unshare(ipa)?;
+    }
+    Ok(())
+}
+
+/// Zeroed pages shared with the host, e.g. to hold virtio queues set up by the payload itself.
+///
+/// The pages are allocated from the heap and shared with MEM_SHARE, if the hypervisor supports it,
+/// then unshared and freed when dropped, so that the host loses access to them before they can be
+/// reused for private data. If the hypervisor fails to unshare them, they are leaked instead. Unlike
+/// the buffers of the shared pool, they don't stay shared for the lifetime of the
+/// [`MemoryTracker`].
+///
+/// On hypervisors which only let the host access a static region of guest memory (see
+/// [`MemoryTracker::init_static_shared_pool`]), the pages are not accessible to the host.
+#[derive(Debug)]
+pub struct SharedPages {
+    base: NonNull<u8>,
+    layout: Layout,
+    granule: usize,
+}
+
+// SAFETY: The pages are owned by the SharedPages and only accessed through raw pointers.
+unsafe impl Send for SharedPages {}
+
+impl SharedPages {
+    /// Allocates and shares with the host enough pages to hold `size` bytes, rounded up to the
+    /// memory protection granule of the hypervisor.
+    pub fn new(size: NonZeroUsize) -> Result<Self> {
+        let granule = match get_mem_sharer() {
+            Some(mem_sharer) => mem_sharer.granule()?,
+            None => PAGE_SIZE,
+        };
+        let layout = Layout::from_size_align(size.get(), max(granule, PAGE_SIZE))
+            .map_err(|_| MemoryTrackerError::InvalidSharedSize(size.get()))?
+            .pad_to_align();
+        // SAFETY: layout has non-zero size.
+        let Some(base) = NonNull::new(unsafe { alloc_zeroed(layout) }) else {
+            handle_alloc_error(layout);
+        };
+
+        let start = base.as_ptr() as usize;
+        match share_range(&(start..start + layout.size()), granule) {
+            Ok(()) => {}
+            // The host may still access some of the pages, so they are leaked rather than reused.
+            Err(e @ MemoryTrackerError::FailedToUnshare) => return Err(e),
+            Err(e) => {
+                // SAFETY: The region was obtained from alloc_zeroed() with the same layout and none
+                // of it remains shared.
+                unsafe { dealloc(base.as_ptr(), layout) };
+                return Err(e);
+            }
+        }
+
+        Ok(Self { base, layout, granule })
+    }
+
+    /// Returns a pointer to the first byte of the pages.
+    ///
+    /// As the host may access the pages concurrently, they should only be accessed through
+    /// volatile or atomic operations, and any data read from them must be validated.
+    pub fn as_ptr(&self) -> NonNull<u8> {
+        self.base
+    }
+
+    /// Returns the intermediate physical address of the pages, which the host uses to access them.
+    pub fn phys_addr(&self) -> usize {
+        virt_to_phys(self.base)
+    }
+
+    /// Returns the size of the pages, in bytes.
+    pub fn len(&self) -> usize {
+        self.layout.size()
+    }
+
+    /// Returns whether the pages are empty, which they never are.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+impl Drop for SharedPages {
+    fn drop(&mut self) {
+        let start = self.base.as_ptr() as usize;
+        let range = start..start + self.len();
+        if let Err(e) = unshare_range(&range, self.granule) {
+            // The pages can't be freed while the host may still access them, so they are leaked.
+            error!("Failed to unshare memory region {range:#x?}, leaking it: {e}");
+            return;
+        }
+
+        // SAFETY: The region was obtained from alloc_zeroed() with the recorded layout.
+        unsafe { dealloc(self.base.as_ptr(), self.layout) };
+    }
+}
+
 /// Handles a translation fault with the given fault address register (FAR).
 #[inline]
 pub fn handle_translation_fault(far: VirtualAddress) -> result::Result<(), HandleExceptionError> {
@@ -0,0 +1,138 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Enumeration of the devices on the PCI bus found in the device tree.
+//!
+//! The buses listed in the DT are walked through the CAM region mapped by
+//! [`crate::virtio::pci::initialize`], so that clients find their devices and BARs from what the
+//! VMM describes instead of assuming where crosvm puts them.
+
+use alloc::vec::Vec;
+use core::fmt;
+use fdtpci::PciInfo;
+use log::debug;
+use virtio_drivers::{
+    transport::{
+        pci::{
+            bus::{self, BarInfo, DeviceFunction, DeviceFunctionInfo, HeaderType, PciRoot},
+            virtio_device_type, PciTransport, VirtioPciError,
+        },
+        DeviceType,
+    },
+    Hal,
+};
+
+/// PCI enumeration errors.
+#[derive(Debug, Clone)]
+pub enum PciError {
+    /// Failed to read a BAR of a device.
+    InvalidBar(DeviceFunction, u8, bus::PciError),
+    /// Failed to set up the VirtIO transport of a device.
+    TransportCreationFailed(DeviceFunction, VirtioPciError),
+}
+
+impl fmt::Display for PciError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidBar(device_function, index, e) => {
+                write!(f, "Failed to read BAR {index} of PCI device {device_function}: {e}")
+            }
+            Self::TransportCreationFailed(device_function, e) => {
+                write!(f, "Failed to create VirtIO transport for PCI device {device_function}: {e}")
+            }
+        }
+    }
+}
+
+/// Result type with PCI enumeration error.
+pub type Result<T> = core::result::Result<T, PciError>;
+
+/// A function of a device found on the PCI bus.
+#[derive(Clone, Debug)]
+pub struct PciDevice {
+    /// Where the function is on the bus.
+    pub device_function: DeviceFunction,
+    /// The identity of the function, from its configuration header.
+    pub info: DeviceFunctionInfo,
+    /// The implemented BARs of the function, with their index.
+    pub bars: Vec<(u8, BarInfo)>,
+}
+
+impl PciDevice {
+    /// Returns the type of the device if it is a VirtIO device.
+    pub fn virtio_type(&self) -> Option<DeviceType> {
+        virtio_device_type(&self.info)
+    }
+
+    /// Returns the memory BAR with the given index, as its address and size.
+    pub fn memory_bar(&self, index: u8) -> Option<(u64, u64)> {
+        self.bars.iter().find(|(i, _)| *i == index).and_then(|(_, bar)| bar.memory_address_size())
+    }
+
+    /// Sets up the VirtIO PCI transport of the device, for use by a VirtIO driver.
+    pub fn transport<T: Hal>(&self, pci_root: &mut PciRoot) -> Result<PciTransport> {
+        PciTransport::new::<T>(pci_root, self.device_function)
+            .map_err(|e| PciError::TransportCreationFailed(self.device_function, e))
+    }
+}
+
+/// Returns the functions of all the devices on the buses of `pci_info`.
+pub fn enumerate(pci_info: &PciInfo, pci_root: &mut PciRoot) -> Result<Vec<PciDevice>> {
+    let mut devices = Vec::new();
+    for bus in pci_info.bus_range.clone() {
+        for (device_function, info) in pci_root.enumerate_bus(bus) {
+            debug!("Found PCI device {info} at {device_function}");
+            let bars = read_bars(pci_root, device_function, &info)?;
+            devices.push(PciDevice { device_function, info, bars });
+        }
+    }
+    Ok(devices)
+}
+
+/// Returns the VirtIO devices of the given type on the PCI bus.
+pub fn find_virtio_devices(
+    pci_info: &PciInfo,
+    pci_root: &mut PciRoot,
+    device_type: DeviceType,
+) -> Result<Vec<PciDevice>> {
+    let mut devices = enumerate(pci_info, pci_root)?;
+    devices.retain(|device| device.virtio_type() == Some(device_type));
+    Ok(devices)
+}
+
+fn read_bars(
+    pci_root: &mut PciRoot,
+    device_function: DeviceFunction,
+    info: &DeviceFunctionInfo,
+) -> Result<Vec<(u8, BarInfo)>> {
+    let bar_count = match info.header_type {
+        HeaderType::Standard => 6,
+        HeaderType::PciPciBridge => 2,
+        _ => 0,
+    };
+    let mut bars = Vec::new();
+    let mut index = 0;
+    while index < bar_count {
+        let bar = pci_root
+            .bar_info(device_function, index)
+            .map_err(|e| PciError::InvalidBar(device_function, index, e))?;
+        let next_index = if bar.takes_two_entries() { index + 2 } else { index + 1 };
+        if bar.memory_address_size().map_or(true, |(_, size)| size != 0) {
+            debug!("  BAR {index}: {bar}");
+            bars.push((index, bar));
+        }
+        index = next_index;
+    }
+    Ok(bars)
+}
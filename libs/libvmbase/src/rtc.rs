@@ -0,0 +1,55 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Minimal driver for a PL031 real time clock. This only implements enough to read the time from
+//! the emulated PL031 provided by crosvm.
+
+/// Offset of the data register, holding the number of seconds since the Unix epoch.
+const RTCDR: usize = 0x000;
+
+/// Minimal driver for a PL031 real time clock, which can only read the current time.
+pub struct Pl031 {
+    base_address: *const u32,
+}
+
+impl Pl031 {
+    /// Constructs a new instance of the RTC driver for a device at the given base address.
+    ///
+    /// # Safety
+    ///
+    /// The given base address must point to the MMIO control registers of a PL031 device, which
+    /// must be mapped into the address space of the process as device memory.
+    pub unsafe fn new(base_address: usize) -> Self {
+        Self { base_address: base_address as *const u32 }
+    }
+
+    /// Reads the current time from the RTC, in seconds since the Unix epoch.
+    pub fn read(&self) -> u32 {
+        let value: u32;
+        // SAFETY: We know that the base address points to the control registers of a PL031 device
+        // which is appropriately mapped. The access is a plain load, as MMIO emulation requires.
+        unsafe {
+            core::arch::asm!(
+                "ldr {value:w}, [{ptr}]",
+                value = out(reg) value,
+                ptr = in(reg) self.base_address.byte_add(RTCDR),
+                options(nostack, readonly, preserves_flags),
+            );
+        }
+        value
+    }
+}
+
+// SAFETY: `Pl031` just contains a pointer to device memory, which can be accessed from any context.
+unsafe impl Send for Pl031 {}
@@ -0,0 +1,84 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Wall-clock time, read from the real time clock of the VM.
+
+use crate::fdt::RtcInfo;
+use crate::memory::{MemoryTrackerError, MEMORY};
+use crate::rtc::Pl031;
+use core::fmt;
+use core::time::Duration;
+use libfdt::{Fdt, FdtError};
+use spin::Once;
+
+/// Base address of the RTC, once it is initialized.
+static RTC_ADDRESS: Once<usize> = Once::new();
+
+/// Errors of the initialization of the wall clock.
+#[derive(Debug)]
+pub enum Error {
+    /// The RTC couldn't be found in the device tree.
+    Fdt(FdtError),
+    /// The memory tracker isn't initialized.
+    MemoryTrackerUnavailable,
+    /// The RTC couldn't be mapped.
+    Map(MemoryTrackerError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Fdt(e) => write!(f, "Failed to find the RTC in the device tree: {e}"),
+            Self::MemoryTrackerUnavailable => write!(f, "The memory tracker is not initialized"),
+            Self::Map(e) => write!(f, "Failed to map the RTC: {e}"),
+        }
+    }
+}
+
+/// Initializes the wall clock with the RTC at the given base address.
+///
+/// # Safety
+///
+/// The given base address must be that of a PL031 RTC, mapped as device memory and (if necessary)
+/// shared with the host as MMIO.
+pub unsafe fn init(base_address: usize) {
+    assert!(!RTC_ADDRESS.is_completed(), "time::init() called more than once");
+    RTC_ADDRESS.call_once(|| base_address);
+}
+
+/// Initializes the wall clock with the RTC described by the device tree, mapping it with the
+/// memory tracker, which must be initialized.
+pub fn init_from_fdt(fdt: &Fdt) -> Result<(), Error> {
+    let range = RtcInfo::new_from_fdt(fdt).map_err(Error::Fdt)?.range;
+    MEMORY
+        .lock()
+        .as_mut()
+        .ok_or(Error::MemoryTrackerUnavailable)?
+        .map_mmio_range(range.clone())
+        .map_err(Error::Map)?;
+    // SAFETY: The device tree describes the range as a PL031, which was just mapped as MMIO.
+    unsafe { init(range.start) };
+    Ok(())
+}
+
+/// Returns the current time, as the duration since the Unix epoch, or `None` if the wall clock
+/// wasn't initialized. The resolution is one second, and the time is provided by the host, so it
+/// must not be relied on for security.
+pub fn now() -> Option<Duration> {
+    let base_address = *RTC_ADDRESS.get()?;
+    // SAFETY: The address was passed to init(), whose caller promised that it is that of a mapped
+    // PL031.
+    let rtc = unsafe { Pl031::new(base_address) };
+    Some(Duration::from_secs(rtc.read().into()))
+}
@@ -14,7 +14,9 @@
 
 //! Modules for working with VirtIO devices.
 
+pub mod blk;
 mod hal;
 pub mod pci;
+pub mod vsock;
 
 pub use hal::HalImpl;
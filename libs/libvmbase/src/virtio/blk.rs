@@ -0,0 +1,198 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Driver for VirtIO block devices, over either the MMIO or the PCI transport.
+
+use super::pci::PciTransportIterator;
+use super::HalImpl;
+use crate::memory::{MemoryTracker, MemoryTrackerError};
+use alloc::vec::Vec;
+use core::fmt;
+use core::ptr::NonNull;
+use cstr::cstr;
+use libfdt::{Fdt, FdtError};
+use log::debug;
+use virtio_drivers::{
+    device::blk::{VirtIOBlk, SECTOR_SIZE},
+    transport::{
+        mmio::{MmioError, MmioTransport, VirtIOHeader},
+        pci::{bus::PciRoot, PciTransport},
+        DeviceType, Transport,
+    },
+};
+
+/// Block device errors.
+#[derive(Debug, Clone)]
+pub enum BlkError {
+    /// Failed to find the VirtIO MMIO devices in the device tree.
+    InvalidFdt(FdtError),
+    /// Failed to map the registers of a VirtIO MMIO device.
+    MmioMapFailed(MemoryTrackerError),
+    /// The registers of a VirtIO MMIO device are invalid.
+    InvalidMmioTransport(MmioError),
+    /// Failed to initialize a VirtIO block device.
+    DeviceCreationFailed(virtio_drivers::Error),
+    /// The device failed to read blocks.
+    ReadFailed(virtio_drivers::Error),
+    /// The device failed to write blocks.
+    WriteFailed(virtio_drivers::Error),
+    /// The device failed to flush its cache.
+    FlushFailed(virtio_drivers::Error),
+    /// The length of the buffer isn't a multiple of the block size.
+    UnalignedBuffer(usize),
+    /// Attempted to write to a read-only device.
+    ReadOnly,
+}
+
+impl fmt::Display for BlkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidFdt(e) => write!(f, "Failed to find the VirtIO MMIO devices: {e}"),
+            Self::MmioMapFailed(e) => write!(f, "Failed to map VirtIO MMIO device: {e}"),
+            Self::InvalidMmioTransport(e) => write!(f, "Invalid VirtIO MMIO device: {e}"),
+            Self::DeviceCreationFailed(e) => {
+                write!(f, "Failed to create VirtIO Block device: {e}")
+            }
+            Self::ReadFailed(e) => write!(f, "Failed to read from disk: {e}"),
+            Self::WriteFailed(e) => write!(f, "Failed to write to disk: {e}"),
+            Self::FlushFailed(e) => write!(f, "Failed to flush disk: {e}"),
+            Self::UnalignedBuffer(len) => write!(f, "Buffer of {len} bytes isn't block-aligned"),
+            Self::ReadOnly => write!(f, "Attempted to write to a read-only disk"),
+        }
+    }
+}
+
+impl From<FdtError> for BlkError {
+    fn from(e: FdtError) -> Self {
+        Self::InvalidFdt(e)
+    }
+}
+
+/// Result type with [`BlkError`].
+pub type Result<T> = core::result::Result<T, BlkError>;
+
+/// A device storing data in blocks of [`BlockDevice::BLOCK_SIZE`] bytes.
+pub trait BlockDevice {
+    /// Size in bytes of a block, which all accesses must be a multiple of.
+    const BLOCK_SIZE: usize;
+
+    /// Returns the number of blocks of the device.
+    fn capacity(&self) -> u64;
+
+    /// Returns whether the device rejects writes.
+    fn readonly(&self) -> bool;
+
+    /// Reads consecutive blocks starting at `index` to fill `buf`.
+    fn read_blocks(&mut self, index: usize, buf: &mut [u8]) -> Result<()>;
+
+    /// Writes `buf` to consecutive blocks starting at `index`.
+    fn write_blocks(&mut self, index: usize, buf: &[u8]) -> Result<()>;
+
+    /// Makes the previous writes persistent.
+    fn flush(&mut self) -> Result<()>;
+}
+
+/// A VirtIO block device over the transport `T`.
+pub struct VirtIOBlkDevice<T: Transport> {
+    device: VirtIOBlk<HalImpl, T>,
+}
+
+impl<T: Transport> VirtIOBlkDevice<T> {
+    /// Initializes the VirtIO block device behind `transport`.
+    pub fn new(transport: T) -> Result<Self> {
+        let device = VirtIOBlk::new(transport).map_err(BlkError::DeviceCreationFailed)?;
+        Ok(Self { device })
+    }
+
+    fn check_aligned(len: usize) -> Result<()> {
+        if len % SECTOR_SIZE == 0 {
+            Ok(())
+        } else {
+            Err(BlkError::UnalignedBuffer(len))
+        }
+    }
+}
+
+impl<T: Transport> BlockDevice for VirtIOBlkDevice<T> {
+    const BLOCK_SIZE: usize = SECTOR_SIZE;
+
+    fn capacity(&self) -> u64 {
+        self.device.capacity()
+    }
+
+    fn readonly(&self) -> bool {
+        self.device.readonly()
+    }
+
+    fn read_blocks(&mut self, index: usize, buf: &mut [u8]) -> Result<()> {
+        Self::check_aligned(buf.len())?;
+        self.device.read_blocks(index, buf).map_err(BlkError::ReadFailed)
+    }
+
+    fn write_blocks(&mut self, index: usize, buf: &[u8]) -> Result<()> {
+        Self::check_aligned(buf.len())?;
+        if self.readonly() {
+            return Err(BlkError::ReadOnly);
+        }
+        self.device.write_blocks(index, buf).map_err(BlkError::WriteFailed)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.device.flush().map_err(BlkError::FlushFailed)
+    }
+}
+
+/// Finds the VirtIO block devices on the PCI bus, which must have been initialized with
+/// [`super::pci::initialize`].
+pub fn find_pci_devices(pci_root: &mut PciRoot) -> Result<Vec<VirtIOBlkDevice<PciTransport>>> {
+    PciTransportIterator::<HalImpl>::new(pci_root)
+        .filter(|transport| transport.device_type() == DeviceType::Block)
+        .map(VirtIOBlkDevice::new)
+        .collect()
+}
+
+/// Finds the VirtIO block devices described by the "virtio,mmio" nodes of the device tree.
+///
+/// The registers of every VirtIO MMIO device are mapped, including those of the devices which
+/// aren't block devices, so this must only be called once.
+pub fn find_mmio_devices(
+    fdt: &Fdt,
+    memory: &mut MemoryTracker,
+) -> Result<Vec<VirtIOBlkDevice<MmioTransport>>> {
+    let mut devices = Vec::new();
+    for node in fdt.compatible_nodes(cstr!("virtio,mmio"))? {
+        let reg = node.first_reg()?;
+        let size = reg.size.ok_or(FdtError::NotFound)?;
+        let start = usize::try_from(reg.addr).map_err(|_| FdtError::BadValue)?;
+        let end = start
+            .checked_add(usize::try_from(size).map_err(|_| FdtError::BadValue)?)
+            .ok_or(FdtError::BadValue)?;
+        memory.map_mmio_range(start..end).map_err(BlkError::MmioMapFailed)?;
+
+        let header = NonNull::new(start as *mut VirtIOHeader).ok_or(FdtError::BadValue)?;
+        // SAFETY: The registers of the device were just mapped as device memory and
+        // `map_mmio_range` checked that they don't overlap with any other memory region.
+        let transport = match unsafe { MmioTransport::new(header) } {
+            Ok(transport) => transport,
+            // The slot isn't backed by any device.
+            Err(MmioError::ZeroDeviceId) => continue,
+            Err(e) => return Err(BlkError::InvalidMmioTransport(e)),
+        };
+        debug!("Found VirtIO MMIO device at {start:#x}: {:?}", transport.device_type());
+        if transport.device_type() == DeviceType::Block {
+            devices.push(VirtIOBlkDevice::new(transport)?);
+        }
+    }
+    Ok(devices)
+}
@@ -0,0 +1,294 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Driver for VirtIO socket devices, exposing a single blocking stream connection.
+
+use super::pci::PciTransportIterator;
+use super::HalImpl;
+use core::fmt;
+use core::hint::spin_loop;
+use core::mem;
+use log::{info, warn};
+use tinyvec::ArrayVec;
+use virtio_drivers::{
+    device::socket::{
+        SocketError, VirtIOSocket, VsockAddr, VsockConnectionManager, VsockEventType,
+    },
+    transport::{
+        pci::{bus::PciRoot, PciTransport},
+        DeviceType, Transport,
+    },
+};
+
+const WRITE_BUF_CAPACITY: usize = 512;
+
+/// Vsock errors.
+#[derive(Debug, Clone)]
+pub enum VsockError {
+    /// Failed to initialize a VirtIO socket device.
+    DeviceCreationFailed(virtio_drivers::Error),
+    /// No VirtIO socket device was found.
+    MissingDevice,
+    /// The peer closed the connection.
+    Disconnected,
+    /// The peer sent an event which isn't valid in the current state of the connection.
+    UnexpectedEvent,
+    /// Failed VirtIO driver operation.
+    DriverOperationFailed(virtio_drivers::Error),
+}
+
+impl fmt::Display for VsockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::DeviceCreationFailed(e) => {
+                write!(f, "Failed to create VirtIO Socket device: {e}")
+            }
+            Self::MissingDevice => write!(f, "Missing VirtIO Socket device"),
+            Self::Disconnected => write!(f, "Connection closed by the peer"),
+            Self::UnexpectedEvent => write!(f, "Unexpected event from the peer"),
+            Self::DriverOperationFailed(e) => write!(f, "Failed VirtIO driver operation: {e}"),
+        }
+    }
+}
+
+impl From<virtio_drivers::Error> for VsockError {
+    fn from(e: virtio_drivers::Error) -> Self {
+        Self::DriverOperationFailed(e)
+    }
+}
+
+/// Result type with [`VsockError`].
+pub type Result<T> = core::result::Result<T, VsockError>;
+
+/// Finds the first VirtIO socket device on the PCI bus, which must have been initialized with
+/// [`super::pci::initialize`].
+pub fn find_pci_device(pci_root: &mut PciRoot) -> Result<VirtIOSocket<HalImpl, PciTransport>> {
+    PciTransportIterator::<HalImpl>::new(pci_root)
+        .find(|transport| transport.device_type() == DeviceType::Socket)
+        .map(VirtIOSocket::new)
+        .transpose()
+        .map_err(VsockError::DeviceCreationFailed)?
+        .ok_or(VsockError::MissingDevice)
+}
+
+/// A stream connection over a VirtIO socket device with the transport `T`.
+///
+/// Writes are buffered until [`VsockStream::flush`] is called or the buffer is full.
+pub struct VsockStream<T: Transport> {
+    connection_manager: VsockConnectionManager<HalImpl, T>,
+    peer_addr: VsockAddr,
+    local_port: u32,
+    write_buf: ArrayVec<[u8; WRITE_BUF_CAPACITY]>,
+}
+
+impl<T: Transport> VsockStream<T> {
+    /// Connects from `local_port` to `peer_addr`, blocking until the peer accepts.
+    pub fn connect(
+        device: VirtIOSocket<HalImpl, T>,
+        peer_addr: VsockAddr,
+        local_port: u32,
+    ) -> Result<Self> {
+        let mut stream = Self::new(device, peer_addr, local_port);
+        stream.connection_manager.connect(peer_addr, local_port)?;
+        loop {
+            match stream.poll_event()? {
+                Some(VsockEventType::Connected) => break,
+                Some(VsockEventType::Disconnected { .. }) => return Err(VsockError::Disconnected),
+                // We shouldn't receive the following events before the connection is
+                // established.
+                Some(VsockEventType::ConnectionRequest | VsockEventType::Received { .. }) => {
+                    return Err(VsockError::UnexpectedEvent)
+                }
+                // Credit requests and updates can be received at any time and are handled by
+                // the connection manager.
+                Some(VsockEventType::CreditRequest | VsockEventType::CreditUpdate) => {}
+                None => spin_loop(),
+            }
+        }
+        info!("Connected to the peer {peer_addr:?}");
+        Ok(stream)
+    }
+
+    /// Listens on `local_port`, blocking until a peer connects. Only that first connection is
+    /// accepted: the port is no longer listened on once this returns.
+    pub fn accept(device: VirtIOSocket<HalImpl, T>, local_port: u32) -> Result<Self> {
+        let mut connection_manager = VsockConnectionManager::new(device);
+        connection_manager.listen(local_port);
+        let peer_addr = loop {
+            match connection_manager.poll()? {
+                // The connection manager accepts the requests to the ports it listens on.
+                Some(event)
+                    if event.event_type == VsockEventType::ConnectionRequest
+                        && event.destination.port == local_port =>
+                {
+                    break event.source
+                }
+                Some(event) => warn!("Ignoring event before connection: {event:?}"),
+                None => spin_loop(),
+            }
+        };
+        connection_manager.unlisten(local_port);
+        info!("Accepted connection from the peer {peer_addr:?}");
+        Ok(Self { connection_manager, peer_addr, local_port, write_buf: ArrayVec::default() })
+    }
+
+    fn new(device: VirtIOSocket<HalImpl, T>, peer_addr: VsockAddr, local_port: u32) -> Self {
+        Self {
+            connection_manager: VsockConnectionManager::new(device),
+            peer_addr,
+            local_port,
+            write_buf: ArrayVec::default(),
+        }
+    }
+
+    /// Returns the address of the peer.
+    pub fn peer_addr(&self) -> VsockAddr {
+        self.peer_addr
+    }
+
+    /// Reads at least one byte into `buffer`, blocking until some data is available, and returns
+    /// the number of bytes read.
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let len = self.recv(buffer)?;
+            if len > 0 {
+                return Ok(len);
+            }
+            self.wait_for_recv()?;
+        }
+    }
+
+    /// Fills `buffer`, blocking until enough data is available.
+    pub fn read_exact(&mut self, buffer: &mut [u8]) -> Result<()> {
+        let mut start = 0;
+        while start < buffer.len() {
+            start += self.read(&mut buffer[start..])?;
+        }
+        Ok(())
+    }
+
+    /// Buffers `data` to be sent to the peer, or sends it right away if it doesn't fit.
+    pub fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() >= self.write_buf.capacity() - self.write_buf.len() {
+            self.flush()?;
+            if data.len() >= self.write_buf.capacity() {
+                return self.wait_for_send(data);
+            }
+        }
+        self.write_buf.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Sends the buffered data to the peer.
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.write_buf.is_empty() {
+            // Take the buffer out of `self` as `wait_for_send` borrows `self` mutably.
+            let buffer = mem::take(&mut self.write_buf);
+            self.wait_for_send(&buffer)?;
+        }
+        Ok(())
+    }
+
+    /// Shuts down the connection.
+    pub fn shutdown(&mut self) -> Result<()> {
+        self.connection_manager.force_close(self.peer_addr, self.local_port)?;
+        info!("Connection shutdown.");
+        Ok(())
+    }
+
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        let bytes_read = self.connection_manager.recv(self.peer_addr, self.local_port, buffer)?;
+
+        let buffer_available_bytes =
+            self.connection_manager.recv_buffer_available_bytes(self.peer_addr, self.local_port)?;
+        if buffer_available_bytes == 0 && bytes_read > 0 {
+            self.connection_manager.update_credit(self.peer_addr, self.local_port)?;
+        }
+        Ok(bytes_read)
+    }
+
+    fn wait_for_send(&mut self, buffer: &[u8]) -> Result<()> {
+        const INSUFFICIENT_BUFFER_SPACE_ERROR: virtio_drivers::Error =
+            virtio_drivers::Error::SocketDeviceError(SocketError::InsufficientBufferSpaceInPeer);
+        loop {
+            match self.connection_manager.send(self.peer_addr, self.local_port, buffer) {
+                Ok(_) => return Ok(()),
+                Err(INSUFFICIENT_BUFFER_SPACE_ERROR) => {
+                    self.poll()?;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn wait_for_recv(&mut self) -> Result<()> {
+        loop {
+            match self.poll()? {
+                Some(VsockEventType::Received { .. }) => return Ok(()),
+                _ => spin_loop(),
+            }
+        }
+    }
+
+    /// Polls the rx queue after the connection is established with the peer, rejecting the
+    /// events which are only valid before. The valid events are handled inside the connection
+    /// manager.
+    fn poll(&mut self) -> Result<Option<VsockEventType>> {
+        match self.poll_event()? {
+            Some(VsockEventType::Disconnected { .. }) => Err(VsockError::Disconnected),
+            Some(VsockEventType::Connected | VsockEventType::ConnectionRequest) => {
+                Err(VsockError::UnexpectedEvent)
+            }
+            // When there is a received event, the received data is buffered in the connection
+            // manager's internal receive buffer, so we don't need to do anything here. The credit
+            // requests and updates are also handled inside the connection manager.
+            event => Ok(event),
+        }
+    }
+
+    /// Polls the rx queue for an event of this connection, ignoring those of other connections.
+    fn poll_event(&mut self) -> Result<Option<VsockEventType>> {
+        let Some(event) = self.connection_manager.poll()? else {
+            return Ok(None);
+        };
+        if event.source != self.peer_addr || event.destination.port != self.local_port {
+            warn!("Ignoring event of another connection: {event:?}");
+            return Ok(None);
+        }
+        Ok(Some(event.event_type))
+    }
+}
+
+impl<T: Transport> ciborium_io::Read for VsockStream<T> {
+    type Error = VsockError;
+
+    fn read_exact(&mut self, data: &mut [u8]) -> Result<()> {
+        VsockStream::read_exact(self, data)
+    }
+}
+
+impl<T: Transport> ciborium_io::Write for VsockStream<T> {
+    type Error = VsockError;
+
+    fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        VsockStream::write_all(self, data)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        VsockStream::flush(self)
+    }
+}
@@ -0,0 +1,102 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::CpuTopology::CpuTopology as AidlCpuTopology;
+use log::warn;
+
+/// The vCPU topology that will be generated for a VM.
+///
+/// Newer platforms may add topologies which this library doesn't know about. These are kept as
+/// `Unknown` with their raw value. Older platforms fail to create a VM with a topology they don't
+/// know, so use [`CpuTopology::to_aidl_or_default`] when the value doesn't come from the platform
+/// the VM is created on.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CpuTopology {
+    /// One vCPU.
+    #[default]
+    OneCpu,
+    /// Match physical CPU topology of the host.
+    MatchHost,
+    /// A topology which was not recognised by the client library.
+    Unknown(i32),
+}
+
+impl CpuTopology {
+    /// Converts to the AIDL value, downgrading unknown topologies to one vCPU instead of letting
+    /// VM creation fail on platforms which don't support them.
+    pub fn to_aidl_or_default(self) -> AidlCpuTopology {
+        match self {
+            Self::OneCpu => AidlCpuTopology::ONE_CPU,
+            Self::MatchHost => AidlCpuTopology::MATCH_HOST,
+            Self::Unknown(value) => {
+                warn!("Unknown CPU topology {value}, falling back to one vCPU");
+                AidlCpuTopology::ONE_CPU
+            }
+        }
+    }
+}
+
+impl From<AidlCpuTopology> for CpuTopology {
+    fn from(cpu_topology: AidlCpuTopology) -> Self {
+        match cpu_topology {
+            AidlCpuTopology::ONE_CPU => Self::OneCpu,
+            AidlCpuTopology::MATCH_HOST => Self::MatchHost,
+            _ => Self::Unknown(cpu_topology.0.into()),
+        }
+    }
+}
+
+impl TryFrom<CpuTopology> for AidlCpuTopology {
+    type Error = std::num::TryFromIntError;
+
+    /// Fails if the value of an unknown topology doesn't fit in the AIDL backing type.
+    fn try_from(cpu_topology: CpuTopology) -> Result<Self, Self::Error> {
+        Ok(match cpu_topology {
+            CpuTopology::OneCpu => Self::ONE_CPU,
+            CpuTopology::MatchHost => Self::MATCH_HOST,
+            CpuTopology::Unknown(value) => Self(value.try_into()?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_topologies_round_trip() {
+        for (topology, aidl) in [
+            (CpuTopology::OneCpu, AidlCpuTopology::ONE_CPU),
+            (CpuTopology::MatchHost, AidlCpuTopology::MATCH_HOST),
+        ] {
+            assert_eq!(CpuTopology::from(aidl), topology);
+            assert_eq!(AidlCpuTopology::try_from(topology), Ok(aidl));
+            assert_eq!(topology.to_aidl_or_default(), aidl);
+        }
+    }
+
+    #[test]
+    fn unknown_topologies_keep_their_value() {
+        let topology = CpuTopology::from(AidlCpuTopology(42));
+        assert_eq!(topology, CpuTopology::Unknown(42));
+        assert_eq!(AidlCpuTopology::try_from(topology), Ok(AidlCpuTopology(42)));
+        assert_eq!(topology.to_aidl_or_default(), AidlCpuTopology::ONE_CPU);
+    }
+
+    #[test]
+    fn unknown_topologies_out_of_range_are_rejected() {
+        assert!(AidlCpuTopology::try_from(CpuTopology::Unknown(1000)).is_err());
+        assert!(AidlCpuTopology::try_from(CpuTopology::Unknown(-1000)).is_err());
+    }
+}
@@ -49,6 +49,10 @@ pub enum DeathReason {
     MicrodroidUnknownRuntimeError,
     /// The VM was killed due to hangup.
     Hangup,
+    /// The guest kernel panicked, and notified it through the pvpanic device.
+    GuestPanic,
+    /// The payload started its watchdog, then stopped kicking it in time.
+    PayloadWatchdogExpired,
     /// VirtualizationService sent a death reason which was not recognised by the client library.
     Unrecognised(AidlDeathReason),
 }
@@ -81,6 +85,8 @@ impl From<AidlDeathReason> for DeathReason {
                 Self::MicrodroidUnknownRuntimeError
             }
             AidlDeathReason::HANGUP => Self::Hangup,
+            AidlDeathReason::GUEST_PANIC => Self::GuestPanic,
+            AidlDeathReason::PAYLOAD_WATCHDOG_EXPIRED => Self::PayloadWatchdogExpired,
             _ => Self::Unrecognised(reason),
         }
     }
@@ -0,0 +1,84 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use android_system_virtualizationservice::aidl::android::system::virtualizationservice::VirtualMachineAppConfig::DebugLevel::DebugLevel as AidlDebugLevel;
+
+/// How debuggable a VM is.
+///
+/// Newer platforms may add debug levels which this library doesn't know about. These are kept as
+/// `Unknown` with their raw value, so that a value read from VirtualizationService can be passed
+/// back to it unchanged. Don't construct `Unknown` to request a debug level; older platforms will
+/// reject values they don't know.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DebugLevel {
+    /// Not debuggable at all.
+    #[default]
+    None,
+    /// Fully debuggable. All logs are shown, kernel messages are shown, and adb shell is
+    /// supported.
+    Full,
+    /// A debug level which was not recognised by the client library.
+    Unknown(i32),
+}
+
+impl DebugLevel {
+    /// Returns whether the VM is debuggable at all. Unknown debug levels are assumed to be
+    /// debuggable, as every level other than `None` is.
+    pub fn is_debuggable(self) -> bool {
+        self != Self::None
+    }
+}
+
+impl From<AidlDebugLevel> for DebugLevel {
+    fn from(debug_level: AidlDebugLevel) -> Self {
+        match debug_level {
+            AidlDebugLevel::NONE => Self::None,
+            AidlDebugLevel::FULL => Self::Full,
+            _ => Self::Unknown(debug_level.0),
+        }
+    }
+}
+
+impl From<DebugLevel> for AidlDebugLevel {
+    fn from(debug_level: DebugLevel) -> Self {
+        match debug_level {
+            DebugLevel::None => Self::NONE,
+            DebugLevel::Full => Self::FULL,
+            DebugLevel::Unknown(value) => Self(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_debug_levels_round_trip() {
+        for (debug_level, aidl) in
+            [(DebugLevel::None, AidlDebugLevel::NONE), (DebugLevel::Full, AidlDebugLevel::FULL)]
+        {
+            assert_eq!(DebugLevel::from(aidl), debug_level);
+            assert_eq!(AidlDebugLevel::from(debug_level), aidl);
+        }
+    }
+
+    #[test]
+    fn unknown_debug_levels_keep_their_value() {
+        let debug_level = DebugLevel::from(AidlDebugLevel(42));
+        assert_eq!(debug_level, DebugLevel::Unknown(42));
+        assert!(debug_level.is_debuggable());
+        assert_eq!(AidlDebugLevel::from(debug_level), AidlDebugLevel(42));
+    }
+}
@@ -30,6 +30,10 @@ pub enum ErrorCode {
     /// Error code indicating that the payload config is invalid.
     PayloadInvalidConfig,
 
+    /// Error code indicating that the payload wasn't ready within the boot deadline of the VM, so
+    /// the VM was killed. The message ends with the last console output of the VM.
+    BootTimeout,
+
     /// Payload sent a death reason which was not recognised by the client library.
     Unrecognised(AidlErrorCode),
 }
@@ -41,6 +45,7 @@ impl From<AidlErrorCode> for ErrorCode {
             AidlErrorCode::PAYLOAD_VERIFICATION_FAILED => Self::PayloadVerificationFailed,
             AidlErrorCode::PAYLOAD_CHANGED => Self::PayloadChanged,
             AidlErrorCode::PAYLOAD_INVALID_CONFIG => Self::PayloadInvalidConfig,
+            AidlErrorCode::BOOT_TIMEOUT => Self::BootTimeout,
             _ => Self::Unrecognised(error_code),
         }
     }
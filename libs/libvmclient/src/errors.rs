@@ -13,8 +13,69 @@
 // limitations under the License.
 
 use super::DeathReason;
+use android_system_virtualizationservice::{
+    aidl::android::system::virtualizationservice::IVirtualizationService::{
+        ERROR_INVALID_CONFIG, ERROR_MEMORY_CAP_EXCEEDED, ERROR_TOO_MANY_VMS,
+        ERROR_UNSUPPORTED_FEATURE,
+    },
+    binder::{ExceptionCode, Status},
+};
+use std::io;
 use thiserror::Error;
 
+/// An error returned by VirtualizationService, classified so that callers can handle the
+/// failures they expect, e.g. by running fewer VMs, without matching exception messages.
+///
+/// The failures are told apart by the service-specific codes of `IVirtualizationService`, except
+/// for missing permissions, which fail with a security exception as in the rest of Android. Convert
+/// the [`Status`] of a failed call with `ServiceError::from`.
+#[derive(Debug, Error)]
+pub enum ServiceError {
+    /// The caller already runs as many VMs as it may.
+    #[error("Too many VMs: {0}")]
+    TooManyVms(String),
+    /// The VMs of the caller would have more memory than it may.
+    #[error("Memory cap exceeded: {0}")]
+    MemoryCapExceeded(String),
+    /// The device doesn't support a feature which was requested in the VM config.
+    #[error("Unsupported: {0}")]
+    Unsupported(String),
+    /// The caller lacks a permission which the call needs.
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+    /// A field of the VM config is invalid.
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+    /// Any other failure.
+    #[error("{0}")]
+    Other(Status),
+}
+
+impl ServiceError {
+    /// Returns whether the call failed because the caller would exceed its quota, which the device
+    /// sets so that a single app can't exhaust the hypervisor.
+    pub fn is_quota_exceeded(&self) -> bool {
+        matches!(self, Self::TooManyVms(_) | Self::MemoryCapExceeded(_))
+    }
+}
+
+impl From<Status> for ServiceError {
+    fn from(status: Status) -> Self {
+        let message = status.get_description();
+        match status.exception_code() {
+            ExceptionCode::SERVICE_SPECIFIC => match status.service_specific_error() {
+                ERROR_TOO_MANY_VMS => Self::TooManyVms(message),
+                ERROR_MEMORY_CAP_EXCEEDED => Self::MemoryCapExceeded(message),
+                ERROR_UNSUPPORTED_FEATURE => Self::Unsupported(message),
+                ERROR_INVALID_CONFIG => Self::InvalidConfig(message),
+                _ => Self::Other(status),
+            },
+            ExceptionCode::SECURITY => Self::PermissionDenied(message),
+            _ => Self::Other(status),
+        }
+    }
+}
+
 /// An error while waiting for a VM to do something.
 #[derive(Clone, Debug, Eq, Error, PartialEq)]
 pub enum VmWaitError {
@@ -31,3 +92,71 @@ pub enum VmWaitError {
     #[error("VM payload finished.")]
     Finished,
 }
+
+/// An error while transferring a file, see [`transfer_file`](crate::transfer_file).
+#[derive(Debug, Error)]
+pub enum TransferError {
+    /// Reading the source or writing the destination failed.
+    #[error("I/O error during transfer: {0}")]
+    Io(#[from] io::Error),
+    /// The source was shorter or longer than expected.
+    #[error("Transferred {actual} bytes, expected {expected}.")]
+    SizeMismatch {
+        /// The expected size.
+        expected: u64,
+        /// The size of the transferred content.
+        actual: u64,
+    },
+    /// The transferred content doesn't have the expected digest.
+    #[error("SHA-256 digest mismatch: expected {expected}, got {actual}.")]
+    DigestMismatch {
+        /// The expected digest, in hex.
+        expected: String,
+        /// The digest of the transferred content, in hex.
+        actual: String,
+    },
+    /// The transferred file was rejected by the verification callback.
+    #[error("Transferred file failed verification.")]
+    VerificationFailed,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quota_errors_are_classified() {
+        let status = Status::new_service_specific_error_str(ERROR_TOO_MANY_VMS, Some("3 VMs"));
+        let error = ServiceError::from(status);
+        assert!(matches!(error, ServiceError::TooManyVms(_)));
+        assert!(error.is_quota_exceeded());
+
+        let status =
+            Status::new_service_specific_error_str(ERROR_MEMORY_CAP_EXCEEDED, Some("1 GiB"));
+        assert!(ServiceError::from(status).is_quota_exceeded());
+    }
+
+    #[test]
+    fn config_errors_are_classified() {
+        let error =
+            |code| ServiceError::from(Status::new_service_specific_error_str(code, Some("reason")));
+        assert!(matches!(error(ERROR_UNSUPPORTED_FEATURE), ServiceError::Unsupported(_)));
+        assert!(matches!(error(ERROR_INVALID_CONFIG), ServiceError::InvalidConfig(_)));
+        assert!(!error(ERROR_INVALID_CONFIG).is_quota_exceeded());
+    }
+
+    #[test]
+    fn exceptions_are_classified() {
+        let error = |code| ServiceError::from(Status::new_exception_str(code, Some("reason")));
+        assert!(matches!(error(ExceptionCode::SECURITY), ServiceError::PermissionDenied(_)));
+        // Only the service-specific codes tell the failures caused by the config apart.
+        assert!(matches!(error(ExceptionCode::UNSUPPORTED_OPERATION), ServiceError::Other(_)));
+        assert!(matches!(error(ExceptionCode::ILLEGAL_ARGUMENT), ServiceError::Other(_)));
+        assert!(matches!(error(ExceptionCode::ILLEGAL_STATE), ServiceError::Other(_)));
+
+        let status = Status::new_service_specific_error_str(-1, Some("failed"));
+        let error = ServiceError::from(status);
+        assert!(matches!(error, ServiceError::Other(_)));
+        assert!(!error.is_quota_exceeded());
+    }
+}
@@ -14,20 +14,33 @@
 
 //! Client library for VirtualizationService.
 
+mod cpu_topology;
 mod death_reason;
+mod debug_level;
 mod error_code;
 mod errors;
+mod log_forwarder;
+mod reconnect;
 mod sync;
+mod transfer;
 
+pub use crate::cpu_topology::CpuTopology;
 pub use crate::death_reason::DeathReason;
+pub use crate::debug_level::DebugLevel;
 pub use crate::error_code::ErrorCode;
-pub use crate::errors::VmWaitError;
+pub use crate::errors::{ServiceError, TransferError, VmWaitError};
+pub use crate::log_forwarder::log_forwarder;
+pub use crate::reconnect::{ReconnectCallback, ReconnectingService};
 use crate::sync::Monitor;
+pub use crate::transfer::{
+    transfer_file, write_file_atomically, AtomicFile, TransferOptions, TransferProgress,
+};
 use android_system_virtualizationcommon::aidl::android::system::virtualizationcommon::{
     DeathReason::DeathReason as AidlDeathReason, ErrorCode::ErrorCode as AidlErrorCode,
 };
 use android_system_virtualizationservice::{
     aidl::android::system::virtualizationservice::{
+        GuestCrashReason::GuestCrashReason,
         IVirtualMachine::IVirtualMachine,
         IVirtualMachineCallback::{BnVirtualMachineCallback, IVirtualMachineCallback},
         IVirtualizationService::IVirtualizationService,
@@ -35,8 +48,8 @@ use android_system_virtualizationservice::{
         VirtualMachineState::VirtualMachineState,
     },
     binder::{
-        BinderFeatures, DeathRecipient, FromIBinder, IBinder, Interface, ParcelFileDescriptor,
-        Result as BinderResult, StatusCode, Strong,
+        BinderFeatures, DeathRecipient, ExceptionCode, FromIBinder, IBinder, Interface,
+        ParcelFileDescriptor, Result as BinderResult, StatusCode, Strong, Weak,
     },
 };
 use command_fds::CommandFdExt;
@@ -163,6 +176,25 @@ impl VirtualizationService {
     }
 }
 
+/// Returns whether the given feature, one of the `FEATURE_*` constants of
+/// `IVirtualizationService`, is enabled. Probe for features this way before using config fields or
+/// enum values which older platforms don't support; platforms which predate the query itself
+/// report every feature as disabled.
+pub fn is_feature_enabled(
+    service: &dyn IVirtualizationService,
+    feature: &str,
+) -> BinderResult<bool> {
+    match service.isFeatureEnabled(feature) {
+        Err(e)
+            if e.exception_code() == ExceptionCode::TRANSACTION_FAILED
+                && e.transaction_error() == StatusCode::UNKNOWN_TRANSACTION =>
+        {
+            Ok(false)
+        }
+        result => result,
+    }
+}
+
 /// A virtual machine which has been started by the VirtualizationService.
 pub struct VmInstance {
     /// The `IVirtualMachine` Binder object representing the VM.
@@ -195,6 +227,23 @@ pub trait VmCallback {
     /// further details.
     fn on_error(&self, cid: i32, error_code: ErrorCode, message: &str) {}
 
+    /// Called when the guest kernel has crashed and its ramdump has been written to the file set
+    /// with `IVirtualMachine::setRamdumpOutput`.
+    fn on_ramdump_available(&self, cid: i32) {}
+
+    /// Called when the payload requests a host file of the given MIME type. The request must be
+    /// answered with `IVirtualMachine::provideHostFile`; it is declined by default.
+    fn on_host_file_requested(&self, cid: i32, request_id: i32, mime_type: &str) -> Option<File> {
+        None
+    }
+
+    /// Called when the guest kernel panicked and the VM was started again, after which the payload
+    /// starts over.
+    fn on_restarted(&self, cid: i32) {}
+
+    /// Called when the guest has reported why it crashed, before `on_died`.
+    fn on_guest_crash_reported(&self, cid: i32, reason: &GuestCrashReason) {}
+
     /// Called when the VM has exited, all resources have been freed, and any logs have been
     /// written. `death_reason` gives an indication why the VM exited.
     fn on_died(&self, cid: i32, death_reason: DeathReason) {}
@@ -202,6 +251,9 @@ pub trait VmCallback {
 
 impl VmInstance {
     /// Creates (but doesn't start) a new VM with the given configuration.
+    ///
+    /// Fails with e.g. [`ServiceError::TooManyVms`] or [`ServiceError::InvalidConfig`] if the VM
+    /// can't be created, see [`ServiceError`].
     pub fn create(
         service: &dyn IVirtualizationService,
         config: &VirtualMachineConfig,
@@ -209,20 +261,30 @@ impl VmInstance {
         console_in: Option<File>,
         log: Option<File>,
         callback: Option<Box<dyn VmCallback + Send + Sync>>,
-    ) -> BinderResult<Self> {
+    ) -> Result<Self, ServiceError> {
         let console_out = console_out.map(ParcelFileDescriptor::new);
         let console_in = console_in.map(ParcelFileDescriptor::new);
         let log = log.map(ParcelFileDescriptor::new);
 
         let vm =
             service.createVm(config, console_out.as_ref(), console_in.as_ref(), log.as_ref())?;
+        for warning in vm.getDeprecationWarnings()? {
+            warn!(
+                "VM config is deprecated: {} ({}). {}.",
+                warning.message, warning.id, warning.replacement
+            );
+        }
 
         let cid = vm.getCid()?;
 
         // Register callback before starting VM, in case it dies immediately.
         let state = Arc::new(Monitor::new(VmState::default()));
         let callback = BnVirtualMachineCallback::new_binder(
-            VirtualMachineCallback { state: state.clone(), client_callback: callback },
+            VirtualMachineCallback {
+                state: state.clone(),
+                client_callback: callback,
+                vm: Strong::downgrade(&vm),
+            },
             BinderFeatures::default(),
         );
         vm.registerCallback(&callback)?;
@@ -232,8 +294,22 @@ impl VmInstance {
     }
 
     /// Starts the VM.
-    pub fn start(&self) -> BinderResult<()> {
-        self.vm.start()
+    pub fn start(&self) -> Result<(), ServiceError> {
+        Ok(self.vm.start()?)
+    }
+
+    /// Shuts the VM down gracefully: the payload is asked to flush its state and exit, then the VM
+    /// is asked to power off, and it is only stopped forcibly if it is still running once
+    /// `timeout` expires. Blocks until the VM is dead, and returns whether it shut down cleanly.
+    pub fn shutdown(&self, timeout: Duration) -> Result<bool, ServiceError> {
+        let timeout_millis = timeout.as_millis().try_into().unwrap_or(i64::MAX);
+        let clean = self.vm.shutdown(timeout_millis)?;
+        // The VM is dead once it's shut down, unless it never started, but the death notification
+        // may still be on its way.
+        if self.vm.getState()? != VirtualMachineState::NOT_STARTED {
+            self.wait_for_death();
+        }
+        Ok(clean)
     }
 
     /// Returns the CID used for vsock connections to the VM.
@@ -242,8 +318,8 @@ impl VmInstance {
     }
 
     /// Returns the current lifecycle state of the VM.
-    pub fn state(&self) -> BinderResult<VirtualMachineState> {
-        self.vm.getState()
+    pub fn state(&self) -> Result<VirtualMachineState, ServiceError> {
+        Ok(self.vm.getState()?)
     }
 
     /// Blocks until the VM or the VirtualizationService itself dies, and then returns the reason
@@ -287,19 +363,42 @@ impl VmInstance {
         &self,
         port: u32,
     ) -> Result<Strong<T>, StatusCode> {
-        RpcSession::new().setup_preconnected_client(|| {
-            match self.vm.connectVsock(port as i32) {
-                Ok(vsock) => {
-                    // Ownership of the fd is transferred to binder
-                    Some(vsock.into_raw_fd())
-                }
-                Err(e) => {
-                    warn!("Vsock connection failed: {}", e);
-                    None
-                }
-            }
-        })
+        connect_vsock_service(self.vm.as_ref(), port)
     }
+
+    /// Connects to an RPC Binder service provided by the VM on the given vsock port, like
+    /// `connect_service`, but sets up a new session whenever the current one dies, e.g. because
+    /// the payload restarted its server. `on_reconnect` is called with the new proxy after each
+    /// reconnection, so that the client can restore the state it had set up through the old one.
+    pub fn connect_service_with_reconnect<T: FromIBinder + ?Sized + 'static>(
+        &self,
+        port: u32,
+        on_reconnect: Option<ReconnectCallback<T>>,
+    ) -> Result<ReconnectingService<T>, StatusCode> {
+        let vm = self.vm.clone();
+        ReconnectingService::new(
+            Box::new(move || connect_vsock_service(vm.as_ref(), port)),
+            on_reconnect,
+        )
+    }
+}
+
+fn connect_vsock_service<T: FromIBinder + ?Sized>(
+    vm: &dyn IVirtualMachine,
+    port: u32,
+) -> Result<Strong<T>, StatusCode> {
+    RpcSession::new().setup_preconnected_client(|| {
+        match vm.connectVsock(port as i32) {
+            Ok(vsock) => {
+                // Ownership of the fd is transferred to binder
+                Some(vsock.into_raw_fd())
+            }
+            Err(e) => {
+                warn!("Vsock connection failed: {}", e);
+                None
+            }
+        }
+    })
 }
 
 impl Debug for VmInstance {
@@ -348,6 +447,8 @@ impl Monitor<VmState> {
 struct VirtualMachineCallback {
     state: Arc<Monitor<VmState>>,
     client_callback: Option<Box<dyn VmCallback + Send + Sync>>,
+    // Weak, so that the callback registered with the VM doesn't keep the VM alive.
+    vm: Weak<dyn IVirtualMachine>,
 }
 
 impl Debug for VirtualMachineCallback {
@@ -398,6 +499,36 @@ impl IVirtualMachineCallback for VirtualMachineCallback {
         Ok(())
     }
 
+    fn onHostFileRequested(&self, cid: i32, request_id: i32, mime_type: &str) -> BinderResult<()> {
+        let file = self
+            .client_callback
+            .as_ref()
+            .and_then(|callback| callback.on_host_file_requested(cid, request_id, mime_type));
+        let file = file.map(ParcelFileDescriptor::new);
+        self.vm.upgrade()?.provideHostFile(request_id, file.as_ref())
+    }
+
+    fn onRamdumpAvailable(&self, cid: i32) -> BinderResult<()> {
+        if let Some(ref callback) = self.client_callback {
+            callback.on_ramdump_available(cid);
+        }
+        Ok(())
+    }
+
+    fn onRestarted(&self, cid: i32) -> BinderResult<()> {
+        if let Some(ref callback) = self.client_callback {
+            callback.on_restarted(cid);
+        }
+        Ok(())
+    }
+
+    fn onGuestCrashReported(&self, cid: i32, reason: &GuestCrashReason) -> BinderResult<()> {
+        if let Some(ref callback) = self.client_callback {
+            callback.on_guest_crash_reported(cid, reason);
+        }
+        Ok(())
+    }
+
     fn onDied(&self, cid: i32, reason: AidlDeathReason) -> BinderResult<()> {
         let reason = reason.into();
         self.state.notify_death(reason);
@@ -0,0 +1,92 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Forwarding of the console and log output of VMs to the log of the client.
+
+use crate::posix_pipe;
+use log::{log, warn, Level};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::thread;
+
+/// Returns the write end of a pipe whose lines are logged by this process, with `tag` as their
+/// target, e.g. to be passed as the console output or the log of a VM.
+///
+/// Lines starting with a `<N>` syslog priority, as printed by the kernel and init of microdroid,
+/// are logged with the matching severity and without the prefix. Other lines are logged as info.
+pub fn log_forwarder(tag: &str) -> io::Result<File> {
+    let (reader_fd, writer_fd) = posix_pipe()?;
+    let reader = File::from(reader_fd);
+    let tag = tag.to_owned();
+
+    thread::spawn(move || {
+        for line in BufReader::new(reader).split(b'\n') {
+            match line {
+                Ok(line) => {
+                    // Don't stop forwarding because of a garbled line.
+                    let line = String::from_utf8_lossy(&line);
+                    let (level, message) = parse_severity(&line);
+                    log!(target: &tag, level, "{message}");
+                }
+                Err(e) => {
+                    warn!("Failed to read line from VM: {e:?}");
+                    break;
+                }
+            }
+        }
+    });
+    Ok(File::from(writer_fd))
+}
+
+/// Strips the syslog priority prefix, e.g. `<3>`, from the line and returns the matching level.
+fn parse_severity(line: &str) -> (Level, &str) {
+    let prefix = line.strip_prefix('<').and_then(|rest| rest.split_once('>'));
+    let Some((priority, message)) = prefix else {
+        return (Level::Info, line);
+    };
+    let Ok(priority) = priority.parse::<u32>() else {
+        return (Level::Info, line);
+    };
+    // The facility is encoded in the bits above the severity.
+    let level = match priority & 0x7 {
+        0..=3 => Level::Error, // LOG_EMERG, LOG_ALERT, LOG_CRIT, LOG_ERR
+        4 => Level::Warn,      // LOG_WARNING
+        5 | 6 => Level::Info,  // LOG_NOTICE, LOG_INFO
+        _ => Level::Debug,     // LOG_DEBUG
+    };
+    (level, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_severity() {
+        assert_eq!(parse_severity("<3>init: failed"), (Level::Error, "init: failed"));
+        assert_eq!(parse_severity("<4>[    1.2] warning"), (Level::Warn, "[    1.2] warning"));
+        assert_eq!(parse_severity("<6>info"), (Level::Info, "info"));
+        assert_eq!(parse_severity("<7>debug"), (Level::Debug, "debug"));
+        // The facility (here, LOG_DAEMON) doesn't change the severity.
+        assert_eq!(parse_severity("<27>daemon error"), (Level::Error, "daemon error"));
+    }
+
+    #[test]
+    fn keeps_lines_without_severity() {
+        assert_eq!(parse_severity("plain"), (Level::Info, "plain"));
+        assert_eq!(parse_severity("<html>"), (Level::Info, "<html>"));
+        assert_eq!(parse_severity("<3 unterminated"), (Level::Info, "<3 unterminated"));
+        assert_eq!(parse_severity(""), (Level::Info, ""));
+    }
+}
@@ -0,0 +1,251 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Proxies to RPC Binder services of a VM which survive restarts of the server in the VM.
+
+use android_system_virtualizationservice::binder::{
+    ExceptionCode, FromIBinder, Result as BinderResult, Status, StatusCode, Strong,
+};
+use log::{info, warn};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// How many times connecting is tried, as the payload may take a while to restart its server.
+const RECONNECT_ATTEMPTS: u32 = 5;
+/// Delay before the second attempt to connect, doubled after each further failed attempt.
+const RECONNECT_DELAY: Duration = Duration::from_millis(100);
+
+/// Sets up a new session with the service and returns the proxy to it.
+pub(crate) type Connector<S> = Box<dyn Fn() -> Result<S, StatusCode> + Send + Sync>;
+
+/// Called with the new proxy to the service after each reconnection.
+pub type ReconnectCallback<T> = Box<dyn Fn(&Strong<T>) + Send + Sync>;
+
+/// A proxy to an RPC Binder service of a VM, which sets up a new session when the current one
+/// dies, e.g. because the payload restarted its server.
+///
+/// It can be shared by any number of threads, which then share the session. Create it with
+/// [`VmInstance::connect_service_with_reconnect`](crate::VmInstance::connect_service_with_reconnect).
+pub struct ReconnectingService<T: FromIBinder + ?Sized> {
+    inner: Reconnecting<Strong<T>>,
+}
+
+impl<T: FromIBinder + ?Sized> ReconnectingService<T> {
+    pub(crate) fn new(
+        connect: Connector<Strong<T>>,
+        on_reconnect: Option<ReconnectCallback<T>>,
+    ) -> Result<Self, StatusCode> {
+        Ok(Self { inner: Reconnecting::new(connect, on_reconnect)? })
+    }
+
+    /// Returns the proxy of the current session. It is only replaced when a call made with
+    /// [`Self::call`] or [`Self::call_idempotent`] finds the session dead.
+    pub fn service(&self) -> Strong<T> {
+        self.inner.service()
+    }
+
+    /// Calls `f` with the proxy to the service. If the session is found dead, a new one is set up
+    /// for the next calls, but the error is returned, as the server may have handled the call
+    /// before the session died.
+    pub fn call<R>(&self, f: impl FnOnce(&T) -> BinderResult<R>) -> BinderResult<R> {
+        self.inner.call(|service| f(service))
+    }
+
+    /// Like [`Self::call`], but if the session is found dead, `f` is called again with the proxy
+    /// of the new session. `f` must therefore only make calls which are safe to repeat.
+    pub fn call_idempotent<R>(&self, f: impl Fn(&T) -> BinderResult<R>) -> BinderResult<R> {
+        self.inner.call_idempotent(|service| f(service))
+    }
+}
+
+struct Connection<S> {
+    service: S,
+    /// Incremented on each reconnection, so that callers which saw the same session die only
+    /// reconnect once.
+    generation: u64,
+}
+
+/// The reconnection logic of [`ReconnectingService`], for any kind of proxy.
+struct Reconnecting<S> {
+    connect: Connector<S>,
+    on_reconnect: Option<Box<dyn Fn(&S) + Send + Sync>>,
+    /// Only held to read or replace the current session, so that callers aren't held up while
+    /// connecting.
+    connection: Mutex<Connection<S>>,
+    /// Held while connecting, so that concurrent callers wait for the same session rather than
+    /// each setting up their own.
+    reconnecting: Mutex<()>,
+}
+
+impl<S: Clone> Reconnecting<S> {
+    fn new(
+        connect: Connector<S>,
+        on_reconnect: Option<Box<dyn Fn(&S) + Send + Sync>>,
+    ) -> Result<Self, StatusCode> {
+        let service = connect()?;
+        let connection = Mutex::new(Connection { service, generation: 0 });
+        Ok(Self { connect, on_reconnect, connection, reconnecting: Mutex::new(()) })
+    }
+
+    fn service(&self) -> S {
+        self.connection.lock().unwrap().service.clone()
+    }
+
+    fn current(&self) -> (S, u64) {
+        let connection = self.connection.lock().unwrap();
+        (connection.service.clone(), connection.generation)
+    }
+
+    fn call<R>(&self, f: impl FnOnce(&S) -> BinderResult<R>) -> BinderResult<R> {
+        let (service, generation) = self.current();
+        let result = f(&service);
+        if let Err(e) = &result {
+            if is_dead_session(e) {
+                if let Err(e) = self.reconnect(generation) {
+                    warn!("Failed to reconnect to the service of the VM: {e:?}");
+                }
+            }
+        }
+        result
+    }
+
+    fn call_idempotent<R>(&self, f: impl Fn(&S) -> BinderResult<R>) -> BinderResult<R> {
+        let (service, generation) = self.current();
+        match f(&service) {
+            Err(e) if is_dead_session(&e) => {
+                let service = self.reconnect(generation)?;
+                f(&service)
+            }
+            result => result,
+        }
+    }
+
+    /// Sets up a new session, unless another caller did so since `generation`, and returns its
+    /// proxy.
+    fn reconnect(&self, generation: u64) -> Result<S, StatusCode> {
+        let service = {
+            let _reconnecting = self.reconnecting.lock().unwrap();
+            let (service, current_generation) = self.current();
+            if current_generation != generation {
+                return Ok(service);
+            }
+            let service = self.connect_with_retries()?;
+            let mut connection = self.connection.lock().unwrap();
+            connection.service = service.clone();
+            connection.generation += 1;
+            service
+        };
+        info!("Reconnected to the service of the VM");
+        // Called without the locks, so that the callback can make calls through this proxy.
+        if let Some(on_reconnect) = &self.on_reconnect {
+            on_reconnect(&service);
+        }
+        Ok(service)
+    }
+
+    fn connect_with_retries(&self) -> Result<S, StatusCode> {
+        let mut delay = RECONNECT_DELAY;
+        for _ in 1..RECONNECT_ATTEMPTS {
+            match (self.connect)() {
+                Ok(service) => return Ok(service),
+                Err(e) => warn!("Failed to reconnect to the service of the VM: {e:?}"),
+            }
+            thread::sleep(delay);
+            delay *= 2;
+        }
+        (self.connect)()
+    }
+}
+
+/// Whether the call failed because the session with the server is gone.
+fn is_dead_session(status: &Status) -> bool {
+    status.exception_code() == ExceptionCode::TRANSACTION_FAILED
+        && status.transaction_error() == StatusCode::DEAD_OBJECT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    /// Connects to sessions numbered from 0, of which only the latest is alive.
+    fn reconnecting(
+        sessions: Arc<AtomicU32>,
+        on_reconnect: Option<Box<dyn Fn(&u32) + Send + Sync>>,
+    ) -> Reconnecting<u32> {
+        let connect = Box::new(move || Ok(sessions.fetch_add(1, Ordering::SeqCst)));
+        Reconnecting::new(connect, on_reconnect).unwrap()
+    }
+
+    fn dead_session() -> Status {
+        Status::from(StatusCode::DEAD_OBJECT)
+    }
+
+    #[test]
+    fn call_reconnects_without_repeating() {
+        let sessions = Arc::new(AtomicU32::new(0));
+        let service = reconnecting(sessions.clone(), None);
+        let calls = AtomicU32::new(0);
+
+        let result = service.call(|_| -> BinderResult<()> {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(dead_session())
+        });
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(service.service(), 1);
+        assert_eq!(service.call(|&session| Ok(session)).unwrap(), 1);
+    }
+
+    #[test]
+    fn call_idempotent_repeats_on_new_session() {
+        let sessions = Arc::new(AtomicU32::new(0));
+        let service = reconnecting(sessions.clone(), None);
+
+        // Only the first session is dead.
+        let call = |&session: &u32| if session == 0 { Err(dead_session()) } else { Ok(session) };
+        assert_eq!(service.call_idempotent(call).unwrap(), 1);
+    }
+
+    #[test]
+    fn other_errors_keep_session() {
+        let sessions = Arc::new(AtomicU32::new(0));
+        let service = reconnecting(sessions.clone(), None);
+
+        let result = service.call_idempotent(|_| -> BinderResult<()> {
+            Err(Status::new_exception(ExceptionCode::ILLEGAL_ARGUMENT, None))
+        });
+        assert!(result.is_err());
+        assert_eq!(sessions.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn reconnects_once_per_dead_session() {
+        let sessions = Arc::new(AtomicU32::new(0));
+        let reconnections = Arc::new(AtomicU32::new(0));
+        let counter = reconnections.clone();
+        let on_reconnect = Box::new(move |_: &u32| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let service = reconnecting(sessions.clone(), Some(on_reconnect));
+
+        // Both callers saw the first session die, but only the first one sets up a new session.
+        assert_eq!(service.reconnect(0).unwrap(), 1);
+        assert_eq!(service.reconnect(0).unwrap(), 1);
+        assert_eq!(sessions.load(Ordering::SeqCst), 2);
+        assert_eq!(reconnections.load(Ordering::SeqCst), 1);
+    }
+}
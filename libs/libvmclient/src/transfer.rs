@@ -0,0 +1,231 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Transfer of large files, e.g. APKs and disk images, to the working directories of VMs.
+
+use crate::errors::TransferError;
+use openssl::sha::Sha256;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Size of the chunks in which files are copied, and progress is reported.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// How far a transfer went, see [`TransferOptions::on_progress`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TransferProgress {
+    /// The number of bytes written so far.
+    pub transferred: u64,
+    /// The total number of bytes to write, if known.
+    pub total: Option<u64>,
+}
+
+/// Options of [`transfer_file`].
+#[derive(Default)]
+pub struct TransferOptions<'a> {
+    /// The size of the source, to report the progress of the transfer against.
+    pub total_size: Option<u64>,
+    /// The SHA-256 digest which the transferred content must have.
+    pub expected_sha256: Option<[u8; 32]>,
+    /// Called after each chunk is written, and once the transfer is complete.
+    pub on_progress: Option<&'a mut dyn FnMut(TransferProgress)>,
+    /// Checks the transferred file, e.g. against the root hash of its idsig, before it replaces
+    /// the destination. Returns whether the file is valid.
+    pub verify: Option<&'a mut dyn FnMut(&mut File) -> io::Result<bool>>,
+}
+
+/// A file which is written at a temporary path next to its destination, and only replaces the
+/// destination once it is complete, so that readers never see a partially written file.
+///
+/// The temporary file is removed if the `AtomicFile` is dropped without being committed.
+pub struct AtomicFile {
+    file: File,
+    temporary_path: PathBuf,
+    destination: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    /// Creates the temporary file for `destination`, replacing any left by an interrupted write.
+    pub fn create(destination: &Path) -> io::Result<Self> {
+        let mut temporary_name = destination.file_name().unwrap_or_default().to_owned();
+        temporary_name.push(".tmp");
+        let temporary_path = destination.with_file_name(temporary_name);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temporary_path)?;
+        Ok(Self { file, temporary_path, destination: destination.to_owned(), committed: false })
+    }
+
+    /// Returns the temporary file, e.g. to pass it to the service.
+    pub fn file(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    /// Flushes the temporary file to disk and renames it to the destination.
+    pub fn commit(mut self) -> io::Result<()> {
+        self.file.sync_all()?;
+        fs::rename(&self.temporary_path, &self.destination)?;
+        self.committed = true;
+        // Persist the rename too.
+        if let Some(parent) = self.destination.parent().filter(|p| !p.as_os_str().is_empty()) {
+            File::open(parent)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            // Best effort: a leftover file is replaced by the next write anyway.
+            let _ = fs::remove_file(&self.temporary_path);
+        }
+    }
+}
+
+/// Copies `source` to `destination`, reporting the progress of the copy, and verifying the
+/// content before atomically replacing `destination` with it. Returns the SHA-256 digest of the
+/// content.
+pub fn transfer_file(
+    source: &mut dyn Read,
+    destination: &Path,
+    mut options: TransferOptions,
+) -> Result<[u8; 32], TransferError> {
+    let mut output = AtomicFile::create(destination)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut transferred = 0;
+    loop {
+        let size = match source.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(size) => size,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        hasher.update(&buffer[..size]);
+        output.file().write_all(&buffer[..size])?;
+        transferred += size as u64;
+        if let Some(on_progress) = options.on_progress.as_mut() {
+            on_progress(TransferProgress { transferred, total: options.total_size });
+        }
+    }
+    if let Some(total) = options.total_size.filter(|&total| total != transferred) {
+        return Err(TransferError::SizeMismatch { expected: total, actual: transferred });
+    }
+
+    let digest = hasher.finish();
+    if let Some(expected) = options.expected_sha256.filter(|expected| *expected != digest) {
+        return Err(TransferError::DigestMismatch {
+            expected: to_hex(&expected),
+            actual: to_hex(&digest),
+        });
+    }
+    if let Some(verify) = options.verify.as_mut() {
+        output.file().seek(SeekFrom::Start(0))?;
+        if !verify(output.file())? {
+            return Err(TransferError::VerificationFailed);
+        }
+    }
+    output.commit()?;
+    Ok(digest)
+}
+
+/// Writes `data` to `destination` atomically, e.g. for small files describing a VM instance,
+/// which must not be left truncated if the writer is interrupted.
+pub fn write_file_atomically(destination: &Path, data: &[u8]) -> io::Result<()> {
+    let mut output = AtomicFile::create(destination)?;
+    output.file().write_all(data)?;
+    output.commit()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::sha::sha256;
+
+    #[test]
+    fn transfers_and_reports_progress() -> Result<(), TransferError> {
+        let dir = tempfile::tempdir()?;
+        let destination = dir.path().join("image.img");
+        let data = vec![0xa5; CHUNK_SIZE * 2 + 1];
+        let mut progress = vec![];
+        let mut on_progress = |p: TransferProgress| progress.push(p.transferred);
+        let options = TransferOptions {
+            total_size: Some(data.len() as u64),
+            expected_sha256: Some(sha256(&data)),
+            on_progress: Some(&mut on_progress),
+            ..Default::default()
+        };
+
+        let digest = transfer_file(&mut data.as_slice(), &destination, options)?;
+
+        assert_eq!(digest, sha256(&data));
+        assert_eq!(fs::read(&destination)?, data);
+        assert_eq!(progress.last(), Some(&(data.len() as u64)));
+        assert!(!dir.path().join("image.img.tmp").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_destination_on_digest_mismatch() -> Result<(), TransferError> {
+        let dir = tempfile::tempdir()?;
+        let destination = dir.path().join("image.img");
+        fs::write(&destination, b"old")?;
+        let options = TransferOptions { expected_sha256: Some([0; 32]), ..Default::default() };
+
+        let result = transfer_file(&mut &b"new"[..], &destination, options);
+
+        assert!(matches!(result, Err(TransferError::DigestMismatch { .. })));
+        assert_eq!(fs::read(&destination)?, b"old");
+        assert!(!dir.path().join("image.img.tmp").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_file_failing_verification() -> Result<(), TransferError> {
+        let dir = tempfile::tempdir()?;
+        let destination = dir.path().join("app.apk");
+        let mut verify = |file: &mut File| -> io::Result<bool> {
+            let mut content = vec![];
+            file.read_to_end(&mut content)?;
+            Ok(content == b"valid")
+        };
+        let options = TransferOptions { verify: Some(&mut verify), ..Default::default() };
+
+        let result = transfer_file(&mut &b"invalid"[..], &destination, options);
+
+        assert!(matches!(result, Err(TransferError::VerificationFailed)));
+        assert!(!destination.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn writes_file_atomically() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let destination = dir.path().join("instance_id");
+        write_file_atomically(&destination, &[1; 64])?;
+        write_file_atomically(&destination, &[2; 64])?;
+        assert_eq!(fs::read(&destination)?, [2u8; 64]);
+        Ok(())
+    }
+}
@@ -243,6 +243,12 @@ public class IsolatedCompilationJobService extends JobService {
                     result = IsolatedCompilationMetrics.RESULT_FAILED_TO_ENABLE_FSVERITY;
                     break;
 
+                case ICompilationTaskCallback.FailureReason.Cancelled:
+                    // Either we cancelled the task, and already reported it, or another client
+                    // did, e.g. ahead of an OTA.
+                    result = IsolatedCompilationMetrics.RESULT_JOB_CANCELED;
+                    break;
+
                 default:
                     result = IsolatedCompilationMetrics.RESULT_UNKNOWN_FAILURE;
                     break;
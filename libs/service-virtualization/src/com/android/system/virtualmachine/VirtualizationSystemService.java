@@ -43,7 +43,8 @@ import java.nio.file.Paths;
  * framework.
  *
  * <p>It currently is responsible for Secretkeeper-related maintenance - ensuring that we are not
- * storing secrets for apps or users that no longer exist.
+ * storing secrets for apps or users that no longer exist. It also provides VM tethering, and
+ * reports the network usage of VMs.
  */
 public class VirtualizationSystemService extends SystemService {
     private static final String TAG = VirtualizationSystemService.class.getName();
@@ -51,6 +52,7 @@ public class VirtualizationSystemService extends SystemService {
             "android.system.virtualizationmaintenance";
     private Handler mHandler;
     private final TetheringService mTetheringService;
+    private volatile VmNetworkStatsProvider mNetworkStatsProvider;
 
     public VirtualizationSystemService(Context context) {
         super(context);
@@ -75,6 +77,12 @@ public class VirtualizationSystemService extends SystemService {
         mHandler = BackgroundThread.getHandler();
         new Receiver().registerForBroadcasts();
 
+        if (mTetheringService != null) {
+            VmNetworkStatsProvider provider = new VmNetworkStatsProvider(getContext(), mHandler);
+            provider.register();
+            mNetworkStatsProvider = provider;
+        }
+
         SecretkeeperJobService.scheduleJob(getContext().getSystemService(JobScheduler.class));
     }
 
@@ -184,5 +192,23 @@ public class VirtualizationSystemService extends SystemService {
         public void disableVmTethering() {
             tm.stopTethering(TetheringManager.TETHERING_VIRTUAL);
         }
+
+        @Override
+        public void startVmNetworkAccounting(String ifaceName, int ownerUid) {
+            VmNetworkStatsProvider provider = mNetworkStatsProvider;
+            if (provider == null) {
+                Log.w(TAG, "Not accounting traffic of " + ifaceName + " before boot completed");
+                return;
+            }
+            provider.startAccounting(ifaceName, ownerUid);
+        }
+
+        @Override
+        public void stopVmNetworkAccounting(String ifaceName) {
+            VmNetworkStatsProvider provider = mNetworkStatsProvider;
+            if (provider != null) {
+                provider.stopAccounting(ifaceName);
+            }
+        }
     }
 }
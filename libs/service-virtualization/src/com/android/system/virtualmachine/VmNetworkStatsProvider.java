@@ -0,0 +1,225 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package com.android.system.virtualmachine;
+
+import static android.app.ActivityManager.RunningAppProcessInfo.IMPORTANCE_FOREGROUND_SERVICE;
+import static android.net.NetworkCapabilities.NET_CAPABILITY_NOT_METERED;
+import static android.net.NetworkCapabilities.NET_CAPABILITY_NOT_ROAMING;
+import static android.net.NetworkStats.DEFAULT_NETWORK_NO;
+import static android.net.NetworkStats.METERED_NO;
+import static android.net.NetworkStats.METERED_YES;
+import static android.net.NetworkStats.ROAMING_NO;
+import static android.net.NetworkStats.ROAMING_YES;
+import static android.net.NetworkStats.SET_DEFAULT;
+import static android.net.NetworkStats.SET_FOREGROUND;
+import static android.net.NetworkStats.TAG_NONE;
+import static android.net.NetworkStats.UID_TETHERING;
+
+import android.app.ActivityManager;
+import android.app.usage.NetworkStatsManager;
+import android.content.Context;
+import android.net.ConnectivityManager;
+import android.net.LinkProperties;
+import android.net.Network;
+import android.net.NetworkCapabilities;
+import android.net.NetworkStats;
+import android.net.TetheringManager;
+import android.net.TetheringManager.TetheringEventCallback;
+import android.net.netstats.provider.NetworkStatsProvider;
+import android.os.Handler;
+import android.os.SystemClock;
+import android.util.Log;
+
+import java.io.IOException;
+import java.nio.file.Files;
+import java.nio.file.Path;
+import java.nio.file.Paths;
+
+/**
+ * Reports the network usage of VMs to NetworkStatsService, so that it shows up in the data usage
+ * of the apps owning the VMs.
+ *
+ * <p>The traffic of a VM leaves the device through the upstream network of VM tethering, so it is
+ * reported on the upstream interface, as metered and roaming as the upstream network is. Tethering
+ * already accounts that traffic to {@link NetworkStats#UID_TETHERING}, so it is taken off that uid
+ * as it is attributed to the owner of the VM, and counted only once.
+ */
+final class VmNetworkStatsProvider extends NetworkStatsProvider {
+    private static final String TAG = VmNetworkStatsProvider.class.getName();
+    private static final Path SYS_CLASS_NET = Paths.get("/sys/class/net");
+
+    private final Context mContext;
+    private final Handler mHandler;
+    private final VmNetworkUsageTracker mTracker =
+            new VmNetworkUsageTracker(VmNetworkStatsProvider::readCounters);
+    private volatile Upstream mUpstream;
+
+    /** The upstream network of VM tethering. */
+    private static final class Upstream {
+        final String iface;
+        final int metered;
+        final int roaming;
+
+        Upstream(String iface, int metered, int roaming) {
+            this.iface = iface;
+            this.metered = metered;
+            this.roaming = roaming;
+        }
+    }
+
+    VmNetworkStatsProvider(Context context, Handler handler) {
+        mContext = context;
+        mHandler = handler;
+    }
+
+    /** Starts receiving the events needed to attribute traffic, and reporting it. */
+    void register() {
+        ActivityManager am = mContext.getSystemService(ActivityManager.class);
+        am.addOnUidImportanceListener(
+                (uid, importance) ->
+                        mHandler.post(() -> onUidForegroundChanged(uid, isForeground(importance))),
+                IMPORTANCE_FOREGROUND_SERVICE);
+
+        TetheringManager tm = mContext.getSystemService(TetheringManager.class);
+        tm.registerTetheringEventCallback(
+                mHandler::post,
+                new TetheringEventCallback() {
+                    @Override
+                    public void onUpstreamChanged(Network network) {
+                        mUpstream = getUpstream(network);
+                    }
+                });
+
+        NetworkStatsManager nsm = mContext.getSystemService(NetworkStatsManager.class);
+        nsm.registerNetworkStatsProvider(TAG, this);
+    }
+
+    void startAccounting(String iface, int ownerUid) {
+        ActivityManager am = mContext.getSystemService(ActivityManager.class);
+        boolean foreground = isForeground(am.getUidImportance(ownerUid));
+        try {
+            mTracker.addInterface(iface, ownerUid, foreground);
+        } catch (IOException e) {
+            Log.e(TAG, "Failed to start accounting traffic of " + iface, e);
+        }
+    }
+
+    void stopAccounting(String iface) {
+        try {
+            mTracker.removeInterface(iface);
+        } catch (IOException e) {
+            Log.e(TAG, "Failed to account remaining traffic of " + iface, e);
+        }
+    }
+
+    @Override
+    public void onRequestStatsUpdate(int token) {
+        NetworkStats ifaceStats = new NetworkStats(SystemClock.elapsedRealtime(), 0);
+        NetworkStats uidStats = new NetworkStats(SystemClock.elapsedRealtime(), 0);
+        Upstream upstream = mUpstream;
+        try {
+            for (VmNetworkUsageTracker.Usage usage : mTracker.takeUsage()) {
+                if (upstream == null) {
+                    Log.w(TAG, "Dropping VM traffic of uid " + usage.uid + " without upstream");
+                    continue;
+                }
+                int set = usage.foreground ? SET_FOREGROUND : SET_DEFAULT;
+                uidStats = uidStats.addEntry(toEntry(upstream, usage.uid, set, usage, 1));
+                // Tethering accounts all of its traffic in the default set.
+                uidStats =
+                        uidStats.addEntry(toEntry(upstream, UID_TETHERING, SET_DEFAULT, usage, -1));
+            }
+        } catch (IOException e) {
+            Log.e(TAG, "Failed to read VM traffic", e);
+        }
+        // The traffic is already included in the counters of the upstream interface.
+        notifyStatsUpdated(token, ifaceStats, uidStats);
+    }
+
+    @Override
+    public void onSetLimit(String iface, long quotaBytes) {
+        // VM traffic is only reported, limits are enforced on the upstream interface.
+    }
+
+    @Override
+    public void onSetAlert(long quotaBytes) {
+        // VM traffic is only reported, alerts are raised for the upstream interface.
+    }
+
+    private void onUidForegroundChanged(int uid, boolean foreground) {
+        try {
+            mTracker.setUidForeground(uid, foreground);
+        } catch (IOException e) {
+            Log.e(TAG, "Failed to account VM traffic of uid " + uid, e);
+        }
+    }
+
+    private Upstream getUpstream(Network network) {
+        if (network == null) return null;
+        ConnectivityManager cm = mContext.getSystemService(ConnectivityManager.class);
+        LinkProperties lp = cm.getLinkProperties(network);
+        NetworkCapabilities nc = cm.getNetworkCapabilities(network);
+        if (lp == null || lp.getInterfaceName() == null || nc == null) return null;
+        return new Upstream(
+                lp.getInterfaceName(),
+                nc.hasCapability(NET_CAPABILITY_NOT_METERED) ? METERED_NO : METERED_YES,
+                nc.hasCapability(NET_CAPABILITY_NOT_ROAMING) ? ROAMING_NO : ROAMING_YES);
+    }
+
+    private static boolean isForeground(int importance) {
+        return importance <= IMPORTANCE_FOREGROUND_SERVICE;
+    }
+
+    /** Returns an entry of {@code usage} for {@code uid}, negated if {@code sign} is -1. */
+    private static NetworkStats.Entry toEntry(
+            Upstream upstream, int uid, int set, VmNetworkUsageTracker.Usage usage, int sign) {
+        return new NetworkStats.Entry(
+                upstream.iface,
+                uid,
+                set,
+                TAG_NONE,
+                upstream.metered,
+                upstream.roaming,
+                DEFAULT_NETWORK_NO,
+                sign * usage.counters.rxBytes,
+                sign * usage.counters.rxPackets,
+                sign * usage.counters.txBytes,
+                sign * usage.counters.txPackets,
+                0 /* operations */);
+    }
+
+    /**
+     * Reads the counters of a TAP interface. What the host receives on the interface is what the
+     * VM transmits, and vice versa.
+     */
+    private static VmNetworkUsageTracker.Counters readCounters(String iface) throws IOException {
+        return new VmNetworkUsageTracker.Counters(
+                readStatistic(iface, "tx_bytes"),
+                readStatistic(iface, "tx_packets"),
+                readStatistic(iface, "rx_bytes"),
+                readStatistic(iface, "rx_packets"));
+    }
+
+    private static long readStatistic(String iface, String name) throws IOException {
+        Path path = SYS_CLASS_NET.resolve(iface).resolve("statistics").resolve(name);
+        try {
+            return Long.parseLong(Files.readString(path).trim());
+        } catch (NumberFormatException e) {
+            throw new IOException("Invalid content of " + path, e);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package com.android.system.virtualmachine;
+
+import java.io.IOException;
+import java.util.ArrayList;
+import java.util.HashMap;
+import java.util.List;
+import java.util.Map;
+import java.util.Objects;
+
+/**
+ * Attributes the traffic of the TAP interfaces of VMs to the apps owning the VMs.
+ *
+ * <p>The traffic is split by whether the owning app was in the foreground or not at the time, like
+ * the rest of the network usage of apps. The traffic since the last call to {@link #takeUsage} is
+ * kept, including the traffic of interfaces which have been removed in the meantime.
+ */
+final class VmNetworkUsageTracker {
+    /** Reads the cumulative counters of an interface. */
+    interface CounterReader {
+        Counters read(String iface) throws IOException;
+    }
+
+    /** Traffic counters, from the point of view of the VM. */
+    static final class Counters {
+        static final Counters ZERO = new Counters(0, 0, 0, 0);
+
+        final long rxBytes;
+        final long rxPackets;
+        final long txBytes;
+        final long txPackets;
+
+        Counters(long rxBytes, long rxPackets, long txBytes, long txPackets) {
+            this.rxBytes = rxBytes;
+            this.rxPackets = rxPackets;
+            this.txBytes = txBytes;
+            this.txPackets = txPackets;
+        }
+
+        Counters plus(Counters other) {
+            return new Counters(
+                    rxBytes + other.rxBytes,
+                    rxPackets + other.rxPackets,
+                    txBytes + other.txBytes,
+                    txPackets + other.txPackets);
+        }
+
+        /**
+         * Returns the traffic since {@code previous}. Counters which went backwards were reset,
+         * e.g. because the interface was recreated, so all of their value is new traffic.
+         */
+        Counters since(Counters previous) {
+            return new Counters(
+                    delta(rxBytes, previous.rxBytes),
+                    delta(rxPackets, previous.rxPackets),
+                    delta(txBytes, previous.txBytes),
+                    delta(txPackets, previous.txPackets));
+        }
+
+        private static long delta(long current, long previous) {
+            return current >= previous ? current - previous : current;
+        }
+
+        boolean isZero() {
+            return rxBytes == 0 && rxPackets == 0 && txBytes == 0 && txPackets == 0;
+        }
+
+        @Override
+        public boolean equals(Object o) {
+            if (!(o instanceof Counters)) return false;
+            Counters other = (Counters) o;
+            return rxBytes == other.rxBytes
+                    && rxPackets == other.rxPackets
+                    && txBytes == other.txBytes
+                    && txPackets == other.txPackets;
+        }
+
+        @Override
+        public int hashCode() {
+            return Objects.hash(rxBytes, rxPackets, txBytes, txPackets);
+        }
+
+        @Override
+        public String toString() {
+            return "Counters{rx=" + rxBytes + "B/" + rxPackets + "p, tx=" + txBytes + "B/"
+                    + txPackets + "p}";
+        }
+    }
+
+    /** Traffic of the VMs of an app, while it was in the foreground or in the background. */
+    static final class Usage {
+        final int uid;
+        final boolean foreground;
+        final Counters counters;
+
+        Usage(int uid, boolean foreground, Counters counters) {
+            this.uid = uid;
+            this.foreground = foreground;
+            this.counters = counters;
+        }
+    }
+
+    private static final class Interface {
+        final int ownerUid;
+        Counters lastCounters;
+
+        Interface(int ownerUid, Counters lastCounters) {
+            this.ownerUid = ownerUid;
+            this.lastCounters = lastCounters;
+        }
+    }
+
+    private static final class UsageKey {
+        final int uid;
+        final boolean foreground;
+
+        UsageKey(int uid, boolean foreground) {
+            this.uid = uid;
+            this.foreground = foreground;
+        }
+
+        @Override
+        public boolean equals(Object o) {
+            if (!(o instanceof UsageKey)) return false;
+            UsageKey other = (UsageKey) o;
+            return uid == other.uid && foreground == other.foreground;
+        }
+
+        @Override
+        public int hashCode() {
+            return Objects.hash(uid, foreground);
+        }
+    }
+
+    private final CounterReader mCounterReader;
+    private final Map<String, Interface> mInterfaces = new HashMap<>();
+    /** Whether the apps owning interfaces are in the foreground. */
+    private final Map<Integer, Boolean> mForegroundUids = new HashMap<>();
+    private final Map<UsageKey, Counters> mPendingUsage = new HashMap<>();
+
+    VmNetworkUsageTracker(CounterReader counterReader) {
+        mCounterReader = counterReader;
+    }
+
+    /**
+     * Starts attributing the traffic of {@code iface} to {@code ownerUid}. Only the traffic from
+     * now on is attributed to the app.
+     */
+    synchronized void addInterface(String iface, int ownerUid, boolean foreground)
+            throws IOException {
+        if (mInterfaces.containsKey(iface)) {
+            removeInterface(iface);
+        }
+        mInterfaces.put(iface, new Interface(ownerUid, mCounterReader.read(iface)));
+        mForegroundUids.put(ownerUid, foreground);
+    }
+
+    /** Stops attributing the traffic of {@code iface}, after accounting for its latest traffic. */
+    synchronized void removeInterface(String iface) throws IOException {
+        Interface removed = mInterfaces.get(iface);
+        if (removed == null) return;
+        try {
+            accountTraffic(iface, removed);
+        } finally {
+            mInterfaces.remove(iface);
+            if (!ownsInterface(removed.ownerUid)) {
+                mForegroundUids.remove(removed.ownerUid);
+            }
+        }
+    }
+
+    /**
+     * Records that {@code uid} moved to the foreground or the background. The traffic until now is
+     * attributed to its previous state.
+     */
+    synchronized void setUidForeground(int uid, boolean foreground) throws IOException {
+        Boolean wasForeground = mForegroundUids.get(uid);
+        if (wasForeground == null || wasForeground == foreground) return;
+        try {
+            for (Map.Entry<String, Interface> entry : mInterfaces.entrySet()) {
+                if (entry.getValue().ownerUid == uid) {
+                    accountTraffic(entry.getKey(), entry.getValue());
+                }
+            }
+        } finally {
+            mForegroundUids.put(uid, foreground);
+        }
+    }
+
+    /** Returns the traffic since the previous call, and forgets about it. */
+    synchronized List<Usage> takeUsage() throws IOException {
+        for (Map.Entry<String, Interface> entry : mInterfaces.entrySet()) {
+            accountTraffic(entry.getKey(), entry.getValue());
+        }
+        List<Usage> usage = new ArrayList<>();
+        for (Map.Entry<UsageKey, Counters> entry : mPendingUsage.entrySet()) {
+            UsageKey key = entry.getKey();
+            usage.add(new Usage(key.uid, key.foreground, entry.getValue()));
+        }
+        mPendingUsage.clear();
+        return usage;
+    }
+
+    private boolean ownsInterface(int uid) {
+        return mInterfaces.values().stream().anyMatch(i -> i.ownerUid == uid);
+    }
+
+    private void accountTraffic(String iface, Interface tracked) throws IOException {
+        Counters counters = mCounterReader.read(iface);
+        Counters traffic = counters.since(tracked.lastCounters);
+        tracked.lastCounters = counters;
+        if (traffic.isZero()) return;
+        boolean foreground = mForegroundUids.getOrDefault(tracked.ownerUid, false);
+        mPendingUsage.merge(new UsageKey(tracked.ownerUid, foreground), traffic, Counters::plus);
+    }
+}
@@ -0,0 +1,170 @@
+/*
+ * Copyright (C) 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package com.android.system.virtualmachine;
+
+import static com.google.common.truth.Truth.assertThat;
+
+import com.android.system.virtualmachine.VmNetworkUsageTracker.Counters;
+import com.android.system.virtualmachine.VmNetworkUsageTracker.Usage;
+
+import org.junit.Before;
+import org.junit.Test;
+import org.junit.runner.RunWith;
+import org.junit.runners.JUnit4;
+
+import java.io.IOException;
+import java.util.HashMap;
+import java.util.List;
+import java.util.Map;
+
+@RunWith(JUnit4.class)
+public class VmNetworkUsageTrackerTest {
+    private static final String IFACE = "avf_tap_fixed";
+    private static final String OTHER_IFACE = "avf_tap_other";
+    private static final int UID = 10123;
+    private static final int OTHER_UID = 10456;
+
+    private final Map<String, Counters> mCounters = new HashMap<>();
+    private VmNetworkUsageTracker mTracker;
+
+    @Before
+    public void setUp() {
+        mTracker =
+                new VmNetworkUsageTracker(
+                        iface -> {
+                            Counters counters = mCounters.get(iface);
+                            if (counters == null) throw new IOException("No interface " + iface);
+                            return counters;
+                        });
+    }
+
+    private void setCounters(String iface, long rxBytes, long txBytes) {
+        mCounters.put(iface, new Counters(rxBytes, rxBytes / 100, txBytes, txBytes / 100));
+    }
+
+    private static Counters traffic(long rxBytes, long txBytes) {
+        return new Counters(rxBytes, rxBytes / 100, txBytes, txBytes / 100);
+    }
+
+    private static Map<String, Counters> byOwner(List<Usage> usage) {
+        Map<String, Counters> result = new HashMap<>();
+        for (Usage u : usage) {
+            result.put(u.uid + (u.foreground ? "/fg" : "/bg"), u.counters);
+        }
+        return result;
+    }
+
+    @Test
+    public void onlyTrafficAfterStartIsAttributed() throws Exception {
+        setCounters(IFACE, 1000, 500);
+        mTracker.addInterface(IFACE, UID, /* foreground= */ false);
+        setCounters(IFACE, 3000, 1500);
+
+        assertThat(byOwner(mTracker.takeUsage()))
+                .containsExactly(UID + "/bg", traffic(2000, 1000));
+    }
+
+    @Test
+    public void foregroundTrafficIsAttributedToForegroundSet() throws Exception {
+        setCounters(IFACE, 0, 0);
+        mTracker.addInterface(IFACE, UID, /* foreground= */ true);
+        setCounters(IFACE, 4000, 200);
+
+        assertThat(byOwner(mTracker.takeUsage()))
+                .containsExactly(UID + "/fg", traffic(4000, 200));
+    }
+
+    @Test
+    public void trafficIsSplitWhenAppChangesState() throws Exception {
+        setCounters(IFACE, 0, 0);
+        mTracker.addInterface(IFACE, UID, /* foreground= */ true);
+        setCounters(IFACE, 1000, 100);
+        mTracker.setUidForeground(UID, false);
+        setCounters(IFACE, 1500, 400);
+        mTracker.setUidForeground(UID, true);
+        setCounters(IFACE, 2500, 500);
+
+        assertThat(byOwner(mTracker.takeUsage()))
+                .containsExactly(
+                        UID + "/fg", traffic(2000, 200),
+                        UID + "/bg", traffic(500, 300));
+    }
+
+    @Test
+    public void stateChangesOfOtherAppsAreIgnored() throws Exception {
+        setCounters(IFACE, 0, 0);
+        mTracker.addInterface(IFACE, UID, /* foreground= */ false);
+        setCounters(IFACE, 1000, 100);
+        mTracker.setUidForeground(OTHER_UID, true);
+        setCounters(IFACE, 2000, 200);
+
+        assertThat(byOwner(mTracker.takeUsage()))
+                .containsExactly(UID + "/bg", traffic(2000, 200));
+    }
+
+    @Test
+    public void usageIsOnlyReportedOnce() throws Exception {
+        setCounters(IFACE, 0, 0);
+        mTracker.addInterface(IFACE, UID, /* foreground= */ false);
+        setCounters(IFACE, 1000, 100);
+        mTracker.takeUsage();
+
+        assertThat(mTracker.takeUsage()).isEmpty();
+
+        setCounters(IFACE, 1200, 300);
+        assertThat(byOwner(mTracker.takeUsage()))
+                .containsExactly(UID + "/bg", traffic(200, 200));
+    }
+
+    @Test
+    public void trafficOfRemovedInterfaceIsReported() throws Exception {
+        setCounters(IFACE, 0, 0);
+        mTracker.addInterface(IFACE, UID, /* foreground= */ true);
+        setCounters(IFACE, 700, 300);
+        mTracker.removeInterface(IFACE);
+        mCounters.remove(IFACE);
+
+        assertThat(byOwner(mTracker.takeUsage()))
+                .containsExactly(UID + "/fg", traffic(700, 300));
+        assertThat(mTracker.takeUsage()).isEmpty();
+    }
+
+    @Test
+    public void interfacesAreAttributedToTheirOwners() throws Exception {
+        setCounters(IFACE, 0, 0);
+        setCounters(OTHER_IFACE, 0, 0);
+        mTracker.addInterface(IFACE, UID, /* foreground= */ true);
+        mTracker.addInterface(OTHER_IFACE, OTHER_UID, /* foreground= */ false);
+        setCounters(IFACE, 100, 200);
+        setCounters(OTHER_IFACE, 300, 400);
+
+        assertThat(byOwner(mTracker.takeUsage()))
+                .containsExactly(
+                        UID + "/fg", traffic(100, 200),
+                        OTHER_UID + "/bg", traffic(300, 400));
+    }
+
+    @Test
+    public void resetCountersAreTreatedAsNewTraffic() throws Exception {
+        setCounters(IFACE, 5000, 5000);
+        mTracker.addInterface(IFACE, UID, /* foreground= */ false);
+        setCounters(IFACE, 100, 200);
+
+        assertThat(byOwner(mTracker.takeUsage()))
+                .containsExactly(UID + "/bg", traffic(100, 200));
+    }
+}
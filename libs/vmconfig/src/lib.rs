@@ -18,10 +18,12 @@ use android_system_virtualizationservice::{
     aidl::android::system::virtualizationservice::CpuTopology::CpuTopology,
     aidl::android::system::virtualizationservice::DiskImage::DiskImage as AidlDiskImage,
     aidl::android::system::virtualizationservice::Partition::Partition as AidlPartition,
+    aidl::android::system::virtualizationservice::PortForwardingRule::PortForwardingRule as AidlPortForwardingRule,
     aidl::android::system::virtualizationservice::UsbConfig::UsbConfig as AidlUsbConfig,
     aidl::android::system::virtualizationservice::VirtualMachineAppConfig::DebugLevel::DebugLevel,
     aidl::android::system::virtualizationservice::VirtualMachineConfig::VirtualMachineConfig,
     aidl::android::system::virtualizationservice::VirtualMachineRawConfig::VirtualMachineRawConfig,
+    aidl::android::system::virtualizationservice::VsockFirewall::VsockFirewall as AidlVsockFirewall,
     binder::ParcelFileDescriptor,
 };
 
@@ -71,6 +73,21 @@ pub struct VmConfig {
     pub console_input_device: Option<String>,
     /// The USB config of the VM.
     pub usb_config: Option<UsbConfig>,
+    /// Rules for forwarding host abstract sockets to vsock ports of the VM.
+    #[serde(default)]
+    pub port_forwarding_rules: Vec<PortForwardingRule>,
+    /// Vsock ports through which the VM and the host may connect to each other, if restricted.
+    pub vsock_firewall: Option<VsockFirewall>,
+    /// Paths to vendor device tree overlays (.dtbo) describing additional devices of the VM.
+    #[serde(default)]
+    pub vendor_dt_overlays: Vec<PathBuf>,
+    /// Whether crosvm runs in the more confined domain for VMs mounting images supplied by the
+    /// vendor.
+    #[serde(default)]
+    pub vendor_domain: bool,
+    /// Names of the vhost-user backends, registered by host daemons, to attach to the VM.
+    #[serde(default)]
+    pub vhost_user_devices: Vec<String>,
 }
 
 impl VmConfig {
@@ -133,6 +150,23 @@ impl VmConfig {
                 .collect::<Result<_>>()?,
             consoleInputDevice: self.console_input_device.clone(),
             usbConfig: usb_config,
+            portForwardingRules: self
+                .port_forwarding_rules
+                .iter()
+                .map(PortForwardingRule::to_parcelable)
+                .collect::<Result<_>>()?,
+            vsockFirewall: self
+                .vsock_firewall
+                .as_ref()
+                .map(VsockFirewall::to_parcelable)
+                .transpose()?,
+            vendorDtOverlays: self
+                .vendor_dt_overlays
+                .iter()
+                .map(|x| open_parcel_file(x, false))
+                .collect::<Result<_>>()?,
+            vendorDomain: self.vendor_domain,
+            vhostUserDevices: self.vhost_user_devices.clone(),
             ..Default::default()
         })
     }
@@ -158,6 +192,9 @@ pub struct DiskImage {
     pub partitions: Vec<Partition>,
     /// Whether this disk should be writable by the VM.
     pub writable: bool,
+    /// Whether this disk should be accessed with O_DIRECT, bypassing the host page cache.
+    #[serde(default)]
+    pub direct_io: bool,
 }
 
 impl DiskImage {
@@ -168,6 +205,7 @@ impl DiskImage {
             image: maybe_open_parcel_file(&self.image, self.writable)?,
             writable: self.writable,
             partitions,
+            directIo: self.direct_io,
         })
     }
 }
@@ -185,6 +223,9 @@ pub struct Partition {
     /// GUID of this partition.
     #[serde(default)]
     pub guid: Option<Uuid>,
+    /// GPT partition type GUID of this partition, the Linux filesystem data type by default.
+    #[serde(default)]
+    pub type_guid: Option<Uuid>,
 }
 
 impl Partition {
@@ -194,6 +235,7 @@ impl Partition {
             writable: self.writable,
             label: self.label.to_owned(),
             guid: None,
+            typeGuid: self.type_guid.map(|type_guid| type_guid.to_string()),
         })
     }
 }
@@ -211,6 +253,50 @@ impl UsbConfig {
     }
 }
 
+/// A rule forwarding connections on a host abstract socket to a vsock port of the VM.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PortForwardingRule {
+    /// Name of the abstract socket to listen on in the host.
+    pub host_socket_name: String,
+    /// The vsock port of the VM to forward connections to.
+    pub guest_port: u32,
+}
+
+impl PortForwardingRule {
+    fn to_parcelable(&self) -> Result<AidlPortForwardingRule> {
+        Ok(AidlPortForwardingRule {
+            hostSocketName: self.host_socket_name.clone(),
+            guestPort: self.guest_port.try_into().context("Invalid guest_port")?,
+        })
+    }
+}
+
+/// Allow-lists of the vsock ports through which the VM and the host may connect to each other.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct VsockFirewall {
+    /// The vsock ports of the VM which host processes may connect to.
+    #[serde(default)]
+    pub allowed_guest_ports: Vec<u32>,
+    /// The vsock ports of the host which the VM may connect to.
+    #[serde(default)]
+    pub allowed_host_ports: Vec<u32>,
+}
+
+impl VsockFirewall {
+    fn to_parcelable(&self) -> Result<AidlVsockFirewall> {
+        Ok(AidlVsockFirewall {
+            allowedGuestPorts: to_aidl_ports(&self.allowed_guest_ports)
+                .context("Invalid allowed_guest_ports")?,
+            allowedHostPorts: to_aidl_ports(&self.allowed_host_ports)
+                .context("Invalid allowed_host_ports")?,
+        })
+    }
+}
+
+fn to_aidl_ports(ports: &[u32]) -> Result<Vec<i32>> {
+    Ok(ports.iter().map(|&port| port.try_into()).collect::<Result<_, _>>()?)
+}
+
 /// Try to open the given file and wrap it in a [`ParcelFileDescriptor`].
 pub fn open_parcel_file(filename: &Path, writable: bool) -> Result<ParcelFileDescriptor> {
     Ok(ParcelFileDescriptor::new(
@@ -14,36 +14,357 @@
  * limitations under the License.
  */
 
-//! Manages running instances of the Microfuchsia VM.
-//! At most one instance should be running at a time.
+//! Manages running instances of the Microfuchsia VM, keyed by their names.
+//! Instances which die unexpectedly are restarted, with an exponential backoff.
 
 use crate::instance_starter::{InstanceStarter, MicrofuchsiaInstance};
 use android_system_virtualizationservice::aidl::android::system::virtualizationservice;
-use anyhow::{bail, Result};
-use binder::Strong;
+use anyhow::{bail, Context, Result};
+use binder::{ParcelFileDescriptor, Strong};
+use log::{error, info, warn};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
 use virtualizationservice::IVirtualizationService::IVirtualizationService;
+use vmclient::{DeathReason, VmCallback};
 
+/// Delay before the first restart of an instance which died.
+const INITIAL_RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// The restart delay doubles each time an instance dies, up to this.
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// An instance which ran for this long before dying is considered to have been healthy, so it is
+/// restarted after the initial delay again.
+const HEALTHY_RUN_TIME: Duration = Duration::from_secs(10 * 60);
+
+/// How long an instance has to power off before it is stopped forcibly.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The state of an instance, as reported to clients.
+pub struct InstanceStatus {
+    pub name: String,
+    /// The CID and console pty of the VM, if it is running.
+    pub vm: Option<(i32, String)>,
+}
+
+#[derive(Clone)]
 pub struct InstanceManager {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
     service: Strong<dyn IVirtualizationService>,
-    started: bool,
+    instances: BTreeMap<String, Instance>,
+    next_generation: u64,
+}
+
+struct Instance {
+    /// Allocated by the VirtualizationService when the instance is started, and kept across
+    /// restarts of its VM.
+    id: [u8; 64],
+    /// Changes each time the VM of the instance is replaced, so that notifications about the
+    /// death of a previous VM are ignored.
+    generation: u64,
+    /// The VM, unless it died and is waiting to be restarted.
+    vm: Option<MicrofuchsiaInstance>,
+    started_at: Instant,
+    restart_delay: Duration,
+}
+
+impl State {
+    fn new_generation(&mut self) -> u64 {
+        self.next_generation += 1;
+        self.next_generation
+    }
+
+    fn status(&self, name: &str) -> Option<InstanceStatus> {
+        let instance = self.instances.get(name)?;
+        let vm = instance
+            .vm
+            .as_ref()
+            .filter(|vm| vm.is_running())
+            .map(|vm| (vm.cid(), vm.console_pty().to_owned()));
+        Some(InstanceStatus { name: name.to_owned(), vm })
+    }
 }
 
 impl InstanceManager {
     pub fn new(service: Strong<dyn IVirtualizationService>) -> Self {
-        Self { service, started: false }
+        let state = State { service, instances: BTreeMap::new(), next_generation: 0 };
+        Self { state: Arc::new(Mutex::new(state)) }
+    }
+
+    /// Returns whether an instance with the given name exists, even if it is being restarted.
+    pub fn has_instance(&self, name: &str) -> bool {
+        self.state.lock().unwrap().instances.contains_key(name)
     }
 
-    pub fn start_instance(&mut self) -> Result<MicrofuchsiaInstance> {
-        if self.started {
-            bail!("Cannot start multiple microfuchsia instances");
+    /// Starts a new instance with the given name, which must not be used by another instance.
+    pub fn start_instance(&self, name: &str) -> Result<InstanceStatus> {
+        if name.is_empty() {
+            bail!("Instance name must not be empty");
+        }
+        if self.has_instance(name) {
+            bail!("Microfuchsia instance {name:?} already exists");
+        }
+
+        let service = self.service();
+        let id = service.allocateInstanceId().context("Allocating instance ID")?;
+        let generation = {
+            let mut state = self.state.lock().unwrap();
+            if state.instances.contains_key(name) {
+                drop(state);
+                remove_instance_id(&*service, name, &id);
+                bail!("Microfuchsia instance {name:?} already exists");
+            }
+            let generation = state.new_generation();
+            // The instance is registered while its VM boots, so that its name can't be taken.
+            let instance = Instance {
+                id,
+                generation,
+                vm: None,
+                started_at: Instant::now(),
+                restart_delay: INITIAL_RESTART_DELAY,
+            };
+            state.instances.insert(name.to_owned(), instance);
+            generation
+        };
+        match self.boot_vm(name, id, generation) {
+            Ok(Some(status)) => Ok(status),
+            Ok(None) => {
+                bail!("Microfuchsia instance {name:?} was stopped or restarted while starting")
+            }
+            Err(e) => {
+                let mut state = self.state.lock().unwrap();
+                if state.instances.get(name).is_some_and(|i| i.generation == generation) {
+                    state.instances.remove(name);
+                    drop(state);
+                    remove_instance_id(&*service, name, &id);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Shuts down the instance with the given name, and stops supervising it. Returns false if no
+    /// instance has that name.
+    pub fn stop_instance(&self, name: &str) -> Result<bool> {
+        let (instance, service) = {
+            let mut state = self.state.lock().unwrap();
+            let Some(instance) = state.instances.remove(name) else {
+                return Ok(false);
+            };
+            (instance, state.service.clone())
+        };
+        if let Some(vm) = instance.vm {
+            vm.shutdown(SHUTDOWN_TIMEOUT)?;
+        }
+        remove_instance_id(&*service, name, &instance.id);
+        info!("Stopped {name} instance");
+        Ok(true)
+    }
+
+    /// Shuts down the VM of the instance with the given name if it is running, and starts a new
+    /// one. The restart delay of the instance is reset. Returns `None` if no instance has that
+    /// name, including if it is stopped concurrently.
+    pub fn restart_instance(&self, name: &str) -> Result<Option<InstanceStatus>> {
+        let (vm, generation) = {
+            let mut state = self.state.lock().unwrap();
+            let generation = state.new_generation();
+            let Some(instance) = state.instances.get_mut(name) else {
+                return Ok(None);
+            };
+            // Ignore the death of the current VM, which is shut down without holding the lock.
+            instance.generation = generation;
+            (instance.vm.take(), generation)
+        };
+        if let Some(vm) = vm {
+            vm.shutdown(SHUTDOWN_TIMEOUT)?;
         }
 
-        let instance_starter = InstanceStarter::new("Microfuchsia", 0);
-        let instance = instance_starter.start_new_instance(&*self.service);
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            let Some(instance) = state.instances.get_mut(name) else {
+                return Ok(None);
+            };
+            if instance.generation != generation {
+                bail!("Microfuchsia instance {name:?} was restarted concurrently");
+            }
+            instance.restart_delay = INITIAL_RESTART_DELAY;
+            instance.id
+        };
+        let status = self.boot_vm(name, id, generation)?;
+        if status.is_none() && self.has_instance(name) {
+            bail!("Microfuchsia instance {name:?} was restarted concurrently");
+        }
+        info!("Restarted {name} instance");
+        Ok(status)
+    }
 
-        if instance.is_ok() {
-            self.started = true;
+    /// Opens a vsock connection to the given port in the VM of the instance with the given name.
+    /// Returns `None` if the VM isn't running.
+    pub fn connect_vsock(&self, name: &str, port: i32) -> Result<Option<ParcelFileDescriptor>> {
+        let state = self.state.lock().unwrap();
+        let Some(instance) = state.instances.get(name) else {
+            bail!("No microfuchsia instance {name:?}");
+        };
+        match instance.vm.as_ref().filter(|vm| vm.is_running()) {
+            Some(vm) => vm.connect_vsock(port).map(Some),
+            None => Ok(None),
         }
-        instance
+    }
+
+    /// Returns the state of the instances, ordered by name.
+    pub fn instances(&self) -> Vec<InstanceStatus> {
+        let state = self.state.lock().unwrap();
+        state.instances.keys().filter_map(|name| state.status(name)).collect()
+    }
+
+    fn service(&self) -> Strong<dyn IVirtualizationService> {
+        self.state.lock().unwrap().service.clone()
+    }
+
+    /// Boots a VM for the instance with the given name, without holding the lock, and makes it the
+    /// VM of the instance if that is still at the given generation. Otherwise the instance was
+    /// stopped or restarted in the meantime, so the new VM is shut down and `None` is returned.
+    fn boot_vm(&self, name: &str, id: [u8; 64], generation: u64) -> Result<Option<InstanceStatus>> {
+        let watcher =
+            DeathWatcher { state: Arc::downgrade(&self.state), name: name.to_owned(), generation };
+        let vm = InstanceStarter::new(name, id)
+            .start_new_instance(&*self.service(), Box::new(watcher))?;
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(instance) = state.instances.get_mut(name).filter(|i| i.generation == generation)
+        {
+            instance.vm = Some(vm);
+            instance.started_at = Instant::now();
+            return Ok(state.status(name));
+        }
+        drop(state);
+        vm.shutdown(SHUTDOWN_TIMEOUT)?;
+        Ok(None)
+    }
+
+    /// Schedules the restart of the instance with the given name, if its VM of the given
+    /// generation is the current one.
+    fn schedule_restart(&self, state: &mut State, name: &str, generation: u64) {
+        let generation_to_restart = state.new_generation();
+        let Some(instance) = state.instances.get_mut(name) else {
+            return;
+        };
+        if instance.generation != generation {
+            return;
+        }
+        // The dead VM is dropped on another thread, as this may be called from its death callback.
+        let dead_vm = instance.vm.take();
+        instance.generation = generation_to_restart;
+        let (delay, next_delay) =
+            restart_delays(instance.started_at.elapsed(), instance.restart_delay);
+        instance.restart_delay = next_delay;
+        info!("Restarting {name} instance in {delay:?}");
+
+        let manager = self.clone();
+        let name = name.to_owned();
+        thread::spawn(move || {
+            drop(dead_vm);
+            thread::sleep(delay);
+            manager.restart_dead_instance(&name, generation_to_restart);
+        });
+    }
+
+    fn restart_dead_instance(&self, name: &str, generation: u64) {
+        let id = {
+            let state = self.state.lock().unwrap();
+            match state.instances.get(name).filter(|i| i.generation == generation) {
+                Some(instance) => instance.id,
+                // The instance was stopped or restarted in the meantime.
+                None => return,
+            }
+        };
+        match self.boot_vm(name, id, generation) {
+            Ok(Some(_)) => info!("Restarted {name} instance"),
+            Ok(None) => {}
+            Err(e) => {
+                error!("Failed to restart {name} instance: {e:?}");
+                let mut state = self.state.lock().unwrap();
+                self.schedule_restart(&mut state, name, generation);
+            }
+        }
+    }
+
+    fn on_vm_died(&self, name: &str, generation: u64, death_reason: DeathReason) {
+        let mut state = self.state.lock().unwrap();
+        if !state.instances.get(name).is_some_and(|instance| instance.generation == generation) {
+            // The VM was shut down on purpose.
+            return;
+        }
+        if death_reason == DeathReason::Shutdown {
+            info!("{name} instance powered off, no longer supervising it");
+            let instance = state.instances.remove(name);
+            // This is called from the death callback of the VM, which can't drop the VM itself.
+            thread::spawn(move || drop(instance));
+            return;
+        }
+        warn!("{name} instance died: {death_reason:?}");
+        self.schedule_restart(&mut state, name, generation);
+    }
+}
+
+/// Releases the instance ID of the instance with the given name, which is no longer used.
+fn remove_instance_id(service: &dyn IVirtualizationService, name: &str, id: &[u8; 64]) {
+    if let Err(e) = service.removeVmInstance(id) {
+        warn!("Failed to remove the instance ID of {name} instance: {e:?}");
+    }
+}
+
+/// Returns how long to wait before restarting an instance whose VM died after running for
+/// `run_time`, and the delay to use if it dies again, given the current `restart_delay`.
+fn restart_delays(run_time: Duration, restart_delay: Duration) -> (Duration, Duration) {
+    let delay = if run_time >= HEALTHY_RUN_TIME { INITIAL_RESTART_DELAY } else { restart_delay };
+    (delay, (delay * 2).min(MAX_RESTART_DELAY))
+}
+
+/// Notifies the manager of the death of the VM of an instance.
+struct DeathWatcher {
+    state: Weak<Mutex<State>>,
+    name: String,
+    generation: u64,
+}
+
+impl VmCallback for DeathWatcher {
+    fn on_died(&self, _cid: i32, death_reason: DeathReason) {
+        if let Some(state) = self.state.upgrade() {
+            InstanceManager { state }.on_vm_died(&self.name, self.generation, death_reason);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restart_delay_grows_exponentially_up_to_max() {
+        let mut restart_delay = INITIAL_RESTART_DELAY;
+        let mut delays = vec![];
+        for _ in 0..12 {
+            let (delay, next_delay) = restart_delays(Duration::ZERO, restart_delay);
+            delays.push(delay);
+            restart_delay = next_delay;
+        }
+        assert_eq!(INITIAL_RESTART_DELAY, delays[0]);
+        assert_eq!(INITIAL_RESTART_DELAY * 2, delays[1]);
+        assert_eq!(INITIAL_RESTART_DELAY * 4, delays[2]);
+        assert_eq!(MAX_RESTART_DELAY, *delays.last().unwrap());
+        assert!(delays.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn restart_delay_is_reset_after_healthy_run() {
+        let (delay, next) = restart_delays(HEALTHY_RUN_TIME, MAX_RESTART_DELAY);
+        assert_eq!(INITIAL_RESTART_DELAY, delay);
+        assert_eq!(INITIAL_RESTART_DELAY * 2, next);
     }
 }
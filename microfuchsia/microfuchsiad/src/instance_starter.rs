@@ -22,38 +22,69 @@ use android_system_virtualizationservice::aidl::android::system::virtualizations
 };
 use anyhow::{ensure, Context, Result};
 use binder::{LazyServiceGuard, ParcelFileDescriptor};
-use log::info;
+use log::{info, warn};
 use std::ffi::CStr;
 use std::fs::File;
 use std::os::fd::FromRawFd;
-use vmclient::VmInstance;
+use std::time::Duration;
+use vmclient::{VmCallback, VmInstance};
 
 pub struct MicrofuchsiaInstance {
-    _vm_instance: VmInstance,
+    vm_instance: VmInstance,
     _lazy_service_guard: LazyServiceGuard,
-    _pty: Pty,
+    pty: Pty,
+}
+
+impl MicrofuchsiaInstance {
+    pub fn cid(&self) -> i32 {
+        self.vm_instance.cid()
+    }
+
+    /// Returns the path of the follower end of the console pty.
+    pub fn console_pty(&self) -> &str {
+        &self.pty.follower_name
+    }
+
+    /// Returns whether the VM is still running, i.e. it has neither been stopped nor crashed.
+    pub fn is_running(&self) -> bool {
+        self.vm_instance.wait_for_death_with_timeout(Duration::ZERO).is_none()
+    }
+
+    /// Opens a vsock connection to the given port in the VM.
+    pub fn connect_vsock(&self, port: i32) -> Result<ParcelFileDescriptor> {
+        self.vm_instance
+            .vm
+            .connectVsock(port)
+            .with_context(|| format!("Connecting to port {port} of VM with CID {}", self.cid()))
+    }
+
+    /// Asks the VM to power off, and stops it if it hasn't within `timeout`.
+    pub fn shutdown(self, timeout: Duration) -> Result<()> {
+        let cid = self.cid();
+        if !self.vm_instance.shutdown(timeout).context("Shutting down VM")? {
+            warn!("VM with CID {cid} didn't power off within {timeout:?}, it was stopped");
+        }
+        Ok(())
+    }
 }
 
 pub struct InstanceStarter {
     instance_name: String,
-    instance_id: u8,
+    instance_id: [u8; 64],
 }
 
 impl InstanceStarter {
-    pub fn new(instance_name: &str, instance_id: u8) -> Self {
+    pub fn new(instance_name: &str, instance_id: [u8; 64]) -> Self {
         Self { instance_name: instance_name.to_owned(), instance_id }
     }
 
     pub fn start_new_instance(
         &self,
         virtualization_service: &dyn IVirtualizationService,
+        callback: Box<dyn VmCallback + Send + Sync>,
     ) -> Result<MicrofuchsiaInstance> {
         info!("Creating {} instance", self.instance_name);
 
-        // Always use instance id 0, because we will only ever have one instance.
-        let mut instance_id = [0u8; 64];
-        instance_id[0] = self.instance_id;
-
         // Open the kernel and initrd files from the microfuchsia.images apex.
         let kernel_fd =
             File::open("/apex/com.android.microfuchsia.images/etc/linux-arm64-boot-shim.bin")
@@ -69,8 +100,8 @@ impl InstanceStarter {
         let console_out = Some(pty.leader.try_clone().context("cloning pty")?);
 
         let config = VirtualMachineConfig::RawConfig(VirtualMachineRawConfig {
-            name: "Microfuchsia".into(),
-            instanceId: instance_id,
+            name: self.instance_name.clone(),
+            instanceId: self.instance_id,
             kernel,
             initrd,
             params: None,
@@ -90,7 +121,7 @@ impl InstanceStarter {
             console_out,
             console_in,
             /* log= */ None,
-            None,
+            Some(callback),
         )
         .context("Failed to create VM")?;
         vm_instance
@@ -99,11 +130,7 @@ impl InstanceStarter {
             .context("Setting host console name")?;
         vm_instance.start().context("Starting VM")?;
 
-        Ok(MicrofuchsiaInstance {
-            _vm_instance: vm_instance,
-            _lazy_service_guard: Default::default(),
-            _pty: pty,
-        })
+        Ok(MicrofuchsiaInstance { vm_instance, _lazy_service_guard: Default::default(), pty })
     }
 }
 
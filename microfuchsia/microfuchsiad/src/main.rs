@@ -15,8 +15,7 @@
  */
 
 //! A daemon that can be launched on bootup that runs microfuchsia in AVF.
-//! An on-demand binder service is also prepared in case we want to communicate with the daemon in
-//! the future.
+//! Additional named instances can be started and stopped through its on-demand binder service.
 
 mod instance_manager;
 mod instance_starter;
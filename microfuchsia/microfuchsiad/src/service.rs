@@ -14,29 +14,142 @@
  * limitations under the License.
  */
 
-//! Implementation of IMicrofuchsiaService that runs microfuchsia in AVF when
-//! created.
+//! Implementation of IMicrofuchsiaService that runs microfuchsia instances in AVF. The default
+//! instance is started when the service is created.
 
-use crate::instance_manager::InstanceManager;
-use crate::instance_starter::MicrofuchsiaInstance;
-use android_system_microfuchsiad::aidl::android::system::microfuchsiad::IMicrofuchsiaService::{
-    BnMicrofuchsiaService, IMicrofuchsiaService,
+use crate::instance_manager::{InstanceManager, InstanceStatus};
+use android_system_microfuchsiad::aidl::android::system::microfuchsiad::{
+    IMicrofuchsiaService::{BnMicrofuchsiaService, IMicrofuchsiaService, DEFAULT_INSTANCE_NAME},
+    InstanceInfo::InstanceInfo,
 };
-use anyhow::Context;
-use binder::{self, BinderFeatures, Interface, Strong};
+use anyhow::{anyhow, Context};
+use binder::{
+    self, BinderFeatures, ExceptionCode, Interface, IntoBinderResult, ParcelFileDescriptor, Status,
+    Strong,
+};
+use log::error;
 
-#[allow(unused)]
 pub struct MicrofuchsiaService {
     instance_manager: InstanceManager,
-    microfuchsia: MicrofuchsiaInstance,
 }
 
-pub fn new_binder(mut instance_manager: InstanceManager) -> Strong<dyn IMicrofuchsiaService> {
-    let microfuchsia = instance_manager.start_instance().context("Starting Microfuchsia").unwrap();
-    let service = MicrofuchsiaService { instance_manager, microfuchsia };
+pub fn new_binder(instance_manager: InstanceManager) -> Strong<dyn IMicrofuchsiaService> {
+    instance_manager
+        .start_instance(DEFAULT_INSTANCE_NAME)
+        .context("Starting Microfuchsia")
+        .unwrap();
+    let service = MicrofuchsiaService { instance_manager };
     BnMicrofuchsiaService::new_binder(service, BinderFeatures::default())
 }
 
+fn to_instance_info(status: InstanceStatus) -> InstanceInfo {
+    let (cid, console_pty) = status.vm.unwrap_or((-1, String::new()));
+    InstanceInfo { name: status.name, running: cid >= 0, cid, consolePty: console_pty }
+}
+
+/// Converts an error of the instance manager to a binder status. Failures of calls to the
+/// VirtualizationService keep their status, so that clients see why the call failed.
+fn to_binder_status(e: anyhow::Error) -> Status {
+    error!("{e:?}");
+    match e.downcast::<Status>() {
+        Ok(status) => status,
+        Err(e) => Status::new_service_specific_error_str(-1, Some(format!("{e:?}"))),
+    }
+}
+
+fn no_such_instance<T>(name: &str) -> binder::Result<T> {
+    Err(anyhow!("No microfuchsia instance {name:?}"))
+        .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT)
+}
+
+impl MicrofuchsiaService {
+    fn check_instance_exists(&self, name: &str) -> binder::Result<()> {
+        if self.instance_manager.has_instance(name) {
+            Ok(())
+        } else {
+            no_such_instance(name)
+        }
+    }
+}
+
 impl Interface for MicrofuchsiaService {}
 
-impl IMicrofuchsiaService for MicrofuchsiaService {}
+impl IMicrofuchsiaService for MicrofuchsiaService {
+    fn startInstance(&self, name: &str) -> binder::Result<InstanceInfo> {
+        if name.is_empty() || self.instance_manager.has_instance(name) {
+            return Err(anyhow!("Invalid or already used instance name {name:?}"))
+                .or_binder_exception(ExceptionCode::ILLEGAL_ARGUMENT);
+        }
+        let status = self
+            .instance_manager
+            .start_instance(name)
+            .with_context(|| format!("Failed to start {name} instance"))
+            .map_err(to_binder_status)?;
+        Ok(to_instance_info(status))
+    }
+
+    fn stopInstance(&self, name: &str) -> binder::Result<()> {
+        let stopped = self
+            .instance_manager
+            .stop_instance(name)
+            .with_context(|| format!("Failed to stop {name} instance"))
+            .map_err(to_binder_status)?;
+        if !stopped {
+            return no_such_instance(name);
+        }
+        Ok(())
+    }
+
+    fn restartInstance(&self, name: &str) -> binder::Result<InstanceInfo> {
+        let status = self
+            .instance_manager
+            .restart_instance(name)
+            .with_context(|| format!("Failed to restart {name} instance"))
+            .map_err(to_binder_status)?;
+        let Some(status) = status else {
+            return no_such_instance(name);
+        };
+        Ok(to_instance_info(status))
+    }
+
+    fn listInstances(&self) -> binder::Result<Vec<InstanceInfo>> {
+        Ok(self.instance_manager.instances().into_iter().map(to_instance_info).collect())
+    }
+
+    fn connectVsock(&self, name: &str, port: i32) -> binder::Result<ParcelFileDescriptor> {
+        self.check_instance_exists(name)?;
+        let fd = self
+            .instance_manager
+            .connect_vsock(name, port)
+            .with_context(|| format!("Failed to connect to port {port} of {name} instance"))
+            .map_err(to_binder_status)?;
+        fd.with_context(|| format!("The VM of {name} instance isn't running"))
+            .or_binder_exception(ExceptionCode::ILLEGAL_STATE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn virtualization_service_status_is_kept() {
+        let status = Status::new_exception_str(ExceptionCode::SECURITY, Some("denied"));
+        let e = anyhow::Error::new(status).context("Stopping VM").context("Failed to stop");
+        let status = to_binder_status(e);
+        assert_eq!(ExceptionCode::SECURITY, status.exception_code());
+    }
+
+    #[test]
+    fn other_errors_are_service_specific() {
+        let status = to_binder_status(anyhow!("VM didn't start"));
+        assert_eq!(ExceptionCode::SERVICE_SPECIFIC, status.exception_code());
+        assert_eq!(-1, status.service_specific_error());
+    }
+
+    #[test]
+    fn missing_instance_is_illegal_argument() {
+        let status = no_such_instance::<()>("foo").unwrap_err();
+        assert_eq!(ExceptionCode::ILLEGAL_ARGUMENT, status.exception_code());
+    }
+}
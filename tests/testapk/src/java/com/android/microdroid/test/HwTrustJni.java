@@ -29,4 +29,70 @@ class HwTrustJni {
      * @return true if the dice chain is valid, false otherwise.
      */
     public static native boolean validateDiceChain(byte[] diceChain, boolean allowAnyMode);
+
+    /**
+     * Validates a DICE chain, describing why it is invalid if it is.
+     *
+     * @param diceChain The dice chain to validate.
+     * @param allowAnyMode Allow the chain's certificates to have any mode.
+     * @return the result of the validation.
+     */
+    public static native DiceChainValidationResult validateDiceChainDetailed(
+            byte[] diceChain, boolean allowAnyMode);
+
+    /**
+     * Parses a DICE chain and describes each of its certificates, so that tests can assert on its
+     * contents.
+     *
+     * @param diceChain The dice chain to parse.
+     * @param allowAnyMode Allow the chain's certificates to have any mode.
+     * @return a JSON array with an object per certificate, starting from the one closest to the
+     *     root, with the {@code component_name}, {@code component_version}, {@code mode} and
+     *     hex-encoded {@code authority_hash} of the certificate.
+     * @throws IllegalArgumentException if the DICE chain is invalid.
+     */
+    public static native String parseDiceChainComponents(byte[] diceChain, boolean allowAnyMode);
+
+    /** The result of {@link #validateDiceChainDetailed}. */
+    static final class DiceChainValidationResult {
+        /** The DICE chain is valid. */
+        static final int ERROR_NONE = 0;
+        /** The DICE chain isn't a well-formed CBOR array. */
+        static final int ERROR_MALFORMED_CBOR = 1;
+        /** An entry of the DICE chain is invalid, see {@link #entryIndex}. */
+        static final int ERROR_INVALID_ENTRY = 2;
+
+        /** One of the {@code ERROR_*} constants. */
+        final int errorKind;
+
+        /**
+         * The index of the first entry of the chain whose addition makes the chain invalid, where
+         * 0 is the root public key, or -1 if unknown. An invalid root public key is reported as
+         * entry 1, as a chain needs at least one certificate.
+         */
+        final int entryIndex;
+
+        /** Why the DICE chain is invalid, or empty if it is valid. */
+        final String message;
+
+        // Called from JNI.
+        DiceChainValidationResult(int errorKind, int entryIndex, String message) {
+            this.errorKind = errorKind;
+            this.entryIndex = entryIndex;
+            this.message = message;
+        }
+
+        boolean isValid() {
+            return errorKind == ERROR_NONE;
+        }
+
+        @Override
+        public String toString() {
+            if (isValid()) {
+                return "Valid DICE chain";
+            }
+            return "Invalid DICE chain (error " + errorKind + ", entry " + entryIndex + "): "
+                    + message;
+        }
+    }
 }
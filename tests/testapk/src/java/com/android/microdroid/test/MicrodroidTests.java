@@ -568,6 +568,7 @@ public class MicrodroidTests extends MicrodroidDeviceTestBase {
 
         assertThat(minimal.getApkPath()).isNull();
         assertThat(minimal.getExtraApks()).isEmpty();
+        assertThat(minimal.getExposedHostPropertyPrefixes()).isEmpty();
         assertThat(minimal.getDebugLevel()).isEqualTo(DEBUG_LEVEL_NONE);
         assertThat(minimal.getMemoryBytes()).isEqualTo(0);
         assertThat(minimal.getCpuTopology()).isEqualTo(CPU_TOPOLOGY_ONE_CPU);
@@ -576,6 +577,7 @@ public class MicrodroidTests extends MicrodroidDeviceTestBase {
         assertThat(minimal.isProtectedVm()).isEqualTo(isProtectedVm());
         assertThat(minimal.isEncryptedStorageEnabled()).isFalse();
         assertThat(minimal.getEncryptedStorageBytes()).isEqualTo(0);
+        assertThat(minimal.getEncryptedStorageMaxBytes()).isEqualTo(0);
         assertThat(minimal.isVmOutputCaptured()).isFalse();
         assertThat(minimal.getOs()).isEqualTo("microdroid");
 
@@ -588,10 +590,13 @@ public class MicrodroidTests extends MicrodroidDeviceTestBase {
                         .setApkPath("/apk/path")
                         .addExtraApk("package.name1")
                         .addExtraApk("package.name2")
+                        .addExposedHostPropertyPrefix("ro.build.version.")
+                        .addExposedHostPropertyPrefix("ro.product.")
                         .setDebugLevel(DEBUG_LEVEL_FULL)
                         .setMemoryBytes(42)
                         .setCpuTopology(CPU_TOPOLOGY_MATCH_HOST)
                         .setEncryptedStorageBytes(1_000_000)
+                        .setEncryptedStorageMaxBytes(2_000_000)
                         .setVmOutputCaptured(true)
                         .setOs("microdroid_gki-android14-6.1");
         VirtualMachineConfig maximal = maximalBuilder.build();
@@ -600,6 +605,9 @@ public class MicrodroidTests extends MicrodroidDeviceTestBase {
         assertThat(maximal.getExtraApks())
                 .containsExactly("package.name1", "package.name2")
                 .inOrder();
+        assertThat(maximal.getExposedHostPropertyPrefixes())
+                .containsExactly("ro.build.version.", "ro.product.")
+                .inOrder();
         assertThat(maximal.getDebugLevel()).isEqualTo(DEBUG_LEVEL_FULL);
         assertThat(maximal.getMemoryBytes()).isEqualTo(42);
         assertThat(maximal.getCpuTopology()).isEqualTo(CPU_TOPOLOGY_MATCH_HOST);
@@ -608,6 +616,7 @@ public class MicrodroidTests extends MicrodroidDeviceTestBase {
         assertThat(maximal.isProtectedVm()).isEqualTo(isProtectedVm());
         assertThat(maximal.isEncryptedStorageEnabled()).isTrue();
         assertThat(maximal.getEncryptedStorageBytes()).isEqualTo(1_000_000);
+        assertThat(maximal.getEncryptedStorageMaxBytes()).isEqualTo(2_000_000);
         assertThat(maximal.isVmOutputCaptured()).isTrue();
         assertThat(maximal.getOs()).isEqualTo("microdroid_gki-android14-6.1");
 
@@ -626,6 +635,7 @@ public class MicrodroidTests extends MicrodroidDeviceTestBase {
         assertThrows(NullPointerException.class, () -> new VirtualMachineConfig.Builder(null));
         assertThrows(NullPointerException.class, () -> builder.setApkPath(null));
         assertThrows(NullPointerException.class, () -> builder.addExtraApk(null));
+        assertThrows(NullPointerException.class, () -> builder.addExposedHostPropertyPrefix(null));
         assertThrows(NullPointerException.class, () -> builder.setPayloadConfigPath(null));
         assertThrows(NullPointerException.class, () -> builder.setPayloadBinaryName(null));
         assertThrows(NullPointerException.class, () -> builder.setVendorDiskImage(null));
@@ -640,6 +650,7 @@ public class MicrodroidTests extends MicrodroidDeviceTestBase {
         assertThrows(IllegalArgumentException.class, () -> builder.setMemoryBytes(0));
         assertThrows(IllegalArgumentException.class, () -> builder.setCpuTopology(-1));
         assertThrows(IllegalArgumentException.class, () -> builder.setEncryptedStorageBytes(0));
+        assertThrows(IllegalArgumentException.class, () -> builder.setEncryptedStorageMaxBytes(0));
 
         // Consistency checks enforced at build time.
         Exception e;
@@ -664,6 +675,13 @@ public class MicrodroidTests extends MicrodroidDeviceTestBase {
                         .setVmConsoleInputSupported(true);
         e = assertThrows(IllegalStateException.class, () -> captureInputOnNonDebuggable.build());
         assertThat(e).hasMessageThat().contains("debug level must be FULL to use console input");
+
+        VirtualMachineConfig.Builder storageMaxBelowSize =
+                newVmConfigBuilderWithPayloadBinary("binary.so")
+                        .setEncryptedStorageBytes(2_000_000)
+                        .setEncryptedStorageMaxBytes(1_000_000);
+        e = assertThrows(IllegalStateException.class, () -> storageMaxBelowSize.build());
+        assertThat(e).hasMessageThat().contains("maximum encrypted storage size");
     }
 
     @Test
@@ -679,6 +697,10 @@ public class MicrodroidTests extends MicrodroidDeviceTestBase {
         assertConfigCompatible(
                         baseline, newBaselineBuilder().setCpuTopology(CPU_TOPOLOGY_MATCH_HOST))
                 .isTrue();
+        assertConfigCompatible(
+                        baseline,
+                        newBaselineBuilder().addExposedHostPropertyPrefix("ro.build.version."))
+                .isTrue();
 
         // Changes that must be incompatible, since they must change the VM identity.
         assertConfigCompatible(baseline, newBaselineBuilder().addExtraApk("foo")).isFalse();
@@ -1335,7 +1357,9 @@ public class MicrodroidTests extends MicrodroidDeviceTestBase {
         String buildType = SystemProperties.get("ro.build.type");
         boolean nonUserBuild = !buildType.isEmpty() && buildType != "user";
 
-        assertThat(HwTrustJni.validateDiceChain(bccBytes, nonUserBuild)).isTrue();
+        HwTrustJni.DiceChainValidationResult result =
+                HwTrustJni.validateDiceChainDetailed(bccBytes, nonUserBuild);
+        assertWithMessage(result.toString()).that(result.isValid()).isTrue();
     }
 
     @Test
@@ -1488,6 +1512,20 @@ public class MicrodroidTests extends MicrodroidDeviceTestBase {
                 "Extra APK package not found");
     }
 
+    @Test
+    public void createFailsWhenExposedHostPropertiesAreNotAllowed() throws Exception {
+        assumeSupportedDevice();
+
+        VirtualMachineConfig config =
+                newVmConfigBuilderWithPayloadBinary("MicrodroidTestNativeLib.so")
+                        .setDebugLevel(DEBUG_LEVEL_FULL)
+                        .addExposedHostPropertyPrefix("persist.")
+                        .build();
+        assertThrowsVmExceptionContaining(
+                () -> tryBootVmWithConfig(config, "test_vm_exposed_host_properties"),
+                "can't expose host properties");
+    }
+
     private BootResult tryBootVmWithConfig(VirtualMachineConfig config, String vmName)
             throws Exception {
         try (VirtualMachine ignored = forceCreateNewVirtualMachine(vmName, config)) {
@@ -2193,6 +2231,51 @@ public class MicrodroidTests extends MicrodroidDeviceTestBase {
         }
     }
 
+    @Test
+    public void testShareVmWithAnotherApp_onlyLatestDescriptorIsImported() throws Exception {
+        assumeSupportedDevice();
+        assumeFeatureEnabled(VirtualMachineManager.FEATURE_LLPVM_CHANGES);
+
+        Context ctx = getContext();
+        Context otherAppCtx = ctx.createPackageContext(VM_SHARE_APP_PACKAGE_NAME, 0);
+
+        VirtualMachineConfig config =
+                new VirtualMachineConfig.Builder(otherAppCtx)
+                        .setDebugLevel(DEBUG_LEVEL_FULL)
+                        .setProtectedVm(isProtectedVm())
+                        .setPayloadBinaryName("MicrodroidPayloadInOtherAppNativeLib.so")
+                        .setOs(os())
+                        .build();
+
+        VirtualMachine vm = forceCreateNewVirtualMachine("vm_to_share", config);
+        // Exporting the VM again revokes the transfer token of the first descriptor.
+        VirtualMachineDescriptor staleVmDesc = vm.toDescriptor();
+        VirtualMachineDescriptor vmDesc = vm.toDescriptor();
+
+        Intent serviceIntent = new Intent();
+        serviceIntent.setComponent(
+                new ComponentName(
+                        VM_SHARE_APP_PACKAGE_NAME,
+                        "com.android.microdroid.test.sharevm.VmShareServiceImpl"));
+        serviceIntent.setAction("com.android.microdroid.test.sharevm.VmShareService");
+
+        VmShareServiceConnection connection = new VmShareServiceConnection();
+        boolean ret = ctx.bindService(serviceIntent, connection, Context.BIND_AUTO_CREATE);
+        assertWithMessage("Failed to bind to " + serviceIntent).that(ret).isTrue();
+
+        IVmShareTestService service = connection.waitForService();
+        assertWithMessage("Timed out connecting to " + serviceIntent).that(service).isNotNull();
+
+        try {
+            // The other app can't claim the instance without a valid transfer token.
+            assertThrows(IllegalStateException.class, () -> service.importVm(staleVmDesc));
+            service.importVm(vmDesc);
+        } finally {
+            staleVmDesc.close();
+            ctx.unbindService(connection);
+        }
+    }
+
     private ITestService transferAndStartVm(
             IVmShareTestService service, VirtualMachineDescriptor vmDesc, String vmName)
             throws Exception {
@@ -16,12 +16,40 @@
 
 //! JNI bindings to call into `hwtrust` from Java.
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use ciborium::value::Value;
 use hwtrust::{dice, session::Session};
-use jni::objects::{JByteArray, JClass};
-use jni::sys::jboolean;
+use jni::objects::{JByteArray, JClass, JObject, JValue};
+use jni::sys::{jboolean, jint, jobject, jstring};
 use jni::JNIEnv;
 use log::{debug, error, info};
+use serde_json::json;
+use std::ptr;
+
+/// Java class of the result of `validateDiceChainDetailed`.
+const VALIDATION_RESULT_CLASS: &str =
+    "com/android/microdroid/test/HwTrustJni$DiceChainValidationResult";
+
+// Error kinds, matching the constants of `HwTrustJni.DiceChainValidationResult`.
+const ERROR_NONE: jint = 0;
+const ERROR_MALFORMED_CBOR: jint = 1;
+const ERROR_INVALID_ENTRY: jint = 2;
+
+/// Why a DICE chain is invalid.
+struct ValidationError {
+    kind: jint,
+    /// Index in the chain of the first invalid entry, where 0 is the root public key, or -1.
+    entry_index: jint,
+    message: String,
+}
+
+fn init_logger() {
+    android_logger::init_once(
+        android_logger::Config::default()
+            .with_tag("hwtrust_jni")
+            .with_max_level(log::LevelFilter::Debug),
+    );
+}
 
 /// Validates the given DICE chain.
 #[no_mangle]
@@ -31,11 +59,7 @@ pub extern "system" fn Java_com_android_microdroid_test_HwTrustJni_validateDiceC
     dice_chain: JByteArray,
     allow_any_mode: jboolean,
 ) -> jboolean {
-    android_logger::init_once(
-        android_logger::Config::default()
-            .with_tag("hwtrust_jni")
-            .with_max_level(log::LevelFilter::Debug),
-    );
+    init_logger();
     debug!("Starting the DICE chain validation ...");
     match validate_dice_chain(env, dice_chain, allow_any_mode) {
         Ok(_) => {
@@ -50,6 +74,149 @@ pub extern "system" fn Java_com_android_microdroid_test_HwTrustJni_validateDiceC
     .into()
 }
 
+/// Validates the given DICE chain, returning a `DiceChainValidationResult` which describes which
+/// entry of the chain is invalid and why, if any.
+#[no_mangle]
+pub extern "system" fn Java_com_android_microdroid_test_HwTrustJni_validateDiceChainDetailed(
+    mut env: JNIEnv,
+    _class: JClass,
+    dice_chain: JByteArray,
+    allow_any_mode: jboolean,
+) -> jobject {
+    init_logger();
+    debug!("Starting the detailed DICE chain validation ...");
+    let result = match env.convert_byte_array(dice_chain) {
+        Ok(dice_chain) => validate_dice_chain_detailed(&dice_chain, allow_any_mode),
+        Err(e) => Err(ValidationError {
+            kind: ERROR_MALFORMED_CBOR,
+            entry_index: -1,
+            message: format!("Failed to read the DICE chain: {e:?}"),
+        }),
+    };
+    match &result {
+        Ok(()) => info!("DICE chain validated successfully"),
+        Err(e) => error!("Invalid DICE chain entry {}: {}", e.entry_index, e.message),
+    }
+    match new_validation_result(&mut env, result) {
+        Ok(result) => result.into_raw(),
+        Err(e) => {
+            // An exception is pending in Java.
+            error!("Failed to create the validation result: {e:?}");
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Parses the given DICE chain, returning as a JSON array the component name, version, mode and
+/// authority hash of each of its certificates, starting from the one closest to the root.
+///
+/// Throws an `IllegalArgumentException` if the chain is invalid.
+#[no_mangle]
+pub extern "system" fn Java_com_android_microdroid_test_HwTrustJni_parseDiceChainComponents(
+    mut env: JNIEnv,
+    _class: JClass,
+    dice_chain: JByteArray,
+    allow_any_mode: jboolean,
+) -> jstring {
+    init_logger();
+    let components = env
+        .convert_byte_array(dice_chain)
+        .map_err(anyhow::Error::from)
+        .and_then(|dice_chain| parse_dice_chain_components(&dice_chain, allow_any_mode));
+    let result = components.and_then(|components| Ok(env.new_string(components)?));
+    match result {
+        Ok(components) => components.into_raw(),
+        Err(e) => {
+            error!("Failed to parse the DICE chain: {e:?}");
+            // Don't hide the exception thrown by a failed JNI call.
+            if !env.exception_check().unwrap_or(true) {
+                let _ = env.throw_new("java/lang/IllegalArgumentException", format!("{e:#}"));
+            }
+            ptr::null_mut()
+        }
+    }
+}
+
+fn parse_dice_chain_components(dice_chain: &[u8], allow_any_mode: jboolean) -> Result<String> {
+    let mut session = Session::default();
+    session.set_allow_any_mode(allow_any_mode == jboolean::from(true));
+    let chain = dice::Chain::from_cbor(&session, dice_chain)?;
+    let components: Vec<_> = chain
+        .payloads()
+        .iter()
+        .map(|payload| {
+            let config = payload.config_desc();
+            json!({
+                "component_name": config.component_name(),
+                "component_version": config.component_version().map(|v| v.to_string()),
+                "mode": format!("{:?}", payload.mode()),
+                "authority_hash": to_hex(payload.authority_hash()),
+            })
+        })
+        .collect();
+    Ok(serde_json::to_string(&components)?)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn new_validation_result<'local>(
+    env: &mut JNIEnv<'local>,
+    result: Result<(), ValidationError>,
+) -> Result<JObject<'local>> {
+    let (kind, entry_index, message) = match result {
+        Ok(()) => (ERROR_NONE, -1, String::new()),
+        Err(e) => (e.kind, e.entry_index, e.message),
+    };
+    let message = env.new_string(message)?;
+    let result = env.new_object(
+        VALIDATION_RESULT_CLASS,
+        "(IILjava/lang/String;)V",
+        &[JValue::Int(kind), JValue::Int(entry_index), JValue::Object(&message)],
+    )?;
+    Ok(result)
+}
+
+/// Validates the DICE chain and, if it is invalid, finds the first entry whose addition to the
+/// chain makes it invalid.
+fn validate_dice_chain_detailed(
+    dice_chain: &[u8],
+    allow_any_mode: jboolean,
+) -> Result<(), ValidationError> {
+    let mut session = Session::default();
+    session.set_allow_any_mode(allow_any_mode == jboolean::from(true));
+    let Err(e) = dice::Chain::from_cbor(&session, dice_chain) else {
+        return Ok(());
+    };
+    let message = format!("{e:#}");
+    let entries = match decode_entries(dice_chain) {
+        Ok(entries) => entries,
+        Err(e) => {
+            return Err(ValidationError {
+                kind: ERROR_MALFORMED_CBOR,
+                entry_index: -1,
+                message: format!("{e:#}"),
+            })
+        }
+    };
+    // The shortest chain has the root public key and one certificate, so neither can be blamed
+    // individually when it is invalid.
+    let entry_index = (2..=entries.len())
+        .find(|&len| {
+            let mut prefix = vec![];
+            ciborium::into_writer(&Value::Array(entries[..len].to_vec()), &mut prefix)
+                .map_or(true, |()| dice::Chain::from_cbor(&session, &prefix).is_err())
+        })
+        .map_or(-1, |len| jint::try_from(len - 1).unwrap_or(-1));
+    Err(ValidationError { kind: ERROR_INVALID_ENTRY, entry_index, message })
+}
+
+fn decode_entries(dice_chain: &[u8]) -> Result<Vec<Value>> {
+    let value: Value = ciborium::from_reader(dice_chain).context("Invalid CBOR")?;
+    value.into_array().ok().context("The DICE chain isn't a CBOR array")
+}
+
 fn validate_dice_chain(
     env: JNIEnv,
     jdice_chain: JByteArray,
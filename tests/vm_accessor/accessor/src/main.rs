@@ -14,19 +14,16 @@
 
 //! Android VM control tool.
 
-mod accessor;
 mod run;
 
-use accessor::Accessor;
-use android_os_accessor::aidl::android::os::IAccessor::BnAccessor;
+use anyhow::bail;
 use anyhow::Error;
-use anyhow::{anyhow, bail};
-use binder::{BinderFeatures, ProcessState};
-use log::info;
+use binder::ProcessState;
 use run::run_vm;
+use vm_accessor::VmAccessor;
 
 // Private contract between IAccessor impl and VM service.
-const PORT: i32 = 5678;
+const PORT: u32 = 5678;
 
 // MUST match with VINTF and init.rc
 // TODO(b/354632613): Get this from VINTF
@@ -39,15 +36,9 @@ fn main() -> Result<(), Error> {
             .with_max_level(log::LevelFilter::Debug),
     );
 
-    let vm = run_vm()?;
-
-    // If you want to serve multiple services in a VM, then register Accessor impls multiple times.
-    let accessor = Accessor::new(vm, PORT, SERVICE_NAME);
-    let accessor_binder = BnAccessor::new_binder(accessor, BinderFeatures::default());
-    binder::register_lazy_service(SERVICE_NAME, accessor_binder.as_binder()).map_err(|e| {
-        anyhow!("Failed to register lazy service, service={SERVICE_NAME}, err={e:?}",)
-    })?;
-    info!("service {SERVICE_NAME} is registered as lazy service");
+    // If you want to serve multiple services in a VM, then register accessors multiple times,
+    // sharing the VM in their suppliers.
+    VmAccessor::new(SERVICE_NAME, PORT, run_vm).register_lazy_service()?;
 
     ProcessState::join_thread_pool();
 
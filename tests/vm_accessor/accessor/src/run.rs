@@ -27,10 +27,8 @@ use glob::glob;
 use log::{error, info};
 use rand::{distributions::Alphanumeric, Rng};
 use std::fs::{self, File};
-use std::io::{self, BufRead, BufReader};
 use std::path::PathBuf;
-use std::thread;
-use vmclient::{ErrorCode, VmInstance};
+use vmclient::{log_forwarder, ErrorCode, VmInstance};
 use vmconfig::open_parcel_file;
 
 // These are private contract between IAccessor impl and VM service.
@@ -106,6 +104,7 @@ pub fn run_vm() -> Result<VmInstance, Error> {
     let payload = Payload::PayloadConfig(VirtualMachinePayloadConfig {
         payloadBinaryName: PAYLOAD_BINARY_NAME.to_owned(),
         extraApks: Default::default(),
+        payloadExtraApkIndex: -1,
     });
 
     let vm_config = VirtualMachineConfig::AppConfig(VirtualMachineAppConfig {
@@ -125,9 +124,9 @@ pub fn run_vm() -> Result<VmInstance, Error> {
     let vm = VmInstance::create(
         service.as_ref(),
         &vm_config,
-        Some(android_log_fd()?), /* console_out */
-        None,                    /* console_in */
-        Some(android_log_fd()?), /* log */
+        Some(log_forwarder(VM_OS_NAME)?), /* console_out */
+        None,                             /* console_in */
+        Some(log_forwarder(VM_OS_NAME)?), /* log */
         Some(Box::new(Callback {})),
     )
     .context("Failed to create VM")?;
@@ -157,25 +156,3 @@ impl vmclient::VmCallback for Callback {
         error!("VM encountered an error: code={:?}, message={}", error_code, message);
     }
 }
-
-/// This function is only exposed for testing.
-/// Production code prefer not expose logs from VM.
-fn android_log_fd() -> io::Result<File> {
-    let (reader_fd, writer_fd) = nix::unistd::pipe()?;
-
-    let reader = File::from(reader_fd);
-    let writer = File::from(writer_fd);
-
-    thread::spawn(|| {
-        for line in BufReader::new(reader).lines() {
-            match line {
-                Ok(l) => info!("{}", l),
-                Err(e) => {
-                    error!("Failed to read line from VM: {e:?}");
-                    break;
-                }
-            }
-        }
-    });
-    Ok(writer)
-}
@@ -0,0 +1,72 @@
+/*
+ * Copyright 2024 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Tests pinning the behavior of the stable VM manager interface of virtualizationservice.
+
+use android_system_virtualmachinemanager::aidl::android::system::virtualmachinemanager::IVirtualMachineManager::{
+    IVirtualMachineManager, SERVICE_NAME,
+};
+use binder::{ExceptionCode, Strong};
+
+/// CIDs which can't be those of a VM of the caller: negative, or reserved for the host.
+const INVALID_CIDS: [i32; 3] = [-1, 0, 2];
+
+/// Returns the service, or None if the device doesn't support VMs and thus doesn't have it.
+fn get_service() -> Option<Strong<dyn IVirtualMachineManager>> {
+    if !hypervisor_props::is_any_vm_supported().unwrap() {
+        return None;
+    }
+    Some(binder::wait_for_interface(SERVICE_NAME).unwrap())
+}
+
+#[test]
+fn vms_are_listed_in_cid_order() {
+    let Some(service) = get_service() else { return };
+
+    let cids: Vec<_> = service.listVirtualMachines().unwrap().iter().map(|vm| vm.cid).collect();
+
+    assert!(cids.windows(2).all(|pair| pair[0] < pair[1]), "CIDs {cids:?} aren't ordered");
+}
+
+#[test]
+fn stats_of_unknown_vm_are_illegal_argument() {
+    let Some(service) = get_service() else { return };
+
+    for cid in INVALID_CIDS {
+        let error = service.getStats(cid).unwrap_err();
+        assert_eq!(error.exception_code(), ExceptionCode::ILLEGAL_ARGUMENT, "CID {cid}");
+    }
+}
+
+#[test]
+fn stopping_unknown_vm_is_illegal_argument() {
+    let Some(service) = get_service() else { return };
+
+    for cid in INVALID_CIDS {
+        let error = service.stopVirtualMachine(cid).unwrap_err();
+        assert_eq!(error.exception_code(), ExceptionCode::ILLEGAL_ARGUMENT, "CID {cid}");
+    }
+}
+
+#[test]
+fn console_of_unknown_vm_is_illegal_argument() {
+    let Some(service) = get_service() else { return };
+
+    for cid in INVALID_CIDS {
+        let error = service.openConsole(cid).unwrap_err();
+        assert_eq!(error.exception_code(), ExceptionCode::ILLEGAL_ARGUMENT, "CID {cid}");
+    }
+}
@@ -83,7 +83,8 @@ fn run_test(
         test_image.write_all(&i.to_le_bytes())?;
     }
     let test_image = ParcelFileDescriptor::new(test_image);
-    let disk_image = DiskImage { image: Some(test_image), writable: false, partitions: vec![] };
+    let disk_image =
+        DiskImage { image: Some(test_image), writable: false, partitions: vec![], directIo: false };
 
     // Make file for empty test disk image.
     let empty_image = File::options()
@@ -94,8 +95,12 @@ fn run_test(
         .open(EMPTY_DISK_IMAGE_PATH)
         .with_context(|| format!("Failed to open empty disk image {}", EMPTY_DISK_IMAGE_PATH))?;
     let empty_image = ParcelFileDescriptor::new(empty_image);
-    let empty_disk_image =
-        DiskImage { image: Some(empty_image), writable: false, partitions: vec![] };
+    let empty_disk_image = DiskImage {
+        image: Some(empty_image),
+        writable: false,
+        partitions: vec![],
+        directIo: false,
+    };
 
     let config = VirtualMachineConfig::RawConfig(VirtualMachineRawConfig {
         name: String::from("VmBaseTest"),